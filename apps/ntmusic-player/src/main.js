@@ -76,6 +76,14 @@ function getCoverDir() {
     return path.join(getAppDataRoot(), 'covers');
 }
 
+function getCacheDir() {
+    return path.join(getAppDataRoot(), 'cache');
+}
+
+function getEngineSettingsPath() {
+    return path.join(getAppDataRoot(), 'engine-settings.json');
+}
+
 function resolveNativeAddon() {
     const appRoot = getAppRoot();
     const candidates = app.isPackaged
@@ -202,6 +210,8 @@ function startAudioEngine() {
         process.env.VMUSIC_SOXR_DIR = soxrDir;
         process.env.VMUSIC_ASSET_DIR = assetDir;
         process.env.NTMUSIC_COVER_DIR = getCoverDir();
+        process.env.NTMUSIC_CACHE_DIR = getCacheDir();
+        process.env.NTMUSIC_SETTINGS = getEngineSettingsPath();
         const controlSpec = ntaBridge ? ntaBridge.getControlSpec() : null;
         if (spectrumSpec && spectrumSpec.path) {
             process.env.NTMUSIC_SPECTRUM_SHM = spectrumSpec.path;
@@ -238,7 +248,9 @@ function startAudioEngine() {
             VMUSIC_ENGINE_URL: ENGINE_URL,
             VMUSIC_ASSET_DIR: engineDir,
             VMUSIC_SOXR_DIR: soxrDir,
-            NTMUSIC_COVER_DIR: getCoverDir()
+            NTMUSIC_COVER_DIR: getCoverDir(),
+            NTMUSIC_CACHE_DIR: getCacheDir(),
+            NTMUSIC_SETTINGS: getEngineSettingsPath()
         };
         if (spectrumSpec && spectrumSpec.path) {
             env.NTMUSIC_SPECTRUM_SHM = spectrumSpec.path;
@@ -466,11 +478,17 @@ function registerPlayerIpc() {
 
     ipcMain.handle(
         'player:captureStart',
-        async (_event, { device_id, deviceId, samplerate, channels } = {}) => {
+        async (_event, { device_id, deviceId, samplerate, channels, loopback } = {}) => {
             const engine = ensureAudioEngine();
             if (!engine || typeof engine.captureStart !== 'function') {
                 return { status: 'error', message: 'AudioEngine not ready.' };
             }
+            if (loopback) {
+                if (typeof engine.startLoopbackCapture !== 'function') {
+                    return { status: 'error', message: 'Loopback capture unavailable.' };
+                }
+                return engine.startLoopbackCapture(true, device_id ?? deviceId ?? null);
+            }
             return engine.captureStart(
                 device_id ?? deviceId ?? null,
                 samplerate ?? null,
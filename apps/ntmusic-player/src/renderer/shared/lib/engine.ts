@@ -24,6 +24,7 @@ export const engineCmd = async (
         deviceId?: string | null;
         samplerate?: number | null;
         channels?: number | null;
+        loopback?: boolean | null;
       }) => Promise<EngineResult>;
       captureStop?: () => Promise<EngineResult>;
     };
@@ -99,6 +100,7 @@ export const engineCmd = async (
           deviceId?: string | null;
           samplerate?: number | null;
           channels?: number | null;
+          loopback?: boolean | null;
         } | undefined;
         const result = await player.captureStart(data);
         if (result?.status === 'error') return fallback();
@@ -13,7 +13,32 @@ export const EngineEvent = z.discriminatedUnion('type', [
     status: z.string(),
     error: z.string().optional(),
   }),
-  z.object({ type: z.literal('spectrum.data'), data: z.array(z.number()) }),
+  z.object({
+    type: z.literal('spectrum.data'),
+    data: z.array(z.number()),
+    peaks: z.array(z.number()).optional(),
+  }),
+  z.object({
+    type: z.literal('level.meter'),
+    peak: z.array(z.number()),
+    hold: z.array(z.number()),
+    rms: z.array(z.number()),
+    clip: z.boolean(),
+  }),
+  z.object({
+    type: z.literal('load.progress'),
+    path: z.string(),
+    stage: z.string(),
+    percent: z.number().nullable(),
+  }),
+  z.object({
+    type: z.literal('device.changed'),
+    deviceId: z.number().nullable(),
+    hostapi: z.string().nullable(),
+    name: z.string().nullable(),
+    fallback: z.boolean(),
+    reopened: z.boolean(),
+  }),
   z.object({
     type: z.literal('engine.status'),
     connected: z.boolean(),
@@ -18,11 +18,34 @@ export type StreamState = {
   error?: string;
 } | null;
 
+export type LevelState = {
+  peak: number[];
+  hold: number[];
+  rms: number[];
+  clip: boolean;
+} | null;
+
+export type LoadState = {
+  path: string;
+  stage: string;
+  percent: number | null;
+} | null;
+
+export type DeviceState = {
+  deviceId: number | null;
+  hostapi: string | null;
+  name: string | null;
+  fallback: boolean;
+} | null;
+
 export type StoreState = {
   playback: PlaybackState;
   buffer: BufferState;
   stream: StreamState;
   spectrum: number[] | null;
+  levels: LevelState;
+  load: LoadState;
+  device: DeviceState;
   engine: EngineState;
 };
 
@@ -35,6 +58,9 @@ let state: StoreState = {
   buffer: null,
   stream: null,
   spectrum: null,
+  levels: null,
+  load: null,
+  device: null,
   engine: { connected: false },
 };
 
@@ -53,7 +79,12 @@ export const ingest = (event: EngineEvent) => {
   const prev = state;
   switch (event.type) {
     case 'playback.state':
-      state = { ...state, playback: event.state };
+      state = {
+        ...state,
+        playback: event.state,
+        // Progress only means anything while the engine is still loading.
+        load: event.state?.stream_status === 'loading' ? state.load : null,
+      };
       break;
     case 'buffer.state':
       state = { ...state, buffer: event };
@@ -64,6 +95,29 @@ export const ingest = (event: EngineEvent) => {
     case 'spectrum.data':
       state = { ...state, spectrum: event.data };
       break;
+    case 'level.meter':
+      state = {
+        ...state,
+        levels: { peak: event.peak, hold: event.hold, rms: event.rms, clip: event.clip },
+      };
+      break;
+    case 'load.progress':
+      state = {
+        ...state,
+        load: { path: event.path, stage: event.stage, percent: event.percent },
+      };
+      break;
+    case 'device.changed':
+      state = {
+        ...state,
+        device: {
+          deviceId: event.deviceId,
+          hostapi: event.hostapi,
+          name: event.name,
+          fallback: event.fallback,
+        },
+      };
+      break;
     case 'engine.status':
       state = {
         ...state,
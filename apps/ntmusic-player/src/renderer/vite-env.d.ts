@@ -41,6 +41,7 @@ declare global {
         deviceId?: string | null;
         samplerate?: number | null;
         channels?: number | null;
+        loopback?: boolean | null;
       }) => Promise<any>;
       captureStop?: () => Promise<any>;
       onProgress?: (callback: (payload: { current: number; duration: number }) => void) => () => void;
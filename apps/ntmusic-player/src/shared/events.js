@@ -13,7 +13,32 @@ const EngineRawEvent = z.discriminatedUnion('type', [
         status: z.string(),
         error: z.string().optional()
     }),
-    z.object({ type: z.literal('spectrum_data'), data: z.array(z.number()) })
+    z.object({
+        type: z.literal('spectrum_data'),
+        data: z.array(z.number()),
+        peaks: z.array(z.number()).optional()
+    }),
+    z.object({
+        type: z.literal('level_meter'),
+        peak: z.array(z.number()),
+        hold: z.array(z.number()),
+        rms: z.array(z.number()),
+        clip: z.boolean()
+    }),
+    z.object({
+        type: z.literal('load_progress'),
+        path: z.string(),
+        stage: z.string(),
+        percent: z.number().nullable()
+    }),
+    z.object({
+        type: z.literal('device_changed'),
+        device_id: z.number().nullable(),
+        hostapi: z.string().nullable(),
+        name: z.string().nullable(),
+        fallback: z.boolean(),
+        reopened: z.boolean()
+    })
 ]);
 
 const EngineEvent = z.discriminatedUnion('type', [
@@ -29,7 +54,32 @@ const EngineEvent = z.discriminatedUnion('type', [
         status: z.string(),
         error: z.string().optional()
     }),
-    z.object({ type: z.literal('spectrum.data'), data: z.array(z.number()) }),
+    z.object({
+        type: z.literal('spectrum.data'),
+        data: z.array(z.number()),
+        peaks: z.array(z.number()).optional()
+    }),
+    z.object({
+        type: z.literal('level.meter'),
+        peak: z.array(z.number()),
+        hold: z.array(z.number()),
+        rms: z.array(z.number()),
+        clip: z.boolean()
+    }),
+    z.object({
+        type: z.literal('load.progress'),
+        path: z.string(),
+        stage: z.string(),
+        percent: z.number().nullable()
+    }),
+    z.object({
+        type: z.literal('device.changed'),
+        deviceId: z.number().nullable(),
+        hostapi: z.string().nullable(),
+        name: z.string().nullable(),
+        fallback: z.boolean(),
+        reopened: z.boolean()
+    }),
     z.object({
         type: z.literal('engine.status'),
         connected: z.boolean(),
@@ -63,7 +113,31 @@ function normalizeEngineEvent(raw) {
                 error: event.error
             };
         case 'spectrum_data':
-            return { type: 'spectrum.data', data: event.data };
+            return { type: 'spectrum.data', data: event.data, peaks: event.peaks };
+        case 'level_meter':
+            return {
+                type: 'level.meter',
+                peak: event.peak,
+                hold: event.hold,
+                rms: event.rms,
+                clip: event.clip
+            };
+        case 'load_progress':
+            return {
+                type: 'load.progress',
+                path: event.path,
+                stage: event.stage,
+                percent: event.percent
+            };
+        case 'device_changed':
+            return {
+                type: 'device.changed',
+                deviceId: event.device_id,
+                hostapi: event.hostapi,
+                name: event.name,
+                fallback: event.fallback,
+                reopened: event.reopened
+            };
         default:
             return null;
     }
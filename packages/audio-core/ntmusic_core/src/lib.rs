@@ -4,18 +4,32 @@ use napi_derive::napi;
 use std::fs::OpenOptions;
 use std::path::PathBuf;
 use std::sync::{
-    atomic::{AtomicU32, Ordering},
-    Arc, Mutex,
+    atomic::{fence, AtomicU32, Ordering},
+    Arc, Mutex, OnceLock,
 };
-use ntmusic_engine::{DeviceInfo as CoreDeviceInfo, EngineHandle, LibraryTrack as CoreLibraryTrack};
+use ntmusic_engine::{CaptureMonitor, DeviceInfo as CoreDeviceInfo, EngineHandle, LibraryTrack as CoreLibraryTrack};
 
 const DEFAULT_SPECTRUM_BINS: u32 = 48;
 const SPECTRUM_FILE_NAME: &str = "ntmusic_spectrum.bin";
-const SPECTRUM_HEADER_BYTES: usize = std::mem::size_of::<u32>();
+// Must match the engine's SPECTRUM_* header: u32 seqlock counter, u32 current
+// bin count (0 = not yet written), u32 version, u32 flags.
+const SPECTRUM_SHM_VERSION: u32 = 2;
+const SPECTRUM_HEADER_BYTES: usize = 4 * std::mem::size_of::<u32>();
+const SPECTRUM_BINS_OFFSET: usize = 4;
+const SPECTRUM_VERSION_OFFSET: usize = 8;
+const SPECTRUM_FLAGS_OFFSET: usize = 12;
+const SPECTRUM_FLAG_PEAKS: u32 = 1;
+// Seqlock passes before a read gives up on a writer that keeps the frame busy.
+const SPECTRUM_READ_ATTEMPTS: usize = 8;
 const DEFAULT_CONTROL_CAPACITY: u32 = 64;
 const CONTROL_FILE_NAME: &str = "ntmusic_control.bin";
 const CONTROL_HEADER_BYTES: usize = 16;
 const CONTROL_CMD_BYTES: usize = 16;
+const STATE_FILE_NAME: &str = "ntmusic_state.bin";
+// Must match the engine's STATE_SHM_* layout (seqlock u32, version u32, then
+// the fields read in `StateReader::read`).
+const STATE_SHM_VERSION: u32 = 1;
+const STATE_SHM_BYTES: usize = 64;
 
 #[napi(object)]
 pub struct SpectrumSpec {
@@ -24,6 +38,30 @@ pub struct SpectrumSpec {
     pub byte_length: u32,
 }
 
+#[napi(object)]
+pub struct StateSpec {
+    pub path: String,
+    pub byte_length: u32,
+}
+
+/// Frequently changing playback numbers from the state shm. Frame counts are
+/// exact up to 2^53, which is far beyond any real session.
+#[napi(object)]
+pub struct PlaybackSnapshot {
+    pub position_frames: f64,
+    pub played_frames: f64,
+    pub buffered_frames: f64,
+    pub underruns: f64,
+    pub sample_rate: u32,
+    pub volume: f64,
+    pub duration: f64,
+    pub is_playing: bool,
+    pub is_paused: bool,
+    /// Stream or capture; use `played_frames` for the position.
+    pub live: bool,
+    pub device_released: bool,
+}
+
 #[napi(object)]
 pub struct ControlSpec {
     pub path: String,
@@ -47,6 +85,20 @@ fn normalize_capacity(capacity: u32) -> u32 {
     }
 }
 
+/// Grow `file` to at least `len` bytes. Never shrinks: the engine may have
+/// grown the spectrum file for more bins and still has it mapped.
+fn grow_file_to(file: &std::fs::File, len: u64) -> Result<()> {
+    let current = file
+        .metadata()
+        .map_err(|err| Error::from_reason(err.to_string()))?
+        .len();
+    if current < len {
+        file.set_len(len)
+            .map_err(|err| Error::from_reason(err.to_string()))?;
+    }
+    Ok(())
+}
+
 fn ensure_spectrum_file(dir: &str, bins: u32) -> Result<(PathBuf, u32)> {
     let bins = normalize_bins(bins);
     let mut dir_path = PathBuf::from(dir);
@@ -62,8 +114,7 @@ fn ensure_spectrum_file(dir: &str, bins: u32) -> Result<(PathBuf, u32)> {
         .create(true)
         .open(&dir_path)
         .map_err(|err| Error::from_reason(err.to_string()))?;
-    file.set_len(file_len as u64)
-        .map_err(|err| Error::from_reason(err.to_string()))?;
+    grow_file_to(&file, file_len as u64)?;
     Ok((dir_path, data_len))
 }
 
@@ -109,6 +160,20 @@ pub fn create_spectrum_shm(dir: String, bins: u32) -> Result<SpectrumSpec> {
     })
 }
 
+/// Create the state file for the engine to write into; pass its path to the
+/// engine as `NTMUSIC_STATE_SHM`.
+#[napi]
+pub fn create_state_shm(dir: String) -> Result<StateSpec> {
+    let mut path = PathBuf::from(dir);
+    std::fs::create_dir_all(&path).map_err(|err| Error::from_reason(err.to_string()))?;
+    path.push(STATE_FILE_NAME);
+    map_spectrum_file(&path, STATE_SHM_BYTES)?;
+    Ok(StateSpec {
+        path: path.to_string_lossy().to_string(),
+        byte_length: STATE_SHM_BYTES as u32,
+    })
+}
+
 #[napi]
 pub fn create_control_shm(dir: String, capacity: u32) -> Result<ControlSpec> {
     let (path, byte_length) = ensure_control_file(&dir, capacity)?;
@@ -119,11 +184,28 @@ pub fn create_control_shm(dir: String, capacity: u32) -> Result<ControlSpec> {
     })
 }
 
+fn map_spectrum_file(path: &PathBuf, min_len: usize) -> Result<MmapMut> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|err| Error::from_reason(err.to_string()))?;
+    grow_file_to(&file, min_len as u64)?;
+    unsafe { MmapMut::map_mut(&file).map_err(|err| Error::from_reason(err.to_string())) }
+}
+
 #[napi]
 pub struct SpectrumReader {
+    path: PathBuf,
     mmap: MmapMut,
+    /// Fallback for files whose header bin count is still zero.
+    default_bins: usize,
     bins: usize,
     last_seq: u32,
+    /// Holds a frame until the seqlock confirms it was read whole.
+    scratch: Vec<f32>,
 }
 
 #[napi]
@@ -134,63 +216,85 @@ impl SpectrumReader {
         let path_buf = PathBuf::from(path);
         let data_len = bins.saturating_mul(std::mem::size_of::<f32>());
         let byte_len = SPECTRUM_HEADER_BYTES.saturating_add(data_len);
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&path_buf)
-            .map_err(|err| Error::from_reason(err.to_string()))?;
-        file.set_len(byte_len as u64)
-            .map_err(|err| Error::from_reason(err.to_string()))?;
-        let mmap = unsafe {
-            MmapMut::map_mut(&file)
-                .map_err(|err| Error::from_reason(err.to_string()))?
-        };
+        let mmap = map_spectrum_file(&path_buf, byte_len)?;
         Ok(SpectrumReader {
+            path: path_buf,
             mmap,
+            default_bins: bins,
             bins,
             last_seq: 0,
+            scratch: Vec::with_capacity(bins),
         })
     }
 
+    fn header_word(&self, offset: usize) -> u32 {
+        let field = unsafe { &*(self.mmap.as_ptr().add(offset) as *const AtomicU32) };
+        field.load(Ordering::Acquire)
+    }
+
+    fn declared_bins(&self) -> usize {
+        match self.header_word(SPECTRUM_BINS_OFFSET) {
+            0 => self.default_bins,
+            bins => bins as usize,
+        }
+    }
+
+    /// Copies the latest frame into `target` and returns how many bins it
+    /// holds, or 0 when nothing new was written. The bin count comes from
+    /// the writer's header, not the constructor; a header of another
+    /// version is an error rather than data read at the wrong offsets.
     #[napi]
     pub fn read_into(&mut self, mut target: Float32Array) -> Result<u32> {
-        let target_slice = target.as_mut();
-        let data_len = self.mmap.len().saturating_sub(SPECTRUM_HEADER_BYTES);
-        let available_bins = data_len / std::mem::size_of::<f32>();
-        let bins = self.bins.min(available_bins);
-        if bins == 0 {
-            return Ok(0);
-        }
-        let len = bins.min(target_slice.len());
-        let seq = unsafe { &*(self.mmap.as_ptr() as *const AtomicU32) };
-        let data_ptr = unsafe { self.mmap.as_ptr().add(SPECTRUM_HEADER_BYTES) as *const f32 };
-        for _ in 0..2 {
-            let seq_start = seq.load(Ordering::Acquire);
-            if seq_start == self.last_seq && seq_start & 1 == 0 {
-                return Ok(0);
-            }
+        self.read_frame(target.as_mut())
+    }
+
+    fn read_frame(&mut self, target: &mut [f32]) -> Result<u32> {
+        for _ in 0..SPECTRUM_READ_ATTEMPTS {
+            let seq_start = self.header_word(0);
             if seq_start & 1 == 1 {
+                // Mid-write, not "nothing new": wait for the writer.
+                std::thread::yield_now();
                 continue;
             }
-            let src = unsafe { std::slice::from_raw_parts(data_ptr, bins) };
-            if len > 0 {
-                target_slice[..len].copy_from_slice(&src[..len]);
+            if seq_start == self.last_seq {
+                return Ok(0);
+            }
+            let version = self.header_word(SPECTRUM_VERSION_OFFSET);
+            if version != SPECTRUM_SHM_VERSION {
+                return Err(Error::from_reason(format!(
+                    "spectrum shm version {} unsupported (expected {})",
+                    version, SPECTRUM_SHM_VERSION
+                )));
             }
-            if len < target_slice.len() {
-                for value in &mut target_slice[len..] {
-                    *value = 0.0;
+            // The engine may have grown the file for a larger bin count.
+            self.bins = self.declared_bins();
+            let needed = SPECTRUM_HEADER_BYTES + self.bins * std::mem::size_of::<f32>();
+            if needed > self.mmap.len() {
+                self.mmap = map_spectrum_file(&self.path, 0)?;
+                if needed > self.mmap.len() {
+                    return Ok(0);
                 }
+                continue;
+            }
+            let bins = self.bins;
+            if bins == 0 {
+                return Ok(0);
             }
-            let seq_end = seq.load(Ordering::Acquire);
-            if seq_start == seq_end && seq_end & 1 == 0 {
-                self.last_seq = seq_end;
+            // Copy out first so a torn attempt never reaches `target`.
+            self.scratch.resize(bins, 0.0);
+            let data_ptr = unsafe { self.mmap.as_ptr().add(SPECTRUM_HEADER_BYTES) as *const f32 };
+            let src = unsafe { std::slice::from_raw_parts(data_ptr, bins) };
+            self.scratch.copy_from_slice(src);
+            fence(Ordering::Acquire);
+            if self.header_word(0) == seq_start {
+                self.last_seq = seq_start;
+                let len = bins.min(target.len());
+                target[..len].copy_from_slice(&self.scratch[..len]);
+                target[len..].fill(0.0);
                 return Ok(len as u32);
             }
         }
-        for value in target_slice.iter_mut() {
-            *value = 0.0;
-        }
+        // Still contended: `target` keeps the last whole frame.
         Ok(0)
     }
 
@@ -198,6 +302,68 @@ impl SpectrumReader {
     pub fn bins(&self) -> u32 {
         self.bins as u32
     }
+
+    /// Whether the writer appends peak-hold values after the spectrum.
+    #[napi]
+    pub fn has_peaks(&self) -> bool {
+        self.header_word(SPECTRUM_FLAGS_OFFSET) & SPECTRUM_FLAG_PEAKS != 0
+    }
+}
+
+/// Polls the engine's state shm without going through JSON.
+#[napi]
+pub struct StateReader {
+    mmap: MmapMut,
+}
+
+#[napi]
+impl StateReader {
+    #[napi(constructor)]
+    pub fn new(path: String) -> Result<Self> {
+        let mmap = map_spectrum_file(&PathBuf::from(path), STATE_SHM_BYTES)?;
+        Ok(StateReader { mmap })
+    }
+
+    /// The latest consistent snapshot, or null if the engine hasn't written
+    /// one yet, is mid-write twice in a row, or uses another layout version.
+    #[napi]
+    pub fn read(&self) -> Option<PlaybackSnapshot> {
+        let seq = unsafe { &*(self.mmap.as_ptr() as *const AtomicU32) };
+        for _ in 0..2 {
+            let seq_start = seq.load(Ordering::Acquire);
+            if seq_start == 0 {
+                return None;
+            }
+            if seq_start & 1 == 1 {
+                continue;
+            }
+            let mut bytes = [0u8; STATE_SHM_BYTES];
+            bytes.copy_from_slice(&self.mmap[..STATE_SHM_BYTES]);
+            if seq.load(Ordering::Acquire) != seq_start {
+                continue;
+            }
+            let u32_at = |at: usize| u32::from_ne_bytes(bytes[at..at + 4].try_into().unwrap());
+            let u64_at = |at: usize| u64::from_ne_bytes(bytes[at..at + 8].try_into().unwrap());
+            if u32_at(4) != STATE_SHM_VERSION {
+                return None;
+            }
+            let flags = u32_at(48);
+            return Some(PlaybackSnapshot {
+                position_frames: u64_at(8) as f64,
+                played_frames: u64_at(16) as f64,
+                buffered_frames: u64_at(24) as f64,
+                underruns: u64_at(32) as f64,
+                sample_rate: u32_at(40),
+                volume: f32::from_ne_bytes(bytes[44..48].try_into().unwrap()) as f64,
+                duration: f64::from_ne_bytes(bytes[56..64].try_into().unwrap()),
+                is_playing: flags & 1 != 0,
+                is_paused: flags & (1 << 1) != 0,
+                live: flags & (1 << 2) != 0,
+                device_released: flags & (1 << 3) != 0,
+            });
+        }
+        None
+    }
 }
 
 #[napi(object)]
@@ -237,8 +403,21 @@ pub struct LibraryTrack {
     pub path: String,
     pub title: Option<String>,
     pub artist: Option<String>,
+    pub artists: Vec<String>,
+    pub album_artist: Option<String>,
     pub album: Option<String>,
+    pub track_number: Option<u32>,
+    pub disc_number: Option<u32>,
+    pub year: Option<u32>,
+    pub genre: Option<String>,
     pub duration: f64,
+    pub sample_rate: Option<u32>,
+    pub bit_depth: Option<u32>,
+    pub channels: Option<u32>,
+    pub track_gain: Option<f64>,
+    pub album_gain: Option<f64>,
+    /// Treated as decodable when unset.
+    pub decodable: Option<bool>,
 }
 
 #[napi(object)]
@@ -255,6 +434,14 @@ pub struct QueueAddResult {
     pub count: u32,
 }
 
+#[napi(object)]
+pub struct QueueListResult {
+    pub status: String,
+    pub message: Option<String>,
+    pub tracks: Vec<LibraryTrack>,
+    pub queue_index: Option<u32>,
+}
+
 #[napi(object)]
 pub struct QueueNextResult {
     pub status: String,
@@ -290,8 +477,20 @@ fn map_library_track(info: CoreLibraryTrack) -> LibraryTrack {
         path: info.path,
         title: info.title,
         artist: info.artist,
+        artists: info.artists,
+        album_artist: info.album_artist,
         album: info.album,
+        track_number: info.track_number,
+        disc_number: info.disc_number,
+        year: info.year,
+        genre: info.genre,
         duration: info.duration,
+        sample_rate: info.sample_rate,
+        bit_depth: info.bit_depth,
+        channels: info.channels,
+        track_gain: info.track_gain.map(f64::from),
+        album_gain: info.album_gain.map(f64::from),
+        decodable: Some(info.decodable),
     }
 }
 
@@ -300,8 +499,20 @@ fn map_library_track_to_core(track: LibraryTrack) -> CoreLibraryTrack {
         path: track.path,
         title: track.title,
         artist: track.artist,
+        artists: track.artists,
+        album_artist: track.album_artist,
         album: track.album,
+        track_number: track.track_number,
+        disc_number: track.disc_number,
+        year: track.year,
+        genre: track.genre,
         duration: track.duration,
+        sample_rate: track.sample_rate,
+        bit_depth: track.bit_depth,
+        channels: track.channels,
+        track_gain: track.track_gain.map(|gain| gain as f32),
+        album_gain: track.album_gain.map(|gain| gain as f32),
+        decodable: track.decodable.unwrap_or(true),
     }
 }
 
@@ -319,6 +530,29 @@ fn status_error(message: impl std::fmt::Display) -> EngineStatusResult {
     }
 }
 
+/// The one engine in this process. Every `AudioEngine` wraps it, so
+/// constructing several (one per window, or by accident on reload) drives the
+/// same playback state and binds the HTTP server only once.
+static ENGINE: OnceLock<Arc<Mutex<EngineHandle>>> = OnceLock::new();
+/// Held while the engine is built, so two first instances racing each other
+/// don't both start one. `OnceLock::get_or_init` can't report the failure.
+static ENGINE_INIT: Mutex<()> = Mutex::new(());
+
+fn shared_engine() -> Result<Arc<Mutex<EngineHandle>>> {
+    if let Some(handle) = ENGINE.get() {
+        return Ok(handle.clone());
+    }
+    let _building = ENGINE_INIT.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(handle) = ENGINE.get() {
+        return Ok(handle.clone());
+    }
+    let handle = EngineHandle::new().map_err(|err| Error::from_reason(err.to_string()))?;
+    Ok(ENGINE.get_or_init(|| Arc::new(Mutex::new(handle))).clone())
+}
+
+/// A frontend's view of the process-wide engine. Instances are cheap and all
+/// share one `EngineHandle`; the server starts with the first instance and
+/// later `start_server` calls are no-ops, whatever port they ask for.
 #[napi]
 pub struct AudioEngine {
     handle: Arc<Mutex<EngineHandle>>,
@@ -328,9 +562,8 @@ pub struct AudioEngine {
 impl AudioEngine {
     #[napi(constructor)]
     pub fn new(_engine_url: Option<String>) -> Result<Self> {
-        let handle = EngineHandle::new().map_err(|err| Error::from_reason(err.to_string()))?;
         let engine = AudioEngine {
-            handle: Arc::new(Mutex::new(handle)),
+            handle: shared_engine()?,
         };
         let port = resolve_engine_port();
         if let Ok(guard) = engine.handle.lock() {
@@ -404,6 +637,15 @@ impl AudioEngine {
         }
     }
 
+    #[napi]
+    pub fn restart(&self) -> Result<EngineStatusResult> {
+        let guard = self.handle.lock().map_err(|_| Error::from_reason("engine lock poisoned".to_string()))?;
+        match guard.restart() {
+            Ok(_) => Ok(status_success()),
+            Err(err) => Ok(status_error(err)),
+        }
+    }
+
     #[napi]
     pub fn set_device(
         &self,
@@ -417,6 +659,15 @@ impl AudioEngine {
         }
     }
 
+    #[napi]
+    pub fn set_output_latency(&self, latency_ms: Option<u32>) -> Result<EngineStatusResult> {
+        let guard = self.handle.lock().map_err(|_| Error::from_reason("engine lock poisoned".to_string()))?;
+        match guard.set_output_latency(latency_ms) {
+            Ok(_) => Ok(status_success()),
+            Err(err) => Ok(status_error(err)),
+        }
+    }
+
     #[napi]
     pub fn get_devices(&self) -> Result<DevicesResult> {
         let guard = self.handle.lock().map_err(|_| Error::from_reason("engine lock poisoned".to_string()))?;
@@ -470,13 +721,45 @@ impl AudioEngine {
         }
     }
 
+    /// Library tracks matching `query`, best first; nothing is played.
     #[napi]
-    pub fn queue_add(&self, tracks: Vec<LibraryTrack>, replace: Option<bool>) -> Result<QueueAddResult> {
+    pub fn search_library(&self, query: String, limit: Option<u32>) -> Result<LibraryScanResult> {
         let guard = self.handle.lock().map_err(|_| Error::from_reason("engine lock poisoned".to_string()))?;
-        match guard.queue_add(
-            tracks.into_iter().map(map_library_track_to_core).collect(),
-            replace.unwrap_or(false),
-        ) {
+        match guard.search_library(&query, limit.map(|limit| limit as usize)) {
+            Ok(tracks) => Ok(LibraryScanResult {
+                status: "success".to_string(),
+                message: None,
+                tracks: tracks.into_iter().map(map_library_track).collect(),
+            }),
+            Err(err) => Ok(LibraryScanResult {
+                status: "error".to_string(),
+                message: Some(err.to_string()),
+                tracks: Vec::new(),
+            }),
+        }
+    }
+
+    #[napi]
+    pub fn queue_add(
+        &self,
+        tracks: Vec<LibraryTrack>,
+        replace: Option<bool>,
+        at: Option<u32>,
+    ) -> Result<QueueAddResult> {
+        if at.is_some() && replace.unwrap_or(false) {
+            return Ok(QueueAddResult {
+                status: "error".to_string(),
+                message: Some("at can't be combined with replace".to_string()),
+                count: 0,
+            });
+        }
+        let guard = self.handle.lock().map_err(|_| Error::from_reason("engine lock poisoned".to_string()))?;
+        let tracks = tracks.into_iter().map(map_library_track_to_core).collect();
+        let result = match at {
+            Some(at) => guard.queue_insert(tracks, at as usize),
+            None => guard.queue_add(tracks, replace.unwrap_or(false)),
+        };
+        match result {
             Ok(count) => Ok(QueueAddResult {
                 status: "success".to_string(),
                 message: None,
@@ -490,6 +773,52 @@ impl AudioEngine {
         }
     }
 
+    #[napi]
+    pub fn queue_list(&self) -> Result<QueueListResult> {
+        let guard = self.handle.lock().map_err(|_| Error::from_reason("engine lock poisoned".to_string()))?;
+        let (tracks, queue_index) = guard.queue_list();
+        Ok(QueueListResult {
+            status: "success".to_string(),
+            message: None,
+            tracks: tracks.into_iter().map(map_library_track).collect(),
+            queue_index: queue_index.map(|index| index as u32),
+        })
+    }
+
+    #[napi]
+    pub fn queue_remove(&self, indices: Vec<u32>) -> Result<QueueAddResult> {
+        let guard = self.handle.lock().map_err(|_| Error::from_reason("engine lock poisoned".to_string()))?;
+        let indices: Vec<usize> = indices.into_iter().map(|index| index as usize).collect();
+        match guard.queue_remove(&indices) {
+            Ok(count) => Ok(QueueAddResult {
+                status: "success".to_string(),
+                message: None,
+                count: count as u32,
+            }),
+            Err(err) => Ok(QueueAddResult {
+                status: "error".to_string(),
+                message: Some(err.to_string()),
+                count: 0,
+            }),
+        }
+    }
+
+    #[napi]
+    pub fn queue_move(&self, from: u32, to: u32) -> Result<EngineStatusResult> {
+        let guard = self.handle.lock().map_err(|_| Error::from_reason("engine lock poisoned".to_string()))?;
+        match guard.queue_move(from as usize, to as usize) {
+            Ok(_) => Ok(status_success()),
+            Err(err) => Ok(status_error(err)),
+        }
+    }
+
+    #[napi]
+    pub fn queue_clear(&self) -> Result<EngineStatusResult> {
+        let guard = self.handle.lock().map_err(|_| Error::from_reason("engine lock poisoned".to_string()))?;
+        guard.queue_clear();
+        Ok(status_success())
+    }
+
     #[napi]
     pub fn next_track(&self) -> Result<QueueNextResult> {
         let guard = self.handle.lock().map_err(|_| Error::from_reason("engine lock poisoned".to_string()))?;
@@ -512,15 +841,45 @@ impl AudioEngine {
         }
     }
 
+    #[napi]
+    pub fn prev_track(&self) -> Result<QueueNextResult> {
+        let guard = self.handle.lock().map_err(|_| Error::from_reason("engine lock poisoned".to_string()))?;
+        match guard.queue_prev() {
+            Ok(Some(track)) => Ok(QueueNextResult {
+                status: "success".to_string(),
+                message: None,
+                track: Some(map_library_track(track)),
+            }),
+            Ok(None) => Ok(QueueNextResult {
+                status: "error".to_string(),
+                message: Some("no previous track".to_string()),
+                track: None,
+            }),
+            Err(err) => Ok(QueueNextResult {
+                status: "error".to_string(),
+                message: Some(err.to_string()),
+                track: None,
+            }),
+        }
+    }
+
     #[napi]
     pub fn capture_start(
         &self,
         device_id: Option<String>,
         samplerate: Option<u32>,
         channels: Option<u16>,
+        monitor: Option<bool>,
+        monitor_device_id: Option<u32>,
+        latency_ms: Option<u32>,
     ) -> Result<EngineStatusResult> {
+        let monitor = CaptureMonitor {
+            enabled: monitor.unwrap_or(true),
+            device_id: monitor_device_id.map(|id| id as usize),
+            latency_ms,
+        };
         let guard = self.handle.lock().map_err(|_| Error::from_reason("engine lock poisoned".to_string()))?;
-        match guard.capture_start(device_id, samplerate, channels) {
+        match guard.capture_start(device_id, samplerate, channels, monitor, false) {
             Ok(_) => Ok(status_success()),
             Err(err) => Ok(status_error(err)),
         }
@@ -535,12 +894,23 @@ impl AudioEngine {
         }
     }
 
+    /// Records what render endpoint `device_id` (the default output when
+    /// omitted) plays; on Windows through WASAPI loopback.
     #[napi]
-    pub fn start_loopback_capture(&self, enable: bool) -> Result<EngineStatusResult> {
-        if enable {
-            self.capture_start(None, None, None)
-        } else {
-            self.capture_stop()
+    pub fn start_loopback_capture(&self, enable: bool, device_id: Option<String>) -> Result<EngineStatusResult> {
+        if !enable {
+            return self.capture_stop();
+        }
+        // Loopback records an output, so playing it back there would feed
+        // into itself.
+        let monitor = CaptureMonitor {
+            enabled: false,
+            ..CaptureMonitor::default()
+        };
+        let guard = self.handle.lock().map_err(|_| Error::from_reason("engine lock poisoned".to_string()))?;
+        match guard.capture_start(device_id, None, None, monitor, true) {
+            Ok(_) => Ok(status_success()),
+            Err(err) => Ok(status_error(err)),
         }
     }
 }
@@ -608,3 +978,64 @@ impl ControlWriter {
         Ok(true)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::time::Duration;
+
+    #[test]
+    fn spectrum_reader_never_sees_a_torn_frame() {
+        const BINS: usize = 512;
+        let path = std::env::temp_dir().join(format!("ntmusic_core_spectrum_{}.bin", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut writer_map = map_spectrum_file(&path, SPECTRUM_HEADER_BYTES + BINS * 4).unwrap();
+        writer_map[SPECTRUM_BINS_OFFSET..SPECTRUM_VERSION_OFFSET].copy_from_slice(&(BINS as u32).to_ne_bytes());
+        writer_map[SPECTRUM_VERSION_OFFSET..SPECTRUM_FLAGS_OFFSET]
+            .copy_from_slice(&SPECTRUM_SHM_VERSION.to_ne_bytes());
+        let mut reader = SpectrumReader::new(path.to_string_lossy().to_string(), 16).unwrap();
+
+        // Rewrites every bin with the frame number, back to back, the way
+        // the engine's seqlock does.
+        let stop = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let stop = Arc::clone(&stop);
+            std::thread::spawn(move || {
+                let mut frame = 0u32;
+                while !stop.load(Ordering::Relaxed) {
+                    frame += 1;
+                    let seq = unsafe { &*(writer_map.as_ptr() as *const AtomicU32) };
+                    seq.store(frame * 2 - 1, Ordering::Release);
+                    let data_ptr = unsafe { writer_map.as_mut_ptr().add(SPECTRUM_HEADER_BYTES) as *mut f32 };
+                    let data = unsafe { std::slice::from_raw_parts_mut(data_ptr, BINS) };
+                    data.fill(frame as f32);
+                    seq.store(frame * 2, Ordering::Release);
+                    std::thread::sleep(Duration::from_micros(20));
+                }
+            })
+        };
+
+        let mut target = vec![0.0f32; BINS];
+        let mut frames = 0;
+        for _ in 0..20_000 {
+            if reader.read_frame(&mut target).unwrap() > 0 {
+                frames += 1;
+            }
+            assert!(target.iter().all(|value| *value == target[0]), "torn frame");
+            // Contention keeps the last frame rather than blanking it.
+            assert!(frames == 0 || target[0] > 0.0);
+        }
+        stop.store(true, Ordering::Relaxed);
+        writer.join().unwrap();
+        assert_eq!(reader.bins(), BINS as u32);
+        assert!(frames > 0);
+
+        // A header from another layout is refused, not copied.
+        let mut map = map_spectrum_file(&path, 0).unwrap();
+        map[SPECTRUM_VERSION_OFFSET..SPECTRUM_FLAGS_OFFSET].copy_from_slice(&1u32.to_ne_bytes());
+        map[..4].copy_from_slice(&u32::MAX.wrapping_sub(1).to_ne_bytes());
+        assert!(reader.read_frame(&mut target).is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,248 @@
+//! Musical key and tempo estimates.
+//!
+//! Both run on mono audio at `ANALYSIS_SAMPLE_RATE`. The key comes from the
+//! track's average chroma (the same pitch-class features the fingerprint
+//! uses) matched against the Krumhansl-Kessler key profiles; the tempo from
+//! the autocorrelation of a spectral-flux onset envelope, i.e. the most
+//! regular spacing between onsets.
+
+use rustfft::{num_complex::Complex, FftPlanner};
+use serde::Serialize;
+
+use crate::fingerprint;
+
+pub(crate) const ANALYSIS_SAMPLE_RATE: u32 = fingerprint::FINGERPRINT_SAMPLE_RATE;
+
+const PITCH_CLASSES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+const MAJOR_PROFILE: [f32; 12] = [6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88];
+const MINOR_PROFILE: [f32; 12] = [6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17];
+
+const ONSET_FRAME: usize = 1024;
+const ONSET_HOP: usize = 128;
+const MIN_BPM: f32 = 60.0;
+const MAX_BPM: f32 = 200.0;
+/// Tempo candidates are weighted towards this to settle half/double-time
+/// ambiguity the way listeners usually do.
+const PREFERRED_BPM: f32 = 120.0;
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct KeyEstimate {
+    /// e.g. "A minor".
+    pub key: String,
+    pub tonic: &'static str,
+    pub mode: &'static str,
+    /// Correlation of the chroma with the winning key profile, 0 to 1.
+    pub confidence: f32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct TempoEstimate {
+    pub bpm: f32,
+    /// Strength of the winning onset period relative to the envelope's
+    /// energy, 0 to 1.
+    pub confidence: f32,
+}
+
+/// Downmix interleaved audio to mono, keeping the whole track.
+pub(crate) fn downmix(samples: &[f32], channels: usize) -> Vec<f32> {
+    let channels = channels.max(1);
+    samples
+        .chunks_exact(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect()
+}
+
+/// `None` when there is too little pitched audio to tell.
+pub(crate) fn estimate_key(mono: &[f32]) -> Option<KeyEstimate> {
+    let mut profile = [0.0f32; 12];
+    for frame in fingerprint::chroma_frames(mono) {
+        for (total, value) in profile.iter_mut().zip(frame) {
+            *total += value;
+        }
+    }
+    if profile.iter().all(|v| *v <= 0.0) {
+        return None;
+    }
+    let mut best: Option<(f32, usize, bool)> = None;
+    for tonic in 0..12 {
+        for (minor, template) in [(false, &MAJOR_PROFILE), (true, &MINOR_PROFILE)] {
+            let rotated: Vec<f32> = (0..12).map(|pc| template[(pc + 12 - tonic) % 12]).collect();
+            let r = correlation(&profile, &rotated);
+            if best.is_none_or(|(best_r, _, _)| r > best_r) {
+                best = Some((r, tonic, minor));
+            }
+        }
+    }
+    let (r, tonic, minor) = best?;
+    let mode = if minor { "minor" } else { "major" };
+    Some(KeyEstimate {
+        key: format!("{} {}", PITCH_CLASSES[tonic], mode),
+        tonic: PITCH_CLASSES[tonic],
+        mode,
+        confidence: r.clamp(0.0, 1.0),
+    })
+}
+
+/// `None` when the track is too short or has no detectable pulse.
+pub(crate) fn estimate_tempo(mono: &[f32]) -> Option<TempoEstimate> {
+    let envelope = onset_envelope(mono);
+    let rate = ANALYSIS_SAMPLE_RATE as f32 / ONSET_HOP as f32;
+    let min_lag = (60.0 * rate / MAX_BPM).floor() as usize;
+    let max_lag = (60.0 * rate / MIN_BPM).ceil() as usize;
+    if envelope.len() < max_lag * 4 {
+        return None;
+    }
+    let autocorr: Vec<f32> = (0..=max_lag + 1)
+        .map(|lag| envelope.iter().zip(&envelope[lag..]).map(|(a, b)| a * b).sum())
+        .collect();
+    let energy = autocorr[0];
+    if energy <= 1e-9 {
+        return None;
+    }
+    let weight = |lag: f32| {
+        let octaves = (60.0 * rate / lag / PREFERRED_BPM).log2();
+        (-0.5 * octaves * octaves).exp()
+    };
+    let best_lag = (min_lag.max(1)..=max_lag)
+        .filter(|&lag| autocorr[lag] >= autocorr[lag - 1] && autocorr[lag] >= autocorr[lag + 1])
+        .max_by(|&a, &b| {
+            (autocorr[a] * weight(a as f32)).total_cmp(&(autocorr[b] * weight(b as f32)))
+        })?;
+    // Parabolic interpolation around the peak for sub-hop precision.
+    let (left, mid, right) = (autocorr[best_lag - 1], autocorr[best_lag], autocorr[best_lag + 1]);
+    let denom = left - 2.0 * mid + right;
+    let offset = if denom.abs() > 1e-12 { 0.5 * (left - right) / denom } else { 0.0 };
+    let lag = best_lag as f32 + offset.clamp(-0.5, 0.5);
+    Some(TempoEstimate {
+        bpm: 60.0 * rate / lag,
+        confidence: (mid / energy).clamp(0.0, 1.0),
+    })
+}
+
+/// Half-wave rectified log-spectral flux, one value per hop, mean removed.
+fn onset_envelope(mono: &[f32]) -> Vec<f32> {
+    if mono.len() < ONSET_FRAME {
+        return Vec::new();
+    }
+    let fft = FftPlanner::new().plan_fft_forward(ONSET_FRAME);
+    let window: Vec<f32> = (0..ONSET_FRAME)
+        .map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / ONSET_FRAME as f32).cos())
+        .collect();
+    let mut buffer = vec![Complex::new(0.0f32, 0.0); ONSET_FRAME];
+    let mut previous = vec![0.0f32; ONSET_FRAME / 2];
+    let mut envelope = Vec::with_capacity((mono.len() - ONSET_FRAME) / ONSET_HOP + 1);
+    let mut start = 0;
+    while start + ONSET_FRAME <= mono.len() {
+        for (slot, (sample, w)) in buffer
+            .iter_mut()
+            .zip(mono[start..start + ONSET_FRAME].iter().zip(&window))
+        {
+            *slot = Complex::new(sample * w, 0.0);
+        }
+        fft.process(&mut buffer);
+        let mut flux = 0.0f32;
+        for (prev, value) in previous.iter_mut().zip(&buffer) {
+            let magnitude = (1.0 + 100.0 * value.norm()).ln();
+            flux += (magnitude - *prev).max(0.0);
+            *prev = magnitude;
+        }
+        envelope.push(flux);
+        start += ONSET_HOP;
+    }
+    let mean = envelope.iter().sum::<f32>() / envelope.len() as f32;
+    for value in envelope.iter_mut() {
+        *value = (*value - mean).max(0.0);
+    }
+    envelope
+}
+
+fn correlation(a: &[f32], b: &[f32]) -> f32 {
+    let n = a.len() as f32;
+    let mean_a = a.iter().sum::<f32>() / n;
+    let mean_b = b.iter().sum::<f32>() / n;
+    let mut cov = 0.0;
+    let mut var_a = 0.0;
+    let mut var_b = 0.0;
+    for (x, y) in a.iter().zip(b) {
+        cov += (x - mean_a) * (y - mean_b);
+        var_a += (x - mean_a) * (x - mean_a);
+        var_b += (y - mean_b) * (y - mean_b);
+    }
+    if var_a <= 0.0 || var_b <= 0.0 {
+        return 0.0;
+    }
+    cov / (var_a * var_b).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone(freqs: &[f32], seconds: f32) -> Vec<f32> {
+        let rate = ANALYSIS_SAMPLE_RATE as f32;
+        (0..(rate * seconds) as usize)
+            .map(|i| {
+                let t = i as f32 / rate;
+                freqs
+                    .iter()
+                    .map(|f| (2.0 * std::f32::consts::PI * f * t).sin())
+                    .sum::<f32>()
+                    * 0.2
+            })
+            .collect()
+    }
+
+    fn semitones(base: f32, steps: i32) -> f32 {
+        base * 2f32.powf(steps as f32 / 12.0)
+    }
+
+    /// I-IV-V-I in the key a semitone offset from C major (or its relative
+    /// minor when `minor`).
+    fn cadence(offset: i32, minor: bool) -> Vec<f32> {
+        let c4 = 261.63;
+        let chords: [[i32; 3]; 4] = if minor {
+            [[9, 12, 16], [2, 5, 9], [4, 8, 11], [9, 12, 16]]
+        } else {
+            [[0, 4, 7], [5, 9, 12], [7, 11, 14], [0, 4, 7]]
+        };
+        chords
+            .iter()
+            .cycle()
+            .take(8)
+            .flat_map(|chord| {
+                let freqs: Vec<f32> = chord.iter().map(|s| semitones(c4, s + offset)).collect();
+                tone(&freqs, 1.0)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn key_of_simple_cadences() {
+        assert_eq!(estimate_key(&cadence(0, false)).unwrap().key, "C major");
+        assert_eq!(estimate_key(&cadence(7, false)).unwrap().key, "G major");
+        let minor = estimate_key(&cadence(0, true)).unwrap();
+        assert_eq!((minor.tonic, minor.mode), ("A", "minor"));
+        assert!(minor.confidence > 0.5);
+        assert!(estimate_key(&[0.0; 100]).is_none());
+    }
+
+    #[test]
+    fn tempo_of_a_click_track() {
+        for bpm in [90.0f32, 128.0] {
+            let rate = ANALYSIS_SAMPLE_RATE as f32;
+            let period = (60.0 / bpm * rate) as usize;
+            let mut seed = 1u32;
+            let clicks: Vec<f32> = (0..(rate * 20.0) as usize)
+                .map(|i| {
+                    seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                    let noise = (seed >> 8) as f32 / (1u32 << 24) as f32 - 0.5;
+                    if i % period < 200 { noise } else { noise * 0.01 }
+                })
+                .collect();
+            let estimate = estimate_tempo(&clicks).unwrap();
+            assert!((estimate.bpm - bpm).abs() < 1.5, "{} vs {}", estimate.bpm, bpm);
+            assert!(estimate.confidence > 0.1);
+        }
+        assert!(estimate_tempo(&[0.0; 1000]).is_none());
+    }
+}
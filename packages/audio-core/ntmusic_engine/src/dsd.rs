@@ -0,0 +1,412 @@
+//! DSD playback from .dsf files, which Symphonia doesn't read.
+//!
+//! Only stereo DSD64 (2.8224 MHz) plays so far. It plays one of two ways:
+//!
+//! PCM: each channel's bitstream, as ±1, runs through a 256-tap
+//! linear-phase FIR (a Blackman-windowed sinc with its -6 dB point at
+//! 50 kHz, normalized to unity gain at DC) and keeps every 8th or 16th
+//! output, giving 352.8 or 176.4 kHz. The response is flat within 0.01 dB
+//! to 20 kHz and at least 71 dB down from 80 kHz, and everything that
+//! would fold back into the audible band at 176.4 kHz is at least 94 dB
+//! down. Most of DSD's ultrasonic noise shaping falls in the stop band;
+//! what lies between 20 and 80 kHz is kept. There is no make-up gain, so
+//! SACD's 0 dB reference (50% modulation) plays at -6 dBFS. Since the
+//! input is one bit per tap, the filter runs as 32 lookup tables of 256
+//! partial sums, one per byte of history.
+//!
+//! DoP ("DSD over PCM" 1.1): 16 bits per channel go out in the low bits of
+//! each 24-bit sample at 176.4 kHz, under a marker byte alternating
+//! between 0x05 and 0xFA from frame to frame. A DAC that finds the markers
+//! plays the bits as DSD; anything else hears quiet noise, so the samples
+//! have to reach the device untouched.
+
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+
+/// DSD64, 64 times 44.1 kHz.
+pub(crate) const DSD64_RATE: u32 = 2_822_400;
+/// The rate DoP carries DSD64 at: 16 bits a sample.
+pub(crate) const DOP_RATE: u32 = DSD64_RATE / 16;
+/// PCM rates DSD64 converts to, by decimating 16 or 8 times.
+pub(crate) const DSD_PCM_RATES: [u32; 2] = [176_400, 352_800];
+
+const DOP_MARKERS: [u8; 2] = [0x05, 0xFA];
+/// Filter length in bytes of bitstream, 8 taps each.
+const FILTER_BYTES: usize = 32;
+const FILTER_CUTOFF_HZ: f64 = 50_000.0;
+/// The idle pattern DSD encoders emit for silence; averages to zero.
+const DSD_SILENCE: u8 = 0x69;
+
+/// How a DSD file is turned into samples for the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DsdOutput {
+    /// Converted to PCM at this rate, one of `DSD_PCM_RATES`.
+    Pcm(u32),
+    /// Packed as DoP words at `DOP_RATE`.
+    Dop,
+}
+
+impl DsdOutput {
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            DsdOutput::Pcm(_) => "pcm",
+            DsdOutput::Dop => "dop",
+        }
+    }
+}
+
+pub(crate) fn is_dsf_path(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("dsf"))
+}
+
+/// What the "fmt " chunk of a .dsf file says, and where its samples are.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct DsfHeader {
+    pub channels: usize,
+    /// DSD samples (bits) per second per channel.
+    pub dsd_rate: u32,
+    /// Bits per channel; the last block is padded past this.
+    pub sample_count: u64,
+    /// Bytes of each channel per block; blocks go channel by channel.
+    block_size: usize,
+    /// Set when each byte holds its earliest bit in the lowest place.
+    lsb_first: bool,
+    data_offset: u64,
+}
+
+impl DsfHeader {
+    pub(crate) fn duration(&self) -> f64 {
+        if self.dsd_rate == 0 {
+            return 0.0;
+        }
+        self.sample_count as f64 / self.dsd_rate as f64
+    }
+
+    /// Whether this file is one `decode_dsf` can play.
+    pub(crate) fn check_playable(&self) -> Result<()> {
+        if self.channels != 2 || self.dsd_rate != DSD64_RATE {
+            return Err(anyhow!(
+                "only stereo DSD64 is supported, not {} channels at {} Hz",
+                self.channels,
+                self.dsd_rate
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn expect_chunk(reader: &mut impl Read, id: &[u8; 4]) -> Result<u64> {
+    let mut found = [0u8; 4];
+    reader.read_exact(&mut found)?;
+    if &found != id {
+        return Err(anyhow!(
+            "expected {:?} chunk, found {:?}",
+            String::from_utf8_lossy(id),
+            String::from_utf8_lossy(&found)
+        ));
+    }
+    read_u64(reader)
+}
+
+/// Read the "DSD ", "fmt " and "data" chunk headers, leaving `reader` at
+/// the first sample.
+pub(crate) fn read_dsf_header(reader: &mut (impl Read + Seek)) -> Result<DsfHeader> {
+    let dsd_size = expect_chunk(reader, b"DSD ").context("not a DSF file")?;
+    reader.seek(SeekFrom::Start(dsd_size))?;
+    let fmt_start = dsd_size;
+    let fmt_size = expect_chunk(reader, b"fmt ")?;
+    let version = read_u32(reader)?;
+    let format_id = read_u32(reader)?;
+    if version != 1 || format_id != 0 {
+        return Err(anyhow!("unsupported DSF format {} (version {})", format_id, version));
+    }
+    let _channel_type = read_u32(reader)?;
+    let channels = read_u32(reader)? as usize;
+    let dsd_rate = read_u32(reader)?;
+    let lsb_first = match read_u32(reader)? {
+        1 => true,
+        8 => false,
+        bits => return Err(anyhow!("unsupported DSF bits per sample: {}", bits)),
+    };
+    let sample_count = read_u64(reader)?;
+    let block_size = read_u32(reader)? as usize;
+    if channels == 0 || block_size == 0 {
+        return Err(anyhow!("DSF file without channels or blocks"));
+    }
+    reader.seek(SeekFrom::Start(fmt_start + fmt_size))?;
+    expect_chunk(reader, b"data")?;
+    Ok(DsfHeader {
+        channels,
+        dsd_rate,
+        sample_count,
+        block_size,
+        lsb_first,
+        data_offset: reader.stream_position()?,
+    })
+}
+
+pub(crate) fn read_dsf_file_header(path: &Path) -> Result<DsfHeader> {
+    let mut reader = BufReader::new(File::open(path).context("open DSF file")?);
+    read_dsf_header(&mut reader)
+}
+
+/// Per-byte partial sums of the decimation filter: `[g][byte]` is what a
+/// byte `g` places from the oldest in the window adds, taking its bits
+/// earliest first from the top.
+fn filter_tables() -> Vec<[f32; 256]> {
+    let taps = FILTER_BYTES * 8;
+    let span = (taps - 1) as f64;
+    let fc = FILTER_CUTOFF_HZ / DSD64_RATE as f64;
+    let mut coefficients: Vec<f64> = (0..taps)
+        .map(|n| {
+            let x = 2.0 * fc * (n as f64 - span / 2.0);
+            let sinc = if x == 0.0 {
+                1.0
+            } else {
+                (std::f64::consts::PI * x).sin() / (std::f64::consts::PI * x)
+            };
+            let phase = 2.0 * std::f64::consts::PI * n as f64 / span;
+            sinc * (0.42 - 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos())
+        })
+        .collect();
+    let total: f64 = coefficients.iter().sum();
+    coefficients.iter_mut().for_each(|c| *c /= total);
+    coefficients
+        .chunks(8)
+        .map(|group| {
+            let mut table = [0.0f32; 256];
+            for (byte, entry) in table.iter_mut().enumerate() {
+                let sum: f64 = group
+                    .iter()
+                    .enumerate()
+                    .map(|(bit, c)| if byte & (0x80 >> bit) != 0 { *c } else { -*c })
+                    .sum();
+                *entry = sum as f32;
+            }
+            table
+        })
+        .collect()
+}
+
+/// One channel's window onto the bitstream, kept twice over so the last
+/// `FILTER_BYTES` are always one contiguous slice.
+struct FilterWindow {
+    bytes: [u8; FILTER_BYTES * 2],
+    next: usize,
+}
+
+impl FilterWindow {
+    fn new() -> Self {
+        FilterWindow {
+            bytes: [DSD_SILENCE; FILTER_BYTES * 2],
+            next: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.bytes[self.next] = byte;
+        self.bytes[self.next + FILTER_BYTES] = byte;
+        self.next = (self.next + 1) % FILTER_BYTES;
+    }
+
+    fn filter(&self, tables: &[[f32; 256]]) -> f32 {
+        let window = &self.bytes[self.next..self.next + FILTER_BYTES];
+        window.iter().zip(tables).map(|(byte, table)| table[*byte as usize]).sum()
+    }
+}
+
+/// Samples `decode_dsf` produced.
+pub(crate) struct DsdDecoded {
+    /// Interleaved; DoP words as full-scale floats, exact to 24 bits.
+    pub samples: Vec<f32>,
+    pub sample_rate: u32,
+    pub channels: usize,
+    /// The whole file's length, even when decoding stopped short of it.
+    pub duration: f64,
+}
+
+/// A DoP word as the float whose 24-bit conversion gives it back.
+fn dop_sample(marker: u8, first: u8, second: u8) -> f32 {
+    let word = i32::from_be_bytes([marker, first, second, 0]) >> 8;
+    word as f32 / (1 << 23) as f32
+}
+
+/// Decode a stereo DSD64 .dsf file as `output` asks, or its first
+/// `max_seconds`, calling `progress` after each block with the fraction
+/// done; `false` abandons it.
+pub(crate) fn decode_dsf(
+    path: &Path,
+    output: DsdOutput,
+    max_seconds: Option<f64>,
+    progress: &mut dyn FnMut(Option<f64>) -> bool,
+) -> Result<DsdDecoded> {
+    let mut reader = BufReader::new(File::open(path).context("open DSF file")?);
+    let header = read_dsf_header(&mut reader)?;
+    header.check_playable()?;
+    reader.seek(SeekFrom::Start(header.data_offset))?;
+    let channels = header.channels;
+    let (sample_rate, bytes_per_frame) = match output {
+        DsdOutput::Dop => (DOP_RATE, 2),
+        DsdOutput::Pcm(rate) if DSD_PCM_RATES.contains(&rate) => (rate, (DSD64_RATE / rate / 8) as usize),
+        DsdOutput::Pcm(rate) => return Err(anyhow!("unsupported DSD PCM rate: {}", rate)),
+    };
+    let mut total_bytes = (header.sample_count / 8) as usize;
+    if let Some(seconds) = max_seconds {
+        total_bytes = total_bytes.min((seconds * header.dsd_rate as f64 / 8.0).ceil() as usize);
+    }
+    let frames = total_bytes / bytes_per_frame;
+    let mut samples = Vec::with_capacity(frames * channels);
+    let tables = filter_tables();
+    let mut windows: Vec<FilterWindow> = (0..channels).map(|_| FilterWindow::new()).collect();
+    let mut block = vec![0u8; header.block_size * channels];
+    let mut frame = 0usize;
+    // Bytes of the current frame per channel, carried across blocks.
+    let mut pending: Vec<Vec<u8>> = vec![Vec::with_capacity(bytes_per_frame); channels];
+    let mut done = 0usize;
+    while done < total_bytes {
+        reader.read_exact(&mut block).context("read DSF block")?;
+        let take = header.block_size.min(total_bytes - done);
+        for index in 0..take {
+            for channel in 0..channels {
+                let mut byte = block[channel * header.block_size + index];
+                if header.lsb_first {
+                    byte = byte.reverse_bits();
+                }
+                pending[channel].push(byte);
+            }
+            if pending[0].len() < bytes_per_frame {
+                continue;
+            }
+            for (channel, bytes) in pending.iter_mut().enumerate() {
+                match output {
+                    DsdOutput::Dop => {
+                        samples.push(dop_sample(DOP_MARKERS[frame % 2], bytes[0], bytes[1]));
+                    }
+                    DsdOutput::Pcm(_) => {
+                        let window = &mut windows[channel];
+                        bytes.iter().for_each(|byte| window.push(*byte));
+                        samples.push(window.filter(&tables));
+                    }
+                }
+                bytes.clear();
+            }
+            frame += 1;
+        }
+        done += take;
+        if !progress(Some(done as f64 / total_bytes as f64)) {
+            return Err(anyhow!("decode cancelled"));
+        }
+    }
+    Ok(DsdDecoded {
+        samples,
+        sample_rate,
+        channels,
+        duration: header.duration(),
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A stereo DSD64 .dsf whose channels repeat `left` and `right`, with
+    /// `bytes` per channel in blocks of `block_size`.
+    pub(crate) fn dsf_bytes(left: u8, right: u8, bytes: usize, block_size: usize) -> Vec<u8> {
+        let blocks = bytes.div_ceil(block_size);
+        let data_len = blocks * block_size * 2;
+        let mut out = Vec::new();
+        out.extend_from_slice(b"DSD ");
+        out.extend_from_slice(&28u64.to_le_bytes());
+        out.extend_from_slice(&((28 + 52 + 12 + data_len) as u64).to_le_bytes());
+        out.extend_from_slice(&0u64.to_le_bytes());
+        out.extend_from_slice(b"fmt ");
+        out.extend_from_slice(&52u64.to_le_bytes());
+        for value in [1u32, 0, 2, 2, DSD64_RATE, 1] {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        out.extend_from_slice(&((bytes * 8) as u64).to_le_bytes());
+        out.extend_from_slice(&(block_size as u32).to_le_bytes());
+        out.extend_from_slice(&0u32.to_le_bytes());
+        out.extend_from_slice(b"data");
+        out.extend_from_slice(&((12 + data_len) as u64).to_le_bytes());
+        for block in 0..blocks {
+            for value in [left, right] {
+                let used = (bytes - block * block_size).min(block_size);
+                out.extend(std::iter::repeat_n(value, used));
+                out.extend(std::iter::repeat_n(0u8, block_size - used));
+            }
+        }
+        out
+    }
+
+    fn write_dsf(name: &str, left: u8, right: u8, bytes: usize) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("ntmusic_{}_{}.dsf", name, std::process::id()));
+        std::fs::write(&path, dsf_bytes(left, right, bytes, 4096)).unwrap();
+        path
+    }
+
+    #[test]
+    fn header_gives_format_and_length() {
+        let path = write_dsf("dsd_header", 0xFF, 0x00, 6000);
+        let header = read_dsf_file_header(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!((header.channels, header.dsd_rate, header.sample_count), (2, DSD64_RATE, 48_000));
+        assert!(header.lsb_first);
+        assert_eq!(header.data_offset, 28 + 52 + 12);
+        assert!((header.duration() - 48_000.0 / DSD64_RATE as f64).abs() < 1e-12);
+        assert!(header.check_playable().is_ok());
+        let mono = DsfHeader { channels: 1, ..header };
+        assert!(mono.check_playable().is_err());
+        assert!(read_dsf_header(&mut std::io::Cursor::new(b"RIFF0000".to_vec())).is_err());
+    }
+
+    #[test]
+    fn pcm_conversion_settles_at_the_bitstream_mean() {
+        // All ones on the left, all zeros on the right, across two blocks.
+        let path = write_dsf("dsd_pcm", 0xFF, 0x00, 6000);
+        let pcm = decode_dsf(&path, DsdOutput::Pcm(176_400), None, &mut |_| true).unwrap();
+        let fast = decode_dsf(&path, DsdOutput::Pcm(352_800), None, &mut |_| true).unwrap();
+        assert!(decode_dsf(&path, DsdOutput::Pcm(96_000), None, &mut |_| true).is_err());
+        assert!(decode_dsf(&path, DsdOutput::Dop, None, &mut |_| false).is_err());
+        let _ = std::fs::remove_file(&path);
+        assert_eq!((pcm.sample_rate, pcm.channels, pcm.samples.len()), (176_400, 2, 3000 * 2));
+        assert_eq!(fast.samples.len(), 6000 * 2);
+        // Past the filter's length the output is flat at full scale.
+        let settled = &pcm.samples[100..];
+        assert!(settled.chunks(2).all(|frame| (frame[0] - 1.0).abs() < 1e-4 && (frame[1] + 1.0).abs() < 1e-4));
+        // It starts from the silence pattern, at zero.
+        assert!(pcm.samples[0].abs() < 0.6);
+    }
+
+    #[test]
+    fn dop_words_carry_the_bits_under_alternating_markers() {
+        // 0x96 stored LSB first is 0x69 in time order.
+        let path = write_dsf("dsd_dop", 0x96, 0x0F, 4);
+        let dop = decode_dsf(&path, DsdOutput::Dop, None, &mut |_| true).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!((dop.sample_rate, dop.samples.len()), (DOP_RATE, 4));
+        let words: Vec<u32> = dop
+            .samples
+            .iter()
+            .map(|sample| ((*sample * (1 << 23) as f32) as i32 as u32) & 0xFF_FFFF)
+            .collect();
+        assert_eq!(words, vec![0x05_6969, 0x05_F0F0, 0xFA_6969, 0xFA_F0F0]);
+    }
+}
@@ -0,0 +1,821 @@
+//! Ten-band graphic EQ, in two flavours.
+//!
+//! IIR: one peaking biquad per octave band, RBJ cookbook coefficients, run
+//! in f64 so the 31 Hz band stays stable at high rates. Cheap, but the
+//! phase shifts around each boosted or cut band.
+//!
+//! FIR: a linear-phase kernel sampled from the band gains (interpolated
+//! over log frequency), windowed with a Blackman window, and run as a
+//! uniformly partitioned FFT convolution in `FIR_BLOCK`-frame blocks. Phase
+//! stays coherent across the spectrum at the cost of `(taps - 1) / 2 +
+//! FIR_BLOCK` frames of latency; short kernels can't resolve the lowest
+//! bands. Kernels are designed ahead of time for `FIR_PREPARED_RATES`, so
+//! the audio callback only ever picks one, fading over to it in one block
+//! with the input history kept.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
+
+/// Band labels as `/set_eq` names them, with their centre frequencies.
+pub(crate) const EQ_BANDS: [(&str, f64); 10] = [
+    ("31", 31.25),
+    ("62", 62.5),
+    ("125", 125.0),
+    ("250", 250.0),
+    ("500", 500.0),
+    ("1k", 1_000.0),
+    ("2k", 2_000.0),
+    ("4k", 4_000.0),
+    ("8k", 8_000.0),
+    ("16k", 16_000.0),
+];
+pub(crate) const EQ_MAX_GAIN_DB: f32 = 24.0;
+
+pub(crate) const DEFAULT_FIR_TAPS: usize = 1023;
+pub(crate) const MIN_FIR_TAPS: usize = 63;
+pub(crate) const MAX_FIR_TAPS: usize = 4095;
+/// The designed response is sampled at least this finely.
+const FIR_DESIGN_MIN_FFT: usize = 8192;
+/// Frames per FIR convolution block, each run through FFTs twice its size.
+/// Output lags input by one block on top of the kernel's own delay.
+pub(crate) const FIR_BLOCK: usize = 256;
+/// Kernels the callback can swap out between two `configure` or
+/// `add_design` calls before it has to drop one itself.
+const FIR_RETIRED_SLOTS: usize = 8;
+/// Output rates whose FIR kernels are designed before they are needed.
+pub(crate) const FIR_PREPARED_RATES: [u32; 8] = [
+    44_100, 48_000, 88_200, 96_000, 176_400, 192_000, 352_800, 384_000,
+];
+
+/// One octave between the -3 dB points.
+const BAND_Q: f64 = std::f64::consts::SQRT_2;
+/// Bands this close to Nyquist can't be realised and are left flat.
+const MAX_CENTRE_RATIO: f64 = 0.45;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Biquad {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+}
+
+const IDENTITY: Biquad = Biquad {
+    b0: 1.0,
+    b1: 0.0,
+    b2: 0.0,
+    a1: 0.0,
+    a2: 0.0,
+};
+
+impl Biquad {
+    fn peaking(freq: f64, gain_db: f32, sample_rate: u32) -> Biquad {
+        let gain_db = gain_db.clamp(-EQ_MAX_GAIN_DB, EQ_MAX_GAIN_DB);
+        if gain_db == 0.0 || freq >= sample_rate as f64 * MAX_CENTRE_RATIO {
+            return IDENTITY;
+        }
+        let a = 10f64.powf(gain_db as f64 / 40.0);
+        let w0 = 2.0 * std::f64::consts::PI * freq / sample_rate as f64;
+        let alpha = w0.sin() / (2.0 * BAND_Q);
+        let cos = w0.cos();
+        let a0 = 1.0 + alpha / a;
+        Biquad {
+            b0: (1.0 + alpha * a) / a0,
+            b1: -2.0 * cos / a0,
+            b2: (1.0 - alpha * a) / a0,
+            a1: -2.0 * cos / a0,
+            a2: (1.0 - alpha / a) / a0,
+        }
+    }
+}
+
+/// Odd, so the kernel has a centre tap, and within the supported range.
+pub(crate) fn normalize_fir_taps(taps: usize) -> usize {
+    taps.clamp(MIN_FIR_TAPS, MAX_FIR_TAPS) | 1
+}
+
+/// Gain at `freq`, linear in dB between band centres on a log-frequency
+/// axis and held beyond the outermost bands.
+fn interpolated_gain_db(gains: &[f64; EQ_BANDS.len()], freq: f64) -> f64 {
+    let (first, last) = (EQ_BANDS[0].1, EQ_BANDS[EQ_BANDS.len() - 1].1);
+    if freq <= first {
+        return gains[0];
+    }
+    if freq >= last {
+        return gains[gains.len() - 1];
+    }
+    let i = EQ_BANDS.iter().rposition(|(_, centre)| *centre <= freq).unwrap_or(0);
+    let t = (freq / EQ_BANDS[i].1).log2() / (EQ_BANDS[i + 1].1 / EQ_BANDS[i].1).log2();
+    gains[i] + (gains[i + 1] - gains[i]) * t
+}
+
+/// Zero-phase response from the band gains, shifted to the kernel's centre
+/// and windowed. The result is symmetric, hence linear-phase.
+fn fir_kernel(gains: &[f64; EQ_BANDS.len()], sample_rate: u32, taps: usize) -> Vec<f32> {
+    let n = (taps * 4).max(FIR_DESIGN_MIN_FFT).next_power_of_two();
+    let mut spectrum: Vec<Complex<f64>> = (0..n)
+        .map(|k| {
+            let bin = k.min(n - k);
+            let freq = bin as f64 * sample_rate as f64 / n as f64;
+            Complex::new(10f64.powf(interpolated_gain_db(gains, freq) / 20.0), 0.0)
+        })
+        .collect();
+    FftPlanner::new().plan_fft_inverse(n).process(&mut spectrum);
+    let half = taps / 2;
+    (0..taps)
+        .map(|j| {
+            let phase = 2.0 * std::f64::consts::PI * j as f64 / (taps - 1) as f64;
+            let window = 0.42 - 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos();
+            let index = (j + n - half) % n;
+            (spectrum[index].re / n as f64 * window) as f32
+        })
+        .collect()
+}
+
+/// A FIR kernel for one rate, cut into `FIR_BLOCK`-tap partitions and
+/// transformed, with the FFT plans that run it.
+pub(crate) struct FirDesign {
+    sample_rate: u32,
+    taps: usize,
+    gains: [f64; EQ_BANDS.len()],
+    /// Spectra of the zero-padded partitions, scaled by the inverse FFT's
+    /// missing `1 / (2 * FIR_BLOCK)`.
+    partitions: Vec<Vec<Complex<f32>>>,
+    forward: Arc<dyn Fft<f32>>,
+    inverse: Arc<dyn Fft<f32>>,
+}
+
+impl std::fmt::Debug for FirDesign {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FirDesign")
+            .field("sample_rate", &self.sample_rate)
+            .field("taps", &self.taps)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FirDesign {
+    fn new(gains: [f64; EQ_BANDS.len()], sample_rate: u32, taps: usize) -> Self {
+        let kernel = fir_kernel(&gains, sample_rate, taps);
+        let mut planner = FftPlanner::new();
+        let forward = planner.plan_fft_forward(2 * FIR_BLOCK);
+        let inverse = planner.plan_fft_inverse(2 * FIR_BLOCK);
+        let scale = 1.0 / (2 * FIR_BLOCK) as f32;
+        let partitions = kernel
+            .chunks(FIR_BLOCK)
+            .map(|part| {
+                let mut spectrum = vec![Complex::default(); 2 * FIR_BLOCK];
+                for (bin, tap) in spectrum.iter_mut().zip(part) {
+                    bin.re = tap * scale;
+                }
+                forward.process(&mut spectrum);
+                spectrum
+            })
+            .collect();
+        FirDesign {
+            sample_rate,
+            taps,
+            gains,
+            partitions,
+            forward,
+            inverse,
+        }
+    }
+
+    fn scratch_len(&self) -> usize {
+        self.forward
+            .get_inplace_scratch_len()
+            .max(self.inverse.get_inplace_scratch_len())
+    }
+}
+
+/// What `FirRequest::design` needs to design a kernel off the audio path.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct FirRequest {
+    gains: [f64; EQ_BANDS.len()],
+    sample_rate: u32,
+    taps: usize,
+}
+
+impl FirRequest {
+    pub(crate) fn design(&self) -> Arc<FirDesign> {
+        Arc::new(FirDesign::new(self.gains, self.sample_rate, self.taps))
+    }
+}
+
+/// Convolution state for two channels, carried as one complex signal: the
+/// kernel is real, so the real and imaginary parts never mix.
+#[derive(Debug, Clone)]
+struct FirPair {
+    /// The previous block's input, then the current one as it fills.
+    input: Vec<Complex<f32>>,
+    /// Spectra of the latest input blocks, newest at `head`, one per
+    /// kernel partition.
+    spectra: Vec<Vec<Complex<f32>>>,
+    head: usize,
+    /// The last finished block, played out while the next one fills.
+    output: Vec<Complex<f32>>,
+}
+
+impl FirPair {
+    fn new(partitions: usize) -> Self {
+        FirPair {
+            input: vec![Complex::default(); 2 * FIR_BLOCK],
+            spectra: vec![vec![Complex::default(); 2 * FIR_BLOCK]; partitions],
+            head: 0,
+            output: vec![Complex::default(); FIR_BLOCK],
+        }
+    }
+
+    fn clear(&mut self) {
+        self.input.fill(Complex::default());
+        for spectrum in &mut self.spectra {
+            spectrum.fill(Complex::default());
+        }
+        self.output.fill(Complex::default());
+    }
+
+    /// Overlap-save one full block through every partition into `output`,
+    /// fading in from `previous`'s output across the block when given.
+    fn run_block(&mut self, design: &FirDesign, previous: Option<&FirDesign>, buffers: &mut FirBuffers) {
+        let count = self.spectra.len();
+        self.head = (self.head + 1) % count;
+        let newest = &mut self.spectra[self.head];
+        newest.copy_from_slice(&self.input);
+        design.forward.process_with_scratch(newest, &mut buffers.scratch);
+        self.convolve(design, &mut buffers.sum, &mut buffers.scratch);
+        // The first half wrapped around; the second is this block's output.
+        self.output.copy_from_slice(&buffers.sum[FIR_BLOCK..]);
+        if let Some(previous) = previous {
+            self.convolve(previous, &mut buffers.sum, &mut buffers.scratch);
+            for (i, (out, old)) in self.output.iter_mut().zip(&buffers.sum[FIR_BLOCK..]).enumerate() {
+                let t = (i + 1) as f32 / FIR_BLOCK as f32;
+                *out = old * (1.0 - t) + *out * t;
+            }
+        }
+        self.input.copy_within(FIR_BLOCK.., 0);
+    }
+
+    fn convolve(&self, design: &FirDesign, sum: &mut [Complex<f32>], scratch: &mut [Complex<f32>]) {
+        let count = self.spectra.len();
+        sum.fill(Complex::default());
+        for (age, partition) in design.partitions.iter().enumerate() {
+            let spectrum = &self.spectra[(self.head + count - age) % count];
+            for ((acc, x), h) in sum.iter_mut().zip(spectrum).zip(partition) {
+                *acc += x * h;
+            }
+        }
+        design.inverse.process_with_scratch(sum, scratch);
+    }
+}
+
+/// Work space shared by every pair's block.
+#[derive(Debug, Clone, Default)]
+struct FirBuffers {
+    scratch: Vec<Complex<f32>>,
+    sum: Vec<Complex<f32>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum EqKind {
+    #[default]
+    Iir,
+    Fir,
+}
+
+impl EqKind {
+    pub(crate) fn from_label(label: &str) -> Option<Self> {
+        match label.to_ascii_uppercase().as_str() {
+            "IIR" => Some(EqKind::Iir),
+            "FIR" => Some(EqKind::Fir),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            EqKind::Iir => "IIR",
+            EqKind::Fir => "FIR",
+        }
+    }
+}
+
+/// Filters for the current gains and sample rate, plus per-channel
+/// history. Gains are only changed by [`EqFilters::configure`]; IIR
+/// history survives it so a gain change mid-track doesn't click. A new
+/// rate is taken up by [`EqFilters::follow_rate`], which never designs a
+/// FIR kernel itself.
+#[derive(Debug, Clone)]
+pub(crate) struct EqFilters {
+    kind: EqKind,
+    fir_taps: usize,
+    sample_rate: u32,
+    gains: [f64; EQ_BANDS.len()],
+    flat: bool,
+    bands: Vec<Biquad>,
+    /// `[x1, x2, y1, y2]` per channel and band, channel-major.
+    history: Vec<[f64; 4]>,
+    /// The kernel in use; `None` passes audio through until the one for
+    /// `sample_rate` is designed.
+    fir: Option<Arc<FirDesign>>,
+    /// Kernels designed for the current gains, by rate.
+    fir_designs: Vec<Arc<FirDesign>>,
+    /// The kernel `fir` replaced, faded out over the next block so a new
+    /// kernel takes over without a click. Only dropped off the callback.
+    fir_previous: Option<Arc<FirDesign>>,
+    fir_fading: bool,
+    /// Kernels swapped out by the callback, waiting to be freed.
+    fir_retired: Vec<Arc<FirDesign>>,
+    /// Channels `fir_pairs` is sized for.
+    fir_channels: usize,
+    fir_pairs: Vec<FirPair>,
+    /// Frames of the current block taken in so far.
+    fir_fill: usize,
+    fir_buffers: FirBuffers,
+}
+
+impl Default for EqFilters {
+    fn default() -> Self {
+        EqFilters {
+            kind: EqKind::Iir,
+            fir_taps: DEFAULT_FIR_TAPS,
+            sample_rate: 0,
+            gains: [0.0; EQ_BANDS.len()],
+            flat: true,
+            bands: Vec::new(),
+            history: Vec::new(),
+            fir: None,
+            fir_designs: Vec::new(),
+            fir_previous: None,
+            fir_fading: false,
+            fir_retired: Vec::new(),
+            fir_channels: 2,
+            fir_pairs: Vec::new(),
+            fir_fill: 0,
+            fir_buffers: FirBuffers::default(),
+        }
+    }
+}
+
+impl EqFilters {
+    /// Switch implementation; takes effect at the next `configure`.
+    pub(crate) fn set_kind(&mut self, kind: EqKind, fir_taps: usize) {
+        self.kind = kind;
+        self.fir_taps = normalize_fir_taps(fir_taps);
+        self.fir = None;
+        self.fir_previous = None;
+        self.fir_designs.clear();
+        self.fir_pairs.clear();
+        self.reset();
+    }
+
+    pub(crate) fn kind(&self) -> EqKind {
+        self.kind
+    }
+
+    pub(crate) fn fir_taps(&self) -> usize {
+        self.fir_taps
+    }
+
+    /// Take up new gains at `sample_rate`. FIR kernels aren't designed
+    /// here: the previous kernel keeps playing until the ones listed by
+    /// [`EqFilters::missing_design`] are handed to [`EqFilters::add_design`].
+    pub(crate) fn configure(&mut self, gains: &HashMap<String, f32>, sample_rate: u32) {
+        let mut band_gains = [0.0f64; EQ_BANDS.len()];
+        for (gain, (label, _)) in band_gains.iter_mut().zip(EQ_BANDS) {
+            *gain = gains
+                .get(label)
+                .copied()
+                .unwrap_or(0.0)
+                .clamp(-EQ_MAX_GAIN_DB, EQ_MAX_GAIN_DB) as f64;
+        }
+        if band_gains != self.gains {
+            self.fir_designs.clear();
+        }
+        self.gains = band_gains;
+        self.release_retired();
+        self.follow_rate(sample_rate);
+    }
+
+    /// Design the kernel for the current rate in place, for callers that
+    /// own their filters and can't hear the wait.
+    pub(crate) fn design_current_rate(&mut self) {
+        if let Some(request) = self.missing_design().filter(|request| request.sample_rate == self.sample_rate) {
+            self.add_design(request.design());
+        }
+    }
+
+    /// Switch to `sample_rate` with the current gains: biquads are
+    /// recomputed, a FIR kernel is only picked from those already designed,
+    /// falling back to the previous gains' kernel at the same rate and
+    /// else to passing audio through. Cheap enough for the audio callback
+    /// once `configure` has run: it neither allocates nor frees there.
+    pub(crate) fn follow_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        match self.kind {
+            EqKind::Iir => {
+                self.bands.resize(EQ_BANDS.len(), IDENTITY);
+                for (band, ((_, freq), gain)) in self.bands.iter_mut().zip(EQ_BANDS.iter().zip(self.gains)) {
+                    *band = Biquad::peaking(*freq, gain as f32, sample_rate);
+                }
+                self.flat = self.bands.iter().all(|band| *band == IDENTITY);
+            }
+            EqKind::Fir => {
+                let was_active = self.fir.is_some() && !self.flat;
+                self.flat = self.gains.iter().all(|gain| *gain == 0.0);
+                let request = self.fir_request(sample_rate);
+                let design = self
+                    .fir_designs
+                    .iter()
+                    .find(|design| Self::designed_for(design, &request))
+                    .or_else(|| {
+                        self.fir
+                            .as_ref()
+                            .filter(|design| design.sample_rate == sample_rate && design.taps == self.fir_taps)
+                    })
+                    .cloned();
+                let changed = match (&self.fir, &design) {
+                    (Some(current), Some(design)) => !Arc::ptr_eq(current, design),
+                    (current, design) => current.is_some() != design.is_some(),
+                };
+                if !changed {
+                    return;
+                }
+                let previous = std::mem::replace(&mut self.fir, design);
+                let fits = self.fir.as_ref().is_some_and(|design| self.fits_buffers(design));
+                if was_active && !self.flat && fits {
+                    // The input history carries over; only the kernel's
+                    // output is swapped, over one block.
+                    if let Some(previous) = previous {
+                        self.fir_fading = self.fits_buffers(&previous);
+                        if let Some(retired) = self.fir_previous.replace(previous) {
+                            self.retire(retired);
+                        }
+                    }
+                } else {
+                    if let Some(previous) = previous {
+                        self.retire(previous);
+                    }
+                    // Nothing was filtering, so the history is stale.
+                    self.fir_fading = false;
+                    for pair in &mut self.fir_pairs {
+                        pair.clear();
+                    }
+                    self.fir_fill = 0;
+                }
+            }
+        }
+    }
+
+    /// Park a kernel the callback stopped using, so the last reference isn't
+    /// dropped there; `release_retired` frees them off the callback.
+    fn retire(&mut self, design: Arc<FirDesign>) {
+        if self.fir_retired.len() < self.fir_retired.capacity() {
+            self.fir_retired.push(design);
+        }
+    }
+
+    fn release_retired(&mut self) {
+        self.fir_retired.clear();
+        self.fir_retired.reserve(FIR_RETIRED_SLOTS);
+    }
+
+    /// Whether `fir_pairs` and `fir_buffers` can run `design` as they are.
+    fn fits_buffers(&self, design: &FirDesign) -> bool {
+        self.fir_pairs.len() == self.fir_channels.div_ceil(2)
+            && self.fir_pairs.iter().all(|pair| pair.spectra.len() == design.partitions.len())
+            && self.fir_buffers.scratch.len() >= design.scratch_len()
+            && self.fir_buffers.sum.len() == 2 * FIR_BLOCK
+    }
+
+    /// Size the convolution state for `design` and `channels`, keeping the
+    /// history when it already fits.
+    fn size_buffers(&mut self, design: &FirDesign, channels: usize) {
+        self.fir_channels = channels;
+        if self.fits_buffers(design) {
+            return;
+        }
+        self.fir_pairs = vec![FirPair::new(design.partitions.len()); channels.div_ceil(2)];
+        self.fir_fill = 0;
+        self.fir_buffers = FirBuffers {
+            scratch: vec![Complex::default(); design.scratch_len()],
+            sum: vec![Complex::default(); 2 * FIR_BLOCK],
+        };
+        self.fir_previous = None;
+        self.fir_fading = false;
+    }
+
+    fn fir_request(&self, sample_rate: u32) -> FirRequest {
+        FirRequest {
+            gains: self.gains,
+            sample_rate,
+            taps: self.fir_taps,
+        }
+    }
+
+    fn designed_for(design: &FirDesign, request: &FirRequest) -> bool {
+        design.sample_rate == request.sample_rate && design.taps == request.taps && design.gains == request.gains
+    }
+
+    fn has_design(&self, request: &FirRequest) -> bool {
+        self.fir_designs.iter().any(|design| Self::designed_for(design, request))
+    }
+
+    /// The next FIR kernel worth designing: the current rate's while it has
+    /// none, then each of `FIR_PREPARED_RATES`. `None` for the IIR EQ, flat
+    /// gains, or once all are there.
+    pub(crate) fn missing_design(&self) -> Option<FirRequest> {
+        if self.kind != EqKind::Fir || self.gains.iter().all(|gain| *gain == 0.0) {
+            return None;
+        }
+        std::iter::once(self.sample_rate)
+            .filter(|rate| *rate > 0)
+            .chain(FIR_PREPARED_RATES)
+            .map(|rate| self.fir_request(rate))
+            .find(|request| !self.has_design(request))
+    }
+
+    /// Keep a kernel from [`FirRequest::design`], unless the gains or taps
+    /// changed while it was being designed, and start using it if it is for
+    /// the current rate.
+    pub(crate) fn add_design(&mut self, design: Arc<FirDesign>) {
+        if self.kind != EqKind::Fir || !Self::designed_for(&design, &self.fir_request(design.sample_rate)) {
+            return;
+        }
+        if self.has_design(&self.fir_request(design.sample_rate)) {
+            return;
+        }
+        // Sized here, off the callback, so taking the kernel up doesn't
+        // allocate there.
+        self.size_buffers(&design, self.fir_channels);
+        if !self.fir_fading {
+            self.fir_previous = None;
+        }
+        self.release_retired();
+        self.fir_designs.push(design);
+        self.follow_rate(self.sample_rate);
+    }
+
+    /// Frames the EQ delays its output by: the FIR kernel's group delay plus
+    /// one block. The IIR EQ has no fixed delay and counts as none.
+    pub(crate) fn latency_frames(&self) -> usize {
+        match &self.fir {
+            Some(design) if self.kind == EqKind::Fir && !self.flat => (design.taps - 1) / 2 + FIR_BLOCK,
+            _ => 0,
+        }
+    }
+
+    pub(crate) fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// True when every band is flat and processing would be a no-op.
+    pub(crate) fn is_flat(&self) -> bool {
+        self.flat
+    }
+
+    /// Forget the signal history, for discontinuities like loads and seeks
+    /// where ringing from the old audio would otherwise carry over.
+    pub(crate) fn reset(&mut self) {
+        self.history.fill([0.0; 4]);
+        for pair in &mut self.fir_pairs {
+            pair.clear();
+        }
+        self.fir_fill = 0;
+        self.fir_fading = false;
+    }
+
+    pub(crate) fn process(&mut self, data: &mut [f32], channels: usize) {
+        match self.kind {
+            EqKind::Iir => self.process_iir(data, channels),
+            EqKind::Fir => self.process_fir(data, channels),
+        }
+    }
+
+    fn process_fir(&mut self, data: &mut [f32], channels: usize) {
+        let channels = channels.max(1);
+        let Some(design) = self.fir.clone() else {
+            return;
+        };
+        if channels != self.fir_channels {
+            // Only a new stream layout gets here; `add_design` sizes for
+            // the layout last seen.
+            self.size_buffers(&design, channels);
+        }
+        for frame in data.chunks_mut(channels) {
+            let fill = self.fir_fill;
+            for (pair, samples) in self.fir_pairs.iter_mut().zip(frame.chunks_mut(2)) {
+                let right = samples.get(1).copied().unwrap_or(0.0);
+                pair.input[FIR_BLOCK + fill] = Complex::new(samples[0], right);
+                let out = pair.output[fill];
+                samples[0] = out.re;
+                if let Some(right) = samples.get_mut(1) {
+                    *right = out.im;
+                }
+            }
+            self.fir_fill += 1;
+            if self.fir_fill == FIR_BLOCK {
+                self.fir_fill = 0;
+                let previous = self.fir_previous.as_deref().filter(|_| self.fir_fading);
+                for pair in &mut self.fir_pairs {
+                    pair.run_block(&design, previous, &mut self.fir_buffers);
+                }
+                self.fir_fading = false;
+            }
+        }
+    }
+
+    fn process_iir(&mut self, data: &mut [f32], channels: usize) {
+        let channels = channels.max(1);
+        let bands = self.bands.len();
+        if self.history.len() != channels * bands {
+            self.history = vec![[0.0; 4]; channels * bands];
+        }
+        for frame in data.chunks_mut(channels) {
+            for (ch, sample) in frame.iter_mut().enumerate() {
+                let mut x = *sample as f64;
+                let history = &mut self.history[ch * bands..(ch + 1) * bands];
+                for (band, h) in self.bands.iter().zip(history.iter_mut()) {
+                    let y = band.b0 * x + band.b1 * h[0] + band.b2 * h[1] - band.a1 * h[2] - band.a2 * h[3];
+                    *h = [x, h[0], y, h[2]];
+                    x = y;
+                }
+                *sample = x as f32;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine_gain_db(filters: &mut EqFilters, freq: f64, sample_rate: u32) -> f64 {
+        let n = sample_rate as usize;
+        let mut data: Vec<f32> = (0..n)
+            .map(|i| (2.0 * std::f64::consts::PI * freq * i as f64 / sample_rate as f64).sin() as f32 * 0.1)
+            .collect();
+        filters.reset();
+        filters.process(&mut data, 1);
+        // Skip the filter's settling time.
+        let tail = &data[n / 2..];
+        let rms = (tail.iter().map(|s| (*s as f64).powi(2)).sum::<f64>() / tail.len() as f64).sqrt();
+        20.0 * (rms / (0.1 / std::f64::consts::SQRT_2)).log10()
+    }
+
+    #[test]
+    fn boosted_band_peaks_at_its_centre() {
+        let mut gains: HashMap<String, f32> = EQ_BANDS.iter().map(|(l, _)| (l.to_string(), 0.0)).collect();
+        let mut filters = EqFilters::default();
+        filters.configure(&gains, 48_000);
+        assert!(filters.is_flat());
+
+        gains.insert("1k".to_string(), 6.0);
+        filters.configure(&gains, 48_000);
+        assert!(!filters.is_flat());
+        assert!((sine_gain_db(&mut filters, 1_000.0, 48_000) - 6.0).abs() < 0.2);
+        assert!(sine_gain_db(&mut filters, 62.5, 48_000).abs() < 0.2);
+        assert!(sine_gain_db(&mut filters, 16_000.0, 48_000).abs() < 0.2);
+    }
+
+    #[test]
+    fn fir_impulse_response_is_symmetric_and_iir_is_not() {
+        let gains = HashMap::from([("125".to_string(), 9.0), ("4k".to_string(), -6.0)]);
+        let taps = 255;
+        let impulse = |kind| {
+            let mut filters = EqFilters::default();
+            filters.set_kind(kind, taps);
+            filters.configure(&gains, 48_000);
+            filters.design_current_rate();
+            let mut data = vec![0.0f32; taps + FIR_BLOCK];
+            data[0] = 1.0;
+            filters.process(&mut data, 1);
+            (data, filters.latency_frames())
+        };
+        let (fir, latency) = impulse(EqKind::Fir);
+        assert_eq!(latency, taps / 2 + FIR_BLOCK);
+        let peak = fir.iter().enumerate().max_by(|a, b| a.1.abs().total_cmp(&b.1.abs())).unwrap().0;
+        assert_eq!(peak, latency);
+        for i in 1..=taps / 2 {
+            assert!((fir[latency - i] - fir[latency + i]).abs() < 1e-5, "tap {}", i);
+        }
+        assert!(fir[..latency - taps / 2].iter().all(|s| s.abs() < 1e-6));
+        let (iir, latency) = impulse(EqKind::Iir);
+        assert_eq!(latency, 0);
+        assert!((iir[1] - iir[taps - 2]).abs() > 1e-3);
+
+        // The FIR response matches the requested gains away from the edges.
+        let mut filters = EqFilters::default();
+        filters.set_kind(EqKind::Fir, 2047);
+        filters.configure(&gains, 48_000);
+        filters.design_current_rate();
+        assert!((sine_gain_db(&mut filters, 4_000.0, 48_000) + 6.0).abs() < 0.3);
+        assert_eq!(normalize_fir_taps(100), 101);
+        assert_eq!(EqKind::from_label("fir"), Some(EqKind::Fir));
+    }
+
+    #[test]
+    fn fir_pairs_keep_channels_apart() {
+        let gains = HashMap::from([("1k".to_string(), 6.0)]);
+        let mut filters = EqFilters::default();
+        filters.set_kind(EqKind::Fir, 511);
+        filters.configure(&gains, 48_000);
+        filters.design_current_rate();
+        let frames = 2048;
+        let mut data = vec![0.0f32; frames * 3];
+        data[0] = 1.0;
+        data[2] = -0.5;
+        filters.process(&mut data, 3);
+        let latency = filters.latency_frames();
+        let channel = |ch: usize| -> Vec<f32> { data.iter().skip(ch).step_by(3).copied().collect() };
+        let (left, right, third) = (channel(0), channel(1), channel(2));
+        assert!(right.iter().all(|s| s.abs() < 1e-6));
+        assert!(left[latency] > 1.0);
+        for (l, t) in left.iter().zip(&third) {
+            assert!((l * -0.5 - t).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn rate_changes_only_pick_prepared_fir_kernels() {
+        let gains = HashMap::from([("250".to_string(), -4.0)]);
+        let mut filters = EqFilters::default();
+        filters.set_kind(EqKind::Fir, 1023);
+        filters.configure(&gains, 48_000);
+        assert_eq!(filters.latency_frames(), 0);
+        filters.design_current_rate();
+        assert_eq!(filters.latency_frames(), 511 + FIR_BLOCK);
+
+        // Nothing for 96 kHz yet: pass through and ask for it.
+        filters.follow_rate(96_000);
+        assert_eq!(filters.latency_frames(), 0);
+        let mut data = vec![0.25f32; 4 * FIR_BLOCK];
+        filters.process(&mut data, 2);
+        assert!(data.iter().all(|s| *s == 0.25));
+        let request = filters.missing_design().unwrap();
+        assert_eq!(request.sample_rate, 96_000);
+
+        // A kernel designed for older gains is dropped, though the one in
+        // use keeps playing until its replacement arrives.
+        let stale = request.design();
+        filters.add_design(request.design());
+        filters.configure(&HashMap::from([("250".to_string(), -5.0)]), 96_000);
+        filters.add_design(stale);
+        assert!(filters.fir_designs.is_empty());
+        assert_eq!(filters.fir.as_ref().unwrap().gains[3], -4.0);
+
+        while let Some(request) = filters.missing_design() {
+            let design = request.design();
+            filters.add_design(design);
+        }
+        assert_eq!(filters.fir_designs.len(), FIR_PREPARED_RATES.len());
+        filters.follow_rate(44_100);
+        assert_eq!(filters.latency_frames(), 511 + FIR_BLOCK);
+        assert_eq!(filters.fir.as_ref().unwrap().sample_rate, 44_100);
+    }
+
+    #[test]
+    fn gain_changes_keep_filter_state_and_buffers() {
+        let mut filters = EqFilters::default();
+        filters.set_kind(EqKind::Fir, 511);
+        filters.configure(&HashMap::from([("1k".to_string(), 6.0)]), 48_000);
+        filters.design_current_rate();
+        let latency = filters.latency_frames();
+        let mut data = vec![0.5f32; 8 * FIR_BLOCK * 2];
+        filters.process(&mut data, 2);
+        let spectra = filters.fir_pairs[0].spectra[0].as_ptr();
+
+        // Designed off the callback; the callback only swaps kernels.
+        filters.configure(&HashMap::from([("1k".to_string(), -6.0)]), 48_000);
+        let design = filters.missing_design().unwrap().design();
+        filters.add_design(design);
+        assert_eq!(filters.fir.as_ref().unwrap().gains[5], -6.0);
+        let mut more = vec![0.5f32; 8 * FIR_BLOCK * 2];
+        filters.process(&mut more, 2);
+        assert_eq!(filters.fir_pairs[0].spectra[0].as_ptr(), spectra);
+        // DC passes both kernels at unity: no gap while the new one takes
+        // over, as there would be if its history started empty.
+        assert!(data[2 * (latency + FIR_BLOCK)..].iter().chain(&more).all(|s| (s - 0.5).abs() < 0.02));
+
+        let mut filters = EqFilters::default();
+        filters.configure(&HashMap::from([("1k".to_string(), 6.0)]), 48_000);
+        let bands = filters.bands.as_ptr();
+        filters.follow_rate(44_100);
+        assert_eq!(filters.bands.as_ptr(), bands);
+        assert!(!filters.is_flat());
+    }
+
+    #[test]
+    fn bands_near_nyquist_stay_flat() {
+        let gains = HashMap::from([("16k".to_string(), 12.0)]);
+        let mut filters = EqFilters::default();
+        filters.configure(&gains, 32_000);
+        assert!(filters.is_flat());
+        filters.configure(&gains, 44_100);
+        assert!(!filters.is_flat());
+        assert_eq!(filters.sample_rate(), 44_100);
+    }
+}
@@ -0,0 +1,365 @@
+//! Writes rendered audio to WAV or FLAC files.
+//!
+//! Both writers stream frames to disk and patch the header once the length
+//! is known. FLAC output uses verbatim subframes: the files are as large as
+//! the WAV equivalent but decode in any player, and no encoder dependency
+//! is needed.
+
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ExportFormat {
+    Wav,
+    Flac,
+}
+
+impl ExportFormat {
+    /// An explicit name wins; otherwise the output path's extension decides.
+    pub(crate) fn resolve(name: Option<&str>, output: &Path) -> Result<Self> {
+        let name = match name {
+            Some(name) => name.trim().to_ascii_lowercase(),
+            None => output
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .map(|ext| ext.to_ascii_lowercase())
+                .ok_or_else(|| anyhow!("format is required when the output path has no extension"))?,
+        };
+        match name.as_str() {
+            "wav" | "wave" => Ok(ExportFormat::Wav),
+            "flac" => Ok(ExportFormat::Flac),
+            other => Err(anyhow!("unsupported export format: {}", other)),
+        }
+    }
+
+    /// 32 is IEEE float and only fits WAV.
+    pub(crate) fn supports_bits(self, bits: u32) -> bool {
+        match self {
+            ExportFormat::Wav => matches!(bits, 16 | 24 | 32),
+            ExportFormat::Flac => matches!(bits, 16 | 24),
+        }
+    }
+
+    /// The closest supported depth to a source's, for when none is requested.
+    pub(crate) fn default_bits(self, source_bits: Option<u32>) -> u32 {
+        match source_bits {
+            Some(bits) if bits > 24 && self == ExportFormat::Wav => 32,
+            Some(bits) if bits > 16 => 24,
+            _ => 16,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub(crate) struct ExportSpec {
+    pub format: ExportFormat,
+    pub sample_rate: u32,
+    pub channels: usize,
+    pub bit_depth: u32,
+}
+
+const FLAC_BLOCK_FRAMES: usize = 4096;
+const FLAC_MAX_CHANNELS: usize = 8;
+const FLAC_STREAMINFO_LEN: usize = 34;
+/// Offset of the STREAMINFO sample-rate/channels/bits/total-samples word,
+/// after the magic and the metadata block header.
+const FLAC_STREAMINFO_PACKED_OFFSET: u64 = 4 + 4 + 10;
+
+pub(crate) struct ExportWriter {
+    out: BufWriter<File>,
+    spec: ExportSpec,
+    frames_written: u64,
+    /// FLAC frames are fixed-size blocks; samples wait here for a full one.
+    pending: Vec<f32>,
+    block_index: u64,
+}
+
+impl ExportWriter {
+    pub(crate) fn create(path: &Path, spec: ExportSpec) -> Result<Self> {
+        if !spec.format.supports_bits(spec.bit_depth) {
+            return Err(anyhow!("{:?} cannot store {}-bit samples", spec.format, spec.bit_depth));
+        }
+        if spec.channels == 0 || spec.sample_rate == 0 {
+            return Err(anyhow!("export needs at least one channel and a sample rate"));
+        }
+        if spec.format == ExportFormat::Flac && spec.channels > FLAC_MAX_CHANNELS {
+            return Err(anyhow!("FLAC holds at most {} channels", FLAC_MAX_CHANNELS));
+        }
+        let file = File::create(path).context("create export file")?;
+        let mut writer = ExportWriter {
+            out: BufWriter::new(file),
+            spec,
+            frames_written: 0,
+            pending: Vec::new(),
+            block_index: 0,
+        };
+        match spec.format {
+            ExportFormat::Wav => writer.write_wav_header(0)?,
+            ExportFormat::Flac => writer.write_flac_header()?,
+        }
+        Ok(writer)
+    }
+
+    /// Append interleaved samples in [-1, 1].
+    pub(crate) fn write(&mut self, samples: &[f32]) -> Result<()> {
+        let channels = self.spec.channels;
+        match self.spec.format {
+            ExportFormat::Wav => {
+                for sample in samples {
+                    match self.spec.bit_depth {
+                        32 => self.out.write_all(&sample.to_le_bytes())?,
+                        bits => {
+                            let value = to_int(*sample, bits);
+                            self.out.write_all(&value.to_le_bytes()[..bits as usize / 8])?;
+                        }
+                    }
+                }
+            }
+            ExportFormat::Flac => {
+                self.pending.extend_from_slice(samples);
+                let block_len = FLAC_BLOCK_FRAMES * channels;
+                while self.pending.len() >= block_len {
+                    let block: Vec<f32> = self.pending.drain(..block_len).collect();
+                    self.write_flac_frame(&block)?;
+                }
+            }
+        }
+        self.frames_written += (samples.len() / channels) as u64;
+        Ok(())
+    }
+
+    /// Flush the last partial block and fill in the header lengths.
+    pub(crate) fn finish(mut self) -> Result<u64> {
+        match self.spec.format {
+            ExportFormat::Wav => {
+                self.out.seek(SeekFrom::Start(0))?;
+                self.write_wav_header(self.frames_written)?;
+            }
+            ExportFormat::Flac => {
+                if !self.pending.is_empty() {
+                    let block = std::mem::take(&mut self.pending);
+                    self.write_flac_frame(&block)?;
+                }
+                self.out.seek(SeekFrom::Start(FLAC_STREAMINFO_PACKED_OFFSET))?;
+                let packed = self.flac_packed_info(self.frames_written);
+                self.out.write_all(&packed.to_be_bytes())?;
+            }
+        }
+        self.out.flush()?;
+        Ok(self.frames_written)
+    }
+
+    fn write_wav_header(&mut self, frames: u64) -> Result<()> {
+        let spec = self.spec;
+        let block_align = spec.channels as u32 * spec.bit_depth / 8;
+        let data_len = u32::try_from(frames * block_align as u64)
+            .map_err(|_| anyhow!("export is too long for a WAV file"))?;
+        // WAVE_FORMAT_IEEE_FLOAT for 32-bit, PCM otherwise.
+        let format_tag: u16 = if spec.bit_depth == 32 { 3 } else { 1 };
+        let out = &mut self.out;
+        out.write_all(b"RIFF")?;
+        out.write_all(&(36 + data_len).to_le_bytes())?;
+        out.write_all(b"WAVEfmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        out.write_all(&format_tag.to_le_bytes())?;
+        out.write_all(&(spec.channels as u16).to_le_bytes())?;
+        out.write_all(&spec.sample_rate.to_le_bytes())?;
+        out.write_all(&(spec.sample_rate * block_align).to_le_bytes())?;
+        out.write_all(&(block_align as u16).to_le_bytes())?;
+        out.write_all(&(spec.bit_depth as u16).to_le_bytes())?;
+        out.write_all(b"data")?;
+        out.write_all(&data_len.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Sample rate (20 bits), channels - 1 (3), bits - 1 (5) and total
+    /// samples (36), as packed in STREAMINFO.
+    fn flac_packed_info(&self, frames: u64) -> u64 {
+        let spec = self.spec;
+        ((spec.sample_rate as u64 & 0xF_FFFF) << 44)
+            | (((spec.channels as u64 - 1) & 0x7) << 41)
+            | (((spec.bit_depth as u64 - 1) & 0x1F) << 36)
+            | (frames & 0xF_FFFF_FFFF)
+    }
+
+    fn write_flac_header(&mut self) -> Result<()> {
+        let mut info = Vec::with_capacity(FLAC_STREAMINFO_LEN);
+        info.extend_from_slice(&(FLAC_BLOCK_FRAMES as u16).to_be_bytes());
+        info.extend_from_slice(&(FLAC_BLOCK_FRAMES as u16).to_be_bytes());
+        // Unknown min/max frame sizes.
+        info.extend_from_slice(&[0; 6]);
+        info.extend_from_slice(&self.flac_packed_info(0).to_be_bytes());
+        // No MD5; decoders treat all zeroes as "not computed".
+        info.extend_from_slice(&[0; 16]);
+        let out = &mut self.out;
+        out.write_all(b"fLaC")?;
+        // Last-metadata-block flag set, type 0 (STREAMINFO).
+        out.write_all(&[0x80])?;
+        out.write_all(&(FLAC_STREAMINFO_LEN as u32).to_be_bytes()[1..])?;
+        out.write_all(&info)?;
+        Ok(())
+    }
+
+    fn write_flac_frame(&mut self, block: &[f32]) -> Result<()> {
+        let channels = self.spec.channels;
+        let bits = self.spec.bit_depth;
+        let frames = block.len() / channels;
+        let mut frame = Vec::with_capacity(16 + block.len() * bits as usize / 8 + channels);
+        // Sync code, fixed-blocksize stream.
+        frame.extend_from_slice(&[0xFF, 0xF8]);
+        // Block size: 4096 has its own code; a short final block stores
+        // size - 1 as 16 bits after the frame number. Sample rate comes
+        // from STREAMINFO.
+        let size_code: u8 = if frames == FLAC_BLOCK_FRAMES { 0b1100 } else { 0b0111 };
+        frame.push(size_code << 4);
+        let bits_code: u8 = if bits == 24 { 0b110 } else { 0b100 };
+        frame.push((((channels - 1) as u8) << 4) | (bits_code << 1));
+        push_utf8_number(&mut frame, self.block_index);
+        if size_code == 0b0111 {
+            frame.extend_from_slice(&((frames - 1) as u16).to_be_bytes());
+        }
+        frame.push(crc8(&frame));
+        for ch in 0..channels {
+            // Verbatim subframe, no wasted bits.
+            frame.push(0b0000_0010);
+            for i in 0..frames {
+                let value = to_int(block[i * channels + ch], bits);
+                frame.extend_from_slice(&value.to_be_bytes()[4 - bits as usize / 8..]);
+            }
+        }
+        frame.extend_from_slice(&crc16(&frame).to_be_bytes());
+        self.out.write_all(&frame)?;
+        self.block_index += 1;
+        Ok(())
+    }
+}
+
+/// Scale to a signed integer the way decoders scale back (full scale is
+/// 2^(bits-1)), clamping the positive peak.
+fn to_int(sample: f32, bits: u32) -> i32 {
+    let scale = (1i64 << (bits - 1)) as f32;
+    let max = (1i64 << (bits - 1)) - 1;
+    ((sample.clamp(-1.0, 1.0) * scale).round() as i64).clamp(-max - 1, max) as i32
+}
+
+/// FLAC's UTF-8-like variable-length frame number.
+fn push_utf8_number(out: &mut Vec<u8>, value: u64) {
+    if value < 0x80 {
+        out.push(value as u8);
+        return;
+    }
+    let mut extra = 1;
+    while value >= 1u64 << (6 * extra + (6 - extra)) {
+        extra += 1;
+    }
+    let lead_bits = 6 - extra;
+    let lead_mask = !(0xFFu8 >> (extra + 1));
+    out.push(lead_mask | ((value >> (6 * extra)) as u8 & ((1u8 << lead_bits) - 1)));
+    for i in (0..extra).rev() {
+        out.push(0x80 | ((value >> (6 * i)) & 0x3F) as u8);
+    }
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ramp(frames: usize, channels: usize) -> Vec<f32> {
+        (0..frames * channels)
+            .map(|i| ((i % 200) as f32 - 100.0) / 128.0)
+            .collect()
+    }
+
+    fn export(name: &str, spec: ExportSpec, samples: &[f32]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("ntmusic_export_{}_{}", name, std::process::id()));
+        let mut writer = ExportWriter::create(&path, spec).unwrap();
+        // Uneven chunks, so FLAC blocks straddle writes.
+        for chunk in samples.chunks(3_000 * spec.channels) {
+            writer.write(chunk).unwrap();
+        }
+        assert_eq!(writer.finish().unwrap() as usize, samples.len() / spec.channels);
+        path
+    }
+
+    #[test]
+    fn flac_and_wav_round_trip_through_the_decoder() {
+        let samples = ramp(10_000, 2);
+        for (format, bits) in [
+            (ExportFormat::Flac, 16),
+            (ExportFormat::Flac, 24),
+            (ExportFormat::Wav, 24),
+            (ExportFormat::Wav, 32),
+        ] {
+            let spec = ExportSpec {
+                format,
+                sample_rate: 44_100,
+                channels: 2,
+                bit_depth: bits,
+            };
+            let ext = if format == ExportFormat::Flac { "flac" } else { "wav" };
+            let path = export(&format!("{}{}.{}", ext, bits, ext), spec, &samples);
+            let decoded = crate::decode_to_pcm(path.to_str().unwrap()).unwrap();
+            let _ = std::fs::remove_file(&path);
+            assert_eq!(decoded.sample_rate, 44_100);
+            assert_eq!(decoded.channels, 2);
+            assert_eq!(decoded.samples.len(), samples.len(), "{:?} {}", format, bits);
+            let tolerance = 1.0 / (1u32 << (bits.min(24) - 1)) as f32;
+            for (a, b) in decoded.samples.iter().zip(&samples) {
+                assert!((a - b).abs() <= tolerance, "{:?} {}: {} vs {}", format, bits, a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn format_resolution_and_limits() {
+        let out = Path::new("mix.FLAC");
+        assert_eq!(ExportFormat::resolve(None, out).unwrap(), ExportFormat::Flac);
+        assert_eq!(ExportFormat::resolve(Some("wav"), out).unwrap(), ExportFormat::Wav);
+        assert!(ExportFormat::resolve(None, Path::new("mix")).is_err());
+        assert!(ExportFormat::resolve(Some("mp3"), out).is_err());
+        assert!(!ExportFormat::Flac.supports_bits(32));
+        assert_eq!(ExportFormat::Flac.default_bits(Some(32)), 24);
+        assert_eq!(ExportFormat::Wav.default_bits(Some(32)), 32);
+        assert_eq!(ExportFormat::Wav.default_bits(None), 16);
+    }
+
+    #[test]
+    fn frame_numbers_use_flac_utf8_coding() {
+        let encode = |value| {
+            let mut out = Vec::new();
+            push_utf8_number(&mut out, value);
+            out
+        };
+        assert_eq!(encode(0x7F), vec![0x7F]);
+        assert_eq!(encode(0x80), vec![0xC2, 0x80]);
+        assert_eq!(encode(0x800), vec![0xE0, 0xA0, 0x80]);
+    }
+}
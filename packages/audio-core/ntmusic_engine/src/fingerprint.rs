@@ -0,0 +1,170 @@
+//! Acoustic fingerprints for duplicate detection.
+//!
+//! This follows the shape of Chromaprint (chroma features from an 11025 Hz
+//! mono signal, one 32-bit hash per frame) without reproducing its exact
+//! filter set, so hashes compare against each other but not against
+//! AcoustID's database.
+
+use rustfft::{num_complex::Complex, FftPlanner};
+use serde::Serialize;
+
+pub(crate) const FINGERPRINT_SAMPLE_RATE: u32 = 11_025;
+/// Only the opening of a track is fingerprinted, as Chromaprint does.
+pub(crate) const FINGERPRINT_MAX_SECONDS: usize = 120;
+pub(crate) const FINGERPRINT_ALGORITHM: &str = "ntmusic-chroma-v1";
+
+const FRAME_SIZE: usize = 4096;
+const HOP_SIZE: usize = FRAME_SIZE / 3;
+const MIN_FREQ: f32 = 28.0;
+const MAX_FREQ: f32 = 3520.0;
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Fingerprint {
+    pub algorithm: &'static str,
+    pub duration: f64,
+    pub hashes: Vec<u32>,
+}
+
+/// Downmix interleaved audio to mono, truncated to the fingerprint window.
+pub(crate) fn mono_window(samples: &[f32], channels: usize, sample_rate: u32) -> Vec<f32> {
+    let channels = channels.max(1);
+    let max_frames = FINGERPRINT_MAX_SECONDS * sample_rate as usize;
+    samples
+        .chunks_exact(channels)
+        .take(max_frames)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect()
+}
+
+/// Hash mono audio already at `FINGERPRINT_SAMPLE_RATE`.
+pub(crate) fn compute_fingerprint(mono: &[f32], duration: f64) -> Fingerprint {
+    let chroma = chroma_frames(mono);
+    let hashes = (2..chroma.len())
+        .map(|t| frame_hash(&chroma[t], &chroma[t - 1], &chroma[t - 2]))
+        .collect();
+    Fingerprint {
+        algorithm: FINGERPRINT_ALGORITHM,
+        duration,
+        hashes,
+    }
+}
+
+/// Unit-normalized pitch-class energy per FFT frame.
+pub(crate) fn chroma_frames(mono: &[f32]) -> Vec<[f32; 12]> {
+    if mono.len() < FRAME_SIZE {
+        return Vec::new();
+    }
+    let fft = FftPlanner::new().plan_fft_forward(FRAME_SIZE);
+    let window: Vec<f32> = (0..FRAME_SIZE)
+        .map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / FRAME_SIZE as f32).cos())
+        .collect();
+    let bin_hz = FINGERPRINT_SAMPLE_RATE as f32 / FRAME_SIZE as f32;
+    let bin_classes: Vec<Option<usize>> = (0..FRAME_SIZE / 2)
+        .map(|bin| {
+            let freq = bin as f32 * bin_hz;
+            if !(MIN_FREQ..=MAX_FREQ).contains(&freq) {
+                return None;
+            }
+            let note = 12.0 * (freq / 440.0).log2() + 69.0;
+            Some((note.round() as i32).rem_euclid(12) as usize)
+        })
+        .collect();
+
+    let mut buffer = vec![Complex::new(0.0f32, 0.0); FRAME_SIZE];
+    let mut frames = Vec::with_capacity((mono.len() - FRAME_SIZE) / HOP_SIZE + 1);
+    let mut start = 0;
+    while start + FRAME_SIZE <= mono.len() {
+        for (slot, (sample, w)) in buffer
+            .iter_mut()
+            .zip(mono[start..start + FRAME_SIZE].iter().zip(&window))
+        {
+            *slot = Complex::new(sample * w, 0.0);
+        }
+        fft.process(&mut buffer);
+        let mut chroma = [0.0f32; 12];
+        for (value, class) in buffer.iter().zip(&bin_classes) {
+            if let Some(class) = class {
+                chroma[*class] += value.norm_sqr();
+            }
+        }
+        let norm = chroma.iter().map(|v| v * v).sum::<f32>().sqrt();
+        if norm > 1e-9 {
+            for v in chroma.iter_mut() {
+                *v /= norm;
+            }
+        }
+        frames.push(chroma);
+        start += HOP_SIZE;
+    }
+    frames
+}
+
+/// 32 bits per frame: 12 from how the pitch-class contour changed since the
+/// previous frame, 12 from each class against two frames back, and 8 from
+/// major-third contrasts within the frame.
+fn frame_hash(cur: &[f32; 12], prev: &[f32; 12], prev2: &[f32; 12]) -> u32 {
+    let mut hash = 0u32;
+    let mut bit = 0;
+    let mut push = |set: bool| {
+        if set {
+            hash |= 1 << bit;
+        }
+        bit += 1;
+    };
+    for b in 0..12 {
+        let n = (b + 1) % 12;
+        push((cur[b] - cur[n]) - (prev[b] - prev[n]) > 0.0);
+    }
+    for b in 0..12 {
+        push(cur[b] > prev2[b]);
+    }
+    for b in 0..8 {
+        push(cur[b] > cur[b + 4]);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn melody(seed_freqs: &[f32], seconds: f32) -> Vec<f32> {
+        let rate = FINGERPRINT_SAMPLE_RATE as f32;
+        let note_len = (rate * 0.5) as usize;
+        (0..(rate * seconds) as usize)
+            .map(|i| {
+                let freq = seed_freqs[(i / note_len) % seed_freqs.len()];
+                (2.0 * std::f32::consts::PI * freq * i as f32 / rate).sin() * 0.5
+            })
+            .collect()
+    }
+
+    fn bit_error_rate(a: &[u32], b: &[u32]) -> f32 {
+        let len = a.len().min(b.len());
+        let errors: u32 = a.iter().zip(b).map(|(x, y)| (x ^ y).count_ones()).sum();
+        errors as f32 / (len * 32) as f32
+    }
+
+    #[test]
+    fn same_audio_matches_and_different_audio_does_not() {
+        let tune = [261.6, 329.6, 392.0, 523.3, 440.0, 349.2];
+        let original = melody(&tune, 10.0);
+        let quieter: Vec<f32> = original.iter().map(|s| s * 0.3).collect();
+        let other = melody(&[196.0, 233.1, 293.7, 311.1, 370.0, 415.3], 10.0);
+
+        let a = compute_fingerprint(&original, 10.0);
+        let b = compute_fingerprint(&quieter, 10.0);
+        let c = compute_fingerprint(&other, 10.0);
+        assert_eq!(a.algorithm, FINGERPRINT_ALGORITHM);
+        assert!(!a.hashes.is_empty());
+        assert!(bit_error_rate(&a.hashes, &b.hashes) < 0.05);
+        assert!(bit_error_rate(&a.hashes, &c.hashes) > 0.2);
+    }
+
+    #[test]
+    fn short_or_multichannel_input() {
+        assert!(compute_fingerprint(&[0.0; 100], 0.01).hashes.is_empty());
+        let stereo = [1.0, 0.0, 0.5, 0.5];
+        assert_eq!(mono_window(&stereo, 2, FINGERPRINT_SAMPLE_RATE), vec![0.5, 0.5]);
+    }
+}
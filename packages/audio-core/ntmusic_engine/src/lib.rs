@@ -2,7 +2,7 @@
 use axum::{
     extract::{State, WebSocketUpgrade},
     http::StatusCode,
-    response::IntoResponse,
+    response::{IntoResponse, Response},
     routing::{get, post},
     Json, Router,
 };
@@ -11,13 +11,13 @@ use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use libloading::Library;
 use memmap2::MmapMut;
 use ringbuf::{HeapCons, HeapProd, HeapRb};
-use ringbuf::traits::{Consumer, Producer, Split};
+use ringbuf::traits::{Consumer, Observer, Producer, Split};
 use rubato::{Resampler, SincFixedIn, SincInterpolationParameters, SincInterpolationType, WindowFunction};
 use rustfft::{num_complex::Complex, Fft, FftPlanner};
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     ffi::CStr,
     fs::{File, OpenOptions},
     hash::{Hash, Hasher},
@@ -26,48 +26,84 @@ use std::{
     path::{Path, PathBuf},
     process::{Child, Command, Stdio},
     sync::{
-        atomic::{AtomicBool, AtomicU32, Ordering},
-        Arc, Mutex, OnceLock,
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, OnceLock, TryLockError,
     },
     thread,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use symphonia::core::{
-    audio::{AudioBufferRef, SampleBuffer},
-    codecs::{CodecParameters, DecoderOptions},
-    formats::FormatOptions,
+    audio::{AudioBufferRef, Channels, SampleBuffer},
+    codecs::{CodecParameters, Decoder, DecoderOptions},
+    errors::Error as SymphoniaError,
+    formats::{FormatOptions, FormatReader, SeekMode, SeekTo},
     io::MediaSourceStream,
-    meta::{MetadataOptions, StandardTagKey, StandardVisualKey},
-    probe::Hint,
+    meta::{MetadataOptions, StandardTagKey, StandardVisualKey, Tag},
+    probe::{Hint, ProbeResult},
     sample::SampleFormat,
+    units::TimeBase,
 };
 use tokio::sync::broadcast;
-use tracing::{error, info};
+use tracing::{error, info, warn};
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
 use walkdir::WalkDir;
 
+mod analysis;
+mod dsd;
+mod eq;
+mod export;
+mod fingerprint;
+mod live_resampler;
+mod playlist;
+mod scan_cache;
+mod settings;
+mod tag_writer;
+
+use analysis::{KeyEstimate, TempoEstimate};
+use eq::{EqFilters, EqKind};
+pub use dsd::DsdOutput;
+use export::{ExportFormat, ExportSpec, ExportWriter};
+use fingerprint::Fingerprint;
+use live_resampler::{FillResult, LiveResampler, LiveResamplerSlot};
+use playlist::{PlaylistEntry, PlaylistKind};
+use scan_cache::{FileStamp, ScanCache};
+use settings::PersistedSettings;
+use tag_writer::TagUpdate;
+
 #[cfg(target_os = "windows")]
-use windows::core::PCSTR;
+use windows::core::{HSTRING, PCSTR, PCWSTR};
 #[cfg(target_os = "windows")]
 use windows::Win32::Foundation::{WAIT_OBJECT_0, WAIT_TIMEOUT};
 #[cfg(target_os = "windows")]
+use windows::Win32::Devices::FunctionDiscovery::PKEY_Device_FriendlyName;
+#[cfg(target_os = "windows")]
 use windows::Win32::Media::Audio::{
-    IAudioClient, IAudioRenderClient, IMMDevice, IMMDeviceEnumerator, MMDeviceEnumerator,
-    eConsole, eRender, AUDCLNT_SHAREMODE_EXCLUSIVE, AUDCLNT_STREAMFLAGS_EVENTCALLBACK,
-    DEVICE_STATE_ACTIVE, WAVEFORMATEX, WAVEFORMATEXTENSIBLE, WAVEFORMATEXTENSIBLE_0,
+    IAudioCaptureClient, IAudioClient, IAudioRenderClient, IMMDevice, IMMDeviceEnumerator,
+    IMMNotificationClient, IMMNotificationClient_Impl, ERole, DEVICE_STATE,
+    MMDeviceEnumerator, eCapture, eConsole, eRender, EDataFlow, AUDCLNT_BUFFERFLAGS_SILENT,
+    AUDCLNT_SHAREMODE_EXCLUSIVE, AUDCLNT_SHAREMODE_SHARED, AUDCLNT_STREAMFLAGS_EVENTCALLBACK,
+    AUDCLNT_STREAMFLAGS_LOOPBACK, DEVICE_STATE_ACTIVE, WAVEFORMATEX, WAVEFORMATEXTENSIBLE,
+    WAVEFORMATEXTENSIBLE_0,
 };
 #[cfg(target_os = "windows")]
+use windows::Win32::Media::Audio::Endpoints::{IAudioEndpointVolume, ENDPOINT_HARDWARE_SUPPORT_VOLUME};
+#[cfg(target_os = "windows")]
 use windows::Win32::Media::KernelStreaming::{
-    SPEAKER_BACK_LEFT, SPEAKER_BACK_RIGHT, SPEAKER_FRONT_CENTER, SPEAKER_FRONT_LEFT,
-    SPEAKER_FRONT_RIGHT, SPEAKER_LOW_FREQUENCY, SPEAKER_SIDE_LEFT, SPEAKER_SIDE_RIGHT,
+    KSDATAFORMAT_SUBTYPE_PCM, SPEAKER_BACK_LEFT, SPEAKER_BACK_RIGHT, SPEAKER_FRONT_CENTER,
+    SPEAKER_FRONT_LEFT, SPEAKER_FRONT_RIGHT, SPEAKER_LOW_FREQUENCY, SPEAKER_SIDE_LEFT,
+    SPEAKER_SIDE_RIGHT,
 };
 #[cfg(target_os = "windows")]
 use windows::Win32::Media::Multimedia::KSDATAFORMAT_SUBTYPE_IEEE_FLOAT;
 #[cfg(target_os = "windows")]
 use windows::Win32::System::Com::{
-    CoCreateInstance, CoInitializeEx, CoUninitialize, CLSCTX_ALL, COINIT_MULTITHREADED,
+    CoCreateInstance, CoInitializeEx, CoTaskMemFree, CoUninitialize, CLSCTX_ALL,
+    COINIT_MULTITHREADED, STGM_READ,
 };
 #[cfg(target_os = "windows")]
 use windows::Win32::System::Threading::{CreateEventA, WaitForSingleObject};
+#[cfg(target_os = "windows")]
+use windows::Win32::UI::Shell::PropertiesSystem::PROPERTYKEY;
 
 #[derive(Clone)]
 pub struct SharedState {
@@ -80,9 +116,32 @@ pub struct SharedState {
     output_scratch: Arc<Mutex<Vec<f32>>>,
     stream_process: Arc<Mutex<Option<Child>>>,
     stream_thread: Arc<Mutex<Option<thread::JoinHandle<()>>>>,
+    /// Tells a suspended stream reader, which isn't blocked on the pipe, to
+    /// exit.
+    stream_stop: Arc<AtomicBool>,
     spectrum_shared: Option<Arc<Mutex<SpectrumShared>>>,
-    spectrum_bins: usize,
     control_shared: Option<Arc<Mutex<ControlShared>>>,
+    state_shared: Option<Arc<Mutex<MmapMut>>>,
+    scan_active: Arc<AtomicBool>,
+    scan_cancel: Arc<AtomicBool>,
+    /// One track decoded ahead of time by `/preload`.
+    preloaded: Arc<Mutex<Option<PreparedTrack>>>,
+    /// Bumped by every load; a decode still running for an older number
+    /// gives up rather than finishing work nobody wants.
+    load_generation: Arc<AtomicU64>,
+    /// Raised by the output stream's error callback when its device goes
+    /// away, for the device watcher to reopen on another.
+    output_lost: Arc<AtomicBool>,
+    /// Wakes the device watcher ahead of its next poll: on Windows when an
+    /// endpoint comes, goes or becomes the default, and anywhere when the
+    /// output is lost.
+    devices_changed: Arc<tokio::sync::Notify>,
+    /// Raised when a setting that outlives a restart changes, for the
+    /// settings writer to save.
+    settings_dirty: Arc<AtomicBool>,
+    /// Held while the track gains file is written, so writes land in the
+    /// order their changes were made.
+    track_gains_save: Arc<Mutex<()>>,
 }
 
 struct OutputStreamHolder(Option<cpal::Stream>);
@@ -106,8 +165,11 @@ impl ExclusiveStreamHandle {
 }
 
 struct SpectrumShared {
+    file: File,
     mmap: MmapMut,
     bins: usize,
+    /// The peak-hold values follow the spectrum; see `SPECTRUM_HEADER_BYTES`.
+    peaks: bool,
 }
 
 struct ControlShared {
@@ -137,9 +199,67 @@ pub struct TrackInfo {
 pub struct LibraryTrack {
     pub path: String,
     pub title: Option<String>,
+    /// Display string for the track artist; multiple values are joined.
     pub artist: Option<String>,
+    #[serde(default)]
+    pub artists: Vec<String>,
+    #[serde(default)]
+    pub album_artist: Option<String>,
     pub album: Option<String>,
+    /// Position on the album from the tags, without any "/total" part.
+    #[serde(default)]
+    pub track_number: Option<u32>,
+    #[serde(default)]
+    pub disc_number: Option<u32>,
+    /// Release year, from the date tag or failing that the original date.
+    #[serde(default)]
+    pub year: Option<u32>,
+    /// The first genre tagged.
+    #[serde(default)]
+    pub genre: Option<String>,
     pub duration: f64,
+    /// Source format from the container, when probing succeeded.
+    #[serde(default)]
+    pub sample_rate: Option<u32>,
+    #[serde(default)]
+    pub bit_depth: Option<u32>,
+    #[serde(default)]
+    pub channels: Option<u32>,
+    /// ReplayGain as linear factors, before preamp and clipping prevention,
+    /// from whichever gain source the file carries.
+    #[serde(default)]
+    pub track_gain: Option<f32>,
+    #[serde(default)]
+    pub album_gain: Option<f32>,
+    /// False when Symphonia has no decoder for the file (or couldn't open
+    /// it at all); such tracks are still listed, titled from the file name.
+    #[serde(default = "default_decodable")]
+    pub decodable: bool,
+}
+
+fn default_decodable() -> bool {
+    true
+}
+
+/// Where captured audio is played back while capturing.
+#[derive(Debug, Clone)]
+pub struct CaptureMonitor {
+    /// With monitoring off the output stays silent; capture still feeds the
+    /// spectrum.
+    pub enabled: bool,
+    /// Output device index from `get_devices`; `None` keeps the current one.
+    pub device_id: Option<usize>,
+    pub latency_ms: Option<u32>,
+}
+
+impl Default for CaptureMonitor {
+    fn default() -> Self {
+        CaptureMonitor {
+            enabled: true,
+            device_id: None,
+            latency_ms: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -204,10 +324,27 @@ impl EngineHandle {
         stop_impl(&self.shared)
     }
 
+    pub fn restart(&self) -> Result<()> {
+        restart_impl(&self.shared)
+    }
+
     pub fn set_device(&self, device_id: Option<usize>, exclusive: Option<bool>) -> Result<()> {
         configure_output_impl(&self.shared, device_id, exclusive)
     }
 
+    pub fn set_output_latency(&self, latency_ms: Option<u32>) -> Result<()> {
+        {
+            let mut state = self.shared.inner.lock().unwrap();
+            state.output_latency_ms = latency_ms.filter(|ms| *ms > 0);
+        }
+        let device_id = self.shared.inner.lock().unwrap().device_id;
+        configure_output_impl(&self.shared, device_id, None)
+    }
+
+    pub fn decode(&self, path: String, options: DecodeOptions) -> Result<DecodedAudio> {
+        decode_to_pcm_with_options(&path, options)
+    }
+
     pub fn get_devices(&self) -> Vec<DeviceInfo> {
         enumerate_devices()
     }
@@ -220,30 +357,70 @@ impl EngineHandle {
         position_from_state(&self.shared)
     }
 
+    /// Blocks until the scan finishes or [`Self::cancel_library_scan`] stops
+    /// it; a cancelled scan returns the tracks found so far.
     pub fn scan_library(&self, path: String) -> Result<Vec<LibraryTrack>> {
-        let tracks = scan_library_impl(&path)?;
-        {
-            let mut state = self.shared.inner.lock().unwrap();
-            state.library = tracks.clone();
-        }
-        Ok(tracks)
+        Ok(run_library_scan(&self.shared, &path, ScanScope::default(), false)?.tracks)
+    }
+
+    /// Library tracks matching `query`, best first, at most `limit` of them;
+    /// playback and the queue are left alone.
+    pub fn search_library(&self, query: &str, limit: Option<usize>) -> Result<Vec<LibraryTrack>> {
+        Ok(library_search_impl(&self.shared, query, limit)?.1)
+    }
+
+    /// Returns false when no scan was running.
+    pub fn cancel_library_scan(&self) -> bool {
+        cancel_library_scan_impl(&self.shared)
     }
 
     pub fn queue_add(&self, tracks: Vec<LibraryTrack>, replace: bool) -> Result<usize> {
         Ok(queue_add_impl(&self.shared, tracks, replace))
     }
 
+    /// Insert before entry `at`; the queue length appends.
+    pub fn queue_insert(&self, tracks: Vec<LibraryTrack>, at: usize) -> Result<usize> {
+        queue_insert_impl(&self.shared, tracks, at)
+    }
+
+    pub fn queue_move(&self, from: usize, to: usize) -> Result<()> {
+        queue_move_impl(&self.shared, from, to)
+    }
+
     pub fn queue_next(&self) -> Result<Option<LibraryTrack>> {
         queue_next_impl(&self.shared)
     }
 
+    /// `None` at the head of the queue.
+    pub fn queue_prev(&self) -> Result<Option<LibraryTrack>> {
+        queue_prev_impl(&self.shared)
+    }
+
+    /// The queue and the index of the entry playing from it, if any.
+    pub fn queue_list(&self) -> (Vec<LibraryTrack>, Option<usize>) {
+        queue_list_impl(&self.shared)
+    }
+
+    /// Returns how many entries are left.
+    pub fn queue_remove(&self, indices: &[usize]) -> Result<usize> {
+        queue_remove_impl(&self.shared, indices)
+    }
+
+    pub fn queue_clear(&self) {
+        queue_clear_impl(&self.shared)
+    }
+
+    /// `loopback` records what an output device plays through WASAPI
+    /// loopback rather than capturing an input; see `CaptureStartRequest`.
     pub fn capture_start(
         &self,
         device_id: Option<String>,
         samplerate: Option<u32>,
         channels: Option<u16>,
+        monitor: CaptureMonitor,
+        loopback: bool,
     ) -> Result<()> {
-        start_capture_impl(&self.shared, device_id, samplerate, channels)
+        start_capture_impl(&self.shared, device_id, samplerate, channels, monitor, loopback)
     }
 
     pub fn capture_stop(&self) -> Result<()> {
@@ -252,12 +429,85 @@ impl EngineHandle {
 }
 
 const DEFAULT_SPECTRUM_BINS: usize = 48;
-const SPECTRUM_FFT_SIZE: usize = 2048;
+const DEFAULT_SPECTRUM_FFT_SIZE: usize = 2048;
+/// FFT sizes `NTMUSIC_SPECTRUM_FFT_SIZE` and `/spectrum/config` accept,
+/// powers of two in between.
+const MIN_SPECTRUM_FFT_SIZE: usize = 256;
+const MAX_SPECTRUM_FFT_SIZE: usize = 16_384;
+const SPECTRUM_WINDOWS: [&str; 4] = ["hann", "hamming", "blackman", "blackman_harris"];
+/// FFT magnitude of a full-scale sine at the default size with a Hann
+/// window. Other sizes and windows are scaled to it, so the display's
+/// levels don't move with the settings.
+const SPECTRUM_REFERENCE_FULL_SCALE: f32 = DEFAULT_SPECTRUM_FFT_SIZE as f32 / 4.0;
 const SPECTRUM_UPDATE_INTERVAL_MS: u64 = 50;
-const SPECTRUM_HEADER_BYTES: usize = std::mem::size_of::<u32>();
+/// Highest `attack`/`release`: the share of the previous frame a bin keeps.
+const MAX_SPECTRUM_SMOOTHING: f32 = 0.99;
+/// Fastest peak-hold fall, in full display heights per second.
+const MAX_SPECTRUM_PEAK_FALL: f32 = 10.0;
+/// Output channels the history keeps; any beyond are neither analysed
+/// nor metered.
+const SPECTRUM_HISTORY_CHANNELS: usize = 8;
+/// How fast the meter's displayed peak falls once the signal drops.
+const LEVEL_METER_DECAY_DB_PER_SEC: f32 = 20.0;
+/// How long the meter holds the highest recent peak before letting go.
+const LEVEL_METER_HOLD_MS: u64 = 1500;
+/// Spectrum shm header, four u32s: seqlock counter, the bin count the data
+/// section currently holds, `SPECTRUM_SHM_VERSION`, and flags (see
+/// `SPECTRUM_FLAG_*`). Readers adopt the header's bin count and refuse a
+/// version they don't know. The data is `bins` f32 values, followed by as
+/// many peak-hold values while `SPECTRUM_FLAG_PEAKS` is set; readers that
+/// only know the first array are unaffected.
+///
+/// Version 1 was the bare seqlock and bin count, with data from byte 8.
+const SPECTRUM_SHM_VERSION: u32 = 2;
+const SPECTRUM_HEADER_BYTES: usize = 4 * std::mem::size_of::<u32>();
+const SPECTRUM_BINS_OFFSET: usize = 4;
+const SPECTRUM_VERSION_OFFSET: usize = 8;
+const SPECTRUM_FLAGS_OFFSET: usize = 12;
+const SPECTRUM_FLAG_PEAKS: u32 = 1;
+/// Half the default FFT size. A smaller FFT allows at most half its size:
+/// more bins than FFT outputs is meaningless.
+const MAX_SPECTRUM_BINS: usize = DEFAULT_SPECTRUM_FFT_SIZE / 2;
+/// Bins below this level (dBFS, relative to a full-scale sine) are shown as
+/// silence. Music sits far above it; idle dither and rounding noise do not.
+const DEFAULT_SPECTRUM_GATE_DB: f32 = -120.0;
+/// Lowest accepted gate, below anything the display can resolve; use it to
+/// turn the gate off.
+const MIN_SPECTRUM_GATE_DB: f32 = -160.0;
 const CONTROL_HEADER_BYTES: usize = 16;
 const CONTROL_CMD_BYTES: usize = 16;
+/// State shm layout, version 1, native endian, 64 bytes:
+///
+/// | offset | type | field |
+/// |-------:|------|-------|
+/// | 0  | u32 | seqlock counter (odd while a write is in progress) |
+/// | 4  | u32 | layout version |
+/// | 8  | u64 | position_frames (file read position) |
+/// | 16 | u64 | played_frames (frames sent to the device in live modes) |
+/// | 24 | u64 | buffered_frames |
+/// | 32 | u64 | underruns |
+/// | 40 | u32 | sample_rate |
+/// | 44 | f32 | volume (target, 0-1) |
+/// | 48 | u32 | flags, see `STATE_FLAG_*` |
+/// | 52 | u32 | reserved |
+/// | 56 | f64 | duration in seconds |
+///
+/// New fields go into the reserved word or past the end with a version bump.
+const STATE_SHM_VERSION: u32 = 1;
+const STATE_SHM_BYTES: usize = 64;
+const STATE_SHM_INTERVAL_MS: u64 = 10;
+const STATE_FLAG_PLAYING: u32 = 1;
+const STATE_FLAG_PAUSED: u32 = 1 << 1;
+/// Stream or capture mode; position is `played_frames` rather than
+/// `position_frames`.
+const STATE_FLAG_LIVE: u32 = 1 << 2;
+const STATE_FLAG_DEVICE_RELEASED: u32 = 1 << 3;
 const MAX_DITHER_CHANNELS: usize = 8;
+/// Channel indices past this are dropped from `invert_channels`; the stage
+/// itself skips any the current output doesn't have.
+const MAX_POLARITY_CHANNELS: usize = 32;
+const MAX_SELECTED_CHANNELS: usize = 32;
+const PARTIAL_DECODE_TOLERANCE_FRAMES: u64 = 8192;
 const DITHER_SHAPER_ORDER1_COEFF: f32 = 1.0;
 const DITHER_SHAPER_ORDER2_COEFF1: f32 = 2.0;
 const DITHER_SHAPER_ORDER2_COEFF2: f32 = -1.0;
@@ -267,43 +517,113 @@ const CONTROL_CMD_PAUSE: u32 = 2;
 const CONTROL_CMD_STOP: u32 = 3;
 const CONTROL_CMD_SEEK: u32 = 4;
 const CONTROL_CMD_VOLUME: u32 = 5;
+const CONTROL_CMD_RESTART: u32 = 6;
+/// Value is a delta in seconds from the current position.
+const CONTROL_CMD_SEEK_RELATIVE: u32 = 7;
 
 struct SpectrumAnalyzer {
     fft_size: usize,
     bins: usize,
+    /// One of `SPECTRUM_WINDOWS`.
+    window_kind: String,
     window: Vec<f32>,
     input: Vec<Complex<f32>>,
+    /// This frame's bins before smoothing.
+    frame: Vec<f32>,
+    /// What is displayed: `frame` smoothed over time.
     output: Vec<f32>,
+    /// Highest recent `output`, falling at `peak_fall` per second.
+    peaks: Vec<f32>,
+    attack: f32,
+    release: f32,
+    peak_fall: f32,
     fft: Arc<dyn Fft<f32>>,
+    /// Brings magnitudes through `window` to `SPECTRUM_REFERENCE_FULL_SCALE`.
+    level_scale: f32,
+    gate_db: f32,
+    gate_magnitude: f32,
 }
 
 impl SpectrumAnalyzer {
-    fn new(fft_size: usize, bins: usize) -> Self {
+    fn new(fft_size: usize, bins: usize, window_kind: &str) -> Self {
         let mut planner = FftPlanner::new();
         let fft = planner.plan_fft_forward(fft_size);
-        let mut window = Vec::with_capacity(fft_size);
-        for i in 0..fft_size {
-            let w = 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / fft_size as f32).cos();
-            window.push(w);
-        }
-        SpectrumAnalyzer {
+        let window = spectrum_window(window_kind, fft_size);
+        // A full-scale sine's magnitude through the window.
+        let full_scale = window.iter().sum::<f32>() / 2.0;
+        let mut analyzer = SpectrumAnalyzer {
             fft_size,
             bins,
+            window_kind: window_kind.to_string(),
             window,
             input: vec![Complex::new(0.0, 0.0); fft_size],
+            frame: vec![0.0; bins.max(1)],
             output: vec![0.0; bins.max(1)],
+            peaks: vec![0.0; bins.max(1)],
+            attack: 0.0,
+            release: 0.0,
+            peak_fall: 0.0,
             fft,
+            level_scale: SPECTRUM_REFERENCE_FULL_SCALE / full_scale.max(f32::MIN_POSITIVE),
+            gate_db: MIN_SPECTRUM_GATE_DB,
+            gate_magnitude: 0.0,
+        };
+        analyzer.set_gate_db(DEFAULT_SPECTRUM_GATE_DB);
+        analyzer
+    }
+
+    fn set_gate_db(&mut self, gate_db: f32) {
+        if gate_db == self.gate_db {
+            return;
+        }
+        self.gate_db = gate_db;
+        self.gate_magnitude = SPECTRUM_REFERENCE_FULL_SCALE * 10f32.powf(gate_db / 20.0);
+    }
+
+    /// Share of the previous frame a bin keeps while rising (`attack`) and
+    /// falling (`release`); 0 shows every frame as it is.
+    fn set_smoothing(&mut self, attack: f32, release: f32) {
+        self.attack = attack;
+        self.release = release;
+    }
+
+    /// Peak-hold fall in display heights per second; 0 turns peak hold off.
+    fn set_peak_fall(&mut self, per_sec: f32) {
+        if per_sec <= 0.0 {
+            self.peaks.fill(0.0);
         }
+        self.peak_fall = per_sec;
+    }
+
+    fn peaks(&self) -> Option<&[f32]> {
+        (self.peak_fall > 0.0).then_some(self.peaks.as_slice())
     }
 
+    /// Analyse one `SPECTRUM_UPDATE_INTERVAL_MS` frame: the new bins are
+    /// blended into the displayed ones and the peak-hold line follows.
     fn compute(&mut self, samples: &[f32], sample_rate: u32) -> &[f32] {
-        let output_len = self.output.len();
+        self.compute_frame(samples, sample_rate);
+        for (shown, &fresh) in self.output.iter_mut().zip(&self.frame) {
+            let keep = if fresh > *shown { self.attack } else { self.release };
+            *shown = fresh + (*shown - fresh) * keep;
+        }
+        if self.peak_fall > 0.0 {
+            let fall = self.peak_fall * SPECTRUM_UPDATE_INTERVAL_MS as f32 / 1000.0;
+            for (peak, &shown) in self.peaks.iter_mut().zip(&self.output) {
+                *peak = shown.max(*peak - fall);
+            }
+        }
+        &self.output
+    }
+
+    fn compute_frame(&mut self, samples: &[f32], sample_rate: u32) {
+        let output_len = self.frame.len();
         if output_len == 0 {
-            return &self.output;
+            return;
         }
-        self.output.fill(0.0);
+        self.frame.fill(0.0);
         if samples.is_empty() || sample_rate == 0 {
-            return &self.output;
+            return;
         }
 
         let len = samples.len().min(self.fft_size);
@@ -323,33 +643,118 @@ impl SpectrumAnalyzer {
         let min_freq = 20.0f32;
         let max_freq = (sample_rate as f32) / 2.0;
         if max_freq <= min_freq {
-            return &self.output;
+            return;
         }
         let log_min = min_freq.log10();
         let log_max = max_freq.log10();
         let denom = (log_max - log_min).max(1e-6);
         let mags_len = (self.fft_size / 2).saturating_sub(1);
         if mags_len == 0 {
-            return &self.output;
+            return;
         }
         for i in 0..mags_len {
-            let mag = self.input[i + 1].norm();
+            let mag = self.input[i + 1].norm() * self.level_scale;
             let freq = (i as f32 / mags_len as f32) * max_freq;
             let log_pos = ((freq.max(min_freq).log10() - log_min) / denom) * self.bins as f32;
             let idx = log_pos.floor() as usize;
             if idx < self.bins {
-                self.output[idx] = self.output[idx].max(mag);
+                self.frame[idx] = self.frame[idx].max(mag);
             }
         }
-        for v in self.output.iter_mut() {
+        for v in self.frame.iter_mut() {
+            if *v < self.gate_magnitude {
+                *v = 0.0;
+                continue;
+            }
             let db = 20.0f32 * (*v + 1e-9f32).log10();
             let norm = ((db + 90.0f32) / 90.0f32).clamp(0.0f32, 1.0f32);
             *v = norm;
         }
-        &self.output
     }
 }
 
+/// A periodic cosine-sum window of `size` points; unknown kinds get Hann.
+fn spectrum_window(kind: &str, size: usize) -> Vec<f32> {
+    let coefficients: &[f32] = match kind {
+        "hamming" => &[0.54, 0.46],
+        "blackman" => &[0.42, 0.5, 0.08],
+        "blackman_harris" => &[0.35875, 0.48829, 0.14128, 0.01168],
+        _ => &[0.5, 0.5],
+    };
+    (0..size)
+        .map(|i| {
+            let x = 2.0 * std::f32::consts::PI * i as f32 / size as f32;
+            coefficients
+                .iter()
+                .enumerate()
+                .map(|(k, a)| {
+                    let sign = if k % 2 == 0 { 1.0 } else { -1.0 };
+                    sign * a * (k as f32 * x).cos()
+                })
+                .sum()
+        })
+        .collect()
+}
+
+fn valid_spectrum_fft_size(size: usize) -> bool {
+    size.is_power_of_two() && (MIN_SPECTRUM_FFT_SIZE..=MAX_SPECTRUM_FFT_SIZE).contains(&size)
+}
+
+/// Frames of output the spectrum ring keeps for `fft_size`: at least twice
+/// the FFT window, so consecutive analyses overlap instead of each seeing
+/// only the last callback's worth of audio, and one update interval at
+/// 192 kHz, so the level meter sees every frame.
+fn spectrum_history_frames(fft_size: usize) -> usize {
+    let interval_frames = (192_000 * SPECTRUM_UPDATE_INTERVAL_MS / 1000) as usize;
+    interval_frames.max(fft_size * 2)
+}
+
+/// Switch the analysis to `fft_size`, reallocating the spectrum ring to
+/// match. Done under the state lock, so the callback and the analyzer never
+/// see a ring of the wrong size.
+fn set_spectrum_fft_size(state: &mut EngineState, fft_size: usize) {
+    if state.spectrum_fft_size == fft_size {
+        return;
+    }
+    state.spectrum_fft_size = fft_size;
+    state.spectrum_history = vec![0.0; spectrum_history_frames(fft_size) * SPECTRUM_HISTORY_CHANNELS];
+    state.spectrum_history_pos = 0;
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct OutputConfigInfo {
+    backend: String,
+    sample_rate: u32,
+    channels: u16,
+    sample_format: String,
+    /// Significant bits per sample; 24 for 24-bit samples in a 32-bit
+    /// integer container.
+    bit_depth: u32,
+    buffer_frames: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ResamplerInfo {
+    backend: String,
+    /// Why the configured mode picked this backend.
+    reason: &'static str,
+    /// soxr's error when "auto" wanted soxr but ended up on rubato.
+    fallback: Option<String>,
+    from_rate: u32,
+    to_rate: u32,
+    requested_quality: String,
+    effective_quality: String,
+    cross_family: bool,
+    sinc_len: Option<usize>,
+    f_cutoff: Option<f32>,
+    oversampling_factor: Option<usize>,
+    input_frames: usize,
+    output_frames: usize,
+    /// Filter delay in output frames, flushed from the resampler so the
+    /// end of the track is not cut short.
+    delay_frames: usize,
+}
+
 #[derive(Debug, Clone, Serialize)]
 struct PlaybackState {
     is_playing: bool,
@@ -364,25 +769,104 @@ struct PlaybackState {
     source_bit_depth: Option<u32>,
     volume: f32,
     device_id: Option<usize>,
+    hostapi: Option<String>,
     exclusive_mode: bool,
+    exclusive_format: String,
+    volume_mode: String,
+    /// "hardware" while the endpoint is taking the volume, else "software".
+    volume_mode_effective: &'static str,
+    output_latency_ms: Option<u32>,
     eq_type: String,
+    /// The implementation filtering right now; null while the EQ is off
+    /// or flat.
+    eq_implementation: Option<&'static str>,
+    eq_fir_taps: usize,
+    /// Delay the FIR EQ adds to the output; 0 for the IIR one.
+    eq_latency_ms: f64,
     dither_enabled: bool,
     dither_type: String,
     dither_bits: u32,
     replaygain_enabled: bool,
+    replaygain_preamp_db: f32,
+    replaygain_prevent_clipping: bool,
+    replaygain_mode: String,
+    replaygain_applied_mode: String,
+    replaygain_gain: f32,
+    /// Gains and peaks read from the current track's tags; all null when
+    /// it has none and `replaygain_gain` falls back to unity.
+    replaygain_tags: ReplayGainInfo,
+    auto_level_enabled: bool,
+    auto_level_target_db: f32,
+    auto_level_max_gain_db: f32,
+    /// Gain auto level is applying right now.
+    auto_level_gain_db: f32,
+    /// False while disabled or while ReplayGain covers the current track.
+    auto_level_active: bool,
     resampler_mode: String,
     resampler_quality: String,
     soxr_available: bool,
     limiter_enabled: bool,
     limiter_threshold: f32,
+    limiter_threshold_db: f32,
+    invert_polarity: bool,
+    invert_channels: Vec<usize>,
+    downmix: String,
     eq_enabled: bool,
     eq_bands: HashMap<String, f32>,
     target_samplerate: Option<u32>,
     mode: String,
     stream_status: String,
+    /// Whether the stream has a length to seek in; null while probing.
+    stream_seekable: Option<bool>,
     buffered_ms: f64,
+    buffer_fill: f64,
     underruns: u64,
+    dropped_samples: u64,
+    clipped_samples: u64,
     spectrum_ws_enabled: bool,
+    spectrum_bins: usize,
+    spectrum_gate_db: f32,
+    spectrum_fft_size: usize,
+    spectrum_window: String,
+    spectrum_attack: f32,
+    spectrum_release: f32,
+    spectrum_peak_fall: f32,
+    spectrum_source: String,
+    partial_decode: Option<PartialDecodeInfo>,
+    gapless_trim_enabled: bool,
+    gapless_trim: Option<GaplessTrimInfo>,
+    /// The current file is decoding as it plays, so it can't loop, crossfade
+    /// into the next track or splice onto it gaplessly.
+    incremental_decode: bool,
+    incremental_decode_secs: f64,
+    output_config: Option<OutputConfigInfo>,
+    resampler_info: Option<ResamplerInfo>,
+    processing_chain: Vec<&'static str>,
+    idle_release_secs: Option<u64>,
+    live_pause_mode: String,
+    track_gain_db: f32,
+    inter_track_silence_ms: u32,
+    command_volume_step: f32,
+    dsd_mode: String,
+    dsd_pcm_rate: u32,
+    /// "pcm" or "dop" while a DSD file is loaded.
+    dsd_output: Option<&'static str>,
+    /// Silence still to play before the current track starts.
+    gap_remaining_ms: f64,
+    crossfade_ms: u32,
+    /// A crossfade into the current track is under way.
+    crossfading: bool,
+    transport_fade_ms: u32,
+    repeat_mode: String,
+    /// A/B loop bounds in seconds, null without one.
+    loop_start: Option<f64>,
+    loop_end: Option<f64>,
+    shuffle: bool,
+    auto_advance: bool,
+    device_released: bool,
+    /// The selected device is unplugged; the default plays until it's back.
+    device_fallback: bool,
+    capture_monitor: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -392,49 +876,249 @@ struct EngineState {
     mode: String,
     file_path: Option<String>,
     data: Vec<f32>,
+    /// Set while the current file decodes into the ring buffer as it plays
+    /// rather than sitting whole in `data`.
+    incremental: Option<IncrementalDecode>,
+    /// Files at least this many seconds long decode as they play; 0 decodes
+    /// every file up front.
+    incremental_decode_secs: f64,
     channels: usize,
     sample_rate: u32,
     source_channels: usize,
     source_sample_rate: u32,
     source_bit_depth: Option<u32>,
+    partial_decode: Option<PartialDecodeInfo>,
+    gapless_trim_enabled: bool,
+    gapless_trim: Option<GaplessTrimInfo>,
     position: usize,
     played_frames: u64,
     duration: f64,
+    /// Target volume; the callback ramps `volume_current` towards it.
     volume: f32,
+    volume_current: f32,
+    /// Ramp over play, pause, stop and seek so they don't click; 0 cuts.
+    transport_fade_ms: u32,
+    /// Where that ramp is, from 0 (silent) to 1. The callback drops it to 0
+    /// while nothing plays, so playback always comes in from silence.
+    transport_gain: f32,
+    /// Ramp to silence rather than full level: a pause, stop or seek is
+    /// waiting for the fade before it takes effect.
+    transport_fading_out: bool,
+    /// "software" scales samples in the callback; "hardware" sets the
+    /// endpoint volume instead, where a WASAPI exclusive device has a
+    /// hardware control, and falls back to software otherwise.
+    volume_mode: String,
+    /// Set while the endpoint is taking the volume; the callback leaves
+    /// samples unscaled.
+    hardware_volume_active: bool,
     device_id: Option<usize>,
+    /// Host and name of the device chosen as `device_id`. Ids are places in
+    /// the device list, which shift as devices come and go, so the device
+    /// watcher finds the choice again by these.
+    selected_device: Option<(String, String)>,
+    /// The selected device is unplugged and the default plays in its place
+    /// until it comes back.
+    device_fallback: bool,
+    /// Host (`"Wasapi"`, `"Asio"`, ...) the default device and any selected
+    /// device must come from; `None` uses cpal's default host.
+    hostapi: Option<String>,
     exclusive_mode: bool,
+    /// Sample format asked of a WASAPI exclusive stream: "f32" or "i24"
+    /// (24 bits in a 32-bit container). The other is tried if the device
+    /// refuses it.
+    exclusive_format: String,
+    output_latency_ms: Option<u32>,
+    /// Release the output device after this long stopped; `None` keeps it open.
+    idle_release_secs: Option<u64>,
+    idle_since: Option<Instant>,
+    device_released: bool,
+    capture_monitor: bool,
+    /// What pausing a stream or capture does with audio that keeps arriving:
+    /// "drop" discards it so resume is live again, "hold" keeps buffering
+    /// (up to `buffer_max_ms`, then the reader drops) and resumes behind
+    /// live, "suspend" stops reading from ffmpeg so it stalls on the pipe
+    /// and resume carries on from the same spot. Suspending suits streams
+    /// served from a file; a live radio server usually disconnects a stalled
+    /// client, in which case resuming rejoins the stream live. Files always
+    /// hold their position.
+    live_pause_mode: String,
+    /// Silence to put between a track that played to its end and the next
+    /// one loaded, unless that one continues it gaplessly.
+    inter_track_silence_ms: u32,
+    /// How far "volume up" and "volume down" commands move the volume.
+    command_volume_step: f32,
+    /// Volume before a "mute" command, for "unmute" to go back to.
+    muted_volume: Option<f32>,
+    /// "pcm" converts DSD files to PCM at `dsd_pcm_rate`; "dop" sends them
+    /// as DoP while exclusive output is on and converts them otherwise.
+    dsd_mode: String,
+    dsd_pcm_rate: u32,
+    /// How the current file was rendered, if it is DSD. DoP samples skip
+    /// the processing chain, volume included.
+    dsd_output: Option<DsdOutput>,
+    /// Output frames of that silence still to go before `position` moves.
+    gap_frames: usize,
+    /// Overlap between a track and the next one from the queue; 0 turns
+    /// crossfading off.
+    crossfade_ms: u32,
+    /// The outgoing track while it fades under the current one. Left in
+    /// place once done, for the background loop to free.
+    crossfade: Option<Crossfade>,
+    /// User gain overrides in dB by file path, saved to `track_gains_path`.
+    track_gains: HashMap<String, f32>,
+    track_gains_path: PathBuf,
+    /// Where device, EQ, dither, upsampling and volume choices are kept
+    /// across restarts; `None` keeps them for this run only.
+    settings_path: Option<PathBuf>,
+    /// Where library scans keep what they probed, to skip unchanged files.
+    scan_cache_path: PathBuf,
+    /// Override for the current file, applied after ReplayGain.
+    track_gain_db: f32,
+    /// Output device, as `device_id` and `selected_device`, and latency to
+    /// restore when capture stops.
+    capture_saved_output: Option<SavedOutput>,
+    output_config: Option<OutputConfigInfo>,
     eq_enabled: bool,
     eq_type: String,
     eq_bands: HashMap<String, f32>,
+    eq_filters: EqFilters,
     dither_enabled: bool,
     dither_type: String,
     dither_bits: u32,
     replaygain_enabled: bool,
+    replaygain: ReplayGainInfo,
+    replaygain_preamp_db: f32,
+    replaygain_prevent_clipping: bool,
+    replaygain_mode: String,
+    replaygain_applied_mode: String,
+    replaygain_gain: f32,
+    /// Real-time normalizer for untagged files; see `apply_auto_level`.
+    auto_level_enabled: bool,
+    /// Loudness to steer towards, as RMS in dBFS.
+    auto_level_target_db: f32,
+    auto_level_max_gain_db: f32,
+    /// Smoothed linear gain currently applied.
+    auto_level_gain: f64,
+    /// Slow running mean square of the signal entering the stage.
+    auto_level_power: f64,
     resampler_mode: String,
     resampler_quality: String,
+    resampler_info: Option<ResamplerInfo>,
     soxr_available: bool,
+    /// Converts stream and capture audio to the device rate in the output
+    /// callback when the two differ; see `refresh_live_resampler`.
+    live_resampler: LiveResamplerSlot,
     limiter_enabled: bool,
     limiter_threshold: f32,
+    /// Flip absolute polarity on every output channel.
+    invert_polarity: bool,
+    /// Output channels to flip when `invert_polarity` is off.
+    invert_channels: Vec<usize>,
+    /// "none", "mono", "left" or "right"; see `apply_downmix`.
+    downmix: String,
     target_samplerate: Option<u32>,
     stream_url: Option<String>,
     stream_status: String,
     stream_error: Option<String>,
+    stream_restart_pending: bool,
+    /// Known once ffmpeg has probed the stream: on-demand sources have a
+    /// length and can seek, live ones can't. `None` while probing.
+    stream_seekable: Option<bool>,
+    /// A control-channel seek in stream mode, in seconds, for the
+    /// background loop to carry out.
+    stream_seek_pending: Option<f64>,
+    server_port: Option<u16>,
+    output_channels: usize,
     buffered_frames: usize,
+    /// Frames the ring buffer holds at the current channel count.
+    ring_capacity_frames: usize,
     buffer_max_ms: u32,
     underrun_count: u64,
+    /// Samples discarded by the stream reader because the ring was full.
+    dropped_sample_count: u64,
+    /// Output samples beyond full scale after the processing chain.
+    clip_count: u64,
+    /// Lowercase extensions, without the dot, that scans and refreshes accept.
+    audio_extensions: Vec<String>,
     library: Vec<LibraryTrack>,
     queue: Vec<LibraryTrack>,
     queue_index: Option<usize>,
-    last_output_chunk: Vec<f32>,
+    /// "off", "one" (loop the current track without a gap) or "all" (wrap
+    /// around the queue).
+    repeat_mode: String,
+    /// A/B loop over frames `start..end` of the current file, set through
+    /// `/loop`; cleared when another track comes in.
+    loop_region: Option<(usize, usize)>,
+    shuffle: bool,
+    shuffle_rng: u64,
+    /// Queue indices played this shuffle cycle, in order, for `queue_prev`.
+    shuffle_history: Vec<usize>,
+    /// Play the next queue entry when a queued file ends, rather than
+    /// leaving that to the client.
+    auto_advance: bool,
+    /// Set by the output callback when a file plays out; the background
+    /// loop takes it to advance the queue.
+    track_finished: bool,
+    lookahead: LookAhead,
+    /// Set by the output callback when it spliced in the staged track; the
+    /// background loop announces the change and frees `retired_samples`.
+    track_spliced: bool,
+    /// The finished track's samples after a splice, so the callback never
+    /// frees a whole track.
+    retired_samples: Vec<f32>,
+    /// Ring of the most recent output frames, interleaved at
+    /// `spectrum_history_channels`; `spectrum_history_pos` is the frame the
+    /// next one goes in. The callback only copies into it; the spectrum and
+    /// level meter reduce it on the background task.
+    spectrum_history: Vec<f32>,
+    spectrum_history_channels: usize,
+    spectrum_history_pos: usize,
+    /// Frames written to the ring so far, so the meter can take only what
+    /// is new since its last read.
+    spectrum_history_written: u64,
     dither_rng: u64,
     dither_shape_err1: [f32; MAX_DITHER_CHANNELS],
     dither_shape_err2: [f32; MAX_DITHER_CHANNELS],
     spectrum_ws_enabled: bool,
+    /// Bins the analyzer produces; the spectrum shm follows changes.
+    spectrum_bins: usize,
+    spectrum_gate_db: f32,
+    /// Set through `set_spectrum_fft_size`, which sizes the ring to it.
+    spectrum_fft_size: usize,
+    /// One of `SPECTRUM_WINDOWS`.
+    spectrum_window: String,
+    /// Smoothing over time; see `SpectrumAnalyzer::set_smoothing`.
+    spectrum_attack: f32,
+    spectrum_release: f32,
+    /// Peak-hold fall in display heights per second; 0 turns the peak-hold
+    /// line off, and with it the second shm array.
+    spectrum_peak_fall: f32,
+    /// What of each output frame the spectrum analyses: "mono" sums every
+    /// channel, "left"/"right" take one, "mid" and "side" are (L+R)/2 and
+    /// (L-R)/2, the latter showing only what differs between the channels.
+    spectrum_source: String,
+}
+
+#[derive(Deserialize)]
+struct ExportRequest {
+    path: String,
+    output_path: String,
+    /// "wav" or "flac"; defaults to the output path's extension.
+    format: Option<String>,
+    sample_rate: Option<u32>,
+    /// 16 or 24, or 32 (float) for WAV.
+    bit_depth: Option<u32>,
+    /// 0 to 1; unity by default rather than the playback volume.
+    volume: Option<f32>,
 }
 
 #[derive(Deserialize)]
 struct LoadRequest {
     path: String,
+    gapless_trim: Option<bool>,
+    /// "keep" (default), "stereo" or "select".
+    channel_mode: Option<String>,
+    select_channels: Option<Vec<usize>>,
 }
 
 #[derive(Deserialize)]
@@ -445,12 +1129,75 @@ struct StreamRequest {
 #[derive(Deserialize)]
 struct LibraryScanRequest {
     path: String,
+    recursive: Option<bool>,
+    follow_links: Option<bool>,
+    /// Probe every file again rather than reusing cached results.
+    force: Option<bool>,
+    /// "title", "artist", "album", "path" or "duration"; unset keeps the
+    /// order the walk found them in.
+    sort_by: Option<String>,
+    /// Defaults to true.
+    ascending: Option<bool>,
+    /// Answer at once with the scan's id and report the result as a
+    /// `scan_complete` event instead.
+    background: Option<bool>,
+    /// Extensions to list for this scan instead of the configured set.
+    extensions: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+struct LoopRequest {
+    /// `false` clears the loop; anything else sets it.
+    enabled: Option<bool>,
+    /// Seconds; the loop runs from the start of the track without it.
+    start: Option<f64>,
+    /// Seconds; the loop runs to the end of the track without it.
+    end: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct QueueModeRequest {
+    repeat_mode: Option<String>,
+    shuffle: Option<bool>,
+    /// Reseed the shuffle order, for a reproducible sequence.
+    seed: Option<u64>,
+    auto_advance: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct LibrarySearchRequest {
+    query: String,
+    /// Most results to return; `DEFAULT_SEARCH_LIMIT` when unset.
+    limit: Option<usize>,
 }
 
 #[derive(Deserialize)]
 struct QueueAddRequest {
     tracks: Vec<LibraryTrack>,
     replace: Option<bool>,
+    /// Insert before this entry rather than at the end; not with `replace`.
+    at: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct QueueMoveRequest {
+    from: usize,
+    /// Where the entry ends up, counted after it is taken out.
+    to: usize,
+}
+
+/// `index`, `indices` or both; all are removed together.
+#[derive(Deserialize)]
+struct QueueRemoveRequest {
+    index: Option<usize>,
+    indices: Option<Vec<usize>>,
+}
+
+#[derive(Deserialize)]
+struct PlaylistLoadRequest {
+    /// Local path or http(s) URL of an M3U, PLS or ASX playlist.
+    path: String,
+    replace: Option<bool>,
 }
 
 #[derive(Deserialize)]
@@ -458,11 +1205,64 @@ struct CommandRequest {
     text: Option<String>,
     action: Option<String>,
     query: Option<String>,
+    /// Always return a play query's matches to choose from.
+    disambiguate: Option<bool>,
 }
 
 #[derive(Deserialize)]
 struct CoverRequest {
     path: String,
+    /// Return the image as a `data:` URI rather than a file path, for
+    /// clients that can't read local files. Covers over
+    /// `INLINE_COVER_MAX_BYTES` are written to a file regardless.
+    inline: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct LyricsRequest {
+    path: String,
+}
+
+#[derive(Deserialize)]
+struct RefreshTrackRequest {
+    path: String,
+}
+
+#[derive(Deserialize)]
+struct MetadataWriteRequest {
+    path: String,
+    #[serde(flatten)]
+    tags: TagUpdate,
+}
+
+#[derive(Deserialize)]
+struct FingerprintRequest {
+    path: String,
+}
+
+#[derive(Deserialize)]
+struct BatchCommand {
+    /// Route of the endpoint to run, e.g. "load" or "spectrum/config".
+    op: String,
+    /// That endpoint's request body; omitted for ones that take none.
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Deserialize)]
+struct BatchRequest {
+    commands: Vec<BatchCommand>,
+    /// Keep going after a failed command instead of stopping there.
+    #[serde(default)]
+    continue_on_error: bool,
+}
+
+#[derive(Deserialize)]
+struct MetadataCoverRequest {
+    path: String,
+    image_path: Option<String>,
+    image_base64: Option<String>,
+    remove_existing: Option<bool>,
 }
 
 #[derive(Deserialize)]
@@ -470,6 +1270,12 @@ struct SeekRequest {
     position: f64,
 }
 
+#[derive(Deserialize)]
+struct SeekRelativeRequest {
+    /// Seconds; negative seeks back.
+    delta: f64,
+}
+
 #[derive(Deserialize)]
 struct VolumeRequest {
     volume: f32,
@@ -479,6 +1285,23 @@ struct VolumeRequest {
 struct ConfigureOutputRequest {
     device_id: Option<usize>,
     exclusive: Option<bool>,
+    latency_ms: Option<u32>,
+    /// "f32" or "i24"; see `EngineState::exclusive_format`.
+    exclusive_format: Option<String>,
+    /// "software" or "hardware"; see `EngineState::volume_mode`.
+    volume_mode: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TrackGainRequest {
+    /// Clamped to +/-12 dB; 0 removes the override.
+    gain_db: f32,
+}
+
+#[derive(Deserialize)]
+struct HostApiRequest {
+    /// A name from `GET /hostapis`; null or empty clears the preference.
+    name: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -486,11 +1309,53 @@ struct SpectrumWsRequest {
     enabled: bool,
 }
 
+#[derive(Deserialize)]
+struct AudioExtensionsRequest {
+    /// Replaces the whole list; `reset` restores the built-in one instead.
+    extensions: Option<Vec<String>>,
+    reset: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct SpectrumConfigRequest {
+    bins: Option<usize>,
+    /// Noise gate in dBFS; bins quieter than this render as zero.
+    gate_db: Option<f32>,
+    /// "mono", "left", "right", "mid" or "side"; see `EngineState::spectrum_source`.
+    source: Option<String>,
+    /// A power of two; larger resolves low frequencies more finely but
+    /// reacts more slowly.
+    fft_size: Option<usize>,
+    /// One of `SPECTRUM_WINDOWS`.
+    window: Option<String>,
+    /// Share of the previous frame a bin keeps, 0 to `MAX_SPECTRUM_SMOOTHING`,
+    /// while rising and while falling.
+    attack: Option<f32>,
+    release: Option<f32>,
+    /// Peak-hold fall in display heights per second; 0 turns it off.
+    peak_fall: Option<f32>,
+}
+
 #[derive(Deserialize)]
 struct ConfigureUpsamplingRequest {
     target_samplerate: Option<u32>,
 }
 
+#[derive(Deserialize)]
+struct ConfigureDownmixRequest {
+    /// One of `DOWNMIX_MODES`.
+    downmix: String,
+}
+
+#[derive(Deserialize)]
+struct ConfigureCrossfadeRequest {
+    /// Clamped to `CROSSFADE_MAX_MS`; 0 turns crossfading off.
+    crossfade_ms: Option<u32>,
+    /// Fade on play, pause, stop and seek, clamped to
+    /// `TRANSPORT_FADE_MAX_MS`; 0 cuts straight away.
+    transport_fade_ms: Option<u32>,
+}
+
 #[derive(Deserialize)]
 struct EqRequest {
     bands: Option<HashMap<String, f32>>,
@@ -499,7 +1364,10 @@ struct EqRequest {
 
 #[derive(Deserialize)]
 struct EqTypeRequest {
+    /// "IIR" or "FIR", case-insensitive.
     r#type: String,
+    /// FIR kernel length, made odd and clamped to 63..=4095.
+    fir_taps: Option<usize>,
 }
 
 #[derive(Deserialize)]
@@ -508,10 +1376,41 @@ struct OptimizeRequest {
     dither_type: Option<String>,
     dither_bits: Option<u32>,
     replaygain_enabled: Option<bool>,
+    replaygain_preamp_db: Option<f32>,
+    replaygain_prevent_clipping: Option<bool>,
+    replaygain_mode: Option<String>,
+    auto_level_enabled: Option<bool>,
+    /// RMS dBFS, clamped to [-40, -6].
+    auto_level_target_db: Option<f32>,
+    /// Most auto level may boost, clamped to [0, 24] dB.
+    auto_level_max_gain_db: Option<f32>,
+    gapless_trim: Option<bool>,
     resampler_mode: Option<String>,
     resampler_quality: Option<String>,
     limiter_enabled: Option<bool>,
+    /// dBFS, or a legacy linear amplitude when positive.
     limiter_threshold: Option<f32>,
+    limiter_threshold_db: Option<f32>,
+    invert_polarity: Option<bool>,
+    /// Output channel indices to invert; an empty list clears them.
+    invert_channels: Option<Vec<usize>>,
+    /// Seconds stopped before the output device is released; 0 keeps it open.
+    idle_release_secs: Option<u64>,
+    /// "drop", "hold" or "suspend"; see `EngineState::live_pause_mode`.
+    live_pause: Option<String>,
+    /// Gap after a track that played to its end; 0 turns it off.
+    inter_track_silence_ms: Option<u32>,
+    /// How far "volume up" and "volume down" commands move the volume.
+    command_volume_step: Option<f32>,
+    /// "pcm" or "dop"; see `EngineState::dsd_mode`.
+    dsd_mode: Option<String>,
+    /// 176400 or 352800; other rates are ignored.
+    dsd_pcm_rate: Option<u32>,
+    /// Length from which files decode as they play; 0 turns that off.
+    /// Playback then starts at once, but such a file can't loop (repeat-one
+    /// or A/B), crossfade into the next track or splice onto it gaplessly;
+    /// files shorter than this, or every file at 0, keep those.
+    incremental_decode_secs: Option<f64>,
 }
 
 #[derive(Deserialize)]
@@ -519,11 +1418,23 @@ struct CaptureStartRequest {
     device_id: Option<String>,
     samplerate: Option<u32>,
     channels: Option<u16>,
+    monitor: Option<bool>,
+    /// Output device (from /devices) to monitor on; must differ from the
+    /// device being captured.
+    monitor_device_id: Option<usize>,
+    latency_ms: Option<u32>,
+    /// On Windows, record what the render endpoint `device_id` (the default
+    /// output when unset) plays through WASAPI loopback instead of going
+    /// through ffmpeg. Linux monitor sources are loopback already. Loopback
+    /// records in the endpoint's mix format, so `samplerate` and `channels`
+    /// must match it or be left unset.
+    #[serde(default)]
+    loopback: bool,
 }
 
 fn default_eq_bands() -> HashMap<String, f32> {
     let mut map = HashMap::new();
-    for band in ["31", "62", "125", "250", "500", "1k", "2k", "4k", "8k", "16k"] {
+    for (band, _) in eq::EQ_BANDS {
         map.insert(band.to_string(), 0.0);
     }
     map
@@ -542,6 +1453,25 @@ fn parse_spectrum_bins() -> usize {
         .and_then(|v| v.parse::<usize>().ok())
         .filter(|v| *v > 0)
         .unwrap_or(DEFAULT_SPECTRUM_BINS)
+        .min(MAX_SPECTRUM_BINS)
+}
+
+/// `NTMUSIC_SPECTRUM_FFT_SIZE`; anything but a power of two in range is
+/// ignored.
+fn parse_spectrum_fft_size() -> usize {
+    let Ok(value) = std::env::var("NTMUSIC_SPECTRUM_FFT_SIZE") else {
+        return DEFAULT_SPECTRUM_FFT_SIZE;
+    };
+    match value.parse::<usize>() {
+        Ok(size) if valid_spectrum_fft_size(size) => size,
+        _ => {
+            warn!(
+                "ignoring NTMUSIC_SPECTRUM_FFT_SIZE={}: not a power of two from {} to {}",
+                value, MIN_SPECTRUM_FFT_SIZE, MAX_SPECTRUM_FFT_SIZE
+            );
+            DEFAULT_SPECTRUM_FFT_SIZE
+        }
+    }
 }
 
 fn parse_control_capacity() -> usize {
@@ -570,7 +1500,7 @@ fn init_spectrum_shared(bins: usize) -> Option<Arc<Mutex<SpectrumShared>>> {
         error!("spectrum shm resize failed: {}", err);
         return None;
     }
-    let mmap = unsafe {
+    let mut mmap = unsafe {
         match MmapMut::map_mut(&file) {
             Ok(map) => map,
             Err(err) => {
@@ -579,7 +1509,52 @@ fn init_spectrum_shared(bins: usize) -> Option<Arc<Mutex<SpectrumShared>>> {
             }
         }
     };
-    Some(Arc::new(Mutex::new(SpectrumShared { mmap, bins })))
+    write_spectrum_header(&mut mmap, bins, false);
+    Some(Arc::new(Mutex::new(SpectrumShared {
+        file,
+        mmap,
+        bins,
+        peaks: false,
+    })))
+}
+
+/// Switch the spectrum shm to `bins`, with or without the peak-hold array
+/// after them, growing and remapping the file when it is too small. The
+/// file never shrinks: a reader still mapping the old length would fault on
+/// the truncated pages.
+fn resize_spectrum_shared(shared: &Option<Arc<Mutex<SpectrumShared>>>, bins: usize, peaks: bool) -> Result<()> {
+    let Some(shared) = shared else {
+        return Ok(());
+    };
+    let mut guard = shared.lock().map_err(|_| anyhow!("spectrum shm lock poisoned"))?;
+    if guard.bins == bins && guard.peaks == peaks {
+        return Ok(());
+    }
+    let arrays = if peaks { 2 } else { 1 };
+    let byte_len = SPECTRUM_HEADER_BYTES + arrays * bins * std::mem::size_of::<f32>();
+    let seq = unsafe { &*(guard.mmap.as_ptr() as *const AtomicU32) };
+    // Odd sequence: readers skip the frame while the layout changes.
+    let start_seq = seq.load(Ordering::Relaxed).wrapping_add(1);
+    seq.store(start_seq, Ordering::Release);
+    if byte_len > guard.mmap.len() {
+        guard.file.set_len(byte_len as u64).context("spectrum shm resize")?;
+        guard.mmap = unsafe { MmapMut::map_mut(&guard.file).context("spectrum shm remap")? };
+    }
+    guard.bins = bins;
+    guard.peaks = peaks;
+    write_spectrum_header(&mut guard.mmap, bins, peaks);
+    guard.mmap[SPECTRUM_HEADER_BYTES..].fill(0);
+    let seq = unsafe { &*(guard.mmap.as_ptr() as *const AtomicU32) };
+    seq.store(start_seq.wrapping_add(1), Ordering::Release);
+    Ok(())
+}
+
+/// Everything in the spectrum header but the seqlock counter.
+fn write_spectrum_header(mmap: &mut MmapMut, bins: usize, peaks: bool) {
+    let flags = if peaks { SPECTRUM_FLAG_PEAKS } else { 0 };
+    mmap[SPECTRUM_BINS_OFFSET..SPECTRUM_VERSION_OFFSET].copy_from_slice(&(bins as u32).to_ne_bytes());
+    mmap[SPECTRUM_VERSION_OFFSET..SPECTRUM_FLAGS_OFFSET].copy_from_slice(&SPECTRUM_SHM_VERSION.to_ne_bytes());
+    mmap[SPECTRUM_FLAGS_OFFSET..SPECTRUM_HEADER_BYTES].copy_from_slice(&flags.to_ne_bytes());
 }
 
 fn init_control_shared(capacity: usize) -> Option<Arc<Mutex<ControlShared>>> {
@@ -620,17 +1595,36 @@ fn init_control_shared(capacity: usize) -> Option<Arc<Mutex<ControlShared>>> {
     Some(Arc::new(Mutex::new(ControlShared { mmap, capacity })))
 }
 
+/// Five seconds of 48 kHz stereo until a stream sizes the ring itself.
+const DEFAULT_RING_SAMPLES: usize = 48_000 * 2 * 5;
+
 fn create_shared_state() -> SharedState {
-    let rb = HeapRb::<f32>::new(48_000 * 2 * 5);
+    let rb = HeapRb::<f32>::new(DEFAULT_RING_SAMPLES);
     let (producer, consumer) = rb.split();
     let (tx, _rx) = broadcast::channel(128);
-    let spectrum_bins = parse_spectrum_bins();
+    let spectrum_fft_size = parse_spectrum_fft_size();
+    let spectrum_bins = parse_spectrum_bins().min(spectrum_fft_size / 2);
     let spectrum_shared = init_spectrum_shared(spectrum_bins);
     let control_capacity = parse_control_capacity();
     let control_shared = init_control_shared(control_capacity);
+    let mut state = initial_state();
+    state.spectrum_bins = spectrum_bins;
+    set_spectrum_fft_size(&mut state, spectrum_fft_size);
+    state.track_gains_path = track_gains_path();
+    state.track_gains = load_track_gains(&state.track_gains_path);
+    state.scan_cache_path = scan_cache_path();
+    state.ring_capacity_frames = DEFAULT_RING_SAMPLES / state.channels.max(1);
+    state.audio_extensions = parse_audio_extensions();
+    // Tests never read or write the user's settings.
+    state.settings_path = if cfg!(test) { None } else { settings_path() };
+    if let Some(path) = state.settings_path.clone() {
+        let settings = PersistedSettings::load(&path);
+        let devices = if settings.device.is_some() { output_device_keys() } else { Vec::new() };
+        settings.apply(&mut state, &devices);
+    }
 
     SharedState {
-        inner: Arc::new(Mutex::new(initial_state())),
+        inner: Arc::new(Mutex::new(state)),
         tx,
         producer: Arc::new(Mutex::new(producer)),
         consumer: Arc::new(Mutex::new(consumer)),
@@ -639,12 +1633,50 @@ fn create_shared_state() -> SharedState {
         output_scratch: Arc::new(Mutex::new(Vec::new())),
         stream_process: Arc::new(Mutex::new(None)),
         stream_thread: Arc::new(Mutex::new(None)),
+        stream_stop: Arc::new(AtomicBool::new(false)),
         spectrum_shared,
-        spectrum_bins,
         control_shared,
+        state_shared: init_state_shared(),
+        scan_active: Arc::new(AtomicBool::new(false)),
+        scan_cancel: Arc::new(AtomicBool::new(false)),
+        preloaded: Arc::new(Mutex::new(None)),
+        load_generation: Arc::new(AtomicU64::new(0)),
+        output_lost: Arc::new(AtomicBool::new(false)),
+        devices_changed: Arc::new(tokio::sync::Notify::new()),
+        settings_dirty: Arc::new(AtomicBool::new(false)),
+        track_gains_save: Arc::new(Mutex::new(())),
+    }
+}
+
+/// Length of the current file in frames, whether it sits whole in `data`
+/// or decodes as it plays.
+fn file_frames(state: &EngineState) -> usize {
+    match state.incremental {
+        Some(incremental) => incremental.frames,
+        None => state.data.len() / state.channels.max(1),
     }
 }
 
+/// Move a file's playback to `frame`. An incremental decode is asked to
+/// pick up from there.
+fn set_file_position(state: &mut EngineState, frame: usize) {
+    state.position = frame;
+    if let Some(incremental) = state.incremental.as_mut() {
+        incremental.seek = Some(frame);
+    }
+}
+
+/// Move a file's play position to `seconds`, clamped to `[0, duration]`, and
+/// return where it landed. Callers check for file mode and a sample rate.
+fn seek_clamped(state: &mut EngineState, seconds: f64) -> f64 {
+    let max_pos = file_frames(state);
+    let new_pos = (seconds.max(0.0) * state.sample_rate as f64) as usize;
+    set_file_position(state, new_pos.min(max_pos));
+    state.eq_filters.reset();
+    cancel_crossfade(state);
+    state.position as f64 / state.sample_rate as f64
+}
+
 fn drain_control_commands(state: &mut EngineState, control: &ControlShared) {
     let header_ptr = control.mmap.as_ptr() as *const u8;
     let write_idx = unsafe { &*(header_ptr as *const AtomicU32) };
@@ -662,6 +1694,19 @@ fn drain_control_commands(state: &mut EngineState, control: &ControlShared) {
         let cmd_ptr = unsafe { header_ptr.add(cmd_offset) };
         let cmd = unsafe { *(cmd_ptr as *const u32) };
         let value = unsafe { *(cmd_ptr.add(4) as *const f32) };
+        let cuts = matches!(
+            cmd,
+            CONTROL_CMD_PAUSE | CONTROL_CMD_STOP | CONTROL_CMD_SEEK | CONTROL_CMD_SEEK_RELATIVE | CONTROL_CMD_RESTART
+        );
+        if cuts && transport_sounding(state) && state.transport_fade_ms > 0 {
+            // Fade out first; the command stays queued until a later
+            // callback finds the output silent.
+            state.transport_fading_out = true;
+            break;
+        }
+        if cuts {
+            state.transport_fading_out = false;
+        }
         match cmd {
             CONTROL_CMD_PLAY => {
                 state.is_playing = true;
@@ -674,18 +1719,38 @@ fn drain_control_commands(state: &mut EngineState, control: &ControlShared) {
                 state.is_playing = false;
                 state.is_paused = false;
                 if state.mode == "file" {
-                    state.position = 0;
+                    set_file_position(state, 0);
                 }
             }
-            CONTROL_CMD_SEEK => {
-                if state.mode == "file" && state.sample_rate > 0 {
-                    let new_pos = (value.max(0.0) * state.sample_rate as f32) as usize;
-                    let max_pos = state.data.len() / state.channels.max(1);
-                    state.position = new_pos.min(max_pos);
-                }
+            CONTROL_CMD_SEEK if state.mode == "file" && state.sample_rate > 0 => {
+                seek_clamped(state, value as f64);
+            }
+            CONTROL_CMD_SEEK_RELATIVE if state.mode == "file" && state.sample_rate > 0 => {
+                let current = state.position as f64 / state.sample_rate as f64;
+                seek_clamped(state, current + value as f64);
+            }
+            // Restarting ffmpeg can't happen on the audio thread either.
+            CONTROL_CMD_SEEK if state.mode == "stream" => state.stream_seek_pending = Some(value as f64),
+            CONTROL_CMD_SEEK_RELATIVE if state.mode == "stream" && state.sample_rate > 0 => {
+                let current = state.played_frames as f64 / state.sample_rate as f64;
+                state.stream_seek_pending = Some(current + value as f64);
             }
-            CONTROL_CMD_VOLUME => {
-                state.volume = value.clamp(0.0, 1.0);
+            CONTROL_CMD_VOLUME => set_volume_target(state, value),
+            CONTROL_CMD_RESTART => {
+                state.is_playing = true;
+                state.is_paused = false;
+                state.eq_filters.reset();
+                cancel_crossfade(state);
+                match state.mode.as_str() {
+                    "file" => {
+                        set_file_position(state, 0);
+                        state.played_frames = 0;
+                    }
+                    // Restarting ffmpeg can't happen on the audio thread; the
+                    // background loop picks this up.
+                    "stream" => state.stream_restart_pending = true,
+                    _ => state.played_frames = 0,
+                }
             }
             _ => {}
         }
@@ -695,7 +1760,78 @@ fn drain_control_commands(state: &mut EngineState, control: &ControlShared) {
     read_idx.store(read, Ordering::Release);
 }
 
-fn write_spectrum_shared(shared: &Option<Arc<Mutex<SpectrumShared>>>, spectrum: &[f32]) {
+fn init_state_shared() -> Option<Arc<Mutex<MmapMut>>> {
+    let path = match std::env::var("NTMUSIC_STATE_SHM") {
+        Ok(value) if !value.is_empty() => value,
+        _ => return None,
+    };
+    let file = match OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path) {
+        Ok(file) => file,
+        Err(err) => {
+            error!("state shm open failed: {}", err);
+            return None;
+        }
+    };
+    if let Err(err) = file.set_len(STATE_SHM_BYTES as u64) {
+        error!("state shm resize failed: {}", err);
+        return None;
+    }
+    let mut mmap = unsafe {
+        match MmapMut::map_mut(&file) {
+            Ok(map) => map,
+            Err(err) => {
+                error!("state shm map failed: {}", err);
+                return None;
+            }
+        }
+    };
+    mmap[4..8].copy_from_slice(&STATE_SHM_VERSION.to_ne_bytes());
+    Some(Arc::new(Mutex::new(mmap)))
+}
+
+/// Everything after the seqlock counter, laid out as documented on
+/// `STATE_SHM_VERSION`.
+fn encode_state_shm(state: &EngineState) -> [u8; STATE_SHM_BYTES - 4] {
+    let mut flags = 0;
+    if state.is_playing {
+        flags |= STATE_FLAG_PLAYING;
+    }
+    if state.is_paused {
+        flags |= STATE_FLAG_PAUSED;
+    }
+    if matches!(state.mode.as_str(), "stream" | "capture") {
+        flags |= STATE_FLAG_LIVE;
+    }
+    if state.device_released {
+        flags |= STATE_FLAG_DEVICE_RELEASED;
+    }
+    let mut out = [0u8; STATE_SHM_BYTES - 4];
+    out[0..4].copy_from_slice(&STATE_SHM_VERSION.to_ne_bytes());
+    out[4..12].copy_from_slice(&(state.position as u64).to_ne_bytes());
+    out[12..20].copy_from_slice(&state.played_frames.to_ne_bytes());
+    out[20..28].copy_from_slice(&(state.buffered_frames as u64).to_ne_bytes());
+    out[28..36].copy_from_slice(&state.underrun_count.to_ne_bytes());
+    out[36..40].copy_from_slice(&state.sample_rate.to_ne_bytes());
+    out[40..44].copy_from_slice(&state.volume.to_ne_bytes());
+    out[44..48].copy_from_slice(&flags.to_ne_bytes());
+    out[52..60].copy_from_slice(&state.duration.to_ne_bytes());
+    out
+}
+
+fn write_state_shared(shared: &Mutex<MmapMut>, fields: &[u8; STATE_SHM_BYTES - 4]) {
+    let Ok(mut mmap) = shared.lock() else {
+        return;
+    };
+    let seq = unsafe { &*(mmap.as_ptr() as *const AtomicU32) };
+    let start_seq = seq.load(Ordering::Relaxed).wrapping_add(1);
+    seq.store(start_seq, Ordering::Release);
+    mmap[4..STATE_SHM_BYTES].copy_from_slice(fields);
+    let seq = unsafe { &*(mmap.as_ptr() as *const AtomicU32) };
+    seq.store(start_seq.wrapping_add(1), Ordering::Release);
+}
+
+/// `peaks` lands after the spectrum when the shm was sized for it.
+fn write_spectrum_shared(shared: &Option<Arc<Mutex<SpectrumShared>>>, spectrum: &[f32], peaks: Option<&[f32]>) {
     let Some(shared) = shared else {
         return;
     };
@@ -709,19 +1845,17 @@ fn write_spectrum_shared(shared: &Option<Arc<Mutex<SpectrumShared>>>, spectrum:
     if bins == 0 {
         return;
     }
-    let len = bins.min(spectrum.len());
+    let peak_bins = if guard.peaks { guard.bins.min(available_bins - bins) } else { 0 };
     let seq = unsafe { &*(guard.mmap.as_ptr() as *const AtomicU32) };
     let data_ptr = unsafe { guard.mmap.as_mut_ptr().add(SPECTRUM_HEADER_BYTES) as *mut f32 };
-    let dst = unsafe { std::slice::from_raw_parts_mut(data_ptr, bins) };
+    let dst = unsafe { std::slice::from_raw_parts_mut(data_ptr, bins + peak_bins) };
+    let (dst, peak_dst) = dst.split_at_mut(bins);
     let start_seq = seq.load(Ordering::Relaxed).wrapping_add(1);
     seq.store(start_seq, Ordering::Release);
-    if len > 0 {
-        dst[..len].copy_from_slice(&spectrum[..len]);
-    }
-    if len < bins {
-        for value in &mut dst[len..] {
-            *value = 0.0;
-        }
+    for (dst, values) in [(dst, spectrum), (peak_dst, peaks.unwrap_or(&[]))] {
+        let len = dst.len().min(values.len());
+        dst[..len].copy_from_slice(&values[..len]);
+        dst[len..].fill(0.0);
     }
     seq.store(start_seq.wrapping_add(1), Ordering::Release);
 }
@@ -865,6 +1999,21 @@ fn detect_soxr_available() -> bool {
     soxr_library().is_some()
 }
 
+static FFMPEG_AVAILABLE: OnceLock<bool> = OnceLock::new();
+
+fn detect_ffmpeg_available() -> bool {
+    *FFMPEG_AVAILABLE.get_or_init(|| {
+        Command::new(ffmpeg_path())
+            .arg("-version")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .map(|status| status.success())
+            .unwrap_or(false)
+    })
+}
+
 fn initial_state() -> EngineState {
     EngineState {
         is_playing: false,
@@ -872,44 +2021,128 @@ fn initial_state() -> EngineState {
         mode: "idle".to_string(),
         file_path: None,
         data: Vec::new(),
+        incremental: None,
+        incremental_decode_secs: DEFAULT_INCREMENTAL_DECODE_SECS,
         channels: 2,
         sample_rate: 48_000,
         source_channels: 2,
         source_sample_rate: 48_000,
         source_bit_depth: None,
+        partial_decode: None,
+        gapless_trim_enabled: true,
+        gapless_trim: None,
         position: 0,
         played_frames: 0,
         duration: 0.0,
         volume: 1.0,
+        volume_current: 1.0,
+        transport_fade_ms: DEFAULT_TRANSPORT_FADE_MS,
+        transport_gain: 0.0,
+        transport_fading_out: false,
+        volume_mode: "software".to_string(),
+        hardware_volume_active: false,
         device_id: None,
+        selected_device: None,
+        device_fallback: false,
+        hostapi: None,
         exclusive_mode: false,
+        exclusive_format: "f32".to_string(),
+        output_latency_ms: None,
+        idle_release_secs: None,
+        live_pause_mode: "drop".to_string(),
+        inter_track_silence_ms: 0,
+        command_volume_step: DEFAULT_COMMAND_VOLUME_STEP,
+        muted_volume: None,
+        dsd_mode: "pcm".to_string(),
+        dsd_pcm_rate: dsd::DSD_PCM_RATES[0],
+        dsd_output: None,
+        crossfade_ms: 0,
+        crossfade: None,
+        gap_frames: 0,
+        track_gains: HashMap::new(),
+        track_gains_path: PathBuf::new(),
+        settings_path: None,
+        scan_cache_path: PathBuf::new(),
+        track_gain_db: 0.0,
+        idle_since: None,
+        device_released: false,
+        capture_monitor: true,
+        capture_saved_output: None,
+        output_config: None,
         eq_enabled: false,
         eq_type: "IIR".to_string(),
         eq_bands: default_eq_bands(),
+        eq_filters: EqFilters::default(),
         dither_enabled: true,
         dither_type: "tpdf".to_string(),
         dither_bits: 24,
         replaygain_enabled: true,
+        replaygain: ReplayGainInfo::default(),
+        replaygain_preamp_db: 0.0,
+        replaygain_prevent_clipping: true,
+        replaygain_mode: "auto".to_string(),
+        replaygain_applied_mode: "track".to_string(),
+        replaygain_gain: 1.0,
+        auto_level_enabled: false,
+        auto_level_target_db: AUTO_LEVEL_DEFAULT_TARGET_DB,
+        auto_level_max_gain_db: AUTO_LEVEL_DEFAULT_MAX_GAIN_DB,
+        auto_level_gain: 1.0,
+        auto_level_power: (db_to_linear(AUTO_LEVEL_DEFAULT_TARGET_DB) as f64).powi(2),
         resampler_mode: "auto".to_string(),
         resampler_quality: "hq".to_string(),
+        resampler_info: None,
         soxr_available: detect_soxr_available(),
+        live_resampler: LiveResamplerSlot::default(),
         limiter_enabled: false,
         limiter_threshold: 0.98,
+        invert_polarity: false,
+        invert_channels: Vec::new(),
+        downmix: "none".to_string(),
         target_samplerate: None,
         stream_url: None,
         stream_status: "idle".to_string(),
         stream_error: None,
+        stream_restart_pending: false,
+        stream_seekable: None,
+        stream_seek_pending: None,
+        server_port: None,
+        output_channels: 0,
         buffered_frames: 0,
+        ring_capacity_frames: 0,
         buffer_max_ms: 5000,
         underrun_count: 0,
+        dropped_sample_count: 0,
+        clip_count: 0,
+        audio_extensions: default_audio_extensions(),
         library: Vec::new(),
         queue: Vec::new(),
         queue_index: None,
-        last_output_chunk: vec![0.0; SPECTRUM_FFT_SIZE],
+        repeat_mode: "off".to_string(),
+        loop_region: None,
+        shuffle: false,
+        shuffle_rng: initial_dither_seed(),
+        shuffle_history: Vec::new(),
+        auto_advance: false,
+        track_finished: false,
+        lookahead: LookAhead::Idle,
+        track_spliced: false,
+        retired_samples: Vec::new(),
+        spectrum_history: vec![0.0; spectrum_history_frames(DEFAULT_SPECTRUM_FFT_SIZE) * SPECTRUM_HISTORY_CHANNELS],
+        spectrum_history_channels: 1,
+        spectrum_history_pos: 0,
+        spectrum_history_written: 0,
         dither_rng: initial_dither_seed(),
         dither_shape_err1: [0.0; MAX_DITHER_CHANNELS],
         dither_shape_err2: [0.0; MAX_DITHER_CHANNELS],
         spectrum_ws_enabled: true,
+        spectrum_bins: DEFAULT_SPECTRUM_BINS,
+        spectrum_gate_db: DEFAULT_SPECTRUM_GATE_DB,
+        spectrum_fft_size: DEFAULT_SPECTRUM_FFT_SIZE,
+        spectrum_window: "hann".to_string(),
+        spectrum_attack: 0.0,
+        spectrum_release: 0.0,
+        spectrum_peak_fall: 0.0,
+        spectrum_source: "mono".to_string(),
     }
 }
 
@@ -948,25 +2181,92 @@ fn build_state_view(state: &EngineState) -> PlaybackState {
         source_bit_depth: state.source_bit_depth,
         volume: state.volume,
         device_id: state.device_id,
+        hostapi: state.hostapi.clone(),
         exclusive_mode: state.exclusive_mode,
+        exclusive_format: state.exclusive_format.clone(),
+        volume_mode: state.volume_mode.clone(),
+        volume_mode_effective: if state.hardware_volume_active { "hardware" } else { "software" },
+        output_latency_ms: state.output_latency_ms,
         eq_type: state.eq_type.clone(),
+        eq_implementation: (state.eq_enabled && !state.eq_filters.is_flat()).then(|| state.eq_filters.kind().label()),
+        eq_fir_taps: state.eq_filters.fir_taps(),
+        eq_latency_ms: eq_latency_ms(state),
         dither_enabled: state.dither_enabled,
         dither_type: state.dither_type.clone(),
         dither_bits: state.dither_bits,
         replaygain_enabled: state.replaygain_enabled,
+        replaygain_preamp_db: state.replaygain_preamp_db,
+        replaygain_prevent_clipping: state.replaygain_prevent_clipping,
+        replaygain_mode: state.replaygain_mode.clone(),
+        replaygain_applied_mode: state.replaygain_applied_mode.clone(),
+        replaygain_gain: state.replaygain_gain,
+        replaygain_tags: state.replaygain,
+        auto_level_enabled: state.auto_level_enabled,
+        auto_level_target_db: state.auto_level_target_db,
+        auto_level_max_gain_db: state.auto_level_max_gain_db,
+        auto_level_gain_db: linear_to_db(state.auto_level_gain as f32),
+        auto_level_active: auto_level_active(state),
         resampler_mode: state.resampler_mode.clone(),
         resampler_quality: state.resampler_quality.clone(),
+        resampler_info: state.resampler_info.clone(),
         soxr_available: state.soxr_available,
         limiter_enabled: state.limiter_enabled,
         limiter_threshold: state.limiter_threshold,
+        limiter_threshold_db: linear_to_db(state.limiter_threshold),
+        invert_polarity: state.invert_polarity,
+        invert_channels: state.invert_channels.clone(),
+        downmix: state.downmix.clone(),
         eq_enabled: state.eq_enabled,
         eq_bands: state.eq_bands.clone(),
         target_samplerate: state.target_samplerate,
         mode: state.mode.clone(),
         stream_status: state.stream_status.clone(),
+        stream_seekable: state.stream_seekable,
         buffered_ms,
+        buffer_fill: buffer_fill_ratio(state),
         underruns: state.underrun_count,
+        dropped_samples: state.dropped_sample_count,
+        clipped_samples: state.clip_count,
         spectrum_ws_enabled: state.spectrum_ws_enabled,
+        spectrum_bins: state.spectrum_bins,
+        spectrum_gate_db: state.spectrum_gate_db,
+        spectrum_fft_size: state.spectrum_fft_size,
+        spectrum_window: state.spectrum_window.clone(),
+        spectrum_attack: state.spectrum_attack,
+        spectrum_release: state.spectrum_release,
+        spectrum_peak_fall: state.spectrum_peak_fall,
+        spectrum_source: state.spectrum_source.clone(),
+        partial_decode: state.partial_decode.clone(),
+        gapless_trim_enabled: state.gapless_trim_enabled,
+        gapless_trim: state.gapless_trim,
+        incremental_decode: state.mode == "file" && state.incremental.is_some(),
+        incremental_decode_secs: state.incremental_decode_secs,
+        output_config: state.output_config.clone(),
+        processing_chain: PROCESSING_CHAIN.iter().map(|stage| stage.name()).collect(),
+        idle_release_secs: state.idle_release_secs,
+        live_pause_mode: state.live_pause_mode.clone(),
+        inter_track_silence_ms: state.inter_track_silence_ms,
+        command_volume_step: state.command_volume_step,
+        dsd_mode: state.dsd_mode.clone(),
+        dsd_pcm_rate: state.dsd_pcm_rate,
+        dsd_output: state.dsd_output.map(DsdOutput::label),
+        gap_remaining_ms: if state.sample_rate > 0 {
+            state.gap_frames as f64 * 1000.0 / state.sample_rate as f64
+        } else {
+            0.0
+        },
+        track_gain_db: state.track_gain_db,
+        crossfade_ms: state.crossfade_ms,
+        crossfading: state.crossfade.as_ref().is_some_and(Crossfade::active),
+        transport_fade_ms: state.transport_fade_ms,
+        repeat_mode: state.repeat_mode.clone(),
+        loop_start: state.loop_region.map(|(start, _)| start as f64 / state.sample_rate.max(1) as f64),
+        loop_end: state.loop_region.map(|(_, end)| end as f64 / state.sample_rate.max(1) as f64),
+        shuffle: state.shuffle,
+        auto_advance: state.auto_advance,
+        device_released: state.device_released,
+        device_fallback: state.device_fallback,
+        capture_monitor: state.capture_monitor,
     }
 }
 
@@ -1007,21 +2307,75 @@ fn position_from_state(shared: &SharedState) -> PositionInfo {
     }
 }
 
-fn is_supported_audio_path(path: &Path) -> bool {
-    let ext = path
-        .extension()
-        .and_then(|e| e.to_str())
-        .unwrap_or("")
-        .to_ascii_lowercase();
-    matches!(
-        ext.as_str(),
-        "mp3" | "flac" | "wav" | "ogg" | "m4a" | "aac" | "aiff" | "alac"
-    )
+const DEFAULT_AUDIO_EXTENSIONS: &[&str] = &["mp3", "flac", "wav", "ogg", "opus", "m4a", "aac", "aiff", "alac", "dsf"];
+
+fn default_audio_extensions() -> Vec<String> {
+    DEFAULT_AUDIO_EXTENSIONS.iter().map(|ext| ext.to_string()).collect()
 }
 
-fn track_title_from_path(path: &Path) -> Option<String> {
-    path.file_stem()
-        .map(|s| s.to_string_lossy().to_string())
+/// Lowercase and strip a leading dot; `None` for anything that doesn't look
+/// like a file extension.
+fn normalize_audio_extension(ext: &str) -> Option<String> {
+    let ext = ext.trim().trim_start_matches('.').to_ascii_lowercase();
+    let plausible = !ext.is_empty() && ext.len() <= 10 && ext.chars().all(|c| c.is_ascii_alphanumeric());
+    plausible.then_some(ext)
+}
+
+fn normalize_audio_extensions<S: AsRef<str>>(list: &[S]) -> Result<Vec<String>> {
+    let mut extensions = Vec::new();
+    for raw in list {
+        let ext = normalize_audio_extension(raw.as_ref())
+            .ok_or_else(|| anyhow!("invalid extension: {:?}", raw.as_ref()))?;
+        if !extensions.contains(&ext) {
+            extensions.push(ext);
+        }
+    }
+    if extensions.is_empty() {
+        return Err(anyhow!("at least one extension is required"));
+    }
+    Ok(extensions)
+}
+
+/// `NTMUSIC_AUDIO_EXTENSIONS`, comma separated, replaces the default list.
+fn parse_audio_extensions() -> Vec<String> {
+    let Ok(value) = std::env::var("NTMUSIC_AUDIO_EXTENSIONS") else {
+        return default_audio_extensions();
+    };
+    let list: Vec<&str> = value.split(',').filter(|ext| !ext.trim().is_empty()).collect();
+    normalize_audio_extensions(&list).unwrap_or_else(|err| {
+        warn!("ignoring NTMUSIC_AUDIO_EXTENSIONS: {}", err);
+        default_audio_extensions()
+    })
+}
+
+fn is_supported_audio_path(path: &Path, extensions: &[String]) -> bool {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    extensions.contains(&ext)
+}
+
+fn track_title_from_path(path: &Path) -> Option<String> {
+    path.file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+}
+
+/// "3" or "3/12" -> 3.
+fn parse_position_tag(value: &str) -> Option<u32> {
+    value.split('/').next()?.trim().parse().ok().filter(|n| *n > 0)
+}
+
+/// The year at the start of a date tag: "1997", "1997-03-14" or
+/// "1997-03-14T10:00:00".
+fn parse_year_tag(value: &str) -> Option<u32> {
+    let value = value.trim();
+    let digits = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    if digits != 4 {
+        return None;
+    }
+    value[..4].parse().ok().filter(|year| *year > 0)
 }
 
 fn tag_value_to_string(tag: &symphonia::core::meta::Tag) -> Option<String> {
@@ -1033,2484 +2387,12835 @@ fn tag_value_to_string(tag: &symphonia::core::meta::Tag) -> Option<String> {
     }
 }
 
+/// Split one tag value into its artists. ID3v2.4 separates values with NUL
+/// and many taggers write `;`; `/` is left alone since it appears in names.
+fn split_artist_values(value: &str) -> Vec<String> {
+    value
+        .split(['\0', ';'])
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn push_unique(values: &mut Vec<String>, value: String) {
+    if !values.iter().any(|v| v.eq_ignore_ascii_case(&value)) {
+        values.push(value);
+    }
+}
+
+#[derive(Default)]
+struct ArtistTags {
+    artist: Vec<String>,
+    artists: Vec<String>,
+    album_artist: Option<String>,
+    sort_album_artist: Option<String>,
+}
+
+impl ArtistTags {
+    fn add(&mut self, tag: &symphonia::core::meta::Tag) {
+        let Some(value) = tag_value_to_string(tag) else {
+            return;
+        };
+        let key = tag.key.to_ascii_uppercase();
+        match tag.std_key {
+            Some(StandardTagKey::Artist) => {
+                for v in split_artist_values(&value) {
+                    push_unique(&mut self.artist, v);
+                }
+            }
+            Some(StandardTagKey::AlbumArtist) => {
+                self.album_artist.get_or_insert(value.trim().to_string());
+            }
+            Some(StandardTagKey::SortAlbumArtist) => {
+                self.sort_album_artist.get_or_insert(value.trim().to_string());
+            }
+            // Picard's ARTISTS (Vorbis, TXXX) lists each credited artist
+            // while ARTIST keeps the display form ("A feat. B").
+            _ if key == "ARTISTS" => {
+                for v in split_artist_values(&value) {
+                    push_unique(&mut self.artists, v);
+                }
+            }
+            // Vorbis has no fixed album-artist key; these are the common spellings.
+            _ if matches!(key.as_str(), "ALBUM ARTIST" | "ALBUM_ARTIST") => {
+                self.album_artist.get_or_insert(value.trim().to_string());
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns `(artist, artists, album_artist)`.
+    fn finish(self) -> (Option<String>, Vec<String>, Option<String>) {
+        let artist = if self.artist.is_empty() {
+            None
+        } else {
+            Some(self.artist.join(", "))
+        };
+        let artists = if self.artists.is_empty() {
+            self.artist
+        } else {
+            self.artists
+        };
+        let album_artist = self
+            .album_artist
+            .or(self.sort_album_artist)
+            .filter(|v| !v.is_empty());
+        (artist, artists, album_artist)
+    }
+}
+
 fn read_library_track(path: &Path) -> Result<LibraryTrack> {
+    if dsd::is_dsf_path(path) {
+        return read_dsf_library_track(path);
+    }
     let file = File::open(path).with_context(|| format!("open {:?}", path))?;
     let mss = MediaSourceStream::new(Box::new(file), Default::default());
     let mut hint = Hint::new();
     if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
         hint.with_extension(ext);
     }
-    let probed = symphonia::default::get_probe()
+    let mut probed = symphonia::default::get_probe()
         .format(&hint, mss, &FormatOptions::default(), &MetadataOptions::default())
         .with_context(|| format!("probe {:?}", path))?;
+    let tags = probed_tags(&mut probed);
+    let id3_tag = has_id3_tag(&mut probed);
     let mut format = probed.format;
 
     let mut title = None;
-    let mut artist = None;
+    let mut artist_tags = ArtistTags::default();
     let mut album = None;
+    let mut track_number = None;
+    let mut disc_number = None;
+    let mut year = None;
+    let mut original_year = None;
+    let mut genre = None;
     if let Some(rev) = format.metadata().current() {
         for tag in rev.tags() {
             if title.is_none() && matches!(tag.std_key, Some(StandardTagKey::TrackTitle)) {
                 title = tag_value_to_string(tag);
             }
-            if artist.is_none() && matches!(tag.std_key, Some(StandardTagKey::Artist)) {
-                artist = tag_value_to_string(tag);
-            }
             if album.is_none() && matches!(tag.std_key, Some(StandardTagKey::Album)) {
                 album = tag_value_to_string(tag);
             }
+            if track_number.is_none() && matches!(tag.std_key, Some(StandardTagKey::TrackNumber)) {
+                track_number = parse_position_tag(&tag.value.to_string());
+            }
+            if disc_number.is_none() && matches!(tag.std_key, Some(StandardTagKey::DiscNumber)) {
+                disc_number = parse_position_tag(&tag.value.to_string());
+            }
         }
     }
-
-    let duration = format
-        .default_track()
-        .and_then(|track| {
-            let params = &track.codec_params;
-            let frames = params.n_frames?;
-            let sample_rate = params.sample_rate?;
-            if sample_rate == 0 {
-                None
-            } else {
-                Some(frames as f64 / sample_rate as f64)
+    // ID3 tags, as on MP3s, come with the probe rather than the container.
+    for tag in &tags {
+        artist_tags.add(tag);
+        match tag.std_key {
+            Some(StandardTagKey::Date | StandardTagKey::ReleaseDate) if year.is_none() => {
+                year = parse_year_tag(&tag.value.to_string());
             }
-        })
+            Some(StandardTagKey::OriginalDate) if original_year.is_none() => {
+                original_year = parse_year_tag(&tag.value.to_string());
+            }
+            Some(StandardTagKey::Genre) if genre.is_none() => {
+                genre = tag_value_to_string(tag).map(|value| value.trim().to_string());
+            }
+            _ => {}
+        }
+    }
+    let (artist, artists, album_artist) = artist_tags.finish();
+
+    let params = format.default_track().map(|track| &track.codec_params);
+    let sample_rate = params.and_then(|p| p.sample_rate).filter(|rate| *rate > 0);
+    let duration = params
+        .and_then(|p| p.n_frames)
+        .zip(sample_rate)
+        .map(|(frames, rate)| frames as f64 / rate as f64)
         .unwrap_or(0.0);
+    let bit_depth = params.and_then(bit_depth_from_codec);
+    let channels = params.and_then(|p| p.channels).map(|c| c.count() as u32);
+    let gain = read_file_replaygain(path, &tags, id3_tag, params);
+    let decodable = params.is_some_and(|p| symphonia::default::get_codecs().get_codec(p.codec).is_some());
 
     Ok(LibraryTrack {
         path: path.to_string_lossy().to_string(),
         title: title.or_else(|| track_title_from_path(path)),
         artist,
+        artists,
+        album_artist,
         album,
+        track_number,
+        disc_number,
+        year: year.or(original_year),
+        genre,
         duration,
+        sample_rate,
+        bit_depth,
+        channels,
+        track_gain: gain.track_gain_db.map(|db| db_to_linear(db + gain.output_gain_db.unwrap_or(0.0))),
+        album_gain: gain.album_gain_db.map(|db| db_to_linear(db + gain.output_gain_db.unwrap_or(0.0))),
+        decodable,
     })
 }
 
-fn scan_library_impl(path: &str) -> Result<Vec<LibraryTrack>> {
-    let root = Path::new(path);
-    if !root.exists() {
-        return Err(anyhow!("scan path not found"));
+/// How often a suspended stream reader checks whether it may read again.
+const STREAM_SUSPEND_POLL: Duration = Duration::from_millis(20);
+/// Minimum gap between `scan_progress` events.
+const SCAN_PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How far a scan walks from its root.
+#[derive(Debug, Clone, Copy)]
+struct ScanScope {
+    /// Off lists only the files directly inside the root.
+    recursive: bool,
+    /// Follow symlinked files and directories; walkdir skips links that
+    /// lead back into their own ancestors.
+    follow_links: bool,
+}
+
+impl Default for ScanScope {
+    fn default() -> Self {
+        ScanScope {
+            recursive: true,
+            follow_links: true,
+        }
     }
-    let mut tracks = Vec::new();
-    for entry in WalkDir::new(root).follow_links(true).into_iter().filter_map(|e| e.ok()) {
+}
+
+/// What a scan found to probe, listed before any file is opened so that
+/// progress can be reported against a total.
+struct ScanListing {
+    files: Vec<PathBuf>,
+    /// Every file walked, audio or not.
+    files_seen: usize,
+    cancelled: bool,
+}
+
+struct ScanOutcome {
+    tracks: Vec<LibraryTrack>,
+    files_seen: usize,
+    /// Tracks taken from the scan cache rather than probed.
+    cached: usize,
+    cancelled: bool,
+}
+
+/// Walk `root` for the audio files a scan should probe, without opening any.
+fn list_scan_files(root: &Path, extensions: &[String], scope: ScanScope, cancel: &AtomicBool) -> ScanListing {
+    let mut listing = ScanListing {
+        files: Vec::new(),
+        files_seen: 0,
+        cancelled: false,
+    };
+    let mut walker = WalkDir::new(root).follow_links(scope.follow_links);
+    if !scope.recursive {
+        walker = walker.max_depth(1);
+    }
+    // Linked trees can reach the same directory or file by several paths;
+    // walkdir only catches links back into an ancestor, so remember what has
+    // been walked by canonical path and visit each physical file once.
+    let mut visited_dirs: HashSet<PathBuf> = HashSet::new();
+    let mut visited_files: HashSet<PathBuf> = HashSet::new();
+    let entries = walker.into_iter().filter_entry(|entry| {
+        if !entry.file_type().is_dir() {
+            return true;
+        }
+        match std::fs::canonicalize(entry.path()) {
+            Ok(canonical) => visited_dirs.insert(canonical),
+            Err(_) => false,
+        }
+    });
+    for entry in entries.filter_map(|e| e.ok()) {
+        if cancel.load(Ordering::Relaxed) {
+            listing.cancelled = true;
+            break;
+        }
         if !entry.file_type().is_file() {
             continue;
         }
         let file_path = entry.path();
-        if !is_supported_audio_path(file_path) {
+        let canonical = std::fs::canonicalize(file_path).unwrap_or_else(|_| file_path.to_path_buf());
+        if !visited_files.insert(canonical) {
             continue;
         }
-        let track = match read_library_track(file_path) {
-            Ok(track) => track,
-            Err(_) => LibraryTrack {
-                path: file_path.to_string_lossy().to_string(),
-                title: track_title_from_path(file_path),
-                artist: None,
-                album: None,
-                duration: 0.0,
-            },
-        };
-        tracks.push(track);
-    }
-    Ok(tracks)
-}
-
-fn queue_add_impl(shared: &SharedState, tracks: Vec<LibraryTrack>, replace: bool) -> usize {
-    let mut state = shared.inner.lock().unwrap();
-    if replace {
-        state.queue = tracks;
-        state.queue_index = None;
-    } else {
-        state.queue.extend(tracks);
+        listing.files_seen += 1;
+        if is_supported_audio_path(file_path, extensions) {
+            listing.files.push(file_path.to_path_buf());
+        }
     }
-    if let Some(path) = state.file_path.as_ref() {
-        state.queue_index = state.queue.iter().position(|track| &track.path == path);
-    } else {
-        state.queue_index = None;
+    listing
+}
+
+/// List the audio files under `path`, then probe those `cache` doesn't have
+/// as they are now. `progress` is called after each file with the number
+/// probed so far and the number listed; the scan stops early once `cancel`
+/// is set and returns what it has.
+fn scan_library_impl(
+    path: &str,
+    extensions: &[String],
+    scope: ScanScope,
+    mut cache: Option<&mut ScanCache>,
+    cancel: &AtomicBool,
+    mut progress: impl FnMut(usize, usize, &Path),
+) -> Result<ScanOutcome> {
+    let root = Path::new(path);
+    if !root.exists() {
+        return Err(anyhow!("scan path not found"));
     }
-    state.queue.len()
-}
-
-fn queue_next_impl(shared: &SharedState) -> Result<Option<LibraryTrack>> {
-    let next = {
-        let mut state = shared.inner.lock().unwrap();
-        if state.queue.is_empty() {
-            return Ok(None);
+    let listing = list_scan_files(root, extensions, scope, cancel);
+    let total = listing.files.len();
+    let mut outcome = ScanOutcome {
+        tracks: Vec::with_capacity(total),
+        files_seen: listing.files_seen,
+        cached: 0,
+        cancelled: listing.cancelled,
+    };
+    for (index, file_path) in listing.files.iter().enumerate() {
+        if outcome.cancelled || cancel.load(Ordering::Relaxed) {
+            outcome.cancelled = true;
+            break;
         }
-        let next_index = match state.queue_index {
-            Some(idx) => idx.saturating_add(1),
-            None => 0,
+        let stamp = FileStamp::of(file_path);
+        let key = file_path.to_string_lossy();
+        let hit = match (cache.as_deref_mut(), stamp) {
+            (Some(cache), Some(stamp)) => cache.get(&key, stamp),
+            _ => None,
         };
-        if next_index >= state.queue.len() {
-            return Ok(None);
+        if let Some(track) = hit {
+            outcome.cached += 1;
+            outcome.tracks.push(track);
+        } else {
+            let track = read_library_track_or_fallback(file_path);
+            if let (Some(cache), Some(stamp)) = (cache.as_deref_mut(), stamp) {
+                cache.insert(key.into_owned(), stamp, track.clone());
+            }
+            outcome.tracks.push(track);
         }
-        state.queue_index = Some(next_index);
-        state.queue[next_index].clone()
-    };
-
-    load_file_impl(shared, next.path.clone())?;
-    play_impl(shared)?;
-    Ok(Some(next))
+        progress(index + 1, total, file_path);
+    }
+    Ok(outcome)
 }
 
-#[derive(Debug, Clone)]
-struct ParsedCommand {
-    action: String,
-    query: Option<String>,
-    raw: String,
+/// Why `begin_library_scan` wouldn't start a scan.
+#[derive(Debug, PartialEq)]
+enum ScanError {
+    /// Another scan holds the scanner.
+    Busy,
+    /// The scan root doesn't exist.
+    NotFound,
 }
 
-#[derive(Debug, Clone)]
-struct CommandResult {
-    action: String,
-    matches: usize,
-    track: Option<LibraryTrack>,
+impl std::fmt::Display for ScanError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScanError::Busy => f.write_str("a library scan is already running"),
+            ScanError::NotFound => f.write_str("scan path not found"),
+        }
+    }
 }
 
-fn normalize_command_text(text: &str) -> String {
-    text.trim().to_lowercase()
+impl std::error::Error for ScanError {}
+
+/// The scanner, held by one scan and freed when dropped, so a scan that
+/// fails or panics part way doesn't leave every later one refused.
+struct ScanClaim {
+    active: Arc<AtomicBool>,
+    id: String,
 }
 
-fn extract_query(raw: &str, key: &str) -> Option<String> {
-    if let Some(pos) = raw.find(key) {
-        let tail = raw[(pos + key.len())..].trim();
-        if tail.is_empty() {
-            None
-        } else {
-            Some(tail.to_string())
-        }
-    } else {
-        None
+impl Drop for ScanClaim {
+    fn drop(&mut self) {
+        self.active.store(false, Ordering::Release);
     }
 }
 
-fn parse_command_text(text: &str) -> Option<ParsedCommand> {
-    let raw = text.trim().to_string();
-    if raw.is_empty() {
-        return None;
+/// Claim the scanner for a scan of `path` and give it an id; only one scan
+/// runs at a time.
+fn begin_library_scan(shared: &SharedState, path: &str) -> std::result::Result<ScanClaim, ScanError> {
+    if shared.scan_active.swap(true, Ordering::AcqRel) {
+        return Err(ScanError::Busy);
     }
-    let normalized = normalize_command_text(&raw);
-    let action = if normalized.contains("暂停") || normalized.contains("pause") {
-        "pause"
-    } else if normalized.contains("停止") || normalized.contains("stop") {
-        "stop"
-    } else if normalized.contains("下一曲") || normalized.contains("next") {
-        "next"
-    } else if normalized.contains("上一曲") || normalized.contains("previous") {
-        "prev"
-    } else if normalized.contains("继续") || normalized.contains("resume") {
-        "play"
-    } else if normalized.contains("播放") || normalized.contains("play") {
-        "play"
-    } else {
-        "unknown"
-    };
-
-    let mut query = None;
-    if action == "play" {
-        query = extract_query(&raw, "播放")
-            .or_else(|| extract_query(&normalized, "play "))
-            .or_else(|| extract_query(&normalized, "play"));
+    let claim = ScanClaim { active: shared.scan_active.clone(), id: uuid::Uuid::new_v4().to_string() };
+    if !Path::new(path).exists() {
+        return Err(ScanError::NotFound);
     }
-    Some(ParsedCommand {
-        action: action.to_string(),
-        query,
-        raw,
-    })
+    shared.scan_cancel.store(false, Ordering::Release);
+    Ok(claim)
 }
 
-fn track_matches_query(track: &LibraryTrack, query: &str) -> bool {
-    let q = query.to_lowercase();
-    let mut haystacks = Vec::new();
-    if let Some(title) = &track.title {
-        haystacks.push(title.as_str());
-    }
-    if let Some(artist) = &track.artist {
-        haystacks.push(artist.as_str());
+/// Claim the scanner and scan on the calling thread; see
+/// `run_claimed_library_scan`.
+fn run_library_scan(shared: &SharedState, path: &str, scope: ScanScope, force: bool) -> Result<ScanOutcome> {
+    let claim = begin_library_scan(shared, path)?;
+    run_claimed_library_scan(shared, claim, path, scope, force, None, None)
+}
+
+/// Run a scan claimed by `begin_library_scan` on the calling thread,
+/// broadcasting `scan_progress` as it goes and `scan_complete` with the
+/// tracks at the end, and store the result (partial if cancelled) as the
+/// library. `force` ignores the scan cache for this tree; `sort` orders the
+/// result rather than leaving it in walk order; `extensions` replaces the
+/// configured list for this scan only.
+fn run_claimed_library_scan(
+    shared: &SharedState,
+    claim: ScanClaim,
+    path: &str,
+    scope: ScanScope,
+    force: bool,
+    sort: Option<(LibrarySort, bool)>,
+    extensions: Option<Vec<String>>,
+) -> Result<ScanOutcome> {
+    let (extensions, cache_path) = {
+        let state = shared.inner.lock().unwrap();
+        (extensions.unwrap_or_else(|| state.audio_extensions.clone()), state.scan_cache_path.clone())
+    };
+    let mut cache = ScanCache::load(&cache_path);
+    if force {
+        cache.forget_under(Path::new(path), &HashSet::new());
+    }
+    let scan_id = claim.id.clone();
+    let mut last_progress = Instant::now();
+    let cancel = &shared.scan_cancel;
+    let result = scan_library_impl(path, &extensions, scope, Some(&mut cache), cancel, |files, total, current| {
+        if last_progress.elapsed() >= SCAN_PROGRESS_INTERVAL || files == total {
+            last_progress = Instant::now();
+            let payload = json!({
+                "type": "scan_progress",
+                "scan_id": scan_id,
+                "files": files,
+                "total": total,
+                "path": current.to_string_lossy(),
+                "finished": false
+            });
+            let _ = shared.tx.send(payload.to_string());
+        }
+    });
+    if let Ok(outcome) = &result {
+        // Only a full walk shows which files are gone.
+        if !outcome.cancelled && scope.recursive {
+            let seen: HashSet<String> = outcome.tracks.iter().map(|track| track.path.clone()).collect();
+            cache.forget_under(Path::new(path), &seen);
+        }
+        if let Err(err) = cache.save(&cache_path) {
+            warn!("failed to save scan cache to {}: {}", cache_path.display(), err);
+        }
     }
-    if let Some(album) = &track.album {
-        haystacks.push(album.as_str());
+    drop(claim);
+    let mut outcome = match result {
+        Ok(outcome) => outcome,
+        Err(err) => {
+            let payload = json!({
+                "type": "scan_complete",
+                "scan_id": scan_id,
+                "status": "error",
+                "message": err.to_string()
+            });
+            let _ = shared.tx.send(payload.to_string());
+            return Err(err);
+        }
+    };
+    if let Some((sort, ascending)) = sort {
+        sort_library_tracks(&mut outcome.tracks, sort, ascending);
     }
-    haystacks.push(track.path.as_str());
-    for item in haystacks {
-        if item.to_lowercase().contains(&q) {
-            return true;
+    shared.inner.lock().unwrap().library = outcome.tracks.clone();
+    let payload = json!({
+        "type": "scan_progress",
+        "scan_id": scan_id,
+        "files": outcome.files_seen,
+        "tracks": outcome.tracks.len(),
+        "cached": outcome.cached,
+        "finished": true,
+        "cancelled": outcome.cancelled
+    });
+    let _ = shared.tx.send(payload.to_string());
+    let payload = json!({
+        "type": "scan_complete",
+        "scan_id": scan_id,
+        "status": "success",
+        "tracks": outcome.tracks,
+        "cached": outcome.cached,
+        "cancelled": outcome.cancelled
+    });
+    let _ = shared.tx.send(payload.to_string());
+    Ok(outcome)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LibrarySort {
+    Title,
+    Artist,
+    Album,
+    Path,
+    Duration,
+}
+
+impl LibrarySort {
+    fn from_label(label: &str) -> Option<Self> {
+        match label.trim().to_ascii_lowercase().as_str() {
+            "title" => Some(LibrarySort::Title),
+            "artist" => Some(LibrarySort::Artist),
+            "album" => Some(LibrarySort::Album),
+            "path" => Some(LibrarySort::Path),
+            "duration" => Some(LibrarySort::Duration),
+            _ => None,
         }
     }
-    false
 }
 
-fn cover_dir() -> PathBuf {
-    if let Ok(dir) = std::env::var("NTMUSIC_COVER_DIR") {
-        if !dir.trim().is_empty() {
-            return PathBuf::from(dir);
-        }
-    }
-    std::env::temp_dir().join("ntmusic_covers")
+/// A tag's place in a library sort. A track missing the tag comes last
+/// whichever way the sort runs, so only present values flip when descending.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum TagKey<T> {
+    Ascending(T),
+    Descending(std::cmp::Reverse<T>),
+    Missing,
 }
 
-fn cover_extension(media_type: &str) -> &'static str {
-    match media_type {
-        "image/jpeg" | "image/jpg" => "jpg",
-        "image/png" => "png",
-        "image/webp" => "webp",
-        "image/bmp" => "bmp",
-        _ => "bin",
+impl<T> TagKey<T> {
+    fn new(value: Option<T>, ascending: bool) -> Self {
+        match value {
+            Some(value) if ascending => TagKey::Ascending(value),
+            Some(value) => TagKey::Descending(std::cmp::Reverse(value)),
+            None => TagKey::Missing,
+        }
     }
 }
 
-fn cover_hash_key(path: &Path) -> u64 {
-    let mut hasher = std::collections::hash_map::DefaultHasher::new();
-    path.to_string_lossy().hash(&mut hasher);
-    hasher.finish()
+/// Sort scanned tracks by `sort`, then by album, disc and track number and
+/// path, so tracks that tie stay in album order. Tags and paths ignore case;
+/// each track's key is folded once rather than on every comparison.
+fn sort_library_tracks(tracks: &mut [LibraryTrack], sort: LibrarySort, ascending: bool) {
+    #[derive(PartialEq, Eq, PartialOrd, Ord)]
+    enum Primary {
+        Text(String),
+        Duration(i64),
+    }
+    let folded = |value: &Option<String>| value.as_deref().map(str::to_lowercase);
+    tracks.sort_by_cached_key(|track| {
+        let path = track.path.to_lowercase();
+        let primary = match sort {
+            LibrarySort::Title => folded(&track.title).map(Primary::Text),
+            LibrarySort::Artist => folded(&track.artist).map(Primary::Text),
+            LibrarySort::Album => folded(&track.album).map(Primary::Text),
+            LibrarySort::Path => Some(Primary::Text(path.clone())),
+            LibrarySort::Duration => {
+                // The same bit flip `f64::total_cmp` orders by.
+                let bits = track.duration.to_bits() as i64;
+                Some(Primary::Duration(bits ^ (((bits >> 63) as u64) >> 1) as i64))
+            }
+        };
+        (
+            TagKey::new(primary, ascending),
+            TagKey::new(folded(&track.album), true),
+            TagKey::new(track.disc_number, true),
+            TagKey::new(track.track_number, true),
+            path,
+        )
+    });
 }
 
-fn write_cover_file(path: &Path, data: &[u8], media_type: &str) -> Result<PathBuf> {
-    let dir = cover_dir();
-    std::fs::create_dir_all(&dir).context("create cover dir")?;
-    let hash = cover_hash_key(path);
-    let ext = cover_extension(media_type);
-    let filename = format!("cover_{}.{}", hash, ext);
-    let cover_path = dir.join(filename);
-    if cover_path.exists() {
-        return Ok(cover_path);
+fn cancel_library_scan_impl(shared: &SharedState) -> bool {
+    if !shared.scan_active.load(Ordering::Acquire) {
+        return false;
     }
-    std::fs::write(&cover_path, data).context("write cover file")?;
-    Ok(cover_path)
+    shared.scan_cancel.store(true, Ordering::Release);
+    true
 }
 
-fn extract_cover_art(path: &Path) -> Result<Option<(Vec<u8>, String)>> {
-    let file = File::open(path).with_context(|| format!("open {:?}", path))?;
-    let mss = MediaSourceStream::new(Box::new(file), Default::default());
-    let mut hint = Hint::new();
-    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
-        hint.with_extension(ext);
-    }
-    let probed = symphonia::default::get_probe()
-        .format(&hint, mss, &FormatOptions::default(), &MetadataOptions::default())
-        .with_context(|| format!("probe {:?}", path))?;
-    let mut format = probed.format;
-    let visuals = format
-        .metadata()
-        .current()
-        .map(|rev| rev.visuals().to_vec())
-        .unwrap_or_default();
-    if visuals.is_empty() {
-        return Ok(None);
-    }
-    let preferred = visuals.iter().find(|visual| {
-        matches!(visual.usage, Some(StandardVisualKey::CoverFront))
-    });
-    let visual = preferred.unwrap_or_else(|| &visuals[0]);
-    let data = visual.data.to_vec();
-    if data.is_empty() {
-        return Ok(None);
+/// A .dsf file as its header describes it; the ID3 tag such files carry
+/// isn't read yet, so the title comes from the file name.
+fn read_dsf_library_track(path: &Path) -> Result<LibraryTrack> {
+    let header = dsd::read_dsf_file_header(path)?;
+    Ok(LibraryTrack {
+        path: path.to_string_lossy().to_string(),
+        title: track_title_from_path(path),
+        artist: None,
+        artists: Vec::new(),
+        album_artist: None,
+        album: None,
+        track_number: None,
+        disc_number: None,
+        year: None,
+        genre: None,
+        duration: header.duration(),
+        sample_rate: Some(header.dsd_rate),
+        bit_depth: Some(1),
+        channels: Some(header.channels as u32),
+        track_gain: None,
+        album_gain: None,
+        decodable: header.check_playable().is_ok(),
+    })
+}
+
+fn read_library_track_or_fallback(path: &Path) -> LibraryTrack {
+    match read_library_track(path) {
+        Ok(track) => track,
+        Err(_) => LibraryTrack {
+            path: path.to_string_lossy().to_string(),
+            title: track_title_from_path(path),
+            artist: None,
+            artists: Vec::new(),
+            album_artist: None,
+            album: None,
+            track_number: None,
+            disc_number: None,
+            year: None,
+            genre: None,
+            duration: 0.0,
+            sample_rate: None,
+            bit_depth: None,
+            channels: None,
+            track_gain: None,
+            album_gain: None,
+            decodable: false,
+        },
     }
-    Ok(Some((data, visual.media_type.clone())))
 }
 
-fn handle_command_impl(shared: &SharedState, cmd: ParsedCommand) -> Result<CommandResult> {
-    match cmd.action.as_str() {
-        "pause" => {
-            pause_impl(shared)?;
-            Ok(CommandResult {
-                action: cmd.action,
-                matches: 0,
-                track: None,
-            })
-        }
-        "stop" => {
-            stop_impl(shared)?;
-            Ok(CommandResult {
-                action: cmd.action,
-                matches: 0,
-                track: None,
-            })
+/// Re-read one file's tags and cover, replacing (or adding) its library entry.
+fn refresh_library_track_impl(shared: &SharedState, path: &str) -> Result<(LibraryTrack, Option<PathBuf>)> {
+    let file_path = Path::new(path);
+    if !file_path.is_file() {
+        return Err(anyhow!("file not found"));
+    }
+    let extensions = shared.inner.lock().unwrap().audio_extensions.clone();
+    if !is_supported_audio_path(file_path, &extensions) {
+        return Err(anyhow!("unsupported audio format"));
+    }
+    let track = read_library_track_or_fallback(file_path);
+    invalidate_cover_cache(file_path);
+    let cover_path = match extract_cover_art(file_path) {
+        Ok(Some((data, media_type))) => write_cover_file(file_path, &data, &media_type).ok(),
+        _ => None,
+    };
+    {
+        let mut state = shared.inner.lock().unwrap();
+        match state.library.iter_mut().find(|entry| entry.path == track.path) {
+            Some(entry) => *entry = track.clone(),
+            None => state.library.push(track.clone()),
         }
-        "next" => match queue_next_impl(shared)? {
-            Some(track) => Ok(CommandResult {
-                action: cmd.action,
-                matches: 1,
-                track: Some(track),
-            }),
-            None => Err(anyhow!("queue empty")),
-        },
-        "play" => {
-            if let Some(query) = cmd.query.clone().filter(|q| !q.is_empty()) {
-                let library = {
-                    let state = shared.inner.lock().unwrap();
-                    state.library.clone()
-                };
-                if library.is_empty() {
-                    return Err(anyhow!("library empty, scan first"));
-                }
-                let matches: Vec<LibraryTrack> = library
-                    .into_iter()
-                    .filter(|track| track_matches_query(track, &query))
-                    .collect();
-                if matches.is_empty() {
-                    return Err(anyhow!("no matches for query"));
-                }
-                let count = matches.len();
-                queue_add_impl(shared, matches, true);
-                let next = queue_next_impl(shared)?;
-                Ok(CommandResult {
-                    action: cmd.action,
-                    matches: count,
-                    track: next,
-                })
-            } else {
-                let has_file = {
-                    let state = shared.inner.lock().unwrap();
-                    state.file_path.is_some()
-                };
-                if has_file {
-                    play_impl(shared)?;
-                    return Ok(CommandResult {
-                        action: cmd.action,
-                        matches: 0,
-                        track: None,
-                    });
-                }
-                match queue_next_impl(shared)? {
-                    Some(track) => Ok(CommandResult {
-                        action: cmd.action,
-                        matches: 1,
-                        track: Some(track),
-                    }),
-                    None => Err(anyhow!("no track loaded")),
-                }
-            }
+        for entry in state.queue.iter_mut().filter(|entry| entry.path == track.path) {
+            *entry = track.clone();
         }
-        "prev" => Err(anyhow!("previous track not implemented")),
-        _ => Err(anyhow!("unknown command")),
     }
+    let payload = json!({ "type": "library_updated", "track": track });
+    let _ = shared.tx.send(payload.to_string());
+    Ok((track, cover_path))
 }
 
-fn start_capture_impl(
+/// `refresh_library_track_impl` on the blocking pool, for the handlers that
+/// have just rewritten the file there.
+async fn refresh_library_track_blocking(
     shared: &SharedState,
-    device_id: Option<String>,
-    samplerate: Option<u32>,
-    channels: Option<u16>,
-) -> Result<()> {
-    stop_stream(shared);
-    let (sample_rate, channels) = {
-        let mut state = shared.inner.lock().unwrap();
-        state.mode = "capture".to_string();
-        state.sample_rate = samplerate.unwrap_or(48_000);
-        state.channels = channels.unwrap_or(2) as usize;
-        state.source_sample_rate = state.sample_rate;
-        state.source_channels = state.channels;
-        state.source_bit_depth = None;
-        state.data.clear();
-        state.position = 0;
-        state.played_frames = 0;
-        state.duration = 0.0;
-        state.buffered_frames = 0;
-        state.stream_status = "starting".to_string();
-        state.stream_url = device_id;
-        (state.sample_rate, state.channels as u16)
-    };
-    reset_ring_buffer(shared);
-    let child = match spawn_capture_ffmpeg(sample_rate, channels) {
-        Ok(child) => child,
-        Err(err) => {
-            update_stream_status(shared, "error", Some(err.to_string()));
-            return Err(err);
-        }
-    };
-    start_stream_reader(shared.clone(), child);
-    let _ = ensure_output_stream(shared);
-    send_state(shared);
-    Ok(())
+    path: String,
+) -> Result<(LibraryTrack, Option<PathBuf>)> {
+    let shared = shared.clone();
+    tokio::task::spawn_blocking(move || refresh_library_track_impl(&shared, &path))
+        .await
+        .unwrap_or_else(|err| Err(anyhow!("library refresh panicked: {}", err)))
 }
 
-fn stop_capture_impl(shared: &SharedState) -> Result<()> {
-    stop_stream(shared);
-    {
-        let mut state = shared.inner.lock().unwrap();
-        state.mode = "idle".to_string();
-        state.is_playing = false;
-        state.is_paused = false;
-    }
-    send_state(shared);
-    Ok(())
+struct CachedAnalysis<T> {
+    modified: Option<SystemTime>,
+    len: u64,
+    /// The cache's clock when last used; the oldest goes first once full.
+    used: u64,
+    value: T,
 }
-fn send_state(shared: &SharedState) {
-    let state = shared.inner.lock().unwrap();
-    let payload = json!({
-        "type": "playback_state",
-        "state": build_state_view(&state)
-    });
-    let _ = shared.tx.send(payload.to_string());
+
+struct AnalysisEntries<T> {
+    clock: u64,
+    files: HashMap<u64, CachedAnalysis<T>>,
 }
 
-fn send_buffer_state(shared: &SharedState) {
-    let state = shared.inner.lock().unwrap();
-    let payload = json!({
-        "type": "buffer_state",
-        "buffered_ms": if state.sample_rate > 0 {
-            (state.buffered_frames as f64 / state.sample_rate as f64) * 1000.0
-        } else {
-            0.0
-        },
-        "underruns": state.underrun_count,
-        "mode": state.mode.clone()
-    });
-    let _ = shared.tx.send(payload.to_string());
+/// Per-file analysis results keyed by path hash, for at most `capacity`
+/// files.
+struct AnalysisCache<T> {
+    capacity: usize,
+    entries: OnceLock<Mutex<AnalysisEntries<T>>>,
 }
 
-fn update_stream_status(shared: &SharedState, status: &str, err: Option<String>) {
-    {
-        let mut state = shared.inner.lock().unwrap();
-        state.stream_status = status.to_string();
-        state.stream_error = err.clone();
+impl<T> AnalysisCache<T> {
+    const fn new(capacity: usize) -> Self {
+        AnalysisCache { capacity, entries: OnceLock::new() }
     }
-    let payload = json!({
-        "type": "stream_state",
-        "status": status,
-        "error": err
-    });
-    let _ = shared.tx.send(payload.to_string());
-}
 
-fn ffmpeg_path() -> PathBuf {
-    if let Ok(dir) = std::env::var("VMUSIC_ASSET_DIR") {
-        let candidate = Path::new(&dir).join("ffmpeg.exe");
-        if candidate.exists() {
-            return candidate;
-        }
+    fn entries(&self) -> &Mutex<AnalysisEntries<T>> {
+        self.entries.get_or_init(|| Mutex::new(AnalysisEntries { clock: 0, files: HashMap::new() }))
     }
-    PathBuf::from("ffmpeg")
 }
 
-fn spawn_ffmpeg(input: &str, sample_rate: u32, channels: u16) -> Result<Child> {
-    let mut cmd = Command::new(ffmpeg_path());
-    cmd.arg("-v")
-        .arg("error")
-        .arg("-i")
-        .arg(input)
-        .arg("-ac")
-        .arg(channels.to_string())
-        .arg("-ar")
-        .arg(sample_rate.to_string())
-        .arg("-acodec")
-        .arg("pcm_f32le")
-        .arg("-f")
-        .arg("f32le")
-        .arg("-");
+/// Fingerprints are a few kilobytes each; this holds a large playlist's
+/// worth without growing with the whole library.
+const FINGERPRINT_CACHE_ENTRIES: usize = 1024;
 
-    let child = cmd
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .context("spawn ffmpeg")?;
-    Ok(child)
-}
+/// Key and tempo estimates are a few bytes, so these keep more files.
+const ESTIMATE_CACHE_ENTRIES: usize = 8192;
 
-fn spawn_capture_ffmpeg(sample_rate: u32, channels: u16) -> Result<Child> {
-    if cfg!(target_os = "windows") {
-        let mut cmd = Command::new(ffmpeg_path());
-        cmd.arg("-v")
-            .arg("error")
-            .arg("-f")
-            .arg("wasapi")
-            .arg("-i")
-            .arg("default")
-            .arg("-ac")
-            .arg(channels.to_string())
-            .arg("-ar")
-            .arg(sample_rate.to_string())
-            .arg("-acodec")
-            .arg("pcm_f32le")
-            .arg("-f")
-            .arg("f32le")
-            .arg("-");
-        let child = cmd
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
-            .context("spawn ffmpeg capture")?;
-        return Ok(child);
+static FINGERPRINT_CACHE: AnalysisCache<Fingerprint> = AnalysisCache::new(FINGERPRINT_CACHE_ENTRIES);
+static KEY_CACHE: AnalysisCache<Option<KeyEstimate>> = AnalysisCache::new(ESTIMATE_CACHE_ENTRIES);
+static TEMPO_CACHE: AnalysisCache<Option<TempoEstimate>> = AnalysisCache::new(ESTIMATE_CACHE_ENTRIES);
+
+/// Run `analyze` on `path`, or reuse its earlier result while the file's
+/// size and mtime match. The flag is true for a cache hit. A full cache
+/// forgets the file used longest ago.
+fn cached_analysis<T: Clone>(
+    cache: &AnalysisCache<T>,
+    path: &str,
+    analyze: impl FnOnce() -> Result<T>,
+) -> Result<(T, bool)> {
+    let file_path = Path::new(path);
+    let meta = std::fs::metadata(file_path).map_err(|_| anyhow!("file not found"))?;
+    let modified = meta.modified().ok();
+    let key = cover_hash_key(file_path);
+    {
+        let mut entries = cache.entries().lock().unwrap();
+        entries.clock += 1;
+        let clock = entries.clock;
+        if let Some(cached) = entries.files.get_mut(&key) {
+            if cached.modified == modified && cached.len == meta.len() {
+                cached.used = clock;
+                return Ok((cached.value.clone(), true));
+            }
+        }
     }
-    Err(anyhow!("capture not supported on this platform"))
+    let value = analyze()?;
+    let mut entries = cache.entries().lock().unwrap();
+    if !entries.files.contains_key(&key) && entries.files.len() >= cache.capacity {
+        let oldest = entries.files.iter().min_by_key(|(_, cached)| cached.used).map(|(key, _)| *key);
+        if let Some(oldest) = oldest {
+            entries.files.remove(&oldest);
+        }
+    }
+    entries.clock += 1;
+    let used = entries.clock;
+    entries.files.insert(
+        key,
+        CachedAnalysis {
+            modified,
+            len: meta.len(),
+            used,
+            value: value.clone(),
+        },
+    );
+    Ok((value, false))
+}
+
+fn fingerprint_file_impl(path: &str) -> Result<(Fingerprint, bool)> {
+    cached_analysis(&FINGERPRINT_CACHE, path, || {
+        let max_seconds = fingerprint::FINGERPRINT_MAX_SECONDS as f64;
+        let decoded = decode_file_head(path, &DecodeOptions::default(), max_seconds)?;
+        let mono = fingerprint::mono_window(&decoded.samples, decoded.channels, decoded.sample_rate);
+        let mono = resample_audio(
+            &mono,
+            1,
+            decoded.sample_rate,
+            fingerprint::FINGERPRINT_SAMPLE_RATE,
+            "std",
+        )?;
+        Ok(fingerprint::compute_fingerprint(&mono, decoded.duration))
+    })
 }
 
-fn start_stream_reader(shared: SharedState, mut child: Child) {
-    let stdout = child.stdout.take();
-    if stdout.is_none() {
-        update_stream_status(&shared, "error", Some("ffmpeg stdout missing".to_string()));
-        return;
+/// The whole track as mono at the key/tempo analysis rate.
+fn analysis_mono(path: &str) -> Result<Vec<f32>> {
+    let decoded = decode_file(path, &DecodeOptions::default())?;
+    let mono = analysis::downmix(&decoded.samples, decoded.channels);
+    if decoded.sample_rate == analysis::ANALYSIS_SAMPLE_RATE {
+        return Ok(mono);
     }
-    let mut stdout = stdout.unwrap();
-    update_stream_status(&shared, "running", None);
-    let producer = shared.producer.clone();
-    let state = shared.inner.clone();
-    let thread = thread::spawn(move || {
-        let channels = {
-            let guard = state.lock().unwrap();
-            guard.channels.max(1)
-        };
-        let mut sample_count = 0usize;
-        let mut buffer = vec![0u8; 8192];
-        loop {
-            match stdout.read(&mut buffer) {
-                Ok(0) => break,
-                Ok(n) => {
-                    let mut offset = 0;
-                    while offset + 4 <= n {
-                        let sample = f32::from_le_bytes([
-                            buffer[offset],
-                            buffer[offset + 1],
-                            buffer[offset + 2],
-                            buffer[offset + 3],
-                        ]);
-                        offset += 4;
-                        if let Ok(mut prod) = producer.lock() {
-                            if prod.try_push(sample).is_ok() {
-                                sample_count += 1;
-                                if sample_count % channels == 0 {
-                                    if let Ok(mut s) = state.lock() {
-                                        s.buffered_frames += 1;
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-                Err(err) => {
-                    error!("ffmpeg read error: {}", err);
-                    break;
-                }
-            }
-        }
-    });
+    resample_audio(&mono, 1, decoded.sample_rate, analysis::ANALYSIS_SAMPLE_RATE, "std")
+}
 
-    *shared.stream_process.lock().unwrap() = Some(child);
-    *shared.stream_thread.lock().unwrap() = Some(thread);
+fn key_file_impl(path: &str) -> Result<(Option<KeyEstimate>, bool)> {
+    cached_analysis(&KEY_CACHE, path, || Ok(analysis::estimate_key(&analysis_mono(path)?)))
 }
 
-fn stop_stream(shared: &SharedState) {
-    if let Some(mut child) = shared.stream_process.lock().unwrap().take() {
-        let _ = child.kill();
-    }
-    if let Some(thread) = shared.stream_thread.lock().unwrap().take() {
-        let _ = thread.join();
-    }
-    let mut state = shared.inner.lock().unwrap();
-    state.stream_status = "stopped".to_string();
-    state.stream_error = None;
-    state.stream_url = None;
-    state.buffered_frames = 0;
+fn tempo_file_impl(path: &str) -> Result<(Option<TempoEstimate>, bool)> {
+    cached_analysis(&TEMPO_CACHE, path, || Ok(analysis::estimate_tempo(&analysis_mono(path)?)))
 }
 
-fn reset_ring_buffer(shared: &SharedState) {
-    let capacity = {
-        let state = shared.inner.lock().unwrap();
-        let sample_rate = state.sample_rate.max(1);
-        let channels = state.channels.max(1);
-        let frames = (state.buffer_max_ms as u64 * sample_rate as u64) / 1000;
-        (frames as usize) * channels
-    };
-    let rb = HeapRb::<f32>::new(capacity);
-    let (prod, cons) = rb.split();
-    *shared.producer.lock().unwrap() = prod;
-    *shared.consumer.lock().unwrap() = cons;
+fn queue_add_impl(shared: &SharedState, tracks: Vec<LibraryTrack>, replace: bool) -> usize {
+    let mut state = shared.inner.lock().unwrap();
+    discard_lookahead(&mut state);
+    if replace {
+        state.queue = tracks;
+        state.queue_index = None;
+        state.shuffle_history.clear();
+    } else {
+        state.queue.extend(tracks);
+    }
+    if let Some(path) = state.file_path.as_ref() {
+        state.queue_index = state.queue.iter().position(|track| &track.path == path);
+    } else {
+        state.queue_index = None;
+    }
+    refresh_replaygain_gain(&mut state);
+    state.queue.len()
 }
 
-struct DecodedAudio {
-    samples: Vec<f32>,
-    sample_rate: u32,
-    channels: usize,
-    duration: f64,
-    bit_depth: Option<u32>,
+/// Insert `tracks` before entry `at`, or after the last one when `at` is
+/// the queue length. Returns the new length.
+fn queue_insert_impl(shared: &SharedState, tracks: Vec<LibraryTrack>, at: usize) -> Result<usize> {
+    let mut state = shared.inner.lock().unwrap();
+    let len = state.queue.len();
+    if at > len {
+        return Err(anyhow!("can't insert at {} (queue has {})", at, len));
+    }
+    discard_lookahead(&mut state);
+    let count = tracks.len();
+    let shifted = |index: usize| if index >= at { index + count } else { index };
+    state.queue_index = state.queue_index.map(shifted);
+    state.shuffle_history = state.shuffle_history.iter().map(|index| shifted(*index)).collect();
+    state.queue.splice(at..at, tracks);
+    refresh_replaygain_gain(&mut state);
+    Ok(state.queue.len())
+}
+
+/// Move entry `from` to `to`, the entries between closing up behind it, so
+/// `queue_index` and the shuffle history follow the tracks they name.
+fn queue_move_impl(shared: &SharedState, from: usize, to: usize) -> Result<()> {
+    let mut state = shared.inner.lock().unwrap();
+    let len = state.queue.len();
+    if from >= len || to >= len {
+        return Err(anyhow!("can't move {} to {} (queue has {})", from, to, len));
+    }
+    if from == to {
+        return Ok(());
+    }
+    discard_lookahead(&mut state);
+    let moved = |index: usize| {
+        if index == from {
+            to
+        } else if from < index && index <= to {
+            index - 1
+        } else if to <= index && index < from {
+            index + 1
+        } else {
+            index
+        }
+    };
+    state.queue_index = state.queue_index.map(moved);
+    state.shuffle_history = state.shuffle_history.iter().map(|index| moved(*index)).collect();
+    let track = state.queue.remove(from);
+    state.queue.insert(to, track);
+    refresh_replaygain_gain(&mut state);
+    Ok(())
 }
 
-fn bit_depth_from_codec(codec_params: &CodecParameters) -> Option<u32> {
-    if let Some(bits) = codec_params
-        .bits_per_sample
-        .or(codec_params.bits_per_coded_sample)
-    {
-        return Some(bits);
-    }
-    codec_params.sample_format.and_then(|format| match format {
-        SampleFormat::U8 | SampleFormat::S8 => Some(8),
-        SampleFormat::U16 | SampleFormat::S16 => Some(16),
-        SampleFormat::U24 | SampleFormat::S24 => Some(24),
-        SampleFormat::U32 | SampleFormat::S32 | SampleFormat::F32 => Some(32),
-        SampleFormat::F64 => Some(64),
-        _ => None,
-    })
+fn queue_list_impl(shared: &SharedState) -> (Vec<LibraryTrack>, Option<usize>) {
+    let state = shared.inner.lock().unwrap();
+    (state.queue.clone(), state.queue_index)
 }
 
-fn apply_gapless_trim(samples: Vec<f32>, channels: usize, delay: usize, padding: usize) -> Vec<f32> {
-    if samples.is_empty() {
-        return samples;
-    }
-    let channels = channels.max(1);
-    let frame_count = samples.len() / channels;
-    if delay == 0 && padding == 0 {
-        return samples;
-    }
-    let start_frame = delay.min(frame_count);
-    let end_frame = frame_count.saturating_sub(padding);
-    if end_frame <= start_frame {
-        return Vec::new();
+/// Remove the entries at `indices`, all or none: one past the end fails the
+/// lot. Indices after the removed ones shift down, so the playing entry
+/// keeps its place in `queue_index`; removing it leaves nothing selected
+/// while it plays on, and `queue_next` starts again from the top.
+fn queue_remove_impl(shared: &SharedState, indices: &[usize]) -> Result<usize> {
+    let mut state = shared.inner.lock().unwrap();
+    let len = state.queue.len();
+    if let Some(index) = indices.iter().find(|index| **index >= len) {
+        return Err(anyhow!("no queue entry at index {} (queue has {})", index, len));
+    }
+    discard_lookahead(&mut state);
+    let mut removed = indices.to_vec();
+    removed.sort_unstable();
+    removed.dedup();
+    // Where each surviving entry ends up.
+    let shifted = |index: usize| removed.binary_search(&index).err().map(|before| index - before);
+    state.queue_index = state.queue_index.and_then(shifted);
+    state.shuffle_history = state.shuffle_history.iter().filter_map(|index| shifted(*index)).collect();
+    let queue = std::mem::take(&mut state.queue);
+    state.queue = queue
+        .into_iter()
+        .enumerate()
+        .filter(|(index, _)| removed.binary_search(index).is_err())
+        .map(|(_, track)| track)
+        .collect();
+    refresh_replaygain_gain(&mut state);
+    Ok(state.queue.len())
+}
+
+fn queue_clear_impl(shared: &SharedState) {
+    let mut state = shared.inner.lock().unwrap();
+    discard_lookahead(&mut state);
+    state.queue.clear();
+    state.queue_index = None;
+    state.shuffle_history.clear();
+    refresh_replaygain_gain(&mut state);
+}
+
+#[derive(Debug, Serialize)]
+struct SkippedPlaylistEntry {
+    location: String,
+    reason: String,
+}
+
+#[derive(Debug, Serialize)]
+struct LoadedPlaylist {
+    kind: PlaylistKind,
+    /// Local files, in playlist order; these are what gets queued.
+    files: Vec<PlaylistEntry>,
+    /// Remote entries, for the client to play through `/load_stream`.
+    streams: Vec<PlaylistEntry>,
+    skipped: Vec<SkippedPlaylistEntry>,
+}
+
+fn read_playlist(location: &str) -> Result<(PlaylistKind, Vec<PlaylistEntry>)> {
+    let content = playlist::read_source(location)?;
+    let kind = PlaylistKind::detect(location, &content)
+        .ok_or_else(|| anyhow!("not a recognised playlist: {}", location))?;
+    Ok((kind, playlist::parse(kind, &content, location)))
+}
+
+/// Parse a playlist and expand entries that are themselves playlists, as
+/// radio station lists often are. Only one level is expanded; entries that
+/// can't be read are reported rather than failing the whole playlist.
+fn load_playlist_impl(location: &str) -> Result<LoadedPlaylist> {
+    let (kind, entries) = read_playlist(location)?;
+    let mut loaded = LoadedPlaylist {
+        kind,
+        files: Vec::new(),
+        streams: Vec::new(),
+        skipped: Vec::new(),
+    };
+    let place = |loaded: &mut LoadedPlaylist, entry: PlaylistEntry| {
+        if entry.location.contains("://") {
+            loaded.streams.push(entry);
+        } else if Path::new(&entry.location).is_file() {
+            loaded.files.push(entry);
+        } else {
+            loaded.skipped.push(SkippedPlaylistEntry {
+                location: entry.location,
+                reason: "file not found".to_string(),
+            });
+        }
+    };
+    for entry in entries {
+        if !playlist::is_playlist_location(&entry.location) {
+            place(&mut loaded, entry);
+            continue;
+        }
+        match read_playlist(&entry.location) {
+            Ok((_, nested)) => {
+                for inner in nested {
+                    if playlist::is_playlist_location(&inner.location) {
+                        loaded.skipped.push(SkippedPlaylistEntry {
+                            location: inner.location,
+                            reason: "playlist nested too deeply".to_string(),
+                        });
+                    } else {
+                        place(&mut loaded, PlaylistEntry {
+                            title: inner.title.or_else(|| entry.title.clone()),
+                            ..inner
+                        });
+                    }
+                }
+            }
+            Err(err) => loaded.skipped.push(SkippedPlaylistEntry {
+                location: entry.location,
+                reason: err.to_string(),
+            }),
+        }
     }
-    let start = start_frame * channels;
-    let end = (end_frame * channels).min(samples.len());
-    samples[start..end].to_vec()
+    Ok(loaded)
 }
 
-fn decode_file(path: &str) -> Result<DecodedAudio> {
-    let file = File::open(path).context("open audio file")?;
-    let mss = MediaSourceStream::new(Box::new(file), Default::default());
-    let mut hint = Hint::new();
-    if let Some(ext) = Path::new(path).extension().and_then(|v| v.to_str()) {
-        hint.with_extension(ext);
-    }
+const REPEAT_MODES: [&str; 3] = ["off", "one", "all"];
 
-    let probed = symphonia::default::get_probe().format(
-        &hint,
-        mss,
-        &FormatOptions::default(),
-        &MetadataOptions::default(),
-    )?;
-    let mut format = probed.format;
-    let track = format
-        .default_track()
-        .ok_or_else(|| anyhow!("no default track"))?;
-    let codec_params = &track.codec_params;
-    let sample_rate = codec_params.sample_rate.unwrap_or(48_000);
-    let channels = codec_params
-        .channels
-        .map(|c| c.count())
-        .unwrap_or(2)
-        .max(1);
-    let bit_depth = bit_depth_from_codec(codec_params);
-    let gapless_delay = codec_params.delay.unwrap_or(0) as usize;
-    let gapless_padding = codec_params.padding.unwrap_or(0) as usize;
+/// The entry `queue_next` plays. Repeat-one replays a track that finished
+/// (a skip mid-track still advances); shuffle draws from the entries not
+/// yet played this cycle; repeat-all starts a new cycle instead of
+/// stopping at the end.
+fn next_queue_index(state: &mut EngineState, ended: bool) -> Option<usize> {
+    let mut rng = state.shuffle_rng;
+    let mut history = std::mem::take(&mut state.shuffle_history);
+    let next = draw_next_queue_index(state, ended, &mut rng, &mut history);
+    state.shuffle_rng = rng;
+    state.shuffle_history = history;
+    next
+}
 
-    let mut decoder = symphonia::default::get_codecs()
-        .make(codec_params, &DecoderOptions::default())?;
+/// The entry that plays after the current one, without drawing it: the
+/// staged track if there is one, else the pick `next_queue_index` would
+/// make, made on a copy of the shuffle state.
+fn peek_next_queue_index(state: &EngineState, ended: bool) -> Option<usize> {
+    if let LookAhead::Pending(index) | LookAhead::Ready(index, _) = &state.lookahead {
+        return Some(*index);
+    }
+    let mut rng = state.shuffle_rng;
+    let mut history = state.shuffle_history.clone();
+    draw_next_queue_index(state, ended, &mut rng, &mut history)
+}
 
-    let mut samples: Vec<f32> = Vec::new();
-    loop {
-        let packet = match format.next_packet() {
-            Ok(packet) => packet,
-            Err(_) => break,
-        };
-        let decoded = match decoder.decode(&packet) {
-            Ok(decoded) => decoded,
-            Err(_) => continue,
+/// `next_queue_index` with the shuffle state passed in, so a peek can draw
+/// from a copy.
+fn draw_next_queue_index(state: &EngineState, ended: bool, rng: &mut u64, history: &mut Vec<usize>) -> Option<usize> {
+    let len = state.queue.len();
+    let current = state.queue_index.filter(|idx| *idx < len);
+    if len == 0 {
+        return None;
+    }
+    if let Some(idx) = current.filter(|_| ended && state.repeat_mode == "one") {
+        return Some(idx);
+    }
+    if !state.shuffle {
+        let next = current.map_or(0, |idx| idx + 1);
+        return if next < len {
+            Some(next)
+        } else if state.repeat_mode == "all" {
+            Some(0)
+        } else {
+            None
         };
-        match decoded {
-            AudioBufferRef::F32(buf) => {
-                let spec = *buf.spec();
-                let mut sample_buf = SampleBuffer::<f32>::new(buf.capacity() as u64, spec);
-                sample_buf.copy_interleaved_ref(AudioBufferRef::F32(buf));
-                samples.extend_from_slice(sample_buf.samples());
-            }
-            _ => {
-                let spec = *decoded.spec();
-                let mut sample_buf = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
-                sample_buf.copy_interleaved_ref(decoded);
-                samples.extend_from_slice(sample_buf.samples());
+    }
+    if let Some(idx) = current.filter(|idx| !history.contains(idx)) {
+        history.push(idx);
+    }
+    let mut unplayed: Vec<usize> = (0..len).filter(|idx| !history.contains(idx)).collect();
+    if unplayed.is_empty() {
+        if state.repeat_mode != "all" {
+            return None;
+        }
+        history.clear();
+        // Don't open the new cycle with the track that closed the last one.
+        unplayed = (0..len).filter(|idx| len == 1 || Some(*idx) != current).collect();
+    }
+    let pick = (next_uniform(rng) * unplayed.len() as f32) as usize;
+    let next = unplayed[pick.min(unplayed.len() - 1)];
+    history.push(next);
+    Some(next)
+}
+
+/// The entry `queue_prev` plays: back through the shuffle order when
+/// shuffling, otherwise the previous index, wrapping only in repeat-all.
+fn prev_queue_index(state: &mut EngineState) -> Option<usize> {
+    let prev = peek_prev_queue_index(state)?;
+    if state.shuffle {
+        state.shuffle_history.pop();
+    }
+    Some(prev)
+}
+
+/// The entry that played before the current one, as `prev_queue_index`
+/// finds it, without stepping back.
+fn peek_prev_queue_index(state: &EngineState) -> Option<usize> {
+    let len = state.queue.len();
+    let current = state.queue_index.filter(|idx| *idx < len)?;
+    if state.shuffle {
+        // A staged next track is already drawn into the history.
+        let mut played = state.shuffle_history.as_slice();
+        if let LookAhead::Pending(index) | LookAhead::Ready(index, _) = &state.lookahead {
+            if played.last() == Some(index) {
+                played = &played[..played.len() - 1];
             }
         }
+        return match played {
+            [.., prev, last] if *last == current => Some(*prev),
+            _ => None,
+        };
     }
-
-    if gapless_delay > 0 || gapless_padding > 0 {
-        samples = apply_gapless_trim(samples, channels, gapless_delay, gapless_padding);
+    match current {
+        0 if state.repeat_mode == "all" => Some(len - 1),
+        0 => None,
+        idx => Some(idx - 1),
     }
+}
 
-    let frames = samples.len() / channels.max(1);
-    let duration = if sample_rate > 0 {
-        frames as f64 / sample_rate as f64
-    } else {
-        0.0
+/// Load and play the entry before the current one. `None` when the queue is
+/// empty, nothing from it is playing, or nothing comes before it: the
+/// first entry outside repeat-all, or the first one played this shuffle
+/// cycle.
+fn queue_prev_impl(shared: &SharedState) -> Result<Option<LibraryTrack>> {
+    let prev = {
+        let mut state = shared.inner.lock().unwrap();
+        discard_lookahead(&mut state);
+        let Some(index) = prev_queue_index(&mut state) else {
+            return Ok(None);
+        };
+        state.queue_index = Some(index);
+        state.queue[index].clone()
     };
-    Ok(DecodedAudio {
-        samples,
-        sample_rate,
-        channels,
-        duration,
-        bit_depth,
-    })
-}
 
-fn normalize_resampler_mode(value: &str) -> String {
-    let normalized = value.to_lowercase();
-    match normalized.as_str() {
-        "auto" | "rubato" | "soxr" => normalized,
-        _ => "auto".to_string(),
-    }
+    load_queued_track(shared, prev.path.clone())?;
+    play_impl(shared)?;
+    Ok(Some(prev))
 }
 
-fn normalize_resampler_quality(value: &str) -> String {
-    let normalized = value.to_lowercase();
-    match normalized.as_str() {
-        "low" | "std" | "hq" | "uhq" => normalized,
-        _ => "hq".to_string(),
-    }
+fn queue_next_impl(shared: &SharedState) -> Result<Option<LibraryTrack>> {
+    let next = {
+        let mut state = shared.inner.lock().unwrap();
+        discard_lookahead(&mut state);
+        let ended = track_ended(&state);
+        let Some(next_index) = next_queue_index(&mut state, ended) else {
+            return Ok(None);
+        };
+        state.queue_index = Some(next_index);
+        state.queue[next_index].clone()
+    };
+
+    load_queued_track(shared, next.path.clone())?;
+    play_impl(shared)?;
+    Ok(Some(next))
 }
 
-fn should_prefer_soxr(resampler_mode: &str, quality: &str, soxr_available: bool) -> bool {
-    if resampler_mode == "soxr" {
+#[derive(Debug, Clone, Serialize)]
+struct GaplessCheck {
+    gapless: bool,
+    /// Why not, when `gapless` is false.
+    reason: Option<String>,
+    current: Option<String>,
+    next: Option<String>,
+}
+
+/// The album position of `next` directly follows `current`'s. Tracks
+/// without numbers are taken to be in order, as queued.
+fn follows_on_album(current: &LibraryTrack, next: &LibraryTrack) -> bool {
+    let (Some(cur), Some(nxt)) = (current.track_number, next.track_number) else {
         return true;
+    };
+    let cur_disc = current.disc_number.unwrap_or(1);
+    let next_disc = next.disc_number.unwrap_or(1);
+    (next_disc == cur_disc && nxt == cur + 1) || (next_disc == cur_disc + 1 && nxt == 1)
+}
+
+/// Fill in format and album position for queue entries added without a
+/// scan.
+fn probe_missing_track_info(track: &mut LibraryTrack) {
+    if track.sample_rate.is_some() && track.channels.is_some() {
+        return;
     }
-    if resampler_mode == "rubato" {
-        return false;
+    if let Ok(probed) = read_library_track(Path::new(&track.path)) {
+        track.sample_rate = track.sample_rate.or(probed.sample_rate);
+        track.channels = track.channels.or(probed.channels);
+        track.track_number = track.track_number.or(probed.track_number);
+        track.disc_number = track.disc_number.or(probed.disc_number);
     }
-    if !soxr_available {
-        return false;
+}
+
+/// Why `next` can't follow `current` without a gap, or `None` when it can:
+/// same rate and channel count, so the output stream carries on unchanged,
+/// and the next track on the same album, since only then is there no
+/// intended pause between them.
+fn gapless_obstacle(current: &LibraryTrack, next: &LibraryTrack) -> Option<String> {
+    match (current.sample_rate, next.sample_rate, current.channels, next.channels) {
+        (Some(a), Some(b), _, _) if a != b => Some(format!("sample rate changes from {} to {} Hz", a, b)),
+        (_, _, Some(a), Some(b)) if a != b => Some(format!("channel count changes from {} to {}", a, b)),
+        (Some(_), Some(_), Some(_), Some(_)) => None,
+        _ => Some("format of one of the tracks is unknown".to_string()),
     }
-    !matches!(quality, "low" | "std")
+    .or_else(|| (!is_same_album(current, next)).then(|| "next track is from another album".to_string()))
+    .or_else(|| {
+        (!follows_on_album(current, next)).then(|| "next track is not the next one on the album".to_string())
+    })
 }
 
-fn normalize_dither_bits(bits: u32) -> u32 {
-    match bits {
-        16 | 24 => bits,
-        _ => 24,
+fn gapless_check_impl(shared: &SharedState) -> GaplessCheck {
+    let (mut current, next) = {
+        let state = shared.inner.lock().unwrap();
+        let Some(index) = state.queue_index.filter(|idx| *idx < state.queue.len()) else {
+            return GaplessCheck {
+                gapless: false,
+                reason: Some("nothing from the queue is playing".to_string()),
+                current: None,
+                next: None,
+            };
+        };
+        let mut current = state.queue[index].clone();
+        // The loaded file's decoded format beats what the scan probed.
+        if state.mode == "file" && state.file_path.as_deref() == Some(current.path.as_str()) {
+            current.sample_rate = Some(state.source_sample_rate).filter(|rate| *rate > 0);
+            current.channels = Some(state.source_channels as u32).filter(|ch| *ch > 0);
+        }
+        let next = peek_next_queue_index(&state, true).and_then(|next| state.queue.get(next));
+        (current, next.cloned())
+    };
+    let Some(mut next) = next else {
+        return GaplessCheck {
+            gapless: false,
+            reason: Some("no next track in the queue".to_string()),
+            current: Some(current.path),
+            next: None,
+        };
+    };
+    probe_missing_track_info(&mut current);
+    probe_missing_track_info(&mut next);
+    let reason = gapless_obstacle(&current, &next);
+    GaplessCheck {
+        gapless: reason.is_none(),
+        reason,
+        current: Some(current.path),
+        next: Some(next.path),
     }
 }
 
-fn normalize_limiter_threshold(value: f32) -> f32 {
-    value.clamp(0.7, 1.0)
+#[derive(Debug, Clone, Default)]
+struct ParsedCommand {
+    action: String,
+    query: Option<String>,
+    raw: String,
+    /// Return the matches for a play query instead of playing any of them.
+    disambiguate: bool,
+    /// What a "volume" command asks for, when the text said.
+    volume: Option<VolumeChange>,
+    /// Where a "seek" command goes, in seconds, when the text said.
+    seek: Option<f64>,
+}
+
+/// What a volume command asks for; levels are 0 to 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum VolumeChange {
+    Set(f32),
+    /// Up or down by the amount given, else by `command_volume_step`.
+    Up(Option<f32>),
+    Down(Option<f32>),
+    Mute,
+    /// Back to the volume before the last mute.
+    Unmute,
+}
+
+#[derive(Debug, Clone, Default)]
+struct CommandResult {
+    action: String,
+    matches: usize,
+    track: Option<LibraryTrack>,
+    /// Best matches, best first, when a play query has no single clear
+    /// winner (or `disambiguate` was set); nothing was played then.
+    candidates: Vec<LibraryTrack>,
+    /// The volume a "volume" command left.
+    volume: Option<f32>,
+    /// Where a "seek" command landed, in seconds.
+    position: Option<f64>,
 }
 
-#[cfg(test)]
-mod gapless_tests {
-    use super::apply_gapless_trim;
+fn normalize_command_text(text: &str) -> String {
+    text.trim().to_lowercase()
+}
 
-    #[test]
-    fn gapless_trim_noop() {
-        let samples = vec![0.1_f32, -0.2, 0.3, -0.4];
-        let trimmed = apply_gapless_trim(samples.clone(), 2, 0, 0);
-        assert_eq!(trimmed, samples);
+fn extract_query(raw: &str, key: &str) -> Option<String> {
+    if let Some(pos) = raw.find(key) {
+        let tail = raw[(pos + key.len())..].trim();
+        if tail.is_empty() {
+            None
+        } else {
+            Some(tail.to_string())
+        }
+    } else {
+        None
     }
+}
 
-    #[test]
-    fn gapless_trim_with_padding() {
-        // 2 channels, 4 frames => 8 samples.
-        let samples = (0..8).map(|v| v as f32).collect::<Vec<f32>>();
-        // Trim 1 leading frame and 1 trailing frame => keep frames 1..3 (2 frames).
-        let trimmed = apply_gapless_trim(samples, 2, 1, 1);
-        assert_eq!(trimmed, vec![2.0, 3.0, 4.0, 5.0]);
-    }
+const VOLUME_WORDS: [&str; 9] = [
+    "音量", "声音", "静音", "volume", "louder", "softer", "quieter", "mute", "unmute",
+];
+const VOLUME_UP_WORDS: [&str; 12] = [
+    "调大", "增大", "加大", "大一点", "大点", "调高", "up", "louder", "raise", "increase", "higher", "more",
+];
+const VOLUME_DOWN_WORDS: [&str; 12] = [
+    "调小", "减小", "降低", "小一点", "小点", "调低", "down", "softer", "quieter", "lower", "decrease", "less",
+];
+
+/// The first number in `text` as a volume level. Whole numbers are
+/// percentages, as is anything followed by `%`; other decimals are taken as
+/// they are, so "50", "50%" and "0.5" are all half. Clamped to 0 to 1.
+fn extract_volume_level(text: &str) -> Option<f32> {
+    let start = text.find(|c: char| c.is_ascii_digit())?;
+    let number: String = text[start..]
+        .chars()
+        .take_while(|c| c.is_ascii_digit() || *c == '.')
+        .collect();
+    let number = number.trim_end_matches('.');
+    let value: f32 = number.parse().ok()?;
+    let percent = !number.contains('.') || text[start + number.len()..].trim_start().starts_with('%');
+    Some(if percent { value / 100.0 } else { value }.clamp(0.0, 1.0))
+}
+
+/// Whether `text` has `word` in it. English words have to stand alone, so
+/// "commute" is not "mute"; Chinese has no spaces and matches anywhere.
+fn contains_word(text: &str, word: &str) -> bool {
+    if !word.is_ascii() {
+        return text.contains(word);
+    }
+    text.match_indices(word).any(|(start, _)| {
+        let before = text[..start].chars().next_back();
+        let after = text[start + word.len()..].chars().next();
+        !before.is_some_and(|c| c.is_ascii_alphanumeric()) && !after.is_some_and(|c| c.is_ascii_alphanumeric())
+    })
+}
 
-    #[test]
-    fn gapless_trim_all() {
-        let samples = vec![0.0_f32; 6];
-        let trimmed = apply_gapless_trim(samples, 2, 2, 2);
-        assert!(trimmed.is_empty());
+/// Read a volume command: mute or unmute, up or down (by a number if one
+/// is given), or a number to set.
+fn parse_volume_change(text: &str) -> Option<VolumeChange> {
+    let text = normalize_command_text(text);
+    let says = |words: &[&str]| words.iter().any(|word| contains_word(&text, word));
+    if says(&["取消静音", "unmute"]) {
+        return Some(VolumeChange::Unmute);
+    }
+    if says(&["静音", "mute"]) {
+        return Some(VolumeChange::Mute);
+    }
+    let level = extract_volume_level(&text);
+    if says(&VOLUME_UP_WORDS) {
+        Some(VolumeChange::Up(level))
+    } else if says(&VOLUME_DOWN_WORDS) {
+        Some(VolumeChange::Down(level))
+    } else {
+        level.map(VolumeChange::Set)
     }
 }
 
-#[cfg(test)]
-mod queue_tests {
-    use super::{create_shared_state, queue_add_impl, LibraryTrack};
+const SEEK_WORDS: [&str; 7] = ["跳转到", "跳到", "定位到", "seek", "goto", "go to", "jump to"];
 
-    fn track(path: &str) -> LibraryTrack {
-        LibraryTrack {
-            path: path.to_string(),
-            title: None,
-            artist: None,
-            album: None,
-            duration: 0.0,
-        }
+/// The first time in `text`, in seconds: plain seconds ("90", "90.5"),
+/// "m:ss" or "h:mm:ss". Fields after the first take two digits and stay
+/// under 60, so "1:05" is 65 seconds and "1:5" or "1:75" are not times.
+fn parse_seek_time(text: &str) -> Option<f64> {
+    let start = text.find(|c: char| c.is_ascii_digit())?;
+    let stamp: String = text[start..]
+        .chars()
+        .take_while(|c| c.is_ascii_digit() || *c == ':' || *c == '.')
+        .collect();
+    let stamp = stamp.trim_end_matches(['.', ':']);
+    let fields: Vec<&str> = stamp.split(':').collect();
+    if fields.len() > 3 {
+        return None;
+    }
+    let (last, leading) = fields.split_last()?;
+    let mut seconds = 0.0;
+    for field in leading {
+        seconds = seconds * 60.0 + field.parse::<u32>().ok()? as f64;
+    }
+    if leading.is_empty() {
+        return last.parse::<f64>().ok().filter(|secs| secs.is_finite());
     }
+    let (whole, _) = last.split_once('.').unwrap_or((last, ""));
+    let in_minute = last.parse::<f64>().ok().filter(|secs| *secs < 60.0)?;
+    if whole.len() != 2 || (fields.len() == 3 && fields[1].len() != 2) {
+        return None;
+    }
+    Some(seconds * 60.0 + in_minute)
+}
 
-    #[test]
-    fn queue_add_sets_index_for_current_path() {
-        let shared = create_shared_state();
-        {
-            let mut state = shared.inner.lock().unwrap();
-            state.file_path = Some("b.flac".to_string());
-        }
-        let count = queue_add_impl(&shared, vec![track("a.flac"), track("b.flac")], true);
-        assert_eq!(count, 2);
-        let state = shared.inner.lock().unwrap();
-        assert_eq!(state.queue_index, Some(1));
+fn parse_command_text(text: &str) -> Option<ParsedCommand> {
+    let raw = text.trim().to_string();
+    if raw.is_empty() {
+        return None;
+    }
+    let normalized = normalize_command_text(&raw);
+    // "play" names a track, which may well have one of these words in it.
+    let playing = normalized.starts_with("播放") || normalized.starts_with("play ");
+    if !playing && VOLUME_WORDS.iter().any(|word| contains_word(&normalized, word)) {
+        return Some(ParsedCommand {
+            action: "volume".to_string(),
+            query: None,
+            volume: parse_volume_change(&normalized),
+            raw,
+            disambiguate: false,
+            ..Default::default()
+        });
+    }
+    // "跳到下一曲" and the like aren't seeks; only a time makes one.
+    if let Some(seconds) = SEEK_WORDS
+        .iter()
+        .find_map(|word| extract_query(&normalized, word))
+        .and_then(|tail| parse_seek_time(&tail))
+    {
+        return Some(ParsedCommand {
+            action: "seek".to_string(),
+            query: None,
+            raw,
+            disambiguate: false,
+            seek: Some(seconds),
+            ..Default::default()
+        });
     }
+    let action = if normalized.contains("重新播放") || normalized.contains("restart") {
+        "restart"
+    } else if normalized.contains("暂停") || normalized.contains("pause") {
+        "pause"
+    } else if normalized.contains("停止") || normalized.contains("stop") {
+        "stop"
+    } else if normalized.contains("下一曲") || normalized.contains("next") {
+        "next"
+    } else if normalized.contains("上一曲") || normalized.contains("previous") {
+        "prev"
+    } else if normalized.contains("继续") || normalized.contains("resume") {
+        "play"
+    } else if normalized.contains("播放") || normalized.contains("play") {
+        "play"
+    } else {
+        "unknown"
+    };
 
-    #[test]
-    fn queue_add_clears_index_when_missing() {
-        let shared = create_shared_state();
-        {
-            let mut state = shared.inner.lock().unwrap();
-            state.file_path = Some("missing.flac".to_string());
+    let mut query = None;
+    if action == "play" {
+        query = extract_query(&raw, "播放")
+            .or_else(|| extract_query(&normalized, "play "))
+            .or_else(|| extract_query(&normalized, "play"));
+    }
+    Some(ParsedCommand {
+        action: action.to_string(),
+        query,
+        raw,
+        disambiguate: false,
+        ..Default::default()
+    })
+}
+
+/// Apply a volume command and return the volume it leaves.
+fn apply_volume_change(state: &mut EngineState, change: VolumeChange) -> f32 {
+    let step = state.command_volume_step;
+    let target = match change {
+        VolumeChange::Set(level) => level,
+        VolumeChange::Up(by) => state.volume + by.unwrap_or(step),
+        VolumeChange::Down(by) => state.volume - by.unwrap_or(step),
+        VolumeChange::Mute => {
+            if state.volume > 0.0 {
+                state.muted_volume = Some(state.volume);
+            }
+            0.0
+        }
+        VolumeChange::Unmute => state.muted_volume.unwrap_or(state.volume),
+    };
+    if change != VolumeChange::Mute {
+        state.muted_volume = None;
+    }
+    set_volume_target(state, target);
+    state.volume
+}
+
+const MATCH_TITLE_EXACT: u32 = 100;
+const MATCH_TITLE_PARTIAL: u32 = 60;
+const MATCH_TAG_EXACT: u32 = 50;
+const MATCH_TAG_PARTIAL: u32 = 30;
+const MATCH_PATH: u32 = 10;
+/// Most a fuzzy match scores, for letters that appear in order but not
+/// together; below any substring match but above a path hit.
+const MATCH_TITLE_FUZZY: u32 = 25;
+const MATCH_TAG_FUZZY: u32 = 20;
+/// Most candidates a command returns for the user to choose from.
+const MAX_COMMAND_CANDIDATES: usize = 10;
+/// Results `/library/search` returns unless asked for another number.
+const DEFAULT_SEARCH_LIMIT: usize = 50;
+
+/// `text` lowercased with accents taken off (NFKD, then combining marks
+/// dropped), so "Café" matches "cafe"; NFKD also folds full-width and
+/// other compatibility forms into the plain ones.
+fn fold_for_match(text: &str) -> String {
+    text.nfkd()
+        .filter(|c| !is_combining_mark(*c))
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// How tightly the letters of `query`, spaces aside, appear in order in
+/// `value`: 1.0 when together, less as they spread out, so a dropped or
+/// doubled letter still matches. `None` when some don't appear, or they
+/// are spread over more than twice their length; queries under three
+/// letters are too short to match this loosely.
+fn subsequence_closeness(value: &str, query: &str) -> Option<f32> {
+    let wanted: Vec<char> = query.chars().filter(|c| !c.is_whitespace()).collect();
+    if wanted.len() < 3 {
+        return None;
+    }
+    let chars: Vec<char> = value.chars().collect();
+    let mut tightest: Option<usize> = None;
+    for start in (0..chars.len()).filter(|start| chars[*start] == wanted[0]) {
+        let mut found = 1;
+        let mut end = start;
+        for (index, c) in chars.iter().enumerate().skip(start + 1) {
+            if found == wanted.len() {
+                break;
+            }
+            if *c == wanted[found] {
+                found += 1;
+                end = index;
+            }
+        }
+        if found == wanted.len() {
+            let span = end - start + 1;
+            tightest = Some(tightest.map_or(span, |best| best.min(span)));
         }
-        let count = queue_add_impl(&shared, vec![track("a.flac")], true);
-        assert_eq!(count, 1);
-        let state = shared.inner.lock().unwrap();
-        assert_eq!(state.queue_index, None);
     }
+    let closeness = wanted.len() as f32 / tightest? as f32;
+    (closeness >= 0.5).then_some(closeness)
+}
+
+/// How well `track` matches a play query, or `None` if it doesn't. The
+/// title counts most, then artists and album, then the file path; each
+/// scores highest when equal, then when containing the query, then when
+/// only a fuzzy match. Case and accents are ignored throughout.
+fn track_match_score(track: &LibraryTrack, query: &str) -> Option<u32> {
+    let q = fold_for_match(query);
+    let score = |value: &str, exact: u32, partial: u32, fuzzy: u32| {
+        let value = fold_for_match(value);
+        if value == q {
+            Some(exact)
+        } else if value.contains(&q) {
+            Some(partial)
+        } else {
+            subsequence_closeness(&value, &q).map(|closeness| (fuzzy as f32 * closeness).round() as u32)
+        }
+    };
+    let title = track
+        .title
+        .as_deref()
+        .and_then(|t| score(t, MATCH_TITLE_EXACT, MATCH_TITLE_PARTIAL, MATCH_TITLE_FUZZY));
+    let tags = track
+        .artist
+        .iter()
+        .chain(&track.artists)
+        .chain(&track.album_artist)
+        .chain(&track.album)
+        .filter_map(|value| score(value, MATCH_TAG_EXACT, MATCH_TAG_PARTIAL, MATCH_TAG_FUZZY))
+        .max();
+    let path = fold_for_match(&track.path).contains(&q).then_some(MATCH_PATH);
+    title.max(tags).max(path)
+}
+
+/// Every track in `library` that matches `query`, with its score, best
+/// first; equal scores keep library order.
+fn rank_library(library: Vec<LibraryTrack>, query: &str) -> Vec<(u32, LibraryTrack)> {
+    let mut scored: Vec<(u32, LibraryTrack)> = library
+        .into_iter()
+        .filter_map(|track| Some((track_match_score(&track, query)?, track)))
+        .collect();
+    scored.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+    scored
+}
+
+/// How many library tracks match `query`, and the best `limit` of them.
+fn library_search_impl(shared: &SharedState, query: &str, limit: Option<usize>) -> Result<(usize, Vec<LibraryTrack>)> {
+    let query = query.trim();
+    if query.is_empty() {
+        return Err(anyhow!("query empty"));
+    }
+    let library = shared.inner.lock().unwrap().library.clone();
+    let ranked = rank_library(library, query);
+    let total = ranked.len();
+    let limit = limit.unwrap_or(DEFAULT_SEARCH_LIMIT).max(1);
+    Ok((total, ranked.into_iter().take(limit).map(|(_, track)| track).collect()))
 }
 
-fn normalize_dither_type(value: &str) -> String {
-    match value.to_lowercase().as_str() {
-        "off" => "off".to_string(),
-        "tpdf" => "tpdf".to_string(),
-        "tpdf_ns1" => "tpdf_ns1".to_string(),
-        "tpdf_ns2" => "tpdf_ns2".to_string(),
-        _ => "tpdf".to_string(),
+fn cover_dir() -> PathBuf {
+    if let Ok(dir) = std::env::var("NTMUSIC_COVER_DIR") {
+        if !dir.trim().is_empty() {
+            return PathBuf::from(dir);
+        }
     }
+    std::env::temp_dir().join("ntmusic_covers")
 }
 
-fn reset_dither_shape_state(state: &mut EngineState) {
-    state.dither_shape_err1.fill(0.0);
-    state.dither_shape_err2.fill(0.0);
+fn scan_cache_path() -> PathBuf {
+    let dir = match std::env::var("NTMUSIC_CACHE_DIR") {
+        Ok(dir) if !dir.trim().is_empty() => PathBuf::from(dir),
+        _ => std::env::temp_dir().join("ntmusic_cache"),
+    };
+    dir.join("library_scan.json")
 }
 
-fn get_sinc_params(quality: &str, ratio: f64) -> SincInterpolationParameters {
-    let f_cutoff = if ratio < 1.0 { 0.90 } else { 0.95 };
-    match quality {
-        "low" => SincInterpolationParameters {
-            sinc_len: 64,
-            f_cutoff,
-            interpolation: SincInterpolationType::Linear,
-            oversampling_factor: 64,
-            window: WindowFunction::Hann,
-        },
-        "std" => SincInterpolationParameters {
-            sinc_len: 128,
-            f_cutoff,
-            interpolation: SincInterpolationType::Linear,
-            oversampling_factor: 128,
-            window: WindowFunction::Blackman,
-        },
-        "hq" => SincInterpolationParameters {
-            sinc_len: 256,
-            f_cutoff,
-            interpolation: SincInterpolationType::Linear,
-            oversampling_factor: 256,
-            window: WindowFunction::BlackmanHarris2,
-        },
-        _ => SincInterpolationParameters {
-            sinc_len: 512,
-            f_cutoff,
-            interpolation: SincInterpolationType::Cubic,
-            oversampling_factor: 512,
-            window: WindowFunction::BlackmanHarris2,
-        },
+/// `NTMUSIC_TRACK_GAINS`, else `track_gains.json` beside the settings,
+/// where the OS won't clear it the way it does the temp dir.
+fn track_gains_path() -> PathBuf {
+    if let Ok(path) = std::env::var("NTMUSIC_TRACK_GAINS") {
+        if !path.trim().is_empty() {
+            return PathBuf::from(path);
+        }
     }
+    settings_path()
+        .and_then(|path| path.parent().map(|dir| dir.join("track_gains.json")))
+        .unwrap_or_else(|| std::env::temp_dir().join("ntmusic_track_gains.json"))
 }
 
-fn resample_audio(
-    data: &[f32],
-    channels: usize,
-    from_rate: u32,
-    to_rate: u32,
-    quality: &str,
-) -> Result<Vec<f32>> {
-    if data.is_empty() || channels == 0 || from_rate == 0 || to_rate == 0 {
-        return Ok(data.to_vec());
-    }
-    if from_rate == to_rate {
-        return Ok(data.to_vec());
+/// `NTMUSIC_SETTINGS`, else `ntmusic/settings.json` in the platform's
+/// config directory.
+fn settings_path() -> Option<PathBuf> {
+    if let Ok(path) = std::env::var("NTMUSIC_SETTINGS") {
+        if !path.trim().is_empty() {
+            return Some(PathBuf::from(path));
+        }
     }
+    let var = |name: &str| std::env::var_os(name).filter(|value| !value.is_empty()).map(PathBuf::from);
+    let dir = if cfg!(target_os = "windows") {
+        var("APPDATA")
+    } else if cfg!(target_os = "macos") {
+        var("HOME").map(|home| home.join("Library").join("Application Support"))
+    } else {
+        var("XDG_CONFIG_HOME").or_else(|| var("HOME").map(|home| home.join(".config")))
+    };
+    dir.map(|dir| dir.join("ntmusic").join("settings.json"))
+}
 
-    let frames = data.len() / channels;
-    if frames == 0 {
-        return Ok(Vec::new());
-    }
+/// How often the settings writer checks for changes, so a dragged volume
+/// slider writes the file a few times rather than once per step.
+const SETTINGS_SAVE_INTERVAL_MS: u64 = 500;
 
-    let ratio = to_rate as f64 / from_rate as f64;
-    let params = get_sinc_params(quality, ratio);
+/// Note that an endpoint changed a setting that outlives a restart; the
+/// settings writer saves it off the request path.
+fn mark_settings_changed(shared: &SharedState) {
+    shared.settings_dirty.store(true, Ordering::Release);
+}
 
-    let mut waves_in: Vec<Vec<f64>> = vec![Vec::with_capacity(frames); channels];
-    for frame in data.chunks_exact(channels) {
-        for (ch, &sample) in frame.iter().enumerate() {
-            waves_in[ch].push(sample as f64);
-        }
+/// Write the settings if any changed since the last write.
+fn flush_settings(shared: &SharedState) {
+    if !shared.settings_dirty.swap(false, Ordering::AcqRel) {
+        return;
     }
+    let (path, settings) = {
+        let state = shared.inner.lock().unwrap();
+        (state.settings_path.clone(), PersistedSettings::from_state(&state))
+    };
+    let Some(path) = path else {
+        return;
+    };
+    if let Err(err) = settings.save(&path) {
+        warn!("could not save settings: {}", err);
+    }
+}
 
-    let mut resampler = SincFixedIn::new(ratio, 2.0, params, frames, channels)
-        .map_err(|err| anyhow!("resampler init failed: {}", err))?;
-    let waves_out = resampler
-        .process(&waves_in, None)
-        .map_err(|err| anyhow!("resampler process failed: {}", err))?;
-
-    let out_frames = waves_out.get(0).map_or(0, |v| v.len());
-    let mut output = vec![0.0f32; out_frames * channels];
-    for i in 0..out_frames {
-        for (ch, channel_data) in waves_out.iter().enumerate() {
-            if let Some(sample) = channel_data.get(i) {
-                output[i * channels + ch] = *sample as f32;
-            }
+/// A missing or unreadable file starts with no overrides.
+fn load_track_gains(path: &Path) -> HashMap<String, f32> {
+    let Ok(text) = std::fs::read_to_string(path) else {
+        return HashMap::new();
+    };
+    match serde_json::from_str::<HashMap<String, f32>>(&text) {
+        Ok(gains) => gains
+            .into_iter()
+            .filter(|(_, db)| db.is_finite())
+            .map(|(path, db)| (path, normalize_track_gain_db(db)))
+            .collect(),
+        Err(err) => {
+            warn!("ignoring unreadable track gains at {}: {}", path.display(), err);
+            HashMap::new()
         }
     }
-
-    Ok(output)
 }
 
-fn resample_audio_soxr(
-    data: &[f32],
-    channels: usize,
-    from_rate: u32,
-    to_rate: u32,
-) -> Result<Vec<f32>> {
-    if data.is_empty() || channels == 0 || from_rate == 0 || to_rate == 0 {
-        return Ok(data.to_vec());
+fn save_track_gains(path: &Path, gains: &HashMap<String, f32>) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).context("create track gains dir")?;
     }
-    if from_rate == to_rate {
-        return Ok(data.to_vec());
+    let temp = path.with_extension(format!("json.{}.tmp", uuid::Uuid::new_v4().simple()));
+    std::fs::write(&temp, serde_json::to_vec_pretty(gains)?).context("write track gains")?;
+    std::fs::rename(&temp, path).context("replace track gains")?;
+    Ok(())
+}
+
+fn cover_extension(media_type: &str) -> &'static str {
+    match media_type {
+        "image/jpeg" | "image/jpg" => "jpg",
+        "image/png" => "png",
+        "image/webp" => "webp",
+        "image/bmp" => "bmp",
+        _ => "bin",
     }
+}
 
-    let frames = data.len() / channels;
-    if frames == 0 {
-        return Ok(Vec::new());
+fn cover_hash_key(path: &Path) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    path.to_string_lossy().hash(&mut hasher);
+    hasher.finish()
+}
+
+const COVER_EXTENSIONS: &[&str] = &["jpg", "png", "webp", "bmp", "bin"];
+
+fn invalidate_cover_cache(path: &Path) {
+    let dir = cover_dir();
+    let hash = cover_hash_key(path);
+    for ext in COVER_EXTENSIONS {
+        let _ = std::fs::remove_file(dir.join(format!("cover_{}.{}", hash, ext)));
     }
+}
 
-    let lib = soxr_library().ok_or_else(|| anyhow!("soxr library not available"))?;
-    let instance = SoxrInstance::new(lib, from_rate, to_rate, channels)?;
-    let ratio = to_rate as f64 / from_rate as f64;
-    let mut output = Vec::with_capacity(((frames as f64 * ratio).ceil() as usize + 256) * channels);
+/// Largest cover `/cover` will return inline.
+const INLINE_COVER_MAX_BYTES: usize = 5 * 1024 * 1024;
 
-    let mut offset = 0usize;
-    let chunk_frames = 8192usize;
-    while offset < frames {
-        let frames_in = (frames - offset).min(chunk_frames);
-        let in_start = offset * channels;
-        let in_end = in_start + frames_in * channels;
-        let in_slice = &data[in_start..in_end];
+fn cover_data_uri(data: &[u8], media_type: &str) -> String {
+    use base64::Engine as _;
+    let media_type = if media_type.is_empty() { "application/octet-stream" } else { media_type };
+    format!("data:{};base64,{}", media_type, base64::engine::general_purpose::STANDARD.encode(data))
+}
 
-        let out_capacity_frames = ((frames_in as f64 * ratio).ceil() as usize + 64).max(1);
-        let mut out_chunk = vec![0.0f32; out_capacity_frames * channels];
-        let mut idone = 0usize;
-        let mut odone = 0usize;
-        let err = unsafe {
-            (instance.lib.process)(
-                instance.handle,
-                in_slice.as_ptr() as *const c_void,
-                frames_in,
-                &mut idone,
-                out_chunk.as_mut_ptr() as *mut c_void,
-                out_capacity_frames,
-                &mut odone,
-            )
-        };
-        if !err.is_null() {
-            let message = unsafe { CStr::from_ptr(err).to_string_lossy().into_owned() };
-            return Err(anyhow!("soxr_process failed: {}", message));
-        }
-        if idone != frames_in {
-            return Err(anyhow!(
-                "soxr_process consumed {} of {} frames",
-                idone,
-                frames_in
-            ));
+fn write_cover_file(path: &Path, data: &[u8], media_type: &str) -> Result<PathBuf> {
+    let dir = cover_dir();
+    std::fs::create_dir_all(&dir).context("create cover dir")?;
+    let hash = cover_hash_key(path);
+    let ext = cover_extension(media_type);
+    let filename = format!("cover_{}.{}", hash, ext);
+    let cover_path = dir.join(filename);
+    if cover_path.exists() {
+        return Ok(cover_path);
+    }
+    std::fs::write(&cover_path, data).context("write cover file")?;
+    Ok(cover_path)
+}
+
+fn extract_cover_art(path: &Path) -> Result<Option<(Vec<u8>, String)>> {
+    let file = File::open(path).with_context(|| format!("open {:?}", path))?;
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(ext);
+    }
+    let probed = symphonia::default::get_probe()
+        .format(&hint, mss, &FormatOptions::default(), &MetadataOptions::default())
+        .with_context(|| format!("probe {:?}", path))?;
+    let mut format = probed.format;
+    let visuals = format
+        .metadata()
+        .current()
+        .map(|rev| rev.visuals().to_vec())
+        .unwrap_or_default();
+    if visuals.is_empty() {
+        return Ok(None);
+    }
+    let preferred = visuals.iter().find(|visual| {
+        matches!(visual.usage, Some(StandardVisualKey::FrontCover))
+    });
+    let visual = preferred.unwrap_or_else(|| &visuals[0]);
+    let data = visual.data.to_vec();
+    if data.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some((data, visual.media_type.clone())))
+}
+
+/// Embedded lyrics (`LYRICS`, `UNSYNCEDLYRICS` or ID3 `USLT`), preferring
+/// a synced copy when the file carries several.
+fn extract_lyrics(path: &Path) -> Result<Option<String>> {
+    let file = File::open(path).with_context(|| format!("open {:?}", path))?;
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(ext);
+    }
+    let mut probed = symphonia::default::get_probe()
+        .format(&hint, mss, &FormatOptions::default(), &MetadataOptions::default())
+        .with_context(|| format!("probe {:?}", path))?;
+    let lyrics: Vec<String> = probed_tags(&mut probed)
+        .iter()
+        .filter(|tag| matches!(tag.std_key, Some(StandardTagKey::Lyrics)))
+        .filter_map(tag_value_to_string)
+        .collect();
+    Ok(lyrics.iter().find(|text| is_synced_lyrics(text)).or(lyrics.first()).cloned())
+}
+
+/// LRC-style lyrics: some line starts with an `[mm:ss.xx]` timestamp.
+fn is_synced_lyrics(text: &str) -> bool {
+    text.lines().any(|line| {
+        let Some((stamp, _)) = line.trim_start().strip_prefix('[').and_then(|rest| rest.split_once(']')) else {
+            return false;
+        };
+        let Some((minutes, seconds)) = stamp.split_once(':') else {
+            return false;
+        };
+        let digits = |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit());
+        let (whole, fraction) = seconds.split_once('.').unwrap_or((seconds, "0"));
+        digits(minutes) && digits(whole) && digits(fraction)
+    })
+}
+
+fn handle_command_impl(shared: &SharedState, cmd: ParsedCommand) -> Result<CommandResult> {
+    match cmd.action.as_str() {
+        "restart" => {
+            restart_impl(shared)?;
+            Ok(CommandResult {
+                action: cmd.action,
+                matches: 0,
+                track: None,
+                candidates: Vec::new(),
+                ..Default::default()
+            })
+        }
+        "pause" => {
+            pause_impl(shared)?;
+            Ok(CommandResult {
+                action: cmd.action,
+                matches: 0,
+                track: None,
+                candidates: Vec::new(),
+                ..Default::default()
+            })
+        }
+        "stop" => {
+            stop_impl(shared)?;
+            Ok(CommandResult {
+                action: cmd.action,
+                matches: 0,
+                track: None,
+                candidates: Vec::new(),
+                ..Default::default()
+            })
+        }
+        "next" => match queue_next_impl(shared)? {
+            Some(track) => Ok(CommandResult {
+                action: cmd.action,
+                matches: 1,
+                track: Some(track),
+                candidates: Vec::new(),
+                ..Default::default()
+            }),
+            None => Err(anyhow!("queue empty")),
+        },
+        "play" => {
+            if let Some(query) = cmd.query.clone().filter(|q| !q.is_empty()) {
+                let library = {
+                    let state = shared.inner.lock().unwrap();
+                    state.library.clone()
+                };
+                if library.is_empty() {
+                    return Err(anyhow!("library empty, scan first"));
+                }
+                let scored = rank_library(library, &query);
+                if scored.is_empty() {
+                    return Err(anyhow!("no matches for query"));
+                }
+                let count = scored.len();
+                let best = scored[0].0;
+                // Several equally good title hits are different songs the
+                // user could have meant; an artist or album query means
+                // all of them, so those play as a queue.
+                let tied = scored.iter().take_while(|(score, _)| *score == best).count();
+                if cmd.disambiguate || (best >= MATCH_TITLE_PARTIAL && tied > 1) {
+                    let shortlist = if cmd.disambiguate { count } else { tied };
+                    return Ok(CommandResult {
+                        action: cmd.action,
+                        matches: count,
+                        track: None,
+                        candidates: scored
+                            .into_iter()
+                            .take(shortlist.min(MAX_COMMAND_CANDIDATES))
+                            .map(|(_, track)| track)
+                            .collect(),
+                        ..Default::default()
+                    });
+                }
+                queue_add_impl(shared, scored.into_iter().map(|(_, track)| track).collect(), true);
+                let next = queue_next_impl(shared)?;
+                Ok(CommandResult {
+                    action: cmd.action,
+                    matches: count,
+                    track: next,
+                    candidates: Vec::new(),
+                    ..Default::default()
+                })
+            } else {
+                let has_file = {
+                    let state = shared.inner.lock().unwrap();
+                    state.file_path.is_some()
+                };
+                if has_file {
+                    play_impl(shared)?;
+                    return Ok(CommandResult {
+                        action: cmd.action,
+                        matches: 0,
+                        track: None,
+                        candidates: Vec::new(),
+                        ..Default::default()
+                    });
+                }
+                match queue_next_impl(shared)? {
+                    Some(track) => Ok(CommandResult {
+                        action: cmd.action,
+                        matches: 1,
+                        track: Some(track),
+                        candidates: Vec::new(),
+                        ..Default::default()
+                    }),
+                    None => Err(anyhow!("no track loaded")),
+                }
+            }
+        }
+        "volume" => {
+            let change = cmd
+                .volume
+                .or_else(|| cmd.query.as_deref().and_then(parse_volume_change))
+                .ok_or_else(|| anyhow!("volume needs a level, up, down or mute"))?;
+            let volume = apply_volume_change(&mut shared.inner.lock().unwrap(), change);
+            mark_settings_changed(shared);
+            send_state(shared);
+            Ok(CommandResult {
+                action: cmd.action,
+                matches: 0,
+                track: None,
+                candidates: Vec::new(),
+                volume: Some(volume),
+                ..Default::default()
+            })
+        }
+        "seek" => {
+            let seconds = cmd
+                .seek
+                .or_else(|| cmd.query.as_deref().and_then(parse_seek_time))
+                .ok_or_else(|| anyhow!("seek needs a time, as seconds or m:ss"))?;
+            if shared.inner.lock().unwrap().mode != "file" {
+                return Err(anyhow!("seek command only supported in file mode"));
+            }
+            let position = seek_file_impl(shared, seconds)?;
+            send_state(shared);
+            Ok(CommandResult {
+                action: cmd.action,
+                matches: 0,
+                track: None,
+                candidates: Vec::new(),
+                position: Some(position),
+                ..Default::default()
+            })
+        }
+        "prev" => match queue_prev_impl(shared)? {
+            Some(track) => Ok(CommandResult {
+                action: cmd.action,
+                matches: 1,
+                track: Some(track),
+                candidates: Vec::new(),
+                ..Default::default()
+            }),
+            None => Err(anyhow!("no previous track")),
+        },
+        _ => Err(anyhow!("unknown command")),
+    }
+}
+
+/// A capture refused for something the request asked for, as opposed to
+/// a device that failed to open.
+#[derive(Debug, PartialEq)]
+enum CaptureError {
+    /// Monitoring aimed at the device the capture records, which would
+    /// feed the capture back into itself.
+    FeedbackLoop { target: String },
+    /// A loopback capture asked for a rate or channel count the endpoint's
+    /// mix format doesn't have; loopback can't convert, so it records in
+    /// that format or not at all.
+    #[cfg(any(target_os = "windows", test))]
+    LoopbackFormat {
+        requested_rate: Option<u32>,
+        requested_channels: Option<u16>,
+        sample_rate: u32,
+        channels: u16,
+    },
+}
+
+impl CaptureError {
+    /// A `LoopbackFormat` error, or `None` when every requested value
+    /// matches the mix format.
+    #[cfg(any(target_os = "windows", test))]
+    fn check_loopback_format(
+        requested_rate: Option<u32>,
+        requested_channels: Option<u16>,
+        sample_rate: u32,
+        channels: u16,
+    ) -> Option<Self> {
+        let mismatch = requested_rate.is_some_and(|rate| rate != sample_rate)
+            || requested_channels.is_some_and(|count| count != channels);
+        mismatch.then_some(CaptureError::LoopbackFormat { requested_rate, requested_channels, sample_rate, channels })
+    }
+}
+
+impl std::fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CaptureError::FeedbackLoop { target } => write!(
+                f,
+                "monitoring on {} would feed the capture back into itself; \
+                 choose another monitor_device_id or set monitor to false",
+                target
+            ),
+            #[cfg(any(target_os = "windows", test))]
+            CaptureError::LoopbackFormat { requested_rate, requested_channels, sample_rate, channels } => write!(
+                f,
+                "loopback records {} Hz, {} channels; {} Hz, {} channels was requested. \
+                 Leave samplerate and channels unset to use the device format",
+                sample_rate,
+                channels,
+                requested_rate.unwrap_or(*sample_rate),
+                requested_channels.unwrap_or(*channels)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CaptureError {}
+
+fn same_device_name(a: &str, b: &str) -> bool {
+    a.trim().eq_ignore_ascii_case(b.trim())
+}
+
+/// Name of the output device a loopback capture of `device_id` records;
+/// `None` for an input such as a line-in, which cannot feed back.
+fn capture_source_name(device_id: Option<&str>) -> Option<String> {
+    match device_id.filter(|id| !id.is_empty() && *id != "default") {
+        Some(id) => match capture_devices().into_iter().find(|device| device.id == id) {
+            Some(device) if device.kind == "input" => None,
+            Some(device) => Some(device.name),
+            None => Some(id.to_string()),
+        },
+        None => cpal::default_host().default_output_device()?.name().ok(),
+    }
+}
+
+fn output_device_name(device_id: Option<usize>, hostapi: Option<&str>) -> Option<String> {
+    match output_device_id(device_id, hostapi) {
+        Some(id) => find_device_by_id(id)?.name().ok(),
+        None => output_host(hostapi).default_output_device()?.name().ok(),
+    }
+}
+
+fn start_capture_impl(
+    shared: &SharedState,
+    device_id: Option<String>,
+    samplerate: Option<u32>,
+    channels: Option<u16>,
+    monitor: CaptureMonitor,
+    loopback: bool,
+) -> Result<()> {
+    let (current_output, hostapi) = {
+        let state = shared.inner.lock().unwrap();
+        (state.device_id, state.hostapi.clone())
+    };
+    let monitor_output = monitor.device_id.or(current_output);
+    if monitor.enabled {
+        let source = capture_source_name(device_id.as_deref());
+        let target = output_device_name(monitor_output, hostapi.as_deref());
+        if let (Some(source), Some(target)) = (source, target) {
+            if same_device_name(&source, &target) {
+                return Err(CaptureError::FeedbackLoop { target }.into());
+            }
+        }
+    }
+    stop_stream(shared);
+    let monitor_key = monitor.device_id.map(device_key);
+    let requested_channels = channels;
+    let (sample_rate, channels) = {
+        let mut state = shared.inner.lock().unwrap();
+        if state.capture_saved_output.is_none() {
+            let saved = (state.device_id, state.selected_device.clone(), state.output_latency_ms);
+            state.capture_saved_output = Some(saved);
+        }
+        state.device_id = monitor_output;
+        if let Some(key) = monitor_key {
+            state.selected_device = key;
+            state.device_fallback = false;
+        }
+        if let Some(latency_ms) = monitor.latency_ms {
+            state.output_latency_ms = Some(latency_ms).filter(|ms| *ms > 0);
+        }
+        state.capture_monitor = monitor.enabled;
+        state.mode = "capture".to_string();
+        state.sample_rate = samplerate.unwrap_or(48_000);
+        state.resampler_info = None;
+        state.channels = channels.unwrap_or(2) as usize;
+        state.source_sample_rate = state.sample_rate;
+        state.source_channels = state.channels;
+        state.source_bit_depth = None;
+        state.partial_decode = None;
+        state.gapless_trim = None;
+        state.replaygain = ReplayGainInfo::default();
+        state.dsd_output = None;
+        refresh_replaygain_gain(&mut state);
+        state.track_gain_db = 0.0;
+        state.data.clear();
+        state.incremental = None;
+        state.position = 0;
+        state.played_frames = 0;
+        state.duration = 0.0;
+        state.buffered_frames = 0;
+        state.stream_status = "starting".to_string();
+        state.stream_url = device_id.clone();
+        (state.sample_rate, state.channels as u16)
+    };
+    reset_ring_buffer(shared);
+    if loopback && cfg!(target_os = "windows") {
+        if let Err(err) = start_wasapi_loopback(shared, device_id, samplerate, requested_channels) {
+            update_stream_status(shared, "error", Some(err.to_string()));
+            return Err(err);
+        }
+    } else {
+        let child = match spawn_capture_ffmpeg(device_id.as_deref(), sample_rate, channels) {
+            Ok(child) => child,
+            Err(err) => {
+                update_stream_status(shared, "error", Some(err.to_string()));
+                return Err(err);
+            }
+        };
+        start_stream_reader(shared.clone(), child);
+    }
+    // Rebuild the stream so the monitor device and latency take effect.
+    stop_exclusive_stream(shared);
+    shared.output_stream.lock().unwrap().0 = None;
+    shared.inner.lock().unwrap().output_config = None;
+    let _ = ensure_output_stream(shared);
+    send_state(shared);
+    Ok(())
+}
+
+fn stop_capture_impl(shared: &SharedState) -> Result<()> {
+    stop_stream(shared);
+    let restored = {
+        let mut state = shared.inner.lock().unwrap();
+        state.mode = "idle".to_string();
+        state.is_playing = false;
+        state.is_paused = false;
+        state.capture_monitor = true;
+        let saved = state.capture_saved_output.take();
+        let changed = saved.as_ref().is_some_and(|(device, _, latency)| {
+            *device != state.device_id || *latency != state.output_latency_ms
+        });
+        if let Some((device, selected, latency)) = saved {
+            state.device_id = device;
+            state.selected_device = selected;
+            state.output_latency_ms = latency;
+        }
+        changed
+    };
+    if restored {
+        stop_exclusive_stream(shared);
+        shared.output_stream.lock().unwrap().0 = None;
+        shared.inner.lock().unwrap().output_config = None;
+    }
+    send_state(shared);
+    Ok(())
+}
+fn send_state(shared: &SharedState) {
+    let state = shared.inner.lock().unwrap();
+    let payload = json!({
+        "type": "playback_state",
+        "state": build_state_view(&state)
+    });
+    let _ = shared.tx.send(payload.to_string());
+}
+
+/// How full the ring buffer is, 0 (underrun risk) to 1 (the reader is
+/// about to drop samples).
+fn buffer_fill_ratio(state: &EngineState) -> f64 {
+    if state.ring_capacity_frames == 0 {
+        return 0.0;
+    }
+    (state.buffered_frames as f64 / state.ring_capacity_frames as f64).min(1.0)
+}
+
+/// Sent the first time a stream drops samples; later drops only show up in
+/// `dropped_samples`.
+fn buffer_overflow_payload(state: &EngineState) -> Value {
+    json!({
+        "type": "buffer_overflow",
+        "dropped_samples": state.dropped_sample_count,
+        "buffer_fill": buffer_fill_ratio(state),
+        "buffer_max_ms": state.buffer_max_ms,
+        "message": "ring buffer full; samples were dropped, consider a larger buffer_max_ms"
+    })
+}
+
+fn send_buffer_state(shared: &SharedState) {
+    let state = shared.inner.lock().unwrap();
+    let payload = json!({
+        "type": "buffer_state",
+        "buffered_ms": if state.sample_rate > 0 {
+            (state.buffered_frames as f64 / state.sample_rate as f64) * 1000.0
+        } else {
+            0.0
+        },
+        "buffer_fill": buffer_fill_ratio(&state),
+        "resampler_latency_ms": live_resampler_latency_ms(&state),
+        "eq_latency_ms": eq_latency_ms(&state),
+        "underruns": state.underrun_count,
+        "dropped_samples": state.dropped_sample_count,
+        "clipped_samples": state.clip_count,
+        "mode": state.mode.clone()
+    });
+    let _ = shared.tx.send(payload.to_string());
+}
+
+/// Audio held inside the live resampler on top of what the ring buffer
+/// reports.
+fn live_resampler_latency_ms(state: &EngineState) -> f64 {
+    let device_rate = state.output_config.as_ref().map_or(0, |config| config.sample_rate);
+    match &state.live_resampler.0 {
+        Some(live) if device_rate > 0 => live.latency_frames() as f64 * 1000.0 / device_rate as f64,
+        _ => 0.0,
+    }
+}
+
+/// Audio held inside the FIR EQ, at the rate it runs at.
+fn eq_latency_ms(state: &EngineState) -> f64 {
+    let sample_rate = state.eq_filters.sample_rate();
+    if !state.eq_enabled || sample_rate == 0 {
+        return 0.0;
+    }
+    state.eq_filters.latency_frames() as f64 * 1000.0 / sample_rate as f64
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+struct DiagnosticCounters {
+    underruns: u64,
+    dropped_samples: u64,
+    clipped_samples: u64,
+}
+
+/// Zero the glitch counters, e.g. to compare buffer settings from a clean
+/// slate, and return what they were.
+fn reset_diagnostics_impl(shared: &SharedState) -> DiagnosticCounters {
+    let previous = {
+        let mut state = shared.inner.lock().unwrap();
+        let previous = DiagnosticCounters {
+            underruns: state.underrun_count,
+            dropped_samples: state.dropped_sample_count,
+            clipped_samples: state.clip_count,
+        };
+        state.underrun_count = 0;
+        state.dropped_sample_count = 0;
+        state.clip_count = 0;
+        previous
+    };
+    let _ = shared
+        .tx
+        .send(json!({ "type": "diagnostics_reset", "previous": previous }).to_string());
+    send_buffer_state(shared);
+    previous
+}
+
+fn update_stream_status(shared: &SharedState, status: &str, err: Option<String>) {
+    {
+        let mut state = shared.inner.lock().unwrap();
+        state.stream_status = status.to_string();
+        state.stream_error = err.clone();
+    }
+    let payload = json!({
+        "type": "stream_state",
+        "status": status,
+        "error": err
+    });
+    let _ = shared.tx.send(payload.to_string());
+}
+
+fn ffmpeg_path() -> PathBuf {
+    if let Ok(dir) = std::env::var("VMUSIC_ASSET_DIR") {
+        let candidate = Path::new(&dir).join("ffmpeg.exe");
+        if candidate.exists() {
+            return candidate;
+        }
+    }
+    PathBuf::from("ffmpeg")
+}
+
+/// `start` seeks the input before decoding, in seconds.
+fn spawn_ffmpeg(input: &str, sample_rate: u32, channels: u16, start: Option<f64>) -> Result<Child> {
+    let mut cmd = Command::new(ffmpeg_path());
+    cmd.arg("-v").arg("error");
+    if let Some(start) = start {
+        cmd.arg("-ss").arg(format!("{:.3}", start));
+    }
+    cmd.arg("-i")
+        .arg(input)
+        .arg("-ac")
+        .arg(channels.to_string())
+        .arg("-ar")
+        .arg(sample_rate.to_string())
+        .arg("-acodec")
+        .arg("pcm_f32le")
+        .arg("-f")
+        .arg("f32le")
+        .arg("-");
+
+    let child = cmd
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("spawn ffmpeg")?;
+    Ok(child)
+}
+
+/// Length of `input` from ffmpeg's banner, or `None` for a live stream
+/// (which ffmpeg reports as "Duration: N/A") or one it couldn't open.
+fn probe_stream_duration(input: &str) -> Option<f64> {
+    let output = Command::new(ffmpeg_path())
+        .args(["-hide_banner", "-i", input])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .output()
+        .ok()?;
+    parse_ffmpeg_duration(&String::from_utf8_lossy(&output.stderr))
+}
+
+fn parse_ffmpeg_duration(banner: &str) -> Option<f64> {
+    let stamp = banner.split("Duration: ").nth(1)?.split(',').next()?.trim();
+    let mut seconds = 0.0;
+    for part in stamp.split(':') {
+        seconds = seconds * 60.0 + part.parse::<f64>().ok()?;
+    }
+    (seconds > 0.0).then_some(seconds)
+}
+
+/// ffmpeg input arguments for a loopback capture of `device_id` on `os`
+/// (as in `std::env::consts::OS`).
+fn capture_input_args(os: &str, device_id: Option<&str>) -> Result<Vec<String>> {
+    let device = device_id.filter(|id| !id.is_empty() && *id != "default");
+    let (format, input) = match os {
+        "windows" => ("wasapi", device.unwrap_or("default").to_string()),
+        // PulseAudio and PipeWire's pulse server expose what a sink plays
+        // as its ".monitor" source; the default source is usually a
+        // microphone, so the default sink's monitor stands in for it.
+        "linux" => ("pulse", device.unwrap_or("@DEFAULT_MONITOR@").to_string()),
+        // macOS has no loopback of its own. Playing through a virtual
+        // device such as BlackHole (inside a Multi-Output Device to keep it
+        // audible) makes it an AVFoundation input, picked by name or index;
+        // the leading ':' asks for audio only.
+        "macos" => match device {
+            Some(device) => ("avfoundation", format!(":{}", device)),
+            None => {
+                return Err(anyhow!(
+                    "capture on macOS needs device_id naming a loopback input such as BlackHole"
+                ))
+            }
+        },
+        _ => return Err(anyhow!("capture not supported on this platform")),
+    };
+    Ok(vec!["-f".to_string(), format.to_string(), "-i".to_string(), input])
+}
+
+fn spawn_capture_ffmpeg(device_id: Option<&str>, sample_rate: u32, channels: u16) -> Result<Child> {
+    let input = capture_input_args(std::env::consts::OS, device_id)?;
+    let mut cmd = Command::new(ffmpeg_path());
+    cmd.arg("-v")
+        .arg("error")
+        .args(input)
+        .arg("-ac")
+        .arg(channels.to_string())
+        .arg("-ar")
+        .arg(sample_rate.to_string())
+        .arg("-acodec")
+        .arg("pcm_f32le")
+        .arg("-f")
+        .arg("f32le")
+        .arg("-");
+    let child = cmd
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("spawn ffmpeg capture")?;
+    Ok(child)
+}
+
+/// What `/capture/devices` lists.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct CaptureDevice {
+    /// Passed back as `device_id` to `/capture/start`.
+    id: String,
+    name: String,
+    backend: &'static str,
+    /// "loopback" records what an output device plays, "input" a
+    /// microphone or line-in.
+    kind: &'static str,
+}
+
+impl CaptureDevice {
+    fn default_loopback(backend: &'static str) -> Self {
+        CaptureDevice {
+            id: "default".to_string(),
+            name: "default".to_string(),
+            backend,
+            kind: "loopback",
+        }
+    }
+}
+
+fn capture_devices() -> Vec<CaptureDevice> {
+    if cfg!(target_os = "windows") {
+        let mut devices = vec![CaptureDevice::default_loopback("wasapi")];
+        match wasapi_capture_endpoints() {
+            Ok(endpoints) => devices.extend(endpoints),
+            Err(err) => warn!("listing wasapi endpoints failed: {}", err),
+        }
+        devices
+    } else if cfg!(target_os = "linux") {
+        let mut devices = vec![CaptureDevice::default_loopback("pulse")];
+        devices.extend(pulse_sources());
+        devices
+    } else {
+        Vec::new()
+    }
+}
+
+/// Sources from `pactl list sources`, monitors as loopback devices.
+fn pulse_sources() -> Vec<CaptureDevice> {
+    let output = Command::new("pactl")
+        .arg("list")
+        .arg("sources")
+        // The long listing's labels are translated.
+        .env("LC_ALL", "C")
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output();
+    match output {
+        Ok(output) if output.status.success() => parse_pulse_sources(&String::from_utf8_lossy(&output.stdout)),
+        Ok(output) => {
+            warn!("pactl list sources failed: {}", output.status);
+            Vec::new()
+        }
+        Err(err) => {
+            warn!("pactl unavailable: {}", err);
+            Vec::new()
+        }
+    }
+}
+
+fn parse_pulse_sources(listing: &str) -> Vec<CaptureDevice> {
+    let mut sources = Vec::new();
+    let mut id: Option<String> = None;
+    for line in listing.lines() {
+        let line = line.trim();
+        if line.starts_with("Source #") {
+            id = None;
+        } else if let Some(value) = line.strip_prefix("Name:") {
+            id = Some(value.trim().to_string());
+        } else if let Some(value) = line.strip_prefix("Description:") {
+            if let Some(id) = id.take() {
+                let kind = if id.ends_with(".monitor") { "loopback" } else { "input" };
+                sources.push(CaptureDevice {
+                    id,
+                    name: value.trim().to_string(),
+                    backend: "pulse",
+                    kind,
+                });
+            }
+        }
+    }
+    sources
+}
+
+/// Active WASAPI endpoints by endpoint id: render endpoints for loopback,
+/// then capture endpoints.
+#[cfg(target_os = "windows")]
+fn wasapi_capture_endpoints() -> Result<Vec<CaptureDevice>> {
+    let _com = ComInit::new()?;
+    let enumerator: IMMDeviceEnumerator = unsafe {
+        CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)
+            .map_err(|err| anyhow!("CoCreateInstance failed: {}", err))?
+    };
+    let mut devices = Vec::new();
+    let flows: [(EDataFlow, &'static str); 2] = [(eRender, "loopback"), (eCapture, "input")];
+    for (flow, kind) in flows {
+        unsafe {
+            let collection = enumerator
+                .EnumAudioEndpoints(flow, DEVICE_STATE_ACTIVE)
+                .map_err(|err| anyhow!("EnumAudioEndpoints failed: {}", err))?;
+            let count = collection
+                .GetCount()
+                .map_err(|err| anyhow!("GetCount failed: {}", err))?;
+            for ordinal in 0..count {
+                let Ok(device) = collection.Item(ordinal) else {
+                    continue;
+                };
+                let Ok(raw_id) = device.GetId() else {
+                    continue;
+                };
+                let id = raw_id.to_string();
+                CoTaskMemFree(Some(raw_id.0 as *const _));
+                let Ok(id) = id else {
+                    continue;
+                };
+                let name = device
+                    .OpenPropertyStore(STGM_READ)
+                    .and_then(|store| store.GetValue(&PKEY_Device_FriendlyName))
+                    .map(|value| value.to_string())
+                    .unwrap_or_else(|_| id.clone());
+                devices.push(CaptureDevice {
+                    id,
+                    name,
+                    backend: "wasapi",
+                    kind,
+                });
+            }
+        }
+    }
+    Ok(devices)
+}
+
+#[cfg(not(target_os = "windows"))]
+fn wasapi_capture_endpoints() -> Result<Vec<CaptureDevice>> {
+    Err(anyhow!("WASAPI is only available on Windows"))
+}
+
+/// Pushes stream or capture samples into the ring buffer, keeping the
+/// buffer counters.
+struct RingFeeder {
+    producer: Arc<Mutex<HeapProd<f32>>>,
+    state: Arc<Mutex<EngineState>>,
+    tx: broadcast::Sender<String>,
+    channels: usize,
+    sample_count: usize,
+    overflow_reported: bool,
+}
+
+impl RingFeeder {
+    /// Frames are counted in the channel layout `shared` has now.
+    fn new(shared: &SharedState) -> Self {
+        let channels = shared.inner.lock().unwrap().channels.max(1);
+        RingFeeder {
+            producer: shared.producer.clone(),
+            state: shared.inner.clone(),
+            tx: shared.tx.clone(),
+            channels,
+            sample_count: 0,
+            overflow_reported: false,
+        }
+    }
+
+    fn push(&mut self, samples: impl IntoIterator<Item = f32>) {
+        // Counters are settled once per batch rather than per sample.
+        let frames_before = self.sample_count / self.channels;
+        let mut dropped = 0u64;
+        if let Ok(mut prod) = self.producer.lock() {
+            for sample in samples {
+                if prod.try_push(sample).is_ok() {
+                    self.sample_count += 1;
+                } else {
+                    dropped += 1;
+                }
+            }
+        }
+        let frames = self.sample_count / self.channels - frames_before;
+        if frames > 0 || dropped > 0 {
+            if let Ok(mut s) = self.state.lock() {
+                s.buffered_frames += frames;
+                s.dropped_sample_count = s.dropped_sample_count.saturating_add(dropped);
+                if dropped > 0 && !self.overflow_reported {
+                    self.overflow_reported = true;
+                    warn!(
+                        "ring buffer full, dropped {} samples (buffer_max_ms {})",
+                        dropped, s.buffer_max_ms
+                    );
+                    let _ = self.tx.send(buffer_overflow_payload(&s).to_string());
+                }
+            }
+        }
+    }
+}
+
+fn start_stream_reader(shared: SharedState, mut child: Child) {
+    let stdout = child.stdout.take();
+    if stdout.is_none() {
+        update_stream_status(&shared, "error", Some("ffmpeg stdout missing".to_string()));
+        return;
+    }
+    let mut stdout = stdout.unwrap();
+    update_stream_status(&shared, "running", None);
+    let mut feeder = RingFeeder::new(&shared);
+    let state = shared.inner.clone();
+    let stop = shared.stream_stop.clone();
+    let thread = thread::spawn(move || {
+        let mut buffer = vec![0u8; 8192];
+        loop {
+            if reader_suspended(&state) {
+                if stop.load(Ordering::Acquire) {
+                    break;
+                }
+                thread::sleep(STREAM_SUSPEND_POLL);
+                continue;
+            }
+            match stdout.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => {
+                    feeder.push(
+                        buffer[..n]
+                            .chunks_exact(4)
+                            .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+                    );
+                }
+                Err(err) => {
+                    error!("ffmpeg read error: {}", err);
+                    break;
+                }
+            }
+        }
+    });
+
+    *shared.stream_process.lock().unwrap() = Some(child);
+    *shared.stream_thread.lock().unwrap() = Some(thread);
+}
+
+/// A paused stream or capture in "suspend" mode leaves ffmpeg's output
+/// unread until it resumes.
+fn reader_suspended(state: &Mutex<EngineState>) -> bool {
+    let state = state.lock().unwrap();
+    state.is_paused && state.live_pause_mode == "suspend" && matches!(state.mode.as_str(), "stream" | "capture")
+}
+
+fn stop_stream(shared: &SharedState) {
+    if let Some(mut child) = shared.stream_process.lock().unwrap().take() {
+        let _ = child.kill();
+    }
+    if let Some(thread) = shared.stream_thread.lock().unwrap().take() {
+        shared.stream_stop.store(true, Ordering::Release);
+        let _ = thread.join();
+        shared.stream_stop.store(false, Ordering::Release);
+    }
+    let mut state = shared.inner.lock().unwrap();
+    state.stream_status = "stopped".to_string();
+    state.stream_error = None;
+    state.stream_url = None;
+    state.buffered_frames = 0;
+}
+
+fn reset_ring_buffer(shared: &SharedState) {
+    let capacity = {
+        let mut state = shared.inner.lock().unwrap();
+        let sample_rate = state.sample_rate.max(1);
+        let channels = state.channels.max(1);
+        let frames = (state.buffer_max_ms as u64 * sample_rate as u64) / 1000;
+        state.ring_capacity_frames = frames as usize;
+        if let Some(live) = state.live_resampler.0.as_mut() {
+            live.reset();
+        }
+        (frames as usize) * channels
+    };
+    let rb = HeapRb::<f32>::new(capacity);
+    let (prod, cons) = rb.split();
+    *shared.producer.lock().unwrap() = prod;
+    *shared.consumer.lock().unwrap() = cons;
+}
+
+/// Interleaved f32 PCM for a whole file, as produced by [`decode_to_pcm`].
+#[derive(Debug, Clone)]
+pub struct DecodedAudio {
+    pub samples: Vec<f32>,
+    pub sample_rate: u32,
+    pub channels: usize,
+    pub duration: f64,
+    pub bit_depth: Option<u32>,
+    partial: Option<PartialDecodeInfo>,
+    replaygain: ReplayGainInfo,
+    gapless: Option<GaplessTrimInfo>,
+    /// How a DSD file was rendered; `None` for anything else.
+    dsd: Option<DsdOutput>,
+}
+
+/// Encoder delay/padding reported by the codec and what was done with it.
+#[derive(Debug, Clone, Copy, Serialize)]
+struct GaplessTrimInfo {
+    applied: bool,
+    delay_frames: usize,
+    padding_frames: usize,
+    removed_frames: usize,
+}
+
+/// How decoded channels are stored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChannelMode {
+    #[default]
+    KeepAll,
+    /// ITU-R BS.775 downmix of anything wider than stereo.
+    Stereo,
+    /// Keep only these source channels, in this order.
+    Select(ChannelSelection),
+}
+
+/// Up to `MAX_SELECTED_CHANNELS` source channel indices, kept inline so
+/// `DecodeOptions` stays `Copy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelSelection {
+    len: usize,
+    channels: [u16; MAX_SELECTED_CHANNELS],
+}
+
+impl ChannelSelection {
+    pub fn new(channels: &[usize]) -> Result<Self> {
+        if channels.len() > MAX_SELECTED_CHANNELS {
+            return Err(anyhow!("at most {} channels can be selected", MAX_SELECTED_CHANNELS));
+        }
+        let mut selection = ChannelSelection { len: channels.len(), channels: [0; MAX_SELECTED_CHANNELS] };
+        for (slot, &channel) in selection.channels.iter_mut().zip(channels) {
+            *slot = u16::try_from(channel).map_err(|_| anyhow!("channel {} out of range", channel))?;
+        }
+        Ok(selection)
+    }
+
+    pub fn as_slice(&self) -> &[u16] {
+        &self.channels[..self.len]
+    }
+}
+
+/// A load or decode refused for something the request asked for, as
+/// opposed to a file that failed to read or decode.
+#[derive(Debug, PartialEq)]
+pub enum LoadError {
+    NotFound,
+    NoChannelsSelected,
+    ChannelOutOfRange { channel: usize, channels: usize },
+    /// A newer load of this path started before this one finished.
+    Superseded(String),
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::NotFound => f.write_str("File not found"),
+            LoadError::NoChannelsSelected => f.write_str("no channels selected"),
+            LoadError::ChannelOutOfRange { channel, channels } => {
+                write!(f, "channel {} out of range for {} channels", channel, channels)
+            }
+            LoadError::Superseded(path) => write!(f, "load of {} superseded by a newer one", path),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// Check a channel selection against a file's channel count.
+fn check_channel_selection(selected: &ChannelSelection, channels: usize) -> std::result::Result<(), LoadError> {
+    if selected.as_slice().is_empty() {
+        return Err(LoadError::NoChannelsSelected);
+    }
+    match selected.as_slice().iter().map(|&idx| usize::from(idx)).find(|&idx| idx >= channels) {
+        Some(channel) => Err(LoadError::ChannelOutOfRange { channel, channels }),
+        None => Ok(()),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DecodeOptions {
+    /// Drop the encoder delay/padding frames reported by the codec.
+    pub gapless_trim: bool,
+    pub channels: ChannelMode,
+    /// How to render DSD files; other files ignore it.
+    pub dsd: DsdOutput,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        DecodeOptions {
+            gapless_trim: true,
+            channels: ChannelMode::KeepAll,
+            dsd: DsdOutput::Pcm(dsd::DSD_PCM_RATES[0]),
+        }
+    }
+}
+
+/// Decode a file to memory without touching any engine playback state.
+pub fn decode_to_pcm(path: &str) -> Result<DecodedAudio> {
+    decode_file(path, &DecodeOptions::default())
+}
+
+pub fn decode_to_pcm_with_options(path: &str, options: DecodeOptions) -> Result<DecodedAudio> {
+    decode_file(path, &options)
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct PartialDecodeInfo {
+    decoded_frames: u64,
+    expected_frames: Option<u64>,
+    skipped_packets: usize,
+    error: Option<String>,
+}
+
+fn is_clean_eof(err: &SymphoniaError) -> bool {
+    matches!(err, SymphoniaError::IoError(io) if io.kind() == std::io::ErrorKind::UnexpectedEof)
+}
+
+fn partial_decode_info(
+    decoded_frames: u64,
+    expected_frames: Option<u64>,
+    skipped_packets: usize,
+    error: Option<String>,
+) -> Option<PartialDecodeInfo> {
+    let truncated = expected_frames
+        .map(|expected| expected.saturating_sub(decoded_frames) > PARTIAL_DECODE_TOLERANCE_FRAMES)
+        .unwrap_or(false);
+    if !truncated && skipped_packets == 0 && error.is_none() {
+        return None;
+    }
+    Some(PartialDecodeInfo {
+        decoded_frames,
+        expected_frames,
+        skipped_packets,
+        error,
+    })
+}
+
+fn bit_depth_from_codec(codec_params: &CodecParameters) -> Option<u32> {
+    if let Some(bits) = codec_params
+        .bits_per_sample
+        .or(codec_params.bits_per_coded_sample)
+    {
+        return Some(bits);
+    }
+    codec_params.sample_format.and_then(|format| match format {
+        SampleFormat::U8 | SampleFormat::S8 => Some(8),
+        SampleFormat::U16 | SampleFormat::S16 => Some(16),
+        SampleFormat::U24 | SampleFormat::S24 => Some(24),
+        SampleFormat::U32 | SampleFormat::S32 | SampleFormat::F32 => Some(32),
+        SampleFormat::F64 => Some(64),
+        _ => None,
+    })
+}
+
+fn apply_gapless_trim(samples: Vec<f32>, channels: usize, delay: usize, padding: usize) -> Vec<f32> {
+    if samples.is_empty() {
+        return samples;
+    }
+    let channels = channels.max(1);
+    let frame_count = samples.len() / channels;
+    if delay == 0 && padding == 0 {
+        return samples;
+    }
+    let start_frame = delay.min(frame_count);
+    let end_frame = frame_count.saturating_sub(padding);
+    if end_frame <= start_frame {
+        return Vec::new();
+    }
+    let start = start_frame * channels;
+    let end = (end_frame * channels).min(samples.len());
+    samples[start..end].to_vec()
+}
+
+fn decode_file(path: &str, options: &DecodeOptions) -> Result<DecodedAudio> {
+    decode_file_with_progress(path, options, &mut |_| true)
+}
+
+/// `decode_file`, calling `progress` after each packet with the fraction of
+/// frames decoded (`None` when the container doesn't say how many there
+/// are). Returning `false` abandons the decode.
+fn decode_file_with_progress(
+    path: &str,
+    options: &DecodeOptions,
+    progress: &mut dyn FnMut(Option<f64>) -> bool,
+) -> Result<DecodedAudio> {
+    decode_file_until(path, options, None, progress)
+}
+
+/// The first `max_seconds` of `path`, for analysis that looks no further,
+/// without reading the rest. `duration` is still the whole file's when the
+/// container says how long it is.
+fn decode_file_head(path: &str, options: &DecodeOptions, max_seconds: f64) -> Result<DecodedAudio> {
+    decode_file_until(path, options, Some(max_seconds), &mut |_| true)
+}
+
+fn decode_file_until(
+    path: &str,
+    options: &DecodeOptions,
+    max_seconds: Option<f64>,
+    progress: &mut dyn FnMut(Option<f64>) -> bool,
+) -> Result<DecodedAudio> {
+    if dsd::is_dsf_path(Path::new(path)) {
+        return decode_dsf_file(path, options, max_seconds, progress);
+    }
+    let mut probed = probe_file(path)?;
+    let gain_tags = probed_tags(&mut probed);
+    let id3_tag = has_id3_tag(&mut probed);
+    let mut format = probed.format;
+    let track = format
+        .default_track()
+        .ok_or_else(|| anyhow!("no default track"))?;
+    let codec_params = &track.codec_params;
+    let replaygain = read_file_replaygain(Path::new(path), &gain_tags, id3_tag, Some(codec_params));
+    let sample_rate = codec_params.sample_rate.unwrap_or(48_000);
+    let channel_layout = codec_params.channels;
+    let channels = channel_layout
+        .map(|c| c.count())
+        .unwrap_or(2)
+        .max(1);
+    if let ChannelMode::Select(selected) = &options.channels {
+        check_channel_selection(selected, channels)?;
+    }
+    let bit_depth = bit_depth_from_codec(codec_params);
+    let gapless_delay = codec_params.delay.unwrap_or(0) as usize;
+    let gapless_padding = codec_params.padding.unwrap_or(0) as usize;
+    let expected_frames = codec_params.n_frames;
+    // Untrimmed frames to decode, counting the encoder delay that trimming
+    // drops.
+    let max_frames = max_seconds.map(|seconds| {
+        (seconds * sample_rate as f64).ceil() as usize + if options.gapless_trim { gapless_delay } else { 0 }
+    });
+
+    let mut decoder = symphonia::default::get_codecs()
+        .make(codec_params, &DecoderOptions::default())?;
+
+    let mut samples: Vec<f32> = Vec::new();
+    let mut skipped_packets = 0usize;
+    let mut read_error = None;
+    let mut stopped_early = false;
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(err) if is_clean_eof(&err) => break,
+            Err(err) => {
+                read_error = Some(err.to_string());
+                break;
+            }
+        };
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            Err(SymphoniaError::DecodeError(_)) | Err(SymphoniaError::IoError(_)) => {
+                skipped_packets += 1;
+                continue;
+            }
+            Err(err) => {
+                read_error = Some(err.to_string());
+                break;
+            }
+        };
+        match decoded {
+            AudioBufferRef::F32(buf) => {
+                let spec = *buf.spec();
+                let mut sample_buf = SampleBuffer::<f32>::new(buf.capacity() as u64, spec);
+                sample_buf.copy_interleaved_ref(AudioBufferRef::F32(buf));
+                samples.extend_from_slice(sample_buf.samples());
+            }
+            _ => {
+                let spec = *decoded.spec();
+                let mut sample_buf = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+                sample_buf.copy_interleaved_ref(decoded);
+                samples.extend_from_slice(sample_buf.samples());
+            }
+        }
+        let fraction = expected_frames
+            .filter(|frames| *frames > 0)
+            .map(|frames| ((samples.len() / channels) as f64 / frames as f64).min(1.0));
+        if !progress(fraction) {
+            return Err(anyhow!("decode cancelled"));
+        }
+        if let Some(max_frames) = max_frames.filter(|max| samples.len() / channels >= *max) {
+            samples.truncate(max_frames * channels);
+            stopped_early = true;
+            break;
+        }
+    }
+
+    let decoded_frames = (samples.len() / channels) as u64;
+    let checked_frames = expected_frames.filter(|_| !stopped_early);
+    let partial = partial_decode_info(decoded_frames, checked_frames, skipped_packets, read_error);
+    if let Some(info) = &partial {
+        warn!(
+            "partial decode of {}: {} of {:?} frames, {} packets skipped",
+            path, info.decoded_frames, info.expected_frames, info.skipped_packets
+        );
+    }
+
+    let mut gapless = None;
+    if gapless_delay > 0 || gapless_padding > 0 {
+        let frames_before = samples.len() / channels;
+        if options.gapless_trim {
+            // The padding is at the end, which a stopped decode never read.
+            let padding = if stopped_early { 0 } else { gapless_padding };
+            samples = apply_gapless_trim(samples, channels, gapless_delay, padding);
+        }
+        gapless = Some(GaplessTrimInfo {
+            applied: options.gapless_trim,
+            delay_frames: gapless_delay,
+            padding_frames: gapless_padding,
+            removed_frames: frames_before - samples.len() / channels,
+        });
+    }
+
+    let (samples, channels) = match &options.channels {
+        ChannelMode::KeepAll => (samples, channels),
+        ChannelMode::Stereo if channels <= 2 => (samples, channels),
+        ChannelMode::Stereo => (downmix_to_stereo(&samples, channels, channel_layout), 2),
+        ChannelMode::Select(selected) => {
+            (select_channels(&samples, channels, selected.as_slice()), selected.as_slice().len())
+        }
+    };
+
+    let mut frames = samples.len() / channels.max(1);
+    if let Some(total) = expected_frames.filter(|_| stopped_early) {
+        let trim = if options.gapless_trim { gapless_delay + gapless_padding } else { 0 };
+        frames = (total as usize).saturating_sub(trim);
+    }
+    let duration = if sample_rate > 0 {
+        frames as f64 / sample_rate as f64
+    } else {
+        0.0
+    };
+    Ok(DecodedAudio {
+        samples,
+        sample_rate,
+        channels,
+        duration,
+        bit_depth,
+        partial,
+        replaygain,
+        gapless,
+        dsd: None,
+    })
+}
+
+/// `decode_file_with_progress` for a .dsf file, rendered as `options.dsd`
+/// says. DSD carries no gain tags or encoder delay, and only stereo files
+/// play, so there is nothing to downmix.
+fn decode_dsf_file(
+    path: &str,
+    options: &DecodeOptions,
+    max_seconds: Option<f64>,
+    progress: &mut dyn FnMut(Option<f64>) -> bool,
+) -> Result<DecodedAudio> {
+    let decoded = dsd::decode_dsf(Path::new(path), options.dsd, max_seconds, progress)?;
+    let (samples, channels) = match &options.channels {
+        ChannelMode::KeepAll | ChannelMode::Stereo => (decoded.samples, decoded.channels),
+        ChannelMode::Select(selected) => {
+            check_channel_selection(selected, decoded.channels)?;
+            (select_channels(&decoded.samples, decoded.channels, selected.as_slice()), selected.as_slice().len())
+        }
+    };
+    let frames = samples.len() / channels.max(1);
+    let duration = match max_seconds {
+        Some(_) => decoded.duration,
+        None => frames as f64 / decoded.sample_rate as f64,
+    };
+    Ok(DecodedAudio {
+        samples,
+        sample_rate: decoded.sample_rate,
+        channels,
+        duration,
+        bit_depth: Some(1),
+        partial: None,
+        replaygain: ReplayGainInfo::default(),
+        gapless: None,
+        dsd: Some(options.dsd),
+    })
+}
+
+/// Open `path` and probe its format, hinted by the extension.
+fn probe_file(path: &str) -> Result<ProbeResult> {
+    let file = File::open(path).context("open audio file")?;
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(ext) = Path::new(path).extension().and_then(|v| v.to_str()) {
+        hint.with_extension(ext);
+    }
+    Ok(symphonia::default::get_probe().format(
+        &hint,
+        mss,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?)
+}
+
+/// Files at least this long decode as they play unless configured otherwise;
+/// shorter ones decode whole about as fast as the first packets would.
+const DEFAULT_INCREMENTAL_DECODE_SECS: f64 = 5.0;
+/// How often the incremental decoder looks for room in a full ring, or for
+/// a seek once it has reached the end.
+const INCREMENTAL_DECODE_POLL: Duration = Duration::from_millis(5);
+
+/// The current file decoding into the ring buffer as it plays, for files
+/// too long to wait for: `run_incremental_decode` keeps the ring topped up
+/// from a background thread and the output callback reads it as it does a
+/// stream. Loops, crossfades out of the track and gapless splices need the
+/// whole file in `data`, so a file played this way goes without them.
+#[derive(Debug, Clone, Copy)]
+struct IncrementalDecode {
+    /// Frames the track plays for, gapless-trimmed; corrected to where the
+    /// file really ends once the decoder gets there.
+    frames: usize,
+    /// A frame to go to. The decoder clears the ring and carries on from
+    /// there; until it has, the callback plays silence rather than what the
+    /// ring held for the old position.
+    seek: Option<usize>,
+    /// The ring holds everything to the end, so running dry means the track
+    /// is over rather than an underrun.
+    finished: bool,
+}
+
+/// A file opened for `run_incremental_decode`, with what `PreparedTrack`
+/// needs to know of it.
+struct IncrementalSource {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    time_base: TimeBase,
+    sample_rate: u32,
+    /// Channels as played, after `channel_mode`.
+    channels: usize,
+    /// Channels as decoded, and their layout for a stereo downmix.
+    decoded_channels: usize,
+    layout: Option<Channels>,
+    channel_mode: ChannelMode,
+    /// Untrimmed frames where playback starts and ends: past the encoder
+    /// delay and short of the padding when gapless trimming is on.
+    skip_start: usize,
+    end: usize,
+    bit_depth: Option<u32>,
+    replaygain: ReplayGainInfo,
+    gapless: Option<GaplessTrimInfo>,
+}
+
+impl IncrementalSource {
+    /// Fails for a file that doesn't say how long it is, as there would be
+    /// no length to show or seek within, and for a channel selection the
+    /// file doesn't have.
+    fn open(path: &str, options: &DecodeOptions) -> Result<Self> {
+        let gapless_trim = options.gapless_trim;
+        let mut probed = probe_file(path)?;
+        let gain_tags = probed_tags(&mut probed);
+        let id3_tag = has_id3_tag(&mut probed);
+        let format = probed.format;
+        let track = format.default_track().ok_or_else(|| anyhow!("no default track"))?;
+        let codec_params = &track.codec_params;
+        let sample_rate = codec_params.sample_rate.filter(|rate| *rate > 0);
+        let (Some(sample_rate), Some(total)) = (sample_rate, codec_params.n_frames) else {
+            return Err(anyhow!("length or sample rate unknown"));
+        };
+        let total = total as usize;
+        let delay = codec_params.delay.unwrap_or(0) as usize;
+        let padding = codec_params.padding.unwrap_or(0) as usize;
+        let (skip_start, end) = if gapless_trim {
+            (delay, total.saturating_sub(padding))
+        } else {
+            (0, total)
+        };
+        let gapless = (delay > 0 || padding > 0).then(|| GaplessTrimInfo {
+            applied: gapless_trim,
+            delay_frames: delay,
+            padding_frames: padding,
+            removed_frames: total - end.saturating_sub(skip_start),
+        });
+        let track_id = track.id;
+        let time_base = codec_params.time_base.unwrap_or_else(|| TimeBase::new(1, sample_rate));
+        let layout = codec_params.channels;
+        let decoded_channels = layout.map(|c| c.count()).unwrap_or(2).max(1);
+        let channels = match &options.channels {
+            ChannelMode::KeepAll => decoded_channels,
+            ChannelMode::Stereo => decoded_channels.min(2),
+            ChannelMode::Select(selected) => {
+                check_channel_selection(selected, decoded_channels)?;
+                selected.as_slice().len()
+            }
+        };
+        let bit_depth = bit_depth_from_codec(codec_params);
+        let replaygain = read_file_replaygain(Path::new(path), &gain_tags, id3_tag, Some(codec_params));
+        let decoder = symphonia::default::get_codecs().make(codec_params, &DecoderOptions::default())?;
+        Ok(IncrementalSource {
+            format,
+            decoder,
+            track_id,
+            time_base,
+            sample_rate,
+            channels,
+            decoded_channels,
+            layout,
+            channel_mode: options.channels,
+            skip_start,
+            end,
+            bit_depth,
+            replaygain,
+            gapless,
+        })
+    }
+
+    /// Frames the track plays for.
+    fn frames(&self) -> usize {
+        self.end.saturating_sub(self.skip_start)
+    }
+
+    /// Decoded frames in the channels `channel_mode` keeps, as
+    /// `decode_file` would store them.
+    fn to_played_channels<'a>(&self, samples: &'a [f32]) -> std::borrow::Cow<'a, [f32]> {
+        match &self.channel_mode {
+            ChannelMode::Stereo if self.decoded_channels > 2 => {
+                downmix_to_stereo(samples, self.decoded_channels, self.layout).into()
+            }
+            ChannelMode::Select(selected) => {
+                select_channels(samples, self.decoded_channels, selected.as_slice()).into()
+            }
+            _ => samples.into(),
+        }
+    }
+
+    /// Go to `frame` of the track as played. Returns the untrimmed frame
+    /// decoding resumes at, which may fall short of it: the packet holding
+    /// the frame starts there.
+    fn seek(&mut self, frame: usize) -> Result<usize> {
+        let rate = self.sample_rate as u128;
+        let TimeBase { numer, denom } = self.time_base;
+        let ts = (frame + self.skip_start) as u128 * denom as u128 / (numer as u128 * rate).max(1);
+        let to = SeekTo::TimeStamp {
+            ts: ts as u64,
+            track_id: self.track_id,
+        };
+        let seeked = self.format.seek(SeekMode::Accurate, to)?;
+        self.decoder.reset();
+        Ok((seeked.actual_ts as u128 * numer as u128 * rate / (denom as u128).max(1)) as usize)
+    }
+}
+
+/// The track for `load_track` to play while it decodes, if `path` is at
+/// least `incremental_decode_secs` long. It stays at the file's rate: with
+/// a target rate set, the device runs at that and the live resampler
+/// converts, as for streams. `None` leaves it to `prepare_track_for_load`,
+/// which also reports why a file won't open.
+fn prepare_incremental_track(shared: &SharedState, path: &str, options: &DecodeOptions) -> Option<PreparedTrack> {
+    let (target, min_secs) = {
+        let state = shared.inner.lock().unwrap();
+        (PrepareTarget::from_state(&state, options), state.incremental_decode_secs)
+    };
+    if min_secs <= 0.0 {
+        return None;
+    }
+    let source = IncrementalSource::open(path, options).ok()?;
+    let duration = source.frames() as f64 / source.sample_rate as f64;
+    if duration < min_secs {
+        return None;
+    }
+    Some(PreparedTrack {
+        path: path.to_string(),
+        stamp: FileStamp::of(Path::new(path)),
+        target,
+        samples: Vec::new(),
+        incremental_frames: Some(source.frames()),
+        sample_rate: source.sample_rate,
+        duration,
+        resampler_info: None,
+        source_sample_rate: source.sample_rate,
+        source_channels: source.channels,
+        source_bit_depth: source.bit_depth,
+        partial_decode: None,
+        gapless_trim: source.gapless,
+        replaygain: source.replaygain,
+        dsd: None,
+    })
+}
+
+/// Start decoding the file `load_track` just installed into the ring. The
+/// thread sits in `stream_thread`, so `stop_stream` ends it as it would a
+/// stream reader.
+fn start_incremental_decode(shared: &SharedState, path: String, options: DecodeOptions) {
+    let decoder_shared = shared.clone();
+    let thread = thread::spawn(move || match IncrementalSource::open(&path, &options) {
+        Ok(source) => run_incremental_decode(&decoder_shared, source),
+        Err(err) => {
+            error!("decoding {} as it plays failed: {}", path, err);
+            // Let the callback find the track over rather than starved.
+            let _ = take_incremental_seek(&decoder_shared);
+            finish_incremental_decode(&decoder_shared, Some(0));
+        }
+    });
+    *shared.stream_thread.lock().unwrap() = Some(thread);
+}
+
+/// Decode the current file into the ring ahead of the callback, starting
+/// over wherever a seek asks, until `stream_stop` is raised or another
+/// source replaces it.
+fn run_incremental_decode(shared: &SharedState, mut source: IncrementalSource) {
+    let channels = source.decoded_channels;
+    // Untrimmed frame the next packet starts at, and the first one to keep.
+    let mut cursor = 0;
+    let mut keep_from = source.skip_start;
+    let mut fresh = true;
+    let mut at_end = false;
+    while !shared.stream_stop.load(Ordering::Acquire) {
+        match take_incremental_seek(shared) {
+            None => return,
+            // A reader that hasn't read anything is at the start already.
+            Some(Some(0)) if fresh => {}
+            Some(Some(frame)) => {
+                keep_from = frame + source.skip_start;
+                at_end = false;
+                fresh = false;
+                match source.seek(frame) {
+                    Ok(resumed) => cursor = resumed,
+                    Err(err) => {
+                        warn!("seek to frame {} failed: {}", frame, err);
+                        finish_incremental_decode(shared, None);
+                        at_end = true;
+                    }
+                }
+            }
+            Some(None) => {}
+        }
+        if at_end {
+            thread::sleep(INCREMENTAL_DECODE_POLL);
+            continue;
+        }
+        fresh = false;
+        let packet = match source.format.next_packet() {
+            Ok(packet) => packet,
+            Err(err) => {
+                if !is_clean_eof(&err) {
+                    warn!("decoding as it plays stopped early: {}", err);
+                }
+                finish_incremental_decode(shared, Some(cursor.min(source.end).saturating_sub(source.skip_start)));
+                at_end = true;
+                continue;
+            }
+        };
+        if packet.track_id() != source.track_id {
+            continue;
+        }
+        let decoded = match source.decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            Err(SymphoniaError::DecodeError(_)) | Err(SymphoniaError::IoError(_)) => continue,
+            Err(err) => {
+                warn!("decoding as it plays stopped early: {}", err);
+                finish_incremental_decode(shared, Some(cursor.min(source.end).saturating_sub(source.skip_start)));
+                at_end = true;
+                continue;
+            }
+        };
+        let frames = decoded.frames();
+        let mut buffer = SampleBuffer::<f32>::new(decoded.capacity() as u64, *decoded.spec());
+        buffer.copy_interleaved_ref(decoded);
+        let keep = keep_from.saturating_sub(cursor).min(frames)..source.end.saturating_sub(cursor).min(frames);
+        cursor += frames;
+        let samples = &buffer.samples()[keep.start * channels..keep.end.max(keep.start) * channels];
+        if !push_incremental(shared, &source.to_played_channels(samples), source.channels) {
+            continue;
+        }
+        if cursor >= source.end {
+            finish_incremental_decode(shared, None);
+            at_end = true;
+        }
+    }
+}
+
+/// Take a seek waiting for the incremental decoder, clearing the ring for
+/// it. `None` once the current file no longer decodes that way.
+fn take_incremental_seek(shared: &SharedState) -> Option<Option<usize>> {
+    let mut guard = shared.inner.lock().unwrap();
+    let state = &mut *guard;
+    let incremental = state.incremental.as_mut()?;
+    let seek = incremental.seek.take();
+    if seek.is_some() {
+        incremental.finished = false;
+        shared.consumer.lock().unwrap().clear();
+        state.buffered_frames = 0;
+    }
+    Some(seek)
+}
+
+/// Mark the incremental decode as having reached the end, at `frames` if
+/// the file turned out shorter than it said.
+fn finish_incremental_decode(shared: &SharedState, frames: Option<usize>) {
+    let mut guard = shared.inner.lock().unwrap();
+    let state = &mut *guard;
+    // A seek that came in meanwhile starts it over.
+    let Some(incremental) = state.incremental.as_mut().filter(|incremental| incremental.seek.is_none()) else {
+        return;
+    };
+    incremental.finished = true;
+    if let Some(frames) = frames.filter(|frames| *frames < incremental.frames) {
+        incremental.frames = frames;
+        state.duration = frames as f64 / state.sample_rate.max(1) as f64;
+    }
+}
+
+/// Push `samples` into the ring a whole frame at a time as it makes room.
+/// Gives up when a seek or stop comes in meanwhile.
+fn push_incremental(shared: &SharedState, mut samples: &[f32], channels: usize) -> bool {
+    while !samples.is_empty() {
+        if shared.stream_stop.load(Ordering::Acquire) {
+            return false;
+        }
+        let pushed = {
+            let mut producer = shared.producer.lock().unwrap();
+            let room = producer.vacant_len() / channels * channels;
+            producer.push_slice(&samples[..room.min(samples.len())])
+        };
+        {
+            let mut state = shared.inner.lock().unwrap();
+            if state.incremental.is_none_or(|incremental| incremental.seek.is_some()) {
+                return false;
+            }
+            state.buffered_frames += pushed / channels;
+        }
+        samples = &samples[pushed..];
+        if pushed == 0 {
+            thread::sleep(INCREMENTAL_DECODE_POLL);
+        }
+    }
+    true
+}
+
+const ITU_SURROUND_GAIN: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+/// Left/right contribution of one source channel in an ITU-R BS.775 downmix.
+/// LFE is dropped, centre and surrounds go in at -3 dB.
+fn stereo_downmix_gains(channel: Channels) -> (f32, f32) {
+    let g = ITU_SURROUND_GAIN;
+    if channel == Channels::FRONT_LEFT {
+        (1.0, 0.0)
+    } else if channel == Channels::FRONT_RIGHT {
+        (0.0, 1.0)
+    } else if (Channels::FRONT_CENTRE | Channels::TOP_CENTRE | Channels::TOP_FRONT_CENTRE).contains(channel) {
+        (g, g)
+    } else if channel == Channels::LFE1 || channel == Channels::LFE2 {
+        (0.0, 0.0)
+    } else if channel == Channels::REAR_CENTRE || channel == Channels::TOP_REAR_CENTRE {
+        (0.5, 0.5)
+    } else if (Channels::REAR_LEFT
+        | Channels::SIDE_LEFT
+        | Channels::FRONT_LEFT_CENTRE
+        | Channels::FRONT_LEFT_WIDE
+        | Channels::FRONT_LEFT_HIGH
+        | Channels::REAR_LEFT_CENTRE
+        | Channels::TOP_FRONT_LEFT
+        | Channels::TOP_REAR_LEFT)
+        .contains(channel)
+    {
+        (g, 0.0)
+    } else if (Channels::REAR_RIGHT
+        | Channels::SIDE_RIGHT
+        | Channels::FRONT_RIGHT_CENTRE
+        | Channels::FRONT_RIGHT_WIDE
+        | Channels::FRONT_RIGHT_HIGH
+        | Channels::REAR_RIGHT_CENTRE
+        | Channels::TOP_FRONT_RIGHT
+        | Channels::TOP_REAR_RIGHT)
+        .contains(channel)
+    {
+        (0.0, g)
+    } else {
+        (0.5, 0.5)
+    }
+}
+
+/// `stereo_downmix_gains` for the channel at `idx` in WAVE order.
+fn wave_order_downmix_gains(idx: usize) -> (f32, f32) {
+    1u32.checked_shl(idx as u32)
+        .and_then(Channels::from_bits)
+        .map(stereo_downmix_gains)
+        .unwrap_or((0.5, 0.5))
+}
+
+fn downmix_to_stereo(samples: &[f32], channels: usize, layout: Option<Channels>) -> Vec<f32> {
+    // Without a layout, assume WAVE order (FL FR FC LFE BL BR SL SR ...),
+    // which is also the order of symphonia's channel bits.
+    let gains: Vec<(f32, f32)> = match layout.filter(|l| l.count() == channels) {
+        Some(layout) => layout.iter().map(stereo_downmix_gains).collect(),
+        None => (0..channels).map(wave_order_downmix_gains).collect(),
+    };
+    // Scale so a full-scale signal on every channel cannot clip.
+    let left_sum: f32 = gains.iter().map(|g| g.0).sum();
+    let right_sum: f32 = gains.iter().map(|g| g.1).sum();
+    let norm = 1.0 / left_sum.max(right_sum).max(1.0);
+    let mut out = Vec::with_capacity(samples.len() / channels * 2);
+    for frame in samples.chunks_exact(channels) {
+        let (mut left, mut right) = (0.0f32, 0.0f32);
+        for (sample, (gl, gr)) in frame.iter().zip(&gains) {
+            left += sample * gl;
+            right += sample * gr;
+        }
+        out.push(left * norm);
+        out.push(right * norm);
+    }
+    out
+}
+
+fn select_channels(samples: &[f32], channels: usize, selected: &[u16]) -> Vec<f32> {
+    let mut out = Vec::with_capacity(samples.len() / channels * selected.len());
+    for frame in samples.chunks_exact(channels) {
+        out.extend(selected.iter().map(|&idx| frame[usize::from(idx)]));
+    }
+    out
+}
+
+fn parse_channel_mode(mode: &str, selected: Option<Vec<usize>>) -> Result<ChannelMode> {
+    match mode.to_lowercase().as_str() {
+        "keep" | "keep_all" | "all" => Ok(ChannelMode::KeepAll),
+        "stereo" | "downmix" => Ok(ChannelMode::Stereo),
+        "select" => {
+            let selected = selected.ok_or_else(|| anyhow!("select_channels required for channel_mode select"))?;
+            Ok(ChannelMode::Select(ChannelSelection::new(&selected)?))
+        }
+        other => Err(anyhow!("unknown channel_mode: {}", other)),
+    }
+}
+
+fn normalize_resampler_mode(value: &str) -> String {
+    let normalized = value.to_lowercase();
+    match normalized.as_str() {
+        "auto" | "rubato" | "soxr" => normalized,
+        _ => "auto".to_string(),
+    }
+}
+
+fn normalize_resampler_quality(value: &str) -> String {
+    let normalized = value.to_lowercase();
+    match normalized.as_str() {
+        "low" | "std" | "hq" | "uhq" => normalized,
+        _ => "hq".to_string(),
+    }
+}
+
+/// The backend a resample will try first, and why.
+fn resampler_choice(resampler_mode: &str, quality: &str, soxr_available: bool) -> (&'static str, &'static str) {
+    match resampler_mode {
+        "soxr" => ("soxr", "resampler_mode is soxr"),
+        "rubato" => ("rubato", "resampler_mode is rubato"),
+        _ if !soxr_available => ("rubato", "auto: soxr not available"),
+        _ if matches!(quality, "low" | "std") => ("rubato", "auto: rubato for low/std quality"),
+        _ => ("soxr", "auto: soxr for hq/uhq quality"),
+    }
+}
+
+fn normalize_exclusive_format(value: &str) -> String {
+    match value.to_lowercase().as_str() {
+        "i24" | "s24" | "int24" | "24" => "i24".to_string(),
+        _ => "f32".to_string(),
+    }
+}
+
+fn normalize_volume_mode(value: &str) -> String {
+    match value.to_lowercase().as_str() {
+        "hardware" | "hw" => "hardware".to_string(),
+        _ => "software".to_string(),
+    }
+}
+
+fn normalize_dither_bits(bits: u32) -> u32 {
+    match bits {
+        16 | 24 => bits,
+        _ => 24,
+    }
+}
+
+fn probed_tags(probed: &mut ProbeResult) -> Vec<Tag> {
+    let mut tags = Vec::new();
+    if let Some(mut metadata) = probed.metadata.get() {
+        if let Some(rev) = metadata.skip_to_latest() {
+            tags.extend(rev.tags().iter().cloned());
+        }
+    }
+    if let Some(rev) = probed.format.metadata().current() {
+        tags.extend(rev.tags().iter().cloned());
+    }
+    tags
+}
+
+/// Whether the probe read an ID3v2 tag in front of the container, the only
+/// metadata it looks for there.
+fn has_id3_tag(probed: &mut ProbeResult) -> bool {
+    probed.metadata.get().is_some()
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+struct ReplayGainInfo {
+    track_gain_db: Option<f32>,
+    track_peak: Option<f32>,
+    album_gain_db: Option<f32>,
+    album_peak: Option<f32>,
+    /// Opus header output gain. The spec has decoders always apply it, so it
+    /// is added on top of whichever ReplayGain mode is in use.
+    output_gain_db: Option<f32>,
+}
+
+fn parse_gain_value(value: &str) -> Option<f32> {
+    let number = value.trim().trim_end_matches("dB").trim_end_matches("db").trim();
+    number.parse::<f32>().ok().filter(|v| v.is_finite())
+}
+
+/// Opus R128 gains are Q7.8 dB relative to -23 LUFS; ReplayGain's reference
+/// is 5 dB louder.
+fn parse_r128_gain(value: &str) -> Option<f32> {
+    value.trim().parse::<i16>().ok().map(|q| q as f32 / 256.0 + 5.0)
+}
+
+/// ReplayGain from Vorbis comments, ID3 TXXX frames or Opus R128 tags. R128
+/// wins when both are present, as Opus tools only keep R128 up to date.
+fn read_replaygain(tags: &[Tag]) -> ReplayGainInfo {
+    let mut info = ReplayGainInfo::default();
+    let mut r128_track = None;
+    let mut r128_album = None;
+    for tag in tags {
+        let value = tag.value.to_string();
+        // Symphonia only maps exact-case TXXX descriptions to standard keys.
+        let key = tag.key.trim_start_matches("TXXX:").to_ascii_uppercase();
+        let slot = match (tag.std_key, key.as_str()) {
+            (Some(StandardTagKey::ReplayGainTrackGain), _) | (_, "REPLAYGAIN_TRACK_GAIN") => {
+                &mut info.track_gain_db
+            }
+            (Some(StandardTagKey::ReplayGainTrackPeak), _) | (_, "REPLAYGAIN_TRACK_PEAK") => {
+                &mut info.track_peak
+            }
+            (Some(StandardTagKey::ReplayGainAlbumGain), _) | (_, "REPLAYGAIN_ALBUM_GAIN") => {
+                &mut info.album_gain_db
+            }
+            (Some(StandardTagKey::ReplayGainAlbumPeak), _) | (_, "REPLAYGAIN_ALBUM_PEAK") => {
+                &mut info.album_peak
+            }
+            (_, "R128_TRACK_GAIN") => {
+                r128_track = r128_track.or_else(|| parse_r128_gain(&value));
+                continue;
+            }
+            (_, "R128_ALBUM_GAIN") => {
+                r128_album = r128_album.or_else(|| parse_r128_gain(&value));
+                continue;
+            }
+            _ => continue,
+        };
+        if slot.is_none() {
+            *slot = parse_gain_value(&value);
+        }
+    }
+    if r128_track.is_some() || r128_album.is_some() {
+        // R128 has no peak values, and ReplayGain peaks belong to the other gains.
+        info.track_gain_db = r128_track;
+        info.album_gain_db = r128_album;
+        info.track_peak = None;
+        info.album_peak = None;
+    }
+    info
+}
+
+/// Output gain (Q7.8 dB) from an OpusHead identification packet.
+fn opus_header_gain_db(extra_data: &[u8]) -> Option<f32> {
+    if extra_data.len() < 19 || !extra_data.starts_with(b"OpusHead") {
+        return None;
+    }
+    let gain = i16::from_le_bytes([extra_data[16], extra_data[17]]);
+    (gain != 0).then_some(gain as f32 / 256.0)
+}
+
+/// An RVA2 frame body: a NUL-terminated identification ("track" or "album"
+/// by convention), then per channel a type byte, a big-endian gain in
+/// 1/512 dB and a peak of the given bit width. Only the master volume
+/// channel (type 1) is used.
+fn parse_rva2(data: &[u8]) -> Option<(String, f32)> {
+    let nul = data.iter().position(|b| *b == 0)?;
+    let ident = String::from_utf8_lossy(&data[..nul]).to_ascii_lowercase();
+    let mut rest = &data[nul + 1..];
+    while rest.len() >= 4 {
+        let gain = i16::from_be_bytes([rest[1], rest[2]]) as f32 / 512.0;
+        if rest[0] == 1 {
+            return Some((ident, gain));
+        }
+        let peak_bytes = (rest[3] as usize).div_ceil(8);
+        rest = rest.get(4 + peak_bytes..)?;
+    }
+    None
+}
+
+/// RVA2 gains, which symphonia drops while reading the ID3 tag, so they
+/// are read from the tag again here. Peaks are left out: writers disagree
+/// on how to scale them.
+fn read_rva2(path: &Path) -> ReplayGainInfo {
+    let mut info = ReplayGainInfo::default();
+    let Ok(tag) = id3::Tag::read_from_path(path) else {
+        return info;
+    };
+    for frame in tag.frames().filter(|frame| frame.id() == "RVA2") {
+        let Ok(body) = frame.content().to_unknown() else {
+            continue;
+        };
+        let Some((ident, gain)) = parse_rva2(&body.data) else {
+            continue;
+        };
+        let slot = if ident == "album" {
+            &mut info.album_gain_db
+        } else {
+            &mut info.track_gain_db
+        };
+        slot.get_or_insert(gain);
+    }
+    info
+}
+
+/// Gain from the source the container uses: tags first, RVA2 when the probe
+/// found an ID3 tag without ReplayGain text frames, plus the Opus header
+/// gain.
+fn read_file_replaygain(
+    path: &Path,
+    tags: &[Tag],
+    id3_tag: bool,
+    params: Option<&CodecParameters>,
+) -> ReplayGainInfo {
+    let mut info = read_replaygain(tags);
+    if id3_tag && info.track_gain_db.is_none() && info.album_gain_db.is_none() {
+        let rva2 = read_rva2(path);
+        info.track_gain_db = rva2.track_gain_db;
+        info.album_gain_db = rva2.album_gain_db;
+    }
+    info.output_gain_db = params
+        .and_then(|p| p.extra_data.as_deref())
+        .and_then(opus_header_gain_db);
+    info
+}
+
+fn db_to_linear(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+fn normalize_replaygain_preamp(value: f32) -> f32 {
+    value.clamp(-15.0, 15.0)
+}
+
+const TRACK_GAIN_MIN_DB: f32 = -12.0;
+const TRACK_GAIN_MAX_DB: f32 = 12.0;
+
+fn normalize_track_gain_db(value: f32) -> f32 {
+    value.clamp(TRACK_GAIN_MIN_DB, TRACK_GAIN_MAX_DB)
+}
+
+fn normalize_dsd_mode(value: &str) -> String {
+    match value.to_lowercase().as_str() {
+        "dop" => "dop".to_string(),
+        _ => "pcm".to_string(),
+    }
+}
+
+fn normalize_live_pause_mode(value: &str) -> String {
+    let normalized = value.to_lowercase();
+    match normalized.as_str() {
+        "drop" | "hold" | "suspend" => normalized,
+        _ => "drop".to_string(),
+    }
+}
+
+fn normalize_replaygain_mode(value: &str) -> String {
+    let normalized = value.to_lowercase();
+    match normalized.as_str() {
+        "auto" | "track" | "album" => normalized,
+        _ => "auto".to_string(),
+    }
+}
+
+// In "auto" mode album gain is used only while the track played before or
+// after the current one (in play order, so shuffle and repeat-all count) is
+// from the same album, i.e. an album being played in order; anything else
+// is treated as a mix of singles.
+fn select_replaygain_mode(state: &EngineState) -> &'static str {
+    match state.replaygain_mode.as_str() {
+        "track" => return "track",
+        "album" => return "album",
+        _ => {}
+    }
+    let Some(index) = state.queue_index.filter(|idx| *idx < state.queue.len()) else {
+        return "track";
+    };
+    let current = &state.queue[index];
+    let same_album = |idx: Option<usize>| {
+        idx.filter(|idx| *idx != index)
+            .and_then(|idx| state.queue.get(idx))
+            .is_some_and(|t| is_same_album(t, current))
+    };
+    if same_album(peek_prev_queue_index(state)) || same_album(peek_next_queue_index(state, false)) {
+        "album"
+    } else {
+        "track"
+    }
+}
+
+/// Same non-empty album title; same-titled albums by different album
+/// artists are not one album.
+fn is_same_album(a: &LibraryTrack, b: &LibraryTrack) -> bool {
+    a.album.as_deref().is_some_and(|album| !album.is_empty() && b.album.as_deref() == Some(album))
+        && match (&a.album_artist, &b.album_artist) {
+            (Some(x), Some(y)) => x.eq_ignore_ascii_case(y),
+            _ => true,
+        }
+}
+
+fn replaygain_factor(
+    gain_db: Option<f32>,
+    peak: Option<f32>,
+    preamp_db: f32,
+    prevent_clipping: bool,
+) -> f32 {
+    let Some(gain_db) = gain_db else {
+        return 1.0;
+    };
+    let factor = db_to_linear(gain_db + preamp_db);
+    match peak.filter(|p| *p > 0.0) {
+        Some(peak) if prevent_clipping => factor.min(1.0 / peak),
+        _ => factor,
+    }
+}
+
+fn refresh_replaygain_gain(state: &mut EngineState) {
+    let mode = select_replaygain_mode(state);
+    let info = state.replaygain;
+    // Files without album tags fall back to their track gain.
+    let (gain_db, peak) = if mode == "album" && info.album_gain_db.is_some() {
+        (info.album_gain_db, info.album_peak)
+    } else {
+        (info.track_gain_db, info.track_peak)
+    };
+    state.replaygain_applied_mode = mode.to_string();
+    state.replaygain_gain = replaygain_factor(
+        gain_db,
+        peak,
+        state.replaygain_preamp_db,
+        state.replaygain_prevent_clipping,
+    ) * info.output_gain_db.map_or(1.0, db_to_linear);
+}
+
+/// Lowest limiter ceiling accepted, in dBFS.
+const LIMITER_MIN_DB: f32 = -12.0;
+
+/// Clamp a linear limiter ceiling to `[LIMITER_MIN_DB, 0]` dBFS.
+fn normalize_limiter_threshold(value: f32) -> f32 {
+    if !value.is_finite() {
+        return 1.0;
+    }
+    value.clamp(db_to_linear(LIMITER_MIN_DB), 1.0)
+}
+
+/// The limiter ceiling from `/configure_optimizations`, in dBFS. Older
+/// clients sent a linear amplitude in (0, 1], so positive values are still
+/// read that way.
+fn parse_limiter_threshold(value: f32) -> f32 {
+    if value > 0.0 {
+        normalize_limiter_threshold(value)
+    } else {
+        normalize_limiter_threshold(db_to_linear(value))
+    }
+}
+
+fn linear_to_db(value: f32) -> f32 {
+    20.0 * value.max(1e-6).log10()
+}
+
+#[cfg(test)]
+mod gapless_tests {
+    use super::apply_gapless_trim;
+
+    #[test]
+    fn gapless_trim_noop() {
+        let samples = vec![0.1_f32, -0.2, 0.3, -0.4];
+        let trimmed = apply_gapless_trim(samples.clone(), 2, 0, 0);
+        assert_eq!(trimmed, samples);
+    }
+
+    #[test]
+    fn gapless_trim_with_padding() {
+        // 2 channels, 4 frames => 8 samples.
+        let samples = (0..8).map(|v| v as f32).collect::<Vec<f32>>();
+        // Trim 1 leading frame and 1 trailing frame => keep frames 1..3 (2 frames).
+        let trimmed = apply_gapless_trim(samples, 2, 1, 1);
+        assert_eq!(trimmed, vec![2.0, 3.0, 4.0, 5.0]);
+    }
+
+    #[test]
+    fn gapless_trim_all() {
+        let samples = vec![0.0_f32; 6];
+        let trimmed = apply_gapless_trim(samples, 2, 2, 2);
+        assert!(trimmed.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod decode_tests {
+    use super::{
+        create_shared_state, decode_to_pcm, decode_to_pcm_with_options, downmix_to_stereo, export_impl,
+        auto_advance, fill_output_buffer, load_file_with_options, preload_impl, queue_add_impl, queue_next_impl,
+        queue_prev_impl, read_library_track, read_library_track_or_fallback, select_channels, take_preloaded, ChannelMode, Channels, DecodeOptions,
+        ExportFormat, ExportJob, finish_splice, stage_next_track, LookAhead, seek_clamped,
+        decode_file_with_progress, decode_file_head, prepare_track_for_load, stop_stream,
+        decode_options_for, dsd, f32_to_i32_sample, prepare_track, DsdOutput, OutputConfigInfo, INT32_OUTPUT_BITS,
+        load_error_status, ChannelSelection, LoadError, pause_impl, TRANSPORT_FADE_MAX_MS, ArtistTags,
+        export_partial_path, is_same_file, prepare_incremental_track, IncrementalSource, EqFilters, EqKind,
+    };
+    use axum::http::StatusCode;
+    use symphonia::core::audio::SampleBuffer;
+    use std::path::PathBuf;
+
+    fn write_wav(name: &str, declared_frames: u32, actual_frames: u32) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("ntmusic_{}_{}.wav", name, std::process::id()));
+        let data_len = declared_frames * 2;
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"RIFF");
+        bytes.extend_from_slice(&(36 + data_len).to_le_bytes());
+        bytes.extend_from_slice(b"WAVEfmt ");
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&48_000u32.to_le_bytes());
+        bytes.extend_from_slice(&96_000u32.to_le_bytes());
+        bytes.extend_from_slice(&2u16.to_le_bytes());
+        bytes.extend_from_slice(&16u16.to_le_bytes());
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&data_len.to_le_bytes());
+        for i in 0..actual_frames {
+            let sample = ((i % 100) as i16 - 50) * 100;
+            bytes.extend_from_slice(&sample.to_le_bytes());
+        }
+        std::fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    /// `write_wav` with `tag` in front of it, the way ID3v2 sits on an MP3.
+    fn write_id3_wav(name: &str, tag: &id3::Tag) -> PathBuf {
+        let path = write_wav(name, 480, 480);
+        let mut bytes = Vec::new();
+        tag.write_to(&mut bytes, id3::Version::Id3v24).unwrap();
+        bytes.extend(std::fs::read(&path).unwrap());
+        std::fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    #[test]
+    fn complete_file_has_no_partial_warning() {
+        let path = write_wav("complete", 24_000, 24_000);
+        let decoded = decode_to_pcm(path.to_str().unwrap()).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(decoded.samples.len(), 24_000);
+        assert!(decoded.partial.is_none());
+        // PCM carries no encoder delay/padding, so there is nothing to trim.
+        assert!(decoded.gapless.is_none());
+    }
+
+    #[test]
+    fn head_decodes_stop_early_and_keep_the_full_duration() {
+        let path = write_wav("head", 96_000, 96_000);
+        let head = decode_file_head(path.to_str().unwrap(), &DecodeOptions::default(), 0.5).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(head.samples.len(), 24_000);
+        assert_eq!(head.duration, 2.0);
+        assert!(head.partial.is_none());
+
+        let path = std::env::temp_dir().join(format!("ntmusic_head_{}.dsf", std::process::id()));
+        std::fs::write(&path, dsd::tests::dsf_bytes(0x69, 0x96, 4096 * 8, 4096)).unwrap();
+        let pcm = DsdOutput::Pcm(176_400);
+        let options = DecodeOptions { dsd: pcm, ..DecodeOptions::default() };
+        let full = decode_to_pcm_with_options(path.to_str().unwrap(), options).unwrap();
+        let head = decode_file_head(path.to_str().unwrap(), &options, full.duration / 4.0).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(head.samples.len() <= full.samples.len() / 4 + 2);
+        assert!((head.duration - full.duration).abs() < 1e-3);
+    }
+
+    #[test]
+    fn loads_report_progress_and_give_way_to_newer_ones() {
+        let path = write_wav("load_progress", 48_000, 48_000);
+        let path_str = path.to_str().unwrap();
+        let options = DecodeOptions::default();
+        let mut fractions = Vec::new();
+        decode_file_with_progress(path_str, &options, &mut |fraction| {
+            fractions.push(fraction.unwrap());
+            true
+        })
+        .unwrap();
+        assert!(fractions.len() > 1);
+        assert!(fractions.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(*fractions.last().unwrap(), 1.0);
+        assert!(decode_file_with_progress(path_str, &options, &mut |_| false).is_err());
+
+        // A load whose number is no longer current stops at the first packet.
+        let shared = create_shared_state();
+        shared.load_generation.store(2, std::sync::atomic::Ordering::Release);
+        let err = prepare_track_for_load(&shared, path_str, &options, 1).unwrap_err();
+        assert_eq!(err.downcast_ref::<LoadError>(), Some(&LoadError::Superseded(path_str.to_string())));
+        assert_eq!(load_error_status(&err), StatusCode::CONFLICT);
+        load_file_with_options(&shared, path_str.to_string(), options).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let state = shared.inner.lock().unwrap();
+        assert_eq!(state.stream_status, "idle");
+        assert_eq!(state.data.len(), 48_000);
+    }
+
+    #[test]
+    fn long_files_play_while_they_decode_and_seek_in_place() {
+        let path = write_wav("incremental", 48_000, 48_000);
+        let shared = create_shared_state();
+        {
+            let mut state = shared.inner.lock().unwrap();
+            state.dither_enabled = false;
+            state.replaygain_enabled = false;
+            state.output_channels = 1;
+            state.transport_fade_ms = 0;
+            state.incremental_decode_secs = 0.5;
+        }
+        load_file_with_options(&shared, path.to_string_lossy().to_string(), DecodeOptions::default()).unwrap();
+        {
+            let mut state = shared.inner.lock().unwrap();
+            assert!(state.data.is_empty());
+            assert_eq!(state.incremental.map(|incremental| incremental.frames), Some(48_000));
+            assert_eq!(state.duration, 1.0);
+            state.is_playing = true;
+        }
+        let decoded_to_end = || {
+            for _ in 0..500 {
+                let incremental = shared.inner.lock().unwrap().incremental.unwrap();
+                if incremental.seek.is_none() && incremental.finished {
+                    return;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            panic!("decoder never reached the end");
+        };
+        let expected = |frame: usize| ((frame % 100) as f32 - 50.0) * 100.0 / 32_768.0;
+        let mut out = vec![1.0f32; 1_024];
+
+        decoded_to_end();
+        fill_output_buffer(&shared.inner, &shared.consumer, &None, &mut out, None);
+        assert!(out.iter().enumerate().all(|(frame, sample)| *sample == expected(frame)));
+        assert_eq!(shared.inner.lock().unwrap().position, 1_024);
+
+        // Until the decoder has moved, the callback holds its place in silence.
+        seek_clamped(&mut shared.inner.lock().unwrap(), 0.5);
+        fill_output_buffer(&shared.inner, &shared.consumer, &None, &mut out, None);
+        if shared.inner.lock().unwrap().incremental.unwrap().seek.is_some() {
+            assert!(out.iter().all(|sample| *sample == 0.0));
+        }
+        decoded_to_end();
+        let at = shared.inner.lock().unwrap().position;
+        assert_eq!(at, 24_000);
+        fill_output_buffer(&shared.inner, &shared.consumer, &None, &mut out, None);
+        assert!(out.iter().enumerate().all(|(frame, sample)| *sample == expected(at + frame)));
+
+        let mut rest = vec![1.0f32; 24_000];
+        fill_output_buffer(&shared.inner, &shared.consumer, &None, &mut rest, None);
+        stop_stream(&shared);
+        let _ = std::fs::remove_file(&path);
+        let state = shared.inner.lock().unwrap();
+        assert_eq!(state.position, 48_000);
+        assert!(state.track_finished && !state.is_playing);
+        assert_eq!(state.underrun_count, 0);
+    }
+
+    #[test]
+    fn incremental_files_keep_channel_options_and_resample_live() {
+        let path = write_wav("incremental_options", 48_000, 48_000);
+        let path_str = path.to_string_lossy().to_string();
+        let shared = create_shared_state();
+        {
+            let mut state = shared.inner.lock().unwrap();
+            state.incremental_decode_secs = 0.5;
+            state.target_samplerate = Some(44_100);
+        }
+        let duplicated = DecodeOptions {
+            channels: ChannelMode::Select(ChannelSelection::new(&[0, 0]).unwrap()),
+            ..DecodeOptions::default()
+        };
+        let track = prepare_incremental_track(&shared, &path_str, &duplicated).expect("incremental track");
+        assert_eq!((track.sample_rate, track.source_channels), (48_000, 2));
+
+        let mut source = IncrementalSource::open(&path_str, &duplicated).unwrap();
+        let packet = source.format.next_packet().unwrap();
+        let decoded = source.decoder.decode(&packet).unwrap();
+        let mut buffer = SampleBuffer::<f32>::new(decoded.capacity() as u64, *decoded.spec());
+        buffer.copy_interleaved_ref(decoded);
+        let played = source.to_played_channels(buffer.samples());
+        assert_eq!(played.len(), buffer.samples().len() * 2);
+        assert!(played.chunks(2).zip(buffer.samples()).all(|(frame, sample)| frame == [*sample, *sample]));
+
+        // A channel the file lacks is left to the whole-file decode to report.
+        let out_of_range = DecodeOptions {
+            channels: ChannelMode::Select(ChannelSelection::new(&[1]).unwrap()),
+            ..DecodeOptions::default()
+        };
+        assert!(prepare_incremental_track(&shared, &path_str, &out_of_range).is_none());
+        let err = load_file_with_options(&shared, path_str, out_of_range).unwrap_err();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(err.downcast_ref::<LoadError>(), Some(&LoadError::ChannelOutOfRange { channel: 1, channels: 1 }));
+    }
+
+    #[test]
+    fn preloaded_track_is_reused_only_for_the_same_target() {
+        let path = write_wav("preload", 4_800, 4_800);
+        let path_str = path.to_str().unwrap();
+        let shared = create_shared_state();
+        let options = DecodeOptions::default();
+
+        assert!((preload_impl(&shared, path_str, &options).unwrap() - 0.1).abs() < 1e-6);
+        // Loading something else leaves the preload for its own load.
+        assert!(take_preloaded(&shared, "other.wav", &options).is_none());
+        let hit = take_preloaded(&shared, path_str, &options).expect("preloaded track");
+        assert_eq!(hit.samples.len(), 4_800);
+        assert_eq!(hit.sample_rate, 48_000);
+        assert!(take_preloaded(&shared, path_str, &options).is_none());
+
+        preload_impl(&shared, path_str, &options).unwrap();
+        shared.inner.lock().unwrap().target_samplerate = Some(44_100);
+        assert!(take_preloaded(&shared, path_str, &options).is_none());
+        shared.inner.lock().unwrap().target_samplerate = None;
+        assert!(take_preloaded(&shared, path_str, &options).is_some());
+
+        // Edited after the preload: decode it again.
+        preload_impl(&shared, path_str, &options).unwrap();
+        write_wav("preload", 2_400, 2_400);
+        let stale = take_preloaded(&shared, path_str, &options);
+        let _ = std::fs::remove_file(&path);
+        assert!(stale.is_none());
+        assert!(shared.preloaded.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn queue_prev_steps_back_and_stops_at_the_head() {
+        let first = write_wav("prev_first", 480, 480);
+        let second = write_wav("prev_second", 480, 480);
+        let shared = create_shared_state();
+        assert!(queue_prev_impl(&shared).unwrap().is_none());
+        let tracks = [&first, &second]
+            .iter()
+            .map(|path| read_library_track_or_fallback(path))
+            .collect();
+        queue_add_impl(&shared, tracks, true);
+        assert!(queue_prev_impl(&shared).unwrap().is_none());
+
+        queue_next_impl(&shared).unwrap();
+        queue_next_impl(&shared).unwrap();
+        let prev = queue_prev_impl(&shared).unwrap().unwrap();
+        let head = queue_prev_impl(&shared).unwrap();
+        let state = shared.inner.lock().unwrap();
+        let _ = std::fs::remove_file(&first);
+        let _ = std::fs::remove_file(&second);
+        assert_eq!(PathBuf::from(&prev.path), first);
+        assert_eq!(state.queue_index, Some(0));
+        assert_eq!(state.file_path.as_deref(), Some(prev.path.as_str()));
+        assert!(head.is_none());
+    }
+
+    #[test]
+    fn finished_queue_track_advances_when_enabled() {
+        let first = write_wav("advance_first", 480, 480);
+        let second = write_wav("advance_second", 480, 480);
+        let shared = create_shared_state();
+        let mut rx = shared.tx.subscribe();
+        let tracks = [&first, &second]
+            .iter()
+            .map(|path| read_library_track_or_fallback(path))
+            .collect();
+        queue_add_impl(&shared, tracks, true);
+        queue_next_impl(&shared).unwrap();
+        let play_out = || {
+            let mut out = vec![0.0f32; 1_024];
+            fill_output_buffer(&shared.inner, &shared.consumer, &None, &mut out, None);
+        };
+
+        // Off by default: the client advances.
+        play_out();
+        auto_advance(&shared);
+        assert_eq!(shared.inner.lock().unwrap().queue_index, Some(0));
+        assert!(!shared.inner.lock().unwrap().track_finished);
+
+        {
+            let mut state = shared.inner.lock().unwrap();
+            state.auto_advance = true;
+            state.position = 0;
+            state.is_playing = true;
+        }
+        play_out();
+        auto_advance(&shared);
+        let state = shared.inner.lock().unwrap();
+        let _ = std::fs::remove_file(&first);
+        let _ = std::fs::remove_file(&second);
+        assert_eq!(state.queue_index, Some(1));
+        assert!(state.is_playing);
+        let changed = std::iter::from_fn(|| rx.try_recv().ok())
+            .filter_map(|msg| serde_json::from_str::<serde_json::Value>(&msg).ok())
+            .find(|msg| msg["type"] == "track_changed")
+            .unwrap();
+        assert_eq!(changed["index"], 1);
+    }
+
+    #[test]
+    fn staged_next_track_splices_without_a_gap() {
+        let first = write_wav("splice_first", 480, 480);
+        let second = write_wav("splice_second", 480, 480);
+        let shared = create_shared_state();
+        let mut rx = shared.tx.subscribe();
+        {
+            let mut state = shared.inner.lock().unwrap();
+            state.dither_enabled = false;
+            state.replaygain_enabled = false;
+            state.output_channels = 1;
+            state.auto_advance = true;
+            state.transport_fade_ms = 0;
+        }
+        let tracks = [&first, &second]
+            .iter()
+            .map(|path| read_library_track_or_fallback(path))
+            .collect();
+        queue_add_impl(&shared, tracks, true);
+        let start_first = || {
+            load_file_with_options(&shared, first.to_string_lossy().to_string(), DecodeOptions::default()).unwrap();
+            shared.inner.lock().unwrap().is_playing = true;
+            stage_next_track(&shared);
+            for _ in 0..500 {
+                if !matches!(shared.inner.lock().unwrap().lookahead, LookAhead::Pending(_)) {
+                    break;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+        };
+
+        start_first();
+        assert!(matches!(shared.inner.lock().unwrap().lookahead, LookAhead::Ready(1, _)));
+        let mut out = vec![1.0f32; 1_024];
+        fill_output_buffer(&shared.inner, &shared.consumer, &None, &mut out, None);
+        assert_eq!(out[479], 2_900.0 / 32_768.0);
+        assert_eq!(out[480], -5_000.0 / 32_768.0);
+        assert!(out[960..].iter().all(|s| *s == 0.0));
+        {
+            let state = shared.inner.lock().unwrap();
+            assert_eq!(state.queue_index, Some(1));
+            assert_eq!(state.position, 480);
+            assert_eq!(state.retired_samples.len(), 480);
+            // The spliced track ran out too, with nothing staged after it.
+            assert!(state.track_finished);
+        }
+        finish_splice(&shared);
+        assert!(shared.inner.lock().unwrap().retired_samples.is_empty());
+        let changed = std::iter::from_fn(|| rx.try_recv().ok())
+            .filter_map(|msg| serde_json::from_str::<serde_json::Value>(&msg).ok())
+            .find(|msg| msg["type"] == "track_changed")
+            .unwrap();
+        assert_eq!((changed["index"].as_u64(), changed["gapless"].as_bool()), (Some(1), Some(true)));
+
+        // Silence between tracks that are not gapless needs a normal load.
+        shared.inner.lock().unwrap().inter_track_silence_ms = 5;
+        start_first();
+        let _ = std::fs::remove_file(&first);
+        let _ = std::fs::remove_file(&second);
+        assert!(matches!(shared.inner.lock().unwrap().lookahead, LookAhead::Skipped));
+    }
+
+    #[test]
+    fn next_crossfades_from_the_playing_track() {
+        let first = write_wav("fade_first", 4_800, 4_800);
+        let second = write_wav("fade_second", 4_800, 4_800);
+        let shared = create_shared_state();
+        {
+            let mut state = shared.inner.lock().unwrap();
+            state.dither_enabled = false;
+            state.replaygain_enabled = false;
+            state.output_channels = 1;
+            state.crossfade_ms = 5;
+            state.transport_fade_ms = 0;
+        }
+        let tracks = [&first, &second]
+            .iter()
+            .map(|path| read_library_track_or_fallback(path))
+            .collect();
+        queue_add_impl(&shared, tracks, true);
+        queue_next_impl(&shared).unwrap();
+        shared.inner.lock().unwrap().position = 10;
+        queue_next_impl(&shared).unwrap();
+        assert_eq!(shared.inner.lock().unwrap().crossfade.as_ref().map(|fade| fade.frames), Some(240));
+
+        let mut out = vec![1.0f32; 256];
+        fill_output_buffer(&shared.inner, &shared.consumer, &None, &mut out, None);
+        // All outgoing (frame 10) at the start, all incoming after 240 frames,
+        // and both at -3 dB halfway.
+        assert_eq!(out[0], -4_000.0 / 32_768.0);
+        let halfway = (-3_000.0 - 2_000.0) / 32_768.0 * std::f32::consts::FRAC_1_SQRT_2;
+        assert!((out[120] - halfway).abs() < 1e-6);
+        assert_eq!(out[240], -1_000.0 / 32_768.0);
+        assert!(!shared.inner.lock().unwrap().crossfade.as_ref().unwrap().active());
+
+        // Seeking mid-fade drops the outgoing track.
+        queue_prev_impl(&shared).unwrap();
+        {
+            let mut state = shared.inner.lock().unwrap();
+            assert!(state.crossfade.as_ref().unwrap().active());
+            seek_clamped(&mut state, 0.0);
+            assert!(!state.crossfade.as_ref().unwrap().active());
+        }
+        fill_output_buffer(&shared.inner, &shared.consumer, &None, &mut out, None);
+        assert_eq!(out[0], -5_000.0 / 32_768.0);
+
+        // Each track keeps its own gain through the overlap.
+        let quieter = 10.0f32.powf(-6.0 / 20.0);
+        {
+            let mut state = shared.inner.lock().unwrap();
+            state.track_gain_db = -6.0;
+            state.position = 10;
+        }
+        queue_next_impl(&shared).unwrap();
+        fill_output_buffer(&shared.inner, &shared.consumer, &None, &mut out, None);
+        let _ = std::fs::remove_file(&first);
+        let _ = std::fs::remove_file(&second);
+        assert!((out[0] - -4_000.0 / 32_768.0 * quieter).abs() < 1e-6);
+        let halfway = (-3_000.0 - 2_000.0 * quieter) / 32_768.0 * std::f32::consts::FRAC_1_SQRT_2;
+        assert!((out[120] - halfway).abs() < 1e-6);
+        assert_eq!(out[240], -1_000.0 / 32_768.0);
+    }
+
+    #[test]
+    fn silence_follows_a_track_that_ended_but_not_a_skip() {
+        let first = write_wav("gap_first", 480, 480);
+        let second = write_wav("gap_second", 4_800, 4_800);
+        let shared = create_shared_state();
+        {
+            let mut state = shared.inner.lock().unwrap();
+            state.inter_track_silence_ms = 5;
+            state.dither_enabled = false;
+            state.replaygain_enabled = false;
+            state.output_channels = 1;
+        }
+        let render = |frames: usize| {
+            let mut out = vec![1.0f32; frames];
+            fill_output_buffer(&shared.inner, &shared.consumer, &None, &mut out, None);
+            out
+        };
+        let load = |path: &PathBuf| {
+            load_file_with_options(&shared, path.to_string_lossy().to_string(), DecodeOptions::default()).unwrap();
+            shared.inner.lock().unwrap().is_playing = true;
+        };
+
+        load(&first);
+        render(1_024);
+        assert!(!shared.inner.lock().unwrap().is_playing);
+        load(&second);
+        assert_eq!(shared.inner.lock().unwrap().gap_frames, 240);
+        let out = render(256);
+        assert!(out[..240].iter().all(|s| *s == 0.0));
+        assert_eq!(out[240], -5_000.0 / 32_768.0);
+        assert_eq!(shared.inner.lock().unwrap().position, 16);
+
+        // Skipping while the track is still playing goes straight on.
+        load(&first);
+        let _ = std::fs::remove_file(&first);
+        let _ = std::fs::remove_file(&second);
+        assert_eq!(shared.inner.lock().unwrap().gap_frames, 0);
+    }
+
+    #[test]
+    fn export_renders_through_the_processing_chain() {
+        let path = write_wav("export_src", 4_800, 4_800);
+        let output = std::env::temp_dir().join(format!("ntmusic_export_out_{}.flac", std::process::id()));
+        let shared = create_shared_state();
+        {
+            // The export has its own volume; playback's doesn't carry over.
+            let mut state = shared.inner.lock().unwrap();
+            state.volume = 0.25;
+            state.dither_enabled = false;
+        }
+        let job = ExportJob {
+            source: path.to_string_lossy().to_string(),
+            output: output.clone(),
+            partial: export_partial_path(&output),
+            format: ExportFormat::Flac,
+            sample_rate: None,
+            bit_depth: None,
+            volume: 0.5,
+        };
+        let mut last_progress = 0.0;
+        let spec = export_impl(&shared, &job, |fraction| last_progress = fraction).unwrap();
+        assert!(!job.partial.exists());
+        let source = decode_to_pcm(path.to_str().unwrap()).unwrap();
+        let rendered = decode_to_pcm(output.to_str().unwrap()).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&output);
+
+        assert_eq!((spec.sample_rate, spec.channels, spec.bit_depth), (48_000, 1, 16));
+        assert_eq!(last_progress, 1.0);
+        assert_eq!(rendered.samples.len(), source.samples.len());
+        for (out, src) in rendered.samples.iter().zip(&source.samples) {
+            assert!((out - src * 0.5).abs() <= 1.0 / 32_768.0);
+        }
+    }
+
+    #[test]
+    fn export_lines_up_with_the_source_through_the_fir_eq() {
+        let path = write_wav("export_fir", 4_800, 4_800);
+        let output = std::env::temp_dir().join(format!("ntmusic_export_fir_out_{}.wav", std::process::id()));
+        let shared = create_shared_state();
+        let gains = {
+            let mut state = shared.inner.lock().unwrap();
+            state.dither_enabled = false;
+            state.eq_enabled = true;
+            state.eq_bands.insert("1k".to_string(), 6.0);
+            state.eq_filters.set_kind(EqKind::Fir, 511);
+            state.eq_bands.clone()
+        };
+        let job = ExportJob {
+            source: path.to_string_lossy().to_string(),
+            output: output.clone(),
+            partial: export_partial_path(&output),
+            format: ExportFormat::Wav,
+            sample_rate: None,
+            bit_depth: Some(32),
+            volume: 1.0,
+        };
+        export_impl(&shared, &job, |_| {}).unwrap();
+        let source = decode_to_pcm(path.to_str().unwrap()).unwrap();
+        let rendered = decode_to_pcm(output.to_str().unwrap()).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&output);
+
+        // The same filter run past the end, less its delay.
+        let mut filters = EqFilters::default();
+        filters.set_kind(EqKind::Fir, 511);
+        filters.configure(&gains, 48_000);
+        filters.design_current_rate();
+        let latency = filters.latency_frames();
+        assert!(latency > 0);
+        let mut expected = source.samples.clone();
+        expected.resize(source.samples.len() + latency, 0.0);
+        filters.process(&mut expected, 1);
+        assert_eq!(rendered.samples.len(), source.samples.len());
+        for (out, want) in rendered.samples.iter().zip(&expected[latency..]) {
+            assert!((out - want).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn export_never_writes_over_its_source() {
+        let path = write_wav("export_self", 480, 480);
+        let dir = path.parent().unwrap();
+        let name = path.file_name().unwrap();
+        assert!(is_same_file(&path, &path));
+        assert!(is_same_file(&path, &dir.join(".").join(name)));
+        assert!(!is_same_file(&path, &dir.join("ntmusic_export_other.wav")));
+        assert!(!is_same_file(&dir.join("ntmusic_export_missing.wav"), &path));
+
+        // A failed render leaves an existing output alone.
+        let output = std::env::temp_dir().join(format!("ntmusic_export_kept_{}.wav", std::process::id()));
+        std::fs::write(&output, b"previous export").unwrap();
+        let job = ExportJob {
+            source: "missing.wav".to_string(),
+            output: output.clone(),
+            partial: export_partial_path(&output),
+            format: ExportFormat::Wav,
+            sample_rate: None,
+            bit_depth: None,
+            volume: 1.0,
+        };
+        assert!(export_impl(&create_shared_state(), &job, |_| {}).is_err());
+        let kept = std::fs::read(&output).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&output);
+        assert_eq!(kept, b"previous export");
+    }
+
+    #[test]
+    fn truncated_file_plays_decoded_part_and_reports_partial() {
+        let path = write_wav("truncated", 48_000, 12_000);
+        let decoded = decode_to_pcm(path.to_str().unwrap()).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(decoded.samples.len(), 12_000);
+        let partial = decoded.partial.expect("partial decode info");
+        assert_eq!(partial.decoded_frames, 12_000);
+        assert_eq!(partial.expected_frames, Some(48_000));
+    }
+
+    #[test]
+    fn library_tracks_carry_source_format() {
+        let path = write_wav("format", 4_800, 4_800);
+        let track = read_library_track(&path).unwrap();
+        std::fs::write(&path, b"not audio").unwrap();
+        let broken = read_library_track_or_fallback(&path);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(track.sample_rate, Some(48_000));
+        assert_eq!(track.bit_depth, Some(16));
+        assert_eq!(track.channels, Some(1));
+        assert!((track.duration - 0.1).abs() < 1e-9);
+        assert_eq!(broken.sample_rate, None);
+        assert_eq!(broken.channels, None);
+        assert!(track.decodable && !broken.decodable);
+    }
+
+    #[test]
+    fn artist_tags_keep_all_artists_and_album_artist() {
+        use symphonia::core::meta::{StandardTagKey, Tag, Value};
+        let tag = |std_key, key: &str, value: &str| Tag::new(std_key, key, Value::String(value.to_string()));
+
+        let mut tags = ArtistTags::default();
+        tags.add(&tag(Some(StandardTagKey::Artist), "ARTIST", "Alpha"));
+        tags.add(&tag(Some(StandardTagKey::Artist), "ARTIST", "Beta\0Gamma"));
+        tags.add(&tag(Some(StandardTagKey::Artist), "ARTIST", "alpha"));
+        tags.add(&tag(Some(StandardTagKey::SortAlbumArtist), "ALBUMARTISTSORT", "Various, The"));
+        tags.add(&tag(None, "ALBUM ARTIST", "Various"));
+        let (artist, artists, album_artist) = tags.finish();
+        assert_eq!(artist.as_deref(), Some("Alpha, Beta, Gamma"));
+        assert_eq!(artists, vec!["Alpha", "Beta", "Gamma"]);
+        assert_eq!(album_artist.as_deref(), Some("Various"));
+
+        // ARTISTS wins for the list while ARTIST stays the display string.
+        let mut tags = ArtistTags::default();
+        tags.add(&tag(Some(StandardTagKey::Artist), "ARTIST", "AC/DC feat. Someone"));
+        tags.add(&tag(None, "ARTISTS", "AC/DC; Someone"));
+        let (artist, artists, album_artist) = tags.finish();
+        assert_eq!(artist.as_deref(), Some("AC/DC feat. Someone"));
+        assert_eq!(artists, vec!["AC/DC", "Someone"]);
+        assert_eq!(album_artist, None);
+    }
+
+    #[test]
+    fn library_tracks_read_artists_from_id3() {
+        use id3::TagLike;
+        let mut tag = id3::Tag::new();
+        tag.set_artist("Alpha");
+        tag.set_album_artist("Various");
+        let path = write_id3_wav("id3_artist", &tag);
+        let track = read_library_track(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(track.artist.as_deref(), Some("Alpha"));
+        assert_eq!(track.artists, vec!["Alpha"]);
+        assert_eq!(track.album_artist.as_deref(), Some("Various"));
+    }
+
+    #[test]
+    fn library_tracks_take_rva2_only_from_an_id3_tag() {
+        // "track", master volume -6 dB, no peak.
+        let mut body = b"track\0".to_vec();
+        body.push(1);
+        body.extend_from_slice(&(-3072i16).to_be_bytes());
+        body.push(0);
+        use id3::TagLike;
+        let mut tag = id3::Tag::new();
+        tag.add_frame(id3::Frame::with_content(
+            "RVA2",
+            id3::Content::Unknown(id3::frame::Unknown { data: body, version: id3::Version::Id3v24 }),
+        ));
+        let path = write_id3_wav("id3_rva2", &tag);
+        let track = read_library_track(&path).unwrap();
+        let plain = write_wav("no_id3", 480, 480);
+        let untagged = read_library_track(&plain).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&plain);
+        assert!((track.track_gain.unwrap() - 0.5).abs() < 0.01);
+        assert_eq!(untagged.track_gain, None);
+    }
+
+    #[test]
+    fn library_tracks_read_year_and_genre_from_id3() {
+        use id3::TagLike;
+        let mut tag = id3::Tag::new();
+        tag.set_date_recorded("1997-03-14".parse().unwrap());
+        tag.set_genre("Jazz");
+        let path = write_id3_wav("id3_year", &tag);
+        let track = read_library_track(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(track.year, Some(1997));
+        assert_eq!(track.genre.as_deref(), Some("Jazz"));
+        assert_eq!(track.sample_rate, Some(48_000));
+    }
+
+    #[test]
+    fn dsd_files_list_convert_and_pass_dop_through_untouched() {
+        let path = std::env::temp_dir().join(format!("ntmusic_dop_{}.dsf", std::process::id()));
+        std::fs::write(&path, dsd::tests::dsf_bytes(0x96, 0x0F, 8192, 4096)).unwrap();
+        let path_str = path.to_str().unwrap();
+        let track = read_library_track(&path).unwrap();
+        assert!(track.decodable);
+        assert_eq!((track.sample_rate, track.channels, track.bit_depth), (Some(dsd::DSD64_RATE), Some(2), Some(1)));
+        assert!((track.duration - 8192.0 * 8.0 / dsd::DSD64_RATE as f64).abs() < 1e-9);
+
+        let shared = create_shared_state();
+        {
+            let mut state = shared.inner.lock().unwrap();
+            state.target_samplerate = Some(96_000);
+            state.dsd_mode = "dop".to_string();
+        }
+        // DoP wants exclusive output; without it the file converts.
+        let options = decode_options_for(&shared.inner.lock().unwrap());
+        assert_eq!(options.dsd, DsdOutput::Pcm(176_400));
+        shared.inner.lock().unwrap().exclusive_mode = true;
+        let options = decode_options_for(&shared.inner.lock().unwrap());
+        assert_eq!(options.dsd, DsdOutput::Dop);
+        // Nor is it resampled to the target rate.
+        let prepared = prepare_track(&shared, path_str, &options).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!((prepared.sample_rate, prepared.dsd), (dsd::DOP_RATE, Some(DsdOutput::Dop)));
+        assert_eq!(prepared.samples.len(), 4096 * 2);
+
+        let words = prepared.samples[..8].to_vec();
+        {
+            let mut state = shared.inner.lock().unwrap();
+            state.mode = "file".to_string();
+            state.data = prepared.samples;
+            state.channels = 2;
+            state.output_channels = 2;
+            state.sample_rate = prepared.sample_rate;
+            state.dsd_output = prepared.dsd;
+            state.is_playing = true;
+            state.transport_gain = 1.0;
+            state.volume = 0.5;
+            state.volume_current = 0.5;
+            state.output_config = Some(OutputConfigInfo {
+                backend: "test".to_string(),
+                sample_rate: dsd::DOP_RATE,
+                channels: 2,
+                sample_format: "i32".to_string(),
+                bit_depth: INT32_OUTPUT_BITS,
+                buffer_frames: None,
+            });
+        }
+        let mut out = vec![0.0f32; 8];
+        fill_output_buffer(&shared.inner, &shared.consumer, &None, &mut out, Some(INT32_OUTPUT_BITS));
+        assert_eq!(out, words);
+        assert_eq!(f32_to_i32_sample(out[0], INT32_OUTPUT_BITS) >> 8, 0x05_6969);
+        // A float device would hear the markers as noise.
+        fill_output_buffer(&shared.inner, &shared.consumer, &None, &mut out, None);
+        assert!(out.iter().all(|sample| *sample == 0.0));
+
+        // DoP can't ramp, so a pause goes silent at the next callback
+        // rather than waiting out the fade.
+        shared.inner.lock().unwrap().transport_fade_ms = TRANSPORT_FADE_MAX_MS;
+        let started = std::time::Instant::now();
+        let pause = {
+            let shared = shared.clone();
+            std::thread::spawn(move || pause_impl(&shared))
+        };
+        while !pause.is_finished() {
+            fill_output_buffer(&shared.inner, &shared.consumer, &None, &mut out, Some(INT32_OUTPUT_BITS));
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        pause.join().unwrap().unwrap();
+        assert!(started.elapsed() < std::time::Duration::from_millis(TRANSPORT_FADE_MAX_MS as u64));
+        assert!(out.iter().all(|sample| *sample == 0.0));
+        assert_eq!(shared.inner.lock().unwrap().transport_gain, 0.0);
+    }
+
+    #[test]
+    fn surround_downmix_follows_itu_coefficients() {
+        let layout = Channels::FRONT_LEFT
+            | Channels::FRONT_RIGHT
+            | Channels::FRONT_CENTRE
+            | Channels::LFE1
+            | Channels::REAR_LEFT
+            | Channels::REAR_RIGHT;
+        // Frames: centre only, LFE only, left surround only.
+        let frames = [
+            [0.0, 0.0, 1.0, 0.0, 0.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0, 0.0, 0.0],
+            [0.0, 0.0, 0.0, 0.0, 1.0, 0.0],
+        ];
+        let samples: Vec<f32> = frames.concat();
+        let stereo = downmix_to_stereo(&samples, 6, Some(layout));
+        let norm = 1.0 / (1.0 + 2.0 * std::f32::consts::FRAC_1_SQRT_2);
+        let expected = [
+            std::f32::consts::FRAC_1_SQRT_2 * norm,
+            std::f32::consts::FRAC_1_SQRT_2 * norm,
+            0.0,
+            0.0,
+            std::f32::consts::FRAC_1_SQRT_2 * norm,
+            0.0,
+        ];
+        for (got, want) in stereo.iter().zip(expected) {
+            assert!((got - want).abs() < 1e-6, "{:?}", stereo);
+        }
+        // No layout falls back to WAVE order and gives the same result.
+        assert_eq!(downmix_to_stereo(&samples, 6, None), stereo);
+
+        assert_eq!(select_channels(&samples, 6, &[2, 0]), vec![1.0, 0.0, 0.0, 0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn channel_options_apply_at_decode_time() {
+        let path = write_wav("channels", 4_800, 4_800);
+        let keep = decode_to_pcm(path.to_str().unwrap()).unwrap();
+        // A mono file is already narrower than stereo and is left alone.
+        let stereo = decode_to_pcm_with_options(
+            path.to_str().unwrap(),
+            DecodeOptions {
+                channels: ChannelMode::Stereo,
+                ..DecodeOptions::default()
+            },
+        )
+        .unwrap();
+        let duplicated = decode_to_pcm_with_options(
+            path.to_str().unwrap(),
+            DecodeOptions {
+                channels: ChannelMode::Select(ChannelSelection::new(&[0, 0]).unwrap()),
+                ..DecodeOptions::default()
+            },
+        )
+        .unwrap();
+        let out_of_range = decode_to_pcm_with_options(
+            path.to_str().unwrap(),
+            DecodeOptions {
+                channels: ChannelMode::Select(ChannelSelection::new(&[1]).unwrap()),
+                ..DecodeOptions::default()
+            },
+        );
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(stereo.channels, 1);
+        assert_eq!(stereo.samples, keep.samples);
+        assert_eq!(duplicated.channels, 2);
+        assert_eq!(duplicated.samples.len(), keep.samples.len() * 2);
+        let out_of_range = out_of_range.err().unwrap();
+        assert_eq!(
+            out_of_range.downcast_ref::<LoadError>(),
+            Some(&LoadError::ChannelOutOfRange { channel: 1, channels: 1 })
+        );
+        assert_eq!(load_error_status(&out_of_range), StatusCode::BAD_REQUEST);
+    }
+}
+
+#[cfg(test)]
+mod queue_tests {
+    use super::{
+        build_state_view, create_shared_state, db_to_linear, gapless_check_impl, next_queue_index,
+        opus_header_gain_db, parse_position_tag, parse_rva2, parse_year_tag, prev_queue_index, queue_add_impl,
+        queue_clear_impl, queue_insert_impl, queue_move_impl, queue_remove_impl, read_file_replaygain, read_replaygain,
+        refresh_replaygain_gain, peek_next_queue_index,
+        LibraryTrack, ReplayGainInfo, StandardTagKey,
+    };
+
+    fn track(path: &str) -> LibraryTrack {
+        LibraryTrack {
+            path: path.to_string(),
+            title: None,
+            artist: None,
+            artists: Vec::new(),
+            album_artist: None,
+            album: None,
+            track_number: None,
+            disc_number: None,
+            year: None,
+            genre: None,
+            duration: 0.0,
+            sample_rate: None,
+            bit_depth: None,
+            channels: None,
+            track_gain: None,
+            album_gain: None,
+            decodable: true,
+        }
+    }
+
+    #[test]
+    fn removing_entries_keeps_the_playing_one_selected() {
+        let shared = create_shared_state();
+        let paths = ["a.flac", "b.flac", "c.flac", "d.flac", "e.flac"];
+        queue_add_impl(&shared, paths.iter().map(|path| track(path)).collect(), true);
+        {
+            let mut state = shared.inner.lock().unwrap();
+            state.queue_index = Some(3);
+            state.shuffle_history = vec![4, 1, 3];
+        }
+        assert!(queue_remove_impl(&shared, &[0, 9]).is_err());
+        assert_eq!(shared.inner.lock().unwrap().queue.len(), 5);
+
+        assert_eq!(queue_remove_impl(&shared, &[1, 0, 1]).unwrap(), 3);
+        {
+            let state = shared.inner.lock().unwrap();
+            let left: Vec<&str> = state.queue.iter().map(|track| track.path.as_str()).collect();
+            assert_eq!(left, ["c.flac", "d.flac", "e.flac"]);
+            assert_eq!(state.queue_index, Some(1));
+            assert_eq!(state.shuffle_history, vec![2, 1]);
+        }
+
+        // The playing entry itself: it plays on, unselected.
+        queue_remove_impl(&shared, &[1]).unwrap();
+        assert_eq!(shared.inner.lock().unwrap().queue_index, None);
+
+        queue_clear_impl(&shared);
+        let state = shared.inner.lock().unwrap();
+        assert!(state.queue.is_empty());
+        assert!(state.shuffle_history.is_empty());
+    }
+
+    #[test]
+    fn moves_and_inserts_keep_the_playing_entry_selected() {
+        let shared = create_shared_state();
+        let paths = ["a.flac", "b.flac", "c.flac", "d.flac"];
+        queue_add_impl(&shared, paths.iter().map(|path| track(path)).collect(), true);
+        shared.inner.lock().unwrap().queue_index = Some(1);
+        let order = |shared: &super::SharedState| -> Vec<String> {
+            shared.inner.lock().unwrap().queue.iter().map(|track| track.path.clone()).collect()
+        };
+
+        assert!(queue_move_impl(&shared, 0, 4).is_err());
+        assert!(queue_insert_impl(&shared, vec![track("x.flac")], 5).is_err());
+        assert_eq!(order(&shared), paths);
+
+        // Drag the playing entry down, then another one up past it.
+        queue_move_impl(&shared, 1, 3).unwrap();
+        assert_eq!(order(&shared), ["a.flac", "c.flac", "d.flac", "b.flac"]);
+        assert_eq!(shared.inner.lock().unwrap().queue_index, Some(3));
+        queue_move_impl(&shared, 2, 0).unwrap();
+        assert_eq!(order(&shared), ["d.flac", "a.flac", "c.flac", "b.flac"]);
+        assert_eq!(shared.inner.lock().unwrap().queue_index, Some(3));
+
+        assert_eq!(queue_insert_impl(&shared, vec![track("x.flac"), track("y.flac")], 1).unwrap(), 6);
+        assert_eq!(order(&shared), ["d.flac", "x.flac", "y.flac", "a.flac", "c.flac", "b.flac"]);
+        assert_eq!(shared.inner.lock().unwrap().queue_index, Some(5));
+        queue_insert_impl(&shared, vec![track("z.flac")], 6).unwrap();
+        assert_eq!(order(&shared).last().unwrap(), "z.flac");
+        assert_eq!(shared.inner.lock().unwrap().queue_index, Some(5));
+    }
+
+    #[test]
+    fn queue_add_sets_index_for_current_path() {
+        let shared = create_shared_state();
+        {
+            let mut state = shared.inner.lock().unwrap();
+            state.file_path = Some("b.flac".to_string());
+        }
+        let count = queue_add_impl(&shared, vec![track("a.flac"), track("b.flac")], true);
+        assert_eq!(count, 2);
+        let state = shared.inner.lock().unwrap();
+        assert_eq!(state.queue_index, Some(1));
+    }
+
+    #[test]
+    fn replaygain_reads_text_tags_and_prefers_r128() {
+        use symphonia::core::meta::{Tag, Value};
+        let tag = |std_key, key: &str, value: &str| Tag::new(std_key, key, Value::String(value.to_string()));
+
+        // FLAC/Vorbis comments, mapped by symphonia.
+        let info = read_replaygain(&[
+            tag(Some(StandardTagKey::ReplayGainTrackGain), "REPLAYGAIN_TRACK_GAIN", "-7.50 dB"),
+            tag(Some(StandardTagKey::ReplayGainTrackPeak), "REPLAYGAIN_TRACK_PEAK", "0.98"),
+        ]);
+        assert_eq!(info.track_gain_db, Some(-7.5));
+        assert_eq!(info.track_peak, Some(0.98));
+
+        // Lowercase ID3 TXXX descriptions are left unmapped by symphonia.
+        let info = read_replaygain(&[tag(None, "TXXX:replaygain_album_gain", "+2.25 dB")]);
+        assert_eq!(info.album_gain_db, Some(2.25));
+
+        // Opus: R128 (-2048/256 = -8 dB vs -23 LUFS) beats stale ReplayGain.
+        let info = read_replaygain(&[
+            tag(Some(StandardTagKey::ReplayGainTrackGain), "REPLAYGAIN_TRACK_GAIN", "-1.00 dB"),
+            tag(Some(StandardTagKey::ReplayGainTrackPeak), "REPLAYGAIN_TRACK_PEAK", "0.5"),
+            tag(None, "R128_TRACK_GAIN", "-2048"),
+        ]);
+        assert_eq!(info.track_gain_db, Some(-3.0));
+        assert_eq!(info.track_peak, None);
+    }
+
+    #[test]
+    fn replaygain_reads_each_container_convention() {
+        use symphonia::core::meta::{Tag, Value};
+        let tag = |std_key, key: &str, value: &str| Tag::new(std_key, key, Value::String(value.to_string()));
+
+        // Opus: -2048/256 = -8 dB vs -23 LUFS, -3 dB on the ReplayGain scale.
+        let info = read_replaygain(&[tag(None, "R128_TRACK_GAIN", "-2048"), tag(None, "R128_ALBUM_GAIN", "256")]);
+        assert_eq!(info.track_gain_db, Some(-3.0));
+        assert_eq!(info.album_gain_db, Some(6.0));
+        assert_eq!(info.track_peak, None);
+
+        let mut head = b"OpusHead".to_vec();
+        head.extend_from_slice(&[1, 2, 0x38, 0x01, 0x80, 0xbb, 0, 0]);
+        head.extend_from_slice(&(-768i16).to_le_bytes());
+        head.push(0);
+        assert_eq!(opus_header_gain_db(&head), Some(-3.0));
+        assert_eq!(opus_header_gain_db(b"OpusHead"), None);
+
+        let shared = create_shared_state();
+        let mut state = shared.inner.lock().unwrap();
+        state.replaygain = ReplayGainInfo {
+            track_gain_db: Some(-3.0),
+            output_gain_db: Some(-3.0),
+            ..Default::default()
+        };
+        refresh_replaygain_gain(&mut state);
+        assert!((state.replaygain_gain - db_to_linear(-6.0)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn replaygain_reads_id3_rva2() {
+        // "track", master volume -6 dB (-3072/512), 16-bit peak.
+        let mut body = b"track\0".to_vec();
+        body.push(1);
+        body.extend_from_slice(&(-3072i16).to_be_bytes());
+        body.extend_from_slice(&[16, 0x7f, 0xff]);
+        assert_eq!(parse_rva2(&body), Some(("track".to_string(), -6.0)));
+
+        use id3::TagLike;
+        let path = std::env::temp_dir().join(format!("ntmusic_rva2_{}.mp3", std::process::id()));
+        let mut tag = id3::Tag::new();
+        tag.add_frame(id3::Frame::with_content(
+            "RVA2",
+            id3::Content::Unknown(id3::frame::Unknown {
+                data: body,
+                version: id3::Version::Id3v24,
+            }),
+        ));
+        std::fs::write(&path, b"").unwrap();
+        tag.write_to_path(&path, id3::Version::Id3v24).unwrap();
+        let info = read_file_replaygain(&path, &[], true, None);
+        assert_eq!(read_file_replaygain(&path, &[], false, None).track_gain_db, None);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(info.track_gain_db, Some(-6.0));
+        assert_eq!(info.album_gain_db, None);
+    }
+
+    fn album_track(path: &str, album: &str) -> LibraryTrack {
+        LibraryTrack {
+            album: Some(album.to_string()),
+            ..track(path)
+        }
+    }
+
+    #[test]
+    fn repeat_modes_wrap_or_replay() {
+        let shared = create_shared_state();
+        queue_add_impl(&shared, vec![track("a"), track("b")], true);
+        let mut state = shared.inner.lock().unwrap();
+        assert_eq!(next_queue_index(&mut state, false), Some(0));
+        state.queue_index = Some(1);
+        assert_eq!(next_queue_index(&mut state, true), None);
+        assert_eq!(prev_queue_index(&mut state), Some(0));
+
+        state.repeat_mode = "all".to_string();
+        assert_eq!(next_queue_index(&mut state, true), Some(0));
+        state.queue_index = Some(0);
+        assert_eq!(prev_queue_index(&mut state), Some(1));
+
+        // Repeat-one replays a finished track but lets a skip through.
+        state.repeat_mode = "one".to_string();
+        assert_eq!(next_queue_index(&mut state, true), Some(0));
+        assert_eq!(next_queue_index(&mut state, false), Some(1));
+    }
+
+    #[test]
+    fn shuffle_plays_each_entry_once_per_cycle() {
+        let shared = create_shared_state();
+        queue_add_impl(&shared, (0..6).map(|i| track(&i.to_string())).collect(), true);
+        let mut state = shared.inner.lock().unwrap();
+        state.shuffle = true;
+        state.shuffle_rng = 7;
+        let mut order = Vec::new();
+        while let Some(idx) = next_queue_index(&mut state, false) {
+            state.queue_index = Some(idx);
+            order.push(idx);
+        }
+        let mut sorted = order.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..6).collect::<Vec<_>>());
+        assert_ne!(order, sorted);
+
+        // Back through the order it played in.
+        assert_eq!(prev_queue_index(&mut state), Some(order[4]));
+        state.queue_index = Some(order[4]);
+        assert_eq!(prev_queue_index(&mut state), Some(order[3]));
+
+        state.repeat_mode = "all".to_string();
+        state.queue_index = Some(order[5]);
+        state.shuffle_history = order.clone();
+        let next = next_queue_index(&mut state, false).unwrap();
+        assert_ne!(next, order[5]);
+        assert_eq!(state.shuffle_history, vec![next]);
+    }
+
+    #[test]
+    fn replaygain_uses_album_gain_for_in_order_album_runs() {
+        let shared = create_shared_state();
+        {
+            let mut state = shared.inner.lock().unwrap();
+            state.file_path = Some("a2.flac".to_string());
+            state.replaygain = ReplayGainInfo {
+                track_gain_db: Some(-3.0),
+                track_peak: None,
+                album_gain_db: Some(-6.0),
+                album_peak: None,
+                output_gain_db: None,
+            };
+        }
+        queue_add_impl(
+            &shared,
+            vec![album_track("a1.flac", "A"), album_track("a2.flac", "A"), album_track("b1.flac", "B")],
+            true,
+        );
+        {
+            let mut state = shared.inner.lock().unwrap();
+            assert_eq!(state.replaygain_applied_mode, "album");
+            assert!((state.replaygain_gain - db_to_linear(-6.0)).abs() < 1e-6);
+            assert_eq!(build_state_view(&state).replaygain_tags.album_gain_db, Some(-6.0));
+            state.replaygain_mode = "track".to_string();
+            refresh_replaygain_gain(&mut state);
+            assert_eq!(state.replaygain_applied_mode, "track");
+        }
+
+        // A lone track from an album among singles keeps its track gain.
+        {
+            let mut state = shared.inner.lock().unwrap();
+            state.replaygain_mode = "auto".to_string();
+            state.file_path = Some("b1.flac".to_string());
+        }
+        queue_add_impl(
+            &shared,
+            vec![album_track("a1.flac", "A"), album_track("b1.flac", "B"), track("c.flac")],
+            true,
+        );
+        let state = shared.inner.lock().unwrap();
+        assert_eq!(state.replaygain_applied_mode, "track");
+        assert!((state.replaygain_gain - db_to_linear(-3.0)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn shuffled_neighbours_follow_the_play_order() {
+        let shared = create_shared_state();
+        shared.inner.lock().unwrap().replaygain = ReplayGainInfo {
+            track_gain_db: Some(-3.0),
+            track_peak: None,
+            album_gain_db: Some(-6.0),
+            album_peak: None,
+            output_gain_db: None,
+        };
+        queue_add_impl(
+            &shared,
+            vec![
+                album_track("a1.flac", "A"),
+                track("x.flac"),
+                album_track("a2.flac", "A"),
+                track("y.flac"),
+                track("z.flac"),
+            ],
+            true,
+        );
+        let mut state = shared.inner.lock().unwrap();
+        state.shuffle = true;
+        // a2 played straight after a1, though x sits between them.
+        state.shuffle_history = vec![0, 2];
+        state.queue_index = Some(2);
+        refresh_replaygain_gain(&mut state);
+        assert_eq!(state.replaygain_applied_mode, "album");
+        // After y this time, with only x and z left to draw: singles.
+        state.shuffle_history = vec![0, 3, 2];
+        refresh_replaygain_gain(&mut state);
+        assert_eq!(state.replaygain_applied_mode, "track");
+
+        // The gapless check looks at the track the shuffle will draw.
+        state.mode = "file".to_string();
+        let next = peek_next_queue_index(&state, true).unwrap();
+        drop(state);
+        let check = gapless_check_impl(&shared);
+        let mut state = shared.inner.lock().unwrap();
+        assert_eq!(check.next.as_deref(), Some(state.queue[next].path.as_str()));
+        assert_eq!(next_queue_index(&mut state, true), Some(next));
+    }
+
+    #[test]
+    fn gapless_check_wants_matching_format_and_album_order() {
+        let numbered = |path: &str, album: &str, disc: u32, number: u32, rate: u32| LibraryTrack {
+            track_number: Some(number),
+            disc_number: Some(disc),
+            sample_rate: Some(rate),
+            channels: Some(2),
+            ..album_track(path, album)
+        };
+        let check = |queue: Vec<LibraryTrack>| {
+            let shared = create_shared_state();
+            {
+                let mut state = shared.inner.lock().unwrap();
+                state.mode = "file".to_string();
+                state.file_path = Some(queue[0].path.clone());
+                state.source_sample_rate = 44_100;
+                state.source_channels = 2;
+            }
+            queue_add_impl(&shared, queue, true);
+            gapless_check_impl(&shared)
+        };
+
+        let ok = check(vec![numbered("a3.flac", "A", 1, 3, 44_100), numbered("a4.flac", "A", 1, 4, 44_100)]);
+        assert!(ok.gapless, "{:?}", ok.reason);
+        assert_eq!(ok.next.as_deref(), Some("a4.flac"));
+        assert!(check(vec![numbered("a9.flac", "A", 1, 9, 44_100), numbered("b1.flac", "A", 2, 1, 44_100)]).gapless);
+
+        let rate = check(vec![numbered("a3.flac", "A", 1, 3, 44_100), numbered("a4.flac", "A", 1, 4, 96_000)]);
+        assert_eq!(rate.reason.as_deref(), Some("sample rate changes from 44100 to 96000 Hz"));
+        let skipped = check(vec![numbered("a3.flac", "A", 1, 3, 44_100), numbered("a5.flac", "A", 1, 5, 44_100)]);
+        assert_eq!(skipped.reason.as_deref(), Some("next track is not the next one on the album"));
+        let other = check(vec![numbered("a3.flac", "A", 1, 3, 44_100), numbered("b4.flac", "B", 1, 4, 44_100)]);
+        assert_eq!(other.reason.as_deref(), Some("next track is from another album"));
+        let last = check(vec![numbered("a3.flac", "A", 1, 3, 44_100)]);
+        assert!(!last.gapless && last.next.is_none());
+
+        assert_eq!(parse_position_tag(" 3/12"), Some(3));
+        assert_eq!(parse_position_tag("0"), None);
+        assert_eq!(parse_year_tag("1997-03-14T10:00:00"), Some(1997));
+        assert_eq!(parse_year_tag(" 2004 "), Some(2004));
+        assert_eq!(parse_year_tag("97"), None);
+        assert_eq!(parse_year_tag("March 1997"), None);
+    }
+
+    #[test]
+    fn queue_add_clears_index_when_missing() {
+        let shared = create_shared_state();
+        {
+            let mut state = shared.inner.lock().unwrap();
+            state.file_path = Some("missing.flac".to_string());
+        }
+        let count = queue_add_impl(&shared, vec![track("a.flac")], true);
+        assert_eq!(count, 1);
+        let state = shared.inner.lock().unwrap();
+        assert_eq!(state.queue_index, None);
+    }
+}
+
+fn normalize_dither_type(value: &str) -> String {
+    match value.to_lowercase().as_str() {
+        "off" => "off".to_string(),
+        "tpdf" => "tpdf".to_string(),
+        "tpdf_ns1" => "tpdf_ns1".to_string(),
+        "tpdf_ns2" => "tpdf_ns2".to_string(),
+        _ => "tpdf".to_string(),
+    }
+}
+
+fn reset_dither_shape_state(state: &mut EngineState) {
+    state.dither_shape_err1.fill(0.0);
+    state.dither_shape_err2.fill(0.0);
+}
+
+fn get_sinc_params(quality: &str, ratio: f64) -> SincInterpolationParameters {
+    let f_cutoff = if ratio < 1.0 { 0.90 } else { 0.95 };
+    match quality {
+        "low" => SincInterpolationParameters {
+            sinc_len: 64,
+            f_cutoff,
+            interpolation: SincInterpolationType::Linear,
+            oversampling_factor: 64,
+            window: WindowFunction::Hann,
+        },
+        "std" => SincInterpolationParameters {
+            sinc_len: 128,
+            f_cutoff,
+            interpolation: SincInterpolationType::Linear,
+            oversampling_factor: 128,
+            window: WindowFunction::Blackman,
+        },
+        "hq" => SincInterpolationParameters {
+            sinc_len: 256,
+            f_cutoff,
+            interpolation: SincInterpolationType::Linear,
+            oversampling_factor: 256,
+            window: WindowFunction::BlackmanHarris2,
+        },
+        _ => SincInterpolationParameters {
+            sinc_len: 512,
+            f_cutoff,
+            interpolation: SincInterpolationType::Cubic,
+            oversampling_factor: 512,
+            window: WindowFunction::BlackmanHarris2,
+        },
+    }
+}
+
+fn is_cross_family(from_rate: u32, to_rate: u32) -> bool {
+    let family_44k = |rate: u32| rate.is_multiple_of(11_025);
+    let family_48k = |rate: u32| rate.is_multiple_of(8_000);
+    (family_44k(from_rate) && family_48k(to_rate)) || (family_48k(from_rate) && family_44k(to_rate))
+}
+
+// 44.1k <-> 48k family conversions use 147/160-style ratios whose images land
+// close to the passband; the short "low"/"std" kernels alias audibly there, so
+// they are promoted to the "hq" kernel regardless of the requested quality.
+fn effective_resampler_quality(quality: &str, from_rate: u32, to_rate: u32) -> String {
+    if matches!(quality, "low" | "std") && is_cross_family(from_rate, to_rate) {
+        "hq".to_string()
+    } else {
+        quality.to_string()
+    }
+}
+
+/// Exact length of a resampled buffer: input frames scaled by the ratio,
+/// rounded to the nearest frame. Both backends trim or pad to this.
+fn resampled_frame_count(frames: usize, from_rate: u32, to_rate: u32) -> usize {
+    if from_rate == 0 {
+        return frames;
+    }
+    let from = from_rate as u64;
+    ((frames as u64 * to_rate as u64 + from / 2) / from) as usize
+}
+
+/// Group delay of rubato's sinc resampler in output frames, mirroring
+/// `SincFixedIn::output_delay` (kernel length rounded up to a multiple of 8).
+fn sinc_output_delay(sinc_len: usize, ratio: f64) -> usize {
+    let sinc_len = 8 * sinc_len.div_ceil(8);
+    (sinc_len as f64 * ratio / 2.0) as usize
+}
+
+fn resampler_info(
+    backend: &str,
+    reason: &'static str,
+    quality: &str,
+    from_rate: u32,
+    to_rate: u32,
+    input_frames: usize,
+) -> ResamplerInfo {
+    let cross_family = is_cross_family(from_rate, to_rate);
+    let output_frames = resampled_frame_count(input_frames, from_rate, to_rate);
+    if backend != "rubato" {
+        // libsoxr compensates for its own filter delay.
+        return ResamplerInfo {
+            backend: backend.to_string(),
+            reason,
+            fallback: None,
+            from_rate,
+            to_rate,
+            requested_quality: quality.to_string(),
+            effective_quality: quality.to_string(),
+            cross_family,
+            sinc_len: None,
+            f_cutoff: None,
+            oversampling_factor: None,
+            input_frames,
+            output_frames,
+            delay_frames: 0,
+        };
+    }
+    let effective_quality = effective_resampler_quality(quality, from_rate, to_rate);
+    let ratio = to_rate as f64 / from_rate.max(1) as f64;
+    let params = get_sinc_params(&effective_quality, ratio);
+    ResamplerInfo {
+        backend: backend.to_string(),
+        reason,
+        fallback: None,
+        from_rate,
+        to_rate,
+        requested_quality: quality.to_string(),
+        effective_quality,
+        cross_family,
+        sinc_len: Some(params.sinc_len),
+        f_cutoff: Some(params.f_cutoff),
+        oversampling_factor: Some(params.oversampling_factor),
+        input_frames,
+        output_frames,
+        delay_frames: sinc_output_delay(params.sinc_len, ratio),
+    }
+}
+
+fn resample_audio(
+    data: &[f32],
+    channels: usize,
+    from_rate: u32,
+    to_rate: u32,
+    quality: &str,
+) -> Result<Vec<f32>> {
+    let ratio = to_rate as f64 / from_rate.max(1) as f64;
+    let effective_quality = effective_resampler_quality(quality, from_rate, to_rate);
+    if effective_quality != quality {
+        info!(
+            "resampling {} -> {} Hz: raising quality {} -> {}",
+            from_rate, to_rate, quality, effective_quality
+        );
+    }
+    let params = get_sinc_params(&effective_quality, ratio);
+    resample_audio_with_params(data, channels, from_rate, to_rate, params)
+}
+
+fn resample_audio_with_params(
+    data: &[f32],
+    channels: usize,
+    from_rate: u32,
+    to_rate: u32,
+    params: SincInterpolationParameters,
+) -> Result<Vec<f32>> {
+    if data.is_empty() || channels == 0 || from_rate == 0 || to_rate == 0 {
+        return Ok(data.to_vec());
+    }
+    if from_rate == to_rate {
+        return Ok(data.to_vec());
+    }
+
+    let frames = data.len() / channels;
+    if frames == 0 {
+        return Ok(Vec::new());
+    }
+
+    let ratio = to_rate as f64 / from_rate as f64;
+
+    let mut waves_in: Vec<Vec<f64>> = vec![Vec::with_capacity(frames); channels];
+    for frame in data.chunks_exact(channels) {
+        for (ch, &sample) in frame.iter().enumerate() {
+            waves_in[ch].push(sample as f64);
+        }
+    }
+
+    let mut resampler = SincFixedIn::new(ratio, 2.0, params, frames, channels)
+        .map_err(|err| anyhow!("resampler init failed: {}", err))?;
+    let out_frames = resampled_frame_count(frames, from_rate, to_rate);
+    let mut waves_out = resampler
+        .process(&waves_in, None)
+        .map_err(|err| anyhow!("resampler process failed: {}", err))?;
+    // Output starts time-aligned, but the filter delay (`output_delay()`
+    // frames) is held back at the end; push silence through to release it.
+    while waves_out.first().map_or(0, |v| v.len()) < out_frames {
+        let tail = resampler
+            .process_partial::<Vec<f64>>(None, None)
+            .map_err(|err| anyhow!("resampler flush failed: {}", err))?;
+        if tail.first().is_none_or(|v| v.is_empty()) {
+            break;
+        }
+        for (channel_data, more) in waves_out.iter_mut().zip(tail) {
+            channel_data.extend(more);
+        }
+    }
+
+    let mut output = vec![0.0f32; out_frames * channels];
+    for i in 0..out_frames {
+        for (ch, channel_data) in waves_out.iter().enumerate() {
+            if let Some(sample) = channel_data.get(i) {
+                output[i * channels + ch] = *sample as f32;
+            }
+        }
+    }
+
+    Ok(output)
+}
+
+fn resample_audio_soxr(
+    data: &[f32],
+    channels: usize,
+    from_rate: u32,
+    to_rate: u32,
+) -> Result<Vec<f32>> {
+    if data.is_empty() || channels == 0 || from_rate == 0 || to_rate == 0 {
+        return Ok(data.to_vec());
+    }
+    if from_rate == to_rate {
+        return Ok(data.to_vec());
+    }
+
+    let frames = data.len() / channels;
+    if frames == 0 {
+        return Ok(Vec::new());
+    }
+
+    let lib = soxr_library().ok_or_else(|| anyhow!("soxr library not available"))?;
+    let instance = SoxrInstance::new(lib, from_rate, to_rate, channels)?;
+    let ratio = to_rate as f64 / from_rate as f64;
+    let mut output = Vec::with_capacity(((frames as f64 * ratio).ceil() as usize + 256) * channels);
+
+    let mut offset = 0usize;
+    let chunk_frames = 8192usize;
+    while offset < frames {
+        let frames_in = (frames - offset).min(chunk_frames);
+        let in_start = offset * channels;
+        let in_end = in_start + frames_in * channels;
+        let in_slice = &data[in_start..in_end];
+
+        let out_capacity_frames = ((frames_in as f64 * ratio).ceil() as usize + 64).max(1);
+        let mut out_chunk = vec![0.0f32; out_capacity_frames * channels];
+        let mut idone = 0usize;
+        let mut odone = 0usize;
+        let err = unsafe {
+            (instance.lib.process)(
+                instance.handle,
+                in_slice.as_ptr() as *const c_void,
+                frames_in,
+                &mut idone,
+                out_chunk.as_mut_ptr() as *mut c_void,
+                out_capacity_frames,
+                &mut odone,
+            )
+        };
+        if !err.is_null() {
+            let message = unsafe { CStr::from_ptr(err).to_string_lossy().into_owned() };
+            return Err(anyhow!("soxr_process failed: {}", message));
+        }
+        if idone != frames_in {
+            return Err(anyhow!(
+                "soxr_process consumed {} of {} frames",
+                idone,
+                frames_in
+            ));
+        }
+        if odone > 0 {
+            output.extend_from_slice(&out_chunk[..odone * channels]);
+        }
+        offset += idone;
+    }
+
+    loop {
+        let out_capacity_frames = 1024usize;
+        let mut out_chunk = vec![0.0f32; out_capacity_frames * channels];
+        let mut idone = 0usize;
+        let mut odone = 0usize;
+        let err = unsafe {
+            (instance.lib.process)(
+                instance.handle,
+                std::ptr::null(),
+                0,
+                &mut idone,
+                out_chunk.as_mut_ptr() as *mut c_void,
+                out_capacity_frames,
+                &mut odone,
+            )
+        };
+        if !err.is_null() {
+            let message = unsafe { CStr::from_ptr(err).to_string_lossy().into_owned() };
+            return Err(anyhow!("soxr_process flush failed: {}", message));
+        }
+        if odone == 0 {
+            break;
+        }
+        output.extend_from_slice(&out_chunk[..odone * channels]);
+    }
+
+    // soxr's flushed length can differ from the exact ratio by a frame or two.
+    output.resize(resampled_frame_count(frames, from_rate, to_rate) * channels, 0.0);
+    Ok(output)
+}
+
+fn resample_for_output(shared: &SharedState, target_rate: u32) -> Result<()> {
+    let (mode, channels, sample_rate, resampler_mode, resampler_quality, soxr_available, data, position) = {
+        let mut state = shared.inner.lock().unwrap();
+        if state.mode != "file" || state.data.is_empty() {
+            return Ok(());
+        }
+        if state.sample_rate == target_rate || target_rate == 0 {
+            return Ok(());
+        }
+        if state.dsd_output == Some(DsdOutput::Dop) {
+            return Err(anyhow!("DoP needs the device at {} Hz", state.sample_rate));
+        }
+        (
+            state.mode.clone(),
+            state.channels,
+            state.sample_rate,
+            state.resampler_mode.clone(),
+            state.resampler_quality.clone(),
+            state.soxr_available,
+            std::mem::take(&mut state.data),
+            state.position,
+        )
+    };
+
+    if mode != "file" {
+        return Ok(());
+    }
+
+    let quality = normalize_resampler_quality(&resampler_quality);
+    let (preferred, reason) = resampler_choice(&resampler_mode, &quality, soxr_available);
+    let mut backend = "rubato";
+    let mut fallback = None;
+    let resampled = if preferred == "soxr" {
+        match resample_audio_soxr(&data, channels, sample_rate, target_rate) {
+            Ok(out) => {
+                backend = "soxr";
+                out
+            }
+            Err(err) => {
+                if resampler_mode == "auto" {
+                    error!("soxr resample failed, falling back to rubato: {}", err);
+                    fallback = Some(err.to_string());
+                    resample_audio(&data, channels, sample_rate, target_rate, &quality)?
+                } else {
+                    return Err(err);
+                }
+            }
+        }
+    } else {
+        resample_audio(&data, channels, sample_rate, target_rate, &quality)?
+    };
+    let mut info = resampler_info(backend, reason, &quality, sample_rate, target_rate, data.len() / channels.max(1));
+    info.fallback = fallback;
+
+    let duration = if target_rate > 0 && channels > 0 {
+        (resampled.len() / channels) as f64 / target_rate as f64
+    } else {
+        0.0
+    };
+    let scaled_pos = if sample_rate > 0 {
+        ((position as f64) * target_rate as f64 / sample_rate as f64) as usize
+    } else {
+        0
+    };
+
+    let mut state = shared.inner.lock().unwrap();
+    state.data = resampled;
+    state.sample_rate = target_rate;
+    state.resampler_info = Some(info);
+    state.duration = duration;
+    state.position = scaled_pos.min(state.data.len() / state.channels.max(1));
+    Ok(())
+}
+
+fn next_uniform(seed: &mut u64) -> f32 {
+    *seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+    let value = (*seed >> 32) as u32;
+    (value as f32) / (u32::MAX as f32)
+}
+
+fn apply_tpdf_dither(samples: &mut [f32], bits: u32, seed: &mut u64) {
+    let effective_bits = bits.clamp(8, 32);
+    let denom = 1u64 << effective_bits.saturating_sub(1);
+    let lsb = 1.0 / denom as f32;
+    for sample in samples.iter_mut() {
+        let noise = (next_uniform(seed) - next_uniform(seed)) * lsb;
+        *sample = (*sample + noise).clamp(-1.0, 1.0);
+    }
+}
+
+fn quantize_to_step(value: f32, step: f32) -> f32 {
+    if step <= 0.0 {
+        return value;
+    }
+    let scaled = (value / step).round();
+    (scaled * step).clamp(-1.0, 1.0)
+}
+
+/// `limit` is a linear ceiling already passed through
+/// [`normalize_limiter_threshold`].
+fn soft_limit_sample(sample: f32, limit: f32) -> f32 {
+    let abs = sample.abs();
+    if abs <= limit {
+        return sample;
+    }
+    let t = ((abs - limit) / (1.0 - limit)).min(1.0);
+    let shaped = limit + (1.0 - limit) * (t + t * t - t * t * t);
+    sample.signum() * shaped
+}
+
+fn apply_tpdf_dither_shaped(
+    samples: &mut [f32],
+    bits: u32,
+    channels: usize,
+    seed: &mut u64,
+    err1: &mut [f32; MAX_DITHER_CHANNELS],
+    err2: &mut [f32; MAX_DITHER_CHANNELS],
+    order: u8,
+) {
+    let effective_bits = bits.clamp(8, 32);
+    let denom = 1u64 << effective_bits.saturating_sub(1);
+    let lsb = 1.0 / denom as f32;
+    let channel_count = channels.max(1);
+    if channel_count > MAX_DITHER_CHANNELS || order == 0 {
+        apply_tpdf_dither(samples, effective_bits, seed);
+        return;
+    }
+    for (idx, sample) in samples.iter_mut().enumerate() {
+        let ch = idx % channel_count;
+        let feedback = if order == 1 {
+            DITHER_SHAPER_ORDER1_COEFF * err1[ch]
+        } else {
+            DITHER_SHAPER_ORDER2_COEFF1 * err1[ch] + DITHER_SHAPER_ORDER2_COEFF2 * err2[ch]
+        };
+        let shaped = *sample + feedback;
+        let noise = (next_uniform(seed) - next_uniform(seed)) * lsb;
+        let dithered = (shaped + noise).clamp(-1.0, 1.0);
+        let quantized = quantize_to_step(dithered, lsb);
+        let error = dithered - quantized;
+        err2[ch] = err1[ch];
+        err1[ch] = error;
+        *sample = quantized;
+    }
+}
+
+fn apply_dither_if_needed(state: &mut EngineState, data: &mut [f32], channels: usize, target_bits: u32) {
+    if !state.dither_enabled || state.dither_type == "off" {
+        return;
+    }
+    let effective_bits = normalize_dither_bits(state.dither_bits).min(target_bits);
+    let order = match state.dither_type.as_str() {
+        "tpdf_ns1" => 1,
+        "tpdf_ns2" => 2,
+        _ => 0,
+    };
+    if order == 0 {
+        apply_tpdf_dither(data, effective_bits, &mut state.dither_rng);
+    } else {
+        apply_tpdf_dither_shaped(
+            data,
+            effective_bits,
+            channels,
+            &mut state.dither_rng,
+            &mut state.dither_shape_err1,
+            &mut state.dither_shape_err2,
+            order,
+        );
+    }
+}
+
+/// DSP stages applied to every output buffer, in order. Dither has to stay
+/// last: anything after it would undo the decorrelation it adds before the
+/// integer conversion. New stages go into `PROCESSING_CHAIN` and
+/// `run_processing_chain`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ProcessingStage {
+    Polarity,
+    ReplayGain,
+    AutoLevel,
+    TrackGain,
+    Downmix,
+    Volume,
+    Eq,
+    Limiter,
+    TransportFade,
+    Dither,
+}
+
+impl ProcessingStage {
+    fn name(self) -> &'static str {
+        match self {
+            ProcessingStage::Polarity => "polarity",
+            ProcessingStage::ReplayGain => "replaygain",
+            ProcessingStage::AutoLevel => "auto_level",
+            ProcessingStage::TrackGain => "track_gain",
+            ProcessingStage::Downmix => "downmix",
+            ProcessingStage::Volume => "volume",
+            ProcessingStage::Eq => "eq",
+            ProcessingStage::Limiter => "limiter",
+            ProcessingStage::TransportFade => "transport_fade",
+            ProcessingStage::Dither => "dither",
+        }
+    }
+}
+
+const PROCESSING_CHAIN: [ProcessingStage; 10] = [
+    ProcessingStage::Polarity,
+    ProcessingStage::ReplayGain,
+    ProcessingStage::AutoLevel,
+    ProcessingStage::TrackGain,
+    ProcessingStage::Downmix,
+    ProcessingStage::Volume,
+    ProcessingStage::Eq,
+    ProcessingStage::Limiter,
+    ProcessingStage::TransportFade,
+    ProcessingStage::Dither,
+];
+
+/// Time for a full-scale (0 to 1) volume change; smaller steps take
+/// proportionally less.
+const VOLUME_RAMP_MS: f32 = 5.0;
+
+fn set_volume_target(state: &mut EngineState, volume: f32) {
+    state.volume = volume.clamp(0.0, 1.0);
+    // Nothing is sounding, so there is no step to smooth over.
+    if !state.is_playing || state.is_paused {
+        state.volume_current = state.volume;
+    }
+}
+
+fn apply_volume_ramp(state: &mut EngineState, data: &mut [f32], channels: usize) {
+    let target = state.volume;
+    let mut current = state.volume_current;
+    if current == target {
+        for sample in data.iter_mut() {
+            *sample *= target;
+        }
+        return;
+    }
+    let step = 1000.0 / (state.sample_rate.max(1) as f32 * VOLUME_RAMP_MS);
+    for frame in data.chunks_mut(channels.max(1)) {
+        current = if current < target {
+            (current + step).min(target)
+        } else {
+            (current - step).max(target)
+        };
+        for sample in frame.iter_mut() {
+            *sample *= current;
+        }
+    }
+    state.volume_current = current;
+}
+
+const DEFAULT_TRANSPORT_FADE_MS: u32 = 15;
+const TRANSPORT_FADE_MAX_MS: u32 = 200;
+/// How much longer than the fade itself `fade_out_transport` waits for the
+/// callback to get there; covers a callback period, or no callback at all.
+const TRANSPORT_FADE_SLACK_MS: u64 = 100;
+
+/// The frames playback wraps around in: an A/B loop, else the whole track
+/// under repeat-one. The data is already gapless-trimmed, so the whole
+/// track loops without the encoder's padding.
+fn file_loop_bounds(state: &EngineState) -> Option<(usize, usize)> {
+    if state.incremental.is_some() {
+        return None;
+    }
+    let total = state.data.len() / state.channels.max(1);
+    let (start, end) = match state.loop_region {
+        Some((start, end)) => (start, end.min(total)),
+        None if state.repeat_mode == "one" => (0, total),
+        None => return None,
+    };
+    (start < end).then_some((start, end))
+}
+
+/// Fill `out` from the file, carrying on from `start` whenever playback
+/// reaches `end`. Past `end` (a seek out of the loop) it plays on to the
+/// end of the file. Returns the samples filled.
+fn read_file_looped(state: &mut EngineState, out: &mut [f32], start: usize, end: usize) -> usize {
+    let channels = state.channels.max(1);
+    let total = state.data.len() / channels;
+    let mut filled = 0;
+    while filled < out.len() {
+        let bound = if state.position < end { end } else { total };
+        let frames = bound.saturating_sub(state.position).min((out.len() - filled) / channels);
+        let from = state.position * channels;
+        out[filled..filled + frames * channels].copy_from_slice(&state.data[from..from + frames * channels]);
+        filled += frames * channels;
+        state.position += frames;
+        if state.position == end {
+            state.position = start;
+        } else if frames == 0 {
+            break;
+        }
+    }
+    filled
+}
+
+fn transport_sounding(state: &EngineState) -> bool {
+    state.is_playing && !state.is_paused && state.transport_gain > 0.0
+}
+
+/// Ramp the callback's output towards silence while a transport change
+/// waits on it, and back to full level otherwise.
+fn apply_transport_fade(state: &mut EngineState, data: &mut [f32], channels: usize) {
+    let target = if state.transport_fading_out { 0.0 } else { 1.0 };
+    let mut gain = state.transport_gain;
+    if gain == target {
+        if target == 0.0 {
+            data.fill(0.0);
+        }
+        return;
+    }
+    let fade_frames = state.transport_fade_ms as f32 * state.sample_rate as f32 / 1000.0;
+    let step = if fade_frames > 1.0 { 1.0 / fade_frames } else { 1.0 };
+    for frame in data.chunks_mut(channels.max(1)) {
+        gain = if gain < target {
+            (gain + step).min(target)
+        } else {
+            (gain - step).max(target)
+        };
+        for sample in frame.iter_mut() {
+            *sample *= gain;
+        }
+    }
+    state.transport_gain = gain;
+}
+
+const AUTO_LEVEL_DEFAULT_TARGET_DB: f32 = -18.0;
+const AUTO_LEVEL_MIN_TARGET_DB: f32 = -40.0;
+const AUTO_LEVEL_MAX_TARGET_DB: f32 = -6.0;
+const AUTO_LEVEL_DEFAULT_MAX_GAIN_DB: f32 = 9.0;
+const AUTO_LEVEL_MAX_GAIN_LIMIT_DB: f32 = 24.0;
+/// Loud material is turned down by at most this much.
+const AUTO_LEVEL_MAX_CUT_DB: f32 = -12.0;
+/// Below this RMS the gain holds, so fade-outs and gaps aren't pulled up.
+const AUTO_LEVEL_GATE_DB: f32 = -50.0;
+/// Time constants of the loudness detector and of the gain falling and
+/// rising. They are long enough that the gain follows a song's overall
+/// level rather than its dynamics, which is what keeps it from pumping.
+const AUTO_LEVEL_DETECTOR_SECS: f32 = 3.0;
+const AUTO_LEVEL_ATTACK_SECS: f32 = 2.0;
+const AUTO_LEVEL_RELEASE_SECS: f32 = 8.0;
+
+/// Tagged ReplayGain is the better measurement, so auto level only acts on
+/// files without it and eases back to unity while it is in use.
+fn auto_level_active(state: &EngineState) -> bool {
+    let tagged = state.replaygain.track_gain_db.is_some() || state.replaygain.album_gain_db.is_some();
+    state.auto_level_enabled && !(state.replaygain_enabled && tagged)
+}
+
+/// Start from unity gain with the detector already at the target level.
+fn reset_auto_level(state: &mut EngineState) {
+    state.auto_level_gain = 1.0;
+    state.auto_level_power = (db_to_linear(state.auto_level_target_db) as f64).powi(2);
+}
+
+fn one_pole_coeff(secs: f32, sample_rate: u32) -> f64 {
+    1.0 - (-1.0 / (secs as f64 * sample_rate.max(1) as f64)).exp()
+}
+
+/// Steer a slowly smoothed gain so the running RMS sits at
+/// `auto_level_target_db`, boosting by at most `auto_level_max_gain_db`.
+/// The state carries over between tracks, which is what evens out a
+/// playlist. The smoothing runs in f64: per-sample steps this slow are
+/// below f32 resolution near unity and the gain would stall short.
+fn apply_auto_level(state: &mut EngineState, data: &mut [f32], channels: usize) {
+    let detector = one_pole_coeff(AUTO_LEVEL_DETECTOR_SECS, state.sample_rate);
+    let attack = one_pole_coeff(AUTO_LEVEL_ATTACK_SECS, state.sample_rate);
+    let release = one_pole_coeff(AUTO_LEVEL_RELEASE_SECS, state.sample_rate);
+    let active = auto_level_active(state);
+    let gate = (db_to_linear(AUTO_LEVEL_GATE_DB) as f64).powi(2);
+    let target = (db_to_linear(state.auto_level_target_db) as f64).powi(2);
+    let min_gain = db_to_linear(AUTO_LEVEL_MAX_CUT_DB) as f64;
+    let max_gain = db_to_linear(state.auto_level_max_gain_db) as f64;
+    let mut power = state.auto_level_power;
+    let mut gain = state.auto_level_gain;
+    for frame in data.chunks_mut(channels.max(1)) {
+        let frame_power = frame.iter().map(|s| (s * s) as f64).sum::<f64>() / frame.len() as f64;
+        power += (frame_power - power) * detector;
+        let desired = if !active {
+            1.0
+        } else if power > gate {
+            (target / power).sqrt().clamp(min_gain, max_gain)
+        } else {
+            gain
+        };
+        gain += (desired - gain) * if desired < gain { attack } else { release };
+        for sample in frame.iter_mut() {
+            *sample *= gain as f32;
+        }
+    }
+    state.auto_level_power = power;
+    state.auto_level_gain = gain;
+}
+
+/// Coefficients follow `eq_bands` and the output rate; history is kept.
+/// FIR kernels come later, from `design_eq_filters`.
+fn refresh_eq_filters(state: &mut EngineState) {
+    let sample_rate = state.sample_rate;
+    state.eq_filters.configure(&state.eq_bands, sample_rate);
+}
+
+/// Design whatever FIR kernels the EQ is missing, for the current rate
+/// first, without holding the state lock while each one is built. Gains
+/// that change meanwhile make `add_design` drop the result.
+fn design_eq_filters(shared: &SharedState) {
+    loop {
+        let Some(request) = shared.inner.lock().unwrap().eq_filters.missing_design() else {
+            return;
+        };
+        let design = request.design();
+        shared.inner.lock().unwrap().eq_filters.add_design(design);
+    }
+}
+
+/// Runs on output channels, after any upmix, so channel indices match the
+/// device's.
+fn apply_polarity(state: &EngineState, data: &mut [f32], channels: usize) {
+    if state.invert_polarity {
+        for sample in data.iter_mut() {
+            *sample = -*sample;
+        }
+        return;
+    }
+    if state.invert_channels.is_empty() {
+        return;
+    }
+    for frame in data.chunks_mut(channels.max(1)) {
+        for ch in &state.invert_channels {
+            if let Some(sample) = frame.get_mut(*ch) {
+                *sample = -*sample;
+            }
+        }
+    }
+}
+
+fn normalize_invert_channels(mut channels: Vec<usize>) -> Vec<usize> {
+    channels.sort_unstable();
+    channels.dedup();
+    channels.retain(|ch| *ch < MAX_POLARITY_CHANNELS);
+    channels
+}
+
+const DOWNMIX_MODES: [&str; 4] = ["none", "mono", "left", "right"];
+
+/// Fold each output frame to a single signal on every channel: "mono" is
+/// the average of the two sides of a stereo fold-down, "left" and "right"
+/// keep one side. Wider frames fold down like `downmix_to_stereo` in WAVE
+/// order, so a 5.1 or 7.1 centre and surrounds come in at -3 dB and LFE is
+/// dropped. Only the first `source_channels` take part; channels added by
+/// the upmix are silent and would only lower the level.
+fn apply_downmix(mode: &str, data: &mut [f32], channels: usize, source_channels: usize) {
+    let pick: fn(f32, f32) -> f32 = match mode {
+        "mono" => |left, right| (left + right) * 0.5,
+        "left" => |left, _| left,
+        "right" => |_, right| right,
+        _ => return,
+    };
+    let channels = channels.max(1);
+    let width = source_channels.clamp(1, channels);
+    let (left_sum, right_sum) = (0..width)
+        .map(wave_order_downmix_gains)
+        .fold((0.0f32, 0.0f32), |(l, r), (gl, gr)| (l + gl, r + gr));
+    let norm = 1.0 / left_sum.max(right_sum).max(1.0);
+    for frame in data.chunks_exact_mut(channels) {
+        let folded = if width == 1 {
+            frame[0]
+        } else {
+            let (mut left, mut right) = (0.0f32, 0.0f32);
+            for (idx, sample) in frame[..width].iter().enumerate() {
+                let (gl, gr) = wave_order_downmix_gains(idx);
+                left += sample * gl;
+                right += sample * gr;
+            }
+            pick(left * norm, right * norm)
+        };
+        frame.fill(folded);
+    }
+}
+
+/// `output_bits` is the integer width of the device format; float outputs
+/// pass `None` and skip dithering.
+fn run_processing_chain(
+    state: &mut EngineState,
+    data: &mut [f32],
+    channels: usize,
+    output_bits: Option<u32>,
+) {
+    for stage in PROCESSING_CHAIN {
+        match stage {
+            ProcessingStage::Polarity => apply_polarity(state, data, channels),
+            ProcessingStage::ReplayGain => {
+                if state.replaygain_enabled && state.replaygain_gain != 1.0 {
+                    let gain = state.replaygain_gain;
+                    for sample in data.iter_mut() {
+                        *sample *= gain;
+                    }
+                }
+            }
+            ProcessingStage::AutoLevel => {
+                if state.auto_level_enabled {
+                    apply_auto_level(state, data, channels);
+                }
+            }
+            ProcessingStage::TrackGain => {
+                if state.track_gain_db != 0.0 {
+                    let gain = db_to_linear(state.track_gain_db);
+                    for sample in data.iter_mut() {
+                        *sample *= gain;
+                    }
+                }
+            }
+            ProcessingStage::Downmix => apply_downmix(&state.downmix, data, channels, state.channels),
+            ProcessingStage::Volume => {
+                if state.hardware_volume_active {
+                    // The device scales; keep the ramp settled for when
+                    // software volume takes over again.
+                    state.volume_current = state.volume;
+                } else {
+                    apply_volume_ramp(state, data, channels);
+                }
+            }
+            ProcessingStage::Eq => {
+                if state.eq_enabled {
+                    if state.eq_filters.sample_rate() != state.sample_rate {
+                        let sample_rate = state.sample_rate;
+                        state.eq_filters.follow_rate(sample_rate);
+                    }
+                    if !state.eq_filters.is_flat() {
+                        state.eq_filters.process(data, channels);
+                    }
+                }
+            }
+            ProcessingStage::Limiter => {
+                if state.limiter_enabled {
+                    let threshold = state.limiter_threshold;
+                    for sample in data.iter_mut() {
+                        *sample = soft_limit_sample(*sample, threshold);
+                    }
+                }
+            }
+            ProcessingStage::TransportFade => apply_transport_fade(state, data, channels),
+            ProcessingStage::Dither => {
+                if let Some(bits) = output_bits {
+                    apply_dither_if_needed(state, data, channels, bits);
+                }
+            }
+        }
+    }
+}
+
+/// Significant bits in a 32-bit integer output sample. DACs that take
+/// 32-bit containers resolve 24 bits at most, so that is what gets dithered
+/// to and kept.
+const INT32_OUTPUT_BITS: u32 = 24;
+
+/// Bits `fill_output_buffer` dithers to for an output sample format; `None`
+/// for float.
+fn output_dither_bits(format: cpal::SampleFormat) -> Option<u32> {
+    match format {
+        cpal::SampleFormat::F32 | cpal::SampleFormat::F64 => None,
+        cpal::SampleFormat::I32 | cpal::SampleFormat::U32 | cpal::SampleFormat::I64 | cpal::SampleFormat::U64 => {
+            Some(INT32_OUTPUT_BITS)
+        }
+        other => Some(other.sample_size() as u32 * 8),
+    }
+}
+
+/// Full-scale f32 to a 32-bit container carrying `bits` significant bits,
+/// rounded at that depth so the padding bits below stay zero.
+fn f32_to_i32_sample(sample: f32, bits: u32) -> i32 {
+    let bits = bits.clamp(8, 32);
+    let scale = (1u64 << (bits - 1)) as f64;
+    let value = (sample as f64 * scale).round().clamp(-scale, scale - 1.0) as i64;
+    (value << (32 - bits)) as i32
+}
+
+fn ensure_output_stream(shared: &SharedState) -> Result<()> {
+    let result = open_output_stream(shared);
+    refresh_live_resampler(shared);
+    result
+}
+
+/// Build or drop the live resampler to match the source and device rates.
+/// Whole files are resampled when they load, so this only applies to stream and
+/// capture sources, whose rate is whatever ffmpeg or the capture endpoint
+/// delivers, and to files decoding as they play. The filter is built outside
+/// the state lock; until it is in place the callback plays silence rather
+/// than audio at the wrong speed.
+fn refresh_live_resampler(shared: &SharedState) {
+    let (from_rate, to_rate, channels, quality) = {
+        let mut state = shared.inner.lock().unwrap();
+        let live = match state.mode.as_str() {
+            "stream" | "capture" => true,
+            "file" => state.incremental.is_some(),
+            _ => false,
+        };
+        let device_rate = state.output_config.as_ref().map_or(0, |config| config.sample_rate);
+        if !live || device_rate == 0 || state.sample_rate == 0 || device_rate == state.sample_rate {
+            state.live_resampler.0 = None;
+            return;
+        }
+        let quality = normalize_resampler_quality(&state.resampler_quality);
+        let channels = state.channels.max(1);
+        if let Some(live) = &state.live_resampler.0 {
+            if live.converts(state.sample_rate, device_rate, channels) && live.quality() == quality {
+                return;
+            }
+        }
+        (state.sample_rate, device_rate, channels, quality)
+    };
+    match LiveResampler::new(from_rate, to_rate, channels, &quality) {
+        Ok(live) => {
+            let mut state = shared.inner.lock().unwrap();
+            // The source or device may have moved on while the filter was built.
+            let device_rate = state.output_config.as_ref().map_or(0, |config| config.sample_rate);
+            if state.sample_rate == from_rate && device_rate == to_rate && state.channels.max(1) == channels {
+                info!("resampling live audio {} -> {} Hz ({})", from_rate, to_rate, quality);
+                state.live_resampler.0 = Some(Box::new(live));
+            }
+        }
+        Err(err) => error!("live resampler failed: {}", err),
+    }
+}
+
+fn open_output_stream(shared: &SharedState) -> Result<()> {
+    let state_snapshot = shared.inner.lock().unwrap().clone();
+    if state_snapshot.exclusive_mode {
+        let hostapi = state_snapshot
+            .device_id
+            .and_then(device_hostapi_by_id)
+            .unwrap_or_else(|| format!("{:?}", output_host(state_snapshot.hostapi.as_deref()).id()));
+        if hostapi == "Wasapi" && cfg!(target_os = "windows") {
+            if shared.exclusive_stream.lock().unwrap().is_some() {
+                return Ok(());
+            }
+            shared.output_stream.lock().unwrap().0 = None;
+            let ordinal = state_snapshot
+                .device_id
+                .and_then(wasapi_device_ordinal_by_id);
+            match start_wasapi_exclusive_stream(shared, ordinal) {
+                Ok(handle) => {
+                    *shared.exclusive_stream.lock().unwrap() = Some(handle);
+                    let mut state = shared.inner.lock().unwrap();
+                    state.device_released = false;
+                    state.idle_since = None;
+                    return Ok(());
+                }
+                Err(err) => {
+                    error!("wasapi exclusive start failed: {}", err);
+                    let mut state = shared.inner.lock().unwrap();
+                    state.exclusive_mode = false;
+                }
+            }
+        }
+    } else if shared.exclusive_stream.lock().unwrap().is_some() {
+        stop_exclusive_stream(shared);
+    }
+
+    let mut guard = shared.output_stream.lock().unwrap();
+    if guard.0.is_some() {
+        return Ok(());
+    }
+
+    let host = output_host(state_snapshot.hostapi.as_deref());
+    let device = if let Some(id) = output_device_id(state_snapshot.device_id, state_snapshot.hostapi.as_deref()) {
+        find_device_by_id(id)
+            .or_else(|| host.default_output_device())
+            .ok_or_else(|| anyhow!("no output device"))?
+    } else {
+        host.default_output_device().ok_or_else(|| anyhow!("no output device"))?
+    };
+
+    let default_config = device.default_output_config()?;
+    let mut sample_format = default_config.sample_format();
+    let mut config = default_config.config();
+    let target_rate = state_snapshot
+        .target_samplerate
+        .unwrap_or(state_snapshot.sample_rate)
+        .max(8000);
+    let source_channels = state_snapshot.channels.max(1) as u16;
+    let target_channels = source_channels.max(default_config.channels());
+    if target_channels != source_channels {
+        info!(
+            "device has {} channels, upmixing {}-channel source",
+            target_channels, source_channels
+        );
+    }
+    config.channels = target_channels;
+
+    let mut matched = None;
+    if let Ok(supported) = device.supported_output_configs() {
+        for range in supported {
+            if range.channels() != target_channels {
+                continue;
+            }
+            let min_rate = range.min_sample_rate().0;
+            let max_rate = range.max_sample_rate().0;
+            if target_rate >= min_rate && target_rate <= max_rate {
+                matched = Some(range.with_sample_rate(cpal::SampleRate(target_rate)));
+                break;
+            }
+        }
+    }
+
+    let supported_buffer = matched
+        .as_ref()
+        .map(|cfg| *cfg.buffer_size())
+        .unwrap_or(*default_config.buffer_size());
+    if let Some(cfg) = matched {
+        sample_format = cfg.sample_format();
+        config = cfg.config();
+    } else {
+        let fallback_rate = default_config.sample_rate().0.max(8000);
+        if fallback_rate != target_rate {
+            if let Err(err) = resample_for_output(shared, fallback_rate) {
+                error!("resample for output failed: {}", err);
+            }
+        }
+        config.sample_rate = cpal::SampleRate(fallback_rate);
+    }
+    config.buffer_size = output_buffer_size(
+        state_snapshot.output_latency_ms,
+        config.sample_rate.0,
+        &supported_buffer,
+    );
+
+    let state = shared.inner.clone();
+    let consumer = shared.consumer.clone();
+    let control_shared = shared.control_shared.clone();
+    let output_scratch = shared.output_scratch.clone();
+
+    let output_lost = shared.output_lost.clone();
+    let devices_changed = shared.devices_changed.clone();
+    let err_fn = move |err| {
+        error!("stream error: {}", err);
+        if matches!(err, cpal::StreamError::DeviceNotAvailable) {
+            output_lost.store(true, Ordering::Release);
+            devices_changed.notify_one();
+        }
+    };
+
+    let stream = match sample_format {
+        cpal::SampleFormat::F32 => device.build_output_stream(
+            &config,
+            move |data: &mut [f32], _| {
+                fill_output_buffer(&state, &consumer, &control_shared, data, None);
+            },
+            err_fn,
+            None,
+        )?,
+        cpal::SampleFormat::I16 => device.build_output_stream(
+            &config,
+            move |data: &mut [i16], _| {
+                let mut scratch = lock_for_callback(&output_scratch).0;
+                if scratch.len() != data.len() {
+                    scratch.resize(data.len(), 0.0);
+                }
+                fill_output_buffer(&state, &consumer, &control_shared, &mut scratch, Some(16));
+                for (dst, src) in data.iter_mut().zip(scratch.iter()) {
+                    *dst = cpal::Sample::from_sample(*src);
+                }
+            },
+            err_fn,
+            None,
+        )?,
+        cpal::SampleFormat::U16 => device.build_output_stream(
+            &config,
+            move |data: &mut [u16], _| {
+                let mut scratch = lock_for_callback(&output_scratch).0;
+                if scratch.len() != data.len() {
+                    scratch.resize(data.len(), 0.0);
+                }
+                fill_output_buffer(&state, &consumer, &control_shared, &mut scratch, Some(16));
+                for (dst, src) in data.iter_mut().zip(scratch.iter()) {
+                    *dst = cpal::Sample::from_sample(*src);
+                }
+            },
+            err_fn,
+            None,
+        )?,
+        cpal::SampleFormat::I32 => device.build_output_stream(
+            &config,
+            move |data: &mut [i32], _| {
+                let mut scratch = lock_for_callback(&output_scratch).0;
+                if scratch.len() != data.len() {
+                    scratch.resize(data.len(), 0.0);
+                }
+                fill_output_buffer(&state, &consumer, &control_shared, &mut scratch, Some(INT32_OUTPUT_BITS));
+                for (dst, src) in data.iter_mut().zip(scratch.iter()) {
+                    *dst = f32_to_i32_sample(*src, INT32_OUTPUT_BITS);
+                }
+            },
+            err_fn,
+            None,
+        )?,
+        _ => return Err(anyhow!("unsupported sample format")),
+    };
+
+    stream.play()?;
+    guard.0 = Some(stream);
+    let mut state = shared.inner.lock().unwrap();
+    state.device_released = false;
+    state.idle_since = None;
+    state.output_channels = config.channels as usize;
+    state.output_config = Some(OutputConfigInfo {
+        backend: state_snapshot
+            .device_id
+            .and_then(device_hostapi_by_id)
+            .unwrap_or_else(|| format!("{:?}", host.id())),
+        sample_rate: config.sample_rate.0,
+        channels: config.channels,
+        sample_format: sample_format.to_string(),
+        bit_depth: output_dither_bits(sample_format).unwrap_or(sample_format.sample_size() as u32 * 8),
+        buffer_frames: match config.buffer_size {
+            cpal::BufferSize::Fixed(frames) => Some(frames),
+            cpal::BufferSize::Default => None,
+        },
+    });
+    Ok(())
+}
+
+fn output_buffer_size(
+    latency_ms: Option<u32>,
+    sample_rate: u32,
+    supported: &cpal::SupportedBufferSize,
+) -> cpal::BufferSize {
+    let Some(latency_ms) = latency_ms.filter(|ms| *ms > 0) else {
+        return cpal::BufferSize::Default;
+    };
+    let frames = ((latency_ms as u64 * sample_rate as u64) / 1000).max(1) as u32;
+    match supported {
+        cpal::SupportedBufferSize::Range { min, max } if frames >= *min && frames <= *max => {
+            cpal::BufferSize::Fixed(frames)
+        }
+        _ => {
+            info!(
+                "requested output latency {} ms ({} frames) unsupported, using device default",
+                latency_ms, frames
+            );
+            cpal::BufferSize::Default
+        }
+    }
+}
+
+static CALLBACK_POISON_LOGGED: AtomicBool = AtomicBool::new(false);
+
+/// Lock a mutex the output callback needs. A thread that panicked while
+/// holding it must not take the audio thread down too, so poisoning is
+/// cleared (logged the first time) and reported as `true`.
+fn lock_for_callback<T>(mutex: &Mutex<T>) -> (MutexGuard<'_, T>, bool) {
+    match mutex.lock() {
+        Ok(guard) => (guard, false),
+        Err(poisoned) => {
+            if !CALLBACK_POISON_LOGGED.swap(true, Ordering::Relaxed) {
+                error!("output callback recovered a lock poisoned by a panicking thread");
+            }
+            mutex.clear_poison();
+            (poisoned.into_inner(), true)
+        }
+    }
+}
+
+fn fill_output_buffer(
+    state: &Arc<Mutex<EngineState>>,
+    consumer: &Arc<Mutex<HeapCons<f32>>>,
+    control_shared: &Option<Arc<Mutex<ControlShared>>>,
+    data: &mut [f32],
+    output_bits: Option<u32>,
+) {
+    let frames = data.len();
+    let (mut local, poisoned) = lock_for_callback(state);
+    if poisoned {
+        // Whatever panicked may have left the state half-updated; play one
+        // buffer of silence and pick up normally from the next callback.
+        data.fill(0.0);
+        return;
+    }
+    if let Some(control) = control_shared {
+        let guard = match control.try_lock() {
+            Ok(guard) => Some(guard),
+            Err(TryLockError::Poisoned(poisoned)) => {
+                control.clear_poison();
+                Some(poisoned.into_inner())
+            }
+            Err(TryLockError::WouldBlock) => None,
+        };
+        if let Some(guard) = guard {
+            drain_control_commands(&mut local, &guard);
+        }
+    }
+    if !local.is_playing || local.is_paused {
+        for sample in data.iter_mut() {
+            *sample = 0.0;
+        }
+        local.transport_gain = 0.0;
+        let live = matches!(local.mode.as_str(), "stream" | "capture");
+        if local.is_paused && live && local.live_pause_mode == "drop" {
+            // Keep pace with the live source so resuming doesn't start
+            // seconds behind it.
+            lock_for_callback(consumer).0.clear();
+            local.buffered_frames = 0;
+        }
+        return;
+    }
+
+    // Sources with fewer channels than the device are read at their own
+    // width into the front of `data` and widened in place afterwards.
+    let source_channels = local.channels.max(1);
+    let output_channels = local.output_channels.max(source_channels);
+    let frame_count = frames / output_channels;
+    let source_len = frame_count * source_channels;
+    match local.mode.as_str() {
+        "file" if local.incremental.is_some() => {
+            // Inter-track silence plays before the track's first frame.
+            let gap = local.gap_frames.min(frame_count);
+            local.gap_frames -= gap;
+            let gap_len = gap * source_channels;
+            data[..gap_len].fill(0.0);
+            let (seeking, finished) = local
+                .incremental
+                .map_or((false, true), |incremental| (incremental.seek.is_some(), incremental.finished));
+            // Nothing is read while a seek waits for the decoder to clear the
+            // ring, or the old position would play on.
+            let (consumed, missing) = if seeking {
+                data[gap_len..source_len].fill(0.0);
+                (0, 0)
+            } else {
+                read_ring(&mut local, consumer, &mut data[gap_len..source_len])
+            };
+            data[source_len..].fill(0.0);
+            local.buffered_frames = local.buffered_frames.saturating_sub(consumed);
+            local.position += consumed;
+            if missing > 0 && finished {
+                local.is_playing = false;
+                local.track_finished = true;
+            } else if missing > 0 {
+                local.underrun_count = local.underrun_count.saturating_add(1);
+            }
+            let gain = crossfade_gain(&local);
+            if let Some(fade) = local.crossfade.as_mut() {
+                mix_crossfade(fade, &mut data[..source_len], source_channels, gain);
+            }
+        }
+        "file" => {
+            // With a crossfade set, a staged next track comes in while this
+            // one still has that long to go.
+            let looping = file_loop_bounds(&local);
+            let remaining = (local.data.len() / source_channels).saturating_sub(local.position);
+            let fade_frames = crossfade_frames(&local);
+            if looping.is_none()
+                && fade_frames > 0
+                && remaining > 0
+                && remaining <= fade_frames
+                && local.crossfade.is_none()
+            {
+                splice_staged_track(&mut local, remaining);
+            }
+            // Inter-track silence plays before the track's first frame.
+            let gap = local.gap_frames.min(frame_count);
+            local.gap_frames -= gap;
+            let gap_len = gap * source_channels;
+            data[..gap_len].fill(0.0);
+            let mut filled = gap_len;
+            if let Some((loop_start, loop_end)) = looping {
+                filled += read_file_looped(&mut local, &mut data[gap_len..source_len], loop_start, loop_end);
+            } else {
+                let read_len = source_len - gap_len;
+                let start = local.position * source_channels;
+                let end = (start + read_len).min(local.data.len());
+                let available = end.saturating_sub(start);
+                data[gap_len..gap_len + available].copy_from_slice(&local.data[start..start + available]);
+                local.position += frame_count - gap;
+                filled += available;
+            }
+            // A staged next track carries on from the very next frame.
+            if filled < source_len && looping.is_none() && splice_staged_track(&mut local, 0) {
+                let spliced = (source_len - filled).min(local.data.len());
+                data[filled..filled + spliced].copy_from_slice(&local.data[..spliced]);
+                local.position = spliced / source_channels;
+                filled += spliced;
+            }
+            if filled < source_len {
+                local.is_playing = false;
+                local.track_finished = true;
+            }
+            for sample in data[filled..].iter_mut() {
+                *sample = 0.0;
+            }
+            let gain = crossfade_gain(&local);
+            if let Some(fade) = local.crossfade.as_mut() {
+                mix_crossfade(fade, &mut data[..source_len], source_channels, gain);
+            }
+        }
+        "stream" | "capture" => {
+            let (consumed, missing) = read_ring(&mut local, consumer, &mut data[..source_len]);
+            for sample in data[source_len..].iter_mut() {
+                *sample = 0.0;
+            }
+            local.buffered_frames = local.buffered_frames.saturating_sub(consumed);
+            if missing > 0 {
+                local.underrun_count = local.underrun_count.saturating_add(1);
+            }
+            // Source frames, so position stays in the source's time.
+            local.played_frames += (consumed + missing) as u64;
+        }
+        _ => {
+            for sample in data.iter_mut() {
+                *sample = 0.0;
+            }
+        }
+    }
+    if output_channels > source_channels {
+        upmix_in_place(data, source_channels, output_channels, frame_count);
+    }
+    if local.mode == "file" && local.dsd_output == Some(DsdOutput::Dop) {
+        // DoP has to reach the DAC bit for bit, so nothing processes it;
+        // where it wouldn't (not 24-bit integer, or not at the file's
+        // rate), silence beats marker noise. Nor can it be ramped, so a
+        // transport change cuts straight to silence instead of fading.
+        let device_rate = local.output_config.as_ref().map_or(0, |config| config.sample_rate);
+        local.transport_gain = if local.transport_fading_out { 0.0 } else { 1.0 };
+        if local.transport_fading_out || output_bits != Some(INT32_OUTPUT_BITS) || device_rate != local.sample_rate {
+            data.fill(0.0);
+        }
+        return;
+    }
+
+    run_processing_chain(&mut local, data, output_channels, output_bits);
+    let clipped = data.iter().filter(|sample| sample.abs() > 1.0).count() as u64;
+    local.clip_count = local.clip_count.saturating_add(clipped);
+    // The spectrum tap sees exactly what goes to the device (or, for an
+    // unmonitored capture, what would have).
+    push_spectrum_history(&mut local, data, output_channels);
+    if local.mode == "capture" && !local.capture_monitor {
+        data.fill(0.0);
+    }
+}
+
+/// Fill `out` from the ring buffer at the source's width, converting to the
+/// device rate where that differs. Returns the source frames read and the
+/// ones the ring didn't have.
+fn read_ring(state: &mut EngineState, consumer: &Mutex<HeapCons<f32>>, out: &mut [f32]) -> (usize, usize) {
+    let channels = state.channels.max(1);
+    let device_rate = state.output_config.as_ref().map_or(state.sample_rate, |config| config.sample_rate);
+    if device_rate == state.sample_rate {
+        let mut consumed = 0usize;
+        let mut cons = lock_for_callback(consumer).0;
+        for sample in out.iter_mut() {
+            if let Some(v) = cons.try_pop() {
+                *sample = v;
+                consumed += 1;
+            } else {
+                *sample = 0.0;
+            }
+        }
+        return (consumed / channels, (out.len() - consumed) / channels);
+    }
+    // Only pulls from the ring once the output it still holds runs out, so
+    // a callback it covers is no underrun.
+    let sample_rate = state.sample_rate;
+    let mut live = state.live_resampler.0.take();
+    let result = match live.as_mut() {
+        Some(resampler) if resampler.converts(sample_rate, device_rate, channels) => {
+            let mut cons = lock_for_callback(consumer).0;
+            resampler.fill(out, || cons.try_pop())
+        }
+        _ => {
+            out.fill(0.0);
+            FillResult::default()
+        }
+    };
+    state.live_resampler.0 = live;
+    (result.consumed, result.missing)
+}
+
+const SPECTRUM_SOURCES: [&str; 5] = ["mono", "left", "right", "mid", "side"];
+
+/// How `spectrum_source` turns one frame into a sample. Mono output has no
+/// separate right channel, so left, right and mid are that channel and side
+/// is silent.
+fn spectrum_source_fn(source: &str) -> fn(&[f32]) -> f32 {
+    fn right(frame: &[f32]) -> f32 {
+        frame.get(1).copied().unwrap_or(frame[0])
+    }
+    match source {
+        "left" => |frame| frame[0],
+        "right" => right,
+        "mid" => |frame| (frame[0] + right(frame)) * 0.5,
+        "side" => |frame| (frame[0] - right(frame)) * 0.5,
+        _ => |frame| frame.iter().sum::<f32>() / frame.len() as f32,
+    }
+}
+
+/// Copy a callback's output into the spectrum ring. A change of channel
+/// count clears the ring rather than mixing frames of two widths.
+fn push_spectrum_history(state: &mut EngineState, data: &[f32], channels: usize) {
+    let len = state.spectrum_history.len() / SPECTRUM_HISTORY_CHANNELS;
+    if len == 0 || channels == 0 {
+        return;
+    }
+    let kept = channels.min(SPECTRUM_HISTORY_CHANNELS);
+    if state.spectrum_history_channels != kept {
+        state.spectrum_history.fill(0.0);
+        state.spectrum_history_channels = kept;
+        state.spectrum_history_pos = 0;
+    }
+    let frames = data.len() / channels;
+    // Anything older than the ring holds would be overwritten anyway.
+    let first = frames.saturating_sub(len);
+    let mut pos = state.spectrum_history_pos % len;
+    for frame in data.chunks_exact(channels).skip(first) {
+        state.spectrum_history[pos * kept..(pos + 1) * kept].copy_from_slice(&frame[..kept]);
+        pos = (pos + 1) % len;
+    }
+    state.spectrum_history_pos = pos;
+    state.spectrum_history_written += frames as u64;
+}
+
+/// The ring's frame `back` frames before the newest one.
+fn spectrum_history_frame(state: &EngineState, back: usize) -> &[f32] {
+    let len = state.spectrum_history.len() / SPECTRUM_HISTORY_CHANNELS;
+    let channels = state.spectrum_history_channels;
+    let pos = (state.spectrum_history_pos + len - 1 - back % len) % len;
+    &state.spectrum_history[pos * channels..(pos + 1) * channels]
+}
+
+/// Fill `out` with the most recent frames of the spectrum ring, oldest
+/// first, each reduced to one sample by `spectrum_source`.
+fn latest_spectrum_window(state: &EngineState, out: &mut [f32]) {
+    let len = state.spectrum_history.len() / SPECTRUM_HISTORY_CHANNELS;
+    let take = out.len().min(len);
+    let (older, recent) = out.split_at_mut(out.len() - take);
+    older.fill(0.0);
+    let sample = spectrum_source_fn(&state.spectrum_source);
+    for (i, slot) in recent.iter_mut().enumerate() {
+        *slot = sample(spectrum_history_frame(state, take - 1 - i));
+    }
+}
+
+/// Per-channel levels for a VU-style display, linear full scale.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct LevelReading {
+    /// Jumps to a new peak, then falls at `LEVEL_METER_DECAY_DB_PER_SEC`.
+    peak: Vec<f32>,
+    /// The highest peak of the last `LEVEL_METER_HOLD_MS`.
+    hold: Vec<f32>,
+    rms: Vec<f32>,
+    /// Some sample since the last reading reached full scale.
+    clip: bool,
+}
+
+/// Reads the output the spectrum ring gained since the last call, once per
+/// `SPECTRUM_UPDATE_INTERVAL_MS`, and smooths the peaks so a display doesn't
+/// flicker.
+#[derive(Default)]
+struct LevelMeter {
+    read_up_to: u64,
+    peak: Vec<f32>,
+    hold: Vec<f32>,
+    hold_age_ms: Vec<u64>,
+}
+
+impl LevelMeter {
+    fn update(&mut self, state: &EngineState) -> LevelReading {
+        let channels = state.spectrum_history_channels;
+        let len = state.spectrum_history.len() / SPECTRUM_HISTORY_CHANNELS;
+        let fresh = state.spectrum_history_written.saturating_sub(self.read_up_to).min(len as u64) as usize;
+        self.read_up_to = state.spectrum_history_written;
+
+        let mut window_peak = vec![0.0f32; channels];
+        let mut sum_squares = vec![0.0f64; channels];
+        for back in 0..fresh {
+            for (ch, sample) in spectrum_history_frame(state, back).iter().enumerate() {
+                window_peak[ch] = window_peak[ch].max(sample.abs());
+                sum_squares[ch] += (*sample as f64) * (*sample as f64);
+            }
+        }
+        let rms = sum_squares
+            .iter()
+            .map(|sum| if fresh > 0 { (sum / fresh as f64).sqrt() as f32 } else { 0.0 })
+            .collect();
+        let clip = window_peak.iter().any(|peak| *peak >= 1.0);
+
+        if self.peak.len() != channels {
+            self.peak = vec![0.0; channels];
+            self.hold = vec![0.0; channels];
+            self.hold_age_ms = vec![0; channels];
+        }
+        let decay = db_to_linear(-LEVEL_METER_DECAY_DB_PER_SEC * SPECTRUM_UPDATE_INTERVAL_MS as f32 / 1000.0);
+        for (ch, &window_peak) in window_peak.iter().enumerate() {
+            self.peak[ch] = window_peak.max(self.peak[ch] * decay);
+            if window_peak >= self.hold[ch] {
+                self.hold[ch] = window_peak;
+                self.hold_age_ms[ch] = 0;
+            } else {
+                self.hold_age_ms[ch] += SPECTRUM_UPDATE_INTERVAL_MS;
+                if self.hold_age_ms[ch] > LEVEL_METER_HOLD_MS {
+                    self.hold[ch] = self.peak[ch];
+                    self.hold_age_ms[ch] = 0;
+                }
+            }
+        }
+        LevelReading {
+            peak: self.peak.clone(),
+            hold: self.hold.clone(),
+            rms,
+            clip,
+        }
+    }
+}
+
+/// Widen `frames` interleaved frames of `source_channels` at the front of
+/// `data` to `output_channels`. Mono is duplicated to L/R; otherwise channels
+/// map one-to-one and the extra outputs are silent.
+fn upmix_in_place(data: &mut [f32], source_channels: usize, output_channels: usize, frames: usize) {
+    // Walking backwards never overwrites a source sample before it is read,
+    // because each output frame starts at or after its source frame.
+    for frame in (0..frames).rev() {
+        let src = frame * source_channels;
+        let dst = frame * output_channels;
+        for ch in (0..output_channels).rev() {
+            data[dst + ch] = if ch < source_channels {
+                data[src + ch]
+            } else if source_channels == 1 && ch == 1 {
+                data[src]
+            } else {
+                0.0
+            };
+        }
+    }
+}
+
+fn enumerate_devices() -> Vec<DeviceInfo> {
+    let mut devices = Vec::new();
+    let mut index = 0usize;
+    for host_id in cpal::available_hosts() {
+        if let Ok(host) = cpal::host_from_id(host_id) {
+            if let Ok(outputs) = host.output_devices() {
+                for device in outputs {
+                    let name = device.name().unwrap_or_else(|_| "Unknown".to_string());
+                    let sample_rate = device
+                        .default_output_config()
+                        .map(|c| c.sample_rate().0)
+                        .unwrap_or(48_000);
+                    devices.push(DeviceInfo {
+                        id: index,
+                        name,
+                        hostapi: format!("{:?}", host_id),
+                        default_samplerate: sample_rate,
+                    });
+                    index += 1;
+                }
+            }
+        }
+    }
+    devices
+}
+
+fn list_devices() -> Value {
+    let mut wasapi = Vec::new();
+    let mut asio = Vec::new();
+    let mut other = Vec::new();
+    for info in enumerate_devices() {
+        match info.hostapi.as_str() {
+            "Wasapi" => wasapi.push(info),
+            "Asio" => asio.push(info),
+            _ => other.push(info),
+        }
+    }
+    json!({
+        "wasapi": wasapi,
+        "asio": asio,
+        "other": other
+    })
+}
+
+fn find_device_by_id(target: usize) -> Option<cpal::Device> {
+    let mut index = 0usize;
+    for host_id in cpal::available_hosts() {
+        if let Ok(host) = cpal::host_from_id(host_id) {
+            if let Ok(devices) = host.output_devices() {
+                for device in devices {
+                    if index == target {
+                        return Some(device);
+                    }
+                    index += 1;
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Match a host name from `/hostapis` against the hosts compiled in and
+/// available, ignoring case.
+fn find_host_id(name: &str) -> Option<cpal::HostId> {
+    cpal::available_hosts()
+        .into_iter()
+        .find(|id| format!("{:?}", id).eq_ignore_ascii_case(name.trim()))
+}
+
+/// The preferred host if it is available, otherwise cpal's default.
+fn output_host(preferred: Option<&str>) -> cpal::Host {
+    preferred
+        .and_then(find_host_id)
+        .and_then(|id| cpal::host_from_id(id).ok())
+        .unwrap_or_else(cpal::default_host)
+}
+
+/// The selected device, unless a host preference rules it out, in which
+/// case the preferred host's default device is used instead.
+fn output_device_id(device_id: Option<usize>, hostapi: Option<&str>) -> Option<usize> {
+    let id = device_id?;
+    match hostapi {
+        Some(preferred) => match device_hostapi_by_id(id) {
+            Some(host) if host.eq_ignore_ascii_case(preferred) => Some(id),
+            _ => {
+                info!("device {} is not on host {}, using that host's default", id, preferred);
+                None
+            }
+        },
+        None => Some(id),
+    }
+}
+
+fn list_hostapis(preferred: Option<&str>) -> Vec<Value> {
+    let default_id = cpal::default_host().id();
+    cpal::available_hosts()
+        .into_iter()
+        .map(|id| {
+            let name = format!("{:?}", id);
+            let devices = cpal::host_from_id(id)
+                .ok()
+                .and_then(|host| host.output_devices().ok())
+                .map(|devices| devices.count())
+                .unwrap_or(0);
+            json!({
+                "name": name,
+                "default": id == default_id,
+                "preferred": preferred.is_some_and(|p| p.eq_ignore_ascii_case(&name)),
+                "devices": devices,
+            })
+        })
+        .collect()
+}
+
+fn device_hostapi_by_id(target: usize) -> Option<String> {
+    let mut index = 0usize;
+    for host_id in cpal::available_hosts() {
+        if let Ok(host) = cpal::host_from_id(host_id) {
+            if let Ok(devices) = host.output_devices() {
+                for _device in devices {
+                    if index == target {
+                        return Some(format!("{:?}", host_id));
+                    }
+                    index += 1;
+                }
+            }
+        }
+    }
+    None
+}
+
+fn wasapi_device_ordinal_by_id(target: usize) -> Option<u32> {
+    let mut index = 0usize;
+    let mut ordinal = 0u32;
+    for host_id in cpal::available_hosts() {
+        if let Ok(host) = cpal::host_from_id(host_id) {
+            if let Ok(devices) = host.output_devices() {
+                for _device in devices {
+                    if index == target {
+                        if format!("{:?}", host_id) == "Wasapi" {
+                            return Some(ordinal);
+                        }
+                        return None;
+                    }
+                    if format!("{:?}", host_id) == "Wasapi" {
+                        ordinal += 1;
+                    }
+                    index += 1;
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Whether exclusive mode is possible on a device of `hostapi`, `None`
+/// being the default device.
+fn resolve_exclusive_mode(hostapi: Option<&str>, requested: bool) -> bool {
+    if !requested {
+        return false;
+    }
+    if let Some(hostapi) = hostapi {
+        if hostapi == "Asio" {
+            return true;
+        }
+        if hostapi == "Wasapi" {
+            return cfg!(target_os = "windows");
+        }
+        return false;
+    }
+    if cfg!(target_os = "windows") {
+        let hostapi = format!("{:?}", cpal::default_host().id());
+        return hostapi == "Asio" || hostapi == "Wasapi";
+    }
+    false
+}
+
+fn stop_exclusive_stream(shared: &SharedState) {
+    let mut guard = shared.exclusive_stream.lock().unwrap();
+    if let Some(mut handle) = guard.take() {
+        handle.stop();
+    }
+}
+
+#[cfg(target_os = "windows")]
+const WAVE_FORMAT_EXTENSIBLE_TAG: u16 = 0xFFFE;
+
+#[cfg(target_os = "windows")]
+fn channel_mask_for(channels: u16) -> u32 {
+    match channels {
+        1 => SPEAKER_FRONT_CENTER,
+        2 => SPEAKER_FRONT_LEFT | SPEAKER_FRONT_RIGHT,
+        6 => {
+            SPEAKER_FRONT_LEFT
+                | SPEAKER_FRONT_RIGHT
+                | SPEAKER_FRONT_CENTER
+                | SPEAKER_LOW_FREQUENCY
+                | SPEAKER_BACK_LEFT
+                | SPEAKER_BACK_RIGHT
+        }
+        8 => {
+            SPEAKER_FRONT_LEFT
+                | SPEAKER_FRONT_RIGHT
+                | SPEAKER_FRONT_CENTER
+                | SPEAKER_LOW_FREQUENCY
+                | SPEAKER_BACK_LEFT
+                | SPEAKER_BACK_RIGHT
+                | SPEAKER_SIDE_LEFT
+                | SPEAKER_SIDE_RIGHT
+        }
+        _ => 0,
+    }
+}
+
+#[cfg(target_os = "windows")]
+struct ComInit;
+
+#[cfg(target_os = "windows")]
+impl ComInit {
+    fn new() -> Result<Self> {
+        unsafe {
+            CoInitializeEx(None, COINIT_MULTITHREADED)
+                .ok()
+                .map_err(|err| anyhow!("CoInitializeEx failed: {}", err))?;
+        }
+        Ok(Self)
+    }
+}
+
+#[cfg(target_os = "windows")]
+impl Drop for ComInit {
+    fn drop(&mut self) {
+        unsafe {
+            CoUninitialize();
+        }
+    }
+}
+
+/// `int24` asks for 24-bit integer samples in 32-bit containers instead of
+/// 32-bit float.
+#[cfg(target_os = "windows")]
+fn build_wave_format(channels: u16, sample_rate: u32, int24: bool) -> WAVEFORMATEXTENSIBLE {
+    let mut format = WAVEFORMATEXTENSIBLE::default();
+    format.Format.wFormatTag = WAVE_FORMAT_EXTENSIBLE_TAG;
+    format.Format.nChannels = channels;
+    format.Format.nSamplesPerSec = sample_rate;
+    format.Format.wBitsPerSample = 32;
+    format.Format.nBlockAlign = channels.saturating_mul(4);
+    format.Format.nAvgBytesPerSec = sample_rate.saturating_mul(format.Format.nBlockAlign as u32);
+    format.Format.cbSize = (std::mem::size_of::<WAVEFORMATEXTENSIBLE>()
+        - std::mem::size_of::<WAVEFORMATEX>()) as u16;
+    format.Samples = WAVEFORMATEXTENSIBLE_0 {
+        wValidBitsPerSample: if int24 { INT32_OUTPUT_BITS as u16 } else { 32 },
+    };
+    format.dwChannelMask = channel_mask_for(channels);
+    format.SubFormat = if int24 {
+        KSDATAFORMAT_SUBTYPE_PCM
+    } else {
+        KSDATAFORMAT_SUBTYPE_IEEE_FLOAT
+    };
+    format
+}
+
+#[cfg(target_os = "windows")]
+fn select_wasapi_device(
+    enumerator: &IMMDeviceEnumerator,
+    device_ordinal: Option<u32>,
+) -> Result<IMMDevice> {
+    unsafe {
+        if let Some(ordinal) = device_ordinal {
+            let collection =
+                enumerator.EnumAudioEndpoints(eRender, DEVICE_STATE_ACTIVE)?;
+            let count = collection
+                .GetCount()
+                .map_err(|err| anyhow!("GetCount failed: {}", err))?;
+            if ordinal >= count {
+                return Err(anyhow!("WASAPI device ordinal out of range"));
+            }
+            let device = collection
+                .Item(ordinal)
+                .map_err(|err| anyhow!("EnumAudioEndpoints item failed: {}", err))?;
+            Ok(device)
+        } else {
+            enumerator
+                .GetDefaultAudioEndpoint(eRender, eConsole)
+                .map_err(|err| anyhow!("GetDefaultAudioEndpoint failed: {}", err))
+        }
+    }
+}
+
+/// The exclusive device's endpoint volume, for `volume_mode` "hardware".
+/// The level and mute it had are put back when software volume takes over
+/// or the stream closes, since they are shared with the rest of the system.
+#[cfg(target_os = "windows")]
+struct HardwareVolume {
+    endpoint: IAudioEndpointVolume,
+    saved_level: f32,
+    saved_mute: bool,
+    applied: Option<f32>,
+}
+
+#[cfg(target_os = "windows")]
+impl HardwareVolume {
+    /// Fails unless the endpoint has a volume control in hardware; a
+    /// software one in the driver would cost the same bits.
+    fn open(device: &IMMDevice) -> Result<Self> {
+        unsafe {
+            let endpoint: IAudioEndpointVolume = device
+                .Activate(CLSCTX_ALL, None)
+                .map_err(|err| anyhow!("IAudioEndpointVolume Activate failed: {}", err))?;
+            let support = endpoint
+                .QueryHardwareSupport()
+                .map_err(|err| anyhow!("QueryHardwareSupport failed: {}", err))?;
+            if support & ENDPOINT_HARDWARE_SUPPORT_VOLUME == 0 {
+                return Err(anyhow!("endpoint has no hardware volume control"));
+            }
+            let saved_level = endpoint
+                .GetMasterVolumeLevelScalar()
+                .map_err(|err| anyhow!("GetMasterVolumeLevelScalar failed: {}", err))?;
+            let saved_mute = endpoint
+                .GetMute()
+                .map_err(|err| anyhow!("GetMute failed: {}", err))?
+                .as_bool();
+            Ok(HardwareVolume {
+                endpoint,
+                saved_level,
+                saved_mute,
+                applied: None,
+            })
+        }
+    }
+
+    /// Set the endpoint to `volume`; zero mutes it.
+    fn apply(&mut self, volume: f32) -> Result<()> {
+        if self.applied == Some(volume) {
+            return Ok(());
+        }
+        unsafe {
+            if volume > 0.0 {
+                self.endpoint
+                    .SetMasterVolumeLevelScalar(volume, std::ptr::null())
+                    .map_err(|err| anyhow!("SetMasterVolumeLevelScalar failed: {}", err))?;
+            }
+            self.endpoint
+                .SetMute(volume == 0.0, std::ptr::null())
+                .map_err(|err| anyhow!("SetMute failed: {}", err))?;
+        }
+        self.applied = Some(volume);
+        Ok(())
+    }
+
+    fn restore(&mut self) {
+        if self.applied.take().is_some() {
+            unsafe {
+                let _ = self.endpoint.SetMasterVolumeLevelScalar(self.saved_level, std::ptr::null());
+                let _ = self.endpoint.SetMute(self.saved_mute, std::ptr::null());
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+impl Drop for HardwareVolume {
+    fn drop(&mut self) {
+        self.restore();
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn run_wasapi_exclusive_loop(
+    stop: Arc<AtomicBool>,
+    shared: SharedState,
+    device_ordinal: Option<u32>,
+    rates: Vec<u32>,
+    channels: u16,
+    prefer_int24: bool,
+) -> Result<()> {
+    let state = shared.inner.clone();
+    let consumer = shared.consumer.clone();
+    let control_shared = shared.control_shared.clone();
+    let _com = ComInit::new()?;
+    let enumerator: IMMDeviceEnumerator = unsafe {
+        CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)
+            .map_err(|err| anyhow!("CoCreateInstance failed: {}", err))?
+    };
+    let device = select_wasapi_device(&enumerator, device_ordinal)?;
+    let audio_client: IAudioClient = unsafe {
+        device
+            .Activate(CLSCTX_ALL, None)
+            .map_err(|err| anyhow!("IMMDevice Activate failed: {}", err))?
+    };
+    let mut negotiated = None;
+    'rates: for &rate in &rates {
+        for int24 in [prefer_int24, !prefer_int24] {
+            let format = build_wave_format(channels, rate, int24);
+            let supported = unsafe {
+                audio_client
+                    .IsFormatSupported(AUDCLNT_SHAREMODE_EXCLUSIVE, &format.Format, None)
+                    .ok()
+            };
+            match supported {
+                Ok(()) => {
+                    negotiated = Some((format, rate, int24));
+                    break 'rates;
+                }
+                Err(err) => info!(
+                    "exclusive {} Hz {} format unsupported: {}",
+                    rate,
+                    if int24 { "24-bit" } else { "float" },
+                    err
+                ),
+            }
+        }
+    }
+    let (format, sample_rate, int24) = negotiated.ok_or_else(|| anyhow!("Exclusive format unsupported"))?;
+    let output_bits = int24.then_some(INT32_OUTPUT_BITS);
+    if let Err(err) = resample_for_output(&shared, sample_rate) {
+        error!("resample for exclusive output failed: {}", err);
+    }
+    let mut default_period = 0i64;
+    let mut min_period = 0i64;
+    unsafe {
+        audio_client
+            .GetDevicePeriod(Some(&mut default_period), Some(&mut min_period))
+            .map_err(|err| anyhow!("GetDevicePeriod failed: {}", err))?;
+    }
+    let period = if default_period > 0 {
+        default_period
+    } else if min_period > 0 {
+        min_period
+    } else {
+        10_000
+    };
+    unsafe {
+        audio_client
+            .Initialize(
+                AUDCLNT_SHAREMODE_EXCLUSIVE,
+                AUDCLNT_STREAMFLAGS_EVENTCALLBACK,
+                period,
+                period,
+                &format.Format,
+                None,
+            )
+            .map_err(|err| anyhow!("IAudioClient Initialize failed: {}", err))?;
+    }
+    let event = unsafe {
+        CreateEventA(None, false, false, PCSTR::null())
+            .map_err(|err| anyhow!("CreateEventA failed: {}", err))?
+    };
+    unsafe {
+        audio_client
+            .SetEventHandle(event)
+            .map_err(|err| anyhow!("SetEventHandle failed: {}", err))?;
+    }
+    let buffer_frames = unsafe {
+        audio_client
+            .GetBufferSize()
+            .map_err(|err| anyhow!("GetBufferSize failed: {}", err))?
+    };
+    let render_client: IAudioRenderClient = unsafe {
+        audio_client
+            .GetService()
+            .map_err(|err| anyhow!("GetService(IARenderClient) failed: {}", err))?
+    };
+    unsafe {
+        audio_client
+            .Start()
+            .map_err(|err| anyhow!("AudioClient Start failed: {}", err))?;
+    }
+    state.lock().unwrap().output_config = Some(OutputConfigInfo {
+        backend: "WasapiExclusive".to_string(),
+        sample_rate,
+        channels,
+        sample_format: if int24 { "i32" } else { "f32" }.to_string(),
+        bit_depth: output_bits.unwrap_or(32),
+        buffer_frames: Some(buffer_frames),
+    });
+    send_state(&shared);
+    let mut hardware_volume = match HardwareVolume::open(&device) {
+        Ok(hardware) => Some(hardware),
+        Err(err) => {
+            info!("hardware volume unavailable, volume stays in software: {}", err);
+            None
+        }
+    };
+    let mut hardware_active = false;
+    let mut scratch = vec![0.0f32; buffer_frames as usize * channels as usize];
+    while !stop.load(Ordering::Acquire) {
+        let wait = unsafe { WaitForSingleObject(event, 100) };
+        if wait != WAIT_OBJECT_0 {
+            if wait == WAIT_TIMEOUT {
+                continue;
+            }
+            continue;
+        }
+        let (volume, wants_hardware) = {
+            let state = state.lock().unwrap();
+            (state.volume, state.volume_mode == "hardware")
+        };
+        if let Some(hardware) = hardware_volume.as_mut() {
+            let result = if wants_hardware {
+                hardware.apply(volume)
+            } else {
+                hardware.restore();
+                Ok(())
+            };
+            if let Err(err) = result {
+                warn!("hardware volume failed, falling back to software: {}", err);
+                hardware_volume = None;
+            }
+        }
+        let active = wants_hardware && hardware_volume.is_some();
+        if active != hardware_active {
+            hardware_active = active;
+            state.lock().unwrap().hardware_volume_active = active;
+            send_state(&shared);
+        }
+        let padding = unsafe {
+            audio_client
+                .GetCurrentPadding()
+                .map_err(|err| anyhow!("GetCurrentPadding failed: {}", err))?
+        };
+        let available = buffer_frames.saturating_sub(padding);
+        if available == 0 {
+            continue;
+        }
+        let frames = available as usize;
+        let needed = frames * channels as usize;
+        if scratch.len() < needed {
+            scratch.resize(needed, 0.0);
+        }
+        fill_output_buffer(&state, &consumer, &control_shared, &mut scratch[..needed], output_bits);
+        let buffer = unsafe {
+            render_client
+                .GetBuffer(available)
+                .map_err(|err| anyhow!("GetBuffer failed: {}", err))?
+        };
+        unsafe {
+            if int24 {
+                let out = std::slice::from_raw_parts_mut(buffer as *mut i32, needed);
+                for (dst, src) in out.iter_mut().zip(&scratch[..needed]) {
+                    *dst = f32_to_i32_sample(*src, INT32_OUTPUT_BITS);
+                }
+            } else {
+                std::ptr::copy_nonoverlapping(
+                    scratch.as_ptr(),
+                    buffer as *mut f32,
+                    needed,
+                );
+            }
+            render_client
+                .ReleaseBuffer(available, 0)
+                .map_err(|err| anyhow!("ReleaseBuffer failed: {}", err))?;
+        }
+    }
+    unsafe {
+        let _ = audio_client.Stop();
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "windows")]
+fn start_wasapi_exclusive_stream(
+    shared: &SharedState,
+    device_ordinal: Option<u32>,
+) -> Result<ExclusiveStreamHandle> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop_flag = stop.clone();
+    let thread_shared = shared.clone();
+    let exit_shared = shared.clone();
+    let (rates, channels, prefer_int24) = {
+        let mut guard = shared.inner.lock().unwrap();
+        guard.output_channels = guard.channels;
+        let source_rate = if guard.source_sample_rate > 0 {
+            guard.source_sample_rate
+        } else {
+            guard.sample_rate
+        };
+        (
+            exclusive_rate_candidates(guard.target_samplerate, source_rate),
+            guard.channels.max(1) as u16,
+            guard.exclusive_format == "i24" || guard.dsd_output == Some(DsdOutput::Dop),
+        )
+    };
+    let thread = thread::spawn(move || {
+        if let Err(err) = run_wasapi_exclusive_loop(
+            stop_flag,
+            thread_shared,
+            device_ordinal,
+            rates,
+            channels,
+            prefer_int24,
+        ) {
+            error!("wasapi exclusive stream failed: {}", err);
+            exit_shared.devices_changed.notify_one();
+        }
+        exit_shared.inner.lock().unwrap().hardware_volume_active = false;
+    });
+    Ok(ExclusiveStreamHandle {
+        stop,
+        thread: Some(thread),
+    })
+}
+
+#[cfg(any(target_os = "windows", test))]
+const COMMON_OUTPUT_RATES: [u32; 6] = [44_100, 48_000, 88_200, 96_000, 176_400, 192_000];
+
+/// Rates to offer an exclusive-mode device, in order: the requested
+/// target, the source's own rate, then `COMMON_OUTPUT_RATES`. The file is
+/// resampled to whichever the device takes.
+#[cfg(any(target_os = "windows", test))]
+fn exclusive_rate_candidates(target: Option<u32>, source_rate: u32) -> Vec<u32> {
+    let mut rates = Vec::with_capacity(COMMON_OUTPUT_RATES.len() + 2);
+    let preferred = [target.unwrap_or(0), source_rate];
+    for rate in preferred.into_iter().chain(COMMON_OUTPUT_RATES) {
+        if rate >= 8000 && !rates.contains(&rate) {
+            rates.push(rate);
+        }
+    }
+    rates
+}
+
+#[cfg(not(target_os = "windows"))]
+fn start_wasapi_exclusive_stream(
+    _shared: &SharedState,
+    _device_ordinal: Option<u32>,
+) -> Result<ExclusiveStreamHandle> {
+    Err(anyhow!("WASAPI exclusive output is only supported on Windows"))
+}
+
+/// Endpoint buffer for loopback capture, in 100 ns units.
+#[cfg(target_os = "windows")]
+const LOOPBACK_BUFFER_HNS: i64 = 2_000_000;
+/// How often the loopback thread drains the endpoint buffer. Loopback
+/// clients get no buffer events on older Windows, so it polls.
+#[cfg(target_os = "windows")]
+const LOOPBACK_POLL: Duration = Duration::from_millis(10);
+
+/// Start a loopback client on render endpoint `device_id` (the default
+/// output when `None`) in its shared-mode mix format, and switch the
+/// capture's rate and channels to that format. A requested rate or channel
+/// count the mix format doesn't have is a `CaptureError::LoopbackFormat`.
+#[cfg(target_os = "windows")]
+fn open_wasapi_loopback(
+    shared: &SharedState,
+    device_id: Option<&str>,
+    requested_rate: Option<u32>,
+    requested_channels: Option<u16>,
+) -> Result<(IAudioClient, IAudioCaptureClient, usize)> {
+    unsafe {
+        let enumerator: IMMDeviceEnumerator = CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)
+            .map_err(|err| anyhow!("CoCreateInstance failed: {}", err))?;
+        let device = match device_id.filter(|id| !id.is_empty() && *id != "default") {
+            Some(id) => enumerator
+                .GetDevice(&HSTRING::from(id))
+                .map_err(|err| anyhow!("GetDevice failed: {}", err))?,
+            None => enumerator
+                .GetDefaultAudioEndpoint(eRender, eConsole)
+                .map_err(|err| anyhow!("GetDefaultAudioEndpoint failed: {}", err))?,
+        };
+        let audio_client: IAudioClient = device
+            .Activate(CLSCTX_ALL, None)
+            .map_err(|err| anyhow!("IMMDevice Activate failed: {}", err))?;
+        let mix = audio_client
+            .GetMixFormat()
+            .map_err(|err| anyhow!("GetMixFormat failed: {}", err))?;
+        let (sample_rate, channels, bits) = ((*mix).nSamplesPerSec, (*mix).nChannels, (*mix).wBitsPerSample);
+        let mismatch = CaptureError::check_loopback_format(requested_rate, requested_channels, sample_rate, channels);
+        // The shared-mode mix format is 32-bit float.
+        let initialized = if let Some(err) = mismatch {
+            Err(err.into())
+        } else if bits == 32 {
+            audio_client
+                .Initialize(
+                    AUDCLNT_SHAREMODE_SHARED,
+                    AUDCLNT_STREAMFLAGS_LOOPBACK,
+                    LOOPBACK_BUFFER_HNS,
+                    0,
+                    mix,
+                    None,
+                )
+                .map_err(|err| anyhow!("IAudioClient Initialize failed: {}", err))
+        } else {
+            Err(anyhow!("unexpected {}-bit mix format", bits))
+        };
+        CoTaskMemFree(Some(mix as *const _));
+        initialized?;
+        let capture_client: IAudioCaptureClient = audio_client
+            .GetService()
+            .map_err(|err| anyhow!("GetService(IAudioCaptureClient) failed: {}", err))?;
+        audio_client
+            .Start()
+            .map_err(|err| anyhow!("AudioClient Start failed: {}", err))?;
+        let mut state = shared.inner.lock().unwrap();
+        state.sample_rate = sample_rate;
+        state.source_sample_rate = sample_rate;
+        state.channels = channels.max(1) as usize;
+        state.source_channels = state.channels;
+        Ok((audio_client, capture_client, state.channels))
+    }
+}
+
+/// `ready` gets the outcome of opening the client; errors after that are
+/// returned.
+#[cfg(target_os = "windows")]
+fn run_wasapi_loopback_loop(
+    stop: Arc<AtomicBool>,
+    shared: SharedState,
+    device_id: Option<String>,
+    requested: (Option<u32>, Option<u16>),
+    ready: std::sync::mpsc::Sender<Result<()>>,
+) -> Result<()> {
+    let _com = ComInit::new()?;
+    let (requested_rate, requested_channels) = requested;
+    let opened = open_wasapi_loopback(&shared, device_id.as_deref(), requested_rate, requested_channels);
+    let (audio_client, capture_client, channels) = match opened {
+        Ok(opened) => {
+            let _ = ready.send(Ok(()));
+            opened
+        }
+        Err(err) => {
+            let _ = ready.send(Err(err));
+            return Ok(());
+        }
+    };
+    let mut feeder = RingFeeder::new(&shared);
+    while !stop.load(Ordering::Acquire) {
+        thread::sleep(LOOPBACK_POLL);
+        loop {
+            let pending = unsafe {
+                capture_client
+                    .GetNextPacketSize()
+                    .map_err(|err| anyhow!("GetNextPacketSize failed: {}", err))?
+            };
+            if pending == 0 {
+                break;
+            }
+            let mut data = std::ptr::null_mut();
+            let mut frames = 0u32;
+            let mut flags = 0u32;
+            unsafe {
+                capture_client
+                    .GetBuffer(&mut data, &mut frames, &mut flags, None, None)
+                    .map_err(|err| anyhow!("GetBuffer failed: {}", err))?;
+            }
+            let len = frames as usize * channels;
+            // Paused in "suspend" mode the packets are still drained, so the
+            // endpoint buffer doesn't overflow, but dropped.
+            if !reader_suspended(&shared.inner) {
+                if flags & AUDCLNT_BUFFERFLAGS_SILENT.0 as u32 != 0 || data.is_null() {
+                    feeder.push(std::iter::repeat(0.0).take(len));
+                } else {
+                    let samples = unsafe { std::slice::from_raw_parts(data as *const f32, len) };
+                    feeder.push(samples.iter().copied());
+                }
+            }
+            unsafe {
+                capture_client
+                    .ReleaseBuffer(frames)
+                    .map_err(|err| anyhow!("ReleaseBuffer failed: {}", err))?;
+            }
+        }
+    }
+    unsafe {
+        let _ = audio_client.Stop();
+    }
+    Ok(())
+}
+
+/// Capture what an output device plays into the ring buffer, in place of
+/// the ffmpeg reader; `stop_stream` ends it. `samplerate` and `channels`,
+/// when set, must match the device's mix format.
+#[cfg(target_os = "windows")]
+fn start_wasapi_loopback(
+    shared: &SharedState,
+    device_id: Option<String>,
+    samplerate: Option<u32>,
+    channels: Option<u16>,
+) -> Result<()> {
+    let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+    let thread_shared = shared.clone();
+    let stop = shared.stream_stop.clone();
+    let requested = (samplerate, channels);
+    let thread = thread::spawn(move || {
+        if let Err(err) = run_wasapi_loopback_loop(stop, thread_shared.clone(), device_id, requested, ready_tx) {
+            error!("wasapi loopback capture failed: {}", err);
+            update_stream_status(&thread_shared, "error", Some(err.to_string()));
+        }
+    });
+    match ready_rx.recv() {
+        Ok(Ok(())) => {
+            *shared.stream_thread.lock().unwrap() = Some(thread);
+            update_stream_status(shared, "running", None);
+            Ok(())
+        }
+        Ok(Err(err)) => {
+            let _ = thread.join();
+            Err(err.context("loopback capture failed"))
+        }
+        Err(_) => {
+            let _ = thread.join();
+            Err(anyhow!("loopback capture thread exited"))
+        }
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn start_wasapi_loopback(
+    _shared: &SharedState,
+    _device_id: Option<String>,
+    _samplerate: Option<u32>,
+    _channels: Option<u16>,
+) -> Result<()> {
+    Err(anyhow!("WASAPI loopback capture is only supported on Windows"))
+}
+
+async fn ws_handler(ws: WebSocketUpgrade, State(state): State<SharedState>) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| handle_socket(socket, state))
+}
+
+fn default_output_device_info() -> Option<Value> {
+    let host = cpal::default_host();
+    let device = host.default_output_device()?;
+    let name = device.name().unwrap_or_else(|_| "Unknown".to_string());
+    let sample_rate = device
+        .default_output_config()
+        .map(|c| c.sample_rate().0)
+        .unwrap_or(48_000);
+    let id = enumerate_devices()
+        .into_iter()
+        .find(|info| info.name == name && info.hostapi == format!("{:?}", host.id()))
+        .map(|info| info.id);
+    Some(json!({
+        "id": id,
+        "name": name,
+        "hostapi": format!("{:?}", host.id()),
+        "default_samplerate": sample_rate
+    }))
+}
+
+fn engine_info_payload(shared: &SharedState) -> Value {
+    let port = shared.inner.lock().unwrap().server_port;
+    json!({
+        "type": "engine_info",
+        "version": env!("CARGO_PKG_VERSION"),
+        "port": port,
+        "soxr_available": detect_soxr_available(),
+        "ffmpeg_available": detect_ffmpeg_available(),
+        "default_device": default_output_device_info()
+    })
+}
+
+async fn handle_socket(mut socket: WebSocket, state: SharedState) {
+    // Subscribe first so nothing broadcast while building the info is lost.
+    let mut rx = state.tx.subscribe();
+    // Device enumeration and the ffmpeg probe block; keep them off the
+    // async workers.
+    let worker = state.clone();
+    let Ok(info) = tokio::task::spawn_blocking(move || engine_info_payload(&worker)).await else {
+        return;
+    };
+    if socket.send(Message::Text(info.to_string().into())).await.is_err() {
+        return;
+    }
+    while let Ok(msg) = rx.recv().await {
+        if socket.send(Message::Text(msg.into())).await.is_err() {
+            break;
+        }
+    }
+}
+
+fn decode_options_for(state: &EngineState) -> DecodeOptions {
+    DecodeOptions {
+        gapless_trim: state.gapless_trim_enabled,
+        channels: ChannelMode::KeepAll,
+        dsd: dsd_output_for(state),
+    }
+}
+
+/// DoP only goes to an exclusive-mode device; a shared one mixes and
+/// resamples, which would garble the words.
+fn dsd_output_for(state: &EngineState) -> DsdOutput {
+    if state.dsd_mode == "dop" && state.exclusive_mode {
+        DsdOutput::Dop
+    } else {
+        DsdOutput::Pcm(state.dsd_pcm_rate)
+    }
+}
+
+fn load_file_impl(shared: &SharedState, path: String) -> Result<()> {
+    let options = decode_options_for(&shared.inner.lock().unwrap());
+    load_file_with_options(shared, path, options)
+}
+
+/// Load a queue entry, crossfading into it from a track still playing.
+fn load_queued_track(shared: &SharedState, path: String) -> Result<()> {
+    let options = decode_options_for(&shared.inner.lock().unwrap());
+    load_track(shared, path, options, true)
+}
+
+/// Resample interleaved PCM with the configured backend, falling back from
+/// soxr to rubato in "auto" mode.
+fn resample_to(
+    samples: Vec<f32>,
+    channels: usize,
+    from: u32,
+    to: u32,
+    resampler_mode: &str,
+    resampler_quality: &str,
+    soxr_available: bool,
+) -> Result<(Vec<f32>, ResamplerInfo)> {
+    let mode = normalize_resampler_mode(resampler_mode);
+    let quality = normalize_resampler_quality(resampler_quality);
+    let input_frames = samples.len() / channels.max(1);
+    let (preferred, reason) = resampler_choice(&mode, &quality, soxr_available);
+    let mut fallback = None;
+    if preferred == "soxr" {
+        match resample_audio_soxr(&samples, channels, from, to) {
+            Ok(resampled) => {
+                return Ok((resampled, resampler_info("soxr", reason, &quality, from, to, input_frames)));
+            }
+            Err(err) if mode == "auto" => {
+                error!("soxr resample failed, falling back to rubato: {}", err);
+                fallback = Some(err.to_string());
+            }
+            Err(err) => return Err(anyhow!("soxr resample failed: {}", err)),
+        }
+    }
+    let resampled = resample_audio(&samples, channels, from, to, &quality)
+        .map_err(|e| anyhow!("resample failed: {}", e))?;
+    let mut info = resampler_info("rubato", reason, &quality, from, to, input_frames);
+    info.fallback = fallback;
+    Ok((resampled, info))
+}
+
+/// Everything a decode-and-resample depends on besides the file itself. A
+/// preloaded track is only reused when this still matches at load time.
+#[derive(Debug, Clone, PartialEq)]
+struct PrepareTarget {
+    options: DecodeOptions,
+    samplerate: Option<u32>,
+    resampler_mode: String,
+    resampler_quality: String,
+}
+
+impl PrepareTarget {
+    fn from_state(state: &EngineState, options: &DecodeOptions) -> Self {
+        PrepareTarget {
+            options: *options,
+            samplerate: state.target_samplerate,
+            resampler_mode: state.resampler_mode.clone(),
+            resampler_quality: state.resampler_quality.clone(),
+        }
+    }
+}
+
+/// A file decoded and resampled to the output rate, ready to become the
+/// current track.
+#[derive(Debug, Clone)]
+struct PreparedTrack {
+    path: String,
+    /// The file as it was before decoding, so a preload of a file edited
+    /// since isn't played.
+    stamp: Option<FileStamp>,
+    target: PrepareTarget,
+    samples: Vec<f32>,
+    /// Set for a file that decodes as it plays, to its length in frames;
+    /// `samples` is empty then.
+    incremental_frames: Option<usize>,
+    sample_rate: u32,
+    duration: f64,
+    resampler_info: Option<ResamplerInfo>,
+    source_sample_rate: u32,
+    source_channels: usize,
+    source_bit_depth: Option<u32>,
+    partial_decode: Option<PartialDecodeInfo>,
+    gapless_trim: Option<GaplessTrimInfo>,
+    replaygain: ReplayGainInfo,
+    dsd: Option<DsdOutput>,
+}
+
+fn prepare_track(shared: &SharedState, path: &str, options: &DecodeOptions) -> Result<PreparedTrack> {
+    prepare_track_with_progress(shared, path, options, &mut |_, _| true)
+}
+
+/// `prepare_track`, reporting the stage ("decode" or "resample") and how
+/// far into it, as `decode_file_with_progress` does; `false` abandons it.
+fn prepare_track_with_progress(
+    shared: &SharedState,
+    path: &str,
+    options: &DecodeOptions,
+    progress: &mut dyn FnMut(&str, Option<f64>) -> bool,
+) -> Result<PreparedTrack> {
+    if !Path::new(path).exists() {
+        return Err(LoadError::NotFound.into());
+    }
+    let stamp = FileStamp::of(Path::new(path));
+    let decoded = decode_file_with_progress(path, options, &mut |fraction| progress("decode", fraction))
+        .map_err(|err| match err.downcast::<LoadError>() {
+            Ok(err) => err.into(),
+            Err(err) => anyhow!("decode failed: {}", err),
+        })?;
+    let source_sample_rate = decoded.sample_rate;
+    let source_channels = decoded.channels;
+    let source_bit_depth = decoded.bit_depth;
+    let partial_decode = decoded.partial;
+    let replaygain = decoded.replaygain;
+    let gapless_trim = decoded.gapless;
+    let dsd = decoded.dsd;
+
+    let soxr_available = detect_soxr_available();
+    let target = {
+        let mut state = shared.inner.lock().unwrap();
+        state.soxr_available = soxr_available;
+        PrepareTarget::from_state(&state, options)
+    };
+    let PrepareTarget {
+        samplerate: target_samplerate,
+        resampler_mode,
+        resampler_quality,
+        ..
+    } = target.clone();
+
+    let mut final_data = decoded.samples;
+    let mut final_sample_rate = decoded.sample_rate;
+    let mut resample_info = None;
+    if let Some(target) = target_samplerate {
+        // DoP words only mean anything at their own rate.
+        if target > 0 && target != final_sample_rate && dsd != Some(DsdOutput::Dop) {
+            // The resamplers run in one go; this is the last chance to stop.
+            if !progress("resample", Some(0.0)) {
+                return Err(anyhow!("load cancelled"));
+            }
+            let (resampled, info) = resample_to(
+                final_data,
+                source_channels,
+                final_sample_rate,
+                target,
+                &resampler_mode,
+                &resampler_quality,
+                soxr_available,
+            )?;
+            final_data = resampled;
+            final_sample_rate = target;
+            resample_info = Some(info);
+        }
+    }
+
+    let duration = if final_sample_rate > 0 && source_channels > 0 {
+        (final_data.len() / source_channels) as f64 / final_sample_rate as f64
+    } else {
+        0.0
+    };
+
+    Ok(PreparedTrack {
+        path: path.to_string(),
+        stamp,
+        target,
+        samples: final_data,
+        incremental_frames: None,
+        sample_rate: final_sample_rate,
+        duration,
+        resampler_info: resample_info,
+        source_sample_rate,
+        source_channels,
+        source_bit_depth,
+        partial_decode,
+        gapless_trim,
+        replaygain,
+        dsd,
+    })
+}
+
+/// Take the preloaded track if it is `path`, unchanged on disk since, prepared
+/// for the current target. Another path or target stays preloaded; `path`
+/// edited since the preload is dropped.
+fn take_preloaded(shared: &SharedState, path: &str, options: &DecodeOptions) -> Option<PreparedTrack> {
+    let target = PrepareTarget::from_state(&shared.inner.lock().unwrap(), options);
+    let stamp = FileStamp::of(Path::new(path));
+    let mut slot = shared.preloaded.lock().unwrap();
+    let prepared = slot.as_ref().filter(|prepared| prepared.path == path)?;
+    if stamp.is_none() || prepared.stamp != stamp {
+        *slot = None;
+        return None;
+    }
+    if prepared.target != target {
+        return None;
+    }
+    slot.take()
+}
+
+/// Decode and resample `path` into the preload slot so a later load of the
+/// same path skips the work. Replaces whatever was preloaded before.
+fn preload_impl(shared: &SharedState, path: &str, options: &DecodeOptions) -> Result<f64> {
+    let prepared = prepare_track(shared, path, options)?;
+    let duration = prepared.duration;
+    *shared.preloaded.lock().unwrap() = Some(prepared);
+    Ok(duration)
+}
+
+/// Frames rendered per pass of the offline export loop.
+const EXPORT_CHUNK_FRAMES: usize = 4096;
+const EXPORT_PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// What `/export` renders and where. Unset fields follow the output target
+/// rate and the source's bit depth.
+struct ExportJob {
+    source: String,
+    output: PathBuf,
+    /// Where the render goes until it's complete, beside `output`.
+    partial: PathBuf,
+    format: ExportFormat,
+    sample_rate: Option<u32>,
+    bit_depth: Option<u32>,
+    volume: f32,
+}
+
+/// A hidden, uniquely named file next to `output` for an export to write
+/// before it takes `output`'s place.
+fn export_partial_path(output: &Path) -> PathBuf {
+    let name = output.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    output.with_file_name(format!(".{}.{}.part", name, uuid::Uuid::new_v4().simple()))
+}
+
+/// Whether `output` is the file at `source`, through links and relative
+/// paths, whether or not `output` exists yet.
+fn is_same_file(source: &Path, output: &Path) -> bool {
+    let Ok(source) = source.canonicalize() else {
+        return false;
+    };
+    let output = output.canonicalize().or_else(|_| {
+        let dir = output.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        let name = output.file_name().ok_or_else(|| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+        dir.canonicalize().map(|dir| dir.join(name))
+    });
+    output.is_ok_and(|output| output == source)
+}
+
+/// A state that renders like `state` does, for exporting `source`: the EQ,
+/// ReplayGain, auto level, track gain, polarity, downmix, limiter and
+/// dither settings, and nothing of the queue, library or playback. The
+/// volume is the export's own.
+fn offline_export_state(state: &EngineState, source: &str) -> EngineState {
+    let mut offline = initial_state();
+    offline.eq_enabled = state.eq_enabled;
+    offline.eq_type = state.eq_type.clone();
+    offline.eq_bands = state.eq_bands.clone();
+    offline.eq_filters.set_kind(state.eq_filters.kind(), state.eq_filters.fir_taps());
+    offline.dither_enabled = state.dither_enabled;
+    offline.dither_type = state.dither_type.clone();
+    offline.dither_bits = state.dither_bits;
+    offline.replaygain_enabled = state.replaygain_enabled;
+    offline.replaygain_preamp_db = state.replaygain_preamp_db;
+    offline.replaygain_prevent_clipping = state.replaygain_prevent_clipping;
+    offline.replaygain_mode = state.replaygain_mode.clone();
+    offline.auto_level_enabled = state.auto_level_enabled;
+    offline.auto_level_target_db = state.auto_level_target_db;
+    offline.auto_level_max_gain_db = state.auto_level_max_gain_db;
+    offline.track_gain_db = state.track_gains.get(source).copied().unwrap_or(0.0);
+    offline.limiter_enabled = state.limiter_enabled;
+    offline.limiter_threshold = state.limiter_threshold;
+    offline.invert_polarity = state.invert_polarity;
+    offline.invert_channels = state.invert_channels.clone();
+    offline.downmix = state.downmix.clone();
+    offline.resampler_mode = state.resampler_mode.clone();
+    offline.resampler_quality = state.resampler_quality.clone();
+    offline.target_samplerate = state.target_samplerate;
+    offline
+}
+
+/// Render a file through the output processing chain into `job.output`,
+/// without touching playback. ReplayGain, polarity, limiter and dither
+/// settings are taken as they are when the render starts; the volume is
+/// `job.volume`. The render goes to `job.partial` and only replaces
+/// `job.output` once complete.
+fn export_impl(shared: &SharedState, job: &ExportJob, mut progress: impl FnMut(f64)) -> Result<ExportSpec> {
+    let (mut offline, options) = {
+        let state = shared.inner.lock().unwrap();
+        let options = DecodeOptions {
+            dsd: DsdOutput::Pcm(state.dsd_pcm_rate),
+            ..decode_options_for(&state)
+        };
+        (offline_export_state(&state, &job.source), options)
+    };
+    let decoded = decode_file(&job.source, &options).map_err(|err| anyhow!("decode failed: {}", err))?;
+    let channels = decoded.channels.max(1);
+    let sample_rate = job
+        .sample_rate
+        .or(offline.target_samplerate)
+        .filter(|rate| *rate > 0)
+        .unwrap_or(decoded.sample_rate);
+    let bit_depth = job.bit_depth.unwrap_or_else(|| job.format.default_bits(decoded.bit_depth));
+    let spec = ExportSpec {
+        format: job.format,
+        sample_rate,
+        channels,
+        bit_depth,
+    };
+    let mut samples = decoded.samples;
+    if sample_rate != decoded.sample_rate {
+        samples = resample_to(
+            samples,
+            channels,
+            decoded.sample_rate,
+            sample_rate,
+            &offline.resampler_mode,
+            &offline.resampler_quality,
+            detect_soxr_available(),
+        )?
+        .0;
+    }
+    let total_frames = samples.len() / channels;
+
+    offline.data = samples;
+    offline.channels = channels;
+    offline.output_channels = channels;
+    offline.sample_rate = sample_rate;
+    offline.mode = "file".to_string();
+    offline.is_playing = true;
+    offline.volume = job.volume.clamp(0.0, 1.0);
+    offline.volume_current = offline.volume;
+    offline.transport_gain = 1.0;
+    offline.replaygain = decoded.replaygain;
+    refresh_replaygain_gain(&mut offline);
+    reset_auto_level(&mut offline);
+    refresh_eq_filters(&mut offline);
+    offline.eq_filters.design_current_rate();
+    // The FIR EQ delays what it plays. Rendering that much silence past the
+    // end and dropping as much from the front keeps the export in time with
+    // the source without cutting off its last frames.
+    let latency = if offline.eq_enabled { offline.eq_filters.latency_frames() } else { 0 };
+    offline.data.resize((total_frames + latency) * channels, 0.0);
+    let state = Arc::new(Mutex::new(offline));
+    let consumer = Arc::new(Mutex::new(HeapRb::<f32>::new(1).split().1));
+
+    let mut writer = ExportWriter::create(&job.partial, spec)?;
+    // Float WAV skips dither, like a float device.
+    let output_bits = (bit_depth != 32).then_some(bit_depth);
+    let mut chunk = vec![0.0f32; EXPORT_CHUNK_FRAMES * channels];
+    let render_frames = total_frames + latency;
+    let mut rendered = 0usize;
+    while rendered < render_frames {
+        let frames = EXPORT_CHUNK_FRAMES.min(render_frames - rendered);
+        let buffer = &mut chunk[..frames * channels];
+        fill_output_buffer(&state, &consumer, &None, buffer, output_bits);
+        let delayed = latency.saturating_sub(rendered).min(frames);
+        writer.write(&buffer[delayed * channels..])?;
+        rendered += frames;
+        progress(rendered as f64 / render_frames as f64);
+    }
+    writer.finish()?;
+    std::fs::rename(&job.partial, &job.output).context("replace export output")?;
+    Ok(spec)
+}
+
+fn load_file_with_options(shared: &SharedState, path: String, options: DecodeOptions) -> Result<()> {
+    load_track(shared, path, options, false)
+}
+
+/// Minimum gap between `load_progress` events.
+const LOAD_PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Decode and resample `path` for `load_track`, with `stream_status` at
+/// "loading" and `load_progress` events going out meanwhile. A newer load
+/// starting makes this one give up.
+fn prepare_track_for_load(
+    shared: &SharedState,
+    path: &str,
+    options: &DecodeOptions,
+    generation: u64,
+) -> Result<PreparedTrack> {
+    let superseded = || shared.load_generation.load(Ordering::Acquire) != generation;
+    let status = std::mem::replace(&mut shared.inner.lock().unwrap().stream_status, "loading".to_string());
+    send_state(shared);
+    let mut last_progress = Instant::now();
+    let result = prepare_track_with_progress(shared, path, options, &mut |stage, fraction| {
+        if superseded() {
+            return false;
+        }
+        if last_progress.elapsed() >= LOAD_PROGRESS_INTERVAL {
+            last_progress = Instant::now();
+            let payload = json!({
+                "type": "load_progress",
+                "path": path,
+                "stage": stage,
+                "percent": fraction.map(|fraction| fraction * 100.0),
+            });
+            let _ = shared.tx.send(payload.to_string());
+        }
+        true
+    });
+    if superseded() {
+        // The newer load owns the status now.
+        return Err(LoadError::Superseded(path.to_string()).into());
+    }
+    if result.is_err() {
+        shared.inner.lock().unwrap().stream_status = status;
+        send_state(shared);
+    }
+    result
+}
+
+fn load_track(shared: &SharedState, path: String, options: DecodeOptions, crossfade: bool) -> Result<()> {
+    if !Path::new(&path).exists() {
+        return Err(LoadError::NotFound.into());
+    }
+    let generation = shared.load_generation.fetch_add(1, Ordering::AcqRel) + 1;
+    stop_stream(shared);
+    let previous = ended_track_for_gap(shared);
+    let prepared = match take_preloaded(shared, &path, &options)
+        .or_else(|| prepare_incremental_track(shared, &path, &options))
+    {
+        Some(prepared) => prepared,
+        None => prepare_track_for_load(shared, &path, &options, generation)?,
+    };
+    let incremental = prepared.incremental_frames.is_some();
+    let gap_frames = previous.map_or(0, |(previous, silence_ms)| {
+        let mut next = known_track(shared, &path);
+        next.sample_rate = Some(prepared.source_sample_rate);
+        next.channels = Some(prepared.source_channels as u32);
+        match gapless_obstacle(&previous, &next) {
+            Some(_) => (silence_ms as u64 * prepared.sample_rate as u64 / 1000) as usize,
+            None => 0,
+        }
+    });
+
+    {
+        let mut state = shared.inner.lock().unwrap();
+        if shared.load_generation.load(Ordering::Acquire) != generation {
+            return Err(LoadError::Superseded(path.to_string()).into());
+        }
+        let fade_frames = if crossfade
+            && state.mode == "file"
+            && state.is_playing
+            && !state.is_paused
+            && state.sample_rate == prepared.sample_rate
+            && state.channels == prepared.source_channels
+            && state.dsd_output != Some(DsdOutput::Dop)
+            && prepared.dsd != Some(DsdOutput::Dop)
+        {
+            let remaining = (state.data.len() / state.channels.max(1)).saturating_sub(state.position);
+            crossfade_frames(&state).min(remaining)
+        } else {
+            0
+        };
+        let outgoing = std::mem::replace(&mut state.data, prepared.samples);
+        // Silent until the decoder has cleared the ring of what was there.
+        state.incremental = prepared.incremental_frames.map(|frames| IncrementalDecode {
+            frames,
+            seek: Some(0),
+            finished: false,
+        });
+        state.crossfade = (fade_frames > 0).then(|| Crossfade::new(outgoing, &state, fade_frames));
+        state.sample_rate = prepared.sample_rate;
+        state.resampler_info = prepared.resampler_info;
+        state.channels = prepared.source_channels;
+        state.source_sample_rate = prepared.source_sample_rate;
+        state.source_channels = prepared.source_channels;
+        state.source_bit_depth = prepared.source_bit_depth;
+        state.partial_decode = prepared.partial_decode;
+        state.gapless_trim = prepared.gapless_trim;
+        state.replaygain = prepared.replaygain;
+        state.dsd_output = prepared.dsd;
+        state.position = 0;
+        state.loop_region = None;
+        state.gap_frames = gap_frames;
+        state.track_finished = false;
+        discard_lookahead(&mut state);
+        if fade_frames == 0 {
+            state.eq_filters.reset();
+        }
+        state.duration = prepared.duration;
+        // Keep the outgoing track sounding until play picks up the fade.
+        state.is_playing = fade_frames > 0;
+        state.is_paused = false;
+        state.file_path = Some(path.clone());
+        state.mode = "file".to_string();
+        state.stream_status = "idle".to_string();
+        state.queue_index = state.queue.iter().position(|track| track.path == path);
+        refresh_replaygain_gain(&mut state);
+        state.track_gain_db = state.track_gains.get(&path).copied().unwrap_or(0.0);
+    }
+
+    reset_ring_buffer(shared);
+    if incremental {
+        start_incremental_decode(shared, path, options);
+    }
+    let _ = ensure_output_stream(shared);
+    send_state(shared);
+    Ok(())
+}
+
+/// How far a "volume up" command moves the volume by default, and the
+/// smallest step `command_volume_step` accepts.
+const DEFAULT_COMMAND_VOLUME_STEP: f32 = 0.1;
+const COMMAND_VOLUME_STEP_MIN: f32 = 0.01;
+
+/// Most silence `inter_track_silence_ms` accepts.
+const INTER_TRACK_SILENCE_MAX_MS: u32 = 10_000;
+
+/// A queue or library entry for `path`, or one probed from the file.
+fn known_track(shared: &SharedState, path: &str) -> LibraryTrack {
+    let known = {
+        let state = shared.inner.lock().unwrap();
+        state.queue.iter().chain(&state.library).find(|track| track.path == path).cloned()
+    };
+    known.unwrap_or_else(|| read_library_track_or_fallback(Path::new(path)))
+}
+
+/// The output callback stops a file that plays out by clearing
+/// `is_playing` with the position at the end, which is how a track that
+/// finished differs from one that was skipped or stopped.
+fn track_ended(state: &EngineState) -> bool {
+    let frames = file_frames(state);
+    state.mode == "file" && !state.is_playing && frames > 0 && state.position >= frames
+}
+
+/// The file that just played to its end, with the configured gap, when a
+/// load now should start with silence. Anything else (a skip while
+/// playing, a load after stop, a stream) gets no gap.
+fn ended_track_for_gap(shared: &SharedState) -> Option<(LibraryTrack, u32)> {
+    let (path, sample_rate, channels, silence_ms) = {
+        let state = shared.inner.lock().unwrap();
+        if !track_ended(&state) || state.inter_track_silence_ms == 0 {
+            return None;
+        }
+        (
+            state.file_path.clone()?,
+            state.source_sample_rate,
+            state.source_channels as u32,
+            state.inter_track_silence_ms,
+        )
+    };
+    let mut track = known_track(shared, &path);
+    track.sample_rate = Some(sample_rate);
+    track.channels = Some(channels);
+    Some((track, silence_ms))
+}
+
+fn play_impl(shared: &SharedState) -> Result<()> {
+    let rejoin_url = {
+        let mut state = shared.inner.lock().unwrap();
+        let suspended = state.is_paused && state.live_pause_mode == "suspend";
+        state.is_playing = true;
+        state.is_paused = false;
+        if suspended && state.mode == "stream" {
+            state.stream_url.clone()
+        } else {
+            None
+        }
+    };
+    // The server gave up on a suspended stream while it sat unread: rejoin.
+    let reader_gone = shared
+        .stream_thread
+        .lock()
+        .unwrap()
+        .as_ref()
+        .is_none_or(|thread| thread.is_finished());
+    if let Some(url) = rejoin_url.filter(|_| reader_gone) {
+        info!("suspended stream ended while paused, rejoining {}", url);
+        return start_stream_impl(shared, url);
+    }
+    let _ = ensure_output_stream(shared);
+    send_state(shared);
+    Ok(())
+}
+
+/// Keeps the output faded out for a transport change; see
+/// `fade_out_transport`. Dropping it lets playback ramp back in.
+struct TransportFade<'a>(&'a SharedState);
+
+impl Drop for TransportFade<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.0.inner.lock() {
+            state.transport_fading_out = false;
+        }
+    }
+}
+
+/// Fade playback to silence ahead of a pause, stop or seek, so the change
+/// doesn't cut the waveform mid-cycle. Waits for the callback to finish the
+/// ramp, or for the fade plus `TRANSPORT_FADE_SLACK_MS` if it doesn't run.
+fn fade_out_transport(shared: &SharedState) -> TransportFade<'_> {
+    let wait = {
+        let mut state = shared.inner.lock().unwrap();
+        state.transport_fading_out = true;
+        (transport_sounding(&state) && state.transport_fade_ms > 0)
+            .then(|| Duration::from_millis(state.transport_fade_ms as u64 + TRANSPORT_FADE_SLACK_MS))
+    };
+    if let Some(wait) = wait {
+        let deadline = Instant::now() + wait;
+        while Instant::now() < deadline && shared.inner.lock().unwrap().transport_gain > 0.0 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+    TransportFade(shared)
+}
+
+/// Run a transport change on a blocking thread, since it sleeps while
+/// `fade_out_transport` waits on the callback.
+async fn run_transport_change<T: Send + 'static>(
+    shared: &SharedState,
+    change: impl FnOnce(&SharedState) -> Result<T> + Send + 'static,
+) -> Result<T> {
+    let shared = shared.clone();
+    tokio::task::spawn_blocking(move || change(&shared))
+        .await
+        .unwrap_or_else(|err| Err(anyhow!("transport change panicked: {}", err)))
+}
+
+fn pause_impl(shared: &SharedState) -> Result<()> {
+    let _fade = fade_out_transport(shared);
+    {
+        let mut state = shared.inner.lock().unwrap();
+        state.is_paused = true;
+    }
+    send_state(shared);
+    Ok(())
+}
+
+fn stop_impl(shared: &SharedState) -> Result<()> {
+    let _fade = fade_out_transport(shared);
+    {
+        let mut state = shared.inner.lock().unwrap();
+        state.is_playing = false;
+        state.is_paused = false;
+        state.position = 0;
+        state.gap_frames = 0;
+        state.played_frames = 0;
+        cancel_crossfade(&mut state);
+        state.mode = "idle".to_string();
+        state.buffered_frames = 0;
+    }
+    stop_stream(shared);
+    send_state(shared);
+    Ok(())
+}
+
+fn start_stream_impl(shared: &SharedState, url: String) -> Result<()> {
+    start_stream_at(shared, url, None)
+}
+
+/// Start ffmpeg on `url`. With `start`, this is a seek within the stream
+/// already playing: the format and probed length carry over and only the
+/// position moves.
+fn start_stream_at(shared: &SharedState, url: String, start: Option<f64>) -> Result<()> {
+    stop_stream(shared);
+    let (sample_rate, channels) = {
+        let mut state = shared.inner.lock().unwrap();
+        state.mode = "stream".to_string();
+        state.stream_url = Some(url.clone());
+        state.buffered_frames = 0;
+        state.stream_status = "starting".to_string();
+        if let Some(start) = start {
+            state.played_frames = (start * state.sample_rate as f64) as u64;
+        } else {
+            reset_stream_state(&mut state);
+        }
+        (state.sample_rate, state.channels as u16)
+    };
+    reset_ring_buffer(shared);
+    let child = match spawn_ffmpeg(&url, sample_rate, channels, start) {
+        Ok(child) => child,
+        Err(err) => {
+            update_stream_status(shared, "error", Some(err.to_string()));
+            return Err(err);
+        }
+    };
+    start_stream_reader(shared.clone(), child);
+    if start.is_none() {
+        let shared = shared.clone();
+        thread::spawn(move || {
+            let duration = probe_stream_duration(&url);
+            let mut state = shared.inner.lock().unwrap();
+            // Another stream may have started meanwhile.
+            if state.mode == "stream" && state.stream_url.as_deref() == Some(url.as_str()) {
+                state.stream_seekable = Some(duration.is_some());
+                state.duration = duration.unwrap_or(0.0);
+            }
+        });
+    }
+    let _ = ensure_output_stream(shared);
+    send_state(shared);
+    Ok(())
+}
+
+/// A new stream starts at the default format with no length known.
+fn reset_stream_state(state: &mut EngineState) {
+    state.sample_rate = state.target_samplerate.unwrap_or(48_000);
+    state.resampler_info = None;
+    state.channels = 2;
+    state.source_sample_rate = state.sample_rate;
+    state.source_channels = state.channels;
+    state.source_bit_depth = None;
+    state.partial_decode = None;
+    state.gapless_trim = None;
+    state.replaygain = ReplayGainInfo::default();
+    state.dsd_output = None;
+    refresh_replaygain_gain(state);
+    state.track_gain_db = 0.0;
+    state.data.clear();
+    state.incremental = None;
+    state.position = 0;
+    state.played_frames = 0;
+    state.duration = 0.0;
+    state.stream_seekable = None;
+}
+
+/// Restart the stream's ffmpeg at `seconds`, clamped to the stream's
+/// length, and return where it landed.
+fn seek_stream_impl(shared: &SharedState, seconds: f64) -> Result<f64> {
+    let (url, target) = {
+        let state = shared.inner.lock().unwrap();
+        if state.mode != "stream" {
+            return Err(anyhow!("no stream playing"));
+        }
+        if !seconds.is_finite() {
+            return Err(anyhow!("invalid seek"));
+        }
+        let url = state.stream_url.clone().ok_or_else(|| anyhow!("stream url missing"))?;
+        match state.stream_seekable {
+            Some(true) => {}
+            Some(false) => return Err(anyhow!("live stream is not seekable")),
+            None => return Err(anyhow!("stream length not known yet")),
+        }
+        (url, seconds.clamp(0.0, state.duration))
+    };
+    start_stream_at(shared, url, Some(target))?;
+    Ok(target)
+}
+
+fn restart_impl(shared: &SharedState) -> Result<()> {
+    let _fade = fade_out_transport(shared);
+    let (mode, url) = {
+        let state = shared.inner.lock().unwrap();
+        (state.mode.clone(), state.stream_url.clone())
+    };
+    match mode.as_str() {
+        "file" => {
+            let mut state = shared.inner.lock().unwrap();
+            set_file_position(&mut state, 0);
+            state.played_frames = 0;
+            cancel_crossfade(&mut state);
+        }
+        "stream" => {
+            let url = url.ok_or_else(|| anyhow!("stream url missing"))?;
+            start_stream_impl(shared, url)?;
+        }
+        "capture" => {
+            shared.inner.lock().unwrap().played_frames = 0;
+        }
+        _ => return Err(anyhow!("nothing to restart")),
+    }
+    {
+        let mut state = shared.inner.lock().unwrap();
+        state.stream_restart_pending = false;
+        state.is_playing = true;
+        state.is_paused = false;
+    }
+    let _ = ensure_output_stream(shared);
+    send_state(shared);
+    Ok(())
+}
+
+/// Set the user gain override for the current file and remember it for
+/// next time; 0 dB forgets it. Returns the stored gain and whether the
+/// file's peak would now clip after ReplayGain.
+fn set_track_gain_impl(shared: &SharedState, gain_db: f32) -> Result<(f32, bool)> {
+    if !gain_db.is_finite() {
+        return Err(anyhow!("gain_db must be a number"));
+    }
+    let gain_db = normalize_track_gain_db(gain_db);
+    let saving = shared.track_gains_save.lock().unwrap();
+    let (path, gains_path, gains, replaygain) = {
+        let mut state = shared.inner.lock().unwrap();
+        let path = match (&state.file_path, state.mode.as_str()) {
+            (Some(path), "file") => path.clone(),
+            _ => return Err(anyhow!("no file loaded")),
+        };
+        state.track_gain_db = gain_db;
+        if gain_db == 0.0 {
+            state.track_gains.remove(&path);
+        } else {
+            state.track_gains.insert(path.clone(), gain_db);
+        }
+        let replaygain = if state.replaygain_enabled { state.replaygain_gain } else { 1.0 };
+        (path, state.track_gains_path.clone(), state.track_gains.clone(), replaygain)
+    };
+    if let Err(err) = save_track_gains(&gains_path, &gains) {
+        warn!("could not save track gains: {}", err);
+    }
+    drop(saving);
+    let clips = buffered_peak(shared, &path).is_some_and(|peak| peak * replaygain * db_to_linear(gain_db) > 1.0);
+    if clips {
+        warn!("track gain of {:.1} dB makes {} clip", gain_db, path);
+    }
+    Ok((gain_db, clips))
+}
+
+/// Samples the peak scan reads per hold of the state lock.
+const PEAK_SCAN_BLOCK: usize = 1 << 16;
+
+/// Peak of the decoded samples of `path`, read a block at a time so the
+/// audio callback can take the lock in between; `None` once another file
+/// has been loaded.
+fn buffered_peak(shared: &SharedState, path: &str) -> Option<f32> {
+    let mut peak = 0.0f32;
+    let mut start = 0;
+    loop {
+        let state = shared.inner.lock().unwrap();
+        if state.file_path.as_deref() != Some(path) {
+            return None;
+        }
+        let end = (start + PEAK_SCAN_BLOCK).min(state.data.len());
+        peak = state.data[start.min(end)..end].iter().fold(peak, |peak, sample| peak.max(sample.abs()));
+        if end == state.data.len() {
+            return Some(peak);
+        }
+        start = end;
+    }
+}
+
+/// Prefer `hostapi` for output (`None` goes back to cpal's default host) and
+/// reopen the output stream on it.
+fn set_hostapi_impl(shared: &SharedState, hostapi: Option<String>) -> Result<()> {
+    let hostapi = match hostapi.as_deref().map(str::trim).filter(|name| !name.is_empty()) {
+        Some(name) => Some(format!(
+            "{:?}",
+            find_host_id(name).ok_or_else(|| anyhow!("host api {} is not available", name))?
+        )),
+        None => None,
+    };
+    shared.inner.lock().unwrap().hostapi = hostapi;
+    stop_exclusive_stream(shared);
+    shared.output_stream.lock().unwrap().0 = None;
+    shared.inner.lock().unwrap().output_config = None;
+    let _ = ensure_output_stream(shared);
+    send_state(shared);
+    Ok(())
+}
+
+fn configure_output_impl(
+    shared: &SharedState,
+    device_id: Option<usize>,
+    exclusive: Option<bool>,
+) -> Result<()> {
+    let selected = checked_device_key(device_id)?;
+    select_output_device(shared, device_id, selected, exclusive);
+    Ok(())
+}
+
+/// Play on `device_id`, whose host and name `checked_device_key` gave as
+/// `selected`, and reopen the output.
+fn select_output_device(
+    shared: &SharedState,
+    device_id: Option<usize>,
+    selected: Option<(String, String)>,
+    exclusive: Option<bool>,
+) {
+    let hostapi = selected.as_ref().map(|(hostapi, _)| hostapi.clone());
+    {
+        let mut state = shared.inner.lock().unwrap();
+        state.device_id = device_id;
+        state.selected_device = selected;
+        state.device_fallback = false;
+        if let Some(exclusive) = exclusive {
+            let effective = resolve_exclusive_mode(hostapi.as_deref(), exclusive);
+            if exclusive && !effective {
+                info!("exclusive mode not supported for selected device, falling back to shared");
+            }
+            state.exclusive_mode = effective;
+        } else if state.exclusive_mode {
+            let effective = resolve_exclusive_mode(hostapi.as_deref(), true);
+            if !effective {
+                info!("exclusive mode not supported for selected device, falling back to shared");
+            }
+            state.exclusive_mode = effective;
+        }
+    }
+    reopen_output(shared);
+    send_state(shared);
+}
+
+/// Drop the output stream and open it again as the state now says.
+fn reopen_output(shared: &SharedState) {
+    stop_exclusive_stream(shared);
+    shared.output_stream.lock().unwrap().0 = None;
+    shared.inner.lock().unwrap().output_config = None;
+    let _ = ensure_output_stream(shared);
+}
+
+/// What resampling the current track got, and what a load would do now
+/// with the current settings. `current` is null when the track plays at its
+/// own rate; `planned` is null when no resampling would happen.
+async fn resampler_status_handler(State(shared): State<SharedState>) -> impl IntoResponse {
+    let soxr_available = detect_soxr_available();
+    let mut state = shared.inner.lock().unwrap();
+    state.soxr_available = soxr_available;
+    let mode = normalize_resampler_mode(&state.resampler_mode);
+    let quality = normalize_resampler_quality(&state.resampler_quality);
+    let from_rate = state.source_sample_rate;
+    let to_rate = state.target_samplerate.filter(|rate| *rate > 0);
+    let planned = to_rate.filter(|rate| *rate != from_rate && state.mode == "file").map(|to_rate| {
+        let (backend, reason) = resampler_choice(&mode, &quality, soxr_available);
+        json!({ "backend": backend, "reason": reason, "from_rate": from_rate, "to_rate": to_rate })
+    });
+    Json(json!({
+        "status": "success",
+        "mode": mode,
+        "quality": quality,
+        "soxr_available": soxr_available,
+        "current": state.resampler_info.clone(),
+        "planned": planned,
+    }))
+}
+
+async fn get_state_handler(State(state): State<SharedState>) -> impl IntoResponse {
+    let state = state.inner.lock().unwrap();
+    let payload = json!({
+        "status": "success",
+        "state": build_state_view(&state)
+    });
+    Json(payload)
+}
+
+async fn list_devices_handler() -> impl IntoResponse {
+    let payload = json!({ "status": "success", "devices": list_devices() });
+    Json(payload)
+}
+
+async fn scan_library_handler(
+    State(shared): State<SharedState>,
+    Json(req): Json<LibraryScanRequest>,
+) -> impl IntoResponse {
+    let scan_shared = shared.clone();
+    let defaults = ScanScope::default();
+    let scope = ScanScope {
+        recursive: req.recursive.unwrap_or(defaults.recursive),
+        follow_links: req.follow_links.unwrap_or(defaults.follow_links),
+    };
+    let force = req.force.unwrap_or(false);
+    let sort = match req.sort_by.as_deref() {
+        Some(label) => match LibrarySort::from_label(label) {
+            Some(sort) => Some(sort),
+            None => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({ "status": "error", "message": format!("unknown sort_by: {}", label) })),
+                )
+            }
+        },
+        None => None,
+    };
+    let sort = sort.map(|sort| (sort, req.ascending.unwrap_or(true)));
+    let extensions = match req.extensions.as_deref().map(normalize_audio_extensions).transpose() {
+        Ok(extensions) => extensions,
+        Err(err) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "status": "error", "message": err.to_string() })),
+            )
+        }
+    };
+    let claim = match begin_library_scan(&shared, &req.path) {
+        Ok(claim) => claim,
+        Err(err) => return scan_error_response(err.into()),
+    };
+    let scan_id = claim.id.clone();
+    let job = tokio::task::spawn_blocking(move || {
+        run_claimed_library_scan(&scan_shared, claim, &req.path, scope, force, sort, extensions)
+    });
+    if req.background.unwrap_or(false) {
+        return (StatusCode::OK, Json(json!({ "status": "success", "scan_id": scan_id })));
+    }
+    let result = job.await.unwrap_or_else(|err| Err(anyhow!("library scan panicked: {}", err)));
+    match result {
+        Ok(outcome) => (
+            StatusCode::OK,
+            Json(json!({
+                "status": "success",
+                "scan_id": scan_id,
+                "tracks": outcome.tracks,
+                "cached": outcome.cached,
+                "cancelled": outcome.cancelled
+            })),
+        ),
+        Err(err) => scan_error_response(err),
+    }
+}
+
+fn scan_error_response(err: anyhow::Error) -> (StatusCode, Json<Value>) {
+    let status = match err.downcast_ref::<ScanError>() {
+        Some(ScanError::Busy) => StatusCode::CONFLICT,
+        _ => StatusCode::BAD_REQUEST,
+    };
+    (status, Json(json!({ "status": "error", "message": err.to_string() })))
+}
+
+async fn audio_extensions_handler(State(shared): State<SharedState>) -> impl IntoResponse {
+    let state = shared.inner.lock().unwrap();
+    Json(json!({ "status": "success", "extensions": state.audio_extensions.clone() }))
+}
+
+async fn set_audio_extensions_handler(
+    State(shared): State<SharedState>,
+    Json(req): Json<AudioExtensionsRequest>,
+) -> impl IntoResponse {
+    let extensions = if req.reset.unwrap_or(false) {
+        default_audio_extensions()
+    } else {
+        match normalize_audio_extensions(req.extensions.as_deref().unwrap_or_default()) {
+            Ok(extensions) => extensions,
+            Err(err) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({ "status": "error", "message": err.to_string() })),
+                );
+            }
+        }
+    };
+    shared.inner.lock().unwrap().audio_extensions = extensions.clone();
+    (StatusCode::OK, Json(json!({ "status": "success", "extensions": extensions })))
+}
+
+async fn scan_cancel_handler(State(shared): State<SharedState>) -> impl IntoResponse {
+    let cancelled = cancel_library_scan_impl(&shared);
+    Json(json!({ "status": "success", "cancelled": cancelled }))
+}
+
+/// Ranked matches from the library, for a client to offer as choices;
+/// unlike a "play" command, nothing is queued or played.
+async fn library_search_handler(
+    State(shared): State<SharedState>,
+    Json(req): Json<LibrarySearchRequest>,
+) -> impl IntoResponse {
+    match library_search_impl(&shared, &req.query, req.limit) {
+        Ok((total, tracks)) => (
+            StatusCode::OK,
+            Json(json!({ "status": "success", "total": total, "tracks": tracks })),
+        ),
+        Err(err) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "error", "message": err.to_string() })),
+        ),
+    }
+}
+
+async fn refresh_track_handler(
+    State(shared): State<SharedState>,
+    Json(req): Json<RefreshTrackRequest>,
+) -> impl IntoResponse {
+    match refresh_library_track_impl(&shared, &req.path) {
+        Ok((track, cover_path)) => (
+            StatusCode::OK,
+            Json(json!({
+                "status": "success",
+                "track": track,
+                "cover_path": cover_path.map(|p| p.to_string_lossy().to_string())
+            })),
+        ),
+        Err(err) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "error", "message": err.to_string() })),
+        ),
+    }
+}
+
+async fn metadata_write_handler(
+    State(shared): State<SharedState>,
+    Json(req): Json<MetadataWriteRequest>,
+) -> impl IntoResponse {
+    let path = req.path.clone();
+    let written = tokio::task::spawn_blocking(move || tag_writer::write_tags(Path::new(&req.path), &req.tags))
+        .await
+        .unwrap_or_else(|err| Err(anyhow!("tag write panicked: {}", err)));
+    if let Err(err) = written {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "error", "message": err.to_string() })),
+        );
+    }
+    match refresh_library_track_blocking(&shared, path).await {
+        Ok((track, _)) => (StatusCode::OK, Json(json!({ "status": "success", "track": track }))),
+        Err(err) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "status": "error", "message": err.to_string() })),
+        ),
+    }
+}
+
+fn read_cover_request_image(req: &MetadataCoverRequest) -> Result<Vec<u8>> {
+    match (req.image_path.as_deref(), req.image_base64.as_deref()) {
+        (Some(path), None) => {
+            let len = std::fs::metadata(path)
+                .with_context(|| format!("cannot read image {}", path))?
+                .len();
+            if len > tag_writer::MAX_COVER_BYTES as u64 {
+                return Err(anyhow!(
+                    "cover image is {} bytes, limit is {}",
+                    len,
+                    tag_writer::MAX_COVER_BYTES
+                ));
+            }
+            Ok(std::fs::read(path)?)
+        }
+        (None, Some(encoded)) => {
+            use base64::Engine as _;
+            base64::engine::general_purpose::STANDARD
+                .decode(encoded.trim())
+                .map_err(|err| anyhow!("invalid image_base64: {}", err))
+        }
+        _ => Err(anyhow!("provide exactly one of image_path or image_base64")),
+    }
+}
+
+async fn metadata_cover_handler(
+    State(shared): State<SharedState>,
+    Json(req): Json<MetadataCoverRequest>,
+) -> impl IntoResponse {
+    let path = req.path.clone();
+    let written = tokio::task::spawn_blocking(move || {
+        read_cover_request_image(&req).and_then(|image| {
+            tag_writer::write_cover(
+                Path::new(&req.path),
+                &image,
+                req.remove_existing.unwrap_or(false),
+            )
+        })
+    })
+    .await
+    .unwrap_or_else(|err| Err(anyhow!("cover write panicked: {}", err)));
+    if let Err(err) = written {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "error", "message": err.to_string() })),
+        );
+    }
+    // The refresh drops the cached cover and extracts the new one.
+    match refresh_library_track_blocking(&shared, path).await {
+        Ok((track, cover_path)) => (
+            StatusCode::OK,
+            Json(json!({
+                "status": "success",
+                "track": track,
+                "cover_path": cover_path.map(|p| p.to_string_lossy().to_string()),
+            })),
+        ),
+        Err(err) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "status": "error", "message": err.to_string() })),
+        ),
+    }
+}
+
+/// Fingerprint of a file's opening `FINGERPRINT_MAX_SECONDS`, on the
+/// blocking pool like `/analyze/key`.
+async fn fingerprint_handler(Json(req): Json<FingerprintRequest>) -> impl IntoResponse {
+    let path = req.path.clone();
+    let result = tokio::task::spawn_blocking(move || fingerprint_file_impl(&path))
+        .await
+        .unwrap_or_else(|err| Err(anyhow!("fingerprint panicked: {}", err)));
+    match result {
+        Ok((fingerprint, cached)) => (
+            StatusCode::OK,
+            Json(json!({
+                "status": "success",
+                "algorithm": fingerprint.algorithm,
+                "duration": fingerprint.duration,
+                "fingerprint": fingerprint.hashes,
+                "cached": cached,
+            })),
+        ),
+        Err(err) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "error", "message": err.to_string() })),
+        ),
+    }
+}
+
+/// Key estimate for a file; decoding the whole track is slow, so this runs
+/// on the blocking pool. `key` is null when nothing tonal was found.
+async fn key_handler(Json(req): Json<FingerprintRequest>) -> impl IntoResponse {
+    let path = req.path.clone();
+    let result = tokio::task::spawn_blocking(move || key_file_impl(&path))
+        .await
+        .unwrap_or_else(|err| Err(anyhow!("key analysis panicked: {}", err)));
+    match result {
+        Ok((estimate, cached)) => (
+            StatusCode::OK,
+            Json(json!({
+                "status": "success",
+                "key": estimate.as_ref().map(|e| e.key.clone()),
+                "tonic": estimate.as_ref().map(|e| e.tonic),
+                "mode": estimate.as_ref().map(|e| e.mode),
+                "confidence": estimate.as_ref().map_or(0.0, |e| e.confidence),
+                "cached": cached,
+            })),
+        ),
+        Err(err) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "error", "message": err.to_string() })),
+        ),
+    }
+}
+
+/// Tempo estimate for a file, on the blocking pool like `/analyze/key`.
+/// `bpm` is null when no steady pulse was found.
+async fn bpm_handler(Json(req): Json<FingerprintRequest>) -> impl IntoResponse {
+    let path = req.path.clone();
+    let result = tokio::task::spawn_blocking(move || tempo_file_impl(&path))
+        .await
+        .unwrap_or_else(|err| Err(anyhow!("tempo analysis panicked: {}", err)));
+    match result {
+        Ok((estimate, cached)) => (
+            StatusCode::OK,
+            Json(json!({
+                "status": "success",
+                "bpm": estimate.as_ref().map(|e| e.bpm),
+                "confidence": estimate.as_ref().map_or(0.0, |e| e.confidence),
+                "cached": cached,
+            })),
+        ),
+        Err(err) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "error", "message": err.to_string() })),
+        ),
+    }
+}
+
+async fn queue_add_handler(
+    State(shared): State<SharedState>,
+    Json(req): Json<QueueAddRequest>,
+) -> impl IntoResponse {
+    let replace = req.replace.unwrap_or(false);
+    let count = match req.at {
+        Some(_) if replace => Err(anyhow!("at can't be combined with replace")),
+        Some(at) => queue_insert_impl(&shared, req.tracks, at),
+        None => Ok(queue_add_impl(&shared, req.tracks, replace)),
+    };
+    match count {
+        Ok(count) => (StatusCode::OK, Json(json!({ "status": "success", "count": count }))),
+        Err(err) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "error", "message": err.to_string() })),
+        ),
+    }
+}
+
+async fn queue_move_handler(
+    State(shared): State<SharedState>,
+    Json(req): Json<QueueMoveRequest>,
+) -> impl IntoResponse {
+    if let Err(err) = queue_move_impl(&shared, req.from, req.to) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "error", "message": err.to_string() })),
+        );
+    }
+    let queue_index = shared.inner.lock().unwrap().queue_index;
+    send_state(&shared);
+    (StatusCode::OK, Json(json!({ "status": "success", "queue_index": queue_index })))
+}
+
+async fn queue_list_handler(State(shared): State<SharedState>) -> impl IntoResponse {
+    let (queue, queue_index) = queue_list_impl(&shared);
+    Json(json!({ "status": "success", "queue": queue, "queue_index": queue_index }))
+}
+
+async fn queue_remove_handler(
+    State(shared): State<SharedState>,
+    Json(req): Json<QueueRemoveRequest>,
+) -> impl IntoResponse {
+    let indices: Vec<usize> = req.index.into_iter().chain(req.indices.unwrap_or_default()).collect();
+    if indices.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "error", "message": "index or indices required" })),
+        );
+    }
+    match queue_remove_impl(&shared, &indices) {
+        Ok(count) => {
+            let queue_index = shared.inner.lock().unwrap().queue_index;
+            send_state(&shared);
+            (
+                StatusCode::OK,
+                Json(json!({ "status": "success", "count": count, "queue_index": queue_index })),
+            )
+        }
+        Err(err) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "error", "message": err.to_string() })),
+        ),
+    }
+}
+
+async fn queue_clear_handler(State(shared): State<SharedState>) -> impl IntoResponse {
+    queue_clear_impl(&shared);
+    send_state(&shared);
+    Json(json!({ "status": "success", "count": 0 }))
+}
+
+/// Load an M3U, PLS or ASX playlist: local files go on the queue (replacing
+/// it when asked), stream entries come back for the client to pick from.
+/// Remote and nested playlists are fetched, so this runs on the blocking pool.
+async fn playlist_load_handler(
+    State(shared): State<SharedState>,
+    Json(req): Json<PlaylistLoadRequest>,
+) -> impl IntoResponse {
+    let path = req.path.clone();
+    // Reading the playlist and probing its files both block.
+    let result = tokio::task::spawn_blocking(move || {
+        let loaded = load_playlist_impl(&path)?;
+        let tracks: Vec<LibraryTrack> = loaded
+            .files
+            .iter()
+            .map(|entry| {
+                let mut track = read_library_track_or_fallback(Path::new(&entry.location));
+                if track.title.is_none() {
+                    track.title = entry.title.clone();
+                }
+                track
+            })
+            .collect();
+        Ok((loaded, tracks))
+    })
+    .await
+    .unwrap_or_else(|err| Err(anyhow!("playlist load panicked: {}", err)));
+    let (loaded, tracks) = match result {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "status": "error", "message": err.to_string() })),
+            )
+        }
+    };
+    let replace = req.replace.unwrap_or(false);
+    let queued = tracks.len();
+    let count = if queued > 0 || replace {
+        queue_add_impl(&shared, tracks, replace)
+    } else {
+        shared.inner.lock().unwrap().queue.len()
+    };
+    (
+        StatusCode::OK,
+        Json(json!({
+            "status": "success",
+            "kind": loaded.kind,
+            "queued": queued,
+            "count": count,
+            "streams": loaded.streams,
+            "skipped": loaded.skipped,
+        })),
+    )
+}
+
+async fn queue_mode_handler(
+    State(shared): State<SharedState>,
+    Json(req): Json<QueueModeRequest>,
+) -> impl IntoResponse {
+    let repeat_mode = req.repeat_mode.map(|mode| mode.to_ascii_lowercase());
+    if let Some(mode) = repeat_mode.as_deref().filter(|mode| !REPEAT_MODES.contains(mode)) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "error", "message": format!("unknown repeat_mode: {}", mode) })),
+        );
+    }
+    {
+        let mut state = shared.inner.lock().unwrap();
+        if let Some(mode) = repeat_mode {
+            state.repeat_mode = mode;
+        }
+        if let Some(shuffle) = req.shuffle {
+            if shuffle != state.shuffle {
+                state.shuffle_history.clear();
+            }
+            state.shuffle = shuffle;
+        }
+        if let Some(seed) = req.seed {
+            state.shuffle_rng = seed;
+        }
+        if let Some(auto_advance) = req.auto_advance {
+            state.auto_advance = auto_advance;
+        }
+    }
+    send_state(&shared);
+    let state = shared.inner.lock().unwrap();
+    (StatusCode::OK, Json(json!({ "status": "success", "state": build_state_view(&state) })))
+}
+
+/// Loop a section of the current file, or all of it without bounds; a
+/// position outside the new loop jumps to its start.
+async fn loop_handler(State(shared): State<SharedState>, Json(req): Json<LoopRequest>) -> impl IntoResponse {
+    if req.enabled == Some(false) {
+        shared.inner.lock().unwrap().loop_region = None;
+        send_state(&shared);
+        let state = shared.inner.lock().unwrap();
+        return (StatusCode::OK, Json(json!({ "status": "success", "state": build_state_view(&state) })));
+    }
+    let (start, end, outside) = {
+        let state = shared.inner.lock().unwrap();
+        if state.mode != "file" || state.sample_rate == 0 {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "status": "error", "message": "loop only supported in file mode" })),
+            );
+        }
+        if state.incremental.is_some() {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "status": "error", "message": "loop needs the whole file, which is still decoding" })),
+            );
+        }
+        let rate = state.sample_rate as f64;
+        let total = state.data.len() / state.channels.max(1);
+        let start_secs = req.start.unwrap_or(0.0);
+        let end_secs = req.end.unwrap_or(total as f64 / rate);
+        let start = (start_secs * rate) as usize;
+        let end = ((end_secs * rate) as usize).min(total);
+        if !start_secs.is_finite() || !end_secs.is_finite() || start_secs < 0.0 || start >= end {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "status": "error",
+                    "message": format!(
+                        "loop needs 0 <= start < end within the track, got {} to {}",
+                        start_secs, end_secs
+                    ),
+                })),
+            );
+        }
+        (start, end, !(start..end).contains(&state.position))
+    };
+    let _ = run_transport_change(&shared, move |shared| {
+        let _fade = outside.then(|| fade_out_transport(shared));
+        let mut state = shared.inner.lock().unwrap();
+        state.loop_region = Some((start, end));
+        if outside {
+            state.position = start;
+            state.eq_filters.reset();
+            cancel_crossfade(&mut state);
+        }
+        Ok(())
+    })
+    .await;
+    send_state(&shared);
+    let state = shared.inner.lock().unwrap();
+    (StatusCode::OK, Json(json!({ "status": "success", "state": build_state_view(&state) })))
+}
+
+async fn queue_next_handler(State(shared): State<SharedState>) -> impl IntoResponse {
+    match queue_next_impl(&shared) {
+        Ok(Some(track)) => (StatusCode::OK, Json(json!({ "status": "success", "track": track }))),
+        Ok(None) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "error", "message": "queue empty" })),
+        ),
+        Err(err) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "status": "error", "message": err.to_string() })),
+        ),
+    }
+}
+
+async fn queue_prev_handler(State(shared): State<SharedState>) -> impl IntoResponse {
+    match queue_prev_impl(&shared) {
+        Ok(Some(track)) => (StatusCode::OK, Json(json!({ "status": "success", "track": track }))),
+        Ok(None) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "error", "message": "no previous track" })),
+        ),
+        Err(err) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "status": "error", "message": err.to_string() })),
+        ),
+    }
+}
+
+/// Whether the next queued track can follow the current one gaplessly,
+/// for clients choosing between gapless and crossfade. Queue entries added
+/// without a scan get probed, so this runs on the blocking pool.
+async fn gapless_check_handler(State(shared): State<SharedState>) -> impl IntoResponse {
+    match tokio::task::spawn_blocking(move || gapless_check_impl(&shared)).await {
+        Ok(check) => (StatusCode::OK, Json(json!({ "status": "success", "check": check }))),
+        Err(err) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "status": "error", "message": err.to_string() })),
+        ),
+    }
+}
+
+async fn command_handler(
+    State(shared): State<SharedState>,
+    Json(req): Json<CommandRequest>,
+) -> impl IntoResponse {
+    let disambiguate = req.disambiguate.unwrap_or(false);
+    let parsed = if let Some(action) = req.action.clone().filter(|a| !a.trim().is_empty()) {
+        ParsedCommand {
+            action,
+            query: req.query.clone().filter(|q| !q.trim().is_empty()),
+            raw: req.text.unwrap_or_default(),
+            disambiguate,
+            ..Default::default()
+        }
+    } else if let Some(text) = req.text.as_ref() {
+        match parse_command_text(text) {
+            Some(cmd) => ParsedCommand { disambiguate, ..cmd },
+            None => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({ "status": "error", "message": "command text empty" })),
+                )
+            }
+        }
+    } else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "error", "message": "command missing" })),
+        );
+    };
+
+    let command = parsed.clone();
+    match run_transport_change(&shared, move |shared| handle_command_impl(shared, command)).await {
+        Ok(result) => (
+            StatusCode::OK,
+            Json(json!({
+                "status": "success",
+                "action": result.action,
+                "matches": result.matches,
+                "track": result.track,
+                "ambiguous": !result.candidates.is_empty(),
+                "candidates": result.candidates,
+                "volume": result.volume,
+                "position": result.position,
+                "raw": parsed.raw
+            })),
+        ),
+        Err(err) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "status": "error",
+                "action": parsed.action,
+                "message": err.to_string(),
+                "raw": parsed.raw
+            })),
+        ),
+    }
+}
+
+async fn cover_handler(
+    State(_shared): State<SharedState>,
+    Json(req): Json<CoverRequest>,
+) -> impl IntoResponse {
+    let path = Path::new(&req.path);
+    if !path.exists() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "error", "message": "file not found" })),
+        );
+    }
+    let inline = req.inline.unwrap_or(false);
+    match extract_cover_art(path) {
+        Ok(Some((data, media_type))) if inline && data.len() <= INLINE_COVER_MAX_BYTES => (
+            StatusCode::OK,
+            Json(json!({
+                "status": "success",
+                "data_uri": cover_data_uri(&data, &media_type),
+                "media_type": media_type,
+                "inline": true
+            })),
+        ),
+        Ok(Some((data, media_type))) => match write_cover_file(path, &data, &media_type) {
+            Ok(saved) => (
+                StatusCode::OK,
+                Json(json!({
+                    "status": "success",
+                    "cover_path": saved.to_string_lossy().to_string(),
+                    "media_type": media_type,
+                    "inline": false
+                })),
+            ),
+            Err(err) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "status": "error", "message": err.to_string() })),
+            ),
+        },
+        Ok(None) => (
+            StatusCode::OK,
+            Json(json!({ "status": "success", "cover_path": null })),
+        ),
+        Err(err) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "status": "error", "message": err.to_string() })),
+        ),
+    }
+}
+
+async fn lyrics_handler(
+    State(_shared): State<SharedState>,
+    Json(req): Json<LyricsRequest>,
+) -> impl IntoResponse {
+    let path = Path::new(&req.path);
+    if !path.exists() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "error", "message": "file not found" })),
+        );
+    }
+    match extract_lyrics(path) {
+        Ok(lyrics) => {
+            let synced = lyrics.as_deref().is_some_and(is_synced_lyrics);
+            (
+                StatusCode::OK,
+                Json(json!({ "status": "success", "lyrics": lyrics, "synced": synced })),
+            )
+        }
+        Err(err) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "status": "error", "message": err.to_string() })),
+        ),
+    }
+}
+
+fn load_request_options(shared: &SharedState, req: &LoadRequest) -> Result<DecodeOptions> {
+    let mut options = decode_options_for(&shared.inner.lock().unwrap());
+    if let Some(value) = req.gapless_trim {
+        options.gapless_trim = value;
+    }
+    if let Some(mode) = req.channel_mode.as_deref() {
+        options.channels = parse_channel_mode(mode, req.select_channels.clone())?;
+    }
+    Ok(options)
+}
+
+fn load_error_status(err: &anyhow::Error) -> StatusCode {
+    match err.downcast_ref::<LoadError>() {
+        Some(LoadError::Superseded(_)) => StatusCode::CONFLICT,
+        Some(_) => StatusCode::BAD_REQUEST,
+        None => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+async fn load_handler(State(shared): State<SharedState>, Json(req): Json<LoadRequest>) -> impl IntoResponse {
+    let options = match load_request_options(&shared, &req) {
+        Ok(options) => options,
+        Err(err) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "status": "error", "message": err.to_string() })),
+            );
+        }
+    };
+    // Decoding a long file takes a while; keep it off the async workers.
+    let load_shared = shared.clone();
+    let result = tokio::task::spawn_blocking(move || load_file_with_options(&load_shared, req.path, options))
+        .await
+        .unwrap_or_else(|err| Err(anyhow!("load panicked: {}", err)));
+    match result {
+        Ok(_) => {
+            let state = shared.inner.lock().unwrap();
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "status": "success",
+                    "gapless_trim": state.gapless_trim,
+                    "state": build_state_view(&state)
+                })),
+            )
+        }
+        Err(err) => (
+            load_error_status(&err),
+            Json(json!({ "status": "error", "message": err.to_string() })),
+        ),
+    }
+}
+
+/// Start rendering a file through the processing chain to WAV or FLAC on a
+/// background thread. Progress and the result arrive as `export_progress`,
+/// `export_complete` and `export_error` events.
+async fn export_handler(State(shared): State<SharedState>, Json(req): Json<ExportRequest>) -> impl IntoResponse {
+    let bad_request = |message: String| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "error", "message": message })),
+        )
+    };
+    if !Path::new(&req.path).exists() {
+        return bad_request("File not found".to_string());
+    }
+    let output = PathBuf::from(&req.output_path);
+    let format = match ExportFormat::resolve(req.format.as_deref(), &output) {
+        Ok(format) => format,
+        Err(err) => return bad_request(err.to_string()),
+    };
+    if let Some(bits) = req.bit_depth {
+        if !format.supports_bits(bits) {
+            return bad_request(format!("{:?} export does not support {}-bit samples", format, bits));
+        }
+    }
+    if req.sample_rate == Some(0) {
+        return bad_request("sample_rate must be positive".to_string());
+    }
+    if req.volume.is_some_and(|volume| !volume.is_finite()) {
+        return bad_request("volume must be a number".to_string());
+    }
+    if is_same_file(Path::new(&req.path), &output) {
+        return bad_request("output path is the source file".to_string());
+    }
+    // The real output is only replaced once the render is complete.
+    let partial = export_partial_path(&output);
+    if let Err(err) = File::create(&partial) {
+        return bad_request(format!("output path is not writable: {}", err));
+    }
+
+    let job = ExportJob {
+        source: req.path.clone(),
+        output,
+        partial,
+        format,
+        sample_rate: req.sample_rate,
+        bit_depth: req.bit_depth,
+        volume: req.volume.unwrap_or(1.0),
+    };
+    let export_shared = shared.clone();
+    thread::spawn(move || {
+        let mut last_progress = Instant::now();
+        let result = export_impl(&export_shared, &job, |fraction| {
+            if last_progress.elapsed() >= EXPORT_PROGRESS_INTERVAL {
+                last_progress = Instant::now();
+                let payload = json!({
+                    "type": "export_progress",
+                    "path": job.source,
+                    "output_path": job.output,
+                    "progress": fraction,
+                });
+                let _ = export_shared.tx.send(payload.to_string());
+            }
+        });
+        let payload = match result {
+            Ok(spec) => json!({
+                "type": "export_complete",
+                "path": job.source,
+                "output_path": job.output,
+                "format": spec,
+            }),
+            Err(err) => {
+                error!("export of {} failed: {}", job.source, err);
+                let _ = std::fs::remove_file(&job.partial);
+                json!({
+                    "type": "export_error",
+                    "path": job.source,
+                    "output_path": job.output,
+                    "message": err.to_string(),
+                })
+            }
+        };
+        let _ = export_shared.tx.send(payload.to_string());
+    });
+    (
+        StatusCode::OK,
+        Json(json!({ "status": "success", "output_path": req.output_path, "format": format })),
+    )
+}
+
+/// Decode a track ahead of time; a `/load` of the same path with the same
+/// options and output target then starts from the cached PCM.
+async fn preload_handler(State(shared): State<SharedState>, Json(req): Json<LoadRequest>) -> impl IntoResponse {
+    let options = match load_request_options(&shared, &req) {
+        Ok(options) => options,
+        Err(err) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "status": "error", "message": err.to_string() })),
+            );
+        }
+    };
+    let preload_shared = shared.clone();
+    let path = req.path.clone();
+    let result = tokio::task::spawn_blocking(move || preload_impl(&preload_shared, &path, &options))
+        .await
+        .unwrap_or_else(|err| Err(anyhow!("preload panicked: {}", err)));
+    match result {
+        Ok(duration) => (
+            StatusCode::OK,
+            Json(json!({ "status": "success", "path": req.path, "duration": duration })),
+        ),
+        Err(err) => (
+            load_error_status(&err),
+            Json(json!({ "status": "error", "message": err.to_string() })),
+        ),
+    }
+}
+
+async fn play_handler(State(shared): State<SharedState>) -> impl IntoResponse {
+    let _ = play_impl(&shared);
+    let state = shared.inner.lock().unwrap();
+    Json(json!({ "status": "success", "state": build_state_view(&state) }))
+}
+
+async fn pause_handler(State(shared): State<SharedState>) -> impl IntoResponse {
+    let _ = run_transport_change(&shared, pause_impl).await;
+    let state = shared.inner.lock().unwrap();
+    Json(json!({ "status": "success", "state": build_state_view(&state) }))
+}
+
+async fn stop_handler(State(shared): State<SharedState>) -> impl IntoResponse {
+    let _ = run_transport_change(&shared, stop_impl).await;
+    let state = shared.inner.lock().unwrap();
+    Json(json!({ "status": "success", "state": build_state_view(&state) }))
+}
+
+async fn seek_handler(State(shared): State<SharedState>, Json(req): Json<SeekRequest>) -> impl IntoResponse {
+    if shared.inner.lock().unwrap().mode == "stream" {
+        return stream_seek_response(&shared, req.position).await;
+    }
+    let position = req.position;
+    if let Err(err) = run_transport_change(&shared, move |shared| seek_file_impl(shared, position)).await {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "status": "error",
+            "message": err.to_string()
+        })));
+    }
+    let state = shared.inner.lock().unwrap();
+    (StatusCode::OK, Json(json!({
+        "status": "success",
+        "state": build_state_view(&state)
+    })))
+}
+
+/// Seek the loaded file to `seconds`, fading around the jump; a position
+/// past the end is an error. Returns where it landed.
+fn seek_file_impl(shared: &SharedState, seconds: f64) -> Result<f64> {
+    let _fade = fade_out_transport(shared);
+    let mut state = shared.inner.lock().unwrap();
+    if state.mode != "file" {
+        return Err(anyhow!("seek only supported in file and stream modes"));
+    }
+    if state.sample_rate == 0 {
+        return Err(anyhow!("invalid sample rate"));
+    }
+    let new_pos = (seconds * state.sample_rate as f64) as usize;
+    if new_pos >= file_frames(&state) {
+        return Err(anyhow!("seek out of range"));
+    }
+    set_file_position(&mut state, new_pos);
+    state.eq_filters.reset();
+    cancel_crossfade(&mut state);
+    Ok(new_pos as f64 / state.sample_rate as f64)
+}
+
+async fn seek_relative_handler(
+    State(shared): State<SharedState>,
+    Json(req): Json<SeekRelativeRequest>,
+) -> impl IntoResponse {
+    let stream_position = {
+        let state = shared.inner.lock().unwrap();
+        (state.mode == "stream" && state.sample_rate > 0)
+            .then(|| state.played_frames as f64 / state.sample_rate as f64)
+    };
+    if let Some(current) = stream_position {
+        return stream_seek_response(&shared, current + req.delta).await;
+    }
+    let delta = req.delta;
+    match run_transport_change(&shared, move |shared| seek_file_relative_impl(shared, delta)).await {
+        Ok(position) => {
+            let state = shared.inner.lock().unwrap();
+            (StatusCode::OK, Json(json!({
+                "status": "success",
+                "position": position,
+                "state": build_state_view(&state)
+            })))
+        }
+        Err(err) => (StatusCode::BAD_REQUEST, Json(json!({
+            "status": "error",
+            "message": err.to_string()
+        }))),
+    }
+}
+
+/// Move the loaded file's position by `delta` seconds, clamped to the
+/// track, fading around the jump. Returns where it landed.
+fn seek_file_relative_impl(shared: &SharedState, delta: f64) -> Result<f64> {
+    let _fade = fade_out_transport(shared);
+    let mut state = shared.inner.lock().unwrap();
+    if state.mode != "file" {
+        return Err(anyhow!("seek only supported in file and stream modes"));
+    }
+    if state.sample_rate == 0 || !delta.is_finite() {
+        return Err(anyhow!("invalid seek"));
+    }
+    // Read and move the position under one lock so playback can't advance
+    // between the two.
+    let current = state.position as f64 / state.sample_rate as f64;
+    Ok(seek_clamped(&mut state, current + delta))
+}
+
+async fn stream_seek_response(shared: &SharedState, seconds: f64) -> (StatusCode, Json<serde_json::Value>) {
+    let result = run_transport_change(shared, move |shared| {
+        let _fade = fade_out_transport(shared);
+        seek_stream_impl(shared, seconds)
+    })
+    .await;
+    match result {
+        Ok(position) => {
+            let state = shared.inner.lock().unwrap();
+            (StatusCode::OK, Json(json!({
+                "status": "success",
+                "position": position,
+                "state": build_state_view(&state)
+            })))
+        }
+        Err(err) => (StatusCode::BAD_REQUEST, Json(json!({
+            "status": "error",
+            "message": err.to_string()
+        }))),
+    }
+}
+
+async fn volume_handler(State(shared): State<SharedState>, Json(req): Json<VolumeRequest>) -> impl IntoResponse {
+    {
+        let mut state = shared.inner.lock().unwrap();
+        set_volume_target(&mut state, req.volume);
+    }
+    mark_settings_changed(&shared);
+    send_state(&shared);
+    let state = shared.inner.lock().unwrap();
+    Json(json!({ "status": "success", "state": build_state_view(&state) }))
+}
+
+async fn configure_output_handler(State(shared): State<SharedState>, Json(req): Json<ConfigureOutputRequest>) -> impl IntoResponse {
+    let worker = shared.clone();
+    // One device enumeration, checked before any setting changes.
+    let result = tokio::task::spawn_blocking(move || {
+        let selected = checked_device_key(req.device_id)?;
+        {
+            let mut state = worker.inner.lock().unwrap();
+            if let Some(latency_ms) = req.latency_ms {
+                state.output_latency_ms = Some(latency_ms).filter(|ms| *ms > 0);
+            }
+            if let Some(format) = req.exclusive_format {
+                state.exclusive_format = normalize_exclusive_format(&format);
+            }
+            if let Some(mode) = req.volume_mode {
+                state.volume_mode = normalize_volume_mode(&mode);
+            }
+        }
+        select_output_device(&worker, req.device_id, selected, req.exclusive);
+        Ok(())
+    })
+    .await
+    .unwrap_or_else(|err| Err(anyhow!("output configuration panicked: {}", err)));
+    if let Err(err) = result {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "error", "message": err.to_string() })),
+        );
+    }
+    mark_settings_changed(&shared);
+    let state = shared.inner.lock().unwrap();
+    (StatusCode::OK, Json(json!({ "status": "success", "state": build_state_view(&state) })))
+}
+
+async fn track_gain_handler(State(shared): State<SharedState>, Json(req): Json<TrackGainRequest>) -> impl IntoResponse {
+    let worker = shared.clone();
+    let result = tokio::task::spawn_blocking(move || set_track_gain_impl(&worker, req.gain_db))
+        .await
+        .unwrap_or_else(|err| Err(anyhow!("track gain panicked: {}", err)));
+    match result {
+        Ok((gain_db, clips)) => {
+            send_state(&shared);
+            let state = shared.inner.lock().unwrap();
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "status": "success",
+                    "gain_db": gain_db,
+                    "clipping": clips,
+                    "state": build_state_view(&state)
+                })),
+            )
+        }
+        Err(err) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "error", "message": err.to_string() })),
+        ),
+    }
+}
+
+async fn hostapis_handler(State(shared): State<SharedState>) -> impl IntoResponse {
+    let preferred = shared.inner.lock().unwrap().hostapi.clone();
+    let listed = preferred.clone();
+    // Counting each host's devices can stall on a busy driver.
+    let hostapis = tokio::task::spawn_blocking(move || list_hostapis(listed.as_deref()))
+        .await
+        .unwrap_or_default();
+    Json(json!({
+        "status": "success",
+        "hostapis": hostapis,
+        "preferred": preferred,
+    }))
+}
+
+async fn set_hostapi_handler(State(shared): State<SharedState>, Json(req): Json<HostApiRequest>) -> impl IntoResponse {
+    let worker = shared.clone();
+    let result = tokio::task::spawn_blocking(move || set_hostapi_impl(&worker, req.name))
+        .await
+        .unwrap_or_else(|err| Err(anyhow!("host api change panicked: {}", err)));
+    if let Err(err) = result {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "error", "message": err.to_string() })),
+        );
+    }
+    mark_settings_changed(&shared);
+    let state = shared.inner.lock().unwrap();
+    (StatusCode::OK, Json(json!({ "status": "success", "state": build_state_view(&state) })))
+}
+
+async fn configure_upsampling_handler(State(shared): State<SharedState>, Json(req): Json<ConfigureUpsamplingRequest>) -> impl IntoResponse {
+    {
+        let mut state = shared.inner.lock().unwrap();
+        state.target_samplerate = req.target_samplerate;
+    }
+    mark_settings_changed(&shared);
+    send_state(&shared);
+    let state = shared.inner.lock().unwrap();
+    Json(json!({ "status": "success", "state": build_state_view(&state) }))
+}
+
+async fn set_eq_handler(State(shared): State<SharedState>, Json(req): Json<EqRequest>) -> impl IntoResponse {
+    let mut state = shared.inner.lock().unwrap();
+    if let Some(enabled) = req.enabled {
+        if enabled && !state.eq_enabled {
+            // Don't resume from whatever the filters last saw.
+            state.eq_filters.reset();
+        }
+        state.eq_enabled = enabled;
+    }
+    if let Some(bands) = req.bands {
+        for (k, v) in bands {
+            if let Some(entry) = state.eq_bands.get_mut(&k) {
+                *entry = v.clamp(-eq::EQ_MAX_GAIN_DB, eq::EQ_MAX_GAIN_DB);
+            }
+        }
+        refresh_eq_filters(&mut state);
+    }
+    let response = Json(json!({ "status": "success", "state": build_state_view(&state) }));
+    drop(state);
+    spawn_eq_design(&shared);
+    mark_settings_changed(&shared);
+    response
+}
+
+fn spawn_eq_design(shared: &SharedState) {
+    let shared = shared.clone();
+    tokio::task::spawn_blocking(move || design_eq_filters(&shared));
+}
+
+/// Switch between the biquad EQ and the linear-phase FIR one, rebuilding
+/// the filters for the current bands.
+async fn set_eq_type_handler(State(shared): State<SharedState>, Json(req): Json<EqTypeRequest>) -> impl IntoResponse {
+    let Some(kind) = EqKind::from_label(&req.r#type) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "error", "message": format!("unknown eq type: {}", req.r#type) })),
+        );
+    };
+    let mut state = shared.inner.lock().unwrap();
+    let fir_taps = req.fir_taps.unwrap_or(state.eq_filters.fir_taps());
+    state.eq_type = kind.label().to_string();
+    state.eq_filters.set_kind(kind, fir_taps);
+    refresh_eq_filters(&mut state);
+    let view = build_state_view(&state);
+    drop(state);
+    spawn_eq_design(&shared);
+    mark_settings_changed(&shared);
+    (StatusCode::OK, Json(json!({ "status": "success", "state": view })))
+}
+
+async fn configure_opt_handler(State(shared): State<SharedState>, Json(req): Json<OptimizeRequest>) -> impl IntoResponse {
+    let mut state = shared.inner.lock().unwrap();
+    if let Some(value) = req.dither_type {
+        let normalized = normalize_dither_type(&value);
+        if normalized != state.dither_type {
+            reset_dither_shape_state(&mut state);
+        }
+        state.dither_type = normalized.clone();
+        if normalized == "off" {
+            state.dither_enabled = false;
+        }
+    }
+    if let Some(bits) = req.dither_bits {
+        state.dither_bits = normalize_dither_bits(bits);
+    }
+    if let Some(val) = req.dither_enabled {
+        state.dither_enabled = val;
+        if !val {
+            state.dither_type = "off".to_string();
+            reset_dither_shape_state(&mut state);
+        } else if state.dither_type == "off" {
+            state.dither_type = "tpdf".to_string();
+        }
+    }
+    if let Some(val) = req.replaygain_enabled {
+        state.replaygain_enabled = val;
+    }
+    if let Some(value) = req.replaygain_preamp_db {
+        state.replaygain_preamp_db = normalize_replaygain_preamp(value);
+    }
+    if let Some(value) = req.replaygain_prevent_clipping {
+        state.replaygain_prevent_clipping = value;
+    }
+    if let Some(value) = req.replaygain_mode {
+        state.replaygain_mode = normalize_replaygain_mode(&value);
+    }
+    if let Some(value) = req.auto_level_enabled {
+        if value != state.auto_level_enabled {
+            reset_auto_level(&mut state);
+        }
+        state.auto_level_enabled = value;
+    }
+    if let Some(value) = req.auto_level_target_db.filter(|v| v.is_finite()) {
+        state.auto_level_target_db = value.clamp(AUTO_LEVEL_MIN_TARGET_DB, AUTO_LEVEL_MAX_TARGET_DB);
+    }
+    if let Some(value) = req.auto_level_max_gain_db.filter(|v| v.is_finite()) {
+        state.auto_level_max_gain_db = value.clamp(0.0, AUTO_LEVEL_MAX_GAIN_LIMIT_DB);
+    }
+    if let Some(value) = req.gapless_trim {
+        state.gapless_trim_enabled = value;
+    }
+    refresh_replaygain_gain(&mut state);
+    if let Some(value) = req.resampler_mode {
+        state.resampler_mode = normalize_resampler_mode(&value);
+    }
+    if let Some(value) = req.resampler_quality {
+        state.resampler_quality = normalize_resampler_quality(&value);
+    }
+    if let Some(value) = req.limiter_enabled {
+        state.limiter_enabled = value;
+    }
+    if let Some(value) = req.limiter_threshold {
+        state.limiter_threshold = parse_limiter_threshold(value);
+    }
+    if let Some(value) = req.limiter_threshold_db {
+        state.limiter_threshold = normalize_limiter_threshold(db_to_linear(value.min(0.0)));
+    }
+    if let Some(value) = req.invert_polarity {
+        state.invert_polarity = value;
+    }
+    if let Some(channels) = req.invert_channels {
+        state.invert_channels = normalize_invert_channels(channels);
+    }
+    if let Some(value) = req.idle_release_secs {
+        state.idle_release_secs = Some(value).filter(|secs| *secs > 0);
+    }
+    if let Some(value) = req.live_pause {
+        state.live_pause_mode = normalize_live_pause_mode(&value);
+    }
+    if let Some(value) = req.inter_track_silence_ms {
+        state.inter_track_silence_ms = value.min(INTER_TRACK_SILENCE_MAX_MS);
+    }
+    if let Some(value) = req.command_volume_step.filter(|step| step.is_finite()) {
+        state.command_volume_step = value.clamp(COMMAND_VOLUME_STEP_MIN, 1.0);
+    }
+    if let Some(value) = req.dsd_mode {
+        state.dsd_mode = normalize_dsd_mode(&value);
+    }
+    if let Some(value) = req.dsd_pcm_rate.filter(|rate| dsd::DSD_PCM_RATES.contains(rate)) {
+        state.dsd_pcm_rate = value;
+    }
+    if let Some(value) = req.incremental_decode_secs.filter(|secs| secs.is_finite()) {
+        state.incremental_decode_secs = value.max(0.0);
+    }
+    state.soxr_available = detect_soxr_available();
+    let response = Json(json!({ "status": "success", "state": build_state_view(&state) }));
+    drop(state);
+    mark_settings_changed(&shared);
+    response
+}
+async fn load_stream_handler(State(shared): State<SharedState>, Json(req): Json<StreamRequest>) -> impl IntoResponse {
+    if start_stream_impl(&shared, req.url).is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+            "status": "error",
+            "message": "failed to start ffmpeg"
+        })));
+    }
+    let state = shared.inner.lock().unwrap();
+    (StatusCode::OK, Json(json!({ "status": "success", "state": build_state_view(&state) })))
+}
+
+async fn restart_handler(State(shared): State<SharedState>) -> impl IntoResponse {
+    if let Err(err) = run_transport_change(&shared, restart_impl).await {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "error", "message": err.to_string() })),
+        );
+    }
+    let state = shared.inner.lock().unwrap();
+    (StatusCode::OK, Json(json!({ "status": "success", "state": build_state_view(&state) })))
+}
+
+async fn capture_start_handler(State(shared): State<SharedState>, Json(req): Json<CaptureStartRequest>) -> impl IntoResponse {
+    let monitor = CaptureMonitor {
+        enabled: req.monitor.unwrap_or(true),
+        device_id: req.monitor_device_id,
+        latency_ms: req.latency_ms,
+    };
+    if let Err(err) = start_capture_impl(&shared, req.device_id, req.samplerate, req.channels, monitor, req.loopback) {
+        let status = if err.downcast_ref::<CaptureError>().is_some() {
+            StatusCode::BAD_REQUEST
+        } else {
+            StatusCode::INTERNAL_SERVER_ERROR
+        };
+        return (
+            status,
+            Json(json!({
+                "status": "error",
+                "message": err.to_string()
+            })),
+        );
+    }
+    let state = shared.inner.lock().unwrap();
+    (
+        StatusCode::OK,
+        Json(json!({ "status": "success", "state": build_state_view(&state) })),
+    )
+}
+
+async fn capture_stop_handler(State(shared): State<SharedState>) -> impl IntoResponse {
+    if let Err(err) = stop_capture_impl(&shared) {
+        return Json(json!({ "status": "error", "message": err.to_string() }));
+    }
+    let state = shared.inner.lock().unwrap();
+    Json(json!({ "status": "success", "state": build_state_view(&state) }))
+}
+
+async fn spectrum_ws_handler(State(shared): State<SharedState>, Json(req): Json<SpectrumWsRequest>) -> impl IntoResponse {
+    {
+        let mut state = shared.inner.lock().unwrap();
+        state.spectrum_ws_enabled = req.enabled;
+    }
+    send_state(&shared);
+    let state = shared.inner.lock().unwrap();
+    Json(json!({ "status": "success", "state": build_state_view(&state) }))
+}
+
+async fn spectrum_config_handler(
+    State(shared): State<SharedState>,
+    Json(req): Json<SpectrumConfigRequest>,
+) -> impl IntoResponse {
+    if let Some(fft_size) = req.fft_size {
+        if !valid_spectrum_fft_size(fft_size) {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "status": "error",
+                    "message": format!(
+                        "fft_size must be a power of two from {} to {}",
+                        MIN_SPECTRUM_FFT_SIZE, MAX_SPECTRUM_FFT_SIZE
+                    ),
+                })),
+            );
         }
-        if odone > 0 {
-            output.extend_from_slice(&out_chunk[..odone * channels]);
+        let bins = req.bins.unwrap_or(shared.inner.lock().unwrap().spectrum_bins);
+        if bins > fft_size / 2 {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "status": "error",
+                    "message": format!("fft_size {} allows at most {} bins", fft_size, fft_size / 2),
+                })),
+            );
         }
-        offset += idone;
     }
+    if let Some(window) = &req.window {
+        if !SPECTRUM_WINDOWS.contains(&window.to_lowercase().as_str()) {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "status": "error",
+                    "message": format!("window must be one of {}", SPECTRUM_WINDOWS.join(", ")),
+                })),
+            );
+        }
+    }
+    for (name, value) in [("attack", req.attack), ("release", req.release)] {
+        if value.is_some_and(|value| !(0.0..=MAX_SPECTRUM_SMOOTHING).contains(&value)) {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "status": "error",
+                    "message": format!("{} must be between 0 and {}", name, MAX_SPECTRUM_SMOOTHING),
+                })),
+            );
+        }
+    }
+    if req.peak_fall.is_some_and(|fall| !(0.0..=MAX_SPECTRUM_PEAK_FALL).contains(&fall)) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "status": "error",
+                "message": format!("peak_fall must be between 0 and {}", MAX_SPECTRUM_PEAK_FALL),
+            })),
+        );
+    }
+    if let Some(bins) = req.bins {
+        let fft_size = req.fft_size.unwrap_or(shared.inner.lock().unwrap().spectrum_fft_size);
+        let max_bins = MAX_SPECTRUM_BINS.min(fft_size / 2);
+        if bins == 0 || bins > max_bins {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "status": "error",
+                    "message": format!("bins must be between 1 and {}", max_bins),
+                })),
+            );
+        }
+        // The analyzer loop picks this up and resizes the shm on its next tick.
+        shared.inner.lock().unwrap().spectrum_bins = bins;
+    }
+    if let Some(gate_db) = req.gate_db {
+        if !gate_db.is_finite() || !(MIN_SPECTRUM_GATE_DB..=0.0).contains(&gate_db) {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "status": "error",
+                    "message": format!("gate_db must be between {} and 0", MIN_SPECTRUM_GATE_DB),
+                })),
+            );
+        }
+        shared.inner.lock().unwrap().spectrum_gate_db = gate_db;
+    }
+    if let Some(source) = req.source {
+        let source = source.to_lowercase();
+        if !SPECTRUM_SOURCES.contains(&source.as_str()) {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "status": "error",
+                    "message": format!("source must be one of {}", SPECTRUM_SOURCES.join(", ")),
+                })),
+            );
+        }
+        shared.inner.lock().unwrap().spectrum_source = source;
+    }
+    // The analyzer loop rebuilds itself for these on its next tick.
+    if let Some(fft_size) = req.fft_size {
+        set_spectrum_fft_size(&mut shared.inner.lock().unwrap(), fft_size);
+    }
+    if let Some(window) = req.window {
+        shared.inner.lock().unwrap().spectrum_window = window.to_lowercase();
+    }
+    {
+        let mut state = shared.inner.lock().unwrap();
+        state.spectrum_attack = req.attack.unwrap_or(state.spectrum_attack);
+        state.spectrum_release = req.release.unwrap_or(state.spectrum_release);
+        state.spectrum_peak_fall = req.peak_fall.unwrap_or(state.spectrum_peak_fall);
+    }
+    send_state(&shared);
+    let state = shared.inner.lock().unwrap();
+    (StatusCode::OK, Json(json!({ "status": "success", "state": build_state_view(&state) })))
+}
 
-    loop {
-        let out_capacity_frames = 1024usize;
-        let mut out_chunk = vec![0.0f32; out_capacity_frames * channels];
-        let mut idone = 0usize;
-        let mut odone = 0usize;
-        let err = unsafe {
-            (instance.lib.process)(
-                instance.handle,
-                std::ptr::null(),
-                0,
-                &mut idone,
-                out_chunk.as_mut_ptr() as *mut c_void,
-                out_capacity_frames,
-                &mut odone,
-            )
+async fn capture_devices_handler() -> impl IntoResponse {
+    let devices = tokio::task::spawn_blocking(capture_devices).await.unwrap_or_default();
+    Json(json!({
+        "status": "success",
+        "devices": devices
+    }))
+}
+
+async fn buffer_state_handler(State(shared): State<SharedState>) -> impl IntoResponse {
+    let state = shared.inner.lock().unwrap();
+    Json(json!({
+        "status": "success",
+        "buffered_ms": if state.sample_rate > 0 {
+            (state.buffered_frames as f64 / state.sample_rate as f64) * 1000.0
+        } else {
+            0.0
+        },
+        "buffer_fill": buffer_fill_ratio(&state),
+        "resampler_latency_ms": live_resampler_latency_ms(&state),
+        "eq_latency_ms": eq_latency_ms(&state),
+        "underruns": state.underrun_count,
+        "dropped_samples": state.dropped_sample_count,
+        "clipped_samples": state.clip_count,
+        "mode": state.mode.clone()
+    }))
+}
+
+async fn diagnostics_reset_handler(State(shared): State<SharedState>) -> impl IntoResponse {
+    let previous = reset_diagnostics_impl(&shared);
+    Json(json!({ "status": "success", "previous": previous }))
+}
+
+fn batch_params<T: DeserializeOwned>(params: Value) -> Result<Json<T>, String> {
+    let params = if params.is_null() { json!({}) } else { params };
+    serde_json::from_value(params)
+        .map(Json)
+        .map_err(|err| format!("invalid params: {}", err))
+}
+
+/// Run one batched command through the same handler its endpoint uses.
+async fn run_batch_command(shared: &SharedState, op: &str, params: Value) -> Result<Response, String> {
+    let shared = State(shared.clone());
+    let response = match op.trim_start_matches('/') {
+        "state" => get_state_handler(shared).await.into_response(),
+        "resampler/status" => resampler_status_handler(shared).await.into_response(),
+        "load" => load_handler(shared, batch_params(params)?).await.into_response(),
+        "preload" => preload_handler(shared, batch_params(params)?).await.into_response(),
+        "play" => play_handler(shared).await.into_response(),
+        "pause" => pause_handler(shared).await.into_response(),
+        "stop" => stop_handler(shared).await.into_response(),
+        "restart" => restart_handler(shared).await.into_response(),
+        "seek" => seek_handler(shared, batch_params(params)?).await.into_response(),
+        "seek_relative" => seek_relative_handler(shared, batch_params(params)?).await.into_response(),
+        "loop" => loop_handler(shared, batch_params(params)?).await.into_response(),
+        "volume" => volume_handler(shared, batch_params(params)?).await.into_response(),
+        "track_gain" => track_gain_handler(shared, batch_params(params)?).await.into_response(),
+        "hostapis" => set_hostapi_handler(shared, batch_params(params)?).await.into_response(),
+        "configure_output" => configure_output_handler(shared, batch_params(params)?).await.into_response(),
+        "configure_upsampling" => configure_upsampling_handler(shared, batch_params(params)?)
+            .await
+            .into_response(),
+        "configure_crossfade" => configure_crossfade_handler(shared, batch_params(params)?)
+            .await
+            .into_response(),
+        "configure_downmix" => configure_downmix_handler(shared, batch_params(params)?)
+            .await
+            .into_response(),
+        "configure_optimizations" => configure_opt_handler(shared, batch_params(params)?).await.into_response(),
+        "set_eq" => set_eq_handler(shared, batch_params(params)?).await.into_response(),
+        "set_eq_type" => set_eq_type_handler(shared, batch_params(params)?).await.into_response(),
+        "spectrum/ws" => spectrum_ws_handler(shared, batch_params(params)?).await.into_response(),
+        "spectrum/config" => spectrum_config_handler(shared, batch_params(params)?).await.into_response(),
+        "load_stream" => load_stream_handler(shared, batch_params(params)?).await.into_response(),
+        "library/search" => library_search_handler(shared, batch_params(params)?).await.into_response(),
+        "lyrics" => lyrics_handler(shared, batch_params(params)?).await.into_response(),
+        "queue/add" => queue_add_handler(shared, batch_params(params)?).await.into_response(),
+        "queue/list" => queue_list_handler(shared).await.into_response(),
+        "queue/remove" => queue_remove_handler(shared, batch_params(params)?).await.into_response(),
+        "queue/clear" => queue_clear_handler(shared).await.into_response(),
+        "queue/move" => queue_move_handler(shared, batch_params(params)?).await.into_response(),
+        "queue/next" => queue_next_handler(shared).await.into_response(),
+        "queue/prev" => queue_prev_handler(shared).await.into_response(),
+        "queue/mode" => queue_mode_handler(shared, batch_params(params)?).await.into_response(),
+        "playlist/load" => playlist_load_handler(shared, batch_params(params)?).await.into_response(),
+        "diagnostics/reset" => diagnostics_reset_handler(shared).await.into_response(),
+        "command" => command_handler(shared, batch_params(params)?).await.into_response(),
+        _ => return Err(format!("unknown op: {}", op)),
+    };
+    Ok(response)
+}
+
+/// Run several commands in order in one request, e.g. the output, EQ and
+/// load setup an app sends on startup. Each result carries the HTTP status
+/// and body its endpoint would have returned. Commands run one after another
+/// but other requests can still interleave, so this saves round-trips
+/// rather than making the sequence atomic.
+async fn batch_handler(State(shared): State<SharedState>, Json(req): Json<BatchRequest>) -> impl IntoResponse {
+    let mut results = Vec::with_capacity(req.commands.len());
+    let mut failed = None;
+    for (index, command) in req.commands.into_iter().enumerate() {
+        let (code, body) = match run_batch_command(&shared, &command.op, command.params).await {
+            Ok(response) => {
+                let code = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .ok()
+                    .and_then(|bytes| serde_json::from_slice::<Value>(&bytes).ok())
+                    .unwrap_or(Value::Null);
+                (code, body)
+            }
+            Err(message) => (
+                StatusCode::BAD_REQUEST,
+                json!({ "status": "error", "message": message }),
+            ),
         };
-        if !err.is_null() {
-            let message = unsafe { CStr::from_ptr(err).to_string_lossy().into_owned() };
-            return Err(anyhow!("soxr_process flush failed: {}", message));
-        }
-        if odone == 0 {
-            break;
+        let ok = code.is_success();
+        results.push(json!({ "op": command.op, "code": code.as_u16(), "ok": ok, "result": body }));
+        if !ok {
+            failed.get_or_insert(index);
+            if !req.continue_on_error {
+                break;
+            }
         }
-        output.extend_from_slice(&out_chunk[..odone * channels]);
     }
+    Json(json!({
+        "status": if failed.is_none() { "success" } else { "error" },
+        "failed_at": failed,
+        "results": results,
+    }))
+}
 
-    Ok(output)
+fn has_ws_subscribers(shared: &SharedState) -> bool {
+    shared.tx.receiver_count() > 0
 }
 
-fn resample_for_output(shared: &SharedState, target_rate: u32) -> Result<()> {
-    let (mode, channels, sample_rate, resampler_mode, resampler_quality, soxr_available, data, position) = {
+/// Tear down the output stream once playback has been stopped for the
+/// configured idle period. The next play/load rebuilds it.
+fn release_idle_output(shared: &SharedState, now: Instant) {
+    {
         let mut state = shared.inner.lock().unwrap();
-        if state.mode != "file" || state.data.is_empty() {
-            return Ok(());
+        if state.is_playing {
+            state.idle_since = None;
+            return;
         }
-        if state.sample_rate == target_rate || target_rate == 0 {
-            return Ok(());
+        let Some(timeout) = state.idle_release_secs else {
+            return;
+        };
+        if state.device_released {
+            return;
         }
-        (
-            state.mode.clone(),
-            state.channels,
-            state.sample_rate,
-            state.resampler_mode.clone(),
-            state.resampler_quality.clone(),
-            state.soxr_available,
-            std::mem::take(&mut state.data),
-            state.position,
-        )
+        let since = *state.idle_since.get_or_insert(now);
+        if now.duration_since(since) < Duration::from_secs(timeout) {
+            return;
+        }
+        state.device_released = true;
+        state.output_config = None;
+    }
+    info!("output idle, releasing device");
+    stop_exclusive_stream(shared);
+    shared.output_stream.lock().unwrap().0 = None;
+    send_state(shared);
+}
+
+/// How often the device watcher looks at what is plugged in when nothing
+/// tells it about changes.
+const DEVICE_WATCH_INTERVAL: Duration = Duration::from_secs(5);
+/// How often it looks anyway while Windows endpoint notifications work;
+/// they don't cover ASIO drivers.
+const DEVICE_WATCH_NOTIFIED_INTERVAL: Duration = Duration::from_secs(30);
+/// Wait after a device notification before looking, so the rest of its
+/// burst (added, state changed, new default) has arrived.
+const DEVICE_EVENT_SETTLE: Duration = Duration::from_millis(250);
+
+/// What `capture_saved_output` keeps: `device_id`, `selected_device` and
+/// `output_latency_ms`.
+type SavedOutput = (Option<usize>, Option<(String, String)>, Option<u32>);
+
+/// Host and name of every output device, in `device_id` order.
+fn output_device_keys() -> Vec<(String, String)> {
+    let mut keys = Vec::new();
+    for host_id in cpal::available_hosts() {
+        if let Ok(host) = cpal::host_from_id(host_id) {
+            if let Ok(devices) = host.output_devices() {
+                for device in devices {
+                    let name = device.name().unwrap_or_else(|_| "Unknown".to_string());
+                    keys.push((format!("{:?}", host_id), name));
+                }
+            }
+        }
+    }
+    keys
+}
+
+/// Host and name of device `id` in the list as it is now.
+fn device_key(id: usize) -> Option<(String, String)> {
+    output_device_keys().into_iter().nth(id)
+}
+
+/// `device_key` for a requested device, or an error naming how many
+/// devices there are when the id is past the end of the list.
+fn checked_device_key(device_id: Option<usize>) -> Result<Option<(String, String)>> {
+    let Some(id) = device_id else {
+        return Ok(None);
+    };
+    let keys = output_device_keys();
+    let count = keys.len();
+    keys.into_iter()
+        .nth(id)
+        .map(Some)
+        .ok_or_else(|| anyhow!("no output device with id {} ({} available)", id, count))
+}
+
+/// The device list and default device as the watcher last saw them.
+#[derive(Debug, Default)]
+struct DeviceWatch {
+    seen: bool,
+    keys: Vec<(String, String)>,
+    default: Option<String>,
+}
+
+/// Take a new look at the devices into `state`: follow the selected device
+/// to wherever it now sits in the list, play on the default while it's
+/// unplugged, and go back to it when it returns. `lost` says the output
+/// failed under us. Returns the `device_changed` event and whether the
+/// output has to reopen, or `None` when nothing changed.
+fn plan_device_change(
+    state: &mut EngineState,
+    watch: &mut DeviceWatch,
+    keys: Vec<(String, String)>,
+    default: Option<String>,
+    lost: bool,
+) -> Option<(Value, bool)> {
+    let first = !watch.seen;
+    let listed = keys != watch.keys;
+    let default_moved = default != watch.default;
+    *watch = DeviceWatch { seen: true, keys, default };
+    if !lost && (first || (!listed && !default_moved)) {
+        return None;
+    }
+    let (device_id, fallback) = match &state.selected_device {
+        Some(selected) => match watch.keys.iter().position(|key| key == selected) {
+            Some(id) => (Some(id), false),
+            None => (None, true),
+        },
+        None => (state.device_id, false),
+    };
+    let reopen = lost || device_id != state.device_id || (device_id.is_none() && default_moved);
+    state.device_id = device_id;
+    state.device_fallback = fallback;
+    let (hostapi, name) = match device_id.and_then(|id| watch.keys.get(id)) {
+        Some((hostapi, name)) => (Some(hostapi.clone()), Some(name.clone())),
+        None => (state.hostapi.clone(), watch.default.clone()),
+    };
+    let event = json!({
+        "type": "device_changed",
+        "device_id": device_id,
+        "hostapi": hostapi,
+        "name": name,
+        "fallback": fallback,
+        "reopened": reopen,
+    });
+    Some((event, reopen))
+}
+
+/// One pass of the device watcher, run when `devices_changed` wakes it and
+/// otherwise on a slow poll. The output reopens on the device
+/// `plan_device_change` picks, and playback carries on from where it was.
+fn check_output_devices(shared: &SharedState, watch: &mut DeviceWatch) {
+    let hostapi = shared.inner.lock().unwrap().hostapi.clone();
+    let keys = output_device_keys();
+    let default = output_host(hostapi.as_deref())
+        .default_output_device()
+        .and_then(|device| device.name().ok());
+    let exclusive_ended = shared
+        .exclusive_stream
+        .lock()
+        .unwrap()
+        .as_ref()
+        .is_some_and(|handle| handle.thread.as_ref().is_some_and(|thread| thread.is_finished()));
+    let lost = shared.output_lost.swap(false, Ordering::AcqRel) || exclusive_ended;
+    let change = plan_device_change(&mut shared.inner.lock().unwrap(), watch, keys, default, lost);
+    let Some((event, reopen)) = change else {
+        return;
     };
+    let open = shared.output_stream.lock().unwrap().0.is_some() || shared.exclusive_stream.lock().unwrap().is_some();
+    if reopen && (open || lost) {
+        info!("output devices changed, reopening: {}", event);
+        reopen_output(shared);
+    }
+    let _ = shared.tx.send(event.to_string());
+    send_state(shared);
+}
 
-    if mode != "file" {
-        return Ok(());
+/// Wakes the device watcher on endpoint changes. Property changes are left
+/// out, as every volume change sets them off.
+#[cfg(target_os = "windows")]
+#[windows::core::implement(IMMNotificationClient)]
+struct EndpointNotifier {
+    wake: Arc<tokio::sync::Notify>,
+}
+
+#[cfg(target_os = "windows")]
+impl IMMNotificationClient_Impl for EndpointNotifier {
+    fn OnDeviceStateChanged(&self, _device_id: &PCWSTR, _state: DEVICE_STATE) -> windows::core::Result<()> {
+        self.wake.notify_one();
+        Ok(())
     }
 
-    let quality = normalize_resampler_quality(&resampler_quality);
-    let prefer_soxr = should_prefer_soxr(&resampler_mode, &quality, soxr_available);
-    let resampled = if prefer_soxr {
-        match resample_audio_soxr(&data, channels, sample_rate, target_rate) {
-            Ok(out) => out,
+    fn OnDeviceAdded(&self, _device_id: &PCWSTR) -> windows::core::Result<()> {
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    fn OnDeviceRemoved(&self, _device_id: &PCWSTR) -> windows::core::Result<()> {
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    fn OnDefaultDeviceChanged(&self, flow: EDataFlow, _role: ERole, _device_id: &PCWSTR) -> windows::core::Result<()> {
+        if flow == eRender {
+            self.wake.notify_one();
+        }
+        Ok(())
+    }
+
+    fn OnPropertyValueChanged(&self, _device_id: &PCWSTR, _key: &PROPERTYKEY) -> windows::core::Result<()> {
+        Ok(())
+    }
+}
+
+/// Register an `EndpointNotifier` on a thread that keeps it, its enumerator
+/// and COM alive for the life of the process. `false` when registering
+/// failed and the watcher has to poll.
+#[cfg(target_os = "windows")]
+fn watch_endpoint_notifications(wake: Arc<tokio::sync::Notify>) -> bool {
+    let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let register = || -> Result<(IMMDeviceEnumerator, IMMNotificationClient, ComInit)> {
+            let com = ComInit::new()?;
+            let enumerator: IMMDeviceEnumerator = unsafe { CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL) }
+                .map_err(|err| anyhow!("CoCreateInstance failed: {}", err))?;
+            let client: IMMNotificationClient = EndpointNotifier { wake }.into();
+            unsafe { enumerator.RegisterEndpointNotificationCallback(&client) }
+                .map_err(|err| anyhow!("RegisterEndpointNotificationCallback failed: {}", err))?;
+            Ok((enumerator, client, com))
+        };
+        match register() {
+            Ok(_registered) => {
+                let _ = ready_tx.send(true);
+                loop {
+                    thread::park();
+                }
+            }
             Err(err) => {
-                if resampler_mode == "auto" {
-                    error!("soxr resample failed, falling back to rubato: {}", err);
-                    resample_audio(&data, channels, sample_rate, target_rate, &quality)?
-                } else {
-                    return Err(err);
+                warn!("device notifications unavailable, polling instead: {}", err);
+                let _ = ready_tx.send(false);
+            }
+        }
+    });
+    ready_rx.recv().unwrap_or(false)
+}
+
+#[cfg(not(target_os = "windows"))]
+fn watch_endpoint_notifications(_wake: Arc<tokio::sync::Notify>) -> bool {
+    false
+}
+
+/// How often to look for a finished track; short, as it adds to the gap
+/// between auto-advanced tracks.
+const AUTO_ADVANCE_POLL_MS: u64 = 20;
+/// With auto advance on, the next queue entry is decoded once the current
+/// track is this close to its end.
+const LOOKAHEAD_SECS: f64 = 2.0;
+
+/// Decoding the next queue entry ahead of time so auto advance can splice
+/// it in without a gap.
+#[derive(Debug, Clone, Default)]
+enum LookAhead {
+    #[default]
+    Idle,
+    /// Decoding on a background thread.
+    Pending(usize),
+    /// Decoded at the output format; spliced in when the current track runs
+    /// out.
+    Ready(usize, Box<PreparedTrack>),
+    /// Nothing to stage for this track: end of the queue, a decode failure,
+    /// or a format or silence setting that needs a normal load.
+    Skipped,
+}
+
+/// Drop any staged or pending next track, and undo its pick from the
+/// shuffle order since it never played.
+fn discard_lookahead(state: &mut EngineState) {
+    if let LookAhead::Pending(index) | LookAhead::Ready(index, _) = std::mem::take(&mut state.lookahead) {
+        if state.shuffle_history.last() == Some(&index) {
+            state.shuffle_history.pop();
+        }
+    }
+}
+
+/// Switch to the staged track from the output callback. Only moves: the
+/// finished track's samples wait in `retired_samples` for the background
+/// loop to free, or fade out for `fade_frames` under the new one.
+fn splice_staged_track(state: &mut EngineState, fade_frames: usize) -> bool {
+    let LookAhead::Ready(index, next) = std::mem::take(&mut state.lookahead) else {
+        return false;
+    };
+    let next = *next;
+    let outgoing = std::mem::replace(&mut state.data, next.samples);
+    if fade_frames > 0 {
+        state.crossfade = Some(Crossfade::new(outgoing, state, fade_frames));
+    } else {
+        state.retired_samples = outgoing;
+    }
+    state.resampler_info = next.resampler_info;
+    state.source_sample_rate = next.source_sample_rate;
+    state.source_channels = next.source_channels;
+    state.source_bit_depth = next.source_bit_depth;
+    state.partial_decode = next.partial_decode;
+    state.gapless_trim = next.gapless_trim;
+    state.replaygain = next.replaygain;
+    state.dsd_output = next.dsd;
+    state.duration = next.duration;
+    state.position = 0;
+    state.loop_region = None;
+    state.track_gain_db = state.track_gains.get(&next.path).copied().unwrap_or(0.0);
+    state.file_path = Some(next.path);
+    state.queue_index = Some(index);
+    refresh_replaygain_gain(state);
+    state.track_spliced = true;
+    true
+}
+
+/// Start decoding the next queue entry once the current one nears its end.
+/// The result is only kept if it can continue the current output as is:
+/// same rate and channels, and no inter-track silence to insert.
+fn stage_next_track(shared: &SharedState) {
+    let (index, path, current_path, options) = {
+        let mut state = shared.inner.lock().unwrap();
+        let frames = state.data.len() / state.channels.max(1);
+        let remaining = frames.saturating_sub(state.position) as f64 / state.sample_rate.max(1) as f64;
+        let idle = matches!(state.lookahead, LookAhead::Idle);
+        let lead = LOOKAHEAD_SECS + state.crossfade_ms as f64 / 1000.0;
+        if !idle || !state.auto_advance || state.mode != "file" || !state.is_playing || remaining > lead {
+            return;
+        }
+        // A looping track never reaches its end, and one decoding as it
+        // plays has no samples to splice onto.
+        if file_loop_bounds(&state).is_some() || state.incremental.is_some() {
+            return;
+        }
+        let (Some(current_path), Some(_)) = (state.file_path.clone(), state.queue_index) else {
+            return;
+        };
+        let Some(index) = next_queue_index(&mut state, true) else {
+            state.lookahead = LookAhead::Skipped;
+            return;
+        };
+        state.lookahead = LookAhead::Pending(index);
+        (index, state.queue[index].path.clone(), current_path, decode_options_for(&state))
+    };
+    let shared = shared.clone();
+    thread::spawn(move || {
+        let prepared = prepare_track(&shared, &path, &options);
+        let (silence_ms, sample_rate, channels, source_rate, dop) = {
+            let state = shared.inner.lock().unwrap();
+            let dop = state.dsd_output == Some(DsdOutput::Dop);
+            (state.inter_track_silence_ms, state.sample_rate, state.channels, state.source_sample_rate, dop)
+        };
+        let staged = match prepared {
+            Ok(next)
+                if next.sample_rate == sample_rate
+                    && next.source_channels == channels
+                    && (next.dsd == Some(DsdOutput::Dop)) == dop =>
+            {
+                let needs_gap = silence_ms > 0 && {
+                    let mut current = known_track(&shared, &current_path);
+                    current.sample_rate = Some(source_rate);
+                    current.channels = Some(channels as u32);
+                    let mut upcoming = known_track(&shared, &path);
+                    upcoming.sample_rate = Some(next.source_sample_rate);
+                    upcoming.channels = Some(next.source_channels as u32);
+                    gapless_obstacle(&current, &upcoming).is_some()
+                };
+                (!needs_gap).then(|| LookAhead::Ready(index, Box::new(next)))
+            }
+            Ok(_) => None,
+            Err(err) => {
+                warn!("look-ahead decode of {} failed: {}", path, err);
+                None
+            }
+        };
+        let mut state = shared.inner.lock().unwrap();
+        // A load or queue change since staging started discarded it.
+        let current = state.file_path.as_deref() == Some(current_path.as_str());
+        if current && matches!(state.lookahead, LookAhead::Pending(pending) if pending == index) {
+            match staged {
+                Some(ready) => state.lookahead = ready,
+                None => {
+                    discard_lookahead(&mut state);
+                    state.lookahead = LookAhead::Skipped;
                 }
             }
         }
-    } else {
-        resample_audio(&data, channels, sample_rate, target_rate, &quality)?
+    });
+}
+
+/// Announce a track the output callback spliced in, and free the one it
+/// replaced.
+fn finish_splice(shared: &SharedState) {
+    let (retired, track, index) = {
+        let mut state = shared.inner.lock().unwrap();
+        if !std::mem::take(&mut state.track_spliced) {
+            return;
+        }
+        let index = state.queue_index;
+        let track = index.and_then(|idx| state.queue.get(idx).cloned());
+        (std::mem::take(&mut state.retired_samples), track, index)
     };
+    drop(retired);
+    let payload = json!({ "type": "track_changed", "track": track, "index": index, "auto": true, "gapless": true });
+    let _ = shared.tx.send(payload.to_string());
+    send_state(shared);
+}
 
-    let duration = if target_rate > 0 && channels > 0 {
-        (resampled.len() / channels) as f64 / target_rate as f64
-    } else {
-        0.0
-    };
-    let scaled_pos = if sample_rate > 0 {
-        ((position as f64) * target_rate as f64 / sample_rate as f64) as usize
-    } else {
-        0
-    };
+/// Longest `crossfade_ms` accepts.
+const CROSSFADE_MAX_MS: u32 = 12_000;
 
-    let mut state = shared.inner.lock().unwrap();
-    state.data = resampled;
-    state.sample_rate = target_rate;
-    state.duration = duration;
-    state.position = scaled_pos.min(state.data.len() / state.channels.max(1));
-    Ok(())
-}
+/// The outgoing track during a crossfade, playing on from `position` under
+/// the incoming one for `frames` frames. It keeps its own ReplayGain and
+/// track gain, which the state moves on to the incoming track's.
+#[derive(Debug, Clone)]
+struct Crossfade {
+    data: Vec<f32>,
+    position: usize,
+    frames: usize,
+    elapsed: usize,
+    replaygain_gain: f32,
+    track_gain_db: f32,
+}
+
+impl Crossfade {
+    /// Fade out `data`, the track `state` is still set up for.
+    fn new(data: Vec<f32>, state: &EngineState, frames: usize) -> Self {
+        Self {
+            data,
+            position: state.position,
+            frames,
+            elapsed: 0,
+            replaygain_gain: state.replaygain_gain,
+            track_gain_db: state.track_gain_db,
+        }
+    }
 
-fn next_uniform(seed: &mut u64) -> f32 {
-    *seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
-    let value = (*seed >> 32) as u32;
-    (value as f32) / (u32::MAX as f32)
+    fn active(&self) -> bool {
+        self.elapsed < self.frames
+    }
 }
 
-fn apply_tpdf_dither(samples: &mut [f32], bits: u32, seed: &mut u64) {
-    let effective_bits = bits.clamp(8, 32);
-    let denom = 1u64 << effective_bits.saturating_sub(1);
-    let lsb = 1.0 / denom as f32;
-    for sample in samples.iter_mut() {
-        let noise = (next_uniform(seed) - next_uniform(seed)) * lsb;
-        *sample = (*sample + noise).clamp(-1.0, 1.0);
-    }
+fn crossfade_frames(state: &EngineState) -> usize {
+    (state.crossfade_ms as u64 * state.sample_rate as u64 / 1000) as usize
 }
 
-fn quantize_to_step(value: f32, step: f32) -> f32 {
-    if step <= 0.0 {
-        return value;
+/// Stop mixing in the outgoing track. It stays allocated until the
+/// background loop frees it, as this also runs on the audio thread.
+fn cancel_crossfade(state: &mut EngineState) {
+    if let Some(fade) = state.crossfade.as_mut() {
+        fade.elapsed = fade.frames;
     }
-    let scaled = (value / step).round();
-    (scaled * step).clamp(-1.0, 1.0)
 }
 
-fn soft_limit_sample(sample: f32, threshold: f32) -> f32 {
-    let limit = normalize_limiter_threshold(threshold);
-    let abs = sample.abs();
-    if abs <= limit {
-        return sample;
-    }
-    let t = ((abs - limit) / (1.0 - limit)).min(1.0);
-    let shaped = limit + (1.0 - limit) * (t + t * t - t * t * t);
-    sample.signum() * shaped
+/// What the outgoing track is scaled by as it is mixed in, so that once the
+/// chain applies the incoming track's ReplayGain and track gain to the mix
+/// it ends up at its own.
+fn crossfade_gain(state: &EngineState) -> f32 {
+    let Some(fade) = &state.crossfade else {
+        return 1.0;
+    };
+    let level = |replaygain_gain: f32, track_gain_db: f32| {
+        let replaygain = if state.replaygain_enabled { replaygain_gain } else { 1.0 };
+        replaygain * db_to_linear(track_gain_db)
+    };
+    level(fade.replaygain_gain, fade.track_gain_db) / level(state.replaygain_gain, state.track_gain_db)
 }
 
-fn apply_tpdf_dither_shaped(
-    samples: &mut [f32],
-    bits: u32,
-    channels: usize,
-    seed: &mut u64,
-    err1: &mut [f32; MAX_DITHER_CHANNELS],
-    err2: &mut [f32; MAX_DITHER_CHANNELS],
-    order: u8,
-) {
-    let effective_bits = bits.clamp(8, 32);
-    let denom = 1u64 << effective_bits.saturating_sub(1);
-    let lsb = 1.0 / denom as f32;
-    let channel_count = channels.max(1);
-    if channel_count > MAX_DITHER_CHANNELS || order == 0 {
-        apply_tpdf_dither(samples, effective_bits, seed);
-        return;
-    }
-    for (idx, sample) in samples.iter_mut().enumerate() {
-        let ch = idx % channel_count;
-        let feedback = if order == 1 {
-            DITHER_SHAPER_ORDER1_COEFF * err1[ch]
-        } else {
-            DITHER_SHAPER_ORDER2_COEFF1 * err1[ch] + DITHER_SHAPER_ORDER2_COEFF2 * err2[ch]
-        };
-        let shaped = *sample + feedback;
-        let noise = (next_uniform(seed) - next_uniform(seed)) * lsb;
-        let dithered = (shaped + noise).clamp(-1.0, 1.0);
-        let quantized = quantize_to_step(dithered, lsb);
-        let error = dithered - quantized;
-        err2[ch] = err1[ch];
-        err1[ch] = error;
-        *sample = quantized;
+/// Mix the outgoing track, scaled by `gain`, into the start of `data`, the
+/// incoming track at `channels` per frame, on an equal-power curve: the
+/// squared gains sum to one, so the overlap holds its loudness for
+/// uncorrelated material.
+fn mix_crossfade(fade: &mut Crossfade, data: &mut [f32], channels: usize, gain: f32) {
+    for frame in data.chunks_exact_mut(channels) {
+        if !fade.active() {
+            break;
+        }
+        let angle = fade.elapsed as f32 / fade.frames as f32 * std::f32::consts::FRAC_PI_2;
+        let (fade_in, fade_out) = angle.sin_cos();
+        let fade_out = fade_out * gain;
+        let start = fade.position * channels;
+        for (ch, sample) in frame.iter_mut().enumerate() {
+            let outgoing = fade.data.get(start + ch).copied().unwrap_or(0.0);
+            *sample = *sample * fade_in + outgoing * fade_out;
+        }
+        fade.position += 1;
+        fade.elapsed += 1;
     }
 }
 
-fn apply_dither_if_needed(state: &Arc<Mutex<EngineState>>, data: &mut [f32], target_bits: u32) {
-    let (enabled, dither_type, bits, mut seed, channels, mut err1, mut err2) = {
-        let guard = state.lock().unwrap();
-        (
-            guard.dither_enabled,
-            guard.dither_type.clone(),
-            guard.dither_bits,
-            guard.dither_rng,
-            guard.channels,
-            guard.dither_shape_err1,
-            guard.dither_shape_err2,
-        )
+/// Free the outgoing track of a crossfade that has run its course.
+fn clear_finished_crossfade(shared: &SharedState) {
+    let finished = {
+        let mut state = shared.inner.lock().unwrap();
+        match &state.crossfade {
+            Some(fade) if !fade.active() => state.crossfade.take(),
+            _ => None,
+        }
     };
-    if !enabled || dither_type == "off" {
-        return;
-    }
-    let effective_bits = normalize_dither_bits(bits).min(target_bits);
-    match dither_type.as_str() {
-        "tpdf_ns1" => {
-            apply_tpdf_dither_shaped(
-                data,
-                effective_bits,
-                channels,
-                &mut seed,
-                &mut err1,
-                &mut err2,
-                1,
-            );
+    drop(finished);
+}
+
+async fn configure_crossfade_handler(
+    State(shared): State<SharedState>,
+    Json(req): Json<ConfigureCrossfadeRequest>,
+) -> impl IntoResponse {
+    {
+        let mut state = shared.inner.lock().unwrap();
+        if let Some(crossfade_ms) = req.crossfade_ms {
+            state.crossfade_ms = crossfade_ms.min(CROSSFADE_MAX_MS);
         }
-        "tpdf_ns2" => {
-            apply_tpdf_dither_shaped(
-                data,
-                effective_bits,
-                channels,
-                &mut seed,
-                &mut err1,
-                &mut err2,
-                2,
-            );
+        if let Some(fade_ms) = req.transport_fade_ms {
+            state.transport_fade_ms = fade_ms.min(TRANSPORT_FADE_MAX_MS);
         }
-        _ => {
-            apply_tpdf_dither(data, effective_bits, &mut seed);
+    }
+    send_state(&shared);
+    let state = shared.inner.lock().unwrap();
+    Json(json!({ "status": "success", "state": build_state_view(&state) }))
+}
+
+async fn configure_downmix_handler(
+    State(shared): State<SharedState>,
+    Json(req): Json<ConfigureDownmixRequest>,
+) -> impl IntoResponse {
+    let downmix = req.downmix.to_lowercase();
+    if !DOWNMIX_MODES.contains(&downmix.as_str()) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "status": "error",
+                "message": format!("downmix must be one of {}", DOWNMIX_MODES.join(", ")),
+            })),
+        );
+    }
+    shared.inner.lock().unwrap().downmix = downmix;
+    send_state(&shared);
+    let state = shared.inner.lock().unwrap();
+    (StatusCode::OK, Json(json!({ "status": "success", "state": build_state_view(&state) })))
+}
+
+/// Play the next queue entry once the output callback has finished a file
+/// from the queue, following the repeat and shuffle modes. The load runs
+/// here rather than in the callback; preloading the next track keeps the
+/// switch short.
+fn auto_advance(shared: &SharedState) {
+    {
+        let mut state = shared.inner.lock().unwrap();
+        let finished = std::mem::take(&mut state.track_finished);
+        if !finished || !state.auto_advance || state.queue_index.is_none() {
+            return;
         }
     }
-    let mut guard = state.lock().unwrap();
-    guard.dither_rng = seed;
-    if dither_type == "tpdf_ns1" || dither_type == "tpdf_ns2" {
-        guard.dither_shape_err1 = err1;
-        guard.dither_shape_err2 = err2;
+    match queue_next_impl(shared) {
+        Ok(Some(track)) => {
+            let index = shared.inner.lock().unwrap().queue_index;
+            let payload = json!({ "type": "track_changed", "track": track, "index": index, "auto": true });
+            let _ = shared.tx.send(payload.to_string());
+        }
+        Ok(None) => {}
+        Err(err) => error!("auto advance failed: {}", err),
     }
 }
-fn ensure_output_stream(shared: &SharedState) -> Result<()> {
-    let state_snapshot = shared.inner.lock().unwrap().clone();
-    if state_snapshot.exclusive_mode {
-        let hostapi = state_snapshot
-            .device_id
-            .and_then(device_hostapi_by_id)
-            .unwrap_or_else(|| format!("{:?}", cpal::default_host().id()));
-        if hostapi == "Wasapi" && cfg!(target_os = "windows") {
-            if shared.exclusive_stream.lock().unwrap().is_some() {
-                return Ok(());
+
+fn start_background_tasks(shared: SharedState) {
+    let state_clone = shared.clone();
+    tokio::spawn(async move {
+        loop {
+            release_idle_output(&state_clone, Instant::now());
+            refresh_live_resampler(&state_clone);
+            // Kernels for a rate the callback has switched to, or for the
+            // rates it may switch to next.
+            if state_clone.inner.lock().unwrap().eq_filters.missing_design().is_some() {
+                let shared = state_clone.clone();
+                let _ = tokio::task::spawn_blocking(move || design_eq_filters(&shared)).await;
             }
-            shared.output_stream.lock().unwrap().0 = None;
-            let ordinal = state_snapshot
-                .device_id
-                .and_then(wasapi_device_ordinal_by_id);
-            match start_wasapi_exclusive_stream(shared, ordinal) {
-                Ok(handle) => {
-                    *shared.exclusive_stream.lock().unwrap() = Some(handle);
-                    return Ok(());
+            let restart_pending = state_clone.inner.lock().unwrap().stream_restart_pending;
+            if restart_pending {
+                if let Err(err) = run_transport_change(&state_clone, restart_impl).await {
+                    error!("stream restart failed: {}", err);
+                    state_clone.inner.lock().unwrap().stream_restart_pending = false;
                 }
-                Err(err) => {
-                    error!("wasapi exclusive start failed: {}", err);
-                    let mut state = shared.inner.lock().unwrap();
-                    state.exclusive_mode = false;
+            }
+            let seek_pending = state_clone.inner.lock().unwrap().stream_seek_pending.take();
+            if let Some(seconds) = seek_pending {
+                if let Err(err) = seek_stream_impl(&state_clone, seconds) {
+                    warn!("stream seek failed: {}", err);
                 }
             }
+            // Nobody is listening; skip building state snapshots until a
+            // /ws client subscribes again.
+            if has_ws_subscribers(&state_clone) {
+                send_state(&state_clone);
+                send_buffer_state(&state_clone);
+            }
+            tokio::time::sleep(Duration::from_millis(250)).await;
         }
-    } else if shared.exclusive_stream.lock().unwrap().is_some() {
-        stop_exclusive_stream(shared);
+    });
+
+    let state_clone = shared.clone();
+    tokio::spawn(async move {
+        loop {
+            finish_splice(&state_clone);
+            clear_finished_crossfade(&state_clone);
+            stage_next_track(&state_clone);
+            if state_clone.inner.lock().unwrap().track_finished {
+                let shared = state_clone.clone();
+                let _ = tokio::task::spawn_blocking(move || auto_advance(&shared)).await;
+            }
+            tokio::time::sleep(Duration::from_millis(AUTO_ADVANCE_POLL_MS)).await;
+        }
+    });
+
+    let state_clone = shared.clone();
+    tokio::spawn(async move {
+        let wake = state_clone.devices_changed.clone();
+        let notifier_wake = wake.clone();
+        let notified = tokio::task::spawn_blocking(move || watch_endpoint_notifications(notifier_wake))
+            .await
+            .unwrap_or(false);
+        let interval = if notified { DEVICE_WATCH_NOTIFIED_INTERVAL } else { DEVICE_WATCH_INTERVAL };
+        let mut watch = DeviceWatch::default();
+        loop {
+            let shared = state_clone.clone();
+            watch = tokio::task::spawn_blocking(move || {
+                check_output_devices(&shared, &mut watch);
+                watch
+            })
+            .await
+            .unwrap_or_default();
+            tokio::select! {
+                _ = wake.notified() => tokio::time::sleep(DEVICE_EVENT_SETTLE).await,
+                _ = tokio::time::sleep(interval) => {}
+            }
+        }
+    });
+
+    let state_clone = shared.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_millis(SETTINGS_SAVE_INTERVAL_MS)).await;
+            if state_clone.settings_dirty.load(Ordering::Acquire) {
+                let shared = state_clone.clone();
+                let _ = tokio::task::spawn_blocking(move || flush_settings(&shared)).await;
+            }
+        }
+    });
+
+    if let Some(state_shared) = shared.state_shared.clone() {
+        let state_clone = shared.clone();
+        tokio::spawn(async move {
+            let mut last = [0u8; STATE_SHM_BYTES - 4];
+            loop {
+                let fields = encode_state_shm(&state_clone.inner.lock().unwrap());
+                // Readers poll; only bump the sequence when something moved.
+                if fields != last {
+                    write_state_shared(&state_shared, &fields);
+                    last = fields;
+                }
+                tokio::time::sleep(Duration::from_millis(STATE_SHM_INTERVAL_MS)).await;
+            }
+        });
     }
 
-    let mut guard = shared.output_stream.lock().unwrap();
-    if guard.0.is_some() {
-        return Ok(());
+    let state_clone = shared.clone();
+    let spectrum_shared = state_clone.spectrum_shared.clone();
+    tokio::spawn(async move {
+        let mut analyzer = {
+            let state = state_clone.inner.lock().unwrap();
+            SpectrumAnalyzer::new(state.spectrum_fft_size, state.spectrum_bins, &state.spectrum_window)
+        };
+        let mut sample_buffer = vec![0.0f32; analyzer.fft_size];
+        let mut meter = LevelMeter::default();
+        loop {
+            let ws_active = has_ws_subscribers(&state_clone)
+                && state_clone.inner.lock().unwrap().spectrum_ws_enabled;
+            // The FFT is the expensive part; only run it when the shm reader
+            // or a /ws subscriber will consume the result.
+            if spectrum_shared.is_none() && !ws_active {
+                tokio::time::sleep(Duration::from_millis(SPECTRUM_UPDATE_INTERVAL_MS)).await;
+                continue;
+            }
+            let (sample_rate, bins, gate_db, levels, window, (attack, release, peak_fall)) = {
+                let state = state_clone.inner.lock().unwrap();
+                // Read the window at the size the ring was set up for.
+                sample_buffer.resize(state.spectrum_fft_size, 0.0);
+                latest_spectrum_window(&state, &mut sample_buffer);
+                let levels = ws_active.then(|| meter.update(&state));
+                let window = (state.spectrum_window != analyzer.window_kind).then(|| state.spectrum_window.clone());
+                let motion = (state.spectrum_attack, state.spectrum_release, state.spectrum_peak_fall);
+                (state.sample_rate, state.spectrum_bins, state.spectrum_gate_db, levels, window, motion)
+            };
+            // The shm layout only depends on the bin count and peak hold.
+            if let Err(err) = resize_spectrum_shared(&spectrum_shared, bins, peak_fall > 0.0) {
+                error!("spectrum shm resize failed: {}", err);
+            }
+            if bins != analyzer.bins || sample_buffer.len() != analyzer.fft_size || window.is_some() {
+                let window = window.unwrap_or_else(|| analyzer.window_kind.clone());
+                analyzer = SpectrumAnalyzer::new(sample_buffer.len(), bins, &window);
+            }
+            analyzer.set_gate_db(gate_db);
+            analyzer.set_smoothing(attack, release);
+            analyzer.set_peak_fall(peak_fall);
+            analyzer.compute(&sample_buffer, sample_rate);
+            write_spectrum_shared(&spectrum_shared, &analyzer.output, analyzer.peaks());
+            if ws_active {
+                let payload = match analyzer.peaks() {
+                    Some(peaks) => json!({ "type": "spectrum_data", "data": analyzer.output, "peaks": peaks }),
+                    None => json!({ "type": "spectrum_data", "data": analyzer.output }),
+                };
+                let _ = state_clone.tx.send(payload.to_string());
+            }
+            if let Some(levels) = levels {
+                let payload = json!({
+                    "type": "level_meter",
+                    "peak": levels.peak,
+                    "hold": levels.hold,
+                    "rms": levels.rms,
+                    "clip": levels.clip
+                });
+                let _ = state_clone.tx.send(payload.to_string());
+            }
+            tokio::time::sleep(Duration::from_millis(SPECTRUM_UPDATE_INTERVAL_MS)).await;
+        }
+    });
+}
+
+pub async fn run_http_server(
+    shared: Option<SharedState>,
+    port: Option<u16>,
+) -> Result<()> {
+    let _ = tracing_subscriber::fmt::try_init();
+    let port = port
+        .or_else(|| std::env::var("VMUSIC_ENGINE_PORT").ok().and_then(|v| v.parse::<u16>().ok()))
+        .unwrap_or(55_554);
+
+    let shared = match shared {
+        Some(shared) => shared,
+        None => create_shared_state(),
+    };
+    shared.inner.lock().unwrap().server_port = Some(port);
+    start_background_tasks(shared.clone());
+
+    let app = Router::new()
+        .route("/ws", get(ws_handler))
+        .route("/state", get(get_state_handler))
+        .route("/devices", get(list_devices_handler))
+        .route("/hostapis", get(hostapis_handler).post(set_hostapi_handler))
+        .route("/library/scan", post(scan_library_handler))
+        .route("/library/scan/cancel", post(scan_cancel_handler))
+        .route(
+            "/library/extensions",
+            get(audio_extensions_handler).post(set_audio_extensions_handler),
+        )
+        .route("/library/refresh_track", post(refresh_track_handler))
+        .route("/library/search", post(library_search_handler))
+        .route("/metadata/write", post(metadata_write_handler))
+        .route("/metadata/cover", post(metadata_cover_handler))
+        .route("/analyze/fingerprint", post(fingerprint_handler))
+        .route("/analyze/key", post(key_handler))
+        .route("/analyze/bpm", post(bpm_handler))
+        .route("/queue/add", post(queue_add_handler))
+        .route("/queue/list", get(queue_list_handler))
+        .route("/queue/remove", post(queue_remove_handler))
+        .route("/queue/clear", post(queue_clear_handler))
+        .route("/queue/move", post(queue_move_handler))
+        .route("/queue/next", post(queue_next_handler))
+        .route("/queue/prev", post(queue_prev_handler))
+        .route("/queue/mode", post(queue_mode_handler))
+        .route("/playlist/load", post(playlist_load_handler))
+        .route("/gapless_check", get(gapless_check_handler))
+        .route("/command", post(command_handler))
+        .route("/cover", post(cover_handler))
+        .route("/lyrics", post(lyrics_handler))
+        .route("/load", post(load_handler))
+        .route("/preload", post(preload_handler))
+        .route("/export", post(export_handler))
+        .route("/play", post(play_handler))
+        .route("/pause", post(pause_handler))
+        .route("/stop", post(stop_handler))
+        .route("/restart", post(restart_handler))
+        .route("/seek", post(seek_handler))
+        .route("/seek_relative", post(seek_relative_handler))
+        .route("/loop", post(loop_handler))
+        .route("/volume", post(volume_handler))
+        .route("/track_gain", post(track_gain_handler))
+        .route("/configure_output", post(configure_output_handler))
+        .route("/configure_upsampling", post(configure_upsampling_handler))
+        .route("/configure_crossfade", post(configure_crossfade_handler))
+        .route("/configure_downmix", post(configure_downmix_handler))
+        .route("/resampler/status", get(resampler_status_handler))
+        .route("/set_eq", post(set_eq_handler))
+        .route("/set_eq_type", post(set_eq_type_handler))
+        .route("/configure_optimizations", post(configure_opt_handler))
+        .route("/spectrum/ws", post(spectrum_ws_handler))
+        .route("/spectrum/config", post(spectrum_config_handler))
+        .route("/load_stream", post(load_stream_handler))
+        .route("/capture/start", post(capture_start_handler))
+        .route("/capture/stop", post(capture_stop_handler))
+        .route("/capture/devices", get(capture_devices_handler))
+        .route("/buffer/state", get(buffer_state_handler))
+        .route("/diagnostics/reset", post(diagnostics_reset_handler))
+        .route("/batch", post(batch_handler))
+        .with_state(shared);
+
+    let addr = format!("127.0.0.1:{}", port);
+    info!("NTmusic engine listening on {}", addr);
+    println!("VMUSIC_ENGINE_READY");
+    axum::serve(tokio::net::TcpListener::bind(addr).await?, app).await?;
+    Ok(())
+}
+
+/// Drives the output callback without audio hardware, for DSP regression
+/// tests.
+///
+/// PCM injected with [`OutputHarness::new`] plays as a loaded file, and each
+/// `render*` call runs one callback of the requested size. The stages run in
+/// this order, matching the real device callbacks:
+///
+/// 1. source read (file position advances by the rendered frames)
+/// 2. upmix to the output channel count
+/// 3. the DSP chain: polarity, ReplayGain, auto level, per-track gain,
+///    downmix, volume, EQ, soft limiter, then dither
+///    (integer output formats only, see [`OutputHarness::render_i16`] and
+///    [`OutputHarness::render_i32`])
+/// 4. spectrum tap
+#[cfg(any(test, feature = "testing"))]
+pub mod testing {
+    use super::*;
+
+    pub struct OutputHarness {
+        shared: SharedState,
     }
 
-    let host = cpal::default_host();
-    let device = if let Some(id) = state_snapshot.device_id {
-        find_device_by_id(id).unwrap_or_else(|| host.default_output_device().unwrap())
-    } else {
-        host.default_output_device().ok_or_else(|| anyhow!("no output device"))?
-    };
+    impl OutputHarness {
+        pub fn new(samples: Vec<f32>, channels: usize, sample_rate: u32) -> Self {
+            let shared = create_shared_state();
+            {
+                let mut state = shared.inner.lock().unwrap();
+                state.mode = "file".to_string();
+                state.data = samples;
+                state.channels = channels.max(1);
+                state.output_channels = channels.max(1);
+                state.sample_rate = sample_rate;
+                state.replaygain_enabled = false;
+                state.dither_enabled = false;
+                state.is_playing = true;
+                // Already under way, not starting from silence.
+                state.transport_gain = 1.0;
+            }
+            OutputHarness { shared }
+        }
 
-    let default_config = device.default_output_config()?;
-    let mut sample_format = default_config.sample_format();
-    let mut config = default_config.config();
-    let target_rate = state_snapshot
-        .target_samplerate
-        .unwrap_or(state_snapshot.sample_rate)
-        .max(8000);
-    let target_channels = state_snapshot.channels.max(1) as u16;
-    config.channels = target_channels;
+        /// A stream-mode harness whose ring buffer holds `samples`.
+        pub fn new_stream(samples: &[f32], channels: usize, sample_rate: u32) -> Self {
+            let harness = OutputHarness::new(Vec::new(), channels, sample_rate);
+            harness.shared.inner.lock().unwrap().mode = "stream".to_string();
+            reset_ring_buffer(&harness.shared);
+            let pushed = harness.shared.producer.lock().unwrap().push_slice(samples);
+            harness.shared.inner.lock().unwrap().buffered_frames = pushed / channels.max(1);
+            harness
+        }
 
-    let mut matched = None;
-    if let Ok(supported) = device.supported_output_configs() {
-        for range in supported {
-            if range.channels() != target_channels {
-                continue;
-            }
-            let min_rate = range.min_sample_rate().0;
-            let max_rate = range.max_sample_rate().0;
-            if target_rate >= min_rate && target_rate <= max_rate {
-                matched = Some(range.with_sample_rate(cpal::SampleRate(target_rate)));
-                break;
+        /// Have the callback run as if the device opened at `rate`,
+        /// converting stream audio to it where that differs.
+        pub fn set_device_rate(&self, rate: u32) {
+            {
+                let mut state = self.shared.inner.lock().unwrap();
+                state.output_config = Some(OutputConfigInfo {
+                    backend: "test".to_string(),
+                    sample_rate: rate,
+                    channels: state.output_channels as u16,
+                    sample_format: "f32".to_string(),
+                    bit_depth: 32,
+                    buffer_frames: None,
+                });
             }
+            refresh_live_resampler(&self.shared);
         }
-    }
 
-    if let Some(cfg) = matched {
-        sample_format = cfg.sample_format();
-        config = cfg.config();
-    } else {
-        let fallback_rate = default_config.sample_rate().0.max(8000);
-        if fallback_rate != target_rate {
-            if let Err(err) = resample_for_output(shared, fallback_rate) {
-                error!("resample for output failed: {}", err);
+        pub fn set_output_channels(&self, channels: usize) {
+            self.shared.inner.lock().unwrap().output_channels = channels;
+        }
+
+        /// Jumps straight to `volume`, bypassing the ramp.
+        pub fn set_volume(&self, volume: f32) {
+            let mut state = self.shared.inner.lock().unwrap();
+            state.volume = volume;
+            state.volume_current = volume;
+        }
+
+        /// Changes volume the way the HTTP and control-shm paths do, ramping
+        /// while playing.
+        pub fn ramp_volume(&self, volume: f32) {
+            set_volume_target(&mut self.shared.inner.lock().unwrap(), volume);
+        }
+
+        /// `None` disables the limiter.
+        pub fn set_limiter(&self, threshold: Option<f32>) {
+            let mut state = self.shared.inner.lock().unwrap();
+            state.limiter_enabled = threshold.is_some();
+            if let Some(threshold) = threshold {
+                state.limiter_threshold = normalize_limiter_threshold(threshold);
             }
         }
-        config.sample_rate = cpal::SampleRate(fallback_rate);
-    }
 
-    let state = shared.inner.clone();
-    let consumer = shared.consumer.clone();
-    let control_shared = shared.control_shared.clone();
-    let output_scratch = shared.output_scratch.clone();
+        /// Apply a fixed linear ReplayGain factor; `None` disables ReplayGain.
+        pub fn set_replaygain_gain(&self, gain: Option<f32>) {
+            let mut state = self.shared.inner.lock().unwrap();
+            state.replaygain_enabled = gain.is_some();
+            state.replaygain_gain = gain.unwrap_or(1.0);
+        }
 
-    let err_fn = |err| {
-        error!("stream error: {}", err);
-    };
+        /// Enable auto level with `(target_db, max_gain_db)`; `None` disables it.
+        pub fn set_auto_level(&self, settings: Option<(f32, f32)>) {
+            let mut state = self.shared.inner.lock().unwrap();
+            state.auto_level_enabled = settings.is_some();
+            if let Some((target_db, max_gain_db)) = settings {
+                state.auto_level_target_db = target_db;
+                state.auto_level_max_gain_db = max_gain_db;
+            }
+            reset_auto_level(&mut state);
+        }
 
-    let stream = match sample_format {
-        cpal::SampleFormat::F32 => device.build_output_stream(
-            &config,
-            move |data: &mut [f32], _| {
-                fill_output_buffer(&state, &consumer, &control_shared, data);
-            },
-            err_fn,
-            None,
-        )?,
-        cpal::SampleFormat::I16 => device.build_output_stream(
-            &config,
-            move |data: &mut [i16], _| {
-                let mut scratch = output_scratch.lock().unwrap();
-                if scratch.len() != data.len() {
-                    scratch.resize(data.len(), 0.0);
-                }
-                fill_output_buffer(&state, &consumer, &control_shared, &mut scratch);
-                apply_dither_if_needed(&state, &mut scratch, 16);
-                for (dst, src) in data.iter_mut().zip(scratch.iter()) {
-                    *dst = cpal::Sample::from_sample(*src);
-                }
-            },
-            err_fn,
-            None,
-        )?,
-        cpal::SampleFormat::U16 => device.build_output_stream(
-            &config,
-            move |data: &mut [u16], _| {
-                let mut scratch = output_scratch.lock().unwrap();
-                if scratch.len() != data.len() {
-                    scratch.resize(data.len(), 0.0);
-                }
-                fill_output_buffer(&state, &consumer, &control_shared, &mut scratch);
-                apply_dither_if_needed(&state, &mut scratch, 16);
-                for (dst, src) in data.iter_mut().zip(scratch.iter()) {
-                    *dst = cpal::Sample::from_sample(*src);
+        /// Set band gains in dB by label ("31" to "16k") and enable the EQ;
+        /// an empty list disables it.
+        pub fn set_eq(&self, bands: &[(&str, f32)]) {
+            let mut state = self.shared.inner.lock().unwrap();
+            state.eq_enabled = !bands.is_empty();
+            for (band, gain) in bands {
+                if let Some(entry) = state.eq_bands.get_mut(*band) {
+                    *entry = *gain;
                 }
-            },
-            err_fn,
-            None,
-        )?,
-        _ => return Err(anyhow!("unsupported sample format")),
-    };
+            }
+            refresh_eq_filters(&mut state);
+            drop(state);
+            design_eq_filters(&self.shared);
+        }
 
-    stream.play()?;
-    guard.0 = Some(stream);
-    Ok(())
-}
+        /// Switch the EQ to the FIR implementation with `taps` taps.
+        pub fn set_eq_fir(&self, taps: usize) {
+            let mut state = self.shared.inner.lock().unwrap();
+            state.eq_filters.set_kind(EqKind::Fir, taps);
+            refresh_eq_filters(&mut state);
+            drop(state);
+            design_eq_filters(&self.shared);
+        }
 
-fn fill_output_buffer(
-    state: &Arc<Mutex<EngineState>>,
-    consumer: &Arc<Mutex<HeapCons<f32>>>,
-    control_shared: &Option<Arc<Mutex<ControlShared>>>,
-    data: &mut [f32],
-) {
-    let frames = data.len();
-    let mut local = state.lock().unwrap();
-    if let Some(control) = control_shared {
-        if let Ok(guard) = control.try_lock() {
-            drain_control_commands(&mut local, &guard);
+        /// Per-track gain override in dB.
+        pub fn set_track_gain(&self, gain_db: f32) {
+            self.shared.inner.lock().unwrap().track_gain_db = normalize_track_gain_db(gain_db);
         }
-    }
-    if !local.is_playing || local.is_paused {
-        for sample in data.iter_mut() {
-            *sample = 0.0;
+
+        /// `all` flips every channel; otherwise only `channels` are flipped.
+        pub fn set_polarity(&self, all: bool, channels: Vec<usize>) {
+            let mut state = self.shared.inner.lock().unwrap();
+            state.invert_polarity = all;
+            state.invert_channels = channels;
         }
-        return;
-    }
 
-    match local.mode.as_str() {
-        "file" => {
-            let channels = local.channels.max(1);
-            let frame_count = frames / channels;
-            let start = local.position * channels;
-            let end = (start + frame_count * channels).min(local.data.len());
-            let available = end.saturating_sub(start);
-            data[..available].copy_from_slice(&local.data[start..start + available]);
-            if available < data.len() {
-                for sample in data[available..].iter_mut() {
-                    *sample = 0.0;
-                }
-                local.is_playing = false;
-            }
-            local.position += frame_count;
+        /// Act as though an exclusive device's endpoint is taking the volume.
+        pub fn set_hardware_volume(&self, active: bool) {
+            let mut state = self.shared.inner.lock().unwrap();
+            state.volume_mode = if active { "hardware" } else { "software" }.to_string();
+            state.hardware_volume_active = active;
         }
-        "stream" | "capture" => {
-            let mut consumed = 0usize;
-            if let Ok(mut cons) = consumer.lock() {
-                for sample in data.iter_mut() {
-                    if let Some(v) = cons.try_pop() {
-                        *sample = v;
-                        consumed += 1;
-                    } else {
-                        *sample = 0.0;
-                    }
-                }
-            }
-            let channels = local.channels.max(1);
-            local.buffered_frames = local.buffered_frames.saturating_sub(consumed / channels);
-            if consumed < data.len() {
-                local.underrun_count += 1;
-            }
-            local.played_frames += (data.len() / channels) as u64;
+
+        /// One of `"none"`, `"mono"`, `"left"` or `"right"`.
+        pub fn set_downmix(&self, mode: &str) {
+            self.shared.inner.lock().unwrap().downmix = mode.to_string();
         }
-        _ => {
-            for sample in data.iter_mut() {
-                *sample = 0.0;
-            }
+
+        /// `"off"` disables dithering; other values follow `/configure_optimizations`.
+        pub fn set_dither(&self, dither_type: &str, bits: u32) {
+            let mut state = self.shared.inner.lock().unwrap();
+            state.dither_type = normalize_dither_type(dither_type);
+            state.dither_enabled = state.dither_type != "off";
+            state.dither_bits = normalize_dither_bits(bits);
         }
-    }
 
-    for sample in data.iter_mut() {
-        *sample *= local.volume;
-    }
-    if local.limiter_enabled {
-        let threshold = local.limiter_threshold;
-        for sample in data.iter_mut() {
-            *sample = soft_limit_sample(*sample, threshold);
+        fn output_channels(&self) -> usize {
+            let state = self.shared.inner.lock().unwrap();
+            state.output_channels.max(state.channels).max(1)
         }
-    }
-    if local.last_output_chunk.len() != SPECTRUM_FFT_SIZE {
-        local.last_output_chunk.resize(SPECTRUM_FFT_SIZE, 0.0);
-    }
-    let channels = local.channels.max(1) as usize;
-    let frames = data.len() / channels;
-    let copy_len = frames.min(SPECTRUM_FFT_SIZE);
-    if copy_len > 0 {
-        if channels == 1 {
-            local.last_output_chunk[..copy_len].copy_from_slice(&data[..copy_len]);
-        } else {
-            for frame in 0..copy_len {
-                let mut sum = 0.0f32;
-                let base = frame * channels;
-                for ch in 0..channels {
-                    sum += data[base + ch];
-                }
-                local.last_output_chunk[frame] = sum / channels as f32;
-            }
+
+        fn run(&self, frames: usize, output_bits: Option<u32>) -> Vec<f32> {
+            let mut out = vec![0.0f32; frames * self.output_channels()];
+            fill_output_buffer(&self.shared.inner, &self.shared.consumer, &None, &mut out, output_bits);
+            out
         }
-    }
-    if copy_len < SPECTRUM_FFT_SIZE {
-        for value in &mut local.last_output_chunk[copy_len..] {
-            *value = 0.0;
+
+        /// Run the f32 device callback for `frames` output frames.
+        pub fn render(&self, frames: usize) -> Vec<f32> {
+            self.run(frames, None)
         }
-    }
-}
-fn enumerate_devices() -> Vec<DeviceInfo> {
-    let mut devices = Vec::new();
-    let mut index = 0usize;
-    for host_id in cpal::available_hosts() {
-        if let Ok(host) = cpal::host_from_id(host_id) {
-            if let Ok(outputs) = host.output_devices() {
-                for device in outputs {
-                    let name = device.name().unwrap_or_else(|_| "Unknown".to_string());
-                    let sample_rate = device
-                        .default_output_config()
-                        .map(|c| c.sample_rate().0)
-                        .unwrap_or(48_000);
-                    devices.push(DeviceInfo {
-                        id: index,
-                        name,
-                        hostapi: format!("{:?}", host_id),
-                        default_samplerate: sample_rate,
-                    });
-                    index += 1;
-                }
-            }
+
+        /// Run the 16-bit integer device callback for `frames` output frames.
+        pub fn render_i16(&self, frames: usize) -> Vec<i16> {
+            self.run(frames, Some(16))
+                .iter()
+                .map(|s| cpal::Sample::from_sample(*s))
+                .collect()
         }
-    }
-    devices
-}
 
-fn list_devices() -> Value {
-    let mut wasapi = Vec::new();
-    let mut asio = Vec::new();
-    let mut other = Vec::new();
-    for info in enumerate_devices() {
-        match info.hostapi.as_str() {
-            "Wasapi" => wasapi.push(info),
-            "Asio" => asio.push(info),
-            _ => other.push(info),
+        /// Run the 32-bit integer device callback for `frames` output frames,
+        /// 24 significant bits in each sample.
+        pub fn render_i32(&self, frames: usize) -> Vec<i32> {
+            self.run(frames, Some(INT32_OUTPUT_BITS))
+                .iter()
+                .map(|s| f32_to_i32_sample(*s, INT32_OUTPUT_BITS))
+                .collect()
         }
-    }
-    json!({
-        "wasapi": wasapi,
-        "asio": asio,
-        "other": other
-    })
-}
 
-fn find_device_by_id(target: usize) -> Option<cpal::Device> {
-    let mut index = 0usize;
-    for host_id in cpal::available_hosts() {
-        if let Ok(host) = cpal::host_from_id(host_id) {
-            if let Ok(devices) = host.output_devices() {
-                for device in devices {
-                    if index == target {
-                        return Some(device);
-                    }
-                    index += 1;
-                }
-            }
+        /// Current read position in source frames.
+        pub fn position(&self) -> usize {
+            self.shared.inner.lock().unwrap().position
         }
-    }
-    None
-}
 
-fn device_hostapi_by_id(target: usize) -> Option<String> {
-    let mut index = 0usize;
-    for host_id in cpal::available_hosts() {
-        if let Ok(host) = cpal::host_from_id(host_id) {
-            if let Ok(devices) = host.output_devices() {
-                for _device in devices {
-                    if index == target {
-                        return Some(format!("{:?}", host_id));
-                    }
-                    index += 1;
-                }
-            }
+        pub fn is_playing(&self) -> bool {
+            self.shared.inner.lock().unwrap().is_playing
         }
-    }
-    None
-}
 
-fn wasapi_device_ordinal_by_id(target: usize) -> Option<u32> {
-    let mut index = 0usize;
-    let mut ordinal = 0u32;
-    for host_id in cpal::available_hosts() {
-        if let Ok(host) = cpal::host_from_id(host_id) {
-            if let Ok(devices) = host.output_devices() {
-                for _device in devices {
-                    if index == target {
-                        if format!("{:?}", host_id) == "Wasapi" {
-                            return Some(ordinal);
-                        }
-                        return None;
-                    }
-                    if format!("{:?}", host_id) == "Wasapi" {
-                        ordinal += 1;
-                    }
-                    index += 1;
-                }
-            }
+        /// Source frames a stream harness has left in its ring buffer.
+        pub fn buffered_frames(&self) -> usize {
+            self.shared.inner.lock().unwrap().buffered_frames
+        }
+
+        pub fn underruns(&self) -> u64 {
+            self.shared.inner.lock().unwrap().underrun_count
+        }
+
+        /// Samples the callbacks so far pushed past full scale.
+        pub fn clipped_samples(&self) -> u64 {
+            self.shared.inner.lock().unwrap().clip_count
+        }
+
+        pub fn auto_level_gain_db(&self) -> f32 {
+            linear_to_db(self.shared.inner.lock().unwrap().auto_level_gain as f32)
         }
     }
-    None
 }
 
-fn resolve_exclusive_mode(device_id: Option<usize>, requested: bool) -> bool {
-    if !requested {
-        return false;
-    }
-    if let Some(id) = device_id {
-        if let Some(hostapi) = device_hostapi_by_id(id) {
-            if hostapi == "Asio" {
-                return true;
-            }
-            if hostapi == "Wasapi" {
-                return cfg!(target_os = "windows");
-            }
-            return false;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A library track with a one-second duration and whichever tags
+    /// `tags` sets, e.g. `json!({ "title": "Home", "album": "Live" })`.
+    fn song(path: &str, tags: Value) -> LibraryTrack {
+        let mut fields = json!({ "path": path, "duration": 1.0 });
+        if let (Some(fields), Value::Object(tags)) = (fields.as_object_mut(), tags) {
+            fields.extend(tags);
         }
+        serde_json::from_value(fields).unwrap()
     }
-    if cfg!(target_os = "windows") {
-        let hostapi = format!("{:?}", cpal::default_host().id());
-        return hostapi == "Asio" || hostapi == "Wasapi";
+
+    #[test]
+    fn parse_restart_intent() {
+        assert_eq!(parse_command_text("重新播放").unwrap().action, "restart");
+        assert_eq!(parse_command_text("restart").unwrap().action, "restart");
+        assert_eq!(parse_command_text("播放").unwrap().action, "play");
     }
-    false
-}
 
-fn stop_exclusive_stream(shared: &SharedState) {
-    let mut guard = shared.exclusive_stream.lock().unwrap();
-    if let Some(mut handle) = guard.take() {
-        handle.stop();
+    #[test]
+    fn parse_volume_commands() {
+        let volume = |text: &str| {
+            let cmd = parse_command_text(text).unwrap();
+            assert_eq!(cmd.action, "volume", "{}", text);
+            cmd.volume
+        };
+        assert_eq!(volume("音量 50"), Some(VolumeChange::Set(0.5)));
+        assert_eq!(volume("音量调到百分之 30%"), Some(VolumeChange::Set(0.3)));
+        assert_eq!(volume("volume 0.25"), Some(VolumeChange::Set(0.25)));
+        assert_eq!(volume("Volume 250"), Some(VolumeChange::Set(1.0)));
+        assert_eq!(volume("volume up"), Some(VolumeChange::Up(None)));
+        assert_eq!(volume("louder"), Some(VolumeChange::Up(None)));
+        assert_eq!(volume("音量调大"), Some(VolumeChange::Up(None)));
+        assert_eq!(volume("volume down 20"), Some(VolumeChange::Down(Some(0.2))));
+        assert_eq!(volume("声音小一点"), Some(VolumeChange::Down(None)));
+        assert_eq!(volume("softer"), Some(VolumeChange::Down(None)));
+        assert_eq!(volume("静音"), Some(VolumeChange::Mute));
+        assert_eq!(volume("mute"), Some(VolumeChange::Mute));
+        assert_eq!(volume("取消静音"), Some(VolumeChange::Unmute));
+        assert_eq!(volume("unmute"), Some(VolumeChange::Unmute));
+        assert_eq!(volume("volume"), None);
+        // Short English words only count on their own.
+        assert_eq!(volume("volume update 40"), Some(VolumeChange::Set(0.4)));
+        assert_eq!(volume("volume for my commute"), None);
+        assert_ne!(parse_command_text("commute").unwrap().action, "volume");
+        assert_eq!(volume("音量up"), Some(VolumeChange::Up(None)));
+        assert_eq!(extract_volume_level("set it to 7"), Some(0.07));
+        assert_eq!(extract_volume_level("no number"), None);
+        assert_eq!(parse_command_text("play Volume One").unwrap().action, "play");
     }
-}
 
-#[cfg(target_os = "windows")]
-const WAVE_FORMAT_EXTENSIBLE_TAG: u16 = 0xFFFE;
+    #[test]
+    fn volume_commands_nudge_clamp_and_unmute() {
+        let shared = create_shared_state();
+        let run = |text: &str| {
+            let cmd = parse_command_text(text).unwrap();
+            handle_command_impl(&shared, cmd).map(|result| result.volume.unwrap())
+        };
+        assert_eq!(run("音量 50").unwrap(), 0.5);
+        assert!((run("volume up").unwrap() - 0.6).abs() < 1e-6);
+        shared.inner.lock().unwrap().command_volume_step = 0.5;
+        assert_eq!(run("louder").unwrap(), 1.0);
+        assert_eq!(run("静音").unwrap(), 0.0);
+        assert_eq!(run("mute").unwrap(), 0.0);
+        assert_eq!(run("unmute").unwrap(), 1.0);
+        assert_eq!(run("volume down 0.25").unwrap(), 0.75);
+        assert!(run("volume").is_err());
+
+        // An explicit action takes its argument from the query.
+        let cmd = ParsedCommand {
+            action: "volume".to_string(),
+            query: Some("30".to_string()),
+            raw: String::new(),
+            disambiguate: false,
+            ..Default::default()
+        };
+        assert_eq!(handle_command_impl(&shared, cmd).unwrap().volume, Some(0.3));
+    }
 
-#[cfg(target_os = "windows")]
-fn channel_mask_for(channels: u16) -> u32 {
-    match channels {
-        1 => SPEAKER_FRONT_CENTER,
-        2 => SPEAKER_FRONT_LEFT | SPEAKER_FRONT_RIGHT,
-        6 => {
-            SPEAKER_FRONT_LEFT
-                | SPEAKER_FRONT_RIGHT
-                | SPEAKER_FRONT_CENTER
-                | SPEAKER_LOW_FREQUENCY
-                | SPEAKER_BACK_LEFT
-                | SPEAKER_BACK_RIGHT
-        }
-        8 => {
-            SPEAKER_FRONT_LEFT
-                | SPEAKER_FRONT_RIGHT
-                | SPEAKER_FRONT_CENTER
-                | SPEAKER_LOW_FREQUENCY
-                | SPEAKER_BACK_LEFT
-                | SPEAKER_BACK_RIGHT
-                | SPEAKER_SIDE_LEFT
-                | SPEAKER_SIDE_RIGHT
+    #[test]
+    fn analysis_cache_forgets_the_least_recently_used_file() {
+        let cache = AnalysisCache::new(2);
+        let paths: Vec<String> = ["a", "b", "c"]
+            .iter()
+            .map(|name| {
+                let path = std::env::temp_dir().join(format!("ntmusic_analysis_{}_{}", name, std::process::id()));
+                std::fs::write(&path, name).unwrap();
+                path.to_string_lossy().into_owned()
+            })
+            .collect();
+        let analyze = |path: &String| cached_analysis(&cache, path, || Ok(path.clone())).unwrap().1;
+        assert!(!analyze(&paths[0]));
+        assert!(!analyze(&paths[1]));
+        assert!(analyze(&paths[0]));
+        // Full: the new file pushes out "b", used longest ago.
+        assert!(!analyze(&paths[2]));
+        assert!(analyze(&paths[0]));
+        assert!(analyze(&paths[2]));
+        assert!(!analyze(&paths[1]));
+        for path in &paths {
+            let _ = std::fs::remove_file(path);
         }
-        _ => 0,
+        assert_eq!(cache.entries().lock().unwrap().files.len(), 2);
     }
-}
 
-#[cfg(target_os = "windows")]
-struct ComInit;
+    #[test]
+    fn settings_changes_are_saved_once_by_the_writer() {
+        let shared = create_shared_state();
+        let path = std::env::temp_dir().join(format!("ntmusic_settings_flush_{}.json", std::process::id()));
+        shared.inner.lock().unwrap().settings_path = Some(path.clone());
+        handle_command_impl(&shared, parse_command_text("volume 30").unwrap()).unwrap();
+        handle_command_impl(&shared, parse_command_text("volume 40").unwrap()).unwrap();
+        assert!(!path.exists(), "changes wait for the writer");
 
-#[cfg(target_os = "windows")]
-impl ComInit {
-    fn new() -> Result<Self> {
-        unsafe {
-            CoInitializeEx(None, COINIT_MULTITHREADED)
-                .ok()
-                .map_err(|err| anyhow!("CoInitializeEx failed: {}", err))?;
-        }
-        Ok(Self)
+        flush_settings(&shared);
+        assert_eq!(PersistedSettings::load(&path).volume, 0.4);
+        std::fs::remove_file(&path).unwrap();
+        flush_settings(&shared);
+        assert!(!path.exists(), "nothing changed since");
     }
-}
 
-#[cfg(target_os = "windows")]
-impl Drop for ComInit {
-    fn drop(&mut self) {
-        unsafe {
-            CoUninitialize();
-        }
+    #[test]
+    fn parse_seek_times() {
+        assert_eq!(parse_seek_time("1:05"), Some(65.0));
+        assert_eq!(parse_seek_time("65"), Some(65.0));
+        assert_eq!(parse_seek_time("0:59.5"), Some(59.5));
+        assert_eq!(parse_seek_time("1:02:03"), Some(3723.0));
+        assert_eq!(parse_seek_time("12.5s"), Some(12.5));
+        assert_eq!(parse_seek_time("2:05."), Some(125.0));
+        assert_eq!(parse_seek_time("1:5"), None);
+        assert_eq!(parse_seek_time("1:75"), None);
+        assert_eq!(parse_seek_time("1:2:03"), None);
+        assert_eq!(parse_seek_time("1:02:3"), None);
+        assert_eq!(parse_seek_time("1:00:00:00"), None);
+        assert_eq!(parse_seek_time("nowhere"), None);
+
+        let seek = |text: &str| {
+            let cmd = parse_command_text(text).unwrap();
+            (cmd.action, cmd.seek)
+        };
+        assert_eq!(seek("跳到 1:30"), ("seek".to_string(), Some(90.0)));
+        assert_eq!(seek("seek 90"), ("seek".to_string(), Some(90.0)));
+        assert_eq!(seek("Goto 2:05"), ("seek".to_string(), Some(125.0)));
+        assert_eq!(seek("jump to 0:07"), ("seek".to_string(), Some(7.0)));
+        assert_eq!(seek("跳到下一曲"), ("next".to_string(), None));
     }
-}
-
-#[cfg(target_os = "windows")]
-fn build_wave_format(channels: u16, sample_rate: u32) -> WAVEFORMATEXTENSIBLE {
-    let mut format = WAVEFORMATEXTENSIBLE::default();
-    format.Format.wFormatTag = WAVE_FORMAT_EXTENSIBLE_TAG;
-    format.Format.nChannels = channels;
-    format.Format.nSamplesPerSec = sample_rate;
-    format.Format.wBitsPerSample = 32;
-    format.Format.nBlockAlign = channels.saturating_mul(4);
-    format.Format.nAvgBytesPerSec = sample_rate.saturating_mul(format.Format.nBlockAlign as u32);
-    format.Format.cbSize = (std::mem::size_of::<WAVEFORMATEXTENSIBLE>()
-        - std::mem::size_of::<WAVEFORMATEX>()) as u16;
-    format.Samples = WAVEFORMATEXTENSIBLE_0 {
-        wValidBitsPerSample: 32,
-    };
-    format.dwChannelMask = channel_mask_for(channels);
-    format.SubFormat = KSDATAFORMAT_SUBTYPE_IEEE_FLOAT;
-    format
-}
 
-#[cfg(target_os = "windows")]
-fn select_wasapi_device(
-    enumerator: &IMMDeviceEnumerator,
-    device_ordinal: Option<u32>,
-) -> Result<IMMDevice> {
-    unsafe {
-        if let Some(ordinal) = device_ordinal {
-            let collection =
-                enumerator.EnumAudioEndpoints(eRender, DEVICE_STATE_ACTIVE)?;
-            let count = collection
-                .GetCount()
-                .map_err(|err| anyhow!("GetCount failed: {}", err))?;
-            if ordinal >= count {
-                return Err(anyhow!("WASAPI device ordinal out of range"));
-            }
-            let device = collection
-                .Item(ordinal)
-                .map_err(|err| anyhow!("EnumAudioEndpoints item failed: {}", err))?;
-            Ok(device)
-        } else {
-            enumerator
-                .GetDefaultAudioEndpoint(eRender, eConsole)
-                .map_err(|err| anyhow!("GetDefaultAudioEndpoint failed: {}", err))
+    #[test]
+    fn seek_commands_move_a_loaded_file_and_refuse_otherwise() {
+        let shared = create_shared_state();
+        let run = |text: &str| handle_command_impl(&shared, parse_command_text(text).unwrap());
+        assert!(run("seek 1").unwrap_err().to_string().contains("file mode"));
+        {
+            let mut state = shared.inner.lock().unwrap();
+            state.mode = "file".to_string();
+            state.sample_rate = 1000;
+            state.channels = 1;
+            state.data = vec![0.0; 120_000];
         }
+        assert_eq!(run("跳到 1:05").unwrap().position, Some(65.0));
+        assert_eq!(shared.inner.lock().unwrap().position, 65_000);
+        assert!(run("seek 2:00").unwrap_err().to_string().contains("out of range"));
+        assert_eq!(shared.inner.lock().unwrap().position, 65_000);
     }
-}
 
-#[cfg(target_os = "windows")]
-fn run_wasapi_exclusive_loop(
-    stop: Arc<AtomicBool>,
-    state: Arc<Mutex<EngineState>>,
-    consumer: Arc<Mutex<HeapCons<f32>>>,
-    control_shared: Option<Arc<Mutex<ControlShared>>>,
-    device_ordinal: Option<u32>,
-    sample_rate: u32,
-    channels: u16,
-) -> Result<()> {
-    let _com = ComInit::new()?;
-    let enumerator: IMMDeviceEnumerator = unsafe {
-        CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)
-            .map_err(|err| anyhow!("CoCreateInstance failed: {}", err))?
-    };
-    let device = select_wasapi_device(&enumerator, device_ordinal)?;
-    let audio_client: IAudioClient = unsafe {
-        device
-            .Activate(CLSCTX_ALL, None)
-            .map_err(|err| anyhow!("IMMDevice Activate failed: {}", err))?
-    };
-    let format = build_wave_format(channels, sample_rate);
-    unsafe {
-        audio_client
-            .IsFormatSupported(AUDCLNT_SHAREMODE_EXCLUSIVE, &format.Format, None)
-            .ok()
-            .map_err(|err| anyhow!("Exclusive format unsupported: {}", err))?;
-    }
-    let mut default_period = 0i64;
-    let mut min_period = 0i64;
-    unsafe {
-        audio_client
-            .GetDevicePeriod(Some(&mut default_period), Some(&mut min_period))
-            .map_err(|err| anyhow!("GetDevicePeriod failed: {}", err))?;
+    #[test]
+    fn ambiguous_title_queries_return_candidates_instead_of_playing() {
+        let shared = create_shared_state();
+        shared.inner.lock().unwrap().library = vec![
+            song("/m/home.flac", json!({ "title": "Home", "artist": "Band", "album": "Live" })),
+            song("/m/home_again.flac", json!({ "title": "Home Again", "artist": "Band", "album": "Studio" })),
+            song("/m/homeward.flac", json!({ "title": "Homeward", "artist": "Band", "album": "Studio" })),
+            song("/m/home_live.flac", json!({ "title": "Home", "artist": "Band", "album": "Studio" })),
+        ];
+        let play = |query: &str, disambiguate: bool| {
+            let cmd = ParsedCommand {
+                action: "play".to_string(),
+                query: Some(query.to_string()),
+                raw: String::new(),
+                disambiguate,
+                ..Default::default()
+            };
+            handle_command_impl(&shared, cmd)
+        };
+
+        // Two songs are titled exactly "Home": ask which.
+        let result = play("home", false).unwrap();
+        assert_eq!(result.matches, 4);
+        assert!(result.track.is_none());
+        let paths: Vec<&str> = result.candidates.iter().map(|t| t.path.as_str()).collect();
+        assert_eq!(paths, vec!["/m/home.flac", "/m/home_live.flac"]);
+        assert!(shared.inner.lock().unwrap().queue.is_empty());
+
+        // Forcing the list returns every match, best first.
+        let result = play("home", true).unwrap();
+        assert_eq!(result.candidates.len(), 4);
+        assert_eq!(result.candidates[2].path, "/m/home_again.flac");
+
+        let homeward = song("/m/a.flac", json!({ "title": "Homeward", "artist": "Band", "album": "Studio" }));
+        let untitled = song("/m/a.flac", json!({ "title": "X", "artist": "Band", "album": "Studio" }));
+        assert_eq!(track_match_score(&homeward, "homeward"), Some(MATCH_TITLE_EXACT));
+        assert_eq!(track_match_score(&untitled, "studio"), Some(MATCH_TAG_EXACT));
+        assert_eq!(track_match_score(&untitled, "m/a"), Some(MATCH_PATH));
+        assert_eq!(track_match_score(&untitled, "zzz"), None);
     }
-    let period = if default_period > 0 {
-        default_period
-    } else if min_period > 0 {
-        min_period
-    } else {
-        10_000
-    };
-    unsafe {
-        audio_client
-            .Initialize(
-                AUDCLNT_SHAREMODE_EXCLUSIVE,
-                AUDCLNT_STREAMFLAGS_EVENTCALLBACK,
-                period,
-                period,
-                &format.Format,
-                None,
-            )
-            .map_err(|err| anyhow!("IAudioClient Initialize failed: {}", err))?;
+
+    #[test]
+    fn queries_ignore_accents_and_tolerate_missing_letters() {
+        let cafe = song("/m/cafe.flac", json!({ "title": "Café del Mar", "artist": "Energy 52" }));
+        assert_eq!(track_match_score(&cafe, "cafe del mar"), Some(MATCH_TITLE_EXACT));
+        assert_eq!(track_match_score(&cafe, "CAFÉ"), Some(MATCH_TITLE_PARTIAL));
+        let wide = song("/m/x.flac", json!({ "title": "Ｈｅｌｌｏ", "artist": "A" }));
+        assert_eq!(track_match_score(&wide, "hello"), Some(MATCH_TITLE_EXACT));
+        let help = song("/m/help.flac", json!({ "title": "Help!", "artist": "The Beatles" }));
+        assert_eq!(track_match_score(&help, "beatles"), Some(MATCH_TAG_PARTIAL));
+        // A dropped letter still finds them, below any real substring hit.
+        let fuzzy = track_match_score(&help, "beatls").unwrap();
+        assert!(fuzzy > MATCH_PATH && fuzzy < MATCH_TAG_PARTIAL, "{}", fuzzy);
+        assert_eq!(track_match_score(&help, "bts"), None);
+        assert_eq!(track_match_score(&help, "bt"), None);
+        assert_eq!(subsequence_closeness("the beatles", "beatles"), Some(1.0));
+        assert_eq!(subsequence_closeness("abc", "cba"), None);
+
+        // A play command finds it the same way.
+        let shared = create_shared_state();
+        shared.inner.lock().unwrap().library = vec![
+            song("/m/loose.flac", json!({ "title": "Blue Eyes Asleep", "artist": "B" })),
+            song("/m/close.flac", json!({ "title": "Blue Skies", "artist": "B" })),
+        ];
+        let cmd = ParsedCommand {
+            action: "play".to_string(),
+            query: Some("bluskies".to_string()),
+            raw: String::new(),
+            disambiguate: true,
+            ..Default::default()
+        };
+        let result = handle_command_impl(&shared, cmd).unwrap();
+        let paths: Vec<&str> = result.candidates.iter().map(|t| t.path.as_str()).collect();
+        assert_eq!(paths, ["/m/close.flac"]);
     }
-    let event = unsafe {
-        CreateEventA(None, false, false, PCSTR::null())
-            .map_err(|err| anyhow!("CreateEventA failed: {}", err))?
-    };
-    unsafe {
-        audio_client
-            .SetEventHandle(event)
-            .map_err(|err| anyhow!("SetEventHandle failed: {}", err))?;
+
+    #[test]
+    fn scan_results_sort_by_tag_with_missing_tags_last() {
+        let mut tracks = vec![
+            song("/m/d.flac", json!({ "album": "Blue", "track_number": 1, "duration": 30.0 })),
+            song("/m/c.flac", json!({ "title": "beta", "album": "blue", "track_number": 2, "duration": 10.0 })),
+            song("/m/b.flac", json!({ "title": "Alpha", "duration": 20.0 })),
+            song("/m/a.flac", json!({ "title": "Beta", "album": "Blue", "track_number": 1, "duration": 40.0 })),
+        ];
+        let order = |tracks: &[LibraryTrack]| tracks.iter().map(|t| t.path.clone()).collect::<Vec<_>>();
+
+        sort_library_tracks(&mut tracks, LibrarySort::Title, true);
+        // Equal titles, whatever their case, fall back to track order.
+        assert_eq!(order(&tracks), ["/m/b.flac", "/m/a.flac", "/m/c.flac", "/m/d.flac"]);
+        sort_library_tracks(&mut tracks, LibrarySort::Title, false);
+        assert_eq!(order(&tracks), ["/m/a.flac", "/m/c.flac", "/m/b.flac", "/m/d.flac"]);
+        sort_library_tracks(&mut tracks, LibrarySort::Album, true);
+        assert_eq!(order(&tracks), ["/m/a.flac", "/m/d.flac", "/m/c.flac", "/m/b.flac"]);
+        sort_library_tracks(&mut tracks, LibrarySort::Duration, false);
+        assert_eq!(order(&tracks), ["/m/a.flac", "/m/d.flac", "/m/b.flac", "/m/c.flac"]);
+        sort_library_tracks(&mut tracks, LibrarySort::Path, false);
+        assert_eq!(order(&tracks), ["/m/d.flac", "/m/c.flac", "/m/b.flac", "/m/a.flac"]);
+        assert_eq!(LibrarySort::from_label(" Artist "), Some(LibrarySort::Artist));
+        assert_eq!(LibrarySort::from_label("rating"), None);
     }
-    let buffer_frames = unsafe {
-        audio_client
-            .GetBufferSize()
-            .map_err(|err| anyhow!("GetBufferSize failed: {}", err))?
-    };
-    let render_client: IAudioRenderClient = unsafe {
-        audio_client
-            .GetService()
-            .map_err(|err| anyhow!("GetService(IARenderClient) failed: {}", err))?
-    };
-    unsafe {
-        audio_client
-            .Start()
-            .map_err(|err| anyhow!("AudioClient Start failed: {}", err))?;
+
+    #[test]
+    fn library_search_ranks_without_touching_playback() {
+        let shared = create_shared_state();
+        shared.inner.lock().unwrap().library = vec![
+            song("/m/river/1.flac", json!({ "title": "Down by the River", "album": "Live" })),
+            song("/m/2.flac", json!({ "title": "River", "album": "Blue" })),
+            song("/m/3.flac", json!({ "title": "Lake", "album": "River Songs" })),
+            song("/m/4.flac", json!({ "title": "Sea", "album": "Open" })),
+        ];
+        let (total, tracks) = library_search_impl(&shared, " river ", None).unwrap();
+        assert_eq!(total, 3);
+        let paths: Vec<&str> = tracks.iter().map(|t| t.path.as_str()).collect();
+        assert_eq!(paths, ["/m/2.flac", "/m/river/1.flac", "/m/3.flac"]);
+        let (total, tracks) = library_search_impl(&shared, "river", Some(1)).unwrap();
+        assert_eq!((total, tracks.len()), (3, 1));
+        assert!(library_search_impl(&shared, "  ", None).is_err());
+        let state = shared.inner.lock().unwrap();
+        assert!(state.queue.is_empty());
+        assert!(state.file_path.is_none());
     }
-    let mut scratch = vec![0.0f32; buffer_frames as usize * channels as usize];
-    while !stop.load(Ordering::Acquire) {
-        let wait = unsafe { WaitForSingleObject(event, 100) };
-        if wait != WAIT_OBJECT_0 {
-            if wait == WAIT_TIMEOUT {
-                continue;
-            }
-            continue;
+
+    #[tokio::test]
+    async fn background_scan_answers_with_an_id_and_reports_completion() {
+        let root = std::env::temp_dir().join(format!("ntmusic_bg_scan_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        for name in ["b.flac", "a.flac"] {
+            std::fs::write(root.join(name), b"x").unwrap();
         }
-        let padding = unsafe {
-            audio_client
-                .GetCurrentPadding()
-                .map_err(|err| anyhow!("GetCurrentPadding failed: {}", err))?
+        let shared = create_shared_state();
+        let cache_path = std::env::temp_dir().join(format!("ntmusic_bg_scan_cache_{}.json", std::process::id()));
+        shared.inner.lock().unwrap().scan_cache_path = cache_path.clone();
+        let mut rx = shared.tx.subscribe();
+        let scan = |body: Value| {
+            let shared = shared.clone();
+            async move {
+                let req = serde_json::from_value(body).unwrap();
+                let response = scan_library_handler(State(shared), Json(req)).await.into_response();
+                let code = response.status();
+                let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (code, serde_json::from_slice::<Value>(&bytes).unwrap())
+            }
         };
-        let available = buffer_frames.saturating_sub(padding);
-        if available == 0 {
-            continue;
-        }
-        let frames = available as usize;
-        let needed = frames * channels as usize;
-        if scratch.len() < needed {
-            scratch.resize(needed, 0.0);
-        }
-        fill_output_buffer(&state, &consumer, &control_shared, &mut scratch[..needed]);
-        let buffer = unsafe {
-            render_client
-                .GetBuffer(available)
-                .map_err(|err| anyhow!("GetBuffer failed: {}", err))?
+
+        let path = root.to_string_lossy();
+        let (code, started) = scan(json!({ "path": path, "background": true, "sort_by": "path" })).await;
+        assert_eq!(code, StatusCode::OK);
+        assert!(started.get("tracks").is_none());
+        let complete = loop {
+            let event: Value = serde_json::from_str(&rx.recv().await.unwrap()).unwrap();
+            if event["type"] == "scan_complete" {
+                break event;
+            }
         };
-        unsafe {
-            std::ptr::copy_nonoverlapping(
-                scratch.as_ptr(),
-                buffer as *mut f32,
-                needed,
-            );
-            render_client
-                .ReleaseBuffer(available, 0)
-                .map_err(|err| anyhow!("ReleaseBuffer failed: {}", err))?;
-        }
-    }
-    unsafe {
-        let _ = audio_client.Stop();
+        assert_eq!(complete["scan_id"], started["scan_id"]);
+        assert_eq!(complete["status"], "success");
+        let tracks = complete["tracks"].as_array().unwrap();
+        let paths: Vec<&str> = tracks.iter().map(|t| t["path"].as_str().unwrap()).collect();
+        assert!(paths[0].ends_with("a.flac") && paths[1].ends_with("b.flac"));
+        assert!(!shared.scan_active.load(Ordering::Acquire));
+
+        let (code, missing) = scan(json!({ "path": root.join("nope").to_string_lossy(), "background": true })).await;
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+        assert_eq!(missing["status"], "error");
+        assert!(!shared.scan_active.load(Ordering::Acquire));
+
+        // A per-scan list replaces the configured one, and files Symphonia
+        // can't decode are still listed, flagged and titled by name.
+        std::fs::write(root.join("c.WV"), b"x").unwrap();
+        let (code, scanned) = scan(json!({ "path": path, "extensions": [".wv", "flac"] })).await;
+        assert_eq!(code, StatusCode::OK);
+        let tracks = scanned["tracks"].as_array().unwrap();
+        assert_eq!(tracks.len(), 3);
+        let wavpack = tracks.iter().find(|t| t["path"].as_str().unwrap().ends_with("c.WV")).unwrap();
+        assert_eq!((wavpack["title"].as_str(), wavpack["decodable"].as_bool()), (Some("c"), Some(false)));
+        assert_eq!(shared.inner.lock().unwrap().audio_extensions, default_audio_extensions());
+        let (code, _) = scan(json!({ "path": path, "extensions": ["../wv"] })).await;
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+        assert!(!shared.scan_active.load(Ordering::Acquire));
+        let _ = std::fs::remove_file(&cache_path);
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[tokio::test]
+    async fn batch_runs_in_order_and_stops_at_the_first_failure() {
+        let shared = create_shared_state();
+        let run = |body: Value| {
+            let shared = shared.clone();
+            async move {
+                let req = serde_json::from_value(body).unwrap();
+                let response = batch_handler(State(shared), Json(req)).await.into_response();
+                let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                serde_json::from_slice::<Value>(&bytes).unwrap()
+            }
+        };
+
+        let stopped = run(json!({ "commands": [
+            { "op": "volume", "params": { "volume": 0.25 } },
+            { "op": "seek", "params": { "position": 1.0 } },
+            { "op": "set_eq_type", "params": { "type": "FIR" } },
+        ]}))
+        .await;
+        assert_eq!(stopped["status"], "error");
+        assert_eq!(stopped["failed_at"], 1);
+        assert_eq!(stopped["results"].as_array().unwrap().len(), 2);
+        assert_eq!(stopped["results"][1]["code"], 400);
+        assert_eq!(shared.inner.lock().unwrap().volume, 0.25);
+        assert_eq!(shared.inner.lock().unwrap().eq_type, "IIR");
+
+        let continued = run(json!({ "continue_on_error": true, "commands": [
+            { "op": "nope" },
+            { "op": "volume", "params": {} },
+            { "op": "/set_eq_type", "params": { "type": "FIR" } },
+            { "op": "state" },
+        ]}))
+        .await;
+        let results = continued["results"].as_array().unwrap();
+        assert_eq!(continued["failed_at"], 0);
+        assert_eq!(results.len(), 4);
+        assert!(results[1]["result"]["message"].as_str().unwrap().starts_with("invalid params"));
+        assert_eq!(results[3]["result"]["state"]["eq_type"], "FIR");
     }
-    Ok(())
-}
 
-#[cfg(target_os = "windows")]
-fn start_wasapi_exclusive_stream(
-    shared: &SharedState,
-    device_ordinal: Option<u32>,
-) -> Result<ExclusiveStreamHandle> {
-    let stop = Arc::new(AtomicBool::new(false));
-    let stop_flag = stop.clone();
-    let state = shared.inner.clone();
-    let consumer = shared.consumer.clone();
-    let control_shared = shared.control_shared.clone();
-    let (sample_rate, channels) = {
-        let guard = state.lock().unwrap();
-        (guard.sample_rate.max(8000), guard.channels.max(1) as u16)
-    };
-    let thread = thread::spawn(move || {
-        if let Err(err) = run_wasapi_exclusive_loop(
-            stop_flag,
-            state,
-            consumer,
-            control_shared,
-            device_ordinal,
-            sample_rate,
-            channels,
-        ) {
-            error!("wasapi exclusive stream failed: {}", err);
+    #[test]
+    fn clipped_samples_are_counted_and_counters_reset() {
+        let harness = testing::OutputHarness::new(vec![0.5, 0.9, -0.9], 1, 48_000);
+        harness.set_volume(1.0);
+        harness.set_track_gain(3.0);
+        harness.render(3);
+        let shared = create_shared_state();
+        {
+            let mut state = shared.inner.lock().unwrap();
+            state.underrun_count = u64::MAX;
+            state.dropped_sample_count = 12;
+            state.clip_count = harness.clipped_samples();
         }
-    });
-    Ok(ExclusiveStreamHandle {
-        stop,
-        thread: Some(thread),
-    })
-}
+        let mut rx = shared.tx.subscribe();
+        let previous = reset_diagnostics_impl(&shared);
+        assert_eq!(
+            previous,
+            DiagnosticCounters { underruns: u64::MAX, dropped_samples: 12, clipped_samples: 2 }
+        );
+        let event: Value = serde_json::from_str(&rx.try_recv().unwrap()).unwrap();
+        assert_eq!(event["type"], "diagnostics_reset");
+        assert_eq!(event["previous"]["clipped_samples"], 2);
+        let view = build_state_view(&shared.inner.lock().unwrap());
+        assert_eq!((view.underruns, view.dropped_samples, view.clipped_samples), (0, 0, 0));
+    }
 
-#[cfg(not(target_os = "windows"))]
-fn start_wasapi_exclusive_stream(
-    _shared: &SharedState,
-    _device_ordinal: Option<u32>,
-) -> Result<ExclusiveStreamHandle> {
-    Err(anyhow!("WASAPI exclusive output is only supported on Windows"))
-}
+    #[test]
+    fn restart_rewinds_file_and_plays() {
+        let shared = create_shared_state();
+        {
+            let mut state = shared.inner.lock().unwrap();
+            state.mode = "file".to_string();
+            state.data = vec![0.0; 4800];
+            state.position = 1200;
+            state.played_frames = 1200;
+            state.is_paused = true;
+        }
+        restart_impl(&shared).unwrap();
+        let mut state = shared.inner.lock().unwrap();
+        assert_eq!((state.position, state.played_frames), (0, 0));
+        assert!(state.is_playing && !state.is_paused);
 
-async fn ws_handler(ws: WebSocketUpgrade, State(state): State<SharedState>) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_socket(socket, state))
-}
+        // The control-shm command resets the same counters.
+        state.position = 1200;
+        state.played_frames = 1200;
+        state.is_paused = true;
+        let mut mmap = MmapMut::map_anon(CONTROL_HEADER_BYTES + 2 * CONTROL_CMD_BYTES).unwrap();
+        mmap[..4].copy_from_slice(&1u32.to_ne_bytes());
+        mmap[CONTROL_HEADER_BYTES..CONTROL_HEADER_BYTES + 4].copy_from_slice(&CONTROL_CMD_RESTART.to_ne_bytes());
+        drain_control_commands(&mut state, &ControlShared { mmap, capacity: 2 });
+        assert_eq!((state.position, state.played_frames), (0, 0));
+        assert!(state.is_playing && !state.is_paused);
+    }
 
-async fn handle_socket(mut socket: WebSocket, state: SharedState) {
-    let mut rx = state.tx.subscribe();
-    while let Ok(msg) = rx.recv().await {
-        if socket.send(Message::Text(msg.into())).await.is_err() {
-            break;
+    #[test]
+    fn unmonitored_capture_is_silent_but_still_analysed() {
+        let shared = create_shared_state();
+        {
+            let mut state = shared.inner.lock().unwrap();
+            state.mode = "capture".to_string();
+            state.channels = 2;
+            state.output_channels = 2;
+            state.is_playing = true;
+            state.transport_gain = 1.0;
+            state.replaygain_enabled = false;
+            state.dither_enabled = false;
+            state.capture_monitor = false;
         }
+        shared.producer.lock().unwrap().push_slice(&[0.5; 512]);
+        let mut out = vec![1.0f32; 512];
+        fill_output_buffer(&shared.inner, &shared.consumer, &None, &mut out, None);
+        assert!(out.iter().all(|s| *s == 0.0));
+        let state = shared.inner.lock().unwrap();
+        let mut window = vec![0.0f32; 256];
+        latest_spectrum_window(&state, &mut window);
+        assert!(window.iter().all(|s| *s > 0.4));
+        assert!(same_device_name(" Speakers (Realtek) ", "speakers (realtek)"));
+        assert!(!same_device_name("Speakers", "Headphones"));
     }
-}
 
-fn load_file_impl(shared: &SharedState, path: String) -> Result<()> {
-    if !Path::new(&path).exists() {
-        return Err(anyhow!("File not found"));
+    #[test]
+    fn loopback_refuses_a_format_the_mix_format_lacks() {
+        assert!(CaptureError::check_loopback_format(None, None, 48_000, 2).is_none());
+        assert!(CaptureError::check_loopback_format(Some(48_000), Some(2), 48_000, 2).is_none());
+        let err = CaptureError::check_loopback_format(Some(44_100), None, 48_000, 2).unwrap();
+        assert!(err.to_string().starts_with("loopback records 48000 Hz, 2 channels; 44100 Hz, 2 channels"));
+        assert!(CaptureError::check_loopback_format(None, Some(6), 48_000, 2).is_some());
     }
-    stop_stream(shared);
-    let decoded = decode_file(&path).map_err(|err| anyhow!("decode failed: {}", err))?;
-    let source_sample_rate = decoded.sample_rate;
-    let source_channels = decoded.channels;
-    let source_bit_depth = decoded.bit_depth;
 
-    let soxr_available = detect_soxr_available();
-    let (target_samplerate, resampler_mode, resampler_quality) = {
+    #[test]
+    fn buffer_fill_tracks_ring_capacity() {
+        let shared = create_shared_state();
+        {
+            let mut state = shared.inner.lock().unwrap();
+            state.sample_rate = 44_100;
+            state.channels = 2;
+            state.buffer_max_ms = 1000;
+        }
+        reset_ring_buffer(&shared);
         let mut state = shared.inner.lock().unwrap();
-        state.soxr_available = soxr_available;
-        (
-            state.target_samplerate,
-            state.resampler_mode.clone(),
-            state.resampler_quality.clone(),
-        )
-    };
+        assert_eq!(state.ring_capacity_frames, 44_100);
+        assert_eq!(buffer_fill_ratio(&state), 0.0);
+        state.buffered_frames = 11_025;
+        assert!((buffer_fill_ratio(&state) - 0.25).abs() < 1e-9);
+        state.buffered_frames = 50_000;
+        assert_eq!(buffer_fill_ratio(&state), 1.0);
+        state.dropped_sample_count = 7;
+        let view = build_state_view(&state);
+        assert_eq!(view.buffer_fill, 1.0);
+        assert_eq!(view.dropped_samples, 7);
+    }
 
-    let mut final_data = decoded.samples;
-    let mut final_sample_rate = decoded.sample_rate;
-    if let Some(target) = target_samplerate {
-        if target > 0 && target != final_sample_rate {
-            let mode = normalize_resampler_mode(&resampler_mode);
-            let quality = normalize_resampler_quality(&resampler_quality);
-            let prefer_soxr = should_prefer_soxr(&mode, &quality, soxr_available);
-            if prefer_soxr {
-                match resample_audio_soxr(&final_data, source_channels, final_sample_rate, target) {
-                    Ok(resampled) => {
-                        final_data = resampled;
-                        final_sample_rate = target;
-                    }
-                    Err(err) => {
-                        if mode == "auto" {
-                            error!("soxr resample failed, falling back to rubato: {}", err);
-                            final_data = resample_audio(
-                                &final_data,
-                                source_channels,
-                                final_sample_rate,
-                                target,
-                                &quality,
-                            )
-                                    .map_err(|e| anyhow!("resample failed: {}", e))?;
-                            final_sample_rate = target;
-                        } else {
-                            return Err(anyhow!("soxr resample failed: {}", err));
-                        }
-                    }
-                }
-            } else {
-                final_data = resample_audio(
-                    &final_data,
-                    source_channels,
-                    final_sample_rate,
-                    target,
-                    &quality,
-                )
-                        .map_err(|e| anyhow!("resample failed: {}", e))?;
-                final_sample_rate = target;
-            }
+    #[test]
+    fn buffer_overflow_is_reported_once_per_stream() {
+        let shared = create_shared_state();
+        {
+            let mut state = shared.inner.lock().unwrap();
+            state.sample_rate = 48_000;
+            state.channels = 2;
+            state.buffer_max_ms = 10;
         }
+        reset_ring_buffer(&shared);
+        let capacity = shared.inner.lock().unwrap().ring_capacity_frames * 2;
+        let mut rx = shared.tx.subscribe();
+        let mut overflows = || {
+            std::iter::from_fn(|| rx.try_recv().ok())
+                .map(|event| serde_json::from_str::<Value>(&event).unwrap())
+                .filter(|event| event["type"] == "buffer_overflow")
+                .collect::<Vec<_>>()
+        };
+
+        let mut feeder = RingFeeder::new(&shared);
+        feeder.push(std::iter::repeat_n(0.5, capacity + 10));
+        feeder.push(std::iter::repeat_n(0.5, 20));
+        let reported = overflows();
+        assert_eq!(reported.len(), 1);
+        assert_eq!(reported[0]["dropped_samples"], 10);
+        assert_eq!(reported[0]["buffer_max_ms"], 10);
+        assert_eq!(shared.inner.lock().unwrap().dropped_sample_count, 30);
+
+        // A new stream warns again the first time it drops samples.
+        let mut feeder = RingFeeder::new(&shared);
+        feeder.push(std::iter::repeat_n(0.5, 4));
+        assert_eq!(overflows().len(), 1);
     }
 
-    let duration = if final_sample_rate > 0 && source_channels > 0 {
-        (final_data.len() / source_channels) as f64 / final_sample_rate as f64
-    } else {
-        0.0
-    };
+    #[test]
+    fn paused_live_stream_drops_or_holds_incoming_audio() {
+        use ringbuf::traits::Observer;
+        let shared = create_shared_state();
+        {
+            let mut state = shared.inner.lock().unwrap();
+            state.mode = "stream".to_string();
+            state.channels = 2;
+            state.output_channels = 2;
+            state.is_playing = true;
+            state.is_paused = true;
+            state.buffered_frames = 256;
+        }
+        shared.producer.lock().unwrap().push_slice(&[0.5; 512]);
+        let mut out = vec![1.0f32; 64];
+        fill_output_buffer(&shared.inner, &shared.consumer, &None, &mut out, None);
+        assert!(out.iter().all(|s| *s == 0.0));
+        assert_eq!(shared.inner.lock().unwrap().buffered_frames, 0);
+        assert_eq!(shared.consumer.lock().unwrap().occupied_len(), 0);
 
-    {
-        let mut state = shared.inner.lock().unwrap();
-        state.data = final_data;
-        state.sample_rate = final_sample_rate;
-        state.channels = source_channels;
-        state.source_sample_rate = source_sample_rate;
-        state.source_channels = source_channels;
-        state.source_bit_depth = source_bit_depth;
-        state.position = 0;
-        state.duration = duration;
-        state.is_playing = false;
-        state.is_paused = false;
-        state.file_path = Some(path.clone());
-        state.mode = "file".to_string();
-        state.stream_status = "idle".to_string();
-        state.queue_index = state.queue.iter().position(|track| track.path == path);
+        shared.inner.lock().unwrap().live_pause_mode = normalize_live_pause_mode("HOLD");
+        shared.producer.lock().unwrap().push_slice(&[0.5; 512]);
+        fill_output_buffer(&shared.inner, &shared.consumer, &None, &mut out, None);
+        assert_eq!(shared.consumer.lock().unwrap().occupied_len(), 512);
+        assert_eq!(normalize_live_pause_mode("rewind"), "drop");
     }
 
-    reset_ring_buffer(shared);
-    let _ = ensure_output_stream(shared);
-    send_state(shared);
-    Ok(())
-}
+    #[cfg(unix)]
+    #[test]
+    fn suspended_stream_reader_leaves_the_pipe_unread() {
+        use ringbuf::traits::Observer;
+        let shared = create_shared_state();
+        {
+            let mut state = shared.inner.lock().unwrap();
+            state.mode = "stream".to_string();
+            state.is_playing = true;
+            state.is_paused = true;
+            state.live_pause_mode = normalize_live_pause_mode("suspend");
+        }
+        let child = Command::new("sh")
+            .args(["-c", "head -c 4096 /dev/zero"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        start_stream_reader(shared.clone(), child);
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(shared.consumer.lock().unwrap().occupied_len(), 0);
+
+        shared.inner.lock().unwrap().is_paused = false;
+        let reader = shared.stream_thread.lock().unwrap().take().unwrap();
+        reader.join().unwrap();
+        assert_eq!(shared.consumer.lock().unwrap().occupied_len(), 1024);
+        assert_eq!(shared.inner.lock().unwrap().buffered_frames, 512);
+
+        // Stopping a suspended reader must not wait for it to resume.
+        shared.inner.lock().unwrap().is_paused = true;
+        let child = Command::new("sh")
+            .args(["-c", "head -c 4096 /dev/zero"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        start_stream_reader(shared.clone(), child);
+        stop_stream(&shared);
+    }
 
-fn play_impl(shared: &SharedState) -> Result<()> {
-    {
-        let mut state = shared.inner.lock().unwrap();
-        state.is_playing = true;
-        state.is_paused = false;
+    #[test]
+    fn state_shm_layout_matches_documentation() {
+        let shared = create_shared_state();
+        let fields = {
+            let mut state = shared.inner.lock().unwrap();
+            state.mode = "stream".to_string();
+            state.is_playing = true;
+            state.position = 7;
+            state.played_frames = 48_000;
+            state.buffered_frames = 1024;
+            state.underrun_count = 3;
+            state.sample_rate = 48_000;
+            state.volume = 0.5;
+            state.duration = 12.5;
+            encode_state_shm(&state)
+        };
+        let mmap = Mutex::new(MmapMut::map_anon(STATE_SHM_BYTES).unwrap());
+        write_state_shared(&mmap, &fields);
+        let bytes = mmap.lock().unwrap();
+        let u32_at = |at: usize| u32::from_ne_bytes(bytes[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_ne_bytes(bytes[at..at + 8].try_into().unwrap());
+        assert_eq!(u32_at(0), 2);
+        assert_eq!(u32_at(4), STATE_SHM_VERSION);
+        assert_eq!(u64_at(8), 7);
+        assert_eq!(u64_at(16), 48_000);
+        assert_eq!(u64_at(24), 1024);
+        assert_eq!(u64_at(32), 3);
+        assert_eq!(u32_at(40), 48_000);
+        assert_eq!(f32::from_ne_bytes(bytes[44..48].try_into().unwrap()), 0.5);
+        assert_eq!(u32_at(48), STATE_FLAG_PLAYING | STATE_FLAG_LIVE);
+        assert_eq!(f64::from_ne_bytes(bytes[56..64].try_into().unwrap()), 12.5);
     }
-    let _ = ensure_output_stream(shared);
-    send_state(shared);
-    Ok(())
-}
 
-fn pause_impl(shared: &SharedState) -> Result<()> {
-    {
-        let mut state = shared.inner.lock().unwrap();
-        state.is_paused = true;
+    #[test]
+    fn engine_info_reports_version_and_port() {
+        let shared = create_shared_state();
+        shared.inner.lock().unwrap().server_port = Some(55_600);
+        let info = engine_info_payload(&shared);
+        assert_eq!(info["type"], "engine_info");
+        assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(info["port"], 55_600);
+        assert!(info["ffmpeg_available"].is_boolean());
     }
-    send_state(shared);
-    Ok(())
-}
 
-fn stop_impl(shared: &SharedState) -> Result<()> {
-    {
-        let mut state = shared.inner.lock().unwrap();
-        state.is_playing = false;
-        state.is_paused = false;
-        state.position = 0;
-        state.played_frames = 0;
-        state.mode = "idle".to_string();
-        state.buffered_frames = 0;
+    #[test]
+    fn subscriber_count_tracks_ws_receivers() {
+        let shared = create_shared_state();
+        assert!(!has_ws_subscribers(&shared));
+        let rx = shared.tx.subscribe();
+        assert!(has_ws_subscribers(&shared));
+        drop(rx);
+        assert!(!has_ws_subscribers(&shared));
     }
-    stop_stream(shared);
-    send_state(shared);
-    Ok(())
-}
 
-fn configure_output_impl(
-    shared: &SharedState,
-    device_id: Option<usize>,
-    exclusive: Option<bool>,
-) -> Result<()> {
-    {
-        let mut state = shared.inner.lock().unwrap();
-        state.device_id = device_id;
-        if let Some(exclusive) = exclusive {
-            let effective = resolve_exclusive_mode(device_id, exclusive);
-            if exclusive && !effective {
-                info!("exclusive mode not supported for selected device, falling back to shared");
-            }
-            state.exclusive_mode = effective;
-        } else if state.exclusive_mode {
-            let effective = resolve_exclusive_mode(device_id, true);
-            if !effective {
-                info!("exclusive mode not supported for selected device, falling back to shared");
-            }
-            state.exclusive_mode = effective;
+    #[test]
+    fn pause_and_resume_ramp_instead_of_cutting() {
+        let shared = create_shared_state();
+        {
+            let mut state = shared.inner.lock().unwrap();
+            state.mode = "file".to_string();
+            state.data = vec![0.5; 48_000];
+            state.channels = 1;
+            state.output_channels = 1;
+            state.sample_rate = 48_000;
+            state.dither_enabled = false;
+            state.replaygain_enabled = false;
+            state.is_playing = true;
+            state.transport_gain = 1.0;
+            state.transport_fade_ms = 10;
+        }
+        let render = |frames: usize| {
+            let mut out = vec![0.0f32; frames];
+            fill_output_buffer(&shared.inner, &shared.consumer, &None, &mut out, None);
+            out
+        };
+        // The pause waits on the fade, so callbacks keep coming meanwhile.
+        let pause = {
+            let shared = shared.clone();
+            std::thread::spawn(move || pause_impl(&shared))
+        };
+        let mut out = Vec::new();
+        while !pause.is_finished() {
+            out.extend(render(64));
+            std::thread::sleep(Duration::from_millis(1));
         }
+        pause.join().unwrap().unwrap();
+        assert!(shared.inner.lock().unwrap().is_paused);
+        let step = 0.5 / 480.0 + 1e-6;
+        assert!(out.windows(2).all(|pair| pair[1] <= pair[0] && pair[0] - pair[1] <= step));
+        assert_eq!(*out.last().unwrap(), 0.0);
+
+        shared.inner.lock().unwrap().is_paused = false;
+        let resumed = render(960);
+        assert!(resumed[0] > 0.0 && resumed[0] <= step);
+        assert!(resumed.windows(2).all(|pair| pair[1] >= pair[0] && pair[1] - pair[0] <= step));
+        assert_eq!(resumed[959], 0.5);
     }
-    stop_exclusive_stream(shared);
-    shared.output_stream.lock().unwrap().0 = None;
-    let _ = ensure_output_stream(shared);
-    send_state(shared);
-    Ok(())
-}
 
-async fn get_state_handler(State(state): State<SharedState>) -> impl IntoResponse {
-    let state = state.inner.lock().unwrap();
-    let payload = json!({
-        "status": "success",
-        "state": build_state_view(&state)
-    });
-    Json(payload)
-}
+    #[test]
+    fn repeat_one_and_ab_loops_wrap_without_a_gap() {
+        let shared = create_shared_state();
+        {
+            let mut state = shared.inner.lock().unwrap();
+            state.mode = "file".to_string();
+            state.data = (0..10).map(|i| i as f32 / 100.0).collect();
+            state.channels = 1;
+            state.output_channels = 1;
+            state.sample_rate = 48_000;
+            state.replaygain_enabled = false;
+            state.dither_enabled = false;
+            state.is_playing = true;
+            state.transport_gain = 1.0;
+            state.repeat_mode = "one".to_string();
+        }
+        let render = |frames: usize| {
+            let mut out = vec![1.0f32; frames];
+            fill_output_buffer(&shared.inner, &shared.consumer, &None, &mut out, None);
+            out.iter().map(|s| (s * 100.0).round() as usize).collect::<Vec<_>>()
+        };
+        let expected: Vec<usize> = (0..25).map(|i| i % 10).collect();
+        assert_eq!(render(25), expected);
+        assert_eq!(shared.inner.lock().unwrap().position, 5);
+        assert!(!shared.inner.lock().unwrap().track_finished);
 
-async fn list_devices_handler() -> impl IntoResponse {
-    let payload = json!({ "status": "success", "devices": list_devices() });
-    Json(payload)
-}
+        // An A/B loop takes over from repeat-one.
+        {
+            let mut state = shared.inner.lock().unwrap();
+            state.loop_region = Some((2, 5));
+            state.position = 3;
+        }
+        assert_eq!(render(7), vec![3, 4, 2, 3, 4, 2, 3]);
+        assert_eq!(shared.inner.lock().unwrap().position, 4);
 
-async fn scan_library_handler(
-    State(shared): State<SharedState>,
-    Json(req): Json<LibraryScanRequest>,
-) -> impl IntoResponse {
-    match scan_library_impl(&req.path) {
-        Ok(tracks) => {
-            {
-                let mut state = shared.inner.lock().unwrap();
-                state.library = tracks.clone();
-            }
-            (StatusCode::OK, Json(json!({ "status": "success", "tracks": tracks })))
+        // Seeked past the loop, it plays out the file and stops.
+        {
+            let mut state = shared.inner.lock().unwrap();
+            state.repeat_mode = "off".to_string();
+            state.position = 7;
         }
-        Err(err) => (
-            StatusCode::BAD_REQUEST,
-            Json(json!({ "status": "error", "message": err.to_string() })),
-        ),
+        assert_eq!(render(5), vec![7, 8, 9, 0, 0]);
+        assert!(shared.inner.lock().unwrap().track_finished);
+    }
+
+    #[test]
+    fn mono_source_is_upmixed_to_stereo_once() {
+        let shared = create_shared_state();
+        {
+            let mut state = shared.inner.lock().unwrap();
+            state.mode = "file".to_string();
+            state.channels = 1;
+            state.output_channels = 2;
+            state.data = vec![0.5, -0.5, 0.25, 1.0];
+            state.volume = 0.5;
+            state.volume_current = 0.5;
+            state.replaygain_enabled = false;
+            state.is_playing = true;
+            state.transport_gain = 1.0;
+        }
+        let mut out = vec![0.0f32; 8];
+        fill_output_buffer(&shared.inner, &shared.consumer, &None, &mut out, None);
+        assert_eq!(out, vec![0.25, 0.25, -0.25, -0.25, 0.125, 0.125, 0.5, 0.5]);
+        assert_eq!(shared.inner.lock().unwrap().position, 4);
+    }
+
+    #[test]
+    fn poisoned_state_lock_does_not_kill_the_callback() {
+        let shared = create_shared_state();
+        {
+            let mut state = shared.inner.lock().unwrap();
+            state.data = vec![0.5; 16];
+            state.sample_rate = 48_000;
+            state.mode = "file".to_string();
+            state.is_playing = true;
+            state.transport_gain = 1.0;
+            state.replaygain_enabled = false;
+            state.dither_enabled = false;
+        }
+        let inner = shared.inner.clone();
+        let consumer = shared.consumer.clone();
+        let _ = std::thread::spawn(move || {
+            let _state = inner.lock().unwrap();
+            let _cons = consumer.lock().unwrap();
+            panic!("handler panicked while holding the locks");
+        })
+        .join();
+        assert!(shared.inner.is_poisoned());
+
+        let mut out = vec![1.0f32; 8];
+        fill_output_buffer(&shared.inner, &shared.consumer, &None, &mut out, None);
+        assert_eq!(out, vec![0.0; 8]);
+        assert!(!shared.inner.is_poisoned());
+
+        fill_output_buffer(&shared.inner, &shared.consumer, &None, &mut out, None);
+        assert_eq!(out, vec![0.5; 8]);
     }
-}
 
-async fn queue_add_handler(
-    State(shared): State<SharedState>,
-    Json(req): Json<QueueAddRequest>,
-) -> impl IntoResponse {
-    let count = queue_add_impl(&shared, req.tracks, req.replace.unwrap_or(false));
-    Json(json!({ "status": "success", "count": count }))
-}
+    #[test]
+    fn hostapi_preference_must_name_an_available_host() {
+        let default_name = format!("{:?}", cpal::default_host().id());
+        assert_eq!(find_host_id(&default_name.to_uppercase()), Some(cpal::default_host().id()));
+        assert_eq!(output_device_id(Some(3), None), Some(3));
 
-async fn queue_next_handler(State(shared): State<SharedState>) -> impl IntoResponse {
-    match queue_next_impl(&shared) {
-        Ok(Some(track)) => (StatusCode::OK, Json(json!({ "status": "success", "track": track }))),
-        Ok(None) => (
-            StatusCode::BAD_REQUEST,
-            Json(json!({ "status": "error", "message": "queue empty" })),
-        ),
-        Err(err) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({ "status": "error", "message": err.to_string() })),
-        ),
+        let shared = create_shared_state();
+        assert!(set_hostapi_impl(&shared, Some("NoSuchHost".to_string())).is_err());
+        assert_eq!(shared.inner.lock().unwrap().hostapi, None);
     }
-}
 
-async fn command_handler(
-    State(shared): State<SharedState>,
-    Json(req): Json<CommandRequest>,
-) -> impl IntoResponse {
-    let parsed = if let Some(action) = req.action.clone().filter(|a| !a.trim().is_empty()) {
-        ParsedCommand {
-            action,
-            query: req.query.clone().filter(|q| !q.trim().is_empty()),
-            raw: req.text.unwrap_or_default(),
+    #[test]
+    fn spectrum_window_spans_many_small_callbacks() {
+        let mut state = initial_state();
+        // 64-frame stereo callbacks counting up, one value per frame.
+        for callback in 0..64 {
+            let data: Vec<f32> = (0..64)
+                .flat_map(|i| {
+                    let v = (callback * 64 + i) as f32;
+                    [v, v]
+                })
+                .collect();
+            push_spectrum_history(&mut state, &data, 2);
         }
-    } else if let Some(text) = req.text.as_ref() {
-        match parse_command_text(text) {
-            Some(cmd) => cmd,
-            None => {
-                return (
-                    StatusCode::BAD_REQUEST,
-                    Json(json!({ "status": "error", "message": "command text empty" })),
-                )
-            }
+        let mut window = vec![0.0f32; DEFAULT_SPECTRUM_FFT_SIZE];
+        latest_spectrum_window(&state, &mut window);
+        let expected: Vec<f32> = (4096 - DEFAULT_SPECTRUM_FFT_SIZE..4096).map(|v| v as f32).collect();
+        assert_eq!(window, expected);
+
+        // A callback bigger than the ring keeps only its newest frames.
+        let ring_frames = spectrum_history_frames(DEFAULT_SPECTRUM_FFT_SIZE);
+        let big: Vec<f32> = (0..ring_frames + 10).map(|v| v as f32).collect();
+        push_spectrum_history(&mut state, &big, 1);
+        latest_spectrum_window(&state, &mut window[..1]);
+        assert_eq!(window[0], (ring_frames + 9) as f32);
+
+        // A larger FFT gets a ring that holds twice its window.
+        set_spectrum_fft_size(&mut state, MAX_SPECTRUM_FFT_SIZE);
+        assert_eq!(state.spectrum_history.len(), MAX_SPECTRUM_FFT_SIZE * 2 * SPECTRUM_HISTORY_CHANNELS);
+        let mut window = vec![1.0f32; MAX_SPECTRUM_FFT_SIZE];
+        latest_spectrum_window(&state, &mut window);
+        assert!(window.iter().all(|v| *v == 0.0));
+    }
+
+    #[test]
+    fn spectrum_source_picks_channels_or_their_difference() {
+        let mut state = initial_state();
+        let mut window = [0.0f32; 1];
+        for (source, expected) in [("mono", 0.5), ("left", 0.8), ("right", 0.2), ("mid", 0.5), ("side", 0.3)] {
+            state.spectrum_source = source.to_string();
+            push_spectrum_history(&mut state, &[0.8, 0.2], 2);
+            latest_spectrum_window(&state, &mut window);
+            assert!((window[0] - expected).abs() < 1e-6, "{}", source);
         }
-    } else {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(json!({ "status": "error", "message": "command missing" })),
-        );
-    };
+        state.spectrum_source = "side".to_string();
+        push_spectrum_history(&mut state, &[0.8], 1);
+        latest_spectrum_window(&state, &mut window);
+        assert_eq!(window[0], 0.0);
+    }
 
-    match handle_command_impl(&shared, parsed.clone()) {
-        Ok(result) => (
-            StatusCode::OK,
-            Json(json!({
-                "status": "success",
-                "action": result.action,
-                "matches": result.matches,
-                "track": result.track,
-                "raw": parsed.raw
-            })),
-        ),
-        Err(err) => (
-            StatusCode::BAD_REQUEST,
-            Json(json!({
-                "status": "error",
-                "action": parsed.action,
-                "message": err.to_string(),
-                "raw": parsed.raw
-            })),
-        ),
+    #[test]
+    fn level_meter_reads_new_output_per_channel() {
+        let mut state = initial_state();
+        let mut meter = LevelMeter::default();
+        // Left a full-scale square wave, right silent.
+        let data: Vec<f32> = (0..480).flat_map(|i| [if i % 2 == 0 { 1.0 } else { -1.0 }, 0.0]).collect();
+        push_spectrum_history(&mut state, &data, 2);
+        let reading = meter.update(&state);
+        assert_eq!(reading.peak, vec![1.0, 0.0]);
+        assert_eq!(reading.rms, vec![1.0, 0.0]);
+        assert!(reading.clip);
+
+        // Quieter audio: the peak falls rather than jumping down, the hold
+        // stays put, and the clip flag clears.
+        push_spectrum_history(&mut state, &[0.1, 0.1].repeat(480), 2);
+        let reading = meter.update(&state);
+        assert!(reading.peak[0] < 1.0 && reading.peak[0] > 0.5, "{:?}", reading.peak);
+        assert_eq!(reading.hold[0], 1.0);
+        assert!((reading.rms[0] - 0.1).abs() < 1e-6);
+        assert!(!reading.clip);
+
+        // Nothing new played: no level, and the hold lets go in time.
+        for _ in 0..LEVEL_METER_HOLD_MS / SPECTRUM_UPDATE_INTERVAL_MS {
+            meter.update(&state);
+        }
+        let reading = meter.update(&state);
+        assert_eq!(reading.rms, vec![0.0, 0.0]);
+        assert!(reading.hold[0] < 0.1, "{:?}", reading.hold);
     }
-}
 
-async fn cover_handler(
-    State(_shared): State<SharedState>,
-    Json(req): Json<CoverRequest>,
-) -> impl IntoResponse {
-    let path = Path::new(&req.path);
-    if !path.exists() {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(json!({ "status": "error", "message": "file not found" })),
-        );
+    #[test]
+    fn spectrum_gate_blanks_near_silence() {
+        // A -140 dBFS tone: visible without the gate, silent with it.
+        let whisper: Vec<f32> = (0..DEFAULT_SPECTRUM_FFT_SIZE)
+            .map(|i| 1e-7 * (2.0 * std::f32::consts::PI * 1_000.0 * i as f32 / 48_000.0).sin())
+            .collect();
+        let mut analyzer = SpectrumAnalyzer::new(DEFAULT_SPECTRUM_FFT_SIZE, DEFAULT_SPECTRUM_BINS, "hann");
+        assert!(analyzer.compute(&whisper, 48_000).iter().all(|v| *v == 0.0));
+
+        analyzer.set_gate_db(MIN_SPECTRUM_GATE_DB);
+        assert!(analyzer.compute(&whisper, 48_000).iter().any(|v| *v > 0.0));
+
+        // Real signal is untouched by the default gate.
+        let tone: Vec<f32> = whisper.iter().map(|v| v * 1e6).collect();
+        analyzer.set_gate_db(DEFAULT_SPECTRUM_GATE_DB);
+        assert!(analyzer.compute(&tone, 48_000).contains(&1.0));
     }
-    match extract_cover_art(path) {
-        Ok(Some((data, media_type))) => match write_cover_file(path, &data, &media_type) {
-            Ok(saved) => (
-                StatusCode::OK,
-                Json(json!({
-                    "status": "success",
-                    "cover_path": saved.to_string_lossy().to_string(),
-                    "media_type": media_type
-                })),
-            ),
-            Err(err) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({ "status": "error", "message": err.to_string() })),
-            ),
-        },
-        Ok(None) => (
-            StatusCode::OK,
-            Json(json!({ "status": "success", "cover_path": null })),
-        ),
-        Err(err) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({ "status": "error", "message": err.to_string() })),
-        ),
+
+    #[test]
+    fn spectrum_smooths_and_holds_peaks() {
+        let tone: Vec<f32> = (0..DEFAULT_SPECTRUM_FFT_SIZE)
+            .map(|i| 0.5 * (2.0 * std::f32::consts::PI * 1_000.0 * i as f32 / 48_000.0).sin())
+            .collect();
+        let silence = vec![0.0f32; DEFAULT_SPECTRUM_FFT_SIZE];
+        let mut analyzer = SpectrumAnalyzer::new(DEFAULT_SPECTRUM_FFT_SIZE, DEFAULT_SPECTRUM_BINS, "hann");
+        let loudest = |values: &[f32]| values.iter().cloned().fold(0.0f32, f32::max);
+        assert!(analyzer.peaks().is_none());
+
+        // No smoothing: the display drops straight to nothing.
+        let level = loudest(analyzer.compute(&tone, 48_000));
+        assert_eq!(loudest(analyzer.compute(&silence, 48_000)), 0.0);
+
+        // Instant attack, halving release, and a peak falling 1/s.
+        analyzer.set_smoothing(0.0, 0.5);
+        analyzer.set_peak_fall(1.0);
+        assert_eq!(loudest(analyzer.compute(&tone, 48_000)), level);
+        assert_eq!(loudest(analyzer.compute(&silence, 48_000)), level * 0.5);
+        let peaks = analyzer.peaks().unwrap();
+        assert!((loudest(peaks) - (level - 0.05)).abs() < 1e-6);
+
+        analyzer.set_peak_fall(0.0);
+        assert!(analyzer.peaks().is_none());
     }
-}
-async fn load_handler(State(shared): State<SharedState>, Json(req): Json<LoadRequest>) -> impl IntoResponse {
-    match load_file_impl(&shared, req.path) {
-        Ok(_) => {
-            let state = shared.inner.lock().unwrap();
-            (StatusCode::OK, Json(json!({ "status": "success", "state": build_state_view(&state) })))
+
+    #[test]
+    fn spectrum_levels_hold_across_fft_sizes_and_windows() {
+        let tone: Vec<f32> = (0..MAX_SPECTRUM_FFT_SIZE)
+            .map(|i| 0.1 * (2.0 * std::f32::consts::PI * 1_000.0 * i as f32 / 48_000.0).sin())
+            .collect();
+        let peak = |fft_size: usize, window: &str| {
+            let mut analyzer = SpectrumAnalyzer::new(fft_size, DEFAULT_SPECTRUM_BINS, window);
+            analyzer.compute(&tone[..fft_size], 48_000).iter().cloned().fold(0.0f32, f32::max)
+        };
+        let reference = peak(DEFAULT_SPECTRUM_FFT_SIZE, "hann");
+        assert!(reference > 0.5);
+        let settings = [
+            (MIN_SPECTRUM_FFT_SIZE, "hamming"),
+            (8_192, "blackman"),
+            (MAX_SPECTRUM_FFT_SIZE, "blackman_harris"),
+        ];
+        for (fft_size, window) in settings {
+            // Within the windows' scalloping loss, about 2 dB.
+            let level = peak(fft_size, window);
+            assert!((level - reference).abs() < 0.025, "{} {}: {} vs {}", fft_size, window, level, reference);
         }
-        Err(err) => {
-            let message = err.to_string();
-            let status = if message.contains("File not found") {
-                StatusCode::BAD_REQUEST
-            } else {
-                StatusCode::INTERNAL_SERVER_ERROR
+        assert!(valid_spectrum_fft_size(4_096));
+        assert!(!valid_spectrum_fft_size(3_000));
+        assert!(!valid_spectrum_fft_size(MAX_SPECTRUM_FFT_SIZE * 2));
+    }
+
+    #[test]
+    fn spectrum_shm_grows_but_never_shrinks() {
+        let path = std::env::temp_dir().join(format!("ntmusic_spectrum_{}.bin", std::process::id()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.set_len((SPECTRUM_HEADER_BYTES + 4 * 4) as u64).unwrap();
+        let mmap = unsafe { MmapMut::map_mut(&file).unwrap() };
+        let shared = Some(Arc::new(Mutex::new(SpectrumShared {
+            file,
+            mmap,
+            bins: 4,
+            peaks: false,
+        })));
+        let header_word = |shared: &Option<Arc<Mutex<SpectrumShared>>>, at: usize| {
+            let guard = shared.as_ref().unwrap().lock().unwrap();
+            u32::from_ne_bytes(guard.mmap[at..at + 4].try_into().unwrap())
+        };
+        let header_bins = |shared: &Option<Arc<Mutex<SpectrumShared>>>| header_word(shared, SPECTRUM_BINS_OFFSET);
+
+        resize_spectrum_shared(&shared, 16, false).unwrap();
+        assert_eq!(header_bins(&shared), 16);
+        assert_eq!(header_word(&shared, SPECTRUM_VERSION_OFFSET), SPECTRUM_SHM_VERSION);
+        assert_eq!(header_word(&shared, SPECTRUM_FLAGS_OFFSET), 0);
+        let grown = std::fs::metadata(&path).unwrap().len();
+        assert_eq!(grown, (SPECTRUM_HEADER_BYTES + 16 * 4) as u64);
+        write_spectrum_shared(&shared, &[1.0; 16], None);
+        {
+            let guard = shared.as_ref().unwrap().lock().unwrap();
+            let seq = u32::from_ne_bytes(guard.mmap[..4].try_into().unwrap());
+            assert_eq!(seq % 2, 0);
+            assert_eq!(&guard.mmap[guard.mmap.len() - 4..], &1.0f32.to_ne_bytes());
+        }
+
+        resize_spectrum_shared(&shared, 8, false).unwrap();
+        assert_eq!(header_bins(&shared), 8);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), grown);
+
+        // Peak hold appends its array; the header still counts one.
+        resize_spectrum_shared(&shared, 12, true).unwrap();
+        assert_eq!(header_bins(&shared), 12);
+        assert_eq!(header_word(&shared, SPECTRUM_FLAGS_OFFSET), SPECTRUM_FLAG_PEAKS);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), (SPECTRUM_HEADER_BYTES + 24 * 4) as u64);
+        write_spectrum_shared(&shared, &[0.25; 12], Some(&[0.75; 12]));
+        {
+            let guard = shared.as_ref().unwrap().lock().unwrap();
+            let value = |index: usize| {
+                let at = SPECTRUM_HEADER_BYTES + index * 4;
+                f32::from_ne_bytes(guard.mmap[at..at + 4].try_into().unwrap())
             };
-            (status, Json(json!({ "status": "error", "message": message })))
+            assert_eq!((value(11), value(12), value(23)), (0.25, 0.75, 0.75));
         }
+        drop(shared);
+        let _ = std::fs::remove_file(&path);
     }
-}
 
-async fn play_handler(State(shared): State<SharedState>) -> impl IntoResponse {
-    let _ = play_impl(&shared);
-    let state = shared.inner.lock().unwrap();
-    Json(json!({ "status": "success", "state": build_state_view(&state) }))
-}
+    #[test]
+    fn output_is_released_after_idle_timeout() {
+        let shared = create_shared_state();
+        let start = Instant::now();
+        release_idle_output(&shared, start + Duration::from_secs(60));
+        assert!(!shared.inner.lock().unwrap().device_released);
+
+        shared.inner.lock().unwrap().idle_release_secs = Some(5);
+        release_idle_output(&shared, start);
+        release_idle_output(&shared, start + Duration::from_secs(4));
+        assert!(!shared.inner.lock().unwrap().device_released);
+        release_idle_output(&shared, start + Duration::from_secs(5));
+        assert!(shared.inner.lock().unwrap().device_released);
+        assert!(build_state_view(&shared.inner.lock().unwrap()).device_released);
+
+        // Playing resets the idle clock.
+        {
+            let mut state = shared.inner.lock().unwrap();
+            state.device_released = false;
+            state.is_playing = true;
+        }
+        release_idle_output(&shared, start + Duration::from_secs(10));
+        shared.inner.lock().unwrap().is_playing = false;
+        release_idle_output(&shared, start + Duration::from_secs(11));
+        release_idle_output(&shared, start + Duration::from_secs(15));
+        assert!(!shared.inner.lock().unwrap().device_released);
+    }
 
-async fn pause_handler(State(shared): State<SharedState>) -> impl IntoResponse {
-    let _ = pause_impl(&shared);
-    let state = shared.inner.lock().unwrap();
-    Json(json!({ "status": "success", "state": build_state_view(&state) }))
-}
+    #[test]
+    fn volume_steps_ramp_instead_of_jumping() {
+        let harness = testing::OutputHarness::new(vec![1.0; 2_000], 1, 48_000);
+        harness.ramp_volume(0.0);
+        let out = harness.render(400);
+        let ramp_frames = (48_000.0 * VOLUME_RAMP_MS / 1000.0) as usize;
+        assert!(out[0] > 0.99);
+        assert!(out.windows(2).all(|w| w[1] <= w[0]));
+        assert!(out[ramp_frames / 2] > 0.4 && out[ramp_frames / 2] < 0.6);
+        assert!(out[ramp_frames + 1..].iter().all(|s| *s == 0.0));
+
+        harness.ramp_volume(0.5);
+        let out = harness.render(400);
+        assert!(out[0] < 0.01);
+        assert!(out.windows(2).all(|w| w[1] >= w[0]));
+        assert_eq!(*out.last().unwrap(), 0.5);
+    }
 
-async fn stop_handler(State(shared): State<SharedState>) -> impl IntoResponse {
-    let _ = stop_impl(&shared);
-    let state = shared.inner.lock().unwrap();
-    Json(json!({ "status": "success", "state": build_state_view(&state) }))
-}
+    #[test]
+    fn harness_runs_gain_then_limiter() {
+        let harness = testing::OutputHarness::new(vec![0.5, -0.5, 0.9, -0.9], 2, 48_000);
+        harness.set_volume(0.5);
+        harness.set_replaygain_gain(Some(2.0));
+        assert_eq!(harness.render(1), vec![0.5, -0.5]);
 
-async fn seek_handler(State(shared): State<SharedState>, Json(req): Json<SeekRequest>) -> impl IntoResponse {
-    let mut state = shared.inner.lock().unwrap();
-    if state.mode != "file" {
-        return (StatusCode::BAD_REQUEST, Json(json!({
-            "status": "error",
-            "message": "seek only supported in file mode"
-        })));
+        harness.set_limiter(Some(0.8));
+        let limited = harness.render(1);
+        assert_eq!(limited, vec![soft_limit_sample(0.9, 0.8), soft_limit_sample(-0.9, 0.8)]);
+        assert_eq!(harness.position(), 2);
+
+        assert_eq!(harness.render(1), vec![0.0, 0.0]);
+        assert!(!harness.is_playing());
     }
-    if state.sample_rate == 0 {
-        return (StatusCode::BAD_REQUEST, Json(json!({
-            "status": "error",
-            "message": "invalid sample rate"
-        })));
+
+    #[test]
+    fn limiter_threshold_accepts_dbfs_and_legacy_linear() {
+        assert!((parse_limiter_threshold(-6.0) - 0.501).abs() < 1e-3);
+        assert_eq!(parse_limiter_threshold(0.9), 0.9);
+        assert_eq!(parse_limiter_threshold(0.0), 1.0);
+        assert!((parse_limiter_threshold(-40.0) - db_to_linear(LIMITER_MIN_DB)).abs() < 1e-6);
+        assert_eq!(parse_limiter_threshold(f32::NAN), 1.0);
+        assert!((linear_to_db(parse_limiter_threshold(-3.0)) + 3.0).abs() < 1e-4);
+        // Ceilings under the old 0.7 floor now reach the limiter's knee.
+        assert_ne!(soft_limit_sample(0.6, 0.5), 0.6);
+        assert_eq!(soft_limit_sample(0.6, 0.7), 0.6);
     }
-    let new_pos = (req.position * state.sample_rate as f64) as usize;
-    if new_pos < state.data.len() / state.channels.max(1) {
-        state.position = new_pos;
-        return (StatusCode::OK, Json(json!({
-            "status": "success",
-            "state": build_state_view(&state)
-        })));
+
+    #[test]
+    fn polarity_inversion_applies_to_output_channels() {
+        let harness = testing::OutputHarness::new(vec![0.5, 0.25, 0.5, 0.25], 2, 48_000);
+        harness.set_polarity(true, Vec::new());
+        assert_eq!(harness.render(1), vec![-0.5, -0.25]);
+        harness.set_polarity(false, vec![1]);
+        assert_eq!(harness.render(1), vec![0.5, -0.25]);
+
+        // Mono upmixed to stereo: only the inverted output channel flips.
+        let harness = testing::OutputHarness::new(vec![0.5], 1, 48_000);
+        harness.set_output_channels(2);
+        harness.set_polarity(false, vec![0]);
+        assert_eq!(harness.render(1), vec![-0.5, 0.5]);
+
+        // Wider than the dither stage's channel limit is fine.
+        assert_eq!(normalize_invert_channels(vec![11, 1, 11, 64]), vec![1, 11]);
     }
-    (StatusCode::BAD_REQUEST, Json(json!({
-        "status": "error",
-        "message": "seek out of range"
-    })))
-}
 
-async fn volume_handler(State(shared): State<SharedState>, Json(req): Json<VolumeRequest>) -> impl IntoResponse {
-    {
-        let mut state = shared.inner.lock().unwrap();
-        state.volume = req.volume.clamp(0.0, 1.0);
+    #[test]
+    fn downmix_folds_every_frame_onto_all_channels() {
+        let harness = testing::OutputHarness::new(vec![0.5, 0.25, 0.5, 0.25, 0.5, 0.25], 2, 48_000);
+        harness.set_downmix("mono");
+        assert_eq!(harness.render(1), vec![0.375, 0.375]);
+        harness.set_downmix("left");
+        assert_eq!(harness.render(1), vec![0.5, 0.5]);
+        harness.set_downmix("right");
+        assert_eq!(harness.render(1), vec![0.25, 0.25]);
+
+        // 5.1: the centre comes in at -3 dB on both sides and the LFE is
+        // dropped; a left surround only reaches the left side.
+        let g = std::f32::consts::FRAC_1_SQRT_2;
+        let norm = 1.0 / (1.0 + 2.0 * g);
+        let frames = [
+            [0.0, 0.0, 1.0, 0.0, 0.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0, 0.0, 0.0],
+            [0.0, 0.0, 0.0, 0.0, 1.0, 0.0],
+        ];
+        let harness = testing::OutputHarness::new(frames.concat(), 6, 48_000);
+        harness.set_downmix("mono");
+        let out = harness.render(3);
+        for (frame, want) in out.chunks(6).zip([g * norm, 0.0, g * norm * 0.5]) {
+            assert!(frame.iter().all(|s| (s - want).abs() < 1e-6), "{:?}", out);
+        }
+
+        // 7.1: the side pair folds in like the rear pair.
+        let mut frame = [0.0f32; 8];
+        frame[7] = 1.0;
+        let harness = testing::OutputHarness::new(frame.to_vec(), 8, 48_000);
+        harness.set_downmix("right");
+        let norm = 1.0 / (1.0 + 3.0 * g);
+        assert!(harness.render(1).iter().all(|s| (s - g * norm).abs() < 1e-6));
+
+        // Mono upmixed to stereo stays at its own level.
+        let harness = testing::OutputHarness::new(vec![0.5], 1, 48_000);
+        harness.set_output_channels(2);
+        harness.set_downmix("right");
+        assert_eq!(harness.render(1), vec![0.5, 0.5]);
     }
-    send_state(&shared);
-    let state = shared.inner.lock().unwrap();
-    Json(json!({ "status": "success", "state": build_state_view(&state) }))
-}
 
-async fn configure_output_handler(State(shared): State<SharedState>, Json(req): Json<ConfigureOutputRequest>) -> impl IntoResponse {
-    let _ = configure_output_impl(&shared, req.device_id, req.exclusive);
-    let state = shared.inner.lock().unwrap();
-    Json(json!({ "status": "success", "state": build_state_view(&state) }))
-}
+    #[test]
+    fn eq_boost_is_heard_in_the_output_callback() {
+        let rate = 48_000;
+        let tone: Vec<f32> = (0..rate)
+            .flat_map(|i| {
+                let s = (2.0 * std::f32::consts::PI * 1_000.0 * i as f32 / rate as f32).sin() * 0.1;
+                [s, s]
+            })
+            .collect();
+        let rms = |out: &[f32]| (out.iter().map(|s| s * s).sum::<f32>() / out.len() as f32).sqrt();
+        let harness = testing::OutputHarness::new(tone, 2, rate);
+        let flat = rms(&harness.render(4_800));
+        harness.set_eq(&[("1k", 6.0)]);
+        harness.render(4_800);
+        let boosted = harness.render(4_800);
+        assert!((linear_to_db(rms(&boosted) / flat) - 6.0).abs() < 0.3);
+        // Both channels get the same filter.
+        assert!(boosted.chunks(2).all(|frame| (frame[0] - frame[1]).abs() < 1e-6));
+        harness.set_eq(&[]);
+        assert!((rms(&harness.render(4_800)) - flat).abs() < 1e-4);
+
+        harness.set_eq(&[("1k", 6.0)]);
+        harness.set_eq_fir(2047);
+        harness.render(4_800);
+        let fir = harness.render(4_800);
+        assert!((linear_to_db(rms(&fir) / flat) - 6.0).abs() < 0.3);
+    }
 
-async fn configure_upsampling_handler(State(shared): State<SharedState>, Json(req): Json<ConfigureUpsamplingRequest>) -> impl IntoResponse {
-    {
+    #[test]
+    fn track_gain_is_applied_remembered_and_clamped() {
+        let harness = testing::OutputHarness::new(vec![0.25], 1, 48_000);
+        harness.set_track_gain(6.0);
+        assert!((harness.render(1)[0] - 0.25 * db_to_linear(6.0)).abs() < 1e-6);
+
+        let path = std::env::temp_dir().join(format!("ntmusic_track_gains_{}.json", std::process::id()));
+        let shared = create_shared_state();
+        assert!(set_track_gain_impl(&shared, 3.0).is_err());
+        {
+            let mut state = shared.inner.lock().unwrap();
+            state.track_gains_path = path.clone();
+            state.track_gains.clear();
+            state.mode = "file".to_string();
+            state.file_path = Some("quiet.flac".to_string());
+            // The loudest sample sits past the first block the peak scan reads.
+            state.data = vec![-0.5; PEAK_SCAN_BLOCK];
+            state.data.push(0.9);
+            state.replaygain_enabled = false;
+        }
+        assert_eq!(set_track_gain_impl(&shared, 3.0).unwrap(), (3.0, true));
+        assert_eq!(set_track_gain_impl(&shared, 40.0).unwrap(), (TRACK_GAIN_MAX_DB, true));
+        assert_eq!(set_track_gain_impl(&shared, -1.0).unwrap(), (-1.0, false));
+        assert_eq!(load_track_gains(&path).get("quiet.flac"), Some(&-1.0));
+        set_track_gain_impl(&shared, 0.0).unwrap();
+        let saved = load_track_gains(&path);
+        let _ = std::fs::remove_file(&path);
+        assert!(saved.is_empty());
+    }
+
+    #[test]
+    fn auto_level_lifts_quiet_audio_and_defers_to_tagged_replaygain() {
+        let rate = 48_000;
+        let quiet: Vec<f32> = (0..rate as usize * 45)
+            .map(|i| 0.05 * (i as f32 * 0.05).sin())
+            .collect();
+        let harness = testing::OutputHarness::new(quiet, 1, rate);
+        harness.set_auto_level(Some((-18.0, 9.0)));
+        for _ in 0..(40 * rate as usize / 1024) {
+            harness.render(1024);
+        }
+        // About -29 dBFS RMS wants +11 dB; the maximum caps it at +9.
+        assert!((harness.auto_level_gain_db() - 9.0).abs() < 0.3);
+        let out = harness.render(1024);
+        assert!(out.iter().all(|s| s.abs() < 0.05 * db_to_linear(9.1)));
+
+        let shared = create_shared_state();
         let mut state = shared.inner.lock().unwrap();
-        state.target_samplerate = req.target_samplerate;
+        state.sample_rate = rate;
+        state.auto_level_enabled = true;
+        state.auto_level_gain = db_to_linear(6.0) as f64;
+        state.replaygain_enabled = true;
+        state.replaygain.track_gain_db = Some(-3.0);
+        assert!(!build_state_view(&state).auto_level_active);
+        let mut data = vec![0.05; rate as usize * 20];
+        apply_auto_level(&mut state, &mut data, 1);
+        assert!((state.auto_level_gain - 1.0).abs() < 1e-3);
     }
-    send_state(&shared);
-    let state = shared.inner.lock().unwrap();
-    Json(json!({ "status": "success", "state": build_state_view(&state) }))
-}
 
-async fn set_eq_handler(State(shared): State<SharedState>, Json(req): Json<EqRequest>) -> impl IntoResponse {
-    let mut state = shared.inner.lock().unwrap();
-    if let Some(enabled) = req.enabled {
-        state.eq_enabled = enabled;
+    #[test]
+    fn harness_integer_output_without_dither_is_exact() {
+        let harness = testing::OutputHarness::new(vec![0.5, -0.25], 1, 48_000);
+        harness.set_dither("off", 16);
+        assert_eq!(harness.render_i16(2), vec![16_384, -8_192]);
     }
-    if let Some(bands) = req.bands {
-        for (k, v) in bands {
-            if let Some(entry) = state.eq_bands.get_mut(&k) {
-                *entry = v;
-            }
-        }
+
+    #[test]
+    fn int32_output_carries_24_bits() {
+        let harness = testing::OutputHarness::new(vec![0.5, -0.25, 1.0, -1.0], 1, 48_000);
+        harness.set_dither("off", 24);
+        assert_eq!(harness.render_i32(4), vec![1 << 30, -(1 << 29), i32::MAX - 255, i32::MIN]);
+
+        // Dithered output keeps the padding byte clear.
+        let harness = testing::OutputHarness::new(vec![0.3; 64], 1, 48_000);
+        harness.set_dither("tpdf", 24);
+        assert!(harness.render_i32(64).iter().all(|s| s & 0xff == 0));
+
+        assert_eq!(output_dither_bits(cpal::SampleFormat::I32), Some(24));
+        assert_eq!(output_dither_bits(cpal::SampleFormat::I16), Some(16));
+        assert_eq!(output_dither_bits(cpal::SampleFormat::F32), None);
     }
-    Json(json!({ "status": "success", "state": build_state_view(&state) }))
-}
 
-async fn set_eq_type_handler(State(shared): State<SharedState>, Json(req): Json<EqTypeRequest>) -> impl IntoResponse {
-    let mut state = shared.inner.lock().unwrap();
-    state.eq_type = req.r#type;
-    Json(json!({ "status": "success", "state": build_state_view(&state) }))
-}
+    #[test]
+    fn hardware_volume_leaves_samples_unscaled() {
+        let harness = testing::OutputHarness::new(vec![0.5; 8], 1, 48_000);
+        harness.set_dither("off", 16);
+        harness.set_volume(0.25);
+        harness.set_hardware_volume(true);
+        assert_eq!(harness.render(4), vec![0.5; 4]);
+        // Back in software, without a ramp from the old level.
+        harness.set_hardware_volume(false);
+        assert_eq!(harness.render(4), vec![0.125; 4]);
+
+        assert_eq!(normalize_volume_mode("Hardware"), "hardware");
+        assert_eq!(normalize_volume_mode("bogus"), "software");
+        let shared = create_shared_state();
+        let view = build_state_view(&shared.inner.lock().unwrap());
+        assert_eq!((view.volume_mode.as_str(), view.volume_mode_effective), ("software", "software"));
+    }
 
-async fn configure_opt_handler(State(shared): State<SharedState>, Json(req): Json<OptimizeRequest>) -> impl IntoResponse {
-    let mut state = shared.inner.lock().unwrap();
-    if let Some(value) = req.dither_type {
-        let normalized = normalize_dither_type(&value);
-        if normalized != state.dither_type {
-            reset_dither_shape_state(&mut state);
+    #[test]
+    fn streams_play_at_the_device_rate() {
+        let source: Vec<f32> = (0..44_100 * 2).map(|i| ((i / 2) as f32 * 0.05).sin() * 0.5).collect();
+        let harness = testing::OutputHarness::new_stream(&source, 2, 44_100);
+        harness.set_dither("off", 16);
+        harness.set_device_rate(48_000);
+        // A tenth of a second at the device rate, over uneven callbacks.
+        for frames in [480, 1_024, 33, 2_000, 1_263] {
+            let out = harness.render(frames);
+            assert!(out.iter().all(|s| s.is_finite() && s.abs() <= 0.6));
         }
-        state.dither_type = normalized.clone();
-        if normalized == "off" {
-            state.dither_enabled = false;
+        let read = 44_100 - harness.buffered_frames();
+        assert!((4_300..=4_900).contains(&read), "read {} source frames", read);
+        assert_eq!(harness.underruns(), 0);
+
+        // Running the ring dry is an underrun once the held output is gone.
+        while harness.buffered_frames() > 0 {
+            harness.render(4_096);
+        }
+        harness.render(4_096);
+        assert!(harness.underruns() > 0);
+
+        // At the source rate nothing is converted.
+        let harness = testing::OutputHarness::new_stream(&source, 2, 44_100);
+        harness.set_device_rate(44_100);
+        harness.render(1_000);
+        assert_eq!(harness.buffered_frames(), 44_100 - 1_000);
+    }
+
+    #[test]
+    fn dither_is_the_last_processing_stage() {
+        assert_eq!(PROCESSING_CHAIN.last(), Some(&ProcessingStage::Dither));
+        let shared = create_shared_state();
+        let view = build_state_view(&shared.inner.lock().unwrap());
+        assert_eq!(
+            view.processing_chain,
+            vec![
+                "polarity",
+                "replaygain",
+                "auto_level",
+                "track_gain",
+                "downmix",
+                "volume",
+                "eq",
+                "limiter",
+                "transport_fade",
+                "dither"
+            ]
+        );
+
+        // Dither runs after the limiter, so even a full-scale input only
+        // moves by the dither noise around the limited value.
+        let harness = testing::OutputHarness::new(vec![1.0; 64], 1, 48_000);
+        harness.set_limiter(Some(0.8));
+        harness.set_dither("tpdf", 16);
+        let expected = (soft_limit_sample(1.0, 0.8) * 32_768.0).min(32_767.0);
+        for sample in harness.render_i16(64) {
+            assert!((sample as f32 - expected).abs() <= 2.0);
         }
     }
-    if let Some(bits) = req.dither_bits {
-        state.dither_bits = normalize_dither_bits(bits);
+
+    #[test]
+    fn exclusive_rates_try_target_then_source() {
+        assert_eq!(
+            exclusive_rate_candidates(Some(96_000), 44_100),
+            vec![96_000, 44_100, 48_000, 88_200, 176_400, 192_000]
+        );
+        assert_eq!(exclusive_rate_candidates(None, 32_000)[..2], [32_000, 44_100]);
+        assert_eq!(exclusive_rate_candidates(None, 0), COMMON_OUTPUT_RATES.to_vec());
+    }
+
+    #[test]
+    fn upmix_keeps_extra_channels_silent() {
+        let mut data = vec![1.0, 2.0, 3.0, 4.0, 0.0, 0.0, 0.0, 0.0];
+        upmix_in_place(&mut data, 2, 4, 2);
+        assert_eq!(data, vec![1.0, 2.0, 0.0, 0.0, 3.0, 4.0, 0.0, 0.0]);
     }
-    if let Some(val) = req.dither_enabled {
-        state.dither_enabled = val;
-        if !val {
-            state.dither_type = "off".to_string();
-            reset_dither_shape_state(&mut state);
-        } else if state.dither_type == "off" {
-            state.dither_type = "tpdf".to_string();
-        }
+
+    #[test]
+    fn gapless_setting_feeds_decode_options() {
+        let shared = create_shared_state();
+        let mut state = shared.inner.lock().unwrap();
+        assert!(decode_options_for(&state).gapless_trim);
+        state.gapless_trim_enabled = false;
+        assert!(!decode_options_for(&state).gapless_trim);
     }
-    if let Some(val) = req.replaygain_enabled {
-        state.replaygain_enabled = val;
+
+    #[test]
+    fn refresh_track_replaces_library_and_queue_entries() {
+        let path = std::env::temp_dir().join(format!("ntmusic_refresh_{}.wav", std::process::id()));
+        std::fs::write(&path, b"not really audio").unwrap();
+        let path_str = path.to_string_lossy().to_string();
+        let stale = LibraryTrack {
+            path: path_str.clone(),
+            title: Some("Old".to_string()),
+            artist: None,
+            artists: Vec::new(),
+            album_artist: None,
+            album: None,
+            track_number: None,
+            disc_number: None,
+            year: None,
+            genre: None,
+            duration: 0.0,
+            sample_rate: None,
+            bit_depth: None,
+            channels: None,
+            track_gain: None,
+            album_gain: None,
+            decodable: true,
+        };
+        let shared = create_shared_state();
+        {
+            let mut state = shared.inner.lock().unwrap();
+            state.library = vec![stale.clone()];
+            state.queue = vec![stale];
+        }
+        let mut rx = shared.tx.subscribe();
+        let (track, _) = refresh_library_track_impl(&shared, &path_str).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let expected_title = path.file_stem().map(|s| s.to_string_lossy().to_string());
+        assert_eq!(track.title, expected_title);
+        let state = shared.inner.lock().unwrap();
+        assert_eq!(state.library.len(), 1);
+        assert_eq!(state.library[0].title, expected_title);
+        assert_eq!(state.queue[0].title, expected_title);
+        let event: Value = serde_json::from_str(&rx.try_recv().unwrap()).unwrap();
+        assert_eq!(event["type"], "library_updated");
+        assert_eq!(event["track"]["path"], path_str.as_str());
     }
-    if let Some(value) = req.resampler_mode {
-        state.resampler_mode = normalize_resampler_mode(&value);
+
+    #[test]
+    fn library_scan_reports_progress_and_stops_on_cancel() {
+        let root = std::env::temp_dir().join(format!("ntmusic_scan_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        for name in ["a.flac", "b.mp3", "notes.txt"] {
+            std::fs::write(root.join(name), b"x").unwrap();
+        }
+        let root_str = root.to_string_lossy().to_string();
+
+        let mut seen = Vec::new();
+        let outcome = scan_library_impl(
+            &root_str,
+            &default_audio_extensions(),
+            ScanScope::default(),
+            None,
+            &AtomicBool::new(false),
+            |files, total, _| seen.push((files, total)),
+        )
+        .unwrap();
+        assert_eq!(outcome.tracks.len(), 2);
+        assert_eq!(outcome.files_seen, 3);
+        assert_eq!(seen, vec![(1, 2), (2, 2)]);
+        assert!(!outcome.cancelled);
+
+        let cancelled = scan_library_impl(
+            &root_str,
+            &default_audio_extensions(),
+            ScanScope::default(),
+            None,
+            &AtomicBool::new(true),
+            |_, _, _| {},
+        )
+        .unwrap();
+        assert!(cancelled.cancelled && cancelled.tracks.is_empty());
+        let text_only = vec!["txt".to_string()];
+        let custom = scan_library_impl(
+            &root_str,
+            &text_only,
+            ScanScope::default(),
+            None,
+            &AtomicBool::new(false),
+            |_, _, _| {},
+        )
+        .unwrap();
+        assert_eq!(custom.tracks.len(), 1);
+
+        let shared = create_shared_state();
+        let cache_path = std::env::temp_dir().join(format!("ntmusic_scan_cache_{}.json", std::process::id()));
+        shared.inner.lock().unwrap().scan_cache_path = cache_path.clone();
+        assert!(!cancel_library_scan_impl(&shared));
+        shared.scan_active.store(true, Ordering::Release);
+        let busy = run_library_scan(&shared, &root_str, ScanScope::default(), false).err().unwrap();
+        assert_eq!(busy.downcast_ref::<ScanError>(), Some(&ScanError::Busy));
+        assert!(cancel_library_scan_impl(&shared));
+        shared.scan_active.store(false, Ordering::Release);
+        let mut rx = shared.tx.subscribe();
+        assert_eq!(run_library_scan(&shared, &root_str, ScanScope::default(), false).unwrap().tracks.len(), 2);
+        assert_eq!(shared.inner.lock().unwrap().library.len(), 2);
+        let events: Vec<Value> = std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|text| serde_json::from_str(&text).unwrap())
+            .collect();
+        let last_progress = &events[events.len() - 2];
+        assert_eq!(last_progress["type"], "scan_progress");
+        assert_eq!(last_progress["finished"], true);
+        let complete = events.last().unwrap();
+        assert_eq!(complete["type"], "scan_complete");
+        assert_eq!(complete["scan_id"], last_progress["scan_id"]);
+        assert_eq!(complete["tracks"].as_array().unwrap().len(), 2);
+        // The last file is always reported, with the total known up front.
+        let probed = events.iter().rev().find(|event| event["finished"] == false).unwrap();
+        assert_eq!((probed["files"].as_u64(), probed["total"].as_u64()), (Some(2), Some(2)));
+
+        // Unchanged files come from the cache; a rewritten one is probed.
+        assert_eq!(run_library_scan(&shared, &root_str, ScanScope::default(), false).unwrap().cached, 2);
+        std::fs::write(root.join("b.mp3"), b"xy").unwrap();
+        let rescan = run_library_scan(&shared, &root_str, ScanScope::default(), false).unwrap();
+        assert_eq!((rescan.cached, rescan.tracks.len()), (1, 2));
+        assert_eq!(run_library_scan(&shared, &root_str, ScanScope::default(), true).unwrap().cached, 0);
+        let _ = std::fs::remove_file(&cache_path);
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn linked_scan_terminates_and_lists_each_file_once() {
+        use std::os::unix::fs::symlink;
+        let root = std::env::temp_dir().join(format!("ntmusic_scan_links_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("albums").join("a")).unwrap();
+        std::fs::write(root.join("albums").join("a").join("01.flac"), b"x").unwrap();
+        // A sibling alias of the same album and a link back up to the root.
+        symlink(root.join("albums").join("a"), root.join("favourites")).unwrap();
+        symlink(&root, root.join("albums").join("a").join("loop")).unwrap();
+        symlink(root.join("albums").join("a").join("01.flac"), root.join("01.flac")).unwrap();
+        let root_str = root.to_string_lossy().to_string();
+        let outcome = scan_library_impl(
+            &root_str,
+            &default_audio_extensions(),
+            ScanScope::default(),
+            None,
+            &AtomicBool::new(false),
+            |_, _, _| {},
+        )
+        .unwrap();
+        let _ = std::fs::remove_dir_all(&root);
+        assert_eq!(outcome.tracks.len(), 1);
+        assert_eq!(outcome.files_seen, 1);
     }
-    if let Some(value) = req.resampler_quality {
-        state.resampler_quality = normalize_resampler_quality(&value);
+
+    #[test]
+    fn non_recursive_scan_stays_in_the_root() {
+        let root = std::env::temp_dir().join(format!("ntmusic_scan_flat_{}", std::process::id()));
+        std::fs::create_dir_all(root.join("disc2")).unwrap();
+        std::fs::write(root.join("01.flac"), b"x").unwrap();
+        std::fs::write(root.join("disc2").join("01.flac"), b"x").unwrap();
+        let root_str = root.to_string_lossy().to_string();
+        let scan = |recursive| {
+            let scope = ScanScope {
+                recursive,
+                follow_links: false,
+            };
+            let no_cancel = AtomicBool::new(false);
+            scan_library_impl(&root_str, &default_audio_extensions(), scope, None, &no_cancel, |_, _, _| {}).unwrap()
+        };
+        let flat = scan(false);
+        let deep = scan(true);
+        let _ = std::fs::remove_dir_all(&root);
+        assert_eq!(flat.tracks.len(), 1);
+        assert!(flat.tracks[0].path.ends_with("01.flac") && !flat.tracks[0].path.contains("disc2"));
+        assert_eq!(deep.tracks.len(), 2);
     }
-    if let Some(value) = req.limiter_enabled {
-        state.limiter_enabled = value;
+
+    #[test]
+    fn nested_playlists_expand_one_level() {
+        let root = std::env::temp_dir().join(format!("ntmusic_playlist_{}", std::process::id()));
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("sub").join("a.flac"), b"x").unwrap();
+        std::fs::write(
+            root.join("top.pls"),
+            "[playlist]\nFile1=sub/inner.m3u\nTitle1=Inner\nFile2=http://radio.example/live\nFile3=gone.pls\n",
+        )
+        .unwrap();
+        std::fs::write(root.join("sub").join("inner.m3u"), "a.flac\nmissing.flac\ndeeper.asx\n").unwrap();
+        let loaded = load_playlist_impl(&root.join("top.pls").to_string_lossy());
+        let _ = std::fs::remove_dir_all(&root);
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.kind, PlaylistKind::Pls);
+        assert_eq!(loaded.files.len(), 1);
+        assert!(loaded.files[0].location.ends_with("a.flac"));
+        assert_eq!(loaded.files[0].title.as_deref(), Some("Inner"));
+        assert_eq!(loaded.streams.len(), 1);
+        let reasons: Vec<&str> = loaded.skipped.iter().map(|s| s.reason.as_str()).collect();
+        assert_eq!(reasons.len(), 3);
+        assert!(reasons.contains(&"file not found"));
+        assert!(reasons.contains(&"playlist nested too deeply"));
     }
-    if let Some(value) = req.limiter_threshold {
-        state.limiter_threshold = normalize_limiter_threshold(value);
+
+    #[test]
+    fn audio_extensions_are_normalized_and_validated() {
+        assert_eq!(
+            normalize_audio_extensions(&[".FLAC", "dsf", "flac"]).unwrap(),
+            vec!["flac".to_string(), "dsf".to_string()]
+        );
+        assert!(normalize_audio_extensions(&["mp3", "../x"]).is_err());
+        assert!(normalize_audio_extensions::<&str>(&[]).is_err());
+        let exts = default_audio_extensions();
+        assert!(is_supported_audio_path(Path::new("/music/Song.MP3"), &exts));
+        assert!(!is_supported_audio_path(Path::new("/music/cover.jpg"), &exts));
     }
-    state.soxr_available = detect_soxr_available();
-    Json(json!({ "status": "success", "state": build_state_view(&state) }))
-}
-async fn load_stream_handler(State(shared): State<SharedState>, Json(req): Json<StreamRequest>) -> impl IntoResponse {
-    stop_stream(&shared);
-    let (sample_rate, channels) = {
+
+    #[test]
+    fn seek_clamped_stays_inside_the_file() {
+        let shared = create_shared_state();
         let mut state = shared.inner.lock().unwrap();
-        state.mode = "stream".to_string();
-        state.stream_url = Some(req.url.clone());
-        state.sample_rate = state.target_samplerate.unwrap_or(48_000);
+        state.mode = "file".to_string();
+        state.sample_rate = 1000;
         state.channels = 2;
-        state.source_sample_rate = state.sample_rate;
-        state.source_channels = state.channels;
-        state.source_bit_depth = None;
-        state.data.clear();
-        state.position = 0;
-        state.played_frames = 0;
-        state.duration = 0.0;
-        state.buffered_frames = 0;
-        state.stream_status = "starting".to_string();
-        (state.sample_rate, state.channels as u16)
-    };
-    reset_ring_buffer(&shared);
-    let child = match spawn_ffmpeg(&req.url, sample_rate, channels) {
-        Ok(child) => child,
-        Err(err) => {
-            update_stream_status(&shared, "error", Some(err.to_string()));
-            return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
-                "status": "error",
-                "message": "failed to start ffmpeg"
-            })));
+        state.data = vec![0.0; 2 * 5000];
+        state.position = 2000;
+        assert_eq!(seek_clamped(&mut state, 2.0 + 1.5), 3.5);
+        assert_eq!(state.position, 3500);
+        assert_eq!(seek_clamped(&mut state, 3.5 - 10.0), 0.0);
+        assert_eq!(seek_clamped(&mut state, 60.0), 5.0);
+        assert_eq!(state.position, 5000);
+    }
+
+    #[test]
+    fn only_streams_with_a_length_seek() {
+        let banner = "Input #0, mp3, from 'http://example.com/a.mp3':\n  Duration: 00:03:25.50, start: 0.000000, bitrate: 128 kb/s\n";
+        assert_eq!(parse_ffmpeg_duration(banner), Some(205.5));
+        assert_eq!(parse_ffmpeg_duration("  Duration: N/A, start: 0.000000, bitrate: 128 kb/s"), None);
+        assert_eq!(parse_ffmpeg_duration("http://x: Connection refused"), None);
+
+        let shared = create_shared_state();
+        {
+            let mut state = shared.inner.lock().unwrap();
+            state.mode = "stream".to_string();
+            state.stream_url = Some("http://example.com/live".to_string());
+            state.sample_rate = 48_000;
         }
-    };
-    start_stream_reader(shared.clone(), child);
-    let _ = ensure_output_stream(&shared);
-    send_state(&shared);
-    let state = shared.inner.lock().unwrap();
-    (StatusCode::OK, Json(json!({ "status": "success", "state": build_state_view(&state) })))
-}
+        let err = seek_stream_impl(&shared, 10.0).unwrap_err();
+        assert_eq!(err.to_string(), "stream length not known yet");
+        shared.inner.lock().unwrap().stream_seekable = Some(false);
+        let err = seek_stream_impl(&shared, 10.0).unwrap_err();
+        assert_eq!(err.to_string(), "live stream is not seekable");
+        assert_eq!(shared.inner.lock().unwrap().stream_url.as_deref(), Some("http://example.com/live"));
+    }
 
-async fn capture_start_handler(State(shared): State<SharedState>, Json(req): Json<CaptureStartRequest>) -> impl IntoResponse {
-    if let Err(err) = start_capture_impl(&shared, req.device_id, req.samplerate, req.channels) {
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({
-                "status": "error",
-                "message": err.to_string()
-            })),
+    #[test]
+    fn capture_input_follows_the_platform() {
+        let args = |os, device| capture_input_args(os, device).map(|args| args.join(" "));
+        assert_eq!(args("windows", None).unwrap(), "-f wasapi -i default");
+        assert_eq!(args("windows", Some("{0.0.1.00000000}.{id}")).unwrap(), "-f wasapi -i {0.0.1.00000000}.{id}");
+        assert_eq!(args("linux", None).unwrap(), "-f pulse -i @DEFAULT_MONITOR@");
+        assert_eq!(args("linux", Some("default")).unwrap(), "-f pulse -i @DEFAULT_MONITOR@");
+        assert_eq!(args("linux", Some("hdmi.monitor")).unwrap(), "-f pulse -i hdmi.monitor");
+        assert_eq!(args("macos", Some("BlackHole 2ch")).unwrap(), "-f avfoundation -i :BlackHole 2ch");
+        assert!(args("macos", None).is_err());
+        assert!(args("freebsd", None).is_err());
+
+        let listing = "Source #0\n\tState: SUSPENDED\n\tName: alsa_output.pci.analog-stereo.monitor\n\t\
+                       Description: Monitor of Built-in Audio\n\tDriver: PipeWire\n\n\
+                       Source #1\n\tName: alsa_input.pci.analog-stereo\n\tDescription: Built-in Microphone\n";
+        let sources = parse_pulse_sources(listing);
+        let summary: Vec<_> = sources.iter().map(|s| (s.id.as_str(), s.name.as_str(), s.kind)).collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("alsa_output.pci.analog-stereo.monitor", "Monitor of Built-in Audio", "loopback"),
+                ("alsa_input.pci.analog-stereo", "Built-in Microphone", "input"),
+            ]
         );
     }
-    let state = shared.inner.lock().unwrap();
-    (
-        StatusCode::OK,
-        Json(json!({ "status": "success", "state": build_state_view(&state) })),
-    )
-}
 
-async fn capture_stop_handler(State(shared): State<SharedState>) -> impl IntoResponse {
-    if let Err(err) = stop_capture_impl(&shared) {
-        return Json(json!({ "status": "error", "message": err.to_string() }));
+    #[test]
+    fn inline_cover_is_a_data_uri() {
+        assert_eq!(cover_data_uri(b"\xff\xd8\xff", "image/jpeg"), "data:image/jpeg;base64,/9j/");
+        assert_eq!(cover_data_uri(b"ab", ""), "data:application/octet-stream;base64,YWI=");
+    }
+
+    /// A FLAC header with the given Vorbis comments and no audio.
+    fn flac_with_comments(name: &str, comments: &[&str]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("ntmusic_{}_{}.flac", name, std::process::id()));
+        let mut bytes = b"fLaC".to_vec();
+        bytes.extend_from_slice(&[0, 0, 0, 34]);
+        bytes.extend_from_slice(&4096u16.to_be_bytes());
+        bytes.extend_from_slice(&4096u16.to_be_bytes());
+        bytes.extend_from_slice(&[0; 6]);
+        bytes.extend_from_slice(&((44_100u64 << 44) | (1 << 41) | (15 << 36)).to_be_bytes());
+        bytes.extend_from_slice(&[0; 16]);
+        let mut block = Vec::new();
+        block.extend_from_slice(&4u32.to_le_bytes());
+        block.extend_from_slice(b"test");
+        block.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+        for comment in comments {
+            block.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            block.extend_from_slice(comment.as_bytes());
+        }
+        bytes.push(0x80 | 4);
+        bytes.extend_from_slice(&(block.len() as u32).to_be_bytes()[1..]);
+        bytes.extend_from_slice(&block);
+        // The reader syncs to a first frame: a 4096-sample 44.1 kHz 16-bit
+        // stereo frame header, with its CRC-8.
+        bytes.extend_from_slice(&[0xff, 0xf8, 0xc9, 0x18, 0x00, 0xc2]);
+        std::fs::write(&path, bytes).unwrap();
+        path
     }
-    let state = shared.inner.lock().unwrap();
-    Json(json!({ "status": "success", "state": build_state_view(&state) }))
-}
 
-async fn spectrum_ws_handler(State(shared): State<SharedState>, Json(req): Json<SpectrumWsRequest>) -> impl IntoResponse {
-    {
-        let mut state = shared.inner.lock().unwrap();
-        state.spectrum_ws_enabled = req.enabled;
+    #[test]
+    fn lyrics_prefer_a_synced_copy() {
+        let both = flac_with_comments(
+            "lyrics_both",
+            &["UNSYNCEDLYRICS=First line\nSecond line", "LYRICS=[00:01.50]First line\n[00:04]Second line"],
+        );
+        let plain = flac_with_comments("lyrics_plain", &["LYRICS=Just words\n[Chorus]"]);
+        let none = flac_with_comments("lyrics_none", &["TITLE=Instrumental"]);
+        let synced = extract_lyrics(&both).unwrap().unwrap();
+        let unsynced = extract_lyrics(&plain).unwrap().unwrap();
+        let missing = extract_lyrics(&none).unwrap();
+        for path in [&both, &plain, &none] {
+            let _ = std::fs::remove_file(path);
+        }
+        assert!(synced.starts_with("[00:01.50]") && is_synced_lyrics(&synced));
+        assert_eq!(unsynced, "Just words\n[Chorus]");
+        assert!(!is_synced_lyrics(&unsynced));
+        assert!(missing.is_none());
     }
-    send_state(&shared);
-    let state = shared.inner.lock().unwrap();
-    Json(json!({ "status": "success", "state": build_state_view(&state) }))
-}
 
-async fn capture_devices_handler() -> impl IntoResponse {
-    if cfg!(target_os = "windows") {
-        Json(json!({
-            "status": "success",
-            "devices": [{ "id": "default", "name": "default", "backend": "wasapi" }]
-        }))
-    } else {
-        Json(json!({
-            "status": "success",
-            "devices": []
-        }))
+    #[test]
+    fn restart_without_source_errors() {
+        let shared = create_shared_state();
+        assert!(restart_impl(&shared).is_err());
+    }
+
+    fn swept_sine(rate: u32, start_hz: f64, end_hz: f64, seconds: f64) -> Vec<f32> {
+        let frames = (rate as f64 * seconds) as usize;
+        let mut phase = 0.0f64;
+        (0..frames)
+            .map(|i| {
+                let t = i as f64 / frames as f64;
+                let freq = start_hz + (end_hz - start_hz) * t;
+                phase += 2.0 * std::f64::consts::PI * freq / rate as f64;
+                (0.5 * phase.sin()) as f32
+            })
+            .collect()
     }
-}
 
-async fn buffer_state_handler(State(shared): State<SharedState>) -> impl IntoResponse {
-    let state = shared.inner.lock().unwrap();
-    Json(json!({
-        "status": "success",
-        "buffered_ms": if state.sample_rate > 0 {
-            (state.buffered_frames as f64 / state.sample_rate as f64) * 1000.0
-        } else {
-            0.0
-        },
-        "underruns": state.underrun_count,
-        "mode": state.mode.clone()
-    }))
-}
+    fn rms(samples: &[f32]) -> f64 {
+        let sum: f64 = samples.iter().map(|s| (*s as f64) * (*s as f64)).sum();
+        (sum / samples.len().max(1) as f64).sqrt()
+    }
 
-fn start_background_tasks(shared: SharedState) {
-    let state_clone = shared.clone();
-    tokio::spawn(async move {
-        loop {
-            send_state(&state_clone);
-            send_buffer_state(&state_clone);
-            tokio::time::sleep(Duration::from_millis(250)).await;
-        }
-    });
+    #[test]
+    fn cross_family_resampling_suppresses_aliasing() {
+        assert!(is_cross_family(48_000, 44_100));
+        assert!(is_cross_family(88_200, 96_000));
+        assert!(!is_cross_family(48_000, 96_000));
+        assert_eq!(effective_resampler_quality("low", 48_000, 44_100), "hq");
+        assert_eq!(effective_resampler_quality("low", 48_000, 96_000), "low");
+
+        // Everything in this sweep sits above the 44.1 kHz Nyquist, so any
+        // energy left after conversion is aliasing.
+        let input = swept_sine(48_000, 22_600.0, 23_800.0, 0.5);
+        let ratio = 44_100.0 / 48_000.0;
+        let naive = resample_audio_with_params(
+            &input,
+            1,
+            48_000,
+            44_100,
+            get_sinc_params("low", ratio),
+        )
+        .unwrap();
+        let guarded = resample_audio(&input, 1, 48_000, 44_100, "low").unwrap();
+        let trim = 2048;
+        let naive_rms = rms(&naive[trim..naive.len() - trim]);
+        let guarded_rms = rms(&guarded[trim..guarded.len() - trim]);
+        assert!(guarded_rms * 100.0 < naive_rms);
 
-    let state_clone = shared.clone();
-    let spectrum_bins = state_clone.spectrum_bins;
-    let spectrum_shared = state_clone.spectrum_shared.clone();
-    tokio::spawn(async move {
-        let mut analyzer = SpectrumAnalyzer::new(SPECTRUM_FFT_SIZE, spectrum_bins);
-        let mut sample_buffer = vec![0.0f32; SPECTRUM_FFT_SIZE];
-        loop {
-            let (sample_rate, ws_enabled) = {
-                let state = state_clone.inner.lock().unwrap();
-                let copy_len = state.last_output_chunk.len().min(SPECTRUM_FFT_SIZE);
-                if copy_len > 0 {
-                    sample_buffer[..copy_len].copy_from_slice(&state.last_output_chunk[..copy_len]);
-                }
-                if copy_len < SPECTRUM_FFT_SIZE {
-                    for value in &mut sample_buffer[copy_len..] {
-                        *value = 0.0;
-                    }
-                }
-                (state.sample_rate, state.spectrum_ws_enabled)
-            };
-            let spectrum = analyzer.compute(&sample_buffer, sample_rate);
-            write_spectrum_shared(&spectrum_shared, spectrum);
-            if ws_enabled {
-                let payload = json!({ "type": "spectrum_data", "data": spectrum });
-                let _ = state_clone.tx.send(payload.to_string());
+        let info = resampler_info("rubato", "resampler_mode is rubato", "low", 48_000, 44_100, 48_000);
+        assert!(info.cross_family);
+        assert_eq!(info.effective_quality, "hq");
+        assert_eq!(info.sinc_len, Some(256));
+        assert_eq!(info.output_frames, 44_100);
+    }
+
+    #[test]
+    fn auto_resampler_choice_follows_quality_and_availability() {
+        assert_eq!(resampler_choice("auto", "hq", true).0, "soxr");
+        assert_eq!(resampler_choice("auto", "std", true).0, "rubato");
+        assert_eq!(resampler_choice("auto", "uhq", false), ("rubato", "auto: soxr not available"));
+        assert_eq!(resampler_choice("soxr", "low", false).0, "soxr");
+    }
+
+    #[test]
+    fn resampling_keeps_clicks_time_aligned() {
+        // One click early and one inside the span the filter delay holds back.
+        for click_at in [10_000usize, 44_050] {
+            let mut input = vec![0.0f32; 44_100];
+            input[click_at] = 1.0;
+            for (quality, to_rate) in [("std", 96_000u32), ("hq", 48_000), ("low", 22_050)] {
+                let output = resample_audio(&input, 1, 44_100, to_rate, quality).unwrap();
+                assert_eq!(output.len(), resampled_frame_count(input.len(), 44_100, to_rate));
+                let peak = output
+                    .iter()
+                    .enumerate()
+                    .max_by(|a, b| a.1.abs().total_cmp(&b.1.abs()))
+                    .map(|(idx, _)| idx)
+                    .unwrap();
+                let expected = click_at as f64 * to_rate as f64 / 44_100.0;
+                assert!(
+                    (peak as f64 - expected).abs() < 1.5,
+                    "{} -> {}: click at {} expected {}",
+                    quality,
+                    to_rate,
+                    peak,
+                    expected
+                );
             }
-            tokio::time::sleep(Duration::from_millis(SPECTRUM_UPDATE_INTERVAL_MS)).await;
         }
-    });
-}
-
-pub async fn run_http_server(
-    shared: Option<SharedState>,
-    port: Option<u16>,
-) -> Result<()> {
-    let _ = tracing_subscriber::fmt::try_init();
-    let port = port
-        .or_else(|| std::env::var("VMUSIC_ENGINE_PORT").ok().and_then(|v| v.parse::<u16>().ok()))
-        .unwrap_or(55_554);
 
-    let shared = match shared {
-        Some(shared) => shared,
-        None => create_shared_state(),
-    };
-    start_background_tasks(shared.clone());
+        let ratio = 48_000.0 / 44_100.0;
+        let params = get_sinc_params("hq", ratio);
+        let resampler = SincFixedIn::<f64>::new(ratio, 2.0, params, 1024, 1).unwrap();
+        assert_eq!(resampler.output_delay(), sinc_output_delay(256, ratio));
+    }
 
-    let app = Router::new()
-        .route("/ws", get(ws_handler))
-        .route("/state", get(get_state_handler))
-        .route("/devices", get(list_devices_handler))
-        .route("/library/scan", post(scan_library_handler))
-        .route("/queue/add", post(queue_add_handler))
-        .route("/queue/next", post(queue_next_handler))
-        .route("/command", post(command_handler))
-        .route("/cover", post(cover_handler))
-        .route("/load", post(load_handler))
-        .route("/play", post(play_handler))
-        .route("/pause", post(pause_handler))
-        .route("/stop", post(stop_handler))
-        .route("/seek", post(seek_handler))
-        .route("/volume", post(volume_handler))
-        .route("/configure_output", post(configure_output_handler))
-        .route("/configure_upsampling", post(configure_upsampling_handler))
-        .route("/set_eq", post(set_eq_handler))
-        .route("/set_eq_type", post(set_eq_type_handler))
-        .route("/configure_optimizations", post(configure_opt_handler))
-        .route("/spectrum/ws", post(spectrum_ws_handler))
-        .route("/load_stream", post(load_stream_handler))
-        .route("/capture/start", post(capture_start_handler))
-        .route("/capture/stop", post(capture_stop_handler))
-        .route("/capture/devices", get(capture_devices_handler))
-        .route("/buffer/state", get(buffer_state_handler))
-        .with_state(shared);
+    #[test]
+    fn output_buffer_size_respects_device_range() {
+        let range = cpal::SupportedBufferSize::Range { min: 64, max: 4096 };
+        assert!(matches!(
+            output_buffer_size(Some(10), 48_000, &range),
+            cpal::BufferSize::Fixed(480)
+        ));
+        assert!(matches!(
+            output_buffer_size(Some(500), 48_000, &range),
+            cpal::BufferSize::Default
+        ));
+        assert!(matches!(
+            output_buffer_size(None, 48_000, &range),
+            cpal::BufferSize::Default
+        ));
+        assert!(matches!(
+            output_buffer_size(Some(10), 48_000, &cpal::SupportedBufferSize::Unknown),
+            cpal::BufferSize::Default
+        ));
+    }
 
-    let addr = format!("127.0.0.1:{}", port);
-    info!("NTmusic engine listening on {}", addr);
-    println!("VMUSIC_ENGINE_READY");
-    axum::serve(tokio::net::TcpListener::bind(addr).await?, app).await?;
-    Ok(())
-}
+    #[test]
+    fn parse_gain_value_handles_units_and_signs() {
+        assert_eq!(parse_gain_value("-6.54 dB"), Some(-6.54));
+        assert_eq!(parse_gain_value("+1.20 dB"), Some(1.2));
+        assert_eq!(parse_gain_value("0.988831"), Some(0.988831));
+        assert_eq!(parse_gain_value("loud"), None);
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn replaygain_preamp_and_clipping_prevention() {
+        assert_eq!(replaygain_factor(None, Some(0.5), 6.0, true), 1.0);
+        let boosted = replaygain_factor(Some(0.0), None, 6.0, true);
+        assert!((boosted - db_to_linear(6.0)).abs() < 1e-6);
+        // +6 dB on a track peaking at 0.9 would clip; the peak caps the gain.
+        let capped = replaygain_factor(Some(6.0), Some(0.9), 0.0, true);
+        assert!((capped * 0.9 - 1.0).abs() < 1e-6);
+        let uncapped = replaygain_factor(Some(6.0), Some(0.9), 0.0, false);
+        assert!(uncapped * 0.9 > 1.0);
+    }
 
     #[test]
     fn normalize_dither_type_accepts_shaped_variants() {
@@ -3540,4 +15245,55 @@ mod tests {
         assert_ne!(seed, 1u64);
         assert!(data.iter().all(|v| *v <= 1.0 && *v >= -1.0));
     }
+
+    #[test]
+    fn unplugged_device_falls_back_to_default_and_returns_when_replugged() {
+        let key = |name: &str| ("Wasapi".to_string(), name.to_string());
+        let mut state = initial_state();
+        state.device_id = Some(1);
+        state.selected_device = Some(key("B"));
+        let mut watch = DeviceWatch::default();
+        let default = Some("A".to_string());
+
+        assert!(plan_device_change(&mut state, &mut watch, vec![key("A"), key("B"), key("C")], default.clone(), false)
+            .is_none());
+        assert!(plan_device_change(&mut state, &mut watch, vec![key("A"), key("B"), key("C")], default.clone(), false)
+            .is_none());
+
+        let (event, reopen) =
+            plan_device_change(&mut state, &mut watch, vec![key("A"), key("C")], default.clone(), false).unwrap();
+        assert!(reopen);
+        assert_eq!(state.device_id, None);
+        assert!(state.device_fallback);
+        assert_eq!(event["type"], "device_changed");
+        assert_eq!(event["name"], "A");
+
+        let (event, reopen) =
+            plan_device_change(&mut state, &mut watch, vec![key("A"), key("C"), key("B")], default, false).unwrap();
+        assert!(reopen);
+        assert_eq!(state.device_id, Some(2));
+        assert!(!state.device_fallback);
+        assert_eq!(event["name"], "B");
+        assert_eq!(event["fallback"], false);
+    }
+
+    #[test]
+    fn out_of_range_device_ids_are_rejected_and_leave_the_output_alone() {
+        let shared = create_shared_state();
+        let err = configure_output_impl(&shared, Some(usize::MAX), None).unwrap_err();
+        assert!(err.to_string().contains("no output device with id"));
+        let state = shared.inner.lock().unwrap();
+        assert_eq!(state.device_id, None);
+        assert_eq!(state.selected_device, None);
+    }
+
+    #[test]
+    fn lost_output_reopens_even_when_the_list_looks_the_same() {
+        let keys = vec![("Wasapi".to_string(), "A".to_string())];
+        let mut state = initial_state();
+        let mut watch = DeviceWatch::default();
+        plan_device_change(&mut state, &mut watch, keys.clone(), None, false);
+        let (_, reopen) = plan_device_change(&mut state, &mut watch, keys, None, true).unwrap();
+        assert!(reopen);
+    }
 }
@@ -0,0 +1,215 @@
+//! Real-time rate conversion for stream and capture playback.
+//!
+//! Files are resampled whole when they load. Live sources arrive at the
+//! rate ffmpeg or the capture endpoint delivers, which the output device
+//! may not run at, so they are converted in the output callback instead:
+//! rubato's fixed-output sinc resampler runs a chunk at a time, pulling
+//! source frames as it needs them, and output a callback didn't take waits
+//! for the next one. Everything is allocated up front.
+
+use std::fmt;
+
+use anyhow::{anyhow, Result};
+use rubato::{Resampler, SincFixedOut};
+
+use crate::get_sinc_params;
+
+/// Output frames per resampler run. Callbacks of any size are served from
+/// whole chunks.
+const CHUNK_FRAMES: usize = 256;
+
+pub(crate) struct LiveResampler {
+    from_rate: u32,
+    to_rate: u32,
+    channels: usize,
+    quality: String,
+    resampler: SincFixedOut<f32>,
+    /// Planar, sized for the largest input a chunk can need.
+    input: Vec<Vec<f32>>,
+    /// Planar output of the last chunk.
+    output: Vec<Vec<f32>>,
+    /// Frames of `output` the last chunk produced, and how many of those
+    /// are handed out already.
+    produced: usize,
+    taken: usize,
+}
+
+/// What one `fill` took from the source.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct FillResult {
+    /// Source frames read.
+    pub consumed: usize,
+    /// Source frames the resampler needed but the source didn't have,
+    /// stood in for by silence.
+    pub missing: usize,
+}
+
+impl LiveResampler {
+    /// `quality` is a `resampler_quality` setting, as for files.
+    pub(crate) fn new(from_rate: u32, to_rate: u32, channels: usize, quality: &str) -> Result<Self> {
+        if from_rate == 0 || to_rate == 0 || channels == 0 {
+            return Err(anyhow!("live resampler needs non-zero rates and channels"));
+        }
+        let ratio = to_rate as f64 / from_rate as f64;
+        let params = get_sinc_params(quality, ratio);
+        // rubato 0.14's `input_frames_max` undercounts for fixed output
+        // when upsampling; a chunk never needs more than its span of input
+        // plus the filter length.
+        let input_frames = (CHUNK_FRAMES as f64 / ratio).ceil() as usize + 2 * params.sinc_len + 3;
+        let resampler = SincFixedOut::<f32>::new(ratio, 1.0, params, CHUNK_FRAMES, channels)
+            .map_err(|err| anyhow!("live resampler init failed: {}", err))?;
+        let input_frames = input_frames.max(resampler.input_frames_max());
+        let input = vec![vec![0.0; input_frames]; channels];
+        let output = vec![vec![0.0; resampler.output_frames_max()]; channels];
+        Ok(LiveResampler {
+            from_rate,
+            to_rate,
+            channels,
+            quality: quality.to_string(),
+            resampler,
+            input,
+            output,
+            produced: 0,
+            taken: 0,
+        })
+    }
+
+    pub(crate) fn converts(&self, from_rate: u32, to_rate: u32, channels: usize) -> bool {
+        self.from_rate == from_rate && self.to_rate == to_rate && self.channels == channels
+    }
+
+    pub(crate) fn quality(&self) -> &str {
+        &self.quality
+    }
+
+    /// Drop held output and filter history, e.g. when a new source starts.
+    pub(crate) fn reset(&mut self) {
+        self.resampler.reset();
+        self.produced = 0;
+        self.taken = 0;
+    }
+
+    /// Output frames between a source frame going in and it being heard:
+    /// the filter delay plus output already made but not yet taken.
+    pub(crate) fn latency_frames(&self) -> usize {
+        self.resampler.output_delay() + (self.produced - self.taken)
+    }
+
+    /// Fill interleaved `out` at the output rate, reading interleaved
+    /// source samples from `next` until it returns `None`.
+    pub(crate) fn fill(&mut self, out: &mut [f32], mut next: impl FnMut() -> Option<f32>) -> FillResult {
+        let channels = self.channels;
+        let mut result = FillResult::default();
+        for frame in out.chunks_exact_mut(channels) {
+            if self.taken == self.produced && !self.run_chunk(&mut next, &mut result) {
+                frame.fill(0.0);
+                continue;
+            }
+            for (ch, sample) in frame.iter_mut().enumerate() {
+                *sample = self.output[ch][self.taken];
+            }
+            self.taken += 1;
+        }
+        result
+    }
+
+    fn run_chunk(&mut self, next: &mut impl FnMut() -> Option<f32>, result: &mut FillResult) -> bool {
+        let needed = self.resampler.input_frames_next();
+        // Only if the sizing in `new` fell short; allocates the once.
+        if needed > self.input[0].len() {
+            for channel in self.input.iter_mut() {
+                channel.resize(needed, 0.0);
+            }
+        }
+        let mut dry = false;
+        for frame in 0..needed {
+            for ch in 0..self.channels {
+                self.input[ch][frame] = if dry { None } else { next() }.unwrap_or_else(|| {
+                    dry = true;
+                    0.0
+                });
+            }
+            if dry {
+                result.missing += 1;
+            } else {
+                result.consumed += 1;
+            }
+        }
+        match self.resampler.process_into_buffer(&self.input, &mut self.output, None) {
+            Ok((_, produced)) => {
+                self.produced = produced;
+                self.taken = 0;
+                produced > 0
+            }
+            Err(_) => {
+                self.produced = 0;
+                self.taken = 0;
+                false
+            }
+        }
+    }
+}
+
+/// Holds the output callback's resampler in `EngineState`. Copies of the
+/// state (snapshots, offline renders) start without one: only the live
+/// state converts, and rebuilding the filter tables per copy would be
+/// wasted work.
+#[derive(Default)]
+pub(crate) struct LiveResamplerSlot(pub(crate) Option<Box<LiveResampler>>);
+
+impl Clone for LiveResamplerSlot {
+    fn clone(&self) -> Self {
+        LiveResamplerSlot(None)
+    }
+}
+
+impl fmt::Debug for LiveResamplerSlot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Some(live) => write!(f, "LiveResampler({} -> {} Hz, {} ch)", live.from_rate, live.to_rate, live.channels),
+            None => f.write_str("None"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn callbacks_of_any_size_keep_the_rate() {
+        let mut live = LiveResampler::new(44_100, 48_000, 2, "low").unwrap();
+        let source: Vec<f32> = (0..44_100 * 2).map(|i| ((i / 2) as f32 * 0.01).sin() * 0.5).collect();
+        let mut source = source.into_iter();
+        let (mut consumed, mut written) = (0usize, 0usize);
+        // Odd, varying callback sizes, as cpal may hand out.
+        for size in [441usize, 1_024, 17, 512, 2_000].iter().cycle().take(40) {
+            let mut out = vec![0.0f32; size * 2];
+            let result = live.fill(&mut out, || source.next());
+            assert_eq!(result.missing, 0);
+            consumed += result.consumed;
+            written += size;
+        }
+        // Source frames read track the output at the rate ratio, give or
+        // take what the resampler holds.
+        let expected = written as f64 * 44_100.0 / 48_000.0;
+        let held = live.latency_frames() + live.input[0].len();
+        assert!((consumed as f64 - expected).abs() <= held as f64, "{} vs {}", consumed, expected);
+    }
+
+    #[test]
+    fn a_dry_source_is_counted_and_padded_with_silence() {
+        let mut live = LiveResampler::new(48_000, 44_100, 1, "low").unwrap();
+        let mut source = vec![0.25f32; 100].into_iter();
+        let mut out = vec![1.0f32; 512];
+        let result = live.fill(&mut out, || source.next());
+        assert_eq!(result.consumed, 100);
+        assert!(result.missing > 0);
+        assert!(out.iter().all(|s| s.is_finite() && s.abs() <= 0.5));
+        assert!(live.converts(48_000, 44_100, 1));
+        assert!(!live.converts(48_000, 48_000, 1));
+
+        let slot = LiveResamplerSlot(Some(Box::new(live)));
+        assert!(slot.clone().0.is_none());
+    }
+}
@@ -0,0 +1,388 @@
+//! M3U, PLS and ASX playlist parsing.
+//!
+//! Entries come back as written, with relative paths resolved against the
+//! playlist's own location. Entries that are themselves playlists are left
+//! for the caller to expand, one level deep.
+
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use http_body_util::{BodyExt, Empty};
+use hyper::body::Bytes;
+use hyper::{header, Uri};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use serde::Serialize;
+
+/// Remote playlists bigger than this are not playlists.
+const MAX_REMOTE_BYTES: usize = 1 << 20;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_REDIRECTS: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum PlaylistKind {
+    M3u,
+    Pls,
+    Asx,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct PlaylistEntry {
+    pub location: String,
+    pub title: Option<String>,
+}
+
+impl PlaylistKind {
+    fn from_extension(location: &str) -> Option<Self> {
+        match location_extension(location)?.as_str() {
+            "m3u" | "m3u8" => Some(PlaylistKind::M3u),
+            "pls" => Some(PlaylistKind::Pls),
+            "asx" | "wax" | "wvx" => Some(PlaylistKind::Asx),
+            _ => None,
+        }
+    }
+
+    /// By extension, falling back to sniffing the content for servers that
+    /// hand out playlists under other names.
+    pub(crate) fn detect(location: &str, content: &str) -> Option<Self> {
+        if let Some(kind) = Self::from_extension(location) {
+            return Some(kind);
+        }
+        let head = content.trim_start_matches('\u{feff}').trim_start();
+        let lower: String = head.chars().take(256).collect::<String>().to_lowercase();
+        if lower.starts_with("[playlist]") {
+            Some(PlaylistKind::Pls)
+        } else if lower.starts_with("<asx") || (lower.starts_with("<?xml") && lower.contains("<asx")) {
+            Some(PlaylistKind::Asx)
+        } else if lower.starts_with("#extm3u") {
+            Some(PlaylistKind::M3u)
+        } else {
+            None
+        }
+    }
+}
+
+/// Lowercase extension of a path or URL, ignoring any query or fragment.
+fn location_extension(location: &str) -> Option<String> {
+    let path = location.split(['?', '#']).next().unwrap_or(location);
+    let name = path.rsplit(['/', '\\']).next()?;
+    let (_, ext) = name.rsplit_once('.')?;
+    Some(ext.to_ascii_lowercase())
+}
+
+pub(crate) fn is_remote(location: &str) -> bool {
+    let lower = location.to_ascii_lowercase();
+    lower.starts_with("http://") || lower.starts_with("https://")
+}
+
+/// Whether an entry points at another playlist rather than at audio.
+pub(crate) fn is_playlist_location(location: &str) -> bool {
+    PlaylistKind::from_extension(location).is_some()
+}
+
+/// Read a local playlist, or fetch a remote one. Blocks; a remote fetch
+/// runs on the current tokio runtime, so call this from `spawn_blocking`.
+pub(crate) fn read_source(location: &str) -> Result<String> {
+    let bytes = if is_remote(location) {
+        let fetch = tokio::time::timeout(FETCH_TIMEOUT, fetch(location));
+        tokio::runtime::Handle::current()
+            .block_on(fetch)
+            .map_err(|_| anyhow!("fetch failed: timed out"))??
+    } else {
+        std::fs::read(location).with_context(|| format!("read {}", location))?
+    };
+    if bytes.len() > MAX_REMOTE_BYTES {
+        return Err(anyhow!("playlist too large"));
+    }
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// GET `location` over HTTP or HTTPS, following redirects.
+async fn fetch(location: &str) -> Result<Vec<u8>> {
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    let client: Client<_, Empty<Bytes>> = Client::builder(TokioExecutor::new()).build(connector);
+    let mut uri: Uri = location.parse().with_context(|| format!("bad playlist url {}", location))?;
+    for _ in 0..=MAX_REDIRECTS {
+        let response = client.get(uri.clone()).await.context("fetch failed")?;
+        let status = response.status();
+        if status.is_redirection() {
+            let target = response
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|value| value.to_str().ok())
+                .ok_or_else(|| anyhow!("fetch failed: {} without a location", status))?;
+            uri = redirect_target(&uri, target).parse().context("fetch failed: bad redirect")?;
+            continue;
+        }
+        if !status.is_success() {
+            return Err(anyhow!("fetch failed: {}", status));
+        }
+        let mut body = response.into_body();
+        let mut bytes = Vec::new();
+        while let Some(frame) = body.frame().await {
+            if let Some(data) = frame.context("fetch failed")?.data_ref() {
+                bytes.extend_from_slice(data);
+                if bytes.len() > MAX_REMOTE_BYTES {
+                    return Err(anyhow!("playlist too large"));
+                }
+            }
+        }
+        return Ok(bytes);
+    }
+    Err(anyhow!("fetch failed: too many redirects"))
+}
+
+/// Where a `Location` header sends a request for `uri`.
+fn redirect_target(uri: &Uri, target: &str) -> String {
+    let scheme = uri.scheme_str().unwrap_or("http");
+    if target.contains("://") {
+        target.to_string()
+    } else if target.starts_with("//") {
+        format!("{}:{}", scheme, target)
+    } else if target.starts_with('/') {
+        let authority = uri.authority().map_or("", |authority| authority.as_str());
+        format!("{}://{}{}", scheme, authority, target)
+    } else {
+        resolve_location(&uri.to_string(), target)
+    }
+}
+
+pub(crate) fn parse(kind: PlaylistKind, content: &str, base: &str) -> Vec<PlaylistEntry> {
+    let mut entries = match kind {
+        PlaylistKind::M3u => parse_m3u(content),
+        PlaylistKind::Pls => parse_pls(content),
+        PlaylistKind::Asx => parse_asx(content),
+    };
+    for entry in entries.iter_mut() {
+        entry.location = resolve_location(base, &entry.location);
+    }
+    entries
+}
+
+/// Relative entries are relative to the playlist: its directory, or its URL.
+fn resolve_location(base: &str, location: &str) -> String {
+    if let Some(path) = location.strip_prefix("file://") {
+        return path.to_string();
+    }
+    if location.contains("://") || Path::new(location).is_absolute() {
+        return location.to_string();
+    }
+    if is_remote(base) {
+        let dir = base.split(['?', '#']).next().unwrap_or(base);
+        return match dir.rfind('/') {
+            Some(slash) if slash > dir.find("://").map_or(0, |i| i + 2) => {
+                format!("{}/{}", &dir[..slash], location)
+            }
+            _ => format!("{}/{}", dir, location),
+        };
+    }
+    match Path::new(base).parent() {
+        Some(dir) => dir.join(location).to_string_lossy().into_owned(),
+        None => location.to_string(),
+    }
+}
+
+fn parse_m3u(content: &str) -> Vec<PlaylistEntry> {
+    let mut entries = Vec::new();
+    let mut title = None;
+    for line in content.lines() {
+        let line = line.trim().trim_start_matches('\u{feff}');
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            title = info
+                .split_once(',')
+                .map(|(_, t)| t.trim().to_string())
+                .filter(|t| !t.is_empty());
+        } else if !line.starts_with('#') {
+            entries.push(PlaylistEntry {
+                location: line.to_string(),
+                title: title.take(),
+            });
+        }
+    }
+    entries
+}
+
+/// `FileN=` and `TitleN=` pairs, in N order. `NumberOfEntries` is often
+/// wrong, so the keys themselves decide what exists.
+fn parse_pls(content: &str) -> Vec<PlaylistEntry> {
+    let mut files: Vec<(u32, String)> = Vec::new();
+    let mut titles: Vec<(u32, String)> = Vec::new();
+    for line in content.lines() {
+        let Some((key, value)) = line.trim().split_once('=') else {
+            continue;
+        };
+        let key = key.trim().to_ascii_lowercase();
+        let value = value.trim().to_string();
+        if let Some(n) = key.strip_prefix("file").and_then(|n| n.parse().ok()) {
+            files.push((n, value));
+        } else if let Some(n) = key.strip_prefix("title").and_then(|n| n.parse().ok()) {
+            titles.push((n, value));
+        }
+    }
+    files.sort_by_key(|(n, _)| *n);
+    files
+        .into_iter()
+        .filter(|(_, location)| !location.is_empty())
+        .map(|(n, location)| PlaylistEntry {
+            location,
+            title: titles
+                .iter()
+                .find(|(t, _)| *t == n)
+                .map(|(_, title)| title.clone())
+                .filter(|t| !t.is_empty()),
+        })
+        .collect()
+}
+
+/// The first `<ref href>` of each `<entry>` (later ones are fallbacks for
+/// the same stream) and every `<entryref href>`. Tag names are
+/// case-insensitive in ASX, which is looser XML than a parser would take.
+fn parse_asx(content: &str) -> Vec<PlaylistEntry> {
+    let lower = content.to_ascii_lowercase();
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    while let Some(offset) = lower[pos..].find('<') {
+        let start = pos + offset;
+        let tag = &lower[start + 1..];
+        if tag.starts_with("entryref") {
+            if let Some(href) = attribute(content, start, "href") {
+                entries.push(PlaylistEntry { location: href, title: None });
+            }
+        } else if tag.starts_with("entry") {
+            let end = lower[start..].find("</entry").map_or(content.len(), |i| start + i);
+            let block_lower = &lower[start..end];
+            let href = block_lower
+                .find("<ref")
+                .and_then(|i| attribute(content, start + i, "href"));
+            let title = block_lower.find("<title").and_then(|i| {
+                let open = start + i + content[start + i..].find('>')? + 1;
+                let close = open + lower[open..].find("</title")?;
+                Some(unescape_xml(content[open..close].trim())).filter(|t| !t.is_empty())
+            });
+            if let Some(location) = href {
+                entries.push(PlaylistEntry { location, title });
+            }
+            pos = end.max(start + 1);
+            continue;
+        }
+        pos = start + 1;
+    }
+    entries
+}
+
+/// Value of `name` in the tag that opens at `tag_start`.
+fn attribute(content: &str, tag_start: usize, name: &str) -> Option<String> {
+    let tag_end = tag_start + content[tag_start..].find('>')?;
+    let tag = &content[tag_start..tag_end];
+    let lower = tag.to_ascii_lowercase();
+    let mut from = 0;
+    while let Some(i) = lower[from..].find(name) {
+        let after = from + i + name.len();
+        let rest = tag[after..].trim_start();
+        if let Some(rest) = rest.strip_prefix('=') {
+            let rest = rest.trim_start();
+            let quote = rest.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+            let value = &rest[1..];
+            let value = &value[..value.find(quote)?];
+            return Some(unescape_xml(value.trim()));
+        }
+        from = after;
+    }
+    None
+}
+
+fn unescape_xml(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn remote_playlists_follow_redirects_and_stay_small() {
+        use axum::response::Redirect;
+        use axum::routing::get;
+
+        let app = axum::Router::new()
+            .route("/old.pls", get(|| async { Redirect::temporary("/radio.pls") }))
+            .route("/radio.pls", get(|| async { "[playlist]\nFile1=live\n" }))
+            .route("/huge.m3u", get(|| async { "x".repeat(MAX_REMOTE_BYTES + 1) }))
+            .route("/loop.m3u", get(|| async { Redirect::temporary("loop.m3u") }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let bytes = fetch(&format!("{}/old.pls", base)).await.unwrap();
+        assert_eq!(bytes, b"[playlist]\nFile1=live\n");
+        let err = fetch(&format!("{}/huge.m3u", base)).await.unwrap_err();
+        assert_eq!(err.to_string(), "playlist too large");
+        let err = fetch(&format!("{}/loop.m3u", base)).await.unwrap_err();
+        assert_eq!(err.to_string(), "fetch failed: too many redirects");
+        let err = fetch(&format!("{}/missing.m3u", base)).await.unwrap_err();
+        assert_eq!(err.to_string(), "fetch failed: 404 Not Found");
+    }
+
+    #[test]
+    fn pls_entries_follow_their_numbers() {
+        let content = "[playlist]\nNumberOfEntries=1\nFile2=http://b.example/stream\nTitle1=First\n\
+                       File1=http://a.example/stream\nTitle2=\nVersion=2\n";
+        assert_eq!(PlaylistKind::detect("radio", content), Some(PlaylistKind::Pls));
+        let entries = parse(PlaylistKind::Pls, content, "radio.pls");
+        assert_eq!(
+            entries,
+            vec![
+                PlaylistEntry { location: "http://a.example/stream".into(), title: Some("First".into()) },
+                PlaylistEntry { location: "http://b.example/stream".into(), title: None },
+            ]
+        );
+    }
+
+    #[test]
+    fn asx_takes_the_first_ref_per_entry_and_entryrefs() {
+        let content = r#"<ASX version="3.0">
+            <Entry><Title>Morning &amp; Co</Title>
+                <Ref HREF="mms://a.example/live"/><Ref href="http://a.example/live"/>
+            </Entry>
+            <entry><ref href='http://b.example/live' /></entry>
+            <EntryRef href="http://c.example/more.asx"/>
+        </ASX>"#;
+        assert_eq!(PlaylistKind::detect("http://x/get?id=1", content), Some(PlaylistKind::Asx));
+        let entries = parse(PlaylistKind::Asx, content, "list.asx");
+        let locations: Vec<&str> = entries.iter().map(|e| e.location.as_str()).collect();
+        assert_eq!(
+            locations,
+            vec!["mms://a.example/live", "http://b.example/live", "http://c.example/more.asx"]
+        );
+        assert_eq!(entries[0].title.as_deref(), Some("Morning & Co"));
+        assert!(is_playlist_location(&entries[2].location));
+    }
+
+    #[test]
+    fn m3u_titles_and_relative_locations() {
+        let content = "#EXTM3U\n#EXTINF:123,Artist - Song\nsongs/a.flac\n\n# comment\n/abs/b.mp3\n";
+        let entries = parse(PlaylistKind::M3u, content, "/music/list.m3u");
+        assert_eq!(entries[0].title.as_deref(), Some("Artist - Song"));
+        assert_eq!(Path::new(&entries[0].location), Path::new("/music/songs/a.flac"));
+        assert_eq!(entries[1], PlaylistEntry { location: "/abs/b.mp3".into(), title: None });
+
+        let remote = parse(PlaylistKind::M3u, "more.pls\n", "https://radio.example/lists/top.m3u?x=1");
+        assert_eq!(remote[0].location, "https://radio.example/lists/more.pls");
+        assert!(is_playlist_location("https://radio.example/lists/more.pls?session=2"));
+        assert_eq!(PlaylistKind::detect("stream", "ICY 200 OK"), None);
+    }
+}
@@ -0,0 +1,164 @@
+//! On-disk cache of library scan results.
+//!
+//! Entries are keyed by path and carry the file's size and modification
+//! time when it was probed; a rescan reuses an entry only while both still
+//! match, so only new or changed files are probed again.
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::LibraryTrack;
+
+/// Bumped when `LibraryTrack` gains fields a probe fills in, so older
+/// caches are dropped rather than served without them.
+const CACHE_VERSION: u32 = 3;
+
+/// What a cached entry is checked against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct FileStamp {
+    pub size: u64,
+    /// Nanoseconds since the Unix epoch.
+    pub modified_ns: u64,
+}
+
+impl FileStamp {
+    pub(crate) fn of(path: &Path) -> Option<Self> {
+        let meta = std::fs::metadata(path).ok()?;
+        let modified = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(FileStamp {
+            size: meta.len(),
+            modified_ns: modified.as_nanos() as u64,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedTrack {
+    stamp: FileStamp,
+    track: LibraryTrack,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct ScanCache {
+    version: u32,
+    entries: HashMap<String, CachedTrack>,
+    #[serde(skip)]
+    dirty: bool,
+}
+
+impl ScanCache {
+    /// A missing, unreadable or outdated cache starts empty.
+    pub(crate) fn load(path: &Path) -> Self {
+        let Ok(text) = std::fs::read_to_string(path) else {
+            return Self::default();
+        };
+        match serde_json::from_str::<ScanCache>(&text) {
+            Ok(cache) if cache.version == CACHE_VERSION => cache,
+            Ok(_) => Self::default(),
+            Err(err) => {
+                warn!("ignoring unreadable scan cache at {}: {}", path.display(), err);
+                Self::default()
+            }
+        }
+    }
+
+    /// Write the cache if anything changed since it was loaded.
+    pub(crate) fn save(&mut self, path: &Path) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).context("create scan cache dir")?;
+        }
+        self.version = CACHE_VERSION;
+        let temp = path.with_extension("json.tmp");
+        std::fs::write(&temp, serde_json::to_vec(self)?).context("write scan cache")?;
+        std::fs::rename(&temp, path).context("replace scan cache")?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// The cached track for `path` if the file is unchanged since; a stale
+    /// entry is dropped.
+    pub(crate) fn get(&mut self, path: &str, stamp: FileStamp) -> Option<LibraryTrack> {
+        match self.entries.get(path) {
+            Some(entry) if entry.stamp == stamp => Some(entry.track.clone()),
+            Some(_) => {
+                self.entries.remove(path);
+                self.dirty = true;
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub(crate) fn insert(&mut self, path: String, stamp: FileStamp, track: LibraryTrack) {
+        self.entries.insert(path, CachedTrack { stamp, track });
+        self.dirty = true;
+    }
+
+    /// Drop entries under `root` other than `keep`: after a full scan,
+    /// files that are gone; with nothing kept, a forced rescan.
+    pub(crate) fn forget_under(&mut self, root: &Path, keep: &HashSet<String>) {
+        let before = self.entries.len();
+        self.entries
+            .retain(|path, _| keep.contains(path) || !Path::new(path).starts_with(root));
+        self.dirty |= self.entries.len() != before;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(path: &str, title: &str) -> LibraryTrack {
+        serde_json::from_value(serde_json::json!({ "path": path, "title": title, "duration": 1.0 })).unwrap()
+    }
+
+    fn stamp(size: u64, modified_ns: u64) -> FileStamp {
+        FileStamp { size, modified_ns }
+    }
+
+    #[test]
+    fn changed_files_miss_and_drop_their_entry() {
+        let mut cache = ScanCache::default();
+        cache.insert("/music/a.flac".into(), stamp(10, 100), track("/music/a.flac", "A"));
+        assert_eq!(cache.get("/music/a.flac", stamp(10, 100)).unwrap().title.as_deref(), Some("A"));
+        // Touched, then rewritten at the same size.
+        assert!(cache.get("/music/a.flac", stamp(10, 200)).is_none());
+        assert!(cache.entries.is_empty());
+        cache.insert("/music/a.flac".into(), stamp(10, 100), track("/music/a.flac", "A"));
+        assert!(cache.get("/music/a.flac", stamp(11, 100)).is_none());
+        assert!(cache.get("/music/b.flac", stamp(10, 100)).is_none());
+    }
+
+    #[test]
+    fn round_trips_and_forgets_under_a_root() {
+        let path = std::env::temp_dir().join(format!("ntmusic_scan_cache_test_{}.json", std::process::id()));
+        let mut cache = ScanCache::default();
+        cache.insert("/music/a/1.flac".into(), stamp(1, 1), track("/music/a/1.flac", "1"));
+        cache.insert("/music/a/2.flac".into(), stamp(2, 2), track("/music/a/2.flac", "2"));
+        cache.insert("/music/ab/3.flac".into(), stamp(3, 3), track("/music/ab/3.flac", "3"));
+        cache.save(&path).unwrap();
+
+        let mut loaded = ScanCache::load(&path);
+        assert_eq!(loaded.entries.len(), 3);
+        let keep: HashSet<String> = ["/music/a/1.flac".to_string()].into();
+        loaded.forget_under(Path::new("/music/a"), &keep);
+        assert!(loaded.get("/music/a/1.flac", stamp(1, 1)).is_some());
+        assert!(loaded.get("/music/a/2.flac", stamp(2, 2)).is_none());
+        // A sibling directory sharing the prefix is not under the root.
+        assert!(loaded.get("/music/ab/3.flac", stamp(3, 3)).is_some());
+
+        std::fs::write(&path, br#"{"version":0,"entries":{}}"#).unwrap();
+        assert!(ScanCache::load(&path).entries.is_empty());
+        std::fs::write(&path, b"not json").unwrap();
+        assert!(ScanCache::load(&path).entries.is_empty());
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,193 @@
+//! User settings kept across restarts.
+//!
+//! Covers the output device, EQ curve, dither, upsampling target and
+//! volume. The file is rewritten shortly after one of those changes; a
+//! missing or unreadable one starts from the defaults, and fields it lacks
+//! keep theirs.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{
+    eq::{self, EqKind},
+    initial_state, normalize_dither_bits, normalize_dither_type, normalize_exclusive_format,
+    normalize_volume_mode, refresh_eq_filters, set_volume_target, EngineState,
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct PersistedSettings {
+    pub hostapi: Option<String>,
+    /// Host and name of the chosen device; ids are places in the device
+    /// list and mean nothing in the next run.
+    pub device: Option<(String, String)>,
+    pub exclusive_mode: bool,
+    pub exclusive_format: String,
+    pub output_latency_ms: Option<u32>,
+    pub volume_mode: String,
+    pub eq_enabled: bool,
+    /// `IIR` or `FIR`.
+    pub eq_type: String,
+    pub eq_bands: HashMap<String, f32>,
+    pub dither_enabled: bool,
+    pub dither_type: String,
+    pub dither_bits: u32,
+    pub target_samplerate: Option<u32>,
+    pub volume: f32,
+}
+
+impl Default for PersistedSettings {
+    fn default() -> Self {
+        Self::from_state(&initial_state())
+    }
+}
+
+impl PersistedSettings {
+    pub(crate) fn from_state(state: &EngineState) -> Self {
+        PersistedSettings {
+            hostapi: state.hostapi.clone(),
+            device: state.selected_device.clone(),
+            exclusive_mode: state.exclusive_mode,
+            exclusive_format: state.exclusive_format.clone(),
+            output_latency_ms: state.output_latency_ms,
+            volume_mode: state.volume_mode.clone(),
+            eq_enabled: state.eq_enabled,
+            eq_type: state.eq_type.clone(),
+            eq_bands: state.eq_bands.clone(),
+            dither_enabled: state.dither_enabled,
+            dither_type: state.dither_type.clone(),
+            dither_bits: state.dither_bits,
+            target_samplerate: state.target_samplerate,
+            volume: state.volume,
+        }
+    }
+
+    /// Put the settings into a fresh `state`, normalized as the endpoints
+    /// that set them would. `devices` is the current device list, host and
+    /// name in id order; a saved device that isn't in it is left selected
+    /// for the device watcher to switch back to once it's plugged in.
+    pub(crate) fn apply(self, state: &mut EngineState, devices: &[(String, String)]) {
+        state.hostapi = self.hostapi;
+        state.device_id = self
+            .device
+            .as_ref()
+            .and_then(|device| devices.iter().position(|key| key == device));
+        state.device_fallback = self.device.is_some() && state.device_id.is_none();
+        state.selected_device = self.device;
+        state.exclusive_mode = self.exclusive_mode;
+        state.exclusive_format = normalize_exclusive_format(&self.exclusive_format);
+        state.output_latency_ms = self.output_latency_ms.filter(|ms| *ms > 0);
+        state.volume_mode = normalize_volume_mode(&self.volume_mode);
+        state.eq_enabled = self.eq_enabled;
+        for (band, gain) in self.eq_bands {
+            if let Some(entry) = state.eq_bands.get_mut(&band).filter(|_| gain.is_finite()) {
+                *entry = gain.clamp(-eq::EQ_MAX_GAIN_DB, eq::EQ_MAX_GAIN_DB);
+            }
+        }
+        if let Some(kind) = EqKind::from_label(&self.eq_type) {
+            state.eq_type = kind.label().to_string();
+            let fir_taps = state.eq_filters.fir_taps();
+            state.eq_filters.set_kind(kind, fir_taps);
+        }
+        refresh_eq_filters(state);
+        state.dither_type = normalize_dither_type(&self.dither_type);
+        state.dither_bits = normalize_dither_bits(self.dither_bits);
+        state.dither_enabled = self.dither_enabled && state.dither_type != "off";
+        state.target_samplerate = self.target_samplerate.filter(|rate| *rate > 0);
+        if self.volume.is_finite() {
+            set_volume_target(state, self.volume);
+        }
+    }
+
+    /// A missing or unreadable file gives the defaults.
+    pub(crate) fn load(path: &Path) -> Self {
+        let Ok(text) = std::fs::read_to_string(path) else {
+            return Self::default();
+        };
+        match serde_json::from_str::<PersistedSettings>(&text) {
+            Ok(settings) => settings,
+            Err(err) => {
+                warn!("ignoring unreadable settings at {}: {}", path.display(), err);
+                Self::default()
+            }
+        }
+    }
+
+    pub(crate) fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).context("create settings dir")?;
+        }
+        // Unique, so two writers never rename each other's half-written file.
+        let temp = path.with_extension(format!("json.{}.tmp", uuid::Uuid::new_v4().simple()));
+        std::fs::write(&temp, serde_json::to_vec_pretty(self)?).context("write settings")?;
+        std::fs::rename(&temp, path).context("replace settings")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(name: &str) -> (String, String) {
+        ("Wasapi".to_string(), name.to_string())
+    }
+
+    #[test]
+    fn settings_round_trip_and_find_the_device_by_name() {
+        let mut state = initial_state();
+        state.selected_device = Some(key("DAC"));
+        state.eq_enabled = true;
+        state.eq_bands.insert("1k".to_string(), 4.5);
+        state.eq_type = "FIR".to_string();
+        state.dither_type = "tpdf_ns2".to_string();
+        state.dither_bits = 16;
+        state.target_samplerate = Some(96_000);
+        state.volume = 0.4;
+        let path = std::env::temp_dir().join(format!("ntmusic_settings_test_{}.json", std::process::id()));
+        PersistedSettings::from_state(&state).save(&path).unwrap();
+        let loaded = PersistedSettings::load(&path);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(loaded, PersistedSettings::from_state(&state));
+
+        let mut restored = initial_state();
+        loaded.clone().apply(&mut restored, &[key("Speakers"), key("DAC")]);
+        assert_eq!(restored.device_id, Some(1));
+        assert!(!restored.device_fallback);
+        assert_eq!(restored.eq_bands.get("1k"), Some(&4.5));
+        assert_eq!(restored.eq_type, "FIR");
+        assert_eq!(restored.eq_filters.kind(), EqKind::Fir);
+        assert_eq!(restored.dither_bits, 16);
+        assert_eq!(restored.target_samplerate, Some(96_000));
+        assert_eq!(restored.volume, 0.4);
+        assert_eq!(restored.volume_current, 0.4);
+
+        // Unplugged since: play on the default, still waiting for it.
+        let mut restored = initial_state();
+        loaded.apply(&mut restored, &[key("Speakers")]);
+        assert_eq!(restored.device_id, None);
+        assert!(restored.device_fallback);
+        assert_eq!(restored.selected_device, Some(key("DAC")));
+    }
+
+    #[test]
+    fn corrupt_or_partial_files_fall_back_to_defaults() {
+        let path = std::env::temp_dir().join(format!("ntmusic_settings_bad_{}.json", std::process::id()));
+        std::fs::write(&path, b"{ not json").unwrap();
+        assert_eq!(PersistedSettings::load(&path), PersistedSettings::default());
+        std::fs::write(&path, br#"{ "volume": 0.5, "eq_bands": { "1k": 99.0, "nope": 3.0 } }"#).unwrap();
+        let partial = PersistedSettings::load(&path);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(partial.volume, 0.5);
+        assert_eq!(partial.dither_bits, PersistedSettings::default().dither_bits);
+        let mut state = initial_state();
+        partial.apply(&mut state, &[]);
+        assert_eq!(state.eq_bands.get("1k"), Some(&eq::EQ_MAX_GAIN_DB));
+        assert!(!state.eq_bands.contains_key("nope"));
+        assert_eq!(PersistedSettings::load(&path.with_extension("missing")), PersistedSettings::default());
+    }
+}
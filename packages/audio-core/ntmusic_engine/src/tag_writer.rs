@@ -0,0 +1,1797 @@
+//! Writes tags and cover art back to audio files.
+//!
+//! MP3, WAV and AIFF carry ID3v2 tags (written as v2.4 through the `id3`
+//! crate); FLAC metadata blocks, the Vorbis comments of Ogg Vorbis and Opus
+//! streams and the `ilst` atoms of MP4 files are rewritten here directly.
+//! Other formats, raw AAC among them, fail with "not supported". Every write
+//! goes to a temporary file next to the original which then replaces it, so a
+//! failed write never leaves a half-written file behind.
+
+use anyhow::{anyhow, Context, Result};
+use id3::TagLike;
+use serde::Deserialize;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// Fields to change. `None` leaves a field untouched; an empty string (or a
+/// zero track/year) removes it.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub(crate) struct TagUpdate {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub track: Option<u32>,
+    pub genre: Option<String>,
+    pub year: Option<i32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TagFormat {
+    Mp3,
+    Wav,
+    Aiff,
+    Flac,
+    Ogg,
+    Mp4,
+}
+
+fn tag_format(path: &Path) -> Result<TagFormat> {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    match ext.as_str() {
+        "mp3" => Ok(TagFormat::Mp3),
+        "wav" => Ok(TagFormat::Wav),
+        "aiff" | "aif" => Ok(TagFormat::Aiff),
+        "flac" => Ok(TagFormat::Flac),
+        "ogg" | "oga" | "opus" => Ok(TagFormat::Ogg),
+        "m4a" | "m4b" | "mp4" => Ok(TagFormat::Mp4),
+        _ => Err(anyhow!("tag writing is not supported for .{} files", ext)),
+    }
+}
+
+/// Unique, so two writes to the same file never rename each other's
+/// half-written copy.
+fn temp_path_for(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    path.with_file_name(format!(
+        ".{}.{}.ntmusic-tmp",
+        name,
+        uuid::Uuid::new_v4().simple()
+    ))
+}
+
+/// The rewritten file takes the original's permissions, not the defaults a
+/// new file gets.
+fn copy_permissions(from: &Path, to: &Path) -> Result<()> {
+    let permissions = std::fs::metadata(from)
+        .context("read original permissions")?
+        .permissions();
+    std::fs::set_permissions(to, permissions).context("set temp file permissions")
+}
+
+fn replace_atomically(path: &Path, write: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+    let tmp = temp_path_for(path);
+    let result = write(&tmp)
+        .and_then(|_| copy_permissions(path, &tmp))
+        .and_then(|_| std::fs::rename(&tmp, path).context("replace original file"));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    result
+}
+
+pub(crate) fn write_tags(path: &Path, update: &TagUpdate) -> Result<()> {
+    let format = tag_format(path)?;
+    if !path.is_file() {
+        return Err(anyhow!("file not found"));
+    }
+    match format {
+        TagFormat::Flac => {
+            let mut file = FlacFile::read(path)?;
+            file.update_comments(update)?;
+            replace_atomically(path, |tmp| file.write(tmp))
+        }
+        TagFormat::Ogg => {
+            let mut file = OggFile::read(path)?;
+            file.edit_comments(|comments| comments.apply(update))?;
+            replace_atomically(path, |tmp| file.write(tmp))
+        }
+        TagFormat::Mp4 => {
+            let mut file = Mp4File::read(path)?;
+            file.edit_items(|items| apply_mp4_update(items, update))?;
+            replace_atomically(path, |tmp| file.write(tmp))
+        }
+        TagFormat::Mp3 | TagFormat::Wav | TagFormat::Aiff => {
+            edit_id3(path, |tag| apply_id3_update(tag, update))
+        }
+    }
+}
+
+// `id3` sniffs the container itself, so MP3, WAV and AIFF share one path.
+fn edit_id3(path: &Path, edit: impl FnOnce(&mut id3::Tag)) -> Result<()> {
+    // Anything other than "no tag yet" means an existing tag we'd clobber.
+    let mut tag = id3::no_tag_ok(id3::Tag::read_from_path(path))
+        .context("read existing ID3 tag")?
+        .unwrap_or_default();
+    edit(&mut tag);
+    replace_atomically(path, |tmp| {
+        std::fs::copy(path, tmp).context("copy to temp file")?;
+        tag.write_to_path(tmp, id3::Version::Id3v24)
+            .context("write ID3 tag")
+    })
+}
+
+/// Largest cover image accepted for embedding.
+pub(crate) const MAX_COVER_BYTES: usize = 10 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CoverImage {
+    pub mime_type: &'static str,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Identify a JPEG or PNG by its signature and read its dimensions.
+pub(crate) fn inspect_cover_image(data: &[u8]) -> Result<CoverImage> {
+    if data.is_empty() {
+        return Err(anyhow!("cover image is empty"));
+    }
+    if data.len() > MAX_COVER_BYTES {
+        return Err(anyhow!(
+            "cover image is {} bytes, limit is {}",
+            data.len(),
+            MAX_COVER_BYTES
+        ));
+    }
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        // IHDR is always the first chunk: width and height follow its tag.
+        let ihdr = data
+            .get(16..24)
+            .ok_or_else(|| anyhow!("truncated PNG header"))?;
+        return Ok(CoverImage {
+            mime_type: "image/png",
+            width: u32::from_be_bytes([ihdr[0], ihdr[1], ihdr[2], ihdr[3]]),
+            height: u32::from_be_bytes([ihdr[4], ihdr[5], ihdr[6], ihdr[7]]),
+        });
+    }
+    if data.starts_with(&[0xff, 0xd8, 0xff]) {
+        let (width, height) = jpeg_dimensions(data).ok_or_else(|| anyhow!("invalid JPEG image"))?;
+        return Ok(CoverImage {
+            mime_type: "image/jpeg",
+            width,
+            height,
+        });
+    }
+    Err(anyhow!(
+        "unsupported cover image format, expected JPEG or PNG"
+    ))
+}
+
+fn jpeg_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let mut offset = 2usize;
+    while offset + 4 <= data.len() {
+        if data[offset] != 0xff {
+            return None;
+        }
+        let marker = data[offset + 1];
+        if marker == 0xff {
+            offset += 1;
+            continue;
+        }
+        let len = u16::from_be_bytes([data[offset + 2], data[offset + 3]]) as usize;
+        // SOF0..SOF15 carry the frame size; C4/C8/CC are DHT/JPG/DAC.
+        if (0xc0..=0xcf).contains(&marker) && !matches!(marker, 0xc4 | 0xc8 | 0xcc) {
+            let sof = data.get(offset + 5..offset + 9)?;
+            let height = u16::from_be_bytes([sof[0], sof[1]]) as u32;
+            let width = u16::from_be_bytes([sof[2], sof[3]]) as u32;
+            return Some((width, height));
+        }
+        offset += 2 + len;
+    }
+    None
+}
+
+/// Embed `image` as the front cover. With `remove_existing` every picture
+/// already in the file is dropped; otherwise only a previous front cover is.
+/// MP4 pictures have no type, so there the first one counts as the front
+/// cover.
+pub(crate) fn write_cover(path: &Path, image: &[u8], remove_existing: bool) -> Result<()> {
+    let format = tag_format(path)?;
+    if !path.is_file() {
+        return Err(anyhow!("file not found"));
+    }
+    let info = inspect_cover_image(image)?;
+    match format {
+        TagFormat::Flac => {
+            let mut file = FlacFile::read(path)?;
+            file.set_front_cover(image, &info, remove_existing);
+            replace_atomically(path, |tmp| file.write(tmp))
+        }
+        TagFormat::Mp3 | TagFormat::Wav | TagFormat::Aiff => edit_id3(path, |tag| {
+            if remove_existing {
+                tag.remove_all_pictures();
+            } else {
+                tag.remove_picture_by_type(id3::frame::PictureType::CoverFront);
+            }
+            tag.add_frame(id3::frame::Picture {
+                mime_type: info.mime_type.to_string(),
+                picture_type: id3::frame::PictureType::CoverFront,
+                description: String::new(),
+                data: image.to_vec(),
+            });
+        }),
+        TagFormat::Ogg => {
+            let mut file = OggFile::read(path)?;
+            file.edit_comments(|comments| comments.set_front_cover(image, &info, remove_existing))?;
+            replace_atomically(path, |tmp| file.write(tmp))
+        }
+        TagFormat::Mp4 => {
+            let mut file = Mp4File::read(path)?;
+            file.edit_items(|items| set_mp4_front_cover(items, image, &info, remove_existing))?;
+            replace_atomically(path, |tmp| file.write(tmp))
+        }
+    }
+}
+
+fn apply_id3_update(tag: &mut id3::Tag, update: &TagUpdate) {
+    if let Some(value) = &update.title {
+        if value.is_empty() {
+            tag.remove_title();
+        } else {
+            tag.set_title(value.as_str());
+        }
+    }
+    if let Some(value) = &update.artist {
+        if value.is_empty() {
+            tag.remove_artist();
+        } else {
+            tag.set_artist(value.as_str());
+        }
+    }
+    if let Some(value) = &update.album {
+        if value.is_empty() {
+            tag.remove_album();
+        } else {
+            tag.set_album(value.as_str());
+        }
+    }
+    if let Some(value) = &update.genre {
+        if value.is_empty() {
+            tag.remove_genre();
+        } else {
+            tag.set_genre(value.as_str());
+        }
+    }
+    match update.track {
+        Some(0) => tag.remove_track(),
+        Some(track) => tag.set_track(track),
+        None => {}
+    }
+    match update.year {
+        Some(0) => tag.remove_year(),
+        Some(year) => tag.set_year(year),
+        None => {}
+    }
+}
+
+const FLAC_MAGIC: &[u8; 4] = b"fLaC";
+const FLAC_BLOCK_VORBIS_COMMENT: u8 = 4;
+const FLAC_BLOCK_PICTURE: u8 = 6;
+const FLAC_PICTURE_FRONT_COVER: u32 = 3;
+const FLAC_MAX_BLOCK_LEN: usize = (1 << 24) - 1;
+
+struct FlacBlock {
+    kind: u8,
+    data: Vec<u8>,
+}
+
+/// A FLAC file split into its metadata blocks and the untouched audio frames.
+struct FlacFile {
+    blocks: Vec<FlacBlock>,
+    audio: Vec<u8>,
+}
+
+impl FlacFile {
+    fn read(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path).with_context(|| format!("read {:?}", path))?;
+        Self::parse(bytes)
+    }
+
+    fn parse(bytes: Vec<u8>) -> Result<Self> {
+        if bytes.len() < 4 || &bytes[..4] != FLAC_MAGIC {
+            return Err(anyhow!("not a FLAC file"));
+        }
+        let mut blocks = Vec::new();
+        let mut offset = 4usize;
+        loop {
+            let header = bytes
+                .get(offset..offset + 4)
+                .ok_or_else(|| anyhow!("truncated FLAC metadata"))?;
+            let last = header[0] & 0x80 != 0;
+            let kind = header[0] & 0x7f;
+            let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+            let data = bytes
+                .get(offset + 4..offset + 4 + len)
+                .ok_or_else(|| anyhow!("truncated FLAC metadata"))?
+                .to_vec();
+            blocks.push(FlacBlock { kind, data });
+            offset += 4 + len;
+            if last {
+                break;
+            }
+        }
+        Ok(FlacFile {
+            blocks,
+            audio: bytes[offset..].to_vec(),
+        })
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(self.audio.len() + 8192);
+        out.extend_from_slice(FLAC_MAGIC);
+        for (idx, block) in self.blocks.iter().enumerate() {
+            if block.data.len() > FLAC_MAX_BLOCK_LEN {
+                return Err(anyhow!("FLAC metadata block too large"));
+            }
+            let last = idx + 1 == self.blocks.len();
+            let len = (block.data.len() as u32).to_be_bytes();
+            out.push(block.kind | if last { 0x80 } else { 0 });
+            out.extend_from_slice(&len[1..]);
+            out.extend_from_slice(&block.data);
+        }
+        out.extend_from_slice(&self.audio);
+        Ok(out)
+    }
+
+    fn write(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, self.to_bytes()?).context("write FLAC file")
+    }
+
+    fn set_front_cover(&mut self, image: &[u8], info: &CoverImage, remove_existing: bool) {
+        self.blocks.retain(|block| {
+            if block.kind != FLAC_BLOCK_PICTURE {
+                return true;
+            }
+            let picture_type = flac_picture_type(&block.data);
+            !remove_existing && picture_type != Some(FLAC_PICTURE_FRONT_COVER)
+        });
+        self.blocks.push(FlacBlock {
+            kind: FLAC_BLOCK_PICTURE,
+            data: flac_front_cover(image, info),
+        });
+    }
+
+    fn update_comments(&mut self, update: &TagUpdate) -> Result<()> {
+        let existing = self
+            .blocks
+            .iter()
+            .position(|block| block.kind == FLAC_BLOCK_VORBIS_COMMENT);
+        let mut comments = match existing {
+            Some(idx) => VorbisComments::parse(&self.blocks[idx].data)?,
+            None => VorbisComments {
+                vendor: "NTmusic".to_string(),
+                entries: Vec::new(),
+            },
+        };
+        comments.apply(update);
+        let data = comments.to_bytes();
+        match existing {
+            Some(idx) => self.blocks[idx].data = data,
+            // STREAMINFO must stay first; put new comments right after it.
+            None => self.blocks.insert(
+                1.min(self.blocks.len()),
+                FlacBlock {
+                    kind: FLAC_BLOCK_VORBIS_COMMENT,
+                    data,
+                },
+            ),
+        }
+        Ok(())
+    }
+}
+
+/// A PICTURE block holding `image` as the front cover. Ogg streams embed
+/// the same block, base64-encoded, as a comment.
+fn flac_front_cover(image: &[u8], info: &CoverImage) -> Vec<u8> {
+    let mut data = Vec::with_capacity(image.len() + 64);
+    let mime = info.mime_type.as_bytes();
+    data.extend_from_slice(&FLAC_PICTURE_FRONT_COVER.to_be_bytes());
+    data.extend_from_slice(&(mime.len() as u32).to_be_bytes());
+    data.extend_from_slice(mime);
+    data.extend_from_slice(&0u32.to_be_bytes()); // description length
+    data.extend_from_slice(&info.width.to_be_bytes());
+    data.extend_from_slice(&info.height.to_be_bytes());
+    data.extend_from_slice(&0u32.to_be_bytes()); // colour depth, unknown
+    data.extend_from_slice(&0u32.to_be_bytes()); // palette size, non-indexed
+    data.extend_from_slice(&(image.len() as u32).to_be_bytes());
+    data.extend_from_slice(image);
+    data
+}
+
+fn flac_picture_type(block: &[u8]) -> Option<u32> {
+    block
+        .get(..4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// Comment key for a picture in Vorbis comments outside FLAC.
+const VORBIS_PICTURE_KEY: &str = "METADATA_BLOCK_PICTURE";
+
+struct VorbisComments {
+    vendor: String,
+    entries: Vec<String>,
+}
+
+impl VorbisComments {
+    fn parse(data: &[u8]) -> Result<Self> {
+        Self::parse_prefix(data).map(|(comments, _)| comments)
+    }
+
+    /// Comments at the start of `data`, and how many bytes they took.
+    fn parse_prefix(data: &[u8]) -> Result<(Self, usize)> {
+        let mut offset = 0usize;
+        let vendor = read_le_string(data, &mut offset)?;
+        let count = read_le_u32(data, &mut offset)?;
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            entries.push(read_le_string(data, &mut offset)?);
+        }
+        Ok((VorbisComments { vendor, entries }, offset))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&(self.vendor.len() as u32).to_le_bytes());
+        out.extend_from_slice(self.vendor.as_bytes());
+        out.extend_from_slice(&(self.entries.len() as u32).to_le_bytes());
+        for entry in &self.entries {
+            out.extend_from_slice(&(entry.len() as u32).to_le_bytes());
+            out.extend_from_slice(entry.as_bytes());
+        }
+        out
+    }
+
+    fn set(&mut self, key: &str, value: Option<String>) {
+        let Some(value) = value else {
+            return;
+        };
+        self.entries.retain(|entry| {
+            entry
+                .split_once('=')
+                .map(|(k, _)| !k.eq_ignore_ascii_case(key))
+                .unwrap_or(true)
+        });
+        if !value.is_empty() {
+            self.entries.push(format!("{}={}", key, value));
+        }
+    }
+
+    /// Like `FlacFile::set_front_cover`, for the base64 picture comments
+    /// of Ogg streams.
+    fn set_front_cover(&mut self, image: &[u8], info: &CoverImage, remove_existing: bool) {
+        use base64::Engine as _;
+        let base64 = base64::engine::general_purpose::STANDARD;
+        self.entries.retain(|entry| match entry.split_once('=') {
+            Some((key, value)) if key.eq_ignore_ascii_case(VORBIS_PICTURE_KEY) => {
+                let block = base64.decode(value).unwrap_or_default();
+                !remove_existing && flac_picture_type(&block) != Some(FLAC_PICTURE_FRONT_COVER)
+            }
+            _ => true,
+        });
+        let block = base64.encode(flac_front_cover(image, info));
+        self.entries
+            .push(format!("{}={}", VORBIS_PICTURE_KEY, block));
+    }
+
+    fn apply(&mut self, update: &TagUpdate) {
+        let number =
+            |value: Option<i64>| value.map(|v| if v == 0 { String::new() } else { v.to_string() });
+        self.set("TITLE", update.title.clone());
+        self.set("ARTIST", update.artist.clone());
+        self.set("ALBUM", update.album.clone());
+        self.set("GENRE", update.genre.clone());
+        self.set("TRACKNUMBER", number(update.track.map(i64::from)));
+        self.set("DATE", number(update.year.map(i64::from)));
+    }
+}
+
+fn read_le_u32(data: &[u8], offset: &mut usize) -> Result<u32> {
+    let bytes = data
+        .get(*offset..*offset + 4)
+        .ok_or_else(|| anyhow!("truncated Vorbis comment"))?;
+    *offset += 4;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read_le_string(data: &[u8], offset: &mut usize) -> Result<String> {
+    let len = read_le_u32(data, offset)? as usize;
+    let bytes = data
+        .get(*offset..*offset + len)
+        .ok_or_else(|| anyhow!("truncated Vorbis comment"))?;
+    *offset += len;
+    Ok(String::from_utf8_lossy(bytes).into_owned())
+}
+
+const OGG_CAPTURE: &[u8; 4] = b"OggS";
+const OGG_PAGE_HEADER_LEN: usize = 27;
+const OGG_CONTINUED: u8 = 0x01;
+const OGG_FIRST_PAGE: u8 = 0x02;
+const OGG_MAX_SEGMENTS: usize = 255;
+/// Granule position of a page on which no packet ends.
+const OGG_NO_GRANULE: u64 = u64::MAX;
+
+const OGG_CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut idx = 0;
+    while idx < 256 {
+        let mut crc = (idx as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[idx] = crc;
+        idx += 1;
+    }
+    table
+};
+
+/// The page checksum: CRC-32 with polynomial 0x04c11db7, unreflected, over
+/// the page with its checksum field zeroed.
+fn ogg_crc(data: &[u8]) -> u32 {
+    data.iter().fold(0, |crc, &byte| {
+        (crc << 8) ^ OGG_CRC_TABLE[((crc >> 24) as u8 ^ byte) as usize]
+    })
+}
+
+struct OggPage {
+    header_type: u8,
+    granule: u64,
+    serial: u32,
+    sequence: u32,
+    segments: Vec<u8>,
+    body: Vec<u8>,
+}
+
+impl OggPage {
+    fn empty(serial: u32, sequence: u32, header_type: u8) -> Self {
+        OggPage {
+            header_type,
+            granule: OGG_NO_GRANULE,
+            serial,
+            sequence,
+            segments: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    /// The page at `offset`, and the offset just past it.
+    fn parse(bytes: &[u8], offset: usize) -> Result<(Self, usize)> {
+        let header = bytes
+            .get(offset..offset + OGG_PAGE_HEADER_LEN)
+            .ok_or_else(|| anyhow!("truncated Ogg page"))?;
+        if &header[..4] != OGG_CAPTURE || header[4] != 0 {
+            return Err(anyhow!("not an Ogg file"));
+        }
+        let le_u32 = |at: usize| {
+            u32::from_le_bytes([header[at], header[at + 1], header[at + 2], header[at + 3]])
+        };
+        let (serial, sequence, crc) = (le_u32(14), le_u32(18), le_u32(22));
+        let mut granule = [0u8; 8];
+        granule.copy_from_slice(&header[6..14]);
+        let body_start = offset + OGG_PAGE_HEADER_LEN + header[26] as usize;
+        let segments = bytes
+            .get(offset + OGG_PAGE_HEADER_LEN..body_start)
+            .ok_or_else(|| anyhow!("truncated Ogg page"))?
+            .to_vec();
+        let end = body_start + segments.iter().map(|&len| len as usize).sum::<usize>();
+        let body = bytes
+            .get(body_start..end)
+            .ok_or_else(|| anyhow!("truncated Ogg page"))?
+            .to_vec();
+        let mut raw = bytes[offset..end].to_vec();
+        raw[22..26].fill(0);
+        if ogg_crc(&raw) != crc {
+            return Err(anyhow!("Ogg page {} fails its checksum", sequence));
+        }
+        let page = OggPage {
+            header_type: header[5],
+            granule: u64::from_le_bytes(granule),
+            serial,
+            sequence,
+            segments,
+            body,
+        };
+        Ok((page, end))
+    }
+
+    fn write_to(&self, out: &mut Vec<u8>, sequence: u32) {
+        let start = out.len();
+        out.extend_from_slice(OGG_CAPTURE);
+        out.push(0);
+        out.push(self.header_type);
+        out.extend_from_slice(&self.granule.to_le_bytes());
+        out.extend_from_slice(&self.serial.to_le_bytes());
+        out.extend_from_slice(&sequence.to_le_bytes());
+        out.extend_from_slice(&[0; 4]);
+        out.push(self.segments.len() as u8);
+        out.extend_from_slice(&self.segments);
+        out.extend_from_slice(&self.body);
+        let crc = ogg_crc(&out[start..]);
+        out[start + 22..start + 26].copy_from_slice(&crc.to_le_bytes());
+    }
+}
+
+/// Lay `packets` out on pages of stream `serial`, numbered from
+/// `first_sequence`. Every packet starts on a fresh segment and the last
+/// one ends its page, as header packets must.
+fn paginate_ogg(packets: &[Vec<u8>], serial: u32, first_sequence: u32) -> Vec<OggPage> {
+    let mut pages = Vec::new();
+    let mut page = OggPage::empty(serial, first_sequence, 0);
+    for packet in packets {
+        let mut offset = 0usize;
+        let mut started = false;
+        loop {
+            if page.segments.len() == OGG_MAX_SEGMENTS {
+                let header_type = if started { OGG_CONTINUED } else { 0 };
+                let next = OggPage::empty(serial, page.sequence.wrapping_add(1), header_type);
+                pages.push(std::mem::replace(&mut page, next));
+            }
+            // A packet is 255-byte segments ended by a shorter, maybe empty, one.
+            let len = (packet.len() - offset).min(255);
+            page.segments.push(len as u8);
+            page.body.extend_from_slice(&packet[offset..offset + len]);
+            offset += len;
+            started = true;
+            if len < 255 {
+                page.granule = 0;
+                break;
+            }
+        }
+    }
+    pages.push(page);
+    pages
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OggCodec {
+    Vorbis,
+    Opus,
+}
+
+impl OggCodec {
+    fn detect(ident: &[u8]) -> Result<Self> {
+        if ident.starts_with(b"\x01vorbis") {
+            Ok(OggCodec::Vorbis)
+        } else if ident.starts_with(b"OpusHead") {
+            Ok(OggCodec::Opus)
+        } else {
+            Err(anyhow!(
+                "tag writing is only supported for Ogg Vorbis and Opus streams"
+            ))
+        }
+    }
+
+    /// Header packets after the identification one: comments, then the
+    /// setup for Vorbis.
+    fn header_packets(self) -> usize {
+        match self {
+            OggCodec::Vorbis => 2,
+            OggCodec::Opus => 1,
+        }
+    }
+
+    fn comment_signature(self) -> &'static [u8] {
+        match self {
+            OggCodec::Vorbis => b"\x03vorbis",
+            OggCodec::Opus => b"OpusTags",
+        }
+    }
+}
+
+/// An Ogg file whose first logical stream is Vorbis or Opus, with that
+/// stream's header packets after the identification header pulled out.
+/// Pages of other streams, and all audio pages, are kept as they are apart
+/// from their sequence numbers.
+struct OggFile {
+    pages: Vec<OggPage>,
+    codec: OggCodec,
+    serial: u32,
+    /// Indices in `pages` of the stream's pages holding `packets`.
+    header_pages: Vec<usize>,
+    packets: Vec<Vec<u8>>,
+}
+
+impl OggFile {
+    fn read(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path).with_context(|| format!("read {:?}", path))?;
+        Self::parse(&bytes)
+    }
+
+    fn parse(bytes: &[u8]) -> Result<Self> {
+        let mut pages = Vec::new();
+        let mut offset = 0usize;
+        while offset < bytes.len() {
+            let (page, next) = OggPage::parse(bytes, offset)?;
+            pages.push(page);
+            offset = next;
+        }
+        let first = pages.first().ok_or_else(|| anyhow!("not an Ogg file"))?;
+        if first.header_type & OGG_FIRST_PAGE == 0 {
+            return Err(anyhow!("Ogg stream does not start with its first page"));
+        }
+        let serial = first.serial;
+        // The identification header is alone on the stream's first page.
+        match first.segments.split_last() {
+            Some((&last, full)) if last < 255 && full.iter().all(|&len| len == 255) => {}
+            _ => return Err(anyhow!("invalid Ogg identification page")),
+        }
+        let codec = OggCodec::detect(&first.body)?;
+
+        let mut header_pages = Vec::new();
+        let mut packets: Vec<Vec<u8>> = Vec::new();
+        let mut packet = Vec::new();
+        for (idx, page) in pages.iter().enumerate().skip(1) {
+            if page.serial != serial {
+                continue;
+            }
+            header_pages.push(idx);
+            let mut body = page.body.as_slice();
+            for &len in &page.segments {
+                let (segment, rest) = body.split_at(len as usize);
+                packet.extend_from_slice(segment);
+                body = rest;
+                if len < 255 {
+                    packets.push(std::mem::take(&mut packet));
+                }
+            }
+            if packets.len() >= codec.header_packets() {
+                break;
+            }
+        }
+        if packets.len() < codec.header_packets() {
+            return Err(anyhow!("truncated Ogg headers"));
+        }
+        // Audio starts on a fresh page, so the headers can be laid out anew.
+        if packets.len() > codec.header_packets() || !packet.is_empty() {
+            return Err(anyhow!("Ogg headers share a page with audio"));
+        }
+        Ok(OggFile {
+            pages,
+            codec,
+            serial,
+            header_pages,
+            packets,
+        })
+    }
+
+    fn edit_comments(&mut self, edit: impl FnOnce(&mut VorbisComments)) -> Result<()> {
+        let signature = self.codec.comment_signature();
+        let body = self.packets[0]
+            .strip_prefix(signature)
+            .ok_or_else(|| anyhow!("invalid Ogg comment header"))?;
+        let (mut comments, used) = VorbisComments::parse_prefix(body)?;
+        // Vorbis ends the packet with a framing bit, Opus may add padding.
+        let trailer = body[used..].to_vec();
+        edit(&mut comments);
+        let mut packet = signature.to_vec();
+        packet.extend_from_slice(&comments.to_bytes());
+        packet.extend_from_slice(&trailer);
+        self.packets[0] = packet;
+        Ok(())
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let first_sequence = self.pages[0].sequence.wrapping_add(1);
+        let headers = paginate_ogg(&self.packets, self.serial, first_sequence);
+        let shift = headers.len() as i32 - self.header_pages.len() as i32;
+        let last_header = self.header_pages[self.header_pages.len() - 1];
+        let mut out = Vec::with_capacity(self.pages.iter().map(|page| page.body.len() + 300).sum());
+        let mut chained = false;
+        for (idx, page) in self.pages.iter().enumerate() {
+            if idx == self.header_pages[0] {
+                for header in &headers {
+                    header.write_to(&mut out, header.sequence);
+                }
+            }
+            if self.header_pages.binary_search(&idx).is_ok() {
+                continue;
+            }
+            let ours = idx > last_header && page.serial == self.serial;
+            // A chained stream may reuse the serial; its pages keep their numbers.
+            chained |= ours && page.header_type & OGG_FIRST_PAGE != 0;
+            let sequence = if ours && !chained {
+                page.sequence.wrapping_add_signed(shift)
+            } else {
+                page.sequence
+            };
+            page.write_to(&mut out, sequence);
+        }
+        out
+    }
+
+    fn write(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, self.to_bytes()).context("write Ogg file")
+    }
+}
+
+const MP4_DATA_IMPLICIT: u32 = 0;
+const MP4_DATA_UTF8: u32 = 1;
+const MP4_TITLE: &[u8; 4] = b"\xa9nam";
+const MP4_ARTIST: &[u8; 4] = b"\xa9ART";
+const MP4_ALBUM: &[u8; 4] = b"\xa9alb";
+const MP4_GENRE: &[u8; 4] = b"\xa9gen";
+/// The ID3v1 genre number older files store instead of `©gen`.
+const MP4_GENRE_NUMBER: &[u8; 4] = b"gnre";
+const MP4_YEAR: &[u8; 4] = b"\xa9day";
+const MP4_TRACK: &[u8; 4] = b"trkn";
+const MP4_COVER: &[u8; 4] = b"covr";
+const MP4_DATA_JPEG: u32 = 13;
+const MP4_DATA_PNG: u32 = 14;
+
+struct Mp4Box {
+    kind: [u8; 4],
+    data: Vec<u8>,
+}
+
+impl Mp4Box {
+    fn len(&self) -> usize {
+        mp4_header_len(self.data.len()) + self.data.len()
+    }
+
+    fn write_to(&self, out: &mut Vec<u8>) {
+        let len = self.len();
+        if let Ok(len) = u32::try_from(len) {
+            out.extend_from_slice(&len.to_be_bytes());
+            out.extend_from_slice(&self.kind);
+        } else {
+            out.extend_from_slice(&1u32.to_be_bytes());
+            out.extend_from_slice(&self.kind);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+        out.extend_from_slice(&self.data);
+    }
+
+    /// A `data` atom holding one value of an `ilst` item.
+    fn data_atom(type_code: u32, payload: &[u8]) -> Self {
+        let mut data = Vec::with_capacity(payload.len() + 8);
+        data.extend_from_slice(&type_code.to_be_bytes());
+        data.extend_from_slice(&0u32.to_be_bytes()); // locale
+        data.extend_from_slice(payload);
+        Mp4Box {
+            kind: *b"data",
+            data,
+        }
+    }
+
+    /// An `ilst` item holding `values` as its `data` atoms.
+    fn item(kind: &[u8; 4], values: &[Mp4Box]) -> Self {
+        let mut data = Vec::new();
+        for value in values {
+            value.write_to(&mut data);
+        }
+        Mp4Box { kind: *kind, data }
+    }
+}
+
+fn mp4_header_len(payload_len: usize) -> usize {
+    if u32::try_from(payload_len + 8).is_ok() {
+        8
+    } else {
+        16
+    }
+}
+
+/// Kind and payload range of the box at `offset`.
+fn mp4_box_at(data: &[u8], offset: usize) -> Result<([u8; 4], Range<usize>)> {
+    let header = data
+        .get(offset..offset + 8)
+        .ok_or_else(|| anyhow!("truncated MP4 box"))?;
+    let kind = [header[4], header[5], header[6], header[7]];
+    let (len, header_len) = match u32::from_be_bytes([header[0], header[1], header[2], header[3]]) {
+        0 => (data.len() - offset, 8),
+        1 => {
+            let large = data
+                .get(offset + 8..offset + 16)
+                .ok_or_else(|| anyhow!("truncated MP4 box"))?;
+            let mut len = [0u8; 8];
+            len.copy_from_slice(large);
+            (
+                usize::try_from(u64::from_be_bytes(len)).unwrap_or(usize::MAX),
+                16,
+            )
+        }
+        len => (len as usize, 8),
+    };
+    if len < header_len || data.len() - offset < len {
+        return Err(anyhow!("truncated MP4 box"));
+    }
+    Ok((kind, offset + header_len..offset + len))
+}
+
+fn parse_mp4_boxes(data: &[u8]) -> Result<Vec<Mp4Box>> {
+    let mut boxes = Vec::new();
+    let mut offset = 0usize;
+    // Some writers end a list with four zero bytes; they carry nothing.
+    while data.len() - offset >= 8 {
+        let (kind, payload) = mp4_box_at(data, offset)?;
+        offset = payload.end;
+        boxes.push(Mp4Box {
+            kind,
+            data: data[payload].to_vec(),
+        });
+    }
+    Ok(boxes)
+}
+
+/// Parse the boxes after the first `skip` bytes of `payload`, edit them and
+/// write them back in place.
+fn edit_mp4_children<T>(
+    payload: &mut Vec<u8>,
+    skip: usize,
+    edit: impl FnOnce(&mut Vec<Mp4Box>) -> Result<T>,
+) -> Result<T> {
+    let mut children = parse_mp4_boxes(payload.get(skip..).unwrap_or_default())?;
+    let result = edit(&mut children)?;
+    payload.truncate(skip);
+    for child in &children {
+        child.write_to(payload);
+    }
+    Ok(result)
+}
+
+/// The first child of kind `kind`, added with `init` as its payload when
+/// there is none.
+fn mp4_child<'a>(
+    boxes: &'a mut Vec<Mp4Box>,
+    kind: &[u8; 4],
+    init: impl FnOnce() -> Vec<u8>,
+) -> &'a mut Mp4Box {
+    let idx = match boxes.iter().position(|child| &child.kind == kind) {
+        Some(idx) => idx,
+        None => {
+            boxes.push(Mp4Box {
+                kind: *kind,
+                data: init(),
+            });
+            boxes.len() - 1
+        }
+    };
+    &mut boxes[idx]
+}
+
+/// Payload of a new `udta/meta`: a full box whose handler marks it as
+/// iTunes-style metadata.
+fn new_mp4_meta() -> Vec<u8> {
+    let mut hdlr = vec![0u8; 8]; // version, flags, pre-defined
+    hdlr.extend_from_slice(b"mdirappl");
+    hdlr.extend_from_slice(&[0; 9]); // reserved, empty name
+    let mut data = vec![0u8; 4];
+    Mp4Box {
+        kind: *b"hdlr",
+        data: hdlr,
+    }
+    .write_to(&mut data);
+    data
+}
+
+/// Where the children of a `meta` box start: ISO files make it a full box
+/// with four zero bytes of version and flags first, QuickTime files start
+/// with the size of the first child.
+fn mp4_meta_children_offset(meta: &[u8]) -> usize {
+    if meta.starts_with(&[0; 4]) {
+        4
+    } else {
+        0
+    }
+}
+
+/// An MP4 file, kept as its bytes plus where its `moov` box is; only `moov`
+/// is ever rewritten.
+struct Mp4File {
+    bytes: Vec<u8>,
+    moov: Range<usize>,
+    moov_header: usize,
+}
+
+impl Mp4File {
+    fn read(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path).with_context(|| format!("read {:?}", path))?;
+        Self::parse(bytes)
+    }
+
+    fn parse(bytes: Vec<u8>) -> Result<Self> {
+        let mut moov = None;
+        let mut offset = 0usize;
+        while offset < bytes.len() {
+            let (kind, payload) = mp4_box_at(&bytes, offset)?;
+            match &kind {
+                b"moov" => moov = Some((offset..payload.end, payload.start - offset)),
+                // Fragments address their samples in ways moving `moov` would break.
+                b"moof" => return Err(anyhow!("fragmented MP4 files are not supported")),
+                _ => {}
+            }
+            offset = payload.end;
+        }
+        let (moov, moov_header) = moov.ok_or_else(|| anyhow!("not an MP4 file"))?;
+        Ok(Mp4File {
+            bytes,
+            moov,
+            moov_header,
+        })
+    }
+
+    /// Edit the items of `moov/udta/meta/ilst`, creating the path as needed.
+    /// When `moov` changes size and sits before the media data, the chunk
+    /// offsets of every track move with it.
+    fn edit_items(&mut self, edit: impl FnOnce(&mut Vec<Mp4Box>) -> Result<()>) -> Result<()> {
+        let old_len = self.moov.len();
+        let moov_end = self.moov.end as u64;
+        let mut payload = self.bytes[self.moov.start + self.moov_header..self.moov.end].to_vec();
+        edit_mp4_children(&mut payload, 0, |moov| {
+            let udta = mp4_child(moov, b"udta", Vec::new);
+            edit_mp4_children(&mut udta.data, 0, |udta| {
+                let meta = mp4_child(udta, b"meta", new_mp4_meta);
+                let skip = mp4_meta_children_offset(&meta.data);
+                edit_mp4_children(&mut meta.data, skip, |meta| {
+                    let ilst = mp4_child(meta, b"ilst", Vec::new);
+                    edit_mp4_children(&mut ilst.data, 0, edit)
+                })
+            })?;
+            let new_len = moov.iter().map(Mp4Box::len).sum::<usize>();
+            let shift = (mp4_header_len(new_len) + new_len) as i64 - old_len as i64;
+            if shift != 0 {
+                for trak in moov.iter_mut().filter(|child| &child.kind == b"trak") {
+                    shift_mp4_chunk_offsets(trak, moov_end, shift)?;
+                }
+            }
+            Ok(())
+        })?;
+        let moov_header = mp4_header_len(payload.len());
+        let mut moov = Vec::with_capacity(payload.len() + moov_header);
+        Mp4Box {
+            kind: *b"moov",
+            data: payload,
+        }
+        .write_to(&mut moov);
+        self.bytes.splice(self.moov.clone(), moov.iter().copied());
+        self.moov = self.moov.start..self.moov.start + moov.len();
+        self.moov_header = moov_header;
+        Ok(())
+    }
+
+    fn write(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, &self.bytes).context("write MP4 file")
+    }
+}
+
+/// Move the chunk offsets in `trak`'s sample table that point at or past
+/// `from` by `shift` bytes.
+fn shift_mp4_chunk_offsets(trak: &mut Mp4Box, from: u64, shift: i64) -> Result<()> {
+    edit_mp4_children(&mut trak.data, 0, |trak| {
+        let Some(mdia) = trak.iter_mut().find(|child| &child.kind == b"mdia") else {
+            return Ok(());
+        };
+        edit_mp4_children(&mut mdia.data, 0, |mdia| {
+            let Some(minf) = mdia.iter_mut().find(|child| &child.kind == b"minf") else {
+                return Ok(());
+            };
+            edit_mp4_children(&mut minf.data, 0, |minf| {
+                let Some(stbl) = minf.iter_mut().find(|child| &child.kind == b"stbl") else {
+                    return Ok(());
+                };
+                edit_mp4_children(&mut stbl.data, 0, |stbl| {
+                    for table in stbl.iter_mut() {
+                        let width = match &table.kind {
+                            b"stco" => 4,
+                            b"co64" => 8,
+                            _ => continue,
+                        };
+                        // Version and flags, then the entry count.
+                        let entries = table
+                            .data
+                            .get_mut(8..)
+                            .ok_or_else(|| anyhow!("truncated MP4 chunk offset table"))?;
+                        for entry in entries.chunks_exact_mut(width) {
+                            let mut value = [0u8; 8];
+                            value[8 - width..].copy_from_slice(entry);
+                            let offset = u64::from_be_bytes(value);
+                            if offset < from {
+                                continue;
+                            }
+                            let moved = offset
+                                .checked_add_signed(shift)
+                                .filter(|moved| width == 8 || *moved <= u32::MAX as u64)
+                                .ok_or_else(|| {
+                                    anyhow!("MP4 chunk offset out of range after retagging")
+                                })?;
+                            entry.copy_from_slice(&moved.to_be_bytes()[8 - width..]);
+                        }
+                    }
+                    Ok(())
+                })
+            })
+        })
+    })
+}
+
+/// Replace item `kind` with the text `value`; see `TagUpdate`.
+fn set_mp4_text(items: &mut Vec<Mp4Box>, kind: &[u8; 4], value: Option<&str>) {
+    let Some(value) = value else {
+        return;
+    };
+    items.retain(|item| &item.kind != kind);
+    if !value.is_empty() {
+        items.push(Mp4Box::item(
+            kind,
+            &[Mp4Box::data_atom(MP4_DATA_UTF8, value.as_bytes())],
+        ));
+    }
+}
+
+/// Put `image` first in `covr`, in place of the previous first image, which
+/// players show as the front cover.
+fn set_mp4_front_cover(
+    items: &mut Vec<Mp4Box>,
+    image: &[u8],
+    info: &CoverImage,
+    remove_existing: bool,
+) -> Result<()> {
+    let type_code = if info.mime_type == "image/png" {
+        MP4_DATA_PNG
+    } else {
+        MP4_DATA_JPEG
+    };
+    let mut values = vec![Mp4Box::data_atom(type_code, image)];
+    let position = items.iter().position(|item| &item.kind == MP4_COVER);
+    if !remove_existing {
+        let mut previous = Vec::new();
+        for item in items.iter().filter(|item| &item.kind == MP4_COVER) {
+            previous.extend(parse_mp4_boxes(&item.data)?);
+        }
+        values.extend(
+            previous
+                .into_iter()
+                .filter(|value| &value.kind == b"data")
+                .skip(1),
+        );
+    }
+    items.retain(|item| &item.kind != MP4_COVER);
+    let cover = Mp4Box::item(MP4_COVER, &values);
+    items.insert(position.unwrap_or(items.len()).min(items.len()), cover);
+    Ok(())
+}
+
+fn apply_mp4_update(items: &mut Vec<Mp4Box>, update: &TagUpdate) -> Result<()> {
+    set_mp4_text(items, MP4_TITLE, update.title.as_deref());
+    set_mp4_text(items, MP4_ARTIST, update.artist.as_deref());
+    set_mp4_text(items, MP4_ALBUM, update.album.as_deref());
+    if update.genre.is_some() {
+        items.retain(|item| &item.kind != MP4_GENRE_NUMBER);
+    }
+    set_mp4_text(items, MP4_GENRE, update.genre.as_deref());
+    let year = update.year.map(|year| {
+        if year == 0 {
+            String::new()
+        } else {
+            year.to_string()
+        }
+    });
+    set_mp4_text(items, MP4_YEAR, year.as_deref());
+    if let Some(track) = update.track {
+        let track =
+            u16::try_from(track).map_err(|_| anyhow!("track {} is too large for MP4", track))?;
+        // trkn is eight bytes: padding, track, total, padding. Keep the total.
+        let total = items
+            .iter()
+            .find(|item| &item.kind == MP4_TRACK)
+            .and_then(|item| parse_mp4_boxes(&item.data).ok())
+            .and_then(|values| values.into_iter().find(|value| &value.kind == b"data"))
+            .and_then(|value| value.data.get(12..14).map(|total| [total[0], total[1]]))
+            .unwrap_or_default();
+        items.retain(|item| &item.kind != MP4_TRACK);
+        if track != 0 {
+            let mut value = vec![0, 0];
+            value.extend_from_slice(&track.to_be_bytes());
+            value.extend_from_slice(&total);
+            value.extend_from_slice(&[0, 0]);
+            items.push(Mp4Box::item(
+                MP4_TRACK,
+                &[Mp4Box::data_atom(MP4_DATA_IMPLICIT, &value)],
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn minimal_flac(extra_blocks: Vec<FlacBlock>) -> FlacFile {
+        let mut blocks = vec![FlacBlock {
+            kind: 0,
+            data: vec![0u8; 34],
+        }];
+        blocks.extend(extra_blocks);
+        FlacFile {
+            blocks,
+            audio: vec![0xff, 0xf8, 1, 2, 3, 4],
+        }
+    }
+
+    #[test]
+    fn flac_comments_are_updated_in_place() {
+        let comments = VorbisComments {
+            vendor: "test".to_string(),
+            entries: vec!["TITLE=Old".to_string(), "COMMENT=keep me".to_string()],
+        };
+        let picture = FlacBlock {
+            kind: 6,
+            data: vec![9; 16],
+        };
+        let original = minimal_flac(vec![
+            FlacBlock {
+                kind: FLAC_BLOCK_VORBIS_COMMENT,
+                data: comments.to_bytes(),
+            },
+            picture,
+        ]);
+        let mut file = FlacFile::parse(original.to_bytes().unwrap()).unwrap();
+        file.update_comments(&TagUpdate {
+            title: Some("New".to_string()),
+            track: Some(3),
+            ..TagUpdate::default()
+        })
+        .unwrap();
+
+        let reparsed = FlacFile::parse(file.to_bytes().unwrap()).unwrap();
+        assert_eq!(reparsed.blocks.len(), 3);
+        assert_eq!(reparsed.blocks[2].kind, 6);
+        assert_eq!(reparsed.audio, original.audio);
+        let comments = VorbisComments::parse(&reparsed.blocks[1].data).unwrap();
+        assert_eq!(comments.vendor, "test");
+        assert_eq!(
+            comments.entries,
+            vec!["COMMENT=keep me", "TITLE=New", "TRACKNUMBER=3"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rewrites_keep_permissions_and_use_unique_temp_files() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path =
+            std::env::temp_dir().join(format!("ntmusic_tags_mode_{}.flac", std::process::id()));
+        assert_ne!(temp_path_for(&path), temp_path_for(&path));
+        std::fs::write(&path, minimal_flac(Vec::new()).to_bytes().unwrap()).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+        write_tags(
+            &path,
+            &TagUpdate {
+                title: Some("Title".to_string()),
+                ..TagUpdate::default()
+            },
+        )
+        .unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(mode & 0o777, 0o640);
+    }
+
+    #[test]
+    fn flac_without_comments_gets_a_block_after_streaminfo() {
+        let mut file = minimal_flac(Vec::new());
+        file.update_comments(&TagUpdate {
+            artist: Some("Someone".to_string()),
+            ..TagUpdate::default()
+        })
+        .unwrap();
+        assert_eq!(file.blocks[0].kind, 0);
+        assert_eq!(file.blocks[1].kind, FLAC_BLOCK_VORBIS_COMMENT);
+    }
+
+    fn tiny_png() -> Vec<u8> {
+        let mut png = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR".to_vec();
+        png.extend_from_slice(&300u32.to_be_bytes());
+        png.extend_from_slice(&200u32.to_be_bytes());
+        png.extend_from_slice(&[8, 2, 0, 0, 0]);
+        png
+    }
+
+    #[test]
+    fn cover_images_are_validated() {
+        let info = inspect_cover_image(&tiny_png()).unwrap();
+        assert_eq!(info.mime_type, "image/png");
+        assert_eq!((info.width, info.height), (300, 200));
+
+        let jpeg = [
+            0xff, 0xd8, 0xff, 0xe0, 0x00, 0x04, 0x00, 0x00, 0xff, 0xc0, 0x00, 0x0b, 0x08, 0x00,
+            0x40, 0x00, 0x80, 0x03,
+        ];
+        let info = inspect_cover_image(&jpeg).unwrap();
+        assert_eq!(info.mime_type, "image/jpeg");
+        assert_eq!((info.width, info.height), (128, 64));
+
+        assert!(inspect_cover_image(b"GIF89a").is_err());
+        assert!(inspect_cover_image(&vec![0u8; MAX_COVER_BYTES + 1]).is_err());
+    }
+
+    #[test]
+    fn flac_front_cover_replaces_previous_front_cover_only() {
+        let back_cover = FlacBlock {
+            kind: FLAC_BLOCK_PICTURE,
+            data: 4u32.to_be_bytes().to_vec(),
+        };
+        let front_cover = FlacBlock {
+            kind: FLAC_BLOCK_PICTURE,
+            data: FLAC_PICTURE_FRONT_COVER.to_be_bytes().to_vec(),
+        };
+        let png = tiny_png();
+        let info = inspect_cover_image(&png).unwrap();
+        let mut file = minimal_flac(vec![back_cover, front_cover]);
+        file.set_front_cover(&png, &info, false);
+        let pictures: Vec<&FlacBlock> = file
+            .blocks
+            .iter()
+            .filter(|block| block.kind == FLAC_BLOCK_PICTURE)
+            .collect();
+        assert_eq!(pictures.len(), 2);
+        assert_eq!(pictures[0].data, 4u32.to_be_bytes().to_vec());
+        assert!(pictures[1].data.ends_with(&png));
+
+        file.set_front_cover(&png, &info, true);
+        let count = file
+            .blocks
+            .iter()
+            .filter(|b| b.kind == FLAC_BLOCK_PICTURE)
+            .count();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn mp3_tags_round_trip_and_unsupported_formats_error() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("ntmusic_tags_{}.mp3", std::process::id()));
+        std::fs::write(&path, [0xffu8, 0xfb, 0x90, 0x00, 0, 0, 0, 0]).unwrap();
+        write_tags(
+            &path,
+            &TagUpdate {
+                title: Some("Title".to_string()),
+                year: Some(1999),
+                ..TagUpdate::default()
+            },
+        )
+        .unwrap();
+        write_tags(
+            &path,
+            &TagUpdate {
+                artist: Some("Artist".to_string()),
+                ..TagUpdate::default()
+            },
+        )
+        .unwrap();
+        let tag = id3::Tag::read_from_path(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(tag.title(), Some("Title"));
+        assert_eq!(tag.artist(), Some("Artist"));
+        assert_eq!(tag.year(), Some(1999));
+        assert!(!temp_path_for(&path).exists());
+
+        let aac = dir.join("ntmusic_tags.aac");
+        let err = write_tags(&aac, &TagUpdate::default()).unwrap_err();
+        assert!(err.to_string().contains("not supported"));
+    }
+
+    fn ogg_page(header_type: u8, granule: u64, sequence: u32, packets: &[&[u8]]) -> OggPage {
+        let mut page = OggPage::empty(7, sequence, header_type);
+        page.granule = granule;
+        for packet in packets {
+            let mut len = packet.len();
+            while len >= 255 {
+                page.segments.push(255);
+                len -= 255;
+            }
+            page.segments.push(len as u8);
+            page.body.extend_from_slice(packet);
+        }
+        page
+    }
+
+    /// A Vorbis or Opus stream: identification page, one page with the
+    /// other headers, two audio pages.
+    fn ogg_stream(ident: &[u8], headers: &[&[u8]]) -> Vec<u8> {
+        let pages = [
+            ogg_page(OGG_FIRST_PAGE, 0, 0, &[ident]),
+            ogg_page(0, 0, 1, headers),
+            ogg_page(0, 960, 2, &[&[0x10; 300], &[0x20; 20]]),
+            ogg_page(0x04, 1920, 3, &[&[0x30; 40]]),
+        ];
+        let mut out = Vec::new();
+        for page in &pages {
+            page.write_to(&mut out, page.sequence);
+        }
+        out
+    }
+
+    fn vorbis_ogg(comments: &VorbisComments) -> Vec<u8> {
+        let mut ident = b"\x01vorbis".to_vec();
+        ident.extend_from_slice(&0u32.to_le_bytes());
+        ident.push(2);
+        ident.extend_from_slice(&44_100u32.to_le_bytes());
+        ident.extend_from_slice(&[0; 4]);
+        ident.extend_from_slice(&128_000i32.to_le_bytes());
+        ident.extend_from_slice(&[0; 4]);
+        ident.extend_from_slice(&[0xb8, 1]); // block sizes 256 and 2048, framing
+        let mut comment = b"\x03vorbis".to_vec();
+        comment.extend_from_slice(&comments.to_bytes());
+        comment.push(1);
+        ogg_stream(&ident, &[&comment, b"\x05vorbis-setup"])
+    }
+
+    fn opus_ogg(comments: &VorbisComments, trailer: &[u8]) -> Vec<u8> {
+        let mut head = b"OpusHead\x01\x02".to_vec();
+        head.extend_from_slice(&312u16.to_le_bytes());
+        head.extend_from_slice(&48_000u32.to_le_bytes());
+        head.extend_from_slice(&[0, 0, 0]); // gain, channel mapping
+        let mut tags = b"OpusTags".to_vec();
+        tags.extend_from_slice(&comments.to_bytes());
+        tags.extend_from_slice(trailer);
+        ogg_stream(&head, &[&tags])
+    }
+
+    /// Metadata as symphonia's Ogg reader sees it, which also checks every
+    /// page's checksum.
+    fn probed_metadata(path: &Path) -> symphonia::core::meta::MetadataRevision {
+        use symphonia::core::formats::FormatOptions;
+        use symphonia::core::io::MediaSourceStream;
+        use symphonia::core::meta::MetadataOptions;
+        use symphonia::core::probe::Hint;
+
+        let file = std::fs::File::open(path).unwrap();
+        let source = MediaSourceStream::new(Box::new(file), Default::default());
+        let mut probed = symphonia::default::get_probe()
+            .format(
+                &Hint::new(),
+                source,
+                &FormatOptions::default(),
+                &MetadataOptions::default(),
+            )
+            .unwrap();
+        let mut metadata = probed.format.metadata();
+        metadata.skip_to_latest().unwrap().clone()
+    }
+
+    fn probed_tags(path: &Path) -> Vec<(String, String)> {
+        probed_metadata(path)
+            .tags()
+            .iter()
+            .map(|tag| (tag.key.clone(), tag.value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn ogg_vorbis_comments_are_rewritten_across_pages() {
+        let path = std::env::temp_dir().join(format!("ntmusic_tags_{}.ogg", std::process::id()));
+        let comments = VorbisComments {
+            vendor: "test".to_string(),
+            entries: vec!["TITLE=Old".to_string(), "COMMENT=keep me".to_string()],
+        };
+        let original = vorbis_ogg(&comments);
+        std::fs::write(&path, &original).unwrap();
+        // Longer than one page can carry, so the audio pages get renumbered.
+        let title = "t".repeat(70_000);
+        write_tags(
+            &path,
+            &TagUpdate {
+                title: Some(title.clone()),
+                artist: Some("Someone".to_string()),
+                ..TagUpdate::default()
+            },
+        )
+        .unwrap();
+        let tags = probed_tags(&path);
+        let bytes = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(tags.contains(&("TITLE".to_string(), title)));
+        assert!(tags.contains(&("ARTIST".to_string(), "Someone".to_string())));
+        assert!(tags.contains(&("COMMENT".to_string(), "keep me".to_string())));
+
+        let before = OggFile::parse(&original).unwrap();
+        let after = OggFile::parse(&bytes).unwrap();
+        assert_eq!(after.header_pages, vec![1, 2]);
+        assert_eq!(after.packets[1], before.packets[1]);
+        assert!(after.packets[0].ends_with(&[1]));
+        let sequences: Vec<u32> = after.pages.iter().map(|page| page.sequence).collect();
+        assert_eq!(sequences, vec![0, 1, 2, 3, 4]);
+        assert_eq!(after.pages[2].header_type, OGG_CONTINUED);
+        assert_eq!(after.pages[1].granule, OGG_NO_GRANULE);
+        for (old, new) in before.pages[2..].iter().zip(&after.pages[3..]) {
+            assert_eq!((old.granule, &old.body), (new.granule, &new.body));
+        }
+    }
+
+    #[test]
+    fn opus_tags_keep_their_trailing_data() {
+        let path = std::env::temp_dir().join(format!("ntmusic_tags_{}.opus", std::process::id()));
+        let comments = VorbisComments {
+            vendor: "test".to_string(),
+            entries: vec!["GENRE=Old".to_string()],
+        };
+        std::fs::write(&path, opus_ogg(&comments, b"\x01binary")).unwrap();
+        write_tags(
+            &path,
+            &TagUpdate {
+                genre: Some("Jazz".to_string()),
+                track: Some(4),
+                ..TagUpdate::default()
+            },
+        )
+        .unwrap();
+        let tags = probed_tags(&path);
+        let file = OggFile::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(tags.contains(&("GENRE".to_string(), "Jazz".to_string())));
+        assert!(tags.contains(&("TRACKNUMBER".to_string(), "4".to_string())));
+        assert_eq!(file.codec, OggCodec::Opus);
+        assert!(file.packets[0].ends_with(b"\x01binary"));
+
+        let speex = ogg_stream(b"Speex   1.2", &[b"comments"]);
+        let err = OggFile::parse(&speex).err().unwrap();
+        assert!(err
+            .to_string()
+            .contains("only supported for Ogg Vorbis and Opus"));
+    }
+
+    fn mp4_container(kind: &[u8; 4], children: &[Mp4Box]) -> Mp4Box {
+        Mp4Box::item(kind, children)
+    }
+
+    /// ftyp, moov (one track, its chunk offset pointing into mdat), mdat.
+    fn mp4_file(udta: Option<Mp4Box>) -> Vec<u8> {
+        let layout = |chunk_offset: u32| {
+            let mut stco = vec![0u8; 4];
+            stco.extend_from_slice(&1u32.to_be_bytes());
+            stco.extend_from_slice(&chunk_offset.to_be_bytes());
+            let stbl = mp4_container(
+                b"stbl",
+                &[Mp4Box {
+                    kind: *b"stco",
+                    data: stco,
+                }],
+            );
+            let minf = mp4_container(b"minf", &[stbl]);
+            let trak = mp4_container(b"trak", &[mp4_container(b"mdia", &[minf])]);
+            let mut moov = Mp4Box::item(b"moov", &[trak]);
+            if let Some(udta) = &udta {
+                udta.write_to(&mut moov.data);
+            }
+            let mut out = Vec::new();
+            Mp4Box {
+                kind: *b"ftyp",
+                data: b"M4A \0\0\0\0M4A isom".to_vec(),
+            }
+            .write_to(&mut out);
+            moov.write_to(&mut out);
+            Mp4Box {
+                kind: *b"mdat",
+                data: b"AUDIO-FRAMES".to_vec(),
+            }
+            .write_to(&mut out);
+            out
+        };
+        let mdat_payload = layout(0).len() - b"AUDIO-FRAMES".len();
+        layout(mdat_payload as u32)
+    }
+
+    fn mp4_items(bytes: Vec<u8>) -> (Vec<Mp4Box>, Vec<u8>) {
+        let file = Mp4File::parse(bytes).unwrap();
+        let child = |data: &[u8], kind: &[u8; 4]| {
+            parse_mp4_boxes(data)
+                .unwrap()
+                .into_iter()
+                .find(|child| &child.kind == kind)
+                .unwrap()
+        };
+        let moov = &file.bytes[file.moov.start + file.moov_header..file.moov.end];
+        let udta = child(moov, b"udta");
+        let meta = child(&udta.data, b"meta");
+        let ilst = child(&meta.data[mp4_meta_children_offset(&meta.data)..], b"ilst");
+        let stbl = [b"trak", b"mdia", b"minf", b"stbl"]
+            .iter()
+            .fold(moov.to_vec(), |data, kind| child(&data, kind).data);
+        let stco = child(&stbl, b"stco").data;
+        let offset = u32::from_be_bytes([stco[8], stco[9], stco[10], stco[11]]) as usize;
+        (
+            parse_mp4_boxes(&ilst.data).unwrap(),
+            file.bytes[offset..].to_vec(),
+        )
+    }
+
+    fn mp4_value<'a>(items: &'a [Mp4Box], kind: &[u8; 4]) -> Option<&'a [u8]> {
+        let item = items.iter().find(|item| &item.kind == kind)?;
+        // One `data` atom: 8-byte header, type, locale, value.
+        item.data.get(16..)
+    }
+
+    #[test]
+    fn mp4_items_are_written_and_chunk_offsets_follow() {
+        let path = std::env::temp_dir().join(format!("ntmusic_tags_{}.m4a", std::process::id()));
+        std::fs::write(&path, mp4_file(None)).unwrap();
+        write_tags(
+            &path,
+            &TagUpdate {
+                title: Some("Title".to_string()),
+                year: Some(2001),
+                track: Some(3),
+                ..TagUpdate::default()
+            },
+        )
+        .unwrap();
+        let (items, chunk) = mp4_items(std::fs::read(&path).unwrap());
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(chunk, b"AUDIO-FRAMES");
+        assert_eq!(mp4_value(&items, MP4_TITLE), Some(&b"Title"[..]));
+        assert_eq!(mp4_value(&items, MP4_YEAR), Some(&b"2001"[..]));
+        assert_eq!(
+            mp4_value(&items, MP4_TRACK),
+            Some(&[0, 0, 0, 3, 0, 0, 0, 0][..])
+        );
+    }
+
+    #[test]
+    fn mp4_updates_keep_other_items_and_the_track_total() {
+        let text = |kind: &[u8; 4], value: &str| {
+            Mp4Box::item(kind, &[Mp4Box::data_atom(MP4_DATA_UTF8, value.as_bytes())])
+        };
+        let total = Mp4Box::item(
+            MP4_TRACK,
+            &[Mp4Box::data_atom(
+                MP4_DATA_IMPLICIT,
+                &[0, 0, 0, 1, 0, 12, 0, 0],
+            )],
+        );
+        let gnre = Mp4Box::item(
+            MP4_GENRE_NUMBER,
+            &[Mp4Box::data_atom(MP4_DATA_IMPLICIT, &[0, 9])],
+        );
+        let ilst = mp4_container(
+            b"ilst",
+            &[
+                text(MP4_TITLE, "Old"),
+                text(MP4_ALBUM, "Album"),
+                total,
+                gnre,
+            ],
+        );
+        // QuickTime-style meta, without version and flags.
+        let meta = mp4_container(b"meta", &[ilst]);
+        let udta = mp4_container(b"udta", &[meta]);
+        let mut file = Mp4File::parse(mp4_file(Some(udta))).unwrap();
+        file.edit_items(|items| {
+            apply_mp4_update(
+                items,
+                &TagUpdate {
+                    title: Some(String::new()),
+                    genre: Some("Jazz".to_string()),
+                    track: Some(5),
+                    ..TagUpdate::default()
+                },
+            )
+        })
+        .unwrap();
+        let (items, chunk) = mp4_items(file.bytes);
+        assert_eq!(chunk, b"AUDIO-FRAMES");
+        assert_eq!(mp4_value(&items, MP4_TITLE), None);
+        assert_eq!(mp4_value(&items, MP4_ALBUM), Some(&b"Album"[..]));
+        assert_eq!(mp4_value(&items, MP4_GENRE), Some(&b"Jazz"[..]));
+        assert_eq!(mp4_value(&items, MP4_GENRE_NUMBER), None);
+        assert_eq!(
+            mp4_value(&items, MP4_TRACK),
+            Some(&[0, 0, 0, 5, 0, 12, 0, 0][..])
+        );
+
+        let mut too_large = Mp4File::parse(mp4_file(None)).unwrap();
+        let update = TagUpdate {
+            track: Some(70_000),
+            ..TagUpdate::default()
+        };
+        assert!(too_large
+            .edit_items(|items| apply_mp4_update(items, &update))
+            .is_err());
+    }
+
+    #[test]
+    fn ogg_front_cover_replaces_previous_front_cover_only() {
+        use base64::Engine as _;
+        use symphonia::core::meta::StandardVisualKey;
+
+        let path = std::env::temp_dir().join(format!("ntmusic_cover_{}.ogg", std::process::id()));
+        let base64 = base64::engine::general_purpose::STANDARD;
+        let png = tiny_png();
+        let info = inspect_cover_image(&png).unwrap();
+        let mut back_cover = flac_front_cover(&png, &info);
+        back_cover[..4].copy_from_slice(&4u32.to_be_bytes());
+        let comments = VorbisComments {
+            vendor: "test".to_string(),
+            entries: vec![
+                format!("{}={}", VORBIS_PICTURE_KEY, base64.encode(&back_cover)),
+                format!(
+                    "{}={}",
+                    VORBIS_PICTURE_KEY,
+                    base64.encode(flac_front_cover(b"old", &info))
+                ),
+                "TITLE=Title".to_string(),
+            ],
+        };
+        std::fs::write(&path, vorbis_ogg(&comments)).unwrap();
+        write_cover(&path, &png, false).unwrap();
+        let kept = probed_metadata(&path);
+        write_cover(&path, &png, true).unwrap();
+        let replaced = probed_metadata(&path);
+        let _ = std::fs::remove_file(&path);
+
+        let usages: Vec<_> = kept.visuals().iter().map(|visual| visual.usage).collect();
+        assert_eq!(
+            usages,
+            vec![
+                Some(StandardVisualKey::BackCover),
+                Some(StandardVisualKey::FrontCover)
+            ]
+        );
+        let front = &kept.visuals()[1];
+        assert_eq!(
+            (front.media_type.as_str(), &*front.data),
+            ("image/png", &png[..])
+        );
+        assert_eq!(replaced.visuals().len(), 1);
+        assert_eq!(replaced.tags()[0].value.to_string(), "Title");
+    }
+
+    #[test]
+    fn mp4_cover_replaces_the_first_image() {
+        let path = std::env::temp_dir().join(format!("ntmusic_cover_{}.m4a", std::process::id()));
+        let covr = Mp4Box::item(
+            MP4_COVER,
+            &[
+                Mp4Box::data_atom(MP4_DATA_JPEG, b"front"),
+                Mp4Box::data_atom(MP4_DATA_JPEG, b"back"),
+            ],
+        );
+        let meta = mp4_container(b"meta", &[mp4_container(b"ilst", &[covr])]);
+        std::fs::write(&path, mp4_file(Some(mp4_container(b"udta", &[meta])))).unwrap();
+        let png = tiny_png();
+        let images = |path: &Path| {
+            let (items, chunk) = mp4_items(std::fs::read(path).unwrap());
+            assert_eq!(chunk, b"AUDIO-FRAMES");
+            let covr = items.iter().find(|item| &item.kind == MP4_COVER).unwrap();
+            parse_mp4_boxes(&covr.data)
+                .unwrap()
+                .into_iter()
+                .map(|value| value.data)
+                .collect::<Vec<_>>()
+        };
+
+        write_cover(&path, &png, false).unwrap();
+        let kept = images(&path);
+        write_cover(&path, &png, true).unwrap();
+        let replaced = images(&path);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(kept.len(), 2);
+        assert_eq!(kept[0][..4], MP4_DATA_PNG.to_be_bytes());
+        assert_eq!(kept[0][8..], png[..]);
+        assert_eq!(&kept[1][8..], b"back");
+        assert_eq!(replaced.len(), 1);
+    }
+}